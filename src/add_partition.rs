@@ -0,0 +1,169 @@
+//! Appending of partitions to existing images.
+
+use crate::table::{Gpt, ALIGN};
+use crate::{image, layout, part_type, size, Filesystem, ImageBuilder, PartitionTable};
+use clap::{Args, Parser};
+use log::*;
+use std::fs::OpenOptions;
+use std::io::{Seek, SeekFrom};
+use std::path::PathBuf;
+
+#[derive(Args, Debug)]
+pub struct AddPartitionArgs {
+    /// Image to add the partition to. It is extended as needed
+    #[arg(long)]
+    image: PathBuf,
+    /// Size of the partition, in bytes (`256M`)
+    #[arg(short, long, value_parser = size::parse_bytes)]
+    size: u64,
+    /// Filesystem to format the partition with. Left unformatted if not set
+    #[arg(
+        value_enum,
+        short,
+        long = "fs",
+        visible_alias = "filesystem",
+        requires = "input_dir"
+    )]
+    filesystem: Option<Filesystem>,
+    /// Directory to copy into the filesystem
+    #[arg(short, long, requires = "filesystem")]
+    input_dir: Option<PathBuf>,
+    /// GPT partition type, as a type GUID or an alias such as `esp`, `generic` or `root[-ARCH]`
+    #[arg(long, value_name = "GUID|ALIAS", default_value = "generic")]
+    gpt_type: part_type::GptType,
+    /// Name of the GPT partition
+    #[arg(long, value_name = "NAME", default_value = "data")]
+    part_label: String,
+    /// MBR system ID of the partition. Derived from the GPT type and filesystem if not set
+    #[arg(long, value_name = "ID|ALIAS", value_parser = part_type::parse_mbr_type)]
+    mbr_type: Option<u8>,
+    /// Architecture for `root` and `usr` partition types. Defaults to the host architecture
+    #[arg(value_enum, long)]
+    arch: Option<part_type::Arch>,
+    /// Volume label of the filesystem
+    #[arg(long)]
+    label: Option<String>,
+    /// Whether to follow symlinks or skip them
+    #[arg(short, long)]
+    link_follow: bool,
+}
+
+/// Add the partition table entry, and return the number and byte range of the partition.
+fn add_entry(args: &AddPartitionArgs) -> anyhow::Result<(usize, u64, u64)> {
+    let mut file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(&args.image)
+        .map_err(|e| anyhow::anyhow!("cannot open {}: {e}", args.image.display()))?;
+
+    let (kind, _) = image::read_partitions(&mut file)?;
+    let sector = image::sector_size(&mut file)?;
+    let old_size = file.seek(SeekFrom::End(0))?;
+    let len = args.size.next_multiple_of(sector);
+
+    let part = layout::Partition {
+        name: args.part_label.clone(),
+        filesystem: args.filesystem,
+        size: None,
+        gpt_type: args.gpt_type.clone(),
+        uuid: None,
+        mbr_type: args.mbr_type,
+        primary: None,
+        bootable: false,
+        attributes: 0,
+        input_dir: None,
+        image: None,
+        label: None,
+        files: vec![],
+        subvolumes: vec![],
+    };
+
+    match kind {
+        PartitionTable::Gpt | PartitionTable::Hybrid => {
+            let mut gpt = Gpt::read(&mut file)?;
+
+            let start = gpt.end().max(gpt.first_usable()).next_multiple_of(ALIGN);
+            let required = start + len + gpt.tail();
+            let disk_size = if required > old_size {
+                required.next_multiple_of(ALIGN)
+            } else {
+                old_size
+            };
+
+            let number = gpt.partitions.keys().max().map_or(1, |n| n + 1);
+
+            gpt.partitions.insert(
+                number,
+                gpt::partition::Partition {
+                    part_type_guid: part
+                        .gpt_type
+                        .to_type(args.arch.or_else(part_type::Arch::host))?,
+                    part_guid: crate::random_guid(&Default::default())?.parse()?,
+                    first_lba: start / sector,
+                    last_lba: (start + len) / sector - 1,
+                    flags: 0,
+                    name: part.name.clone(),
+                },
+            );
+
+            gpt.write(&mut file, disk_size)?;
+
+            Ok((number as usize, start, len))
+        }
+        PartitionTable::Mbr => {
+            let mut mbr = mbrman::MBR::read_from(&mut file, sector as u32)?;
+
+            let end = mbr
+                .iter()
+                .filter(|(_, p)| p.is_used())
+                .map(|(_, p)| (p.starting_lba as u64 + p.sectors as u64) * sector)
+                .max()
+                .unwrap_or(sector);
+            let start = end.next_multiple_of(ALIGN);
+
+            let number = mbr
+                .iter()
+                .find(|(_, p)| !p.is_used())
+                .map(|(n, _)| n)
+                .ok_or_else(|| anyhow::anyhow!("all 4 primary partitions of the MBR are in use"))?;
+
+            mbr[number] = crate::mbr_entry(&part, start, len, sector, crate::mbr_type(&part))?;
+
+            if start + len > old_size {
+                file.set_len(start + len)?;
+            }
+            mbr.write_into(&mut file)?;
+
+            Ok((number, start, len))
+        }
+        _ => anyhow::bail!(
+            "{} has no partition table to add a partition to",
+            args.image.display()
+        ),
+    }
+}
+
+pub fn run(args: &AddPartitionArgs) -> anyhow::Result<()> {
+    let (number, start, len) = add_entry(args)?;
+
+    info!("Added partition {number}: start {start:x} size {len:x}");
+
+    if let (Some(filesystem), Some(input_dir)) = (args.filesystem, &args.input_dir) {
+        let mut argv = vec!["mkimg".into(), "--input-dir".into(), input_dir.into()];
+        argv.extend(["--output-path".into(), args.image.clone().into_os_string()]);
+        argv.extend(["--into-partition".into(), number.to_string().into()]);
+        if let Some(label) = &args.label {
+            argv.extend(["--label".into(), label.into()]);
+        }
+
+        let mut create = crate::Args::try_parse_from::<_, std::ffi::OsString>(argv)?;
+        create.filesystem = filesystem;
+        create.link_follow = args.link_follow;
+
+        ImageBuilder::new(create).build()?;
+    }
+
+    println!("Partition {number} added to {}", args.image.display());
+
+    Ok(())
+}
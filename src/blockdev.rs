@@ -0,0 +1,182 @@
+//! Direct writes of finished images to block devices, such as SD cards and USB sticks.
+
+use log::*;
+use std::collections::HashSet;
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::os::unix::fs::{FileTypeExt, OpenOptionsExt};
+use std::path::{Path, PathBuf};
+
+/// Size of each write, a multiple of every logical block size
+const CHUNK_SIZE: usize = 4 << 20;
+
+/// Alignment of the write buffer, as O_DIRECT requires
+const ALIGNMENT: usize = 4096;
+
+pub fn is_block_device(path: &Path) -> bool {
+    fs::metadata(path).is_ok_and(|m| m.file_type().is_block_device())
+}
+
+pub struct Device {
+    path: PathBuf,
+    /// Kernel name, such as `sdb`
+    name: String,
+    model: String,
+    size: u64,
+    logical_block_size: u64,
+}
+
+impl Device {
+    pub fn open(path: &Path) -> anyhow::Result<Self> {
+        let path = fs::canonicalize(path)?;
+        let name = path
+            .file_name()
+            .map(|n| n.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let sys = Path::new("/sys/class/block").join(&name);
+
+        let read = |file: &str| {
+            fs::read_to_string(sys.join(file))
+                .map(|s| s.trim().to_owned())
+                .ok()
+                .filter(|s| !s.is_empty())
+        };
+
+        let model = [
+            read("device/vendor")
+                .into_iter()
+                .chain(read("device/model"))
+                .collect::<Vec<_>>()
+                .join(" "),
+            read("device/name").unwrap_or_default(),
+            read("loop/backing_file").unwrap_or_default(),
+        ]
+        .into_iter()
+        .find(|m| !m.is_empty())
+        .unwrap_or_else(|| "unknown model".into());
+
+        let size = File::open(&path)?.seek(SeekFrom::End(0))?;
+        let logical_block_size = read("queue/logical_block_size")
+            .and_then(|s| s.parse().ok())
+            .unwrap_or(512);
+
+        Ok(Self {
+            path,
+            name,
+            model,
+            size,
+            logical_block_size,
+        })
+    }
+
+    pub fn describe(&self) -> String {
+        format!(
+            "{} ({}, {} bytes, {:.1} GiB)",
+            self.path.display(),
+            self.model,
+            self.size,
+            self.size as f64 / (1u64 << 30) as f64
+        )
+    }
+
+    /// Kernel names of the device and its partitions.
+    fn names(&self) -> HashSet<String> {
+        let sys = Path::new("/sys/class/block").join(&self.name);
+        let partitions = fs::read_dir(sys)
+            .into_iter()
+            .flatten()
+            .filter_map(Result::ok)
+            .filter(|e| e.path().join("partition").exists())
+            .map(|e| e.file_name().to_string_lossy().into_owned());
+
+        std::iter::once(self.name.clone())
+            .chain(partitions)
+            .collect()
+    }
+
+    /// Fail if the device or any of its partitions is mounted, used as swap or held by another
+    /// device, such as a RAID array or LVM volume.
+    pub fn check_unused(&self) -> anyhow::Result<()> {
+        let names = self.names();
+
+        let sources = ["/proc/self/mounts", "/proc/swaps"]
+            .into_iter()
+            .filter_map(|f| fs::read_to_string(f).ok())
+            .flat_map(|s| {
+                s.lines()
+                    .filter_map(|l| l.split_whitespace().next().map(str::to_owned))
+                    .collect::<Vec<_>>()
+            });
+
+        for source in sources.filter(|s| s.starts_with('/')) {
+            let name = fs::canonicalize(&source)
+                .ok()
+                .and_then(|p| p.file_name().map(|n| n.to_string_lossy().into_owned()));
+            if name.is_some_and(|n| names.contains(&n)) {
+                anyhow::bail!("{} is in use: {source} is mounted", self.path.display());
+            }
+        }
+
+        for name in &names {
+            let holders = Path::new("/sys/class/block").join(name).join("holders");
+            if let Some(holder) = fs::read_dir(holders).into_iter().flatten().flatten().next() {
+                anyhow::bail!(
+                    "{} is in use: {name} is held by {}",
+                    self.path.display(),
+                    holder.file_name().to_string_lossy()
+                );
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Copy the image at `src` to the start of the device, bypassing the page cache if the
+    /// device allows it.
+    pub fn write(&self, src: &Path) -> anyhow::Result<()> {
+        let mut input = File::open(src)?;
+        let len = input.metadata()?.len();
+
+        if len > self.size {
+            anyhow::bail!("the image is {len} bytes, larger than {}", self.describe());
+        }
+
+        let open = |flags| {
+            OpenOptions::new()
+                .write(true)
+                .custom_flags(flags)
+                .open(&self.path)
+        };
+        let (mut out, direct) = match open(libc::O_DIRECT) {
+            Ok(f) => (f, true),
+            Err(e) if e.raw_os_error() == Some(libc::EINVAL) => (open(0)?, false),
+            Err(e) => return Err(e.into()),
+        };
+        debug!("Writing {} with O_DIRECT: {direct}", self.path.display());
+
+        // Over-allocate, and use the aligned part of the buffer
+        let mut storage = vec![0u8; CHUNK_SIZE + ALIGNMENT];
+        let skip = storage.as_ptr().align_offset(ALIGNMENT);
+        let buf = &mut storage[skip..skip + CHUNK_SIZE];
+
+        let mut written = 0;
+        while written < len {
+            let n = (len - written).min(CHUNK_SIZE as u64) as usize;
+            input.read_exact(&mut buf[..n])?;
+
+            // Direct writes are whole logical blocks, so the tail is padded with zeroes
+            let padded = (n as u64).next_multiple_of(self.logical_block_size) as usize;
+            let padded = padded.min((self.size - written) as usize);
+            buf[n..padded].fill(0);
+
+            out.write_all(&buf[..padded])?;
+            written += n as u64;
+        }
+
+        out.sync_all()?;
+
+        info!("Wrote {len} bytes to {}", self.describe());
+
+        Ok(())
+    }
+}
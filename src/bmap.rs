@@ -0,0 +1,82 @@
+//! Block map files for bmaptool, describing which parts of the image hold data.
+
+use crate::sha256::{self, Sha256};
+use crate::sparse;
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::Path;
+
+const BLOCK_SIZE: u64 = 4096;
+
+/// Generate a bmap (format version 2.0) for the image at `path`.
+pub fn generate(path: &Path) -> io::Result<String> {
+    let mut file = File::open(path)?;
+    let len = file.metadata()?.len();
+    let blocks = len.div_ceil(BLOCK_SIZE);
+
+    // Widen to block granularity and merge ranges that touch
+    let mut block_ranges: Vec<(u64, u64)> = vec![];
+
+    for (start, end) in sparse::data_ranges(&file, len)? {
+        let (first, last) = (start / BLOCK_SIZE, end.div_ceil(BLOCK_SIZE) - 1);
+
+        match block_ranges.last_mut() {
+            Some((_, prev_last)) if *prev_last + 1 >= first => *prev_last = last.max(*prev_last),
+            _ => block_ranges.push((first, last)),
+        }
+    }
+
+    let mut map = String::new();
+    let mut mapped = 0;
+    let mut buf = vec![0; BLOCK_SIZE as usize];
+
+    for (first, last) in block_ranges {
+        file.seek(SeekFrom::Start(first * BLOCK_SIZE))?;
+
+        let mut hasher = Sha256::new();
+        let mut remaining = ((last + 1) * BLOCK_SIZE).min(len) - first * BLOCK_SIZE;
+
+        while remaining > 0 {
+            let n = remaining.min(BLOCK_SIZE) as usize;
+            file.read_exact(&mut buf[..n])?;
+            hasher.update(&buf[..n]);
+            remaining -= n as u64;
+        }
+
+        let range = if first == last {
+            format!("{first}")
+        } else {
+            format!("{first}-{last}")
+        };
+
+        map += &format!(
+            "        <Range chksum=\"{}\"> {range} </Range>\n",
+            sha256::hex(&hasher.finish())
+        );
+        mapped += last - first + 1;
+    }
+
+    let bmap = |checksum: &str| {
+        format!(
+            r#"<?xml version="1.0" ?>
+<!-- Generated by mkimg {} -->
+<bmap version="2.0">
+    <ImageSize> {len} </ImageSize>
+    <BlockSize> {BLOCK_SIZE} </BlockSize>
+    <BlocksCount> {blocks} </BlocksCount>
+    <MappedBlocksCount> {mapped} </MappedBlocksCount>
+    <ChecksumType> sha256 </ChecksumType>
+    <BmapFileChecksum> {checksum} </BmapFileChecksum>
+    <BlockMap>
+{map}    </BlockMap>
+</bmap>
+"#,
+            env!("CARGO_PKG_VERSION")
+        )
+    };
+
+    // The file checksum is calculated with the checksum field set to all zeroes
+    let checksum = sha256::hex(&sha256::digest(bmap(&"0".repeat(64)).as_bytes()));
+
+    Ok(bmap(&checksum))
+}
@@ -0,0 +1,1206 @@
+//! btrfs images on a single device.
+//!
+//! Uses 4K sectors and 16K tree blocks, both checksummed with crc32c. After the first megabyte
+//! comes a system chunk with the chunk tree, the data chunk with the file extents, and a
+//! metadata chunk with the root, extent, device, FS and checksum trees, each mapped at its
+//! offset on the device and without copies. The rest of the device is left unallocated for the
+//! kernel to create chunks in. Symbolic links and files of up to 2K are stored inline in the FS
+//! tree, and larger files in extents of up to 128M.
+//!
+//! Directories can be made subvolumes, each with an FS tree of its own, and one of them the
+//! default subvolume mounted instead of the top level.
+
+use crate::tree::{Kind, Tree};
+use crc::crc32;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+
+const SECTOR_SIZE: u64 = 4096;
+const NODE_SIZE: u64 = 16384;
+const HEADER_SIZE: usize = 101;
+const ITEM_SIZE: usize = 25;
+const KEY_PTR_SIZE: usize = 33;
+/// Space for the items of a leaf and their data
+const LEAF_DATA: usize = NODE_SIZE as usize - HEADER_SIZE;
+const NODE_PTRS: usize = LEAF_DATA / KEY_PTR_SIZE;
+/// Largest file stored inline, the default of the kernel
+const MAX_INLINE: u64 = 2048;
+const MAX_EXTENT: u64 = 128 << 20;
+/// Checksums in a single item, as the kernel limits them
+const MAX_CSUMS: usize = (LEAF_DATA - 2 * ITEM_SIZE) / 4 - 1;
+/// Largest item of the extent tree, which sizes its leaves before the items are known
+const MAX_EXTENT_ITEM: usize = 53;
+
+const SUPER_OFFSET: u64 = 64 << 10;
+const SUPER_SIZE: usize = 4096;
+/// Copies of the superblock, written when the device is large enough
+const SUPER_MIRRORS: [u64; 2] = [64 << 20, 256 << 30];
+const MAGIC: &[u8; 8] = b"_BHRfS_M";
+/// Chunks leave out the first megabyte, which holds the superblock and boot loaders
+const RESERVED: u64 = 1 << 20;
+const STRIPE_LEN: u64 = 64 << 10;
+const CHUNK_ALIGN: u64 = 1 << 20;
+/// The chunk tree only holds the device and three chunks
+const SYSTEM_CHUNK: u64 = 1 << 20;
+const DATA_START: u64 = RESERVED + SYSTEM_CHUNK;
+/// Free metadata space for the first transactions after mounting
+const METADATA_SLACK: u64 = 4 << 20;
+const GENERATION: u64 = 1;
+
+const ROOT_TREE: u64 = 1;
+const EXTENT_TREE: u64 = 2;
+const CHUNK_TREE: u64 = 3;
+const DEV_TREE: u64 = 4;
+const FS_TREE: u64 = 5;
+const ROOT_TREE_DIR: u64 = 6;
+const CSUM_TREE: u64 = 7;
+const DEV_ITEMS: u64 = 1;
+const FIRST_CHUNK_TREE: u64 = 256;
+const FIRST_FREE: u64 = 256;
+const EXTENT_CSUM: u64 = -10i64 as u64;
+
+const INODE_ITEM: u8 = 1;
+const INODE_REF: u8 = 12;
+const DIR_ITEM: u8 = 84;
+const DIR_INDEX: u8 = 96;
+const EXTENT_DATA: u8 = 108;
+const EXTENT_CSUM_KEY: u8 = 128;
+const ROOT_ITEM: u8 = 132;
+const ROOT_BACKREF: u8 = 144;
+const ROOT_REF: u8 = 156;
+const EXTENT_ITEM: u8 = 168;
+const METADATA_ITEM: u8 = 169;
+const TREE_BLOCK_REF: u8 = 176;
+const EXTENT_DATA_REF: u8 = 178;
+const BLOCK_GROUP_ITEM: u8 = 192;
+const DEV_EXTENT: u8 = 204;
+const DEV_ITEM: u8 = 216;
+const CHUNK_ITEM: u8 = 228;
+
+const FT_REG_FILE: u8 = 1;
+const FT_DIR: u8 = 2;
+const FT_SYMLINK: u8 = 7;
+
+const S_IFDIR: u32 = 0o040000;
+const S_IFREG: u32 = 0o100000;
+const S_IFLNK: u32 = 0o120000;
+
+const BLOCK_GROUP_DATA: u64 = 1;
+const BLOCK_GROUP_SYSTEM: u64 = 2;
+const BLOCK_GROUP_METADATA: u64 = 4;
+
+const EXTENT_FLAG_DATA: u64 = 1;
+const EXTENT_FLAG_TREE_BLOCK: u64 = 2;
+const HEADER_FLAG_WRITTEN: u64 = 1;
+const MIXED_BACKREF_REV: u64 = 1 << 56;
+
+const FILE_EXTENT_INLINE: u8 = 0;
+const FILE_EXTENT_REG: u8 = 1;
+
+/// MIXED_BACKREF, BIG_METADATA, EXTENDED_IREF, SKINNY_METADATA and NO_HOLES
+const INCOMPAT_FLAGS: u64 = 0x1 | 0x20 | 0x40 | 0x100 | 0x200;
+const INCOMPAT_DEFAULT_SUBVOL: u64 = 0x2;
+
+#[derive(Clone, Debug, Default)]
+pub struct Options {
+    pub label: Option<String>,
+    /// Directories of the tree made subvolumes, by path
+    pub subvolumes: Vec<PathBuf>,
+    /// One of `subvolumes`, mounted when no subvolume is asked for
+    pub default_subvolume: Option<PathBuf>,
+}
+
+/// crc32c of data and tree blocks.
+fn crc32c(data: &[u8]) -> u32 {
+    crc32::checksum_castagnoli(data)
+}
+
+/// Hash of a name in the keys of directory items.
+fn name_hash(name: &[u8]) -> u64 {
+    // crc32c seeded with ~1, without the final inversion
+    !crc32::update(1, &crc32::CASTAGNOLI_TABLE, name) as u64
+}
+
+/// Compute the checksum of a tree block or superblock, which covers all that follows it.
+fn set_csum(block: &mut [u8]) {
+    let crc = crc32c(&block[32..]);
+    block[..32].fill(0);
+    block[..4].copy_from_slice(&crc.to_le_bytes());
+}
+
+fn le16(buf: &mut [u8], off: usize, v: u16) {
+    buf[off..off + 2].copy_from_slice(&v.to_le_bytes());
+}
+
+fn le32(buf: &mut [u8], off: usize, v: u32) {
+    buf[off..off + 4].copy_from_slice(&v.to_le_bytes());
+}
+
+fn le64(buf: &mut [u8], off: usize, v: u64) {
+    buf[off..off + 8].copy_from_slice(&v.to_le_bytes());
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+struct Key {
+    objectid: u64,
+    kind: u8,
+    offset: u64,
+}
+
+impl Key {
+    fn new(objectid: u64, kind: u8, offset: u64) -> Self {
+        Self {
+            objectid,
+            kind,
+            offset,
+        }
+    }
+
+    fn to_bytes(self) -> [u8; 17] {
+        let mut b = [0; 17];
+        le64(&mut b, 0, self.objectid);
+        b[8] = self.kind;
+        le64(&mut b, 9, self.offset);
+        b
+    }
+}
+
+/// Items of a tree, in any order until they are sorted by their keys.
+type Items = Vec<(Key, Vec<u8>)>;
+
+/// Blocks of a tree by level, from the leaves up to the root. Every block holds a range of the
+/// items, or of the blocks of the level below.
+struct Shape {
+    levels: Vec<Vec<Range<usize>>>,
+}
+
+impl Shape {
+    /// Pack items of the given sizes into leaves, at most `per_leaf` of them to a leaf, and add
+    /// the nodes pointing to them. An empty tree is a single empty leaf.
+    fn new(sizes: impl Iterator<Item = usize>, per_leaf: usize) -> Self {
+        let mut leaves = vec![];
+        let mut start = 0;
+        let mut used = 0;
+        let mut count = 0;
+
+        for (i, size) in sizes.enumerate() {
+            if i > start && (used + ITEM_SIZE + size > LEAF_DATA || i - start == per_leaf) {
+                leaves.push(start..i);
+                start = i;
+                used = 0;
+            }
+            used += ITEM_SIZE + size;
+            count = i + 1;
+        }
+        leaves.push(start..count);
+
+        let mut levels = vec![leaves];
+        while levels.last().unwrap().len() > 1 {
+            let below = levels.last().unwrap().len();
+            let nodes = (0..below)
+                .step_by(NODE_PTRS)
+                .map(|s| s..(s + NODE_PTRS).min(below))
+                .collect();
+            levels.push(nodes);
+        }
+
+        Self { levels }
+    }
+
+    fn of(items: &Items) -> Self {
+        Self::new(items.iter().map(|(_, data)| data.len()), usize::MAX)
+    }
+
+    fn blocks(&self) -> usize {
+        self.levels.iter().map(Vec::len).sum()
+    }
+
+    fn level(&self) -> u8 {
+        self.levels.len() as u8 - 1
+    }
+
+    /// Level of the block at `idx`, counting the blocks level by level.
+    fn block_level(&self, mut idx: usize) -> u8 {
+        for (level, ranges) in self.levels.iter().enumerate() {
+            if idx < ranges.len() {
+                return level as u8;
+            }
+            idx -= ranges.len();
+        }
+        unreachable!("block {idx} is not in the tree")
+    }
+}
+
+/// Identifiers shared by all tree blocks, and those of the subvolumes.
+struct Ids {
+    fsid: [u8; 16],
+    chunk_tree_uuid: [u8; 16],
+    dev_uuid: [u8; 16],
+    /// UUID of every FS tree, zero for the top level
+    subvolumes: Vec<[u8; 16]>,
+}
+
+/// A tree with its blocks placed.
+struct Placed {
+    owner: u64,
+    items: Items,
+    shape: Shape,
+    /// Address of every block, level by level
+    addrs: Vec<u64>,
+}
+
+impl Placed {
+    fn root(&self) -> u64 {
+        *self.addrs.last().unwrap()
+    }
+
+    /// Encode the blocks of the tree, as `(address, block)`.
+    fn encode(&self, ids: &Ids) -> Vec<(u64, Vec<u8>)> {
+        let mut blocks = vec![];
+        let mut below_keys: Vec<Key> = vec![];
+        let mut below_base = 0;
+        let mut base = 0;
+
+        for (level, ranges) in self.shape.levels.iter().enumerate() {
+            let mut keys = vec![];
+
+            for (i, range) in ranges.iter().enumerate() {
+                let mut b = vec![0u8; NODE_SIZE as usize];
+
+                if level == 0 {
+                    let mut end = LEAF_DATA;
+                    for (j, (key, data)) in self.items[range.clone()].iter().enumerate() {
+                        end -= data.len();
+                        let off = HEADER_SIZE + j * ITEM_SIZE;
+                        b[off..off + 17].copy_from_slice(&key.to_bytes());
+                        le32(&mut b, off + 17, end as u32);
+                        le32(&mut b, off + 21, data.len() as u32);
+                        b[HEADER_SIZE + end..HEADER_SIZE + end + data.len()].copy_from_slice(data);
+                    }
+                    keys.push(self.items.get(range.start).map_or(Key::default(), |i| i.0));
+                } else {
+                    for (j, child) in range.clone().enumerate() {
+                        let off = HEADER_SIZE + j * KEY_PTR_SIZE;
+                        b[off..off + 17].copy_from_slice(&below_keys[child].to_bytes());
+                        le64(&mut b, off + 17, self.addrs[below_base + child]);
+                        le64(&mut b, off + 25, GENERATION);
+                    }
+                    keys.push(below_keys[range.start]);
+                }
+
+                let addr = self.addrs[base + i];
+                b[32..48].copy_from_slice(&ids.fsid);
+                le64(&mut b, 48, addr);
+                le64(&mut b, 56, HEADER_FLAG_WRITTEN | MIXED_BACKREF_REV);
+                b[64..80].copy_from_slice(&ids.chunk_tree_uuid);
+                le64(&mut b, 80, GENERATION);
+                le64(&mut b, 88, self.owner);
+                le32(&mut b, 96, range.len() as u32);
+                b[100] = level as u8;
+                set_csum(&mut b);
+                blocks.push((addr, b));
+            }
+
+            below_keys = keys;
+            below_base = base;
+            base += ranges.len();
+        }
+
+        blocks
+    }
+}
+
+/// Extent of file data, at `offset` in the file.
+#[derive(Clone, Copy, Debug)]
+struct Extent {
+    offset: u64,
+    disk: u64,
+    /// Length on disk, in whole sectors
+    len: u64,
+}
+
+/// Stripe holding a copy of the superblock that starts at or after `pos`, which chunks leave
+/// empty.
+fn next_mirror(pos: u64) -> Option<u64> {
+    SUPER_MIRRORS.into_iter().find(|&m| m + STRIPE_LEN > pos)
+}
+
+/// Start of `len` bytes at or after `pos`, moved past the stripes of superblock copies.
+fn skip_mirrors(pos: u64, len: u64) -> u64 {
+    match next_mirror(pos) {
+        Some(m) if pos + len > m => m + STRIPE_LEN,
+        _ => pos,
+    }
+}
+
+fn is_inline(kind: &Kind) -> bool {
+    match kind {
+        Kind::File { len, .. } => (1..=MAX_INLINE).contains(len),
+        Kind::Symlink(_) => true,
+        Kind::Dir(_) => false,
+    }
+}
+
+/// Object ID of the FS tree at `idx` of [`Layout::roots`].
+fn tree_id(idx: usize) -> u64 {
+    match idx {
+        0 => FS_TREE,
+        idx => FIRST_FREE + idx as u64 - 1,
+    }
+}
+
+/// Placement of the nodes in FS trees, and of the file data.
+struct Layout {
+    /// Root directory of every FS tree, the top level first and then the subvolumes
+    roots: Vec<usize>,
+    /// FS tree of every node, as an index of `roots`
+    owners: Vec<usize>,
+    /// Inode number of every node in its FS tree
+    inos: Vec<u64>,
+    /// Index of the default subvolume in `roots`
+    default: usize,
+    /// Extents of every node, none for those without data or stored inline
+    extents: Vec<Vec<Extent>>,
+    data_chunk: Range<u64>,
+}
+
+impl Layout {
+    fn new(tree: &Tree, opts: &Options) -> anyhow::Result<Self> {
+        for node in &tree.nodes[1..] {
+            if node.name.len() > 255 {
+                anyhow::bail!(
+                    "btrfs names are limited to 255 bytes: {}",
+                    node.path.display()
+                );
+            }
+        }
+
+        let mut roots = vec![0];
+        for path in &opts.subvolumes {
+            let idx = path
+                .iter()
+                .try_fold(0, |dir, name| tree.child(dir, name.to_str()?))
+                .filter(|&idx| tree.nodes[idx].is_dir())
+                .ok_or_else(|| {
+                    anyhow::anyhow!("subvolume {} is not a directory", path.display())
+                })?;
+            roots.push(idx);
+        }
+        let default = match &opts.default_subvolume {
+            Some(path) => 1 + opts.subvolumes.iter().position(|p| p == path).unwrap(),
+            None => 0,
+        };
+
+        // Nodes belong to the FS tree of their parent, unless they are the root of one
+        let mut owners = vec![0; tree.nodes.len()];
+        let mut stack = vec![0];
+        while let Some(idx) = stack.pop() {
+            owners[idx] = match roots.iter().position(|&r| r == idx) {
+                Some(root) => root,
+                None => owners[tree.nodes[idx].parent],
+            };
+            if let Kind::Dir(children) = &tree.nodes[idx].kind {
+                stack.extend(children);
+            }
+        }
+
+        let mut next = vec![FIRST_FREE + 1; roots.len()];
+        let inos = (0..tree.nodes.len())
+            .map(|idx| match roots.contains(&idx) {
+                true => FIRST_FREE,
+                false => {
+                    next[owners[idx]] += 1;
+                    next[owners[idx]] - 1
+                }
+            })
+            .collect();
+
+        let mut pos = DATA_START;
+        let extents = tree
+            .nodes
+            .iter()
+            .map(|node| {
+                let mut extents = vec![];
+                let Kind::File { len, .. } = node.kind else {
+                    return extents;
+                };
+                if is_inline(&node.kind) {
+                    return extents;
+                }
+
+                let mut offset = 0;
+                let total = len.next_multiple_of(SECTOR_SIZE);
+                while offset < total {
+                    pos = skip_mirrors(pos, SECTOR_SIZE);
+                    let mut len = (total - offset).min(MAX_EXTENT);
+                    if let Some(m) = next_mirror(pos) {
+                        len = len.min(m - pos);
+                    }
+                    extents.push(Extent {
+                        offset,
+                        disk: pos,
+                        len,
+                    });
+                    offset += len;
+                    pos += len;
+                }
+                extents
+            })
+            .collect();
+
+        let end = pos.max(DATA_START + 1).next_multiple_of(CHUNK_ALIGN);
+        Ok(Self {
+            roots,
+            owners,
+            inos,
+            default,
+            extents,
+            data_chunk: DATA_START..end,
+        })
+    }
+}
+
+fn timespec(buf: &mut [u8], off: usize, sec: i64) {
+    le64(buf, off, sec as u64);
+}
+
+fn inode_item(size: u64, nbytes: u64, mode: u32, uid: u32, gid: u32, mtime: i64) -> Vec<u8> {
+    let mut i = vec![0u8; 160];
+    le64(&mut i, 0, GENERATION);
+    le64(&mut i, 8, GENERATION);
+    le64(&mut i, 16, size);
+    le64(&mut i, 24, nbytes);
+    le32(&mut i, 40, 1);
+    le32(&mut i, 44, uid);
+    le32(&mut i, 48, gid);
+    le32(&mut i, 52, mode);
+    for off in [112, 124, 136, 148] {
+        timespec(&mut i, off, mtime);
+    }
+    i
+}
+
+fn inode_ref(index: u64, name: &[u8]) -> Vec<u8> {
+    let mut r = vec![0u8; 10];
+    le64(&mut r, 0, index);
+    le16(&mut r, 8, name.len() as u16);
+    r.extend_from_slice(name);
+    r
+}
+
+fn dir_item(location: Key, file_type: u8, name: &[u8]) -> Vec<u8> {
+    let mut d = vec![0u8; 30];
+    d[0..17].copy_from_slice(&location.to_bytes());
+    le64(&mut d, 17, GENERATION);
+    le16(&mut d, 27, name.len() as u16);
+    d[29] = file_type;
+    d.extend_from_slice(name);
+    d
+}
+
+fn file_extent(kind: u8, ram_bytes: u64) -> Vec<u8> {
+    let mut e = vec![0u8; 21];
+    le64(&mut e, 0, GENERATION);
+    le64(&mut e, 8, ram_bytes);
+    e[20] = kind;
+    e
+}
+
+/// Entries of directory `dir` in the FS tree: a directory item for every name hash, holding all
+/// names with that hash, and an index item for every entry.
+fn dir_entries(items: &mut Items, dir: u64, entries: &[(Key, u8, &[u8])]) {
+    let mut by_hash = std::collections::BTreeMap::<u64, Vec<u8>>::new();
+
+    for (i, &(location, file_type, name)) in entries.iter().enumerate() {
+        let index = 2 + i as u64;
+        let item = dir_item(location, file_type, name);
+        by_hash
+            .entry(name_hash(name))
+            .or_default()
+            .extend_from_slice(&item);
+        items.push((Key::new(dir, DIR_INDEX, index), item));
+    }
+
+    for (hash, item) in by_hash {
+        items.push((Key::new(dir, DIR_ITEM, hash), item));
+    }
+}
+
+/// Items of every FS tree. `inline` gives the contents of files stored inline.
+fn fs_items(
+    tree: &Tree,
+    layout: &Layout,
+    inline: &mut dyn FnMut(usize) -> anyhow::Result<Vec<u8>>,
+) -> anyhow::Result<Vec<Items>> {
+    let mut trees = vec![vec![]; layout.roots.len()];
+
+    for (idx, node) in tree.nodes.iter().enumerate() {
+        let ino = layout.inos[idx];
+        let items = &mut trees[layout.owners[idx]];
+        let (mode, size, nbytes) = match &node.kind {
+            Kind::Dir(children) => {
+                let names = children.iter().map(|&c| tree.nodes[c].name.len() as u64);
+                (S_IFDIR, 2 * names.sum::<u64>(), 0)
+            }
+            Kind::File { len, .. } => {
+                let extents = &layout.extents[idx];
+                let nbytes = match extents.is_empty() {
+                    true => *len,
+                    false => extents.iter().map(|e| e.len).sum(),
+                };
+                (S_IFREG, *len, nbytes)
+            }
+            Kind::Symlink(target) => (S_IFLNK, target.len() as u64, target.len() as u64),
+        };
+        let item = inode_item(
+            size,
+            nbytes,
+            mode | node.mode,
+            node.uid,
+            node.gid,
+            node.mtime,
+        );
+        items.push((Key::new(ino, INODE_ITEM, 0), item));
+
+        // The root directory of an FS tree refers to itself, and its entry in the parent is that
+        // of the subvolume
+        match layout.roots.contains(&idx) {
+            true => items.push((Key::new(ino, INODE_REF, ino), inode_ref(0, b".."))),
+            false => items.push((
+                Key::new(ino, INODE_REF, layout.inos[node.parent]),
+                inode_ref(dir_index(tree, idx), node.name.as_bytes()),
+            )),
+        }
+
+        match &node.kind {
+            Kind::Dir(children) => {
+                let entries = children
+                    .iter()
+                    .map(|&c| {
+                        let child = &tree.nodes[c];
+                        let file_type = match child.kind {
+                            Kind::Dir(_) => FT_DIR,
+                            Kind::File { .. } => FT_REG_FILE,
+                            Kind::Symlink(_) => FT_SYMLINK,
+                        };
+                        let location = match layout.owners[c] {
+                            owner if owner != layout.owners[idx] => {
+                                Key::new(tree_id(owner), ROOT_ITEM, u64::MAX)
+                            }
+                            _ => Key::new(layout.inos[c], INODE_ITEM, 0),
+                        };
+                        (location, file_type, child.name.as_bytes())
+                    })
+                    .collect::<Vec<_>>();
+                dir_entries(items, ino, &entries);
+            }
+            Kind::File { .. } if is_inline(&node.kind) => {
+                let data = inline(idx)?;
+                let mut e = file_extent(FILE_EXTENT_INLINE, data.len() as u64);
+                e.extend(data);
+                items.push((Key::new(ino, EXTENT_DATA, 0), e));
+            }
+            Kind::File { .. } => {
+                for extent in &layout.extents[idx] {
+                    let mut e = file_extent(FILE_EXTENT_REG, extent.len);
+                    e.extend(extent.disk.to_le_bytes());
+                    e.extend(extent.len.to_le_bytes());
+                    e.extend(0u64.to_le_bytes());
+                    e.extend(extent.len.to_le_bytes());
+                    items.push((Key::new(ino, EXTENT_DATA, extent.offset), e));
+                }
+            }
+            Kind::Symlink(target) => {
+                let mut e = file_extent(FILE_EXTENT_INLINE, target.len() as u64);
+                e.extend(target.as_bytes());
+                items.push((Key::new(ino, EXTENT_DATA, 0), e));
+            }
+        }
+    }
+
+    Ok(trees)
+}
+
+/// Index of the entry of the node at `idx` in its directory.
+fn dir_index(tree: &Tree, idx: usize) -> u64 {
+    let Kind::Dir(siblings) = &tree.nodes[tree.nodes[idx].parent].kind else {
+        unreachable!("parents are directories")
+    };
+    2 + siblings.iter().position(|&s| s == idx).unwrap() as u64
+}
+
+/// Items of the checksum tree, from the checksums of the sectors of every node.
+fn csum_items(layout: &Layout, sums: &[Vec<u32>]) -> Items {
+    // Runs of checksums of adjacent extents
+    let mut runs: Vec<(u64, Vec<u32>)> = vec![];
+    for (extents, sums) in layout.extents.iter().zip(sums) {
+        let mut sums = &sums[..];
+        for e in extents {
+            let (these, rest) = sums.split_at((e.len / SECTOR_SIZE) as usize);
+            sums = rest;
+            match runs.last_mut() {
+                Some((start, run)) if *start + run.len() as u64 * SECTOR_SIZE == e.disk => {
+                    run.extend_from_slice(these)
+                }
+                _ => runs.push((e.disk, these.to_vec())),
+            }
+        }
+    }
+
+    let mut items = vec![];
+    for (start, run) in runs {
+        for (i, chunk) in run.chunks(MAX_CSUMS).enumerate() {
+            let offset = start + (i * MAX_CSUMS) as u64 * SECTOR_SIZE;
+            let data = chunk.iter().flat_map(|s| s.to_le_bytes()).collect();
+            items.push((Key::new(EXTENT_CSUM, EXTENT_CSUM_KEY, offset), data));
+        }
+    }
+    items
+}
+
+fn root_item(tree: &Placed, root_dirid: u64, uuid: &[u8; 16], time: i64) -> Vec<u8> {
+    let mut r = vec![0u8; 439];
+    let inode = inode_item(3, NODE_SIZE, S_IFDIR | 0o755, 0, 0, 0);
+    r[..160].copy_from_slice(&inode);
+    le64(&mut r, 160, GENERATION);
+    le64(&mut r, 168, root_dirid);
+    le64(&mut r, 176, tree.root());
+    le64(&mut r, 192, tree.shape.blocks() as u64 * NODE_SIZE);
+    le32(&mut r, 216, 1);
+    r[238] = tree.shape.level();
+    le64(&mut r, 239, GENERATION);
+    r[247..263].copy_from_slice(uuid);
+    le64(&mut r, 295, GENERATION);
+    timespec(&mut r, 327, time);
+    timespec(&mut r, 339, time);
+    r
+}
+
+/// Device item, as kept in the chunk tree and the superblock.
+fn dev_item(total_bytes: u64, bytes_used: u64, ids: &Ids) -> Vec<u8> {
+    let mut d = vec![0u8; 98];
+    le64(&mut d, 0, 1);
+    le64(&mut d, 8, total_bytes);
+    le64(&mut d, 16, bytes_used);
+    le32(&mut d, 24, SECTOR_SIZE as u32);
+    le32(&mut d, 28, SECTOR_SIZE as u32);
+    le32(&mut d, 32, SECTOR_SIZE as u32);
+    d[66..82].copy_from_slice(&ids.dev_uuid);
+    d[82..98].copy_from_slice(&ids.fsid);
+    d
+}
+
+/// Chunk with a single stripe at the same offset of the device.
+fn chunk_item(chunk: &Range<u64>, flags: u64, ids: &Ids) -> Vec<u8> {
+    let mut c = vec![0u8; 80];
+    le64(&mut c, 0, chunk.end - chunk.start);
+    le64(&mut c, 8, EXTENT_TREE);
+    le64(&mut c, 16, STRIPE_LEN);
+    le64(&mut c, 24, flags);
+    le32(&mut c, 32, STRIPE_LEN as u32);
+    le32(&mut c, 36, STRIPE_LEN as u32);
+    le32(&mut c, 40, SECTOR_SIZE as u32);
+    le16(&mut c, 44, 1);
+    le16(&mut c, 46, 1);
+    le64(&mut c, 48, 1);
+    le64(&mut c, 56, chunk.start);
+    c[64..80].copy_from_slice(&ids.dev_uuid);
+    c
+}
+
+/// All trees of the image, placed in the system and metadata chunks.
+struct Trees {
+    trees: Vec<Placed>,
+    chunks: [(Range<u64>, u64); 3],
+    bytes_used: u64,
+}
+
+impl Trees {
+    /// Place the trees behind the data of `layout`, holding the items of the FS trees and the
+    /// checksum tree. The device spans `total_bytes`, which only ends up in the items.
+    fn new(
+        tree: &Tree,
+        layout: &Layout,
+        fs: Vec<Items>,
+        csum: Items,
+        total_bytes: u64,
+        ids: &Ids,
+        time: i64,
+    ) -> Self {
+        let mut trees = fs
+            .into_iter()
+            .enumerate()
+            .map(|(i, items)| (tree_id(i), items))
+            .chain([(CSUM_TREE, csum)])
+            .map(|(owner, mut items)| {
+                items.sort_by_key(|i| i.0);
+                let shape = Shape::of(&items);
+                (owner, items, shape)
+            })
+            .collect::<Vec<_>>();
+        let data_extents = layout.extents.iter().flatten().count();
+
+        // Root tree, whose root items are filled in once the trees are placed
+        let owners = [EXTENT_TREE, DEV_TREE, CSUM_TREE]
+            .into_iter()
+            .chain((0..layout.roots.len()).map(tree_id));
+        let mut root_items = owners
+            .map(|owner| (Key::new(owner, ROOT_ITEM, 0), vec![0; 439]))
+            .collect::<Items>();
+        root_items.push((
+            Key::new(ROOT_TREE_DIR, INODE_ITEM, 0),
+            inode_item(0, NODE_SIZE, S_IFDIR | 0o755, 0, 0, time),
+        ));
+        root_items.push((
+            Key::new(ROOT_TREE_DIR, INODE_REF, ROOT_TREE_DIR),
+            inode_ref(0, b".."),
+        ));
+        root_items.push((
+            Key::new(ROOT_TREE_DIR, DIR_ITEM, name_hash(b"default")),
+            dir_item(
+                Key::new(tree_id(layout.default), ROOT_ITEM, u64::MAX),
+                FT_DIR,
+                b"default",
+            ),
+        ));
+        // Every subvolume refers to its parent, and the parent to it
+        for (i, &root) in layout.roots.iter().enumerate().skip(1) {
+            let node = &tree.nodes[root];
+            let parent = tree_id(layout.owners[node.parent]);
+            let mut r = vec![0u8; 18];
+            le64(&mut r, 0, layout.inos[node.parent]);
+            le64(&mut r, 8, dir_index(tree, root));
+            le16(&mut r, 16, node.name.len() as u16);
+            r.extend_from_slice(node.name.as_bytes());
+            root_items.push((Key::new(parent, ROOT_REF, tree_id(i)), r.clone()));
+            root_items.push((Key::new(tree_id(i), ROOT_BACKREF, parent), r));
+        }
+        root_items.sort_by_key(|i| i.0);
+        let root_shape = Shape::of(&root_items);
+
+        // The device and chunk trees only hold the three chunks, so their sizes are known before
+        // their contents
+        let dev_shape = Shape::new([48; 3].into_iter(), usize::MAX);
+        let chunk_shape = Shape::new([98, 80, 80, 80].into_iter(), usize::MAX);
+
+        // The extent tree holds an item for every tree block, including its own
+        let per_leaf = LEAF_DATA / (ITEM_SIZE + MAX_EXTENT_ITEM);
+        let other_blocks = trees.iter().map(|t| t.2.blocks()).sum::<usize>()
+            + root_shape.blocks()
+            + dev_shape.blocks()
+            + chunk_shape.blocks();
+        let mut extent_blocks = 0;
+        let extent_shape = loop {
+            let count = data_extents + other_blocks + extent_blocks + 3;
+            let shape = Shape::new(std::iter::repeat_n(MAX_EXTENT_ITEM, count), per_leaf);
+            if shape.blocks() == extent_blocks {
+                break shape;
+            }
+            extent_blocks = shape.blocks();
+        };
+
+        // Addresses of the blocks, the chunk tree in the system chunk and the rest in the
+        // metadata chunk
+        let system = RESERVED..RESERVED + SYSTEM_CHUNK;
+        let chunk_addrs = (0..chunk_shape.blocks())
+            .map(|i| system.start + i as u64 * NODE_SIZE)
+            .collect::<Vec<_>>();
+
+        let meta_start = layout.data_chunk.end;
+        let mut pos = meta_start;
+        let mut alloc = |shape: &Shape| {
+            (0..shape.blocks())
+                .map(|_| {
+                    pos = skip_mirrors(pos, NODE_SIZE);
+                    pos += NODE_SIZE;
+                    pos - NODE_SIZE
+                })
+                .collect::<Vec<_>>()
+        };
+        let root_addrs = alloc(&root_shape);
+        let extent_addrs = alloc(&extent_shape);
+        let dev_addrs = alloc(&dev_shape);
+        let addrs = trees.iter().map(|t| alloc(&t.2)).collect::<Vec<_>>();
+        let chunk_blocks = chunk_shape.blocks();
+        let metadata = meta_start..(pos + METADATA_SLACK).next_multiple_of(CHUNK_ALIGN);
+
+        let chunks = [
+            (system, BLOCK_GROUP_SYSTEM),
+            (layout.data_chunk.clone(), BLOCK_GROUP_DATA),
+            (metadata, BLOCK_GROUP_METADATA),
+        ];
+
+        let mut placed = trees
+            .drain(..)
+            .zip(addrs)
+            .map(|((owner, items, shape), addrs)| Placed {
+                owner,
+                items,
+                shape,
+                addrs,
+            })
+            .collect::<Vec<_>>();
+
+        // Device tree, with an extent for every chunk
+        let dev_items = chunks
+            .iter()
+            .map(|(chunk, _)| {
+                let mut e = vec![0u8; 48];
+                le64(&mut e, 0, CHUNK_TREE);
+                le64(&mut e, 8, FIRST_CHUNK_TREE);
+                le64(&mut e, 16, chunk.start);
+                le64(&mut e, 24, chunk.end - chunk.start);
+                e[32..48].copy_from_slice(&ids.chunk_tree_uuid);
+                (Key::new(1, DEV_EXTENT, chunk.start), e)
+            })
+            .collect::<Items>();
+        placed.push(Placed {
+            owner: DEV_TREE,
+            items: dev_items,
+            shape: dev_shape,
+            addrs: dev_addrs,
+        });
+
+        // Chunk tree
+        let chunk_bytes = chunks.iter().map(|(c, _)| c.end - c.start).sum();
+        let mut chunk_items = vec![(
+            Key::new(DEV_ITEMS, DEV_ITEM, 1),
+            dev_item(total_bytes, chunk_bytes, ids),
+        )];
+        for (chunk, flags) in &chunks {
+            chunk_items.push((
+                Key::new(FIRST_CHUNK_TREE, CHUNK_ITEM, chunk.start),
+                chunk_item(chunk, *flags, ids),
+            ));
+        }
+        chunk_items.sort_by_key(|i| i.0);
+        placed.push(Placed {
+            owner: CHUNK_TREE,
+            items: chunk_items,
+            shape: chunk_shape,
+            addrs: chunk_addrs,
+        });
+
+        // Extent tree, with the block groups and the references to every extent
+        let mut extent_items = vec![];
+        let data_used = layout.extents.iter().flatten().map(|e| e.len).sum::<u64>();
+        let system_used = chunk_blocks as u64 * NODE_SIZE;
+        let meta_used = (other_blocks + extent_blocks - chunk_blocks) as u64 * NODE_SIZE;
+        for ((chunk, flags), used) in chunks.iter().zip([system_used, data_used, meta_used]) {
+            let mut g = vec![0u8; 24];
+            le64(&mut g, 0, used);
+            le64(&mut g, 8, FIRST_CHUNK_TREE);
+            le64(&mut g, 16, *flags);
+            extent_items.push((
+                Key::new(chunk.start, BLOCK_GROUP_ITEM, chunk.end - chunk.start),
+                g,
+            ));
+        }
+
+        let own_refs = [(ROOT_TREE, &root_shape, &root_addrs)].into_iter().chain([(
+            EXTENT_TREE,
+            &extent_shape,
+            &extent_addrs,
+        )]);
+        let tree_refs = placed
+            .iter()
+            .map(|t| (t.owner, &t.shape, &t.addrs))
+            .chain(own_refs)
+            .flat_map(|(owner, shape, addrs)| {
+                addrs
+                    .iter()
+                    .enumerate()
+                    .map(move |(i, &addr)| (addr, shape.block_level(i), owner))
+            });
+        for (addr, level, owner) in tree_refs {
+            let mut e = vec![0u8; 33];
+            le64(&mut e, 0, 1);
+            le64(&mut e, 8, GENERATION);
+            le64(&mut e, 16, EXTENT_FLAG_TREE_BLOCK);
+            e[24] = TREE_BLOCK_REF;
+            le64(&mut e, 25, owner);
+            extent_items.push((Key::new(addr, METADATA_ITEM, level as u64), e));
+        }
+
+        for (idx, extents) in layout.extents.iter().enumerate() {
+            for extent in extents {
+                let mut e = vec![0u8; 53];
+                le64(&mut e, 0, 1);
+                le64(&mut e, 8, GENERATION);
+                le64(&mut e, 16, EXTENT_FLAG_DATA);
+                e[24] = EXTENT_DATA_REF;
+                le64(&mut e, 25, tree_id(layout.owners[idx]));
+                le64(&mut e, 33, layout.inos[idx]);
+                le64(&mut e, 41, extent.offset);
+                le32(&mut e, 49, 1);
+                extent_items.push((Key::new(extent.disk, EXTENT_ITEM, extent.len), e));
+            }
+        }
+        extent_items.sort_by_key(|i| i.0);
+        debug_assert_eq!(
+            Shape::new(extent_items.iter().map(|i| i.1.len()), per_leaf).blocks(),
+            extent_shape.blocks()
+        );
+        placed.push(Placed {
+            owner: EXTENT_TREE,
+            items: extent_items,
+            shape: extent_shape,
+            addrs: extent_addrs,
+        });
+
+        // Root items of all trees placed so far
+        for (key, data) in &mut root_items {
+            if key.kind != ROOT_ITEM {
+                continue;
+            }
+            let tree = placed.iter().find(|t| t.owner == key.objectid).unwrap();
+            let (dirid, uuid) = match (0..layout.roots.len()).find(|&i| tree_id(i) == tree.owner) {
+                Some(i) => (FIRST_FREE, ids.subvolumes[i]),
+                None => (0, [0; 16]),
+            };
+            *data = root_item(tree, dirid, &uuid, time);
+        }
+        placed.push(Placed {
+            owner: ROOT_TREE,
+            items: root_items,
+            shape: root_shape,
+            addrs: root_addrs,
+        });
+
+        Self {
+            trees: placed,
+            chunks,
+            bytes_used: data_used + meta_used + system_used,
+        }
+    }
+
+    fn tree(&self, owner: u64) -> &Placed {
+        self.trees.iter().find(|t| t.owner == owner).unwrap()
+    }
+
+    /// End of the last chunk.
+    fn end(&self) -> u64 {
+        self.chunks[2].0.end
+    }
+}
+
+/// Smallest filesystem size the tree fits in.
+pub fn estimate_size(tree: &Tree, opts: &Options) -> anyhow::Result<u64> {
+    let layout = Layout::new(tree, opts)?;
+    let fs = fs_items(tree, &layout, &mut |idx| match tree.nodes[idx].kind {
+        Kind::File { len, .. } => Ok(vec![0; len as usize]),
+        _ => unreachable!(),
+    })?;
+    let sums = layout
+        .extents
+        .iter()
+        .map(|e| vec![0; (e.iter().map(|e| e.len).sum::<u64>() / SECTOR_SIZE) as usize])
+        .collect::<Vec<_>>();
+    let ids = Ids {
+        fsid: [0; 16],
+        chunk_tree_uuid: [0; 16],
+        dev_uuid: [0; 16],
+        subvolumes: vec![[0; 16]; layout.roots.len()],
+    };
+    let trees = Trees::new(tree, &layout, fs, csum_items(&layout, &sums), 0, &ids, 0);
+    Ok(trees.end())
+}
+
+fn write_at<W: Write + Seek>(out: &mut W, offset: u64, data: &[u8]) -> io::Result<()> {
+    out.seek(SeekFrom::Start(offset))?;
+    out.write_all(data)
+}
+
+/// Write the image into `out`, which spans `size` bytes.
+///
+/// `on_file` is called with the image path and length of every file written.
+pub fn write<W: Write + Seek>(
+    out: &mut W,
+    size: u64,
+    tree: &Tree,
+    opts: &Options,
+    ctx: &crate::BuildContext,
+    on_file: &mut dyn FnMut(&Path, u64),
+) -> anyhow::Result<()> {
+    let label = opts.label.as_deref().unwrap_or_default();
+    if label.len() > 255 {
+        anyhow::bail!("btrfs volume label is longer than 255 bytes");
+    }
+
+    let layout = Layout::new(tree, opts)?;
+    let total_bytes = size / SECTOR_SIZE * SECTOR_SIZE;
+    if layout.data_chunk.end > total_bytes {
+        anyhow::bail!(
+            "btrfs image needs more than {} bytes, but only {size} are available",
+            layout.data_chunk.end
+        );
+    }
+
+    let mut ids = Ids {
+        fsid: [0; 16],
+        chunk_tree_uuid: [0; 16],
+        dev_uuid: [0; 16],
+        subvolumes: vec![[0; 16]; layout.roots.len()],
+    };
+    ctx.random.fill(&mut ids.fsid)?;
+    ctx.random.fill(&mut ids.chunk_tree_uuid)?;
+    ctx.random.fill(&mut ids.dev_uuid)?;
+    for uuid in &mut ids.subvolumes[1..] {
+        ctx.random.fill(uuid)?;
+    }
+    let time = ctx.time.timestamp();
+
+    // File data, with a checksum of every sector
+    let mut sums = vec![vec![]; tree.nodes.len()];
+    let mut buf = vec![0u8; CHUNK_ALIGN as usize];
+    for (idx, node) in tree.nodes.iter().enumerate() {
+        let Kind::File { source, len } = &node.kind else {
+            continue;
+        };
+        if layout.extents[idx].is_empty() {
+            on_file(&node.path, *len);
+            continue;
+        }
+
+        let mut file = source.open()?;
+        let mut left = *len;
+        for e in &layout.extents[idx] {
+            out.seek(SeekFrom::Start(e.disk))?;
+            for offset in (0..e.len).step_by(buf.len()) {
+                let chunk = &mut buf[..(e.len - offset).min(CHUNK_ALIGN) as usize];
+                let n = left.min(chunk.len() as u64) as usize;
+                file.read_exact(&mut chunk[..n]).map_err(|e| {
+                    anyhow::anyhow!("{} changed while being copied: {e}", node.path.display())
+                })?;
+                chunk[n..].fill(0);
+                left -= n as u64;
+
+                out.write_all(chunk)?;
+                sums[idx].extend(chunk.chunks(SECTOR_SIZE as usize).map(crc32c));
+            }
+        }
+        on_file(&node.path, *len);
+    }
+
+    let fs = fs_items(tree, &layout, &mut |idx| {
+        let node = &tree.nodes[idx];
+        let Kind::File { source, len } = &node.kind else {
+            unreachable!()
+        };
+        let mut data = vec![0; *len as usize];
+        source.open()?.read_exact(&mut data).map_err(|e| {
+            anyhow::anyhow!("{} changed while being copied: {e}", node.path.display())
+        })?;
+        Ok(data)
+    })?;
+    let trees = Trees::new(
+        tree,
+        &layout,
+        fs,
+        csum_items(&layout, &sums),
+        total_bytes,
+        &ids,
+        time,
+    );
+
+    if trees.end() > total_bytes {
+        anyhow::bail!(
+            "btrfs image needs {} bytes, but only {size} are available",
+            trees.end()
+        );
+    }
+
+    for tree in &trees.trees {
+        for (addr, block) in tree.encode(&ids) {
+            write_at(out, addr, &block)?;
+        }
+    }
+
+    // Superblock, with the system chunk that leads to the chunk tree
+    let root = trees.tree(ROOT_TREE);
+    let chunk = trees.tree(CHUNK_TREE);
+    let (system, system_flags) = &trees.chunks[0];
+    let chunk_bytes = trees.chunks.iter().map(|(c, _)| c.end - c.start).sum();
+
+    let mut sb = vec![0u8; SUPER_SIZE];
+    sb[0x20..0x30].copy_from_slice(&ids.fsid);
+    sb[0x40..0x48].copy_from_slice(MAGIC);
+    le64(&mut sb, 0x48, GENERATION);
+    le64(&mut sb, 0x50, root.root());
+    le64(&mut sb, 0x58, chunk.root());
+    le64(&mut sb, 0x70, total_bytes);
+    le64(&mut sb, 0x78, trees.bytes_used);
+    le64(&mut sb, 0x80, ROOT_TREE_DIR);
+    le64(&mut sb, 0x88, 1);
+    le32(&mut sb, 0x90, SECTOR_SIZE as u32);
+    le32(&mut sb, 0x94, NODE_SIZE as u32);
+    le32(&mut sb, 0x98, NODE_SIZE as u32);
+    le32(&mut sb, 0x9c, SECTOR_SIZE as u32);
+    le32(&mut sb, 0xa0, 17 + 80);
+    le64(&mut sb, 0xa4, GENERATION);
+    let default_subvol = match layout.default {
+        0 => 0,
+        _ => INCOMPAT_DEFAULT_SUBVOL,
+    };
+    le64(&mut sb, 0xbc, INCOMPAT_FLAGS | default_subvol);
+    sb[0xc6] = root.shape.level();
+    sb[0xc7] = chunk.shape.level();
+    sb[0xc9..0x12b].copy_from_slice(&dev_item(total_bytes, chunk_bytes, &ids));
+    sb[0x12b..0x12b + label.len()].copy_from_slice(label.as_bytes());
+    // No space cache
+    le64(&mut sb, 0x22b, u64::MAX);
+    let system_key = Key::new(FIRST_CHUNK_TREE, CHUNK_ITEM, system.start);
+    sb[0x32b..0x33c].copy_from_slice(&system_key.to_bytes());
+    sb[0x33c..0x38c].copy_from_slice(&chunk_item(system, *system_flags, &ids));
+
+    // Backup of the tree roots in the first slot
+    let backup = &mut sb[0xb2b..0xb2b + 168];
+    for (i, owner) in [
+        ROOT_TREE,
+        CHUNK_TREE,
+        EXTENT_TREE,
+        FS_TREE,
+        DEV_TREE,
+        CSUM_TREE,
+    ]
+    .into_iter()
+    .enumerate()
+    {
+        let tree = trees.tree(owner);
+        le64(backup, i * 16, tree.root());
+        le64(backup, i * 16 + 8, GENERATION);
+        backup[152 + i] = tree.shape.level();
+    }
+    le64(backup, 96, total_bytes);
+    le64(backup, 104, trees.bytes_used);
+    le64(backup, 112, 1);
+
+    for offset in std::iter::once(SUPER_OFFSET).chain(SUPER_MIRRORS) {
+        if offset + SUPER_SIZE as u64 > total_bytes {
+            break;
+        }
+        le64(&mut sb, 0x30, offset);
+        set_csum(&mut sb);
+        write_at(out, offset, &sb)?;
+    }
+
+    Ok(())
+}
+
+impl crate::FsWriter for Options {
+    fn estimate_size(&self, tree: &Tree) -> anyhow::Result<u64> {
+        estimate_size(tree, self)
+    }
+
+    fn write(
+        &self,
+        mut out: &mut dyn fatfs::ReadWriteSeek,
+        size: u64,
+        tree: &Tree,
+        ctx: &crate::BuildContext,
+        on_file: &mut dyn FnMut(&Path, u64),
+    ) -> anyhow::Result<()> {
+        write(&mut out, size, tree, self, ctx, on_file)
+    }
+}
@@ -0,0 +1,50 @@
+//! Generated file describing how an image was built.
+
+use crate::tree::{Kind, Tree};
+use crate::{json, sha256};
+use chrono::{DateTime, Utc};
+use std::io::Read;
+
+/// Hash the input tree in an order independent of the host's directory listing.
+pub fn hash_tree(tree: &Tree) -> anyhow::Result<String> {
+    let mut entries = vec![];
+
+    for node in &tree.nodes[1..] {
+        let path = node.path.display();
+        entries.push(match &node.kind {
+            Kind::Dir(_) => format!("D {path}\n"),
+            Kind::File { source, len } => {
+                let digest = sha256::Sha256::new()
+                    .read_from(source.open()?.take(*len))?
+                    .finish();
+                format!("F {path} {}\n", sha256::hex(&digest))
+            }
+            Kind::Symlink(target) => format!("L {path} {target}\n"),
+        });
+    }
+
+    entries.sort();
+
+    let mut hasher = sha256::Sha256::new();
+    entries.iter().for_each(|e| hasher.update(e.as_bytes()));
+
+    Ok(sha256::hex(&hasher.finish()))
+}
+
+/// Generate the contents of the build information file, in `KEY=VALUE` form.
+pub fn generate(
+    config: &json::Value,
+    tree: &Tree,
+    time: &DateTime<Utc>,
+) -> anyhow::Result<Vec<u8>> {
+    let config_hash = sha256::hex(&sha256::digest(config.to_string().as_bytes()));
+    let input_hash = hash_tree(tree)?;
+
+    Ok(format!(
+        "MKIMG_VERSION={}\nBUILD_TIME={}\nBUILD_EPOCH={}\nCONFIG_SHA256={config_hash}\nINPUT_SHA256={input_hash}\n",
+        env!("CARGO_PKG_VERSION"),
+        time.format("%Y-%m-%dT%H:%M:%SZ"),
+        time.timestamp(),
+    )
+    .into_bytes())
+}
@@ -0,0 +1,315 @@
+//! Fluent construction of images, for build scripts that would otherwise shell out to `mkimg`.
+//!
+//! ```no_run
+//! use mkimg::builder::{DiskImageBuilder, Partition};
+//! use mkimg::Filesystem;
+//!
+//! # fn main() -> anyhow::Result<()> {
+//! DiskImageBuilder::new()
+//!     .gpt()
+//!     .partition(Partition::esp().size_auto().populate_from("boot/"))
+//!     .partition(Partition::root().filesystem(Filesystem::Ext3).populate_from("rootfs/"))
+//!     .build("disk.img")?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Options without a method of their own are passed as on the command line, with
+//! [`DiskImageBuilder::arg`].
+
+use crate::size::PartitionSize;
+use crate::{layout, Args, BuildSummary, Filesystem, ImageBuilder, PartitionTable};
+use clap::{value_parser, Arg, CommandFactory, FromArgMatches};
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+
+/// A partition of a [`DiskImageBuilder`] image.
+#[derive(Clone, Debug)]
+pub struct Partition {
+    part: layout::Partition,
+    /// Parsed when the image is built, so that errors surface there
+    gpt_type: Option<String>,
+    /// Looked up among the subvolumes when the image is built
+    default_subvolume: Option<PathBuf>,
+}
+
+impl Partition {
+    /// Unformatted partition named `name`, of the GPT type with that alias or `generic`.
+    pub fn new(name: &str) -> Self {
+        Self {
+            part: layout::Partition {
+                name: name.into(),
+                filesystem: None,
+                size: None,
+                gpt_type: name.parse().unwrap_or_else(|_| "generic".parse().unwrap()),
+                uuid: None,
+                mbr_type: None,
+                primary: None,
+                bootable: false,
+                attributes: 0,
+                input_dir: None,
+                image: None,
+                label: None,
+                files: vec![],
+                subvolumes: vec![],
+            },
+            gpt_type: None,
+            default_subvolume: None,
+        }
+    }
+
+    /// EFI system partition, formatted as FAT.
+    pub fn esp() -> Self {
+        Self::new("esp").filesystem(Filesystem::Vfat)
+    }
+
+    /// Extended boot loader partition, formatted as FAT. `--preset systemd-boot` puts the kernel
+    /// into it instead of the ESP.
+    pub fn xbootldr() -> Self {
+        Self::new("xbootldr").filesystem(Filesystem::Vfat)
+    }
+
+    /// Root partition of the target architecture, formatted as ext2.
+    pub fn root() -> Self {
+        Self::new("root").filesystem(Filesystem::Ext2)
+    }
+
+    pub fn filesystem(mut self, filesystem: Filesystem) -> Self {
+        self.part.filesystem = Some(filesystem);
+        self
+    }
+
+    /// Leave the partition unformatted.
+    pub fn unformatted(mut self) -> Self {
+        self.part.filesystem = None;
+        self.part.input_dir = None;
+        self.part.image = None;
+        self
+    }
+
+    /// Directory to copy into the filesystem of the partition.
+    pub fn populate_from(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.part.input_dir = Some(dir.into());
+        self
+    }
+
+    /// Copy an existing image into the partition instead of formatting it. The partition takes
+    /// its size unless one is set.
+    pub fn content_image(mut self, image: impl Into<PathBuf>) -> Self {
+        self.part.filesystem = None;
+        self.part.input_dir = None;
+        self.part.image = Some(image.into());
+        self
+    }
+
+    pub fn size(mut self, bytes: u64) -> Self {
+        self.part.size = Some(PartitionSize::Bytes(bytes));
+        self
+    }
+
+    /// Percentage of the image size, which must be set.
+    pub fn size_percent(mut self, percent: u64) -> Self {
+        self.part.size = Some(PartitionSize::Percent(percent));
+        self
+    }
+
+    /// Everything left in the image, for the last partition.
+    pub fn size_rest(mut self) -> Self {
+        self.part.size = Some(PartitionSize::Rest);
+        self
+    }
+
+    /// Estimate the size from the contents, which is the default.
+    pub fn size_auto(mut self) -> Self {
+        self.part.size = None;
+        self
+    }
+
+    /// GPT type, as a GUID or an alias such as `esp` or `root-x86-64`.
+    pub fn gpt_type(mut self, ty: &str) -> Self {
+        self.gpt_type = Some(ty.into());
+        self
+    }
+
+    pub fn mbr_type(mut self, ty: u8) -> Self {
+        self.part.mbr_type = Some(ty);
+        self
+    }
+
+    pub fn uuid(mut self, uuid: &str) -> Self {
+        self.part.uuid = Some(uuid.into());
+        self
+    }
+
+    /// Volume label of the filesystem.
+    pub fn label(mut self, label: &str) -> Self {
+        self.part.label = Some(label.into());
+        self
+    }
+
+    pub fn bootable(mut self) -> Self {
+        self.part.bootable = true;
+        self
+    }
+
+    /// GPT attribute bits, in addition to the bootable one.
+    pub fn attributes(mut self, attributes: u64) -> Self {
+        self.part.attributes = attributes;
+        self
+    }
+
+    /// Make a btrfs subvolume at `path`, filled from `dir`.
+    pub fn subvolume(mut self, path: &str, dir: impl Into<PathBuf>) -> Self {
+        self.part.subvolumes.push(layout::Subvolume {
+            path: path.trim_start_matches('/').into(),
+            input_dir: Some(dir.into()),
+            default: false,
+        });
+        self
+    }
+
+    /// Make an empty btrfs subvolume at `path`.
+    pub fn empty_subvolume(mut self, path: &str) -> Self {
+        self.part.subvolumes.push(layout::Subvolume {
+            path: path.trim_start_matches('/').into(),
+            input_dir: None,
+            default: false,
+        });
+        self
+    }
+
+    /// Mount the subvolume at `path` instead of the top level when no subvolume is asked for.
+    pub fn default_subvolume(mut self, path: &str) -> Self {
+        self.default_subvolume = Some(path.trim_start_matches('/').into());
+        self
+    }
+
+    fn resolve(&self) -> anyhow::Result<layout::Partition> {
+        let mut part = self.part.clone();
+        if let Some(ty) = &self.gpt_type {
+            part.gpt_type = ty
+                .parse()
+                .map_err(|e| anyhow::anyhow!("partition `{}`: {e}", part.name))?;
+        }
+        if let Some(path) = &self.default_subvolume {
+            let sub = part.subvolumes.iter_mut().find(|s| &s.path == path);
+            let sub = sub.ok_or_else(|| {
+                anyhow::anyhow!(
+                    "partition `{}` has no subvolume `{}`",
+                    part.name,
+                    path.display()
+                )
+            })?;
+            sub.default = true;
+        }
+        Ok(part)
+    }
+}
+
+/// Image with a partition table, or a single partition without one.
+#[derive(Clone, Debug, Default)]
+pub struct DiskImageBuilder {
+    partition_table: Option<PartitionTable>,
+    image_size: Option<u64>,
+    sector_size: Option<u64>,
+    partitions: Vec<Partition>,
+    /// Options without a method of their own, as on the command line
+    args: Vec<OsString>,
+}
+
+impl DiskImageBuilder {
+    /// Image without a partition table, until one is chosen.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn gpt(mut self) -> Self {
+        self.partition_table = Some(PartitionTable::Gpt);
+        self
+    }
+
+    pub fn mbr(mut self) -> Self {
+        self.partition_table = Some(PartitionTable::Mbr);
+        self
+    }
+
+    /// GPT with the first partitions mirrored into the MBR.
+    pub fn hybrid(mut self) -> Self {
+        self.partition_table = Some(PartitionTable::Hybrid);
+        self
+    }
+
+    pub fn image_size(mut self, bytes: u64) -> Self {
+        self.image_size = Some(bytes);
+        self
+    }
+
+    /// Logical sector size of the target device, 512 or 4096 bytes.
+    pub fn sector_size(mut self, bytes: u64) -> Self {
+        self.sector_size = Some(bytes);
+        self
+    }
+
+    /// Add a partition after the ones added before.
+    pub fn partition(mut self, partition: Partition) -> Self {
+        self.partitions.push(partition);
+        self
+    }
+
+    /// Pass a command line argument, for options without a method of their own.
+    pub fn arg(mut self, arg: impl Into<OsString>) -> Self {
+        self.args.push(arg.into());
+        self
+    }
+
+    /// Options equivalent to the image, written to `output`. Those set with methods take
+    /// precedence over the same ones passed with [`DiskImageBuilder::arg`].
+    pub fn to_args(&self, output: impl AsRef<Path>) -> anyhow::Result<Args> {
+        let mut argv: Vec<OsString> = vec!["mkimg".into()];
+        argv.extend(self.args.iter().cloned());
+        argv.push("--output-path".into());
+        argv.push(output.as_ref().into());
+
+        let mut cmd = Args::command();
+        if !self.partitions.is_empty() {
+            // The partitions take the place of the input directory the command line requires
+            cmd = cmd.mut_arg("input_dir", |_| {
+                Arg::new("input_dir")
+                    .short('i')
+                    .long("input-dir")
+                    .value_parser(value_parser!(PathBuf))
+            });
+        }
+        let mut args = Args::from_arg_matches(&cmd.try_get_matches_from(argv)?)?;
+
+        if let Some(table) = self.partition_table {
+            args.partition_table = table;
+        }
+        if let Some(bytes) = self.image_size {
+            args.image_size = Some(bytes);
+        }
+        if let Some(bytes) = self.sector_size {
+            if !matches!(bytes, 512 | 4096) {
+                anyhow::bail!("sector size must be 512 or 4096 bytes, not {bytes}");
+            }
+            args.sector_size = bytes;
+        }
+        if !self.partitions.is_empty() {
+            if args.layout.is_some() || args.from_wks.is_some() {
+                anyhow::bail!("partitions can not be combined with --layout or --from-wks");
+            }
+            args.partition = self
+                .partitions
+                .iter()
+                .map(Partition::resolve)
+                .collect::<anyhow::Result<_>>()?;
+        }
+
+        Ok(args)
+    }
+
+    /// Build the image at `output`.
+    pub fn build(&self, output: impl AsRef<Path>) -> anyhow::Result<BuildSummary> {
+        ImageBuilder::new(self.to_args(output)?).build()
+    }
+}
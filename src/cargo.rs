@@ -0,0 +1,65 @@
+//! Integration with cargo, for projects building images as part of `cargo build`.
+//!
+//! A build script can turn the directory it staged into a bootable image, and is rerun when
+//! anything the image is built from changes:
+//!
+//! ```no_run
+//! // build.rs
+//! # fn main() -> anyhow::Result<()> {
+//! let out = std::path::PathBuf::from(std::env::var_os("OUT_DIR").unwrap());
+//! mkimg::cargo::esp_image("target/esp-staging", out.join("esp.img"))?;
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::builder::{DiskImageBuilder, Partition};
+use crate::{BuildSummary, ImageBuilder};
+use std::ffi::OsString;
+use std::path::Path;
+
+/// Command line arguments, with the extra subcommand name cargo passes when mkimg is invoked
+/// as `cargo mkimg` (through a `cargo-mkimg` link on the `PATH`) removed.
+pub fn args() -> Vec<OsString> {
+    let mut args = std::env::args_os().collect::<Vec<_>>();
+
+    let is_cargo_subcommand = args
+        .first()
+        .and_then(|a| Path::new(a).file_stem())
+        .is_some_and(|s| s == "cargo-mkimg");
+
+    if is_cargo_subcommand && args.get(1).is_some_and(|a| a == "mkimg") {
+        args.remove(1);
+    }
+
+    args
+}
+
+/// Print `rerun-if-changed` directives for every host path the image is built from, so a
+/// build script invoking mkimg and forwarding its output is only rerun when they change.
+///
+/// Cargo scans directories recursively, so the input directory is printed as a whole.
+pub fn print_rerun_if_changed<'a>(paths: impl IntoIterator<Item = &'a Path>) {
+    for path in paths {
+        println!("cargo:rerun-if-changed={}", path.display());
+    }
+}
+
+/// Build `image` at `output` from a build script, printing `rerun-if-changed` directives for its
+/// inputs.
+pub fn build(image: &DiskImageBuilder, output: impl AsRef<Path>) -> anyhow::Result<BuildSummary> {
+    let mut args = image.to_args(output)?;
+    args.cargo_rerun_if_changed = true;
+    ImageBuilder::new(args).build()
+}
+
+/// Build a GPT image at `output` with a single ESP holding the files of `staging`, as
+/// [`build`] does.
+pub fn esp_image(
+    staging: impl AsRef<Path>,
+    output: impl AsRef<Path>,
+) -> anyhow::Result<BuildSummary> {
+    let image = DiskImageBuilder::new()
+        .gpt()
+        .partition(Partition::esp().populate_from(staging.as_ref()));
+    build(&image, output)
+}
@@ -0,0 +1,106 @@
+//! Digests stored inside the image for boot ROMs and updaters to verify.
+
+use crate::{sha256, size};
+use crc::crc32;
+use std::fs::OpenOptions;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::str::FromStr;
+
+#[derive(Clone, Copy, Debug)]
+pub enum Algorithm {
+    /// CRC-32 (IEEE), stored little endian
+    Crc32,
+    Sha256,
+}
+
+impl Algorithm {
+    fn len(&self) -> u64 {
+        match self {
+            Self::Crc32 => 4,
+            Self::Sha256 => 32,
+        }
+    }
+}
+
+/// Digest of the image, appended to its end or written at a fixed offset.
+#[derive(Clone, Copy, Debug)]
+pub struct Checksum {
+    pub algorithm: Algorithm,
+    pub offset: Option<u64>,
+}
+
+impl FromStr for Checksum {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        let (algo, offset) = match s.split_once(',') {
+            Some((algo, offset)) => (algo, Some(size::parse_bytes(offset)?)),
+            None => (s, None),
+        };
+
+        let algorithm = match algo {
+            "crc32" => Algorithm::Crc32,
+            "sha256" => Algorithm::Sha256,
+            _ => return Err(format!("unknown checksum algorithm `{algo}`")),
+        };
+
+        Ok(Self { algorithm, offset })
+    }
+}
+
+impl Checksum {
+    /// Compute the digest of the image and store it.
+    ///
+    /// When written at an offset, the digest covers the image with the digest field zeroed.
+    pub fn apply(&self, path: &Path) -> anyhow::Result<()> {
+        let mut file = OpenOptions::new().read(true).write(true).open(path)?;
+        let image_len = file.metadata()?.len();
+        let digest_len = self.algorithm.len();
+
+        let offset = match self.offset {
+            Some(offset)
+                if offset
+                    .checked_add(digest_len)
+                    .is_none_or(|end| end > image_len) =>
+            {
+                anyhow::bail!("checksum at {offset:#x} does not fit in {image_len:#x} byte image")
+            }
+            Some(offset) => {
+                file.seek(SeekFrom::Start(offset))?;
+                file.write_all(&vec![0; digest_len as usize])?;
+                offset
+            }
+            None => image_len,
+        };
+
+        file.seek(SeekFrom::Start(0))?;
+
+        let mut crc = 0;
+        let mut sha = sha256::Sha256::new();
+        let mut buf = vec![0; 0x10000];
+
+        loop {
+            let n = file.read(&mut buf)?;
+            if n == 0 {
+                break;
+            }
+            match self.algorithm {
+                Algorithm::Crc32 => crc = crc32::update(crc, &crc32::IEEE_TABLE, &buf[..n]),
+                Algorithm::Sha256 => sha.update(&buf[..n]),
+            }
+        }
+
+        let digest = match self.algorithm {
+            Algorithm::Crc32 => crc.to_le_bytes().to_vec(),
+            Algorithm::Sha256 => sha.finish().to_vec(),
+        };
+
+        log::info!("Image checksum: {}", sha256::hex(&digest));
+
+        file.seek(SeekFrom::Start(offset))?;
+        file.write_all(&digest)?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,816 @@
+//! Block compressors for compressed read-only filesystems.
+//!
+//! These favour simplicity over ratio: zlib uses the fixed Huffman codes of deflate, and both
+//! formats find matches with a greedy hash chain search. The level, from 1 to 9, doubles the
+//! candidates looked at for every step. Deflate streams are also decompressed, for zip archives
+//! given as input. zstd and xz are not built in, and run the `zstd` and `xz` tools instead.
+
+use crate::output::{self, Compressor};
+use clap::ValueEnum;
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Compression {
+    /// zlib (deflate), readable everywhere
+    Gzip,
+    /// Faster to decompress, lower ratio
+    Lz4,
+    /// zstd, with levels up to 19, run as the `zstd` tool
+    Zstd,
+    /// xz (LZMA2), the smallest and slowest, run as the `xz` tool
+    Xz,
+}
+
+impl Compression {
+    /// Compress a block, returning `None` if that does not make it smaller.
+    pub fn compress(self, data: &[u8], level: u8) -> anyhow::Result<Option<Vec<u8>>> {
+        let out = match self {
+            Self::Gzip => zlib(data, level),
+            Self::Lz4 => lz4(data, level),
+            // The size is given for the frame to record it and keep its window within the
+            // block, which is all the kernel decompresses it with
+            Self::Zstd => {
+                let args = [format!("--stream-size={}", data.len())];
+                self.tool(level).compress(data, &args)?
+            }
+            Self::Xz => {
+                // Blocks are decompressed with a dictionary of at most the block size, and the
+                // kernel only checks CRC32
+                let dict = data.len().next_power_of_two().max(4096);
+                let args = [
+                    "--check=crc32".into(),
+                    format!("--lzma2=preset={level},dict={dict}"),
+                ];
+                output::Compress {
+                    compressor: Compressor::Xz,
+                    level: None,
+                }
+                .compress(data, &args)?
+            }
+        };
+        Ok((out.len() < data.len()).then_some(out))
+    }
+
+    /// Compress what `write` writes into the file `dst`, in the standalone format of the
+    /// compressor. The tools take it as it streams, on one thread so the output does not depend
+    /// on the host, while the built in compressors take it whole.
+    pub fn compress_file<T>(
+        self,
+        dst: &Path,
+        level: u8,
+        write: impl FnOnce(&mut dyn Write) -> anyhow::Result<T>,
+    ) -> anyhow::Result<T> {
+        match self {
+            Self::Zstd => self.tool(level).stream(dst, 1, &[], write),
+            // The kernel unpacks initramfs archives checked with CRC32 only
+            Self::Xz => self
+                .tool(level)
+                .stream(dst, 1, &["--check=crc32".into()], write),
+            Self::Gzip | Self::Lz4 => {
+                let mut data = vec![];
+                let ret = write(&mut data)?;
+                let data = match self {
+                    Self::Gzip => gzip(&data, level),
+                    _ => lz4_legacy(&data, level),
+                };
+                fs::write(dst, data)?;
+                Ok(ret)
+            }
+        }
+    }
+
+    /// Highest level of the compressor.
+    pub fn max_level(self) -> u8 {
+        match self {
+            Self::Zstd => 19,
+            Self::Gzip | Self::Lz4 | Self::Xz => MAX_LEVEL,
+        }
+    }
+
+    /// Check that the tool of the compressor is installed, if it is not built in.
+    pub fn check(self) -> anyhow::Result<()> {
+        let name = self.to_possible_value().unwrap();
+        let name = name.get_name();
+        if matches!(self, Self::Zstd | Self::Xz) && !output::on_path(name) {
+            anyhow::bail!("--compression {name} runs `{name}`, which is not in the PATH");
+        }
+        Ok(())
+    }
+
+    /// Tool compressing at `level`, for the compressors that are not built in.
+    fn tool(self, level: u8) -> output::Compress {
+        let compressor = match self {
+            Self::Zstd => Compressor::Zstd,
+            Self::Xz => Compressor::Xz,
+            Self::Gzip | Self::Lz4 => unreachable!("{self:?} is built in"),
+        };
+        output::Compress {
+            compressor,
+            level: Some(level as u32),
+        }
+    }
+}
+
+const MIN_MATCH: usize = 3;
+const HASH_BITS: u32 = 15;
+/// Level of the formats written without a choice, which looks at 32 candidates for every position
+pub const DEFAULT_LEVEL: u8 = 6;
+pub const MAX_LEVEL: u8 = 9;
+
+/// Finds earlier occurrences of the bytes at a position.
+struct Matcher {
+    head: Vec<u32>,
+    prev: Vec<u32>,
+    window: usize,
+    /// Candidates looked at for every position
+    chain: usize,
+}
+
+impl Matcher {
+    fn new(window: usize, level: u8) -> Self {
+        Self {
+            head: vec![u32::MAX; 1 << HASH_BITS],
+            prev: vec![u32::MAX; window],
+            window,
+            chain: 1 << (level.clamp(1, MAX_LEVEL) - 1),
+        }
+    }
+
+    fn hash(data: &[u8], pos: usize) -> usize {
+        let v = u32::from_le_bytes([data[pos], data[pos + 1], data[pos + 2], 0]);
+        (v.wrapping_mul(0x9E37_79B1) >> (32 - HASH_BITS)) as usize
+    }
+
+    fn insert(&mut self, data: &[u8], pos: usize) {
+        if pos + MIN_MATCH <= data.len() {
+            let h = Self::hash(data, pos);
+            self.prev[pos % self.window] = self.head[h];
+            self.head[h] = pos as u32;
+        }
+    }
+
+    /// Longest match for `pos` as `(distance, length)`, up to `max_len` bytes.
+    fn find(
+        &self,
+        data: &[u8],
+        pos: usize,
+        min_len: usize,
+        max_len: usize,
+    ) -> Option<(usize, usize)> {
+        if pos + MIN_MATCH > data.len() || max_len < min_len {
+            return None;
+        }
+
+        let mut best = None;
+        let mut best_len = min_len - 1;
+        let mut candidate = self.head[Self::hash(data, pos)];
+
+        for _ in 0..self.chain {
+            if candidate == u32::MAX {
+                break;
+            }
+            let c = candidate as usize;
+            if c >= pos || pos - c >= self.window {
+                break;
+            }
+
+            let len = data[c..]
+                .iter()
+                .zip(&data[pos..pos + max_len])
+                .take_while(|(a, b)| a == b)
+                .count();
+
+            if len > best_len {
+                best_len = len;
+                best = Some((pos - c, len));
+                if len == max_len {
+                    break;
+                }
+            }
+
+            candidate = self.prev[c % self.window];
+        }
+
+        best
+    }
+}
+
+struct BitWriter {
+    out: Vec<u8>,
+    acc: u64,
+    bits: u32,
+}
+
+impl BitWriter {
+    fn write(&mut self, value: u32, bits: u32) {
+        self.acc |= (value as u64) << self.bits;
+        self.bits += bits;
+        while self.bits >= 8 {
+            self.out.push(self.acc as u8);
+            self.acc >>= 8;
+            self.bits -= 8;
+        }
+    }
+
+    /// Write a Huffman code, which is packed starting from its most significant bit.
+    fn write_code(&mut self, code: u32, bits: u32) {
+        self.write(code.reverse_bits() >> (32 - bits), bits);
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        if self.bits > 0 {
+            self.out.push(self.acc as u8);
+        }
+        self.out
+    }
+}
+
+const LEN_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131,
+    163, 195, 227, 258,
+];
+const LEN_EXTRA: [u8; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0,
+];
+const DIST_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537,
+    2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577,
+];
+const DIST_EXTRA: [u8; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13,
+    13,
+];
+
+/// Emit a literal or length symbol with the fixed Huffman code.
+fn fixed_symbol(w: &mut BitWriter, sym: u32) {
+    match sym {
+        0..=143 => w.write_code(0x30 + sym, 8),
+        144..=255 => w.write_code(0x190 + sym - 144, 9),
+        256..=279 => w.write_code(sym - 256, 7),
+        _ => w.write_code(0xc0 + sym - 280, 8),
+    }
+}
+
+/// Raw deflate stream of a single block with fixed Huffman codes.
+pub fn deflate(data: &[u8], level: u8) -> Vec<u8> {
+    deflate_window(data, level, 32 << 10)
+}
+
+/// Raw deflate stream referring back less than `window` bytes, for decompressors set up with a
+/// smaller window than deflate allows.
+pub fn deflate_window(data: &[u8], level: u8, window: usize) -> Vec<u8> {
+    let mut w = BitWriter {
+        out: vec![],
+        acc: 0,
+        bits: 0,
+    };
+    let mut matcher = Matcher::new(window, level);
+
+    // Final block, fixed codes
+    w.write(1, 1);
+    w.write(1, 2);
+
+    let mut pos = 0;
+    while pos < data.len() {
+        let max_len = (data.len() - pos).min(258);
+
+        match matcher.find(data, pos, MIN_MATCH, max_len) {
+            Some((dist, len)) => {
+                let i = LEN_BASE.iter().rposition(|&b| b as usize <= len).unwrap();
+                fixed_symbol(&mut w, 257 + i as u32);
+                w.write((len - LEN_BASE[i] as usize) as u32, LEN_EXTRA[i] as u32);
+
+                let d = DIST_BASE.iter().rposition(|&b| b as usize <= dist).unwrap();
+                w.write_code(d as u32, 5);
+                w.write((dist - DIST_BASE[d] as usize) as u32, DIST_EXTRA[d] as u32);
+
+                for p in pos..pos + len {
+                    matcher.insert(data, p);
+                }
+                pos += len;
+            }
+            None => {
+                fixed_symbol(&mut w, data[pos] as u32);
+                matcher.insert(data, pos);
+                pos += 1;
+            }
+        }
+    }
+
+    fixed_symbol(&mut w, 256);
+    w.finish()
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+    for chunk in data.chunks(5552) {
+        for &byte in chunk {
+            a += byte as u32;
+            b += a;
+        }
+        a %= 65521;
+        b %= 65521;
+    }
+    (b << 16) | a
+}
+
+/// zlib stream, as used by the `gzip` compressor of squashfs.
+pub fn zlib(data: &[u8], level: u8) -> Vec<u8> {
+    // 32K window, fastest level
+    let mut out = vec![0x78, 0x01];
+    out.extend(deflate(data, level));
+    out.extend(adler32(data).to_be_bytes());
+    out
+}
+
+/// gzip file, without a name or timestamp so the output is reproducible.
+pub fn gzip(data: &[u8], level: u8) -> Vec<u8> {
+    // Deflate, no flags or mtime, Unix
+    let mut out = vec![0x1f, 0x8b, 8, 0, 0, 0, 0, 0, 0, 3];
+    out.extend(deflate(data, level));
+    out.extend(crc::crc32::checksum_ieee(data).to_le_bytes());
+    out.extend((data.len() as u32).to_le_bytes());
+    out
+}
+
+/// Reads the bits of a deflate stream, least significant first.
+struct BitReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+    acc: u32,
+    bits: u32,
+}
+
+impl BitReader<'_> {
+    fn read(&mut self, count: u32) -> anyhow::Result<u32> {
+        while self.bits < count {
+            let byte = *self
+                .data
+                .get(self.pos)
+                .ok_or_else(|| anyhow::anyhow!("deflate stream ends early"))?;
+            self.acc |= (byte as u32) << self.bits;
+            self.pos += 1;
+            self.bits += 8;
+        }
+
+        let v = self.acc & ((1u64 << count) - 1) as u32;
+        self.acc = self.acc.checked_shr(count).unwrap_or(0);
+        self.bits -= count;
+        Ok(v)
+    }
+
+    /// Skip to the next byte boundary, for stored blocks.
+    fn align(&mut self) {
+        self.acc = 0;
+        self.bits = 0;
+    }
+}
+
+/// Canonical Huffman code, as the number of codes of each length and the symbols in code order.
+struct Huffman {
+    counts: [u16; 16],
+    symbols: Vec<u16>,
+}
+
+impl Huffman {
+    fn new(lengths: &[u8]) -> Self {
+        let mut counts = [0u16; 16];
+        for &len in lengths {
+            counts[len as usize] += 1;
+        }
+        counts[0] = 0;
+
+        let mut symbols = vec![];
+        for len in 1..16 {
+            for (sym, _) in lengths.iter().enumerate().filter(|(_, &l)| l == len) {
+                symbols.push(sym as u16);
+            }
+        }
+
+        Self { counts, symbols }
+    }
+
+    fn decode(&self, r: &mut BitReader) -> anyhow::Result<u16> {
+        // Codes are stored from their most significant bit
+        let (mut code, mut first, mut index) = (0i32, 0i32, 0i32);
+
+        for len in 1..16 {
+            code |= r.read(1)? as i32;
+            let count = self.counts[len] as i32;
+            if code - first < count {
+                return Ok(self.symbols[(index + code - first) as usize]);
+            }
+            index += count;
+            first = (first + count) << 1;
+            code <<= 1;
+        }
+
+        anyhow::bail!("invalid Huffman code in deflate stream")
+    }
+}
+
+/// Order in which the code length code lengths of dynamic blocks are stored
+const CODE_LENGTH_ORDER: [usize; 19] = [
+    16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15,
+];
+
+fn dynamic_codes(r: &mut BitReader) -> anyhow::Result<(Huffman, Huffman)> {
+    let literals = r.read(5)? as usize + 257;
+    let distances = r.read(5)? as usize + 1;
+    let code_lengths = r.read(4)? as usize + 4;
+
+    let mut lengths = [0u8; 19];
+    for &i in &CODE_LENGTH_ORDER[..code_lengths] {
+        lengths[i] = r.read(3)? as u8;
+    }
+    let code = Huffman::new(&lengths);
+
+    let mut lengths = vec![];
+    while lengths.len() < literals + distances {
+        let (len, repeat) = match code.decode(r)? {
+            sym @ 0..=15 => (sym as u8, 1),
+            16 => {
+                let prev = *lengths
+                    .last()
+                    .ok_or_else(|| anyhow::anyhow!("repeated code length with none before"))?;
+                (prev, 3 + r.read(2)?)
+            }
+            17 => (0, 3 + r.read(3)?),
+            _ => (0, 11 + r.read(7)?),
+        };
+        lengths.extend(std::iter::repeat_n(len, repeat as usize));
+    }
+
+    if lengths.len() > literals + distances {
+        anyhow::bail!("code lengths run past the end of the table");
+    }
+
+    Ok((
+        Huffman::new(&lengths[..literals]),
+        Huffman::new(&lengths[literals..]),
+    ))
+}
+
+fn fixed_codes() -> (Huffman, Huffman) {
+    let mut lengths = [0u8; 288];
+    lengths[..144].fill(8);
+    lengths[144..256].fill(9);
+    lengths[256..280].fill(7);
+    lengths[280..].fill(8);
+
+    (Huffman::new(&lengths), Huffman::new(&[5; 30]))
+}
+
+/// Decompress a raw deflate stream, as found in zip archives.
+pub fn inflate(data: &[u8]) -> anyhow::Result<Vec<u8>> {
+    let mut r = BitReader {
+        data,
+        pos: 0,
+        acc: 0,
+        bits: 0,
+    };
+    let mut out = vec![];
+
+    loop {
+        let last = r.read(1)? == 1;
+
+        let (literals, distances) = match r.read(2)? {
+            0 => {
+                r.align();
+                let header = data
+                    .get(r.pos..r.pos + 4)
+                    .ok_or_else(|| anyhow::anyhow!("deflate stream ends early"))?;
+                let len = u16::from_le_bytes([header[0], header[1]]) as usize;
+                let stored = data
+                    .get(r.pos + 4..r.pos + 4 + len)
+                    .ok_or_else(|| anyhow::anyhow!("deflate stream ends early"))?;
+                out.extend_from_slice(stored);
+                r.pos += 4 + len;
+
+                if last {
+                    return Ok(out);
+                }
+                continue;
+            }
+            1 => fixed_codes(),
+            2 => dynamic_codes(&mut r)?,
+            _ => anyhow::bail!("invalid deflate block type"),
+        };
+
+        loop {
+            let sym = literals.decode(&mut r)? as usize;
+            match sym {
+                0..=255 => out.push(sym as u8),
+                256 => break,
+                _ => {
+                    let i = sym - 257;
+                    if i >= LEN_BASE.len() {
+                        anyhow::bail!("invalid length in deflate stream");
+                    }
+                    let len = LEN_BASE[i] as usize + r.read(LEN_EXTRA[i] as u32)? as usize;
+
+                    let d = distances.decode(&mut r)? as usize;
+                    if d >= DIST_BASE.len() {
+                        anyhow::bail!("invalid distance in deflate stream");
+                    }
+                    let dist = DIST_BASE[d] as usize + r.read(DIST_EXTRA[d] as u32)? as usize;
+                    if dist > out.len() {
+                        anyhow::bail!("deflate stream refers to data before its start");
+                    }
+
+                    // Matches may overlap the bytes they produce
+                    let start = out.len() - dist;
+                    for k in 0..len {
+                        out.push(out[start + k]);
+                    }
+                }
+            }
+        }
+
+        if last {
+            return Ok(out);
+        }
+    }
+}
+
+/// The last match has to start this far from the end of an LZ4 block
+const LZ4_MF_LIMIT: usize = 12;
+/// The last bytes of an LZ4 block are always literals
+const LZ4_LAST_LITERALS: usize = 5;
+
+fn lz4_len(out: &mut Vec<u8>, mut len: usize) {
+    while len >= 255 {
+        out.push(255);
+        len -= 255;
+    }
+    out.push(len as u8);
+}
+
+fn lz4_sequence(out: &mut Vec<u8>, literals: &[u8], matched: Option<(usize, usize)>) {
+    let lit = literals.len();
+    let ml = matched.map_or(0, |(_, len)| len - 4);
+
+    out.push(((lit.min(15) as u8) << 4) | ml.min(15) as u8);
+    if lit >= 15 {
+        lz4_len(out, lit - 15);
+    }
+    out.extend_from_slice(literals);
+
+    if let Some((dist, _)) = matched {
+        out.extend((dist as u16).to_le_bytes());
+        if ml >= 15 {
+            lz4_len(out, ml - 15);
+        }
+    }
+}
+
+/// Uncompressed size of the chunks of the legacy LZ4 format
+const LZ4_LEGACY_CHUNK: usize = 8 << 20;
+
+/// Legacy LZ4 file, as written by `lz4 -l` and read by the kernel.
+pub fn lz4_legacy(data: &[u8], level: u8) -> Vec<u8> {
+    let mut out = 0x184C_2102u32.to_le_bytes().to_vec();
+    for chunk in data.chunks(LZ4_LEGACY_CHUNK) {
+        let block = lz4(chunk, level);
+        out.extend((block.len() as u32).to_le_bytes());
+        out.extend(block);
+    }
+    out
+}
+
+/// LZ4 block, without a frame.
+pub fn lz4(data: &[u8], level: u8) -> Vec<u8> {
+    let mut out = vec![];
+    let mut matcher = Matcher::new(64 << 10, level);
+    let mut anchor = 0;
+    let mut pos = 0;
+
+    while pos + LZ4_MF_LIMIT < data.len() {
+        let max_len = data.len() - LZ4_LAST_LITERALS - pos;
+
+        match matcher.find(data, pos, 4, max_len) {
+            Some((dist, len)) => {
+                lz4_sequence(&mut out, &data[anchor..pos], Some((dist, len)));
+                for p in pos..pos + len {
+                    matcher.insert(data, p);
+                }
+                pos += len;
+                anchor = pos;
+            }
+            None => {
+                matcher.insert(data, pos);
+                pos += 1;
+            }
+        }
+    }
+
+    lz4_sequence(&mut out, &data[anchor..], None);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Inputs from empty to larger than the 32K deflate window.
+    fn samples() -> Vec<Vec<u8>> {
+        let text = b"The quick brown fox jumps over the lazy dog. ".repeat(2000);
+        let mut state = 0x2545_f491u32;
+        let random = (0..100_000)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 17;
+                state ^= state << 5;
+                state as u8
+            })
+            .collect();
+        vec![
+            vec![],
+            b"a".to_vec(),
+            b"abcabcabcabcabcabcabcabc".to_vec(),
+            vec![0; 70_000],
+            (0..=255).cycle().take(40_000).collect(),
+            text,
+            random,
+        ]
+    }
+
+    /// Decompress an LZ4 block, checking the rules for its end.
+    fn lz4_decode(data: &[u8]) -> Vec<u8> {
+        let mut out = vec![];
+        let mut pos = 0;
+        let len = |pos: &mut usize, mut n: usize| {
+            if n == 15 {
+                loop {
+                    let b = data[*pos];
+                    *pos += 1;
+                    n += b as usize;
+                    if b != 255 {
+                        break;
+                    }
+                }
+            }
+            n
+        };
+
+        loop {
+            let token = data[pos];
+            pos += 1;
+            let lit = len(&mut pos, (token >> 4) as usize);
+            out.extend_from_slice(&data[pos..pos + lit]);
+            pos += lit;
+            if pos == data.len() {
+                return out;
+            }
+
+            let dist = u16::from_le_bytes([data[pos], data[pos + 1]]) as usize;
+            pos += 2;
+            let matched = len(&mut pos, (token & 15) as usize) + 4;
+            assert!(dist > 0 && dist <= out.len());
+            let start = out.len() - dist;
+            for k in 0..matched {
+                out.push(out[start + k]);
+            }
+        }
+    }
+
+    #[test]
+    fn deflate_round_trip() {
+        for data in samples() {
+            for level in [1, DEFAULT_LEVEL, MAX_LEVEL] {
+                let packed = deflate(&data, level);
+                assert_eq!(inflate(&packed).unwrap(), data, "level {level}");
+            }
+        }
+    }
+
+    #[test]
+    fn deflate_compresses_repetition() {
+        let data = vec![0; 70_000];
+        assert!(deflate(&data, DEFAULT_LEVEL).len() < 1000);
+    }
+
+    #[test]
+    fn deflate_window_round_trip() {
+        for data in samples() {
+            let packed = deflate_window(&data, DEFAULT_LEVEL, 1 << 11);
+            assert_eq!(inflate(&packed).unwrap(), data);
+        }
+
+        // A repeat further back than the window is left as literals
+        let random = &samples()[6][..4096];
+        let data = [random, random].concat();
+        assert!(deflate_window(&data, DEFAULT_LEVEL, 1 << 11).len() > data.len());
+        assert!(deflate(&data, DEFAULT_LEVEL).len() < data.len());
+    }
+
+    #[test]
+    fn inflate_stored_and_dynamic() {
+        // Written by zlib at levels 0 and 9
+        let stored = [
+            0x01, 0x06, 0x00, 0xf9, 0xff, b's', b't', b'o', b'r', b'e', b'd',
+        ];
+        assert_eq!(inflate(&stored).unwrap(), b"stored");
+
+        let dynamic = [
+            0x0b, 0xc9, 0x48, 0x55, 0x28, 0x2c, 0xcd, 0x4c, 0xce, 0x56, 0x48, 0x2a, 0xca, 0x2f,
+            0xcf, 0x53, 0x48, 0xcb, 0xaf, 0x50, 0xc8, 0x2a, 0xcd, 0x2d, 0x28, 0x56, 0xc8, 0x2f,
+            0x4b, 0x2d, 0x52, 0x28, 0x01, 0x4a, 0xe7, 0x24, 0x56, 0x55, 0x2a, 0xa4, 0xe4, 0xa7,
+            0xeb, 0x29, 0x84, 0x8c, 0x2a, 0x26, 0x57, 0x31, 0x03, 0x23, 0x13, 0x33, 0x0b, 0x2b,
+            0x1b, 0x3b, 0x07, 0x27, 0x17, 0x37, 0x0f, 0x2f, 0x1f, 0xbf, 0x80, 0xa0, 0x90, 0xb0,
+            0x88, 0xa8, 0x98, 0xb8, 0x84, 0xa4, 0x94, 0xb4, 0x8c, 0xac, 0x9c, 0xbc, 0x82, 0xa2,
+            0x92, 0xb2, 0x8a, 0xaa, 0x9a, 0xba, 0x86, 0xa6, 0x96, 0xb6, 0x8e, 0xae, 0x9e, 0xbe,
+            0x81, 0xa1, 0x91, 0xb1, 0x89, 0xa9, 0x99, 0xb9, 0x85, 0xa5, 0x95, 0xb5, 0x8d, 0xad,
+            0x9d, 0x3d, 0x00,
+        ];
+        let mut expected = b"The quick brown fox jumps over the lazy dog. ".repeat(8);
+        expected.extend(0..64);
+        assert_eq!(inflate(&dynamic).unwrap(), expected);
+    }
+
+    #[test]
+    fn inflate_errors() {
+        assert!(inflate(&[]).is_err());
+        // Block type 3
+        assert!(inflate(&[0x07]).is_err());
+        // Stored block longer than the input
+        assert!(inflate(&[0x01, 0x10, 0x00, 0xef, 0xff, 0]).is_err());
+        // Truncated fixed Huffman block
+        let packed = deflate(b"hello hello hello hello", DEFAULT_LEVEL);
+        assert!(inflate(&packed[..packed.len() - 2]).is_err());
+    }
+
+    #[test]
+    fn zlib_and_gzip_framing() {
+        let data = b"hello hello hello hello";
+        let z = zlib(data, DEFAULT_LEVEL);
+        // The header is a multiple of 31
+        assert_eq!(u16::from_be_bytes([z[0], z[1]]) % 31, 0);
+        assert_eq!(z[z.len() - 4..], adler32(data).to_be_bytes());
+        assert_eq!(inflate(&z[2..z.len() - 4]).unwrap(), data);
+
+        let g = gzip(data, DEFAULT_LEVEL);
+        assert_eq!(g[..3], [0x1f, 0x8b, 8]);
+        assert_eq!(g[g.len() - 4..], (data.len() as u32).to_le_bytes());
+        assert_eq!(inflate(&g[10..g.len() - 8]).unwrap(), data);
+    }
+
+    #[test]
+    fn adler32_known_answer() {
+        assert_eq!(adler32(b""), 1);
+        assert_eq!(adler32(b"Wikipedia"), 0x11e6_0398);
+    }
+
+    #[test]
+    fn lz4_round_trip() {
+        for data in samples() {
+            for level in [1, DEFAULT_LEVEL, MAX_LEVEL] {
+                let packed = lz4(&data, level);
+                assert_eq!(lz4_decode(&packed), data, "level {level}");
+            }
+        }
+    }
+
+    #[test]
+    fn lz4_block_end() {
+        // Blocks of up to 12 bytes are a single run of literals
+        for n in 0..=LZ4_MF_LIMIT {
+            let data = vec![b'x'; n];
+            let packed = lz4(&data, DEFAULT_LEVEL);
+            assert_eq!(packed[0] >> 4, n as u8);
+            assert_eq!(packed.len(), n + 1);
+        }
+
+        // However long the match, the last five bytes are literals
+        let packed = lz4(&[0; 1000], DEFAULT_LEVEL);
+        assert_eq!(packed[packed.len() - 6..], [0x50, 0, 0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn lz4_legacy_chunks() {
+        let data = vec![7; LZ4_LEGACY_CHUNK + 100];
+        let file = lz4_legacy(&data, 1);
+        assert_eq!(file[..4], 0x184C_2102u32.to_le_bytes());
+
+        let mut pos = 4;
+        let mut out = vec![];
+        while pos < file.len() {
+            let len = u32::from_le_bytes(file[pos..pos + 4].try_into().unwrap()) as usize;
+            out.extend(lz4_decode(&file[pos + 4..pos + 4 + len]));
+            pos += 4 + len;
+        }
+        assert_eq!(out, data);
+    }
+
+    #[test]
+    fn incompressible_blocks_stay_raw() {
+        let random = samples().pop().unwrap();
+        for c in [Compression::Gzip, Compression::Lz4] {
+            assert_eq!(c.compress(&random[..4096], DEFAULT_LEVEL).unwrap(), None);
+            assert_eq!(c.compress(&[], DEFAULT_LEVEL).unwrap(), None);
+            assert!(c.compress(&[0; 4096], DEFAULT_LEVEL).unwrap().is_some());
+        }
+    }
+}
@@ -0,0 +1,210 @@
+//! Conversion of the partition tables of existing images between MBR and GPT.
+
+use crate::table::Gpt;
+use crate::{image, part_type, DiskId, PartitionTable};
+use clap::{Args, ValueEnum};
+use std::collections::BTreeMap;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+
+/// Type GUID of FAT and NTFS partitions on GPT, as Windows expects them
+const BASIC_DATA: &str = "EBD0A0A2-B9E5-4433-87C0-68B6B72699C7";
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum Target {
+    Mbr,
+    Gpt,
+}
+
+#[derive(Args, Debug)]
+pub struct ConvertTableArgs {
+    /// Image to convert in place
+    #[arg(long)]
+    image: PathBuf,
+    /// Partition table to convert to
+    #[arg(value_enum, long)]
+    to: Target,
+}
+
+/// GPT type of an MBR partition, from its system ID.
+fn gpt_type(sys: u8) -> anyhow::Result<gpt::partition_types::Type> {
+    let ty = match sys {
+        0xef => "esp",
+        0x82 => "swap",
+        0x01 | 0x04 | 0x06 | 0x07 | 0x0b | 0x0c | 0x0e => BASIC_DATA,
+        _ => "generic",
+    };
+    let ty = ty
+        .parse::<part_type::GptType>()
+        .map_err(anyhow::Error::msg)?;
+    ty.to_type(None)
+}
+
+/// MBR system ID of a GPT partition, from its type and the filesystem in it.
+fn mbr_type(file: &File, part: &gpt::partition::Partition, sector: u64) -> anyhow::Result<u8> {
+    let guid = part.part_type_guid.guid;
+    let is = |alias: &str| -> anyhow::Result<bool> {
+        let ty = alias
+            .parse::<part_type::GptType>()
+            .map_err(anyhow::Error::msg)?;
+        Ok(ty.guid(None)?.eq_ignore_ascii_case(guid))
+    };
+
+    if is("esp")? {
+        return Ok(0xef);
+    }
+    if is("swap")? {
+        return Ok(0x82);
+    }
+
+    let start = part.first_lba * sector;
+    let end = (part.last_lba + 1) * sector;
+    let mut slice = fscommon::StreamSlice::new(file.try_clone()?, start, end)?;
+
+    // FAT32 and exFAT (NTFS type), with LBA addressing
+    Ok(match image::detect_filesystem(&mut slice)? {
+        Some("FAT") => 0x0c,
+        Some("exFAT") => 0x07,
+        _ => 0x83,
+    })
+}
+
+fn to_gpt(file: &mut File, kind: PartitionTable) -> anyhow::Result<()> {
+    let gpt = match kind {
+        // Hybrid MBRs only lose their mirrored entries
+        PartitionTable::Hybrid => Gpt::read(file)?,
+        _ => {
+            let sector = image::sector_size(file)?;
+            let mbr = mbrman::MBR::read_from(file, sector as u32)?;
+
+            let mut gpt = Gpt::new(BTreeMap::new(), sector);
+
+            for (i, (number, p)) in image::mbr_partitions(&mbr).enumerate() {
+                let start = p.starting_lba as u64 * sector;
+                if start < gpt.first_usable() {
+                    anyhow::bail!(
+                        "partition {number} starts at sector {}, where the GPT goes",
+                        p.starting_lba
+                    );
+                }
+
+                let flags = if p.boot == mbrman::BOOT_ACTIVE {
+                    gpt::partition::PartitionAttributes::BOOTABLE.bits()
+                } else {
+                    0
+                };
+
+                gpt.partitions.insert(
+                    i as u32 + 1,
+                    gpt::partition::Partition {
+                        part_type_guid: gpt_type(p.sys)?,
+                        part_guid: crate::random_guid(&Default::default())?.parse()?,
+                        first_lba: p.starting_lba as u64,
+                        last_lba: p.starting_lba as u64 + p.sectors as u64 - 1,
+                        flags,
+                        name: format!("part{number}"),
+                    },
+                );
+            }
+
+            gpt
+        }
+    };
+
+    // The backup goes after the last partition, growing the image if it is right at its end
+    let size = file.seek(SeekFrom::End(0))?;
+    let disk_size = size.max(gpt.end() + gpt.tail());
+
+    let mbr = gpt::mbr::ProtectiveMBR::with_lb_size(
+        u32::try_from(disk_size / gpt.sector - 1).unwrap_or(u32::MAX),
+    );
+    mbr.update_conservative(file)?;
+
+    gpt.write(file, disk_size)
+}
+
+fn to_mbr(file: &mut File) -> anyhow::Result<()> {
+    let gpt = Gpt::read(file)?;
+
+    if gpt.partitions.len() > 4 {
+        anyhow::bail!(
+            "the GPT has {} partitions, but an MBR holds at most 4 primary ones",
+            gpt.partitions.len()
+        );
+    }
+
+    let mut entries = vec![];
+    for (&number, part) in &gpt.partitions {
+        let lba = |sector: u64| {
+            u32::try_from(sector).map_err(|_| {
+                anyhow::anyhow!("partition {number} ends past what an MBR can address")
+            })
+        };
+
+        entries.push(mbrman::MBRPartitionEntry {
+            boot: if part.flags & gpt::partition::PartitionAttributes::BOOTABLE.bits() != 0 {
+                mbrman::BOOT_ACTIVE
+            } else {
+                mbrman::BOOT_INACTIVE
+            },
+            first_chs: mbrman::CHS::empty(),
+            sys: mbr_type(file, part, gpt.sector)?,
+            last_chs: mbrman::CHS::empty(),
+            starting_lba: lba(part.first_lba)?,
+            sectors: lba(part.last_lba - part.first_lba + 1)?,
+        });
+        lba(part.last_lba + 1)?;
+    }
+
+    // Boot code is kept, the disk signature is new as the protective MBR has none
+    let mut code = [0u8; 440];
+    file.seek(SeekFrom::Start(0))?;
+    file.read_exact(&mut code)?;
+
+    gpt.erase(file)?;
+
+    let mut mbr = mbrman::MBR::new_from(
+        file,
+        gpt.sector as u32,
+        DiskId::Random.signature(&Default::default())?,
+    )?;
+    for (i, entry) in entries.into_iter().enumerate() {
+        mbr[i + 1] = entry;
+    }
+    mbr.write_into(file)?;
+
+    file.seek(SeekFrom::Start(0))?;
+    file.write_all(&code)?;
+
+    Ok(())
+}
+
+pub fn run(args: &ConvertTableArgs) -> anyhow::Result<()> {
+    let mut file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(&args.image)
+        .map_err(|e| anyhow::anyhow!("cannot open {}: {e}", args.image.display()))?;
+
+    let (kind, _) = image::read_partitions(&mut file)?;
+    let name = match args.to {
+        Target::Mbr => "MBR",
+        Target::Gpt => "GPT",
+    };
+
+    match (kind, args.to) {
+        (PartitionTable::None, _) => {
+            anyhow::bail!("{} has no partition table to convert", args.image.display())
+        }
+        (PartitionTable::Mbr, Target::Mbr) | (PartitionTable::Gpt, Target::Gpt) => {
+            anyhow::bail!("{} already uses {name}", args.image.display())
+        }
+        (_, Target::Gpt) => to_gpt(&mut file, kind)?,
+        (_, Target::Mbr) => to_mbr(&mut file)?,
+    }
+
+    println!("{} converted to {name}", args.image.display());
+
+    Ok(())
+}
@@ -0,0 +1,149 @@
+//! cpio archives in the `newc` format, as unpacked by the kernel from an initramfs.
+
+use crate::tree::{Kind, Tree};
+use std::io::{self, Read, Write};
+use std::path::Path;
+
+const MAGIC: &str = "070701";
+const TRAILER: &str = "TRAILER!!!";
+
+const S_IFDIR: u32 = 0o040000;
+const S_IFREG: u32 = 0o100000;
+const S_IFLNK: u32 = 0o120000;
+
+/// Header fields, in order, after the magic.
+struct Header {
+    ino: u32,
+    mode: u32,
+    uid: u32,
+    gid: u32,
+    nlink: u32,
+    mtime: u32,
+    filesize: u32,
+}
+
+/// Both the name and the data are padded to a multiple of 4 bytes, counting the header.
+fn padding(len: u64) -> usize {
+    (len.wrapping_neg() % 4) as usize
+}
+
+fn write_entry<W: Write + ?Sized>(out: &mut W, h: &Header, name: &str) -> io::Result<u64> {
+    // The device numbers and the checksum are 0
+    let fields = [
+        h.ino,
+        h.mode,
+        h.uid,
+        h.gid,
+        h.nlink,
+        h.mtime,
+        h.filesize,
+        0,
+        0,
+        0,
+        0,
+        name.len() as u32 + 1,
+        0,
+    ];
+
+    let mut header = String::from(MAGIC);
+    for field in fields {
+        header.push_str(&format!("{field:08x}"));
+    }
+
+    let len = (header.len() + name.len() + 1) as u64;
+    out.write_all(header.as_bytes())?;
+    out.write_all(name.as_bytes())?;
+    out.write_all(&[0; 4][..1 + padding(len)])?;
+
+    Ok(len + padding(len) as u64)
+}
+
+/// Write the tree as an archive, with the root as `.`. Returns the archive size.
+///
+/// `on_file` is called with the image path and length of every file written.
+pub fn write<W: Write + ?Sized>(
+    out: &mut W,
+    tree: &Tree,
+    on_file: &mut dyn FnMut(&Path, u64),
+) -> anyhow::Result<u64> {
+    let mut written = 0;
+
+    // Parents come before their children, so the kernel can create entries while unpacking
+    for (idx, node) in tree.nodes.iter().enumerate() {
+        let name = match idx {
+            0 => ".",
+            _ => node.path.to_str().unwrap(),
+        };
+
+        let mtime = node.mtime.clamp(0, u32::MAX as i64) as u32;
+
+        match &node.kind {
+            Kind::Dir(children) => {
+                let subdirs = children.iter().filter(|&&c| tree.nodes[c].is_dir()).count();
+                let header = Header {
+                    ino: idx as u32 + 1,
+                    mode: S_IFDIR | node.mode,
+                    uid: node.uid,
+                    gid: node.gid,
+                    nlink: 2 + subdirs as u32,
+                    mtime,
+                    filesize: 0,
+                };
+                written += write_entry(out, &header, name)?;
+            }
+            Kind::File { source, len } => {
+                let filesize = u32::try_from(*len).map_err(|_| {
+                    anyhow::anyhow!("cpio archives are limited to 4GiB files: {name}")
+                })?;
+                let header = Header {
+                    ino: idx as u32 + 1,
+                    mode: S_IFREG | node.mode,
+                    uid: node.uid,
+                    gid: node.gid,
+                    nlink: 1,
+                    mtime,
+                    filesize,
+                };
+                written += write_entry(out, &header, name)?;
+
+                let copied = io::copy(&mut source.open()?.take(*len), out)?;
+                if copied != *len {
+                    anyhow::bail!("{} changed while being copied", node.path.display());
+                }
+                out.write_all(&[0; 3][..padding(*len)])?;
+                written += *len + padding(*len) as u64;
+
+                on_file(&node.path, *len);
+            }
+            Kind::Symlink(target) => {
+                // The target is the data of the entry
+                let header = Header {
+                    ino: idx as u32 + 1,
+                    mode: S_IFLNK | node.mode,
+                    uid: node.uid,
+                    gid: node.gid,
+                    nlink: 1,
+                    mtime,
+                    filesize: target.len() as u32,
+                };
+                written += write_entry(out, &header, name)?;
+                out.write_all(target.as_bytes())?;
+                out.write_all(&[0; 3][..padding(target.len() as u64)])?;
+                written += (target.len() + padding(target.len() as u64)) as u64;
+            }
+        }
+    }
+
+    let trailer = Header {
+        ino: 0,
+        mode: 0,
+        uid: 0,
+        gid: 0,
+        nlink: 1,
+        mtime: 0,
+        filesize: 0,
+    };
+    written += write_entry(out, &trailer, TRAILER)?;
+
+    Ok(written)
+}
@@ -0,0 +1,314 @@
+//! cramfs images, zlib compressed.
+//!
+//! Directories are laid out breadth first right after the superblock, with their entries sorted
+//! by name, followed by the data of every file in the same order. Symbolic links store their
+//! target as data. Owners only keep their low 16 bits of the uid and 8 bits of the gid.
+
+use crate::compress;
+use crate::tree::{Kind, Tree};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+const MAGIC: u32 = 0x28CD_3D45;
+const SIGNATURE: &[u8; 16] = b"Compressed ROMFS";
+const SUPERBLOCK_SIZE: u64 = 76;
+const INODE_SIZE: u64 = 12;
+/// Files are compressed in blocks of a page
+const BLOCK_SIZE: u64 = 4096;
+/// Images are padded to this, as mkcramfs does
+const PAD: u64 = 4096;
+
+const FLAG_FSID_VERSION_2: u32 = 0x1;
+const FLAG_SORTED_DIRS: u32 = 0x2;
+
+/// Largest file and directory size, which is a 24 bit field
+const MAX_SIZE: u64 = (1 << 24) - 1;
+/// Largest name length, stored in units of 4 bytes in a 6 bit field
+const MAX_NAME: usize = 63 * 4;
+
+const S_IFDIR: u32 = 0o040000;
+const S_IFREG: u32 = 0o100000;
+const S_IFLNK: u32 = 0o120000;
+
+#[derive(Clone, Debug, Default)]
+pub struct Options {
+    pub label: Option<String>,
+}
+
+fn align4(v: u64) -> u64 {
+    v.div_ceil(4) * 4
+}
+
+fn inode(mode: u32, (uid, gid): (u32, u32), size: u64, name: &str, offset: u64) -> Vec<u8> {
+    let name_len = align4(name.len() as u64);
+
+    let mut i = vec![0u8; (INODE_SIZE + name_len) as usize];
+    i[0..4].copy_from_slice(&(mode & 0xffff | uid << 16).to_le_bytes());
+    i[4..8].copy_from_slice(&(size as u32 | gid << 24).to_le_bytes());
+    i[8..12].copy_from_slice(&((name_len / 4) as u32 | ((offset / 4) as u32) << 6).to_le_bytes());
+    i[12..12 + name.len()].copy_from_slice(name.as_bytes());
+    i
+}
+
+/// Placement of the directory entries.
+struct Layout {
+    /// Directories in the order their entries are written, and children sorted by name
+    dirs: Vec<(usize, Vec<usize>)>,
+    /// Offset and size of the entries of every directory
+    entries: Vec<(u64, u64)>,
+    /// End of the directory entries, where file data starts
+    end: u64,
+}
+
+impl Layout {
+    fn new(tree: &Tree) -> anyhow::Result<Self> {
+        for node in &tree.nodes[1..] {
+            if node.name.len() > MAX_NAME {
+                anyhow::bail!(
+                    "cramfs names are limited to {MAX_NAME} bytes: {}",
+                    node.path.display()
+                );
+            }
+            if let Kind::File { len, .. } = node.kind {
+                if len > MAX_SIZE {
+                    anyhow::bail!("cramfs files are limited to 16MiB: {}", node.path.display());
+                }
+            }
+        }
+
+        let mut dirs = vec![];
+        let mut entries = vec![(0, 0); tree.nodes.len()];
+        let mut next = SUPERBLOCK_SIZE;
+        let mut queue = std::collections::VecDeque::from([0]);
+
+        while let Some(idx) = queue.pop_front() {
+            let Kind::Dir(children) = &tree.nodes[idx].kind else {
+                continue;
+            };
+
+            let mut children = children.clone();
+            children.sort_by(|&a, &b| tree.nodes[a].name.cmp(&tree.nodes[b].name));
+
+            let start = next;
+            for &c in &children {
+                next += INODE_SIZE + align4(tree.nodes[c].name.len() as u64);
+                if tree.nodes[c].is_dir() {
+                    queue.push_back(c);
+                }
+            }
+
+            if next - start > MAX_SIZE {
+                anyhow::bail!(
+                    "{} has too many entries for cramfs",
+                    tree.nodes[idx].path.display()
+                );
+            }
+
+            entries[idx] = (start, next - start);
+            dirs.push((idx, children));
+        }
+
+        Ok(Self {
+            dirs,
+            entries,
+            end: next,
+        })
+    }
+}
+
+/// Tracks the write position, so the same code can measure and write the image.
+struct Output<'a, W> {
+    out: Option<&'a mut W>,
+    pos: u64,
+    limit: u64,
+}
+
+impl<W: Write> Output<'_, W> {
+    fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        if let Some(out) = &mut self.out {
+            if self.pos + data.len() as u64 > self.limit {
+                return Err(io::Error::other(format!(
+                    "cramfs image does not fit in {} bytes",
+                    self.limit
+                )));
+            }
+            out.write_all(data)?;
+        }
+        self.pos += data.len() as u64;
+        Ok(())
+    }
+}
+
+/// Lay out, and with `out` write the image. Returns the image size.
+fn build<W: Read + Write + Seek>(
+    mut out: Option<&mut W>,
+    size: u64,
+    tree: &Tree,
+    opts: &Options,
+    on_file: &mut dyn FnMut(&Path, u64),
+) -> anyhow::Result<u64> {
+    let label = opts.label.as_deref().unwrap_or_default();
+    if label.len() > 16 {
+        anyhow::bail!("cramfs volume label is longer than 16 bytes");
+    }
+
+    let layout = Layout::new(tree)?;
+
+    if let Some(out) = &mut out {
+        out.seek(SeekFrom::Start(layout.end))?;
+    }
+    let mut o = Output {
+        out: out.as_deref_mut(),
+        pos: layout.end,
+        limit: size,
+    };
+
+    // File data, each starting with pointers to the end of its compressed blocks
+    let mut data = vec![0; tree.nodes.len()];
+    let mut total_blocks = 0;
+    let mut block = vec![0u8; BLOCK_SIZE as usize];
+
+    for (_, children) in &layout.dirs {
+        for &c in children {
+            let node = &tree.nodes[c];
+            let (mut file, len): (Box<dyn Read>, _) = match &node.kind {
+                Kind::File { source, len } => (source.open()?, *len),
+                Kind::Symlink(target) => (Box::new(target.as_bytes()), target.len() as u64),
+                Kind::Dir(_) => continue,
+            };
+            if len == 0 {
+                on_file(&node.path, 0);
+                continue;
+            }
+
+            let count = len.div_ceil(BLOCK_SIZE);
+            let mut pointers = vec![];
+            let mut compressed = vec![];
+            let mut end = o.pos + count * 4;
+
+            for i in 0..count {
+                let n = (len - i * BLOCK_SIZE).min(BLOCK_SIZE) as usize;
+                file.read_exact(&mut block[..n]).map_err(|e| {
+                    anyhow::anyhow!("{} changed while being copied: {e}", node.path.display())
+                })?;
+
+                let z = compress::zlib(&block[..n], compress::DEFAULT_LEVEL);
+                end += z.len() as u64;
+                pointers.extend((end as u32).to_le_bytes());
+                compressed.extend(z);
+            }
+
+            data[c] = o.pos;
+            o.write(&pointers)?;
+            o.write(&compressed)?;
+            o.write(&[0; 3][..(align4(o.pos) - o.pos) as usize])?;
+            total_blocks += count;
+
+            if node.is_file() {
+                on_file(&node.path, len);
+            }
+        }
+    }
+
+    let image_size = o.pos.div_ceil(PAD) * PAD;
+    if image_size > u32::MAX as u64 {
+        anyhow::bail!("cramfs images are limited to 4GiB");
+    }
+    o.write(&vec![0; (image_size - o.pos) as usize])?;
+
+    let Some(out) = out else {
+        return Ok(image_size);
+    };
+
+    // Directory entries
+    let entry = |idx: usize, name: &str| {
+        let node = &tree.nodes[idx];
+        let owner = (node.uid & 0xffff, node.gid & 0xff);
+        match &node.kind {
+            Kind::Dir(_) => {
+                let (offset, size) = layout.entries[idx];
+                let offset = if size > 0 { offset } else { 0 };
+                inode(S_IFDIR | node.mode, owner, size, name, offset)
+            }
+            Kind::File { len, .. } => inode(S_IFREG | node.mode, owner, *len, name, data[idx]),
+            Kind::Symlink(target) => {
+                let len = target.len() as u64;
+                inode(S_IFLNK | node.mode, owner, len, name, data[idx])
+            }
+        }
+    };
+
+    let mut meta = vec![];
+    for (_, children) in &layout.dirs {
+        for &c in children {
+            meta.extend(entry(c, &tree.nodes[c].name));
+        }
+    }
+
+    let mut sb = vec![0u8; SUPERBLOCK_SIZE as usize - INODE_SIZE as usize];
+    sb[0..4].copy_from_slice(&MAGIC.to_le_bytes());
+    sb[4..8].copy_from_slice(&(image_size as u32).to_le_bytes());
+    sb[8..12].copy_from_slice(&(FLAG_FSID_VERSION_2 | FLAG_SORTED_DIRS).to_le_bytes());
+    sb[16..32].copy_from_slice(SIGNATURE);
+    sb[40..44].copy_from_slice(&(total_blocks as u32).to_le_bytes());
+    sb[44..48].copy_from_slice(&(tree.nodes.len() as u32).to_le_bytes());
+    sb[48..48 + label.len()].copy_from_slice(label.as_bytes());
+    sb.extend(entry(0, ""));
+
+    out.seek(SeekFrom::Start(0))?;
+    out.write_all(&sb)?;
+    out.write_all(&meta)?;
+
+    // The checksum covers the whole image, with its own field as 0
+    out.seek(SeekFrom::Start(0))?;
+    let mut crc = 0;
+    let mut buf = vec![0u8; 1 << 20];
+    let mut remaining = image_size;
+    while remaining > 0 {
+        let n = remaining.min(buf.len() as u64) as usize;
+        out.read_exact(&mut buf[..n])?;
+        crc = crc::crc32::update(crc, &crc::crc32::IEEE_TABLE, &buf[..n]);
+        remaining -= n as u64;
+    }
+    out.seek(SeekFrom::Start(32))?;
+    out.write_all(&crc.to_le_bytes())?;
+
+    Ok(image_size)
+}
+
+/// Smallest filesystem size the tree fits in.
+pub fn estimate_size(tree: &Tree, opts: &Options) -> anyhow::Result<u64> {
+    build::<io::Cursor<Vec<u8>>>(None, u64::MAX, tree, opts, &mut |_, _| ())
+}
+
+/// Write the image into `out`, which spans `size` bytes.
+///
+/// `on_file` is called with the image path and length of every file written.
+pub fn write<W: Read + Write + Seek>(
+    out: &mut W,
+    size: u64,
+    tree: &Tree,
+    opts: &Options,
+    on_file: &mut dyn FnMut(&Path, u64),
+) -> anyhow::Result<()> {
+    build(Some(out), size, tree, opts, on_file)?;
+
+    Ok(())
+}
+
+impl crate::FsWriter for Options {
+    fn estimate_size(&self, tree: &Tree) -> anyhow::Result<u64> {
+        estimate_size(tree, self)
+    }
+
+    fn write(
+        &self,
+        mut out: &mut dyn fatfs::ReadWriteSeek,
+        size: u64,
+        tree: &Tree,
+        _ctx: &crate::BuildContext,
+        on_file: &mut dyn FnMut(&Path, u64),
+    ) -> anyhow::Result<()> {
+        write(&mut out, size, tree, self, on_file)
+    }
+}
@@ -0,0 +1,430 @@
+//! Placement of partitions in the image, and the MBR, EBRs or GPT describing them.
+
+use crate::size::PartitionSize;
+use crate::{
+    content_size, layout, mbr_entry, mbr_type, part_type, random, random_guid, Args, BuildContext,
+    ExtraFile, Fill, GptBackupAt, PartitionTable,
+};
+use log::*;
+use std::fs::File;
+use std::io::{self, Seek, Write};
+
+/// Partitions placed in the image.
+pub struct Placement {
+    /// Numbers of the partitions in the table, from 5 for logical ones
+    pub numbers: Vec<u32>,
+    /// Start and length of each partition
+    pub placed: Vec<(u64, u64)>,
+    /// Space the partition table takes at the start of the image
+    pub head: u64,
+    /// Space the partition table takes at the end of the image
+    pub tail: u64,
+    /// Size of the image, padded to the erase block size
+    pub total_size: u64,
+}
+
+/// Size `parts` and place them one after another in an image of `image_size`, or in one just
+/// large enough for them.
+pub fn place(
+    args: &Args,
+    ctx: &BuildContext,
+    parts: &[layout::Partition],
+    part_files: &[Vec<ExtraFile>],
+    image_size: Option<u64>,
+) -> anyhow::Result<Placement> {
+    match args.partition_table {
+        PartitionTable::None if parts.len() > 1 => {
+            anyhow::bail!("multiple partitions require a partition table")
+        }
+        PartitionTable::Mbr => {}
+        _ if parts.iter().any(|p| p.primary.is_some()) => {
+            anyhow::bail!("primary and logical partitions only apply to MBR partition tables")
+        }
+        _ => {}
+    }
+
+    let numbers = match args.partition_table {
+        PartitionTable::Mbr => mbr_numbers(parts)?,
+        _ => (1..=parts.len() as u32).collect(),
+    };
+    let logical = |i: usize| numbers[i] >= 5;
+
+    let (head, tail) = args.partition_table.reserved(args.sector_size);
+    let last = parts.len() - 1;
+
+    // Partitions are placed one after another, from the end of the table at the start of the
+    // image. `None` takes the rest of the image.
+    let mut sizes = vec![];
+
+    for (i, part) in parts.iter().enumerate() {
+        let size = if args.expand_last && i == last {
+            Some(PartitionSize::Rest)
+        } else {
+            part.size
+        };
+
+        let size = match (size, image_size) {
+            (Some(PartitionSize::Bytes(size)), _) => Some(size),
+            (Some(PartitionSize::Percent(p)), Some(image_size)) => {
+                Some(image_size * p / 100 / args.sector_size * args.sector_size)
+            }
+            (Some(PartitionSize::Rest), Some(_)) if i == last => None,
+            (Some(PartitionSize::Rest), Some(_)) => {
+                anyhow::bail!("only the last partition can take the rest of the image")
+            }
+            (Some(size), None) => anyhow::bail!("partition size `{size}` requires --image-size"),
+            (None, _) if part.image.is_some() => Some(content_size(part)?),
+            (None, Some(_)) if part.filesystem.is_none() && i == last => None,
+            (None, _) if part.filesystem.is_none() && args.layout.is_none() => {
+                anyhow::bail!("--no-filesystem requires --size or --image-size")
+            }
+            (None, _) if part.filesystem.is_none() => {
+                anyhow::bail!("unformatted partition `{}` requires a size", part.name)
+            }
+            (None, _) => Some(part.filesystem.unwrap().estimate_size(
+                args,
+                part,
+                &part_files[i],
+                ctx,
+            )?),
+        };
+
+        sizes.push(size);
+    }
+
+    // Partition tables other than none place partitions at whole sectors, starting at the
+    // alignment
+    let sector = args.sector_size;
+    let (round, align) = match args.partition_table {
+        PartitionTable::None => (1, 1),
+        _ => (sector, args.align),
+    };
+
+    // Space between the partition table and the first partition is left for raw writes
+    let first = match args.first_partition_offset {
+        Some(offset) if offset < head => anyhow::bail!(
+            "first partition offset {offset} overlaps the partition table, which ends at {head}"
+        ),
+        Some(offset) => offset,
+        None => head,
+    };
+
+    let mut placed = vec![];
+    let mut end = first;
+
+    for (i, (part, size)) in parts.iter().zip(sizes).enumerate() {
+        // Logical partitions follow their EBR
+        if logical(i) {
+            end += sector;
+        }
+        end = end.next_multiple_of(align);
+
+        let len = match size {
+            Some(size) => size.div_ceil(round) * round,
+            None => {
+                let image_size = image_size.unwrap();
+                image_size
+                    .checked_sub(end + tail)
+                    .filter(|&len| len >= sector)
+                    .ok_or_else(|| {
+                        anyhow::anyhow!(
+                            "image size {image_size} leaves no room for partition `{}`",
+                            part.name
+                        )
+                    })?
+                    / sector
+                    * sector
+            }
+        };
+
+        debug!("Partition {}: start {end:x} size {len:x}", part.name);
+
+        placed.push((end, len));
+        end += len;
+    }
+
+    if let Some(image_size) = image_size {
+        let required = end + tail;
+        if image_size < required {
+            anyhow::bail!(
+                "image size {image_size} is too small, at least {required} bytes are required"
+            );
+        }
+    }
+
+    let total_size = args.pad(match (image_size, args.partition_table) {
+        (Some(size), _) => size,
+        (None, PartitionTable::Gpt | PartitionTable::Hybrid) if !args.growable => {
+            (end + tail).next_multiple_of(align)
+        }
+        (None, _) => end + tail,
+    });
+
+    Ok(Placement {
+        numbers,
+        placed,
+        head,
+        tail,
+        total_size,
+    })
+}
+
+/// Size the image `file` and write the partition table of `placement` into it, returning the file
+/// to write the partitions through.
+pub fn write_table(
+    args: &Args,
+    ctx: &BuildContext,
+    parts: &[layout::Partition],
+    placement: &Placement,
+    mut file: File,
+) -> anyhow::Result<File> {
+    let Placement {
+        numbers,
+        placed,
+        head,
+        total_size,
+        ..
+    } = placement;
+    let (sector, head, total_size) = (args.sector_size, *head, *total_size);
+    let logical = |i: usize| numbers[i] >= 5;
+
+    let file = match args.partition_table {
+        PartitionTable::None => {
+            prepare_image(&mut file, total_size, args.fill_byte, &ctx.random)?;
+
+            file
+        }
+        PartitionTable::Mbr => {
+            prepare_image(&mut file, total_size, args.fill_byte, &ctx.random)?;
+
+            let mut mbr = mbrman::MBR::new_from(
+                &mut file,
+                sector as u32,
+                args.disk_id.signature(&ctx.random)?,
+            )?;
+
+            for (i, (part, &(start, len))) in parts.iter().zip(placed).enumerate() {
+                let entry = mbr_entry(part, start, len, sector, mbr_type(part))?;
+
+                if !logical(i) {
+                    mbr[numbers[i] as usize] = entry;
+                    continue;
+                }
+
+                let ebr = start - sector;
+
+                if i == 0 || !logical(i - 1) {
+                    // The extended partition spans all logical partitions and their EBRs
+                    let last = (i..parts.len()).take_while(|&j| logical(j)).last().unwrap();
+                    let (last_start, last_len) = placed[last];
+                    let slot = numbers[..i].iter().filter(|&&n| n < 5).count() + 1;
+
+                    let mut extended =
+                        mbr_entry(part, ebr, last_start + last_len - ebr, sector, 0x0f)?;
+                    extended.boot = mbrman::BOOT_INACTIVE;
+                    mbr[slot] = extended;
+                }
+
+                mbr.logical_partitions.push(mbrman::LogicalPartition {
+                    partition: entry,
+                    absolute_ebr_lba: (ebr / sector) as u32,
+                    ebr_sectors: Some(((len + sector) / sector) as u32),
+                    ebr_first_chs: mbrman::CHS::empty(),
+                    ebr_last_chs: Some(mbrman::CHS::empty()),
+                    bootstrap_code: [0; 446],
+                });
+            }
+
+            mbr.write_into(&mut file)?;
+
+            file
+        }
+        PartitionTable::Gpt | PartitionTable::Hybrid => {
+            // The GPT library places the backup header at the end of the device, so pretend the
+            // image spans the whole medium while writing the table.
+            let disk_size = match args.gpt_backup_at {
+                GptBackupAt::EndOfMedium => total_size,
+                GptBackupAt::MediumSize(size) if size >= total_size => size,
+                GptBackupAt::MediumSize(size) => {
+                    anyhow::bail!("medium size {size} is smaller than the image ({total_size})")
+                }
+            };
+
+            debug!("Total size: {total_size:x} disk size: {disk_size:x}");
+
+            prepare_image(&mut file, total_size, args.fill_byte, &ctx.random)?;
+            if file.metadata()?.is_file() {
+                file.set_len(disk_size)?;
+            }
+            let file_handle = file.try_clone()?;
+
+            let mbr = gpt::mbr::ProtectiveMBR::with_lb_size(
+                u32::try_from((disk_size / sector) - 1).unwrap_or(0xFF_FF_FF_FF),
+            );
+            mbr.overwrite_lba0(&mut file).expect("failed to write MBR");
+
+            let mut gdisk = gpt::GptConfig::default()
+                .initialized(false)
+                .writable(true)
+                .logical_block_size(match sector {
+                    4096 => gpt::disk::LogicalBlockSize::Lb4096,
+                    _ => gpt::disk::LogicalBlockSize::Lb512,
+                })
+                .create_from_device(Box::new(file), Some(random_guid(&ctx.random)?.parse()?))?;
+
+            let arch = args.arch.or_else(part_type::Arch::host);
+            let mut entries = std::collections::BTreeMap::new();
+
+            for (i, (part, &(start, len))) in parts.iter().zip(placed).enumerate() {
+                let flags = if part.bootable {
+                    part.attributes | gpt::partition::PartitionAttributes::BOOTABLE.bits()
+                } else {
+                    part.attributes
+                };
+
+                entries.insert(
+                    i as u32 + 1,
+                    gpt::partition::Partition {
+                        part_type_guid: part.gpt_type.to_type(arch)?,
+                        part_guid: match &part.uuid {
+                            Some(uuid) => uuid.parse()?,
+                            None => random_guid(&ctx.random)?.parse()?,
+                        },
+                        first_lba: start / sector,
+                        last_lba: (start + len) / sector - 1,
+                        flags,
+                        name: part.name.clone(),
+                    },
+                );
+            }
+
+            gdisk.update_partitions(entries)?;
+            gdisk.write()?;
+
+            if matches!(args.partition_table, PartitionTable::Hybrid) {
+                let mut file = file_handle.try_clone()?;
+                let mut mbr = mbrman::MBR::new_from(
+                    &mut file,
+                    sector as u32,
+                    args.disk_id.signature(&ctx.random)?,
+                )?;
+
+                let mirrored = parts.iter().zip(placed).take(3);
+                for (i, (part, &(start, len))) in mirrored.enumerate() {
+                    mbr[i + 1] = mbr_entry(part, start, len, sector, mbr_type(part))?;
+                }
+
+                // Protective entry after the mirrored ones, over the GPT header and entries
+                mbr[parts.len().min(3) + 1] = mbrman::MBRPartitionEntry {
+                    boot: mbrman::BOOT_INACTIVE,
+                    first_chs: mbrman::CHS::empty(),
+                    sys: 0xee,
+                    last_chs: mbrman::CHS::empty(),
+                    starting_lba: 1,
+                    sectors: (head / sector - 1) as u32,
+                };
+
+                mbr.write_into(&mut file)?;
+            }
+
+            if disk_size != total_size {
+                info!(
+                    "Backup GPT header is at {:x}, outside of the image",
+                    disk_size - sector
+                );
+                file_handle.set_len(total_size)?;
+            }
+
+            file_handle
+        }
+    };
+
+    Ok(file)
+}
+
+/// Size the image file, and explicitly write its contents if a fill is requested.
+fn prepare_image(
+    file: &mut File,
+    len: u64,
+    fill: Option<Fill>,
+    random: &random::Random,
+) -> io::Result<()> {
+    if file.metadata()?.is_file() {
+        file.set_len(len)?;
+    } else if file.seek(io::SeekFrom::End(0))? < len {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("device is smaller than the image ({len} bytes)"),
+        ));
+    } else {
+        file.rewind()?;
+    }
+
+    let Some(fill) = fill else {
+        return Ok(());
+    };
+
+    let mut buf = vec![0; 0x100000];
+    let fill_random = match fill {
+        Fill::Byte(b) => {
+            buf.fill(b);
+            false
+        }
+        Fill::Random => true,
+    };
+
+    let mut remaining = len;
+
+    while remaining > 0 {
+        let n = remaining.min(buf.len() as u64) as usize;
+        if fill_random {
+            random.fill(&mut buf[..n])?;
+        }
+        file.write_all(&buf[..n])?;
+        remaining -= n as u64;
+    }
+
+    file.rewind()
+}
+
+/// Partition numbers in an MBR. Primary partitions and the extended partition take the four
+/// slots of the table, and logical partitions inside the extended one are numbered from 5.
+fn mbr_numbers(parts: &[layout::Partition]) -> anyhow::Result<Vec<u32>> {
+    let mut numbers = vec![];
+    let (mut slot, mut next_logical) = (1, 5);
+    let mut extended = false;
+    let mut prev_logical = false;
+
+    for (i, part) in parts.iter().enumerate() {
+        let logical = match part.primary {
+            Some(primary) => !primary,
+            None => parts.len() > 4 && i >= 3,
+        };
+
+        if logical {
+            if !prev_logical {
+                if extended {
+                    anyhow::bail!(
+                        "logical partitions must follow each other, unlike `{}`",
+                        part.name
+                    );
+                }
+                extended = true;
+                slot += 1;
+            }
+            numbers.push(next_logical);
+            next_logical += 1;
+        } else {
+            numbers.push(slot);
+            slot += 1;
+        }
+
+        prev_logical = logical;
+    }
+
+    if slot > 5 {
+        anyhow::bail!("MBR partition tables hold at most 4 primary and extended partitions");
+    }
+
+    Ok(numbers)
+}
@@ -0,0 +1,278 @@
+//! EROFS images, uncompressed.
+//!
+//! Every inode uses the extended on-disk form, so modification times and large files are kept,
+//! and all data is stored in plain contiguous blocks, including the targets of symbolic links.
+
+use crate::tree::{Kind, Tree};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+const MAGIC: u32 = 0xE0F5_E1E2;
+const BLOCK_SIZE: u64 = 4096;
+const SUPERBLOCK_OFFSET: u64 = 1024;
+/// Block the inodes start at, right after the superblock
+const META_BLOCK: u64 = 1;
+const INODE_SIZE: u64 = 64;
+/// Inodes are addressed in units of this
+const NID_SIZE: u64 = 32;
+const DIRENT_SIZE: usize = 12;
+
+/// Extended inode, flat plain data layout
+const FORMAT_EXTENDED: u16 = 1;
+
+const FT_REG_FILE: u8 = 1;
+const FT_DIR: u8 = 2;
+const FT_SYMLINK: u8 = 7;
+
+const S_IFDIR: u16 = 0o040000;
+const S_IFREG: u16 = 0o100000;
+const S_IFLNK: u16 = 0o120000;
+
+#[derive(Clone, Debug, Default)]
+pub struct Options {
+    pub label: Option<String>,
+}
+
+fn nid(idx: usize) -> u64 {
+    idx as u64 * INODE_SIZE / NID_SIZE
+}
+
+/// Directory entry before it is placed into a block.
+struct Entry<'a> {
+    name: &'a [u8],
+    nid: u64,
+    file_type: u8,
+}
+
+/// Directory contents, packed into blocks. Returns the blocks and the size of the directory.
+fn dir_blocks(tree: &Tree, idx: usize) -> (Vec<Vec<u8>>, u64) {
+    let node = &tree.nodes[idx];
+    let Kind::Dir(children) = &node.kind else {
+        return (vec![], 0);
+    };
+
+    let mut entries = vec![
+        Entry {
+            name: b".",
+            nid: nid(idx),
+            file_type: FT_DIR,
+        },
+        Entry {
+            name: b"..",
+            nid: nid(node.parent),
+            file_type: FT_DIR,
+        },
+    ];
+    entries.extend(children.iter().map(|&c| Entry {
+        name: tree.nodes[c].name.as_bytes(),
+        nid: nid(c),
+        file_type: match tree.nodes[c].kind {
+            Kind::Dir(_) => FT_DIR,
+            Kind::File { .. } => FT_REG_FILE,
+            Kind::Symlink(_) => FT_SYMLINK,
+        },
+    }));
+    // Lookups binary search the names, including . and ..
+    entries.sort_by(|a, b| a.name.cmp(b.name));
+
+    let mut blocks = vec![];
+    let mut rest = &entries[..];
+    let mut last_len = 0;
+
+    while !rest.is_empty() {
+        let mut used = 0;
+        let count = rest
+            .iter()
+            .take_while(|e| {
+                used += DIRENT_SIZE + e.name.len();
+                used <= BLOCK_SIZE as usize
+            })
+            .count();
+
+        let (chunk, tail) = rest.split_at(count);
+        let mut block = vec![0u8; BLOCK_SIZE as usize];
+        let mut name_off = DIRENT_SIZE * chunk.len();
+
+        for (i, e) in chunk.iter().enumerate() {
+            let d = &mut block[i * DIRENT_SIZE..(i + 1) * DIRENT_SIZE];
+            d[0..8].copy_from_slice(&e.nid.to_le_bytes());
+            d[8..10].copy_from_slice(&(name_off as u16).to_le_bytes());
+            d[10] = e.file_type;
+            block[name_off..name_off + e.name.len()].copy_from_slice(e.name);
+            name_off += e.name.len();
+        }
+
+        last_len = name_off as u64;
+        blocks.push(block);
+        rest = tail;
+    }
+
+    let size = (blocks.len() as u64 - 1) * BLOCK_SIZE + last_len;
+    (blocks, size)
+}
+
+/// Placement of directory and file data, in blocks.
+struct Layout {
+    /// First data block and size of every node
+    data: Vec<(u64, u64)>,
+    blocks: u64,
+}
+
+impl Layout {
+    fn new(tree: &Tree) -> anyhow::Result<Self> {
+        for node in &tree.nodes[1..] {
+            if node.name.len() > 255 {
+                anyhow::bail!(
+                    "EROFS names are limited to 255 bytes: {}",
+                    node.path.display()
+                );
+            }
+        }
+
+        let inode_blocks = (tree.nodes.len() as u64 * INODE_SIZE).div_ceil(BLOCK_SIZE);
+        let mut next = META_BLOCK + inode_blocks;
+
+        let data = tree
+            .nodes
+            .iter()
+            .enumerate()
+            .map(|(idx, node)| {
+                let size = match &node.kind {
+                    Kind::Dir(_) => dir_blocks(tree, idx).1,
+                    Kind::File { len, .. } => *len,
+                    Kind::Symlink(target) => target.len() as u64,
+                };
+                let start = next;
+                next += size.div_ceil(BLOCK_SIZE);
+                (start, size)
+            })
+            .collect();
+
+        if next > u32::MAX as u64 {
+            anyhow::bail!("EROFS images are limited to {} blocks", u32::MAX);
+        }
+
+        Ok(Self { data, blocks: next })
+    }
+}
+
+/// Smallest filesystem size the tree fits in.
+pub fn estimate_size(tree: &Tree) -> anyhow::Result<u64> {
+    Ok(Layout::new(tree)?.blocks * BLOCK_SIZE)
+}
+
+fn write_at<W: Write + Seek>(out: &mut W, offset: u64, data: &[u8]) -> io::Result<()> {
+    out.seek(SeekFrom::Start(offset))?;
+    out.write_all(data)
+}
+
+/// Write the image into `out`, which spans `size` bytes.
+///
+/// `on_file` is called with the image path and length of every file written.
+pub fn write<W: Write + Seek>(
+    out: &mut W,
+    size: u64,
+    tree: &Tree,
+    opts: &Options,
+    ctx: &crate::BuildContext,
+    on_file: &mut dyn FnMut(&Path, u64),
+) -> anyhow::Result<()> {
+    let layout = Layout::new(tree)?;
+
+    if layout.blocks * BLOCK_SIZE > size {
+        anyhow::bail!(
+            "EROFS image needs {} bytes, but only {size} are available",
+            layout.blocks * BLOCK_SIZE
+        );
+    }
+
+    let label = opts.label.as_deref().unwrap_or_default();
+    if label.len() > 16 {
+        anyhow::bail!("EROFS volume label is longer than 16 bytes");
+    }
+
+    // Inodes
+    let mut inodes = vec![0u8; (tree.nodes.len() as u64 * INODE_SIZE) as usize];
+    for (idx, node) in tree.nodes.iter().enumerate() {
+        let (mode, nlink) = match &node.kind {
+            Kind::Dir(children) => (
+                S_IFDIR,
+                2 + children.iter().filter(|&&c| tree.nodes[c].is_dir()).count() as u32,
+            ),
+            Kind::File { .. } => (S_IFREG, 1),
+            Kind::Symlink(_) => (S_IFLNK, 1),
+        };
+        let (start, size) = layout.data[idx];
+
+        let i = &mut inodes[idx * INODE_SIZE as usize..(idx + 1) * INODE_SIZE as usize];
+        i[0..2].copy_from_slice(&FORMAT_EXTENDED.to_le_bytes());
+        i[4..6].copy_from_slice(&(mode | (node.mode as u16 & 0o7777)).to_le_bytes());
+        i[8..16].copy_from_slice(&size.to_le_bytes());
+        i[16..20].copy_from_slice(&(start as u32).to_le_bytes());
+        i[20..24].copy_from_slice(&(idx as u32 + 1).to_le_bytes());
+        i[24..28].copy_from_slice(&node.uid.to_le_bytes());
+        i[28..32].copy_from_slice(&node.gid.to_le_bytes());
+        i[32..40].copy_from_slice(&node.mtime.to_le_bytes());
+        i[44..48].copy_from_slice(&nlink.to_le_bytes());
+    }
+    write_at(out, META_BLOCK * BLOCK_SIZE, &inodes)?;
+
+    // Directories and files
+    for (idx, node) in tree.nodes.iter().enumerate() {
+        let (start, _) = layout.data[idx];
+
+        match &node.kind {
+            Kind::Dir(_) => {
+                let (blocks, _) = dir_blocks(tree, idx);
+                write_at(out, start * BLOCK_SIZE, &blocks.concat())?;
+            }
+            Kind::File { source, len } => {
+                if *len > 0 {
+                    out.seek(SeekFrom::Start(start * BLOCK_SIZE))?;
+                    let copied = io::copy(&mut source.open()?.take(*len), out)?;
+                    if copied != *len {
+                        anyhow::bail!("{} changed while being copied", node.path.display());
+                    }
+                }
+                on_file(&node.path, *len);
+            }
+            Kind::Symlink(target) => write_at(out, start * BLOCK_SIZE, target.as_bytes())?,
+        }
+    }
+
+    // Superblock
+    let mut uuid = [0u8; 16];
+    ctx.random.fill(&mut uuid)?;
+    let time = ctx.time;
+
+    let mut sb = [0u8; 128];
+    sb[0..4].copy_from_slice(&MAGIC.to_le_bytes());
+    sb[12] = BLOCK_SIZE.trailing_zeros() as u8;
+    sb[14..16].copy_from_slice(&(nid(0) as u16).to_le_bytes());
+    sb[16..24].copy_from_slice(&(tree.nodes.len() as u64).to_le_bytes());
+    sb[24..32].copy_from_slice(&time.timestamp().to_le_bytes());
+    sb[36..40].copy_from_slice(&(layout.blocks as u32).to_le_bytes());
+    sb[40..44].copy_from_slice(&(META_BLOCK as u32).to_le_bytes());
+    sb[48..64].copy_from_slice(&uuid);
+    sb[64..64 + label.len()].copy_from_slice(label.as_bytes());
+    write_at(out, SUPERBLOCK_OFFSET, &sb)?;
+
+    Ok(())
+}
+
+impl crate::FsWriter for Options {
+    fn estimate_size(&self, tree: &Tree) -> anyhow::Result<u64> {
+        estimate_size(tree)
+    }
+
+    fn write(
+        &self,
+        mut out: &mut dyn fatfs::ReadWriteSeek,
+        size: u64,
+        tree: &Tree,
+        ctx: &crate::BuildContext,
+        on_file: &mut dyn FnMut(&Path, u64),
+    ) -> anyhow::Result<()> {
+        write(&mut out, size, tree, self, ctx, on_file)
+    }
+}
@@ -0,0 +1,524 @@
+//! exFAT filesystem images.
+//!
+//! Files and directories are stored contiguously and marked as such, so only the allocation
+//! bitmap, up-case table and root directory need chains in the FAT.
+
+use crate::tree::{Kind, Tree};
+use chrono::{Datelike, TimeZone, Timelike, Utc};
+use std::collections::HashSet;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+const SECTOR_SIZE: u64 = 512;
+const FAT_OFFSET: u64 = 128;
+const FIRST_CLUSTER: u32 = 2;
+const ENTRY_SIZE: usize = 32;
+
+const ATTR_DIRECTORY: u16 = 0x10;
+const ATTR_ARCHIVE: u16 = 0x20;
+
+const FLAG_ALLOCATION_POSSIBLE: u8 = 0x1;
+const FLAG_NO_FAT_CHAIN: u8 = 0x2;
+
+#[derive(Clone, Debug, Default)]
+pub struct Options {
+    pub label: Option<String>,
+}
+
+fn cluster_size_for(size: u64) -> u64 {
+    match size {
+        0..=0xfffffff => 4 << 10,
+        0x10000000..=0x7ffffffff => 32 << 10,
+        _ => 128 << 10,
+    }
+}
+
+/// Up-case mapping of a UTF-16 code unit.
+fn upcase(c: u16) -> u16 {
+    let Some(ch) = char::from_u32(c as u32) else {
+        return c;
+    };
+
+    let mut up = ch.to_uppercase();
+    match (up.next(), up.next()) {
+        (Some(u), None) if (u as u32) <= 0xffff => u as u16,
+        _ => c,
+    }
+}
+
+/// Up-case table, with runs of unchanged characters compressed.
+fn upcase_table() -> Vec<u8> {
+    let mut table = vec![];
+    let mut c = 0u32;
+
+    while c <= 0xffff {
+        let run = (c..=0xffff)
+            .take_while(|&i| upcase(i as u16) == i as u16)
+            .count() as u32;
+
+        if run > 2 {
+            table.extend_from_slice(&0xffffu16.to_le_bytes());
+            table.extend_from_slice(&(run as u16).to_le_bytes());
+            c += run;
+        } else {
+            table.extend_from_slice(&upcase(c as u16).to_le_bytes());
+            c += 1;
+        }
+    }
+
+    table
+}
+
+fn checksum32(data: &[u8], skip: &[usize], mut sum: u32) -> u32 {
+    for (i, &b) in data.iter().enumerate() {
+        if !skip.contains(&i) {
+            sum = sum.rotate_right(1).wrapping_add(b as u32);
+        }
+    }
+    sum
+}
+
+fn checksum16(data: &[u8], skip: &[usize]) -> u16 {
+    let mut sum = 0u16;
+    for (i, &b) in data.iter().enumerate() {
+        if !skip.contains(&i) {
+            sum = sum.rotate_right(1).wrapping_add(b as u16);
+        }
+    }
+    sum
+}
+
+/// Directory entries taken by a file named `name`.
+fn entry_count(name: &str) -> usize {
+    2 + name.encode_utf16().count().div_ceil(15)
+}
+
+fn timestamp(time: i64) -> u32 {
+    let time = Utc
+        .timestamp_opt(time, 0)
+        .single()
+        .unwrap_or_default()
+        .max(Utc.with_ymd_and_hms(1980, 1, 1, 0, 0, 0).unwrap());
+
+    ((time.year() as u32 - 1980).min(127) << 25)
+        | (time.month() << 21)
+        | (time.day() << 16)
+        | (time.hour() << 11)
+        | (time.minute() << 5)
+        | (time.second() / 2)
+}
+
+fn file_entries(name: &str, attrs: u16, mtime: i64, first_cluster: u32, len: u64) -> Vec<u8> {
+    let name16 = name.encode_utf16().collect::<Vec<_>>();
+    let count = entry_count(name);
+    let mut set = vec![0u8; count * ENTRY_SIZE];
+
+    let ts = timestamp(mtime);
+    let f = &mut set[..ENTRY_SIZE];
+    f[0] = 0x85;
+    f[1] = (count - 1) as u8;
+    f[4..6].copy_from_slice(&attrs.to_le_bytes());
+    for off in [8, 12, 16] {
+        f[off..off + 4].copy_from_slice(&ts.to_le_bytes());
+    }
+    f[20] = (mtime.rem_euclid(2) * 100) as u8;
+    f[21] = f[20];
+    // UTC
+    f[22..25].fill(0x80);
+
+    let hash = name16
+        .iter()
+        .flat_map(|&c| upcase(c).to_le_bytes())
+        .fold(0u16, |h, b| h.rotate_right(1).wrapping_add(b as u16));
+
+    let s = &mut set[ENTRY_SIZE..ENTRY_SIZE * 2];
+    s[0] = 0xc0;
+    s[1] = if len == 0 {
+        FLAG_ALLOCATION_POSSIBLE
+    } else {
+        FLAG_ALLOCATION_POSSIBLE | FLAG_NO_FAT_CHAIN
+    };
+    s[3] = name16.len() as u8;
+    s[4..6].copy_from_slice(&hash.to_le_bytes());
+    s[8..16].copy_from_slice(&len.to_le_bytes());
+    s[20..24].copy_from_slice(&first_cluster.to_le_bytes());
+    s[24..32].copy_from_slice(&len.to_le_bytes());
+
+    for (i, chunk) in name16.chunks(15).enumerate() {
+        let n = &mut set[ENTRY_SIZE * (2 + i)..ENTRY_SIZE * (3 + i)];
+        n[0] = 0xc1;
+        for (j, c) in chunk.iter().enumerate() {
+            n[2 + j * 2..4 + j * 2].copy_from_slice(&c.to_le_bytes());
+        }
+    }
+
+    let sum = checksum16(&set, &[2, 3]);
+    set[2..4].copy_from_slice(&sum.to_le_bytes());
+
+    set
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Layout {
+    cluster_size: u64,
+    volume_sectors: u64,
+    fat_sectors: u64,
+    heap_offset: u64,
+    cluster_count: u64,
+}
+
+impl Layout {
+    fn new(size: u64) -> anyhow::Result<Self> {
+        let cluster_size = cluster_size_for(size);
+        let spc = cluster_size / SECTOR_SIZE;
+        let volume_sectors = size / SECTOR_SIZE;
+
+        let mut cluster_count = volume_sectors / spc;
+        loop {
+            let fat_sectors = ((cluster_count + 2) * 4).div_ceil(SECTOR_SIZE);
+            let heap_offset = (FAT_OFFSET + fat_sectors).next_multiple_of(spc);
+            let fits = volume_sectors.saturating_sub(heap_offset) / spc;
+
+            if fits >= cluster_count {
+                if cluster_count < 3 {
+                    anyhow::bail!("partition of {size} bytes is too small for exFAT");
+                }
+                if cluster_count > 0xfffffff5 - 2 {
+                    anyhow::bail!("partition of {size} bytes is too large for exFAT");
+                }
+                return Ok(Self {
+                    cluster_size,
+                    volume_sectors,
+                    fat_sectors,
+                    heap_offset,
+                    cluster_count,
+                });
+            }
+
+            cluster_count = fits;
+        }
+    }
+
+    fn clusters(&self, len: u64) -> u64 {
+        len.div_ceil(self.cluster_size)
+    }
+
+    fn offset(&self, cluster: u32) -> u64 {
+        (self.heap_offset + (cluster - FIRST_CLUSTER) as u64 * (self.cluster_size / SECTOR_SIZE))
+            * SECTOR_SIZE
+    }
+}
+
+fn check_names(tree: &Tree) -> anyhow::Result<()> {
+    for node in &tree.nodes {
+        if node.name.encode_utf16().count() > 255 {
+            anyhow::bail!("file name too long for exFAT: {}", node.path.display());
+        }
+
+        let Kind::Dir(children) = &node.kind else {
+            continue;
+        };
+
+        let mut seen = HashSet::new();
+        for &c in children {
+            let name = tree.nodes[c]
+                .name
+                .encode_utf16()
+                .map(upcase)
+                .collect::<Vec<_>>();
+            if !seen.insert(name) {
+                anyhow::bail!(
+                    "{} differs from another file only in case, exFAT cannot hold both",
+                    tree.nodes[c].path.display()
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Bytes of directory entries in every directory of the tree.
+fn dir_sizes(tree: &Tree, label: bool) -> Vec<u64> {
+    tree.nodes
+        .iter()
+        .enumerate()
+        .map(|(idx, node)| match &node.kind {
+            Kind::Dir(children) => {
+                // Bitmap and up-case table entries in the root
+                let own = if idx == 0 { 2 + label as usize } else { 0 };
+                let entries = own
+                    + children
+                        .iter()
+                        .map(|&c| entry_count(&tree.nodes[c].name))
+                        .sum::<usize>();
+                (entries * ENTRY_SIZE) as u64
+            }
+            Kind::File { .. } | Kind::Symlink(_) => 0,
+        })
+        .collect()
+}
+
+/// Clusters needed for every node, directories getting at least one.
+fn node_clusters(tree: &Tree, layout: &Layout, dir_sizes: &[u64]) -> Vec<u64> {
+    tree.nodes
+        .iter()
+        .zip(dir_sizes)
+        .map(|(node, &dir_size)| match &node.kind {
+            Kind::Dir(_) => layout.clusters(dir_size).max(1),
+            Kind::File { len, .. } => layout.clusters(*len),
+            Kind::Symlink(_) => unreachable!("exFAT has no symbolic links"),
+        })
+        .collect()
+}
+
+fn needed_clusters(tree: &Tree, layout: &Layout, label: bool) -> u64 {
+    let bitmap = layout.clusters(layout.cluster_count.div_ceil(8));
+    let upcase = layout.clusters(upcase_table().len() as u64);
+    let dirs = dir_sizes(tree, label);
+    bitmap + upcase + node_clusters(tree, layout, &dirs).iter().sum::<u64>()
+}
+
+/// Smallest filesystem size the tree fits in.
+pub fn estimate_size(tree: &Tree, label: bool) -> anyhow::Result<u64> {
+    check_names(tree)?;
+
+    let content = tree
+        .nodes
+        .iter()
+        .map(|n| match &n.kind {
+            Kind::File { len, .. } => len.next_multiple_of(4096),
+            Kind::Dir(_) => 4096,
+            Kind::Symlink(_) => unreachable!("exFAT has no symbolic links"),
+        })
+        .sum::<u64>();
+
+    let mut size = (content + (256 << 10)).next_multiple_of(4096);
+
+    loop {
+        if let Ok(layout) = Layout::new(size) {
+            if needed_clusters(tree, &layout, label) <= layout.cluster_count {
+                return Ok(size);
+            }
+        }
+        size = (size + size / 16).next_multiple_of(4096);
+    }
+}
+
+fn write_at<W: Write + Seek>(out: &mut W, offset: u64, data: &[u8]) -> io::Result<()> {
+    out.seek(SeekFrom::Start(offset))?;
+    out.write_all(data)
+}
+
+/// Format `out`, which spans `size` bytes, and fill it with the tree.
+///
+/// `on_file` is called with the image path and length of every file written.
+pub fn write<W: Write + Seek>(
+    out: &mut W,
+    size: u64,
+    tree: &Tree,
+    opts: &Options,
+    ctx: &crate::BuildContext,
+    on_file: &mut dyn FnMut(&Path, u64),
+) -> anyhow::Result<()> {
+    check_names(tree)?;
+
+    let label = opts
+        .label
+        .as_ref()
+        .map(|l| l.encode_utf16().collect::<Vec<_>>());
+
+    if label.as_ref().is_some_and(|l| l.len() > 11) {
+        anyhow::bail!("exFAT volume label is longer than 11 characters");
+    }
+
+    let layout = Layout::new(size)?;
+    let upcase = upcase_table();
+    let dirs = dir_sizes(tree, label.is_some());
+    let clusters = node_clusters(tree, &layout, &dirs);
+
+    let needed = needed_clusters(tree, &layout, label.is_some());
+    if needed > layout.cluster_count {
+        anyhow::bail!(
+            "exFAT filesystem of {size} bytes is too small, {} more clusters are needed",
+            needed - layout.cluster_count
+        );
+    }
+
+    // Allocate everything sequentially
+    let mut next = FIRST_CLUSTER;
+    let mut alloc = |count: u64| {
+        let first = if count == 0 { 0 } else { next };
+        next += count as u32;
+        first
+    };
+
+    let bitmap_len = layout.cluster_count.div_ceil(8);
+    let bitmap_cluster = alloc(layout.clusters(bitmap_len));
+    let upcase_cluster = alloc(layout.clusters(upcase.len() as u64));
+    let first = clusters.iter().map(|&c| alloc(c)).collect::<Vec<_>>();
+    let used = (next - FIRST_CLUSTER) as u64;
+
+    // Directories
+    for (idx, node) in tree.nodes.iter().enumerate() {
+        let Kind::Dir(children) = &node.kind else {
+            continue;
+        };
+
+        let mut data = vec![];
+
+        if idx == 0 {
+            if let Some(label) = &label {
+                let mut e = [0u8; ENTRY_SIZE];
+                e[0] = 0x83;
+                e[1] = label.len() as u8;
+                for (i, c) in label.iter().enumerate() {
+                    e[2 + i * 2..4 + i * 2].copy_from_slice(&c.to_le_bytes());
+                }
+                data.extend_from_slice(&e);
+            }
+
+            let mut e = [0u8; ENTRY_SIZE];
+            e[0] = 0x81;
+            e[20..24].copy_from_slice(&bitmap_cluster.to_le_bytes());
+            e[24..32].copy_from_slice(&bitmap_len.to_le_bytes());
+            data.extend_from_slice(&e);
+
+            let mut e = [0u8; ENTRY_SIZE];
+            e[0] = 0x82;
+            e[4..8].copy_from_slice(&checksum32(&upcase, &[], 0).to_le_bytes());
+            e[20..24].copy_from_slice(&upcase_cluster.to_le_bytes());
+            e[24..32].copy_from_slice(&(upcase.len() as u64).to_le_bytes());
+            data.extend_from_slice(&e);
+        }
+
+        for &c in children {
+            let child = &tree.nodes[c];
+            let (attrs, len) = match &child.kind {
+                Kind::Dir(_) => (ATTR_DIRECTORY, clusters[c] * layout.cluster_size),
+                Kind::File { len, .. } => (ATTR_ARCHIVE, *len),
+                Kind::Symlink(_) => unreachable!("exFAT has no symbolic links"),
+            };
+            data.extend(file_entries(&child.name, attrs, child.mtime, first[c], len));
+        }
+
+        data.resize((clusters[idx] * layout.cluster_size) as usize, 0);
+        write_at(out, layout.offset(first[idx]), &data)?;
+    }
+
+    // Allocation bitmap
+    let mut bitmap = vec![0u8; (layout.clusters(bitmap_len) * layout.cluster_size) as usize];
+    for i in 0..used {
+        bitmap[(i / 8) as usize] |= 1 << (i % 8);
+    }
+    write_at(out, layout.offset(bitmap_cluster), &bitmap)?;
+
+    let mut table = upcase.clone();
+    table.resize(
+        (layout.clusters(upcase.len() as u64) * layout.cluster_size) as usize,
+        0,
+    );
+    write_at(out, layout.offset(upcase_cluster), &table)?;
+
+    // FAT, with chains for the clusters not marked contiguous
+    let mut fat = vec![0u8; (layout.fat_sectors * SECTOR_SIZE) as usize];
+    let mut set = |cluster: u32, value: u32| {
+        fat[cluster as usize * 4..cluster as usize * 4 + 4].copy_from_slice(&value.to_le_bytes());
+    };
+    set(0, 0xfffffff8);
+    set(1, 0xffffffff);
+    for (start, count) in [
+        (bitmap_cluster, layout.clusters(bitmap_len)),
+        (upcase_cluster, layout.clusters(upcase.len() as u64)),
+        (first[0], clusters[0]),
+    ] {
+        for i in 0..count as u32 {
+            let next = if i + 1 == count as u32 {
+                0xffffffff
+            } else {
+                start + i + 1
+            };
+            set(start + i, next);
+        }
+    }
+    write_at(out, FAT_OFFSET * SECTOR_SIZE, &fat)?;
+
+    // Files
+    for (idx, node) in tree.nodes.iter().enumerate() {
+        if let Kind::File { source, len } = &node.kind {
+            if *len > 0 {
+                out.seek(SeekFrom::Start(layout.offset(first[idx])))?;
+                let copied = io::copy(&mut source.open()?.take(*len), out)?;
+                if copied != *len {
+                    anyhow::bail!("{} changed while being copied", node.path.display());
+                }
+            }
+            on_file(&node.path, *len);
+        }
+    }
+
+    // Main and backup boot regions
+    let mut serial = [0u8; 4];
+    ctx.random.fill(&mut serial)?;
+
+    let mut boot = vec![0u8; 12 * SECTOR_SIZE as usize];
+    {
+        let b = &mut boot[..SECTOR_SIZE as usize];
+        b[0..3].copy_from_slice(&[0xeb, 0x76, 0x90]);
+        b[3..11].copy_from_slice(b"EXFAT   ");
+        b[72..80].copy_from_slice(&layout.volume_sectors.to_le_bytes());
+        b[80..84].copy_from_slice(&(FAT_OFFSET as u32).to_le_bytes());
+        b[84..88].copy_from_slice(&(layout.fat_sectors as u32).to_le_bytes());
+        b[88..92].copy_from_slice(&(layout.heap_offset as u32).to_le_bytes());
+        b[92..96].copy_from_slice(&(layout.cluster_count as u32).to_le_bytes());
+        b[96..100].copy_from_slice(&first[0].to_le_bytes());
+        b[100..104].copy_from_slice(&serial);
+        b[104..106].copy_from_slice(&0x0100u16.to_le_bytes());
+        b[108] = SECTOR_SIZE.trailing_zeros() as u8;
+        b[109] = (layout.cluster_size / SECTOR_SIZE).trailing_zeros() as u8;
+        b[110] = 1;
+        b[111] = 0x80;
+        b[112] = (used * 100 / layout.cluster_count) as u8;
+        // No boot code, halt
+        b[120..510].fill(0xf4);
+        b[510..512].copy_from_slice(&[0x55, 0xaa]);
+    }
+    for sector in 1..9 {
+        let off = sector * SECTOR_SIZE as usize;
+        boot[off + 510..off + 512].copy_from_slice(&[0x55, 0xaa]);
+    }
+
+    let mut sum = checksum32(&boot[..SECTOR_SIZE as usize], &[106, 107, 112], 0);
+    sum = checksum32(
+        &boot[SECTOR_SIZE as usize..11 * SECTOR_SIZE as usize],
+        &[],
+        sum,
+    );
+    for chunk in boot[11 * SECTOR_SIZE as usize..].chunks_mut(4) {
+        chunk.copy_from_slice(&sum.to_le_bytes());
+    }
+
+    write_at(out, 0, &boot)?;
+    write_at(out, 12 * SECTOR_SIZE, &boot)?;
+
+    out.flush()?;
+
+    Ok(())
+}
+
+impl crate::FsWriter for Options {
+    fn estimate_size(&self, tree: &Tree) -> anyhow::Result<u64> {
+        // Leave room for a volume label entry
+        estimate_size(tree, true)
+    }
+
+    fn write(
+        &self,
+        mut out: &mut dyn fatfs::ReadWriteSeek,
+        size: u64,
+        tree: &Tree,
+        ctx: &crate::BuildContext,
+        on_file: &mut dyn FnMut(&Path, u64),
+    ) -> anyhow::Result<()> {
+        write(&mut out, size, tree, self, ctx, on_file)
+    }
+}
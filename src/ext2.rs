@@ -0,0 +1,1157 @@
+//! ext2 and ext3 filesystem images.
+//!
+//! Files are mapped with indirect blocks, which every ext driver reads. Features of later
+//! drivers, such as metadata checksums and 64 bit group descriptors, may be turned on.
+//!
+//! The whole layout is computed up front from the directory tree, so blocks are allocated
+//! sequentially and files end up contiguous, apart from the group metadata they span. Files with
+//! the same data, such as hard links of the input or the destinations of an `--alias`, share an
+//! inode.
+
+use crate::tree::{Kind, Tree};
+use crate::FileSource;
+use crc::crc32;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+
+/// Inode size without --ext-inode-size, the original ext2 one
+const INODE_SIZE: u32 = 128;
+/// Size of the inode fields after the original 128 bytes that are filled in
+const EXTRA_ISIZE: u16 = 32;
+const FIRST_INODE: u32 = 11;
+const ROOT_INODE: u32 = 2;
+const JOURNAL_INODE: u32 = 8;
+const LOST_AND_FOUND_INODE: u32 = 11;
+/// One inode for every this many bytes without --ext-bytes-per-inode, like mke2fs
+const BYTES_PER_INODE: u64 = 16384;
+const DIRECT_BLOCKS: u64 = 12;
+
+const COMPAT_HAS_JOURNAL: u32 = 0x4;
+const INCOMPAT_FILETYPE: u32 = 0x2;
+const INCOMPAT_64BIT: u32 = 0x80;
+const RO_COMPAT_SPARSE_SUPER: u32 = 0x1;
+const RO_COMPAT_LARGE_FILE: u32 = 0x2;
+const RO_COMPAT_HUGE_FILE: u32 = 0x8;
+const RO_COMPAT_EXTRA_ISIZE: u32 = 0x40;
+const RO_COMPAT_METADATA_CSUM: u32 = 0x400;
+
+/// Offset of the checksum in group descriptors
+const DESC_CSUM: usize = 0x1e;
+/// Size of the entry at the end of directory blocks holding their checksum
+const DIR_TAIL_LEN: usize = 12;
+
+const S_IFDIR: u16 = 0o040000;
+const S_IFREG: u16 = 0o100000;
+const S_IFLNK: u16 = 0o120000;
+/// Symbolic links shorter than this are stored in the block pointers of the inode
+const FAST_SYMLINK_LEN: usize = 60;
+
+#[derive(Clone, Debug)]
+pub struct Options {
+    /// Add an ext3 journal
+    pub journal: bool,
+    /// Count the blocks of inodes in 48 bits, for files of more than 2 TiB
+    pub huge_file: bool,
+    /// Protect the superblock, group descriptors, bitmaps, inodes and directories with crc32c
+    pub metadata_csum: bool,
+    /// Use 64 byte group descriptors, as for filesystems of more than 2^32 blocks
+    pub bit64: bool,
+    pub inode_size: u32,
+    /// One inode for every this many bytes of the filesystem, or more if the tree needs them
+    pub bytes_per_inode: u64,
+    pub label: Option<String>,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            journal: false,
+            huge_file: false,
+            metadata_csum: false,
+            bit64: false,
+            inode_size: INODE_SIZE,
+            bytes_per_inode: BYTES_PER_INODE,
+            label: None,
+        }
+    }
+}
+
+/// Feature that can be turned on or off, with the name mke2fs gives it.
+#[derive(Clone, Copy, Debug)]
+pub enum Feature {
+    HasJournal,
+    HugeFile,
+    MetadataCsum,
+    Bit64,
+}
+
+impl Feature {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::HasJournal => "has_journal",
+            Self::HugeFile => "huge_file",
+            Self::MetadataCsum => "metadata_csum",
+            Self::Bit64 => "64bit",
+        }
+    }
+}
+
+/// Parse a feature to turn on, or to turn off with a `^` prefix.
+pub fn parse_feature(s: &str) -> Result<(Feature, bool), String> {
+    let (name, on) = match s.strip_prefix('^') {
+        Some(name) => (name, false),
+        None => (s, true),
+    };
+
+    [
+        Feature::HasJournal,
+        Feature::HugeFile,
+        Feature::MetadataCsum,
+        Feature::Bit64,
+    ]
+    .into_iter()
+    .find(|f| f.name() == name)
+    .map(|f| (f, on))
+    .ok_or_else(|| {
+        format!(
+            "unknown ext feature `{name}`, expected has_journal, huge_file, metadata_csum or 64bit"
+        )
+    })
+}
+
+impl Options {
+    pub fn set(&mut self, feature: Feature, on: bool) {
+        match feature {
+            Feature::HasJournal => self.journal = on,
+            Feature::HugeFile => self.huge_file = on,
+            Feature::MetadataCsum => self.metadata_csum = on,
+            Feature::Bit64 => self.bit64 = on,
+        }
+    }
+
+    fn desc_size(&self) -> u64 {
+        if self.bit64 {
+            64
+        } else {
+            32
+        }
+    }
+}
+
+/// crc32c as ext4 chains it, continuing from `crc` and without the final inversion.
+fn csum(crc: u32, data: &[u8]) -> u32 {
+    !crc32::update(!crc, &crc32::CASTAGNOLI_TABLE, data)
+}
+
+/// Checksum seed of the metadata of inode `ino`, with a generation of 0.
+fn inode_seed(seed: u32, ino: u32) -> u32 {
+    csum(csum(seed, &ino.to_le_bytes()), &0u32.to_le_bytes())
+}
+
+fn block_size_for(size: u64) -> u64 {
+    if size < 512 << 20 {
+        1024
+    } else {
+        4096
+    }
+}
+
+/// Journal size in blocks for a filesystem of `blocks` blocks, matching mke2fs defaults.
+fn journal_blocks(blocks: u64) -> Option<u64> {
+    Some(match blocks {
+        0..=2047 => return None,
+        2048..=32767 => 1024,
+        32768..=262143 => 4096,
+        262144..=524287 => 8192,
+        524288..=4194303 => 16384,
+        4194304..=8388607 => 32768,
+        8388608..=16777215 => 65536,
+        16777216..=33554431 => 131072,
+        _ => 262144,
+    })
+}
+
+/// Indirect blocks needed to map `n` data blocks.
+fn indirect_blocks(n: u64, block_size: u64) -> u64 {
+    let per_block = block_size / 4;
+    let mut n = n.saturating_sub(DIRECT_BLOCKS);
+    let mut meta = 0;
+
+    let mut span = 1;
+    for _ in 0..3 {
+        if n == 0 {
+            break;
+        }
+        span *= per_block;
+        let mapped = n.min(span);
+        // One block at the top, then every level below it
+        let mut level = span;
+        while level > 1 {
+            level /= per_block;
+            meta += mapped.div_ceil(level * per_block).max(1);
+        }
+        n -= mapped;
+    }
+
+    meta
+}
+
+/// Serialize directory entries into whole blocks, ending in an entry for the checksum of the
+/// block with `tail`, which is filled in once the block is written.
+fn dir_data(entries: &[(u32, u8, &[u8])], block_size: usize, tail: bool) -> Vec<u8> {
+    let usable = block_size - if tail { DIR_TAIL_LEN } else { 0 };
+    let mut data = vec![0u8; block_size];
+    let mut block_start = 0;
+    let mut last = 0;
+    let mut off = 0;
+
+    for &(inode, file_type, name) in entries {
+        let rec_len = 8 + name.len().div_ceil(4) * 4;
+
+        if off + rec_len > block_start + usable {
+            // Let the previous entry span the rest of its block
+            let len = block_start + usable - last;
+            data[last + 4..last + 6].copy_from_slice(&(len as u16).to_le_bytes());
+            block_start += block_size;
+            off = block_start;
+            data.resize(block_start + block_size, 0);
+        }
+
+        data[off..off + 4].copy_from_slice(&inode.to_le_bytes());
+        data[off + 4..off + 6].copy_from_slice(&(rec_len as u16).to_le_bytes());
+        data[off + 6] = name.len() as u8;
+        data[off + 7] = file_type;
+        data[off + 8..off + 8 + name.len()].copy_from_slice(name);
+
+        last = off;
+        off += rec_len;
+    }
+
+    let len = block_start + usable - last;
+    data[last + 4..last + 6].copy_from_slice(&(len as u16).to_le_bytes());
+
+    if tail {
+        for block in data.chunks_mut(block_size) {
+            let tail = &mut block[usable..];
+            tail[4..6].copy_from_slice(&(DIR_TAIL_LEN as u16).to_le_bytes());
+            tail[7] = 0xde;
+        }
+    }
+
+    data
+}
+
+/// Fill in the checksum in the tail of each directory block of inode `ino`.
+fn set_dir_csums(data: &mut [u8], block_size: usize, seed: u32, ino: u32) {
+    let seed = inode_seed(seed, ino);
+    for block in data.chunks_mut(block_size) {
+        let crc = csum(seed, &block[..block_size - DIR_TAIL_LEN]);
+        block[block_size - 4..].copy_from_slice(&crc.to_le_bytes());
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Layout {
+    block_size: u64,
+    blocks_count: u64,
+    first_data_block: u64,
+    blocks_per_group: u64,
+    groups: u64,
+    inodes_per_group: u64,
+    inode_size: u64,
+    desc_size: u64,
+    gdt_blocks: u64,
+    inode_table_blocks: u64,
+}
+
+impl Layout {
+    fn new(size: u64, inodes: u64, opts: &Options) -> anyhow::Result<Self> {
+        let block_size = block_size_for(size);
+        let mut blocks_count = size / block_size;
+        let first_data_block = (block_size == 1024) as u64;
+        let blocks_per_group = block_size * 8;
+
+        if blocks_count > u32::MAX as u64 {
+            anyhow::bail!("filesystem of {size} bytes is too large for ext2");
+        }
+
+        loop {
+            let groups = (blocks_count.saturating_sub(first_data_block)).div_ceil(blocks_per_group);
+
+            if groups == 0 {
+                anyhow::bail!("partition of {size} bytes is too small for ext2");
+            }
+
+            let inodes = inodes.max(blocks_count * block_size / opts.bytes_per_inode);
+            let inode_size = opts.inode_size as u64;
+            let per_table_block = block_size / inode_size;
+            // Whole bytes of the inode bitmap, which checksums cover
+            let inodes_per_group = inodes
+                .div_ceil(groups)
+                .next_multiple_of(per_table_block.max(8))
+                .max(per_table_block.max(8));
+
+            if inodes_per_group > blocks_per_group {
+                anyhow::bail!("too many files for an ext2 filesystem of {size} bytes");
+            }
+
+            let layout = Self {
+                block_size,
+                blocks_count,
+                first_data_block,
+                blocks_per_group,
+                groups,
+                inodes_per_group,
+                inode_size,
+                desc_size: opts.desc_size(),
+                gdt_blocks: (groups * opts.desc_size()).div_ceil(block_size),
+                inode_table_blocks: inodes_per_group / per_table_block,
+            };
+
+            // A trailing group too small for its own metadata is cut off
+            let last = groups - 1;
+            if layout.group_blocks(last) <= layout.overhead(last) {
+                blocks_count = layout.group_start(last);
+                continue;
+            }
+
+            return Ok(layout);
+        }
+    }
+
+    fn inodes_count(&self) -> u64 {
+        self.inodes_per_group * self.groups
+    }
+
+    fn group_start(&self, group: u64) -> u64 {
+        self.first_data_block + group * self.blocks_per_group
+    }
+
+    fn group_blocks(&self, group: u64) -> u64 {
+        (self.blocks_count - self.group_start(group)).min(self.blocks_per_group)
+    }
+
+    fn has_super(&self, group: u64) -> bool {
+        let is_power = |mut g: u64, base: u64| {
+            while g.is_multiple_of(base) {
+                g /= base;
+            }
+            g == 1
+        };
+        group <= 1 || is_power(group, 3) || is_power(group, 5) || is_power(group, 7)
+    }
+
+    fn overhead(&self, group: u64) -> u64 {
+        let sb = if self.has_super(group) {
+            1 + self.gdt_blocks
+        } else {
+            0
+        };
+        sb + 2 + self.inode_table_blocks
+    }
+
+    fn block_bitmap(&self, group: u64) -> u64 {
+        self.group_start(group) + self.overhead(group) - 2 - self.inode_table_blocks
+    }
+
+    fn inode_bitmap(&self, group: u64) -> u64 {
+        self.block_bitmap(group) + 1
+    }
+
+    fn inode_table(&self, group: u64) -> u64 {
+        self.block_bitmap(group) + 2
+    }
+
+    fn free_data_blocks(&self) -> u64 {
+        (0..self.groups)
+            .map(|g| self.group_blocks(g) - self.overhead(g))
+            .sum()
+    }
+}
+
+/// Sequential block allocator, skipping over group metadata.
+struct Allocator {
+    layout: Layout,
+    group: u64,
+    next: u64,
+    /// Blocks handed out in each group
+    used: Vec<u64>,
+}
+
+impl Allocator {
+    fn new(layout: Layout) -> Self {
+        Self {
+            layout,
+            group: 0,
+            next: layout.group_start(0) + layout.overhead(0),
+            used: vec![0; layout.groups as usize],
+        }
+    }
+
+    fn alloc(&mut self, count: u64) -> io::Result<Vec<u32>> {
+        let mut blocks = Vec::with_capacity(count as usize);
+
+        while (blocks.len() as u64) < count {
+            let layout = &self.layout;
+            let end = layout.group_start(self.group) + layout.group_blocks(self.group);
+
+            if self.next >= end {
+                self.group += 1;
+                if self.group >= layout.groups {
+                    return Err(io::Error::other("ext2 filesystem is full"));
+                }
+                self.next = layout.group_start(self.group) + layout.overhead(self.group);
+                continue;
+            }
+
+            blocks.push(self.next as u32);
+            self.used[self.group as usize] += 1;
+            self.next += 1;
+        }
+
+        Ok(blocks)
+    }
+}
+
+/// Block map of a single inode.
+#[derive(Default)]
+struct BlockMap {
+    i_block: [u32; 15],
+    data: Vec<u32>,
+    /// Contents of indirect blocks
+    indirect: Vec<(u32, Vec<u8>)>,
+}
+
+impl BlockMap {
+    fn allocate(alloc: &mut Allocator, data_blocks: u64) -> io::Result<Self> {
+        let block_size = alloc.layout.block_size;
+        let meta = alloc.alloc(indirect_blocks(data_blocks, block_size))?;
+        let data = alloc.alloc(data_blocks)?;
+
+        let mut map = Self {
+            data,
+            ..Default::default()
+        };
+
+        let direct = map.data.len().min(DIRECT_BLOCKS as usize);
+        map.i_block[..direct].copy_from_slice(&map.data[..direct]);
+
+        let mut pos = direct;
+        let mut meta = meta.into_iter();
+
+        for level in 1..=3 {
+            if pos == map.data.len() {
+                break;
+            }
+            map.i_block[11 + level] = Self::indirect(
+                level,
+                block_size as usize,
+                (&map.data, &mut pos),
+                &mut meta,
+                &mut map.indirect,
+            );
+        }
+
+        Ok(map)
+    }
+
+    /// Fill an indirect block of the given level, returning its number.
+    fn indirect(
+        level: usize,
+        block_size: usize,
+        (data, pos): (&[u32], &mut usize),
+        meta: &mut impl Iterator<Item = u32>,
+        out: &mut Vec<(u32, Vec<u8>)>,
+    ) -> u32 {
+        let block = meta.next().expect("indirect block count mismatch");
+        let idx = out.len();
+        out.push((block, vec![0; block_size]));
+
+        for slot in 0..block_size / 4 {
+            if *pos == data.len() {
+                break;
+            }
+
+            let ptr = if level == 1 {
+                *pos += 1;
+                data[*pos - 1]
+            } else {
+                Self::indirect(level - 1, block_size, (data, pos), meta, out)
+            };
+
+            out[idx].1[slot * 4..slot * 4 + 4].copy_from_slice(&ptr.to_le_bytes());
+        }
+
+        block
+    }
+
+    fn sectors(&self, block_size: u64) -> u64 {
+        (self.data.len() + self.indirect.len()) as u64 * block_size / 512
+    }
+}
+
+struct Inode {
+    mode: u16,
+    uid: u32,
+    gid: u32,
+    size: u64,
+    links: u16,
+    mtime: u32,
+    map: BlockMap,
+    /// Target of a fast symbolic link, in place of the block pointers
+    inline: Option<Vec<u8>>,
+}
+
+impl Inode {
+    /// Raw inode of `inode_size` bytes, without its checksum.
+    fn serialize(&self, block_size: u64, inode_size: u64) -> Vec<u8> {
+        let mut raw = vec![0u8; inode_size as usize];
+        let sectors = self.map.sectors(block_size);
+        raw[0..2].copy_from_slice(&self.mode.to_le_bytes());
+        raw[2..4].copy_from_slice(&(self.uid as u16).to_le_bytes());
+        raw[4..8].copy_from_slice(&(self.size as u32).to_le_bytes());
+        for off in [8, 12, 16] {
+            raw[off..off + 4].copy_from_slice(&self.mtime.to_le_bytes());
+        }
+        raw[24..26].copy_from_slice(&(self.gid as u16).to_le_bytes());
+        raw[26..28].copy_from_slice(&self.links.to_le_bytes());
+        raw[28..32].copy_from_slice(&(sectors as u32).to_le_bytes());
+        match &self.inline {
+            Some(data) => raw[40..40 + data.len()].copy_from_slice(data),
+            None => {
+                for (i, b) in self.map.i_block.iter().enumerate() {
+                    raw[40 + i * 4..44 + i * 4].copy_from_slice(&b.to_le_bytes());
+                }
+            }
+        }
+        raw[108..112].copy_from_slice(&((self.size >> 32) as u32).to_le_bytes());
+        // Only read with huge_file, which is needed once they are set
+        raw[116..118].copy_from_slice(&((sectors >> 32) as u16).to_le_bytes());
+        // High halves of the owner
+        raw[120..122].copy_from_slice(&((self.uid >> 16) as u16).to_le_bytes());
+        raw[122..124].copy_from_slice(&((self.gid >> 16) as u16).to_le_bytes());
+        if inode_size > INODE_SIZE as u64 {
+            raw[128..130].copy_from_slice(&EXTRA_ISIZE.to_le_bytes());
+            // Creation time
+            raw[144..148].copy_from_slice(&self.mtime.to_le_bytes());
+        }
+        raw
+    }
+}
+
+/// Fill in the checksum of raw inode `ino`, of which sizes above the original 128 bytes hold
+/// the high half.
+fn set_inode_csum(raw: &mut [u8], seed: u32, ino: u32) {
+    let crc = csum(inode_seed(seed, ino), raw);
+    raw[124..126].copy_from_slice(&(crc as u16).to_le_bytes());
+    if raw.len() > INODE_SIZE as usize {
+        raw[130..132].copy_from_slice(&((crc >> 16) as u16).to_le_bytes());
+    }
+}
+
+/// Inode numbers assigned to tree nodes, and their directory contents.
+/// What the data of a file is read from, equal for the files that can share an inode.
+#[derive(PartialEq, Eq, Hash)]
+enum Origin<'a> {
+    /// Device and inode of a host file
+    Host(u64, u64),
+    Data(*const u8),
+    Range(&'a Path, u64),
+}
+
+/// Origin of a file, `None` for empty generated data, which has no address of its own.
+fn origin(source: &FileSource) -> io::Result<Option<Origin<'_>>> {
+    Ok(Some(match source {
+        FileSource::Host(path) => {
+            let metadata = fs::metadata(path)?;
+            Origin::Host(metadata.dev(), metadata.ino())
+        }
+        FileSource::Data(data) if data.is_empty() => return Ok(None),
+        FileSource::Data(data) => Origin::Data(data.as_ptr()),
+        FileSource::Range { path, offset, .. } => Origin::Range(path, *offset),
+    }))
+}
+
+/// Inode of every node. Files with the same origin take the inode of the first of them.
+fn number_inodes(tree: &Tree) -> io::Result<Vec<u32>> {
+    let mut next = FIRST_INODE + 1;
+    let mut files = HashMap::new();
+    let mut inodes = vec![ROOT_INODE];
+
+    for node in &tree.nodes[1..] {
+        let ino = match &node.kind {
+            Kind::File { source, .. } => match origin(source)? {
+                Some(origin) => *files.entry(origin).or_insert(next),
+                None => next,
+            },
+            Kind::Dir(_) | Kind::Symlink(_) => next,
+        };
+        if ino == next {
+            next += 1;
+        }
+        inodes.push(ino);
+    }
+
+    Ok(inodes)
+}
+
+/// Whether each node is the first with its inode, rather than a hard link to an earlier one.
+fn inode_owners(inodes: &[u32]) -> Vec<bool> {
+    let mut seen = HashSet::new();
+    inodes.iter().map(|ino| seen.insert(*ino)).collect()
+}
+
+fn dir_contents(tree: &Tree, inodes: &[u32], idx: usize, block_size: u64, tail: bool) -> Vec<u8> {
+    let Kind::Dir(children) = &tree.nodes[idx].kind else {
+        unreachable!()
+    };
+
+    let parent = inodes[tree.nodes[idx].parent];
+
+    let mut entries = vec![(inodes[idx], 2, &b"."[..]), (parent, 2, &b".."[..])];
+
+    if idx == 0 {
+        entries.push((LOST_AND_FOUND_INODE, 2, b"lost+found"));
+    }
+
+    for &c in children {
+        let node = &tree.nodes[c];
+        let file_type = match node.kind {
+            Kind::Dir(_) => 2,
+            Kind::File { .. } => 1,
+            Kind::Symlink(_) => 7,
+        };
+        entries.push((inodes[c], file_type, node.name.as_bytes()));
+    }
+
+    dir_data(&entries, block_size as usize, tail)
+}
+
+fn check_names(tree: &Tree) -> anyhow::Result<()> {
+    for node in &tree.nodes {
+        if node.name.len() > 255 {
+            anyhow::bail!("file name too long for ext2: {}", node.path.display());
+        }
+    }
+    Ok(())
+}
+
+/// Data blocks needed for everything in the tree, excluding the journal.
+fn data_blocks(tree: &Tree, inodes: &[u32], block_size: u64, opts: &Options) -> u64 {
+    let blocks = |n: u64| n + indirect_blocks(n, block_size);
+
+    let mut total = blocks(1); // lost+found
+    let owners = inode_owners(inodes);
+
+    for (idx, node) in tree.nodes.iter().enumerate() {
+        if !owners[idx] {
+            continue;
+        }
+        total += match &node.kind {
+            Kind::Dir(_) => {
+                let data = dir_contents(tree, inodes, idx, block_size, opts.metadata_csum);
+                blocks(data.len() as u64 / block_size)
+            }
+            Kind::File { len, .. } => blocks(len.div_ceil(block_size)),
+            Kind::Symlink(target) if target.len() < FAST_SYMLINK_LEN => 0,
+            Kind::Symlink(target) => blocks((target.len() as u64).div_ceil(block_size)),
+        };
+    }
+
+    total
+}
+
+fn fits(size: u64, tree: &Tree, inodes: &[u32], opts: &Options) -> anyhow::Result<bool> {
+    let used_inodes = FIRST_INODE as u64 + tree.nodes.len() as u64;
+    let Ok(layout) = Layout::new(size, used_inodes, opts) else {
+        return Ok(false);
+    };
+
+    let mut needed = data_blocks(tree, inodes, layout.block_size, opts);
+
+    if opts.journal {
+        let Some(j) = journal_blocks(layout.blocks_count) else {
+            return Ok(false);
+        };
+        needed += j + indirect_blocks(j, layout.block_size);
+    }
+
+    Ok(needed <= layout.free_data_blocks() && used_inodes <= layout.inodes_count())
+}
+
+/// Smallest filesystem size the tree fits in, rounded to whole 4K blocks.
+pub fn estimate_size(tree: &Tree, opts: &Options) -> anyhow::Result<u64> {
+    check_names(tree)?;
+    let inodes = number_inodes(tree)?;
+
+    let content = tree
+        .nodes
+        .iter()
+        .map(|n| match &n.kind {
+            Kind::File { len, .. } => len.next_multiple_of(1024),
+            Kind::Symlink(target) => (target.len() as u64).next_multiple_of(1024),
+            Kind::Dir(_) => 1024,
+        })
+        .sum::<u64>();
+
+    let mut size = (content + content / 16 + (256 << 10)).next_multiple_of(4096);
+
+    // Filesystems count their blocks in 32 bits, so none is larger than this
+    let max = u32::MAX as u64 * 4096;
+    while !fits(size, tree, &inodes, opts)? {
+        if size >= max {
+            anyhow::bail!("the input does not fit in an ext2 filesystem of any size");
+        }
+        size = (size + size / 16).next_multiple_of(4096).min(max);
+    }
+
+    Ok(size)
+}
+
+fn write_at<W: Write + Seek>(
+    out: &mut W,
+    block: u64,
+    block_size: u64,
+    data: &[u8],
+) -> io::Result<()> {
+    out.seek(SeekFrom::Start(block * block_size))?;
+    out.write_all(data)
+}
+
+/// Format `out`, which spans `size` bytes, and fill it with the tree.
+///
+/// `on_file` is called with the image path and length of every file written.
+pub fn write<W: Write + Seek>(
+    out: &mut W,
+    size: u64,
+    tree: &Tree,
+    opts: &Options,
+    ctx: &crate::BuildContext,
+    on_file: &mut dyn FnMut(&Path, u64),
+) -> anyhow::Result<()> {
+    check_names(tree)?;
+    let inodes = number_inodes(tree)?;
+    let owners = inode_owners(&inodes);
+    let layout = Layout::new(size, FIRST_INODE as u64 + tree.nodes.len() as u64, opts)?;
+    let block_size = layout.block_size;
+    let inode_size = layout.inode_size;
+
+    if FIRST_INODE as u64 + tree.nodes.len() as u64 > layout.inodes_count() {
+        anyhow::bail!("too many files for an ext2 filesystem of {size} bytes");
+    }
+
+    let time = ctx.time.timestamp() as u32;
+    let mut alloc = Allocator::new(layout);
+    let mut table = vec![];
+
+    let mut file_links = HashMap::<u32, u16>::new();
+    for (idx, node) in tree.nodes.iter().enumerate() {
+        if node.is_file() {
+            *file_links.entry(inodes[idx]).or_default() += 1;
+        }
+    }
+
+    let links = |idx: usize| match &tree.nodes[idx].kind {
+        Kind::Dir(children) => {
+            let subdirs = children.iter().filter(|&&c| tree.nodes[c].is_dir()).count();
+            (2 + subdirs + (idx == 0) as usize) as u16
+        }
+        Kind::File { .. } => file_links[&inodes[idx]],
+        Kind::Symlink(_) => 1,
+    };
+
+    let mut dir_blocks = vec![];
+    // Entry in the inode table of every node owning its inode
+    let mut slots = vec![None; tree.nodes.len()];
+
+    for (idx, node) in tree.nodes.iter().enumerate() {
+        if !owners[idx] {
+            continue;
+        }
+
+        let (mode, size, data) = match &node.kind {
+            Kind::Dir(_) => {
+                let data = dir_contents(tree, &inodes, idx, block_size, opts.metadata_csum);
+                (S_IFDIR, data.len() as u64, Some(data))
+            }
+            Kind::File { len, .. } => (S_IFREG, *len, None),
+            Kind::Symlink(target) => (S_IFLNK, target.len() as u64, None),
+        };
+
+        let inline = match &node.kind {
+            Kind::Symlink(target) if target.len() < FAST_SYMLINK_LEN => {
+                Some(target.as_bytes().to_vec())
+            }
+            _ => None,
+        };
+        let data_blocks = match inline {
+            Some(_) => 0,
+            None => size.div_ceil(block_size),
+        };
+        let map = BlockMap::allocate(&mut alloc, data_blocks)?;
+
+        if let Some(data) = data {
+            dir_blocks.push((inodes[idx], map.data.clone(), data));
+        }
+
+        slots[idx] = Some(table.len());
+        table.push((
+            inodes[idx],
+            Inode {
+                mode: mode | node.mode as u16,
+                uid: node.uid,
+                gid: node.gid,
+                size,
+                links: links(idx),
+                mtime: node.mtime.clamp(0, u32::MAX as i64) as u32,
+                map,
+                inline,
+            },
+        ));
+    }
+
+    let lost_found = dir_data(
+        &[(LOST_AND_FOUND_INODE, 2, b"."), (ROOT_INODE, 2, b"..")],
+        block_size as usize,
+        opts.metadata_csum,
+    );
+    let map = BlockMap::allocate(&mut alloc, 1)?;
+    dir_blocks.push((LOST_AND_FOUND_INODE, map.data.clone(), lost_found));
+    table.push((
+        LOST_AND_FOUND_INODE,
+        Inode {
+            mode: S_IFDIR | 0o700,
+            uid: 0,
+            gid: 0,
+            size: block_size,
+            links: 2,
+            mtime: time,
+            map,
+            inline: None,
+        },
+    ));
+
+    let journal = if opts.journal {
+        let blocks = journal_blocks(layout.blocks_count)
+            .ok_or_else(|| anyhow::anyhow!("partition of {size} bytes is too small for ext3"))?;
+        let map = BlockMap::allocate(&mut alloc, blocks)?;
+        let data = map.data.clone();
+        table.push((
+            JOURNAL_INODE,
+            Inode {
+                mode: S_IFREG | 0o600,
+                uid: 0,
+                gid: 0,
+                size: blocks * block_size,
+                links: 1,
+                mtime: time,
+                map,
+                inline: None,
+            },
+        ));
+        Some(data)
+    } else {
+        None
+    };
+
+    let mut uuid = [0u8; 16];
+    ctx.random.fill(&mut uuid)?;
+    uuid[6] = (uuid[6] & 0x0f) | 0x40;
+    uuid[8] = (uuid[8] & 0x3f) | 0x80;
+    let seed = csum(!0, &uuid);
+
+    // Inode tables
+    let ipg = layout.inodes_per_group;
+    let mut used_inodes = vec![0u64; layout.groups as usize];
+    let mut used_dirs = vec![0u64; layout.groups as usize];
+    let mut tables =
+        vec![vec![0u8; (layout.inode_table_blocks * block_size) as usize]; layout.groups as usize];
+
+    for (ino, inode) in &table {
+        if inode.map.sectors(block_size) > u32::MAX as u64 && !opts.huge_file {
+            anyhow::bail!("files of 2 TiB or more need the huge_file feature");
+        }
+
+        let idx = (*ino - 1) as u64;
+        let (group, slot) = ((idx / ipg) as usize, (idx % ipg) as usize);
+        let mut raw = inode.serialize(block_size, inode_size);
+        if opts.metadata_csum {
+            set_inode_csum(&mut raw, seed, *ino);
+        }
+        let off = slot * inode_size as usize;
+        tables[group][off..off + inode_size as usize].copy_from_slice(&raw);
+        if inode.mode & 0o170000 == S_IFDIR {
+            used_dirs[group] += 1;
+        }
+    }
+
+    // Inodes are handed out in order, including the reserved ones
+    let max_inode = table.iter().map(|(ino, _)| *ino as u64).max().unwrap_or(0);
+    for (g, used) in used_inodes.iter_mut().enumerate() {
+        *used = max_inode.saturating_sub(g as u64 * ipg).min(ipg);
+    }
+
+    let free_blocks = (0..layout.groups)
+        .map(|g| layout.group_blocks(g) - layout.overhead(g) - alloc.used[g as usize])
+        .collect::<Vec<_>>();
+
+    // Block and inode bitmaps
+    let bitmaps = (0..layout.groups)
+        .map(|g| {
+            let used_blocks = layout.overhead(g) + alloc.used[g as usize];
+            let mut blocks = vec![0u8; block_size as usize];
+            for bit in (0..used_blocks).chain(layout.group_blocks(g)..block_size * 8) {
+                blocks[(bit / 8) as usize] |= 1 << (bit % 8);
+            }
+
+            let mut inodes = vec![0u8; block_size as usize];
+            for bit in (0..used_inodes[g as usize]).chain(ipg..block_size * 8) {
+                inodes[(bit / 8) as usize] |= 1 << (bit % 8);
+            }
+
+            (blocks, inodes)
+        })
+        .collect::<Vec<_>>();
+
+    // Group descriptors
+    let desc_size = layout.desc_size as usize;
+    let mut gdt = vec![0u8; (layout.gdt_blocks * block_size) as usize];
+    for g in 0..layout.groups {
+        let d = &mut gdt[g as usize * desc_size..(g as usize + 1) * desc_size];
+        d[0..4].copy_from_slice(&(layout.block_bitmap(g) as u32).to_le_bytes());
+        d[4..8].copy_from_slice(&(layout.inode_bitmap(g) as u32).to_le_bytes());
+        d[8..12].copy_from_slice(&(layout.inode_table(g) as u32).to_le_bytes());
+        d[12..14].copy_from_slice(&(free_blocks[g as usize] as u16).to_le_bytes());
+        d[14..16].copy_from_slice(&((ipg - used_inodes[g as usize]) as u16).to_le_bytes());
+        d[16..18].copy_from_slice(&(used_dirs[g as usize] as u16).to_le_bytes());
+
+        if opts.metadata_csum {
+            let (blocks, inodes) = &bitmaps[g as usize];
+            let block_crc = csum(seed, blocks);
+            let inode_crc = csum(seed, &inodes[..(ipg / 8) as usize]);
+            d[0x18..0x1a].copy_from_slice(&(block_crc as u16).to_le_bytes());
+            d[0x1a..0x1c].copy_from_slice(&(inode_crc as u16).to_le_bytes());
+            if desc_size == 64 {
+                d[0x38..0x3a].copy_from_slice(&((block_crc >> 16) as u16).to_le_bytes());
+                d[0x3a..0x3c].copy_from_slice(&((inode_crc >> 16) as u16).to_le_bytes());
+            }
+
+            let crc = csum(csum(seed, &(g as u32).to_le_bytes()), d);
+            d[DESC_CSUM..DESC_CSUM + 2].copy_from_slice(&(crc as u16).to_le_bytes());
+        }
+    }
+
+    // Superblock
+    let mut sb = [0u8; 1024];
+    {
+        let mut put = |off: usize, v: u32| sb[off..off + 4].copy_from_slice(&v.to_le_bytes());
+        put(0, layout.inodes_count() as u32);
+        put(4, layout.blocks_count as u32);
+        put(12, free_blocks.iter().sum::<u64>() as u32);
+        put(16, (layout.inodes_count() - max_inode) as u32);
+        put(20, layout.first_data_block as u32);
+        put(24, (block_size / 1024).trailing_zeros());
+        put(28, (block_size / 1024).trailing_zeros());
+        put(32, layout.blocks_per_group as u32);
+        put(36, layout.blocks_per_group as u32);
+        put(40, ipg as u32);
+        put(48, time);
+        put(64, time);
+        put(76, 1);
+        put(84, FIRST_INODE);
+        put(92, if opts.journal { COMPAT_HAS_JOURNAL } else { 0 });
+        put(
+            96,
+            INCOMPAT_FILETYPE | if opts.bit64 { INCOMPAT_64BIT } else { 0 },
+        );
+        let mut ro_compat = RO_COMPAT_SPARSE_SUPER | RO_COMPAT_LARGE_FILE;
+        if opts.huge_file {
+            ro_compat |= RO_COMPAT_HUGE_FILE;
+        }
+        if inode_size > INODE_SIZE as u64 {
+            ro_compat |= RO_COMPAT_EXTRA_ISIZE;
+        }
+        if opts.metadata_csum {
+            ro_compat |= RO_COMPAT_METADATA_CSUM;
+        }
+        put(100, ro_compat);
+        put(264, time);
+        if opts.journal {
+            put(224, JOURNAL_INODE);
+        }
+    }
+    sb[54..56].copy_from_slice(&(-1i16).to_le_bytes());
+    sb[56..58].copy_from_slice(&0xef53u16.to_le_bytes());
+    sb[58..60].copy_from_slice(&1u16.to_le_bytes());
+    sb[60..62].copy_from_slice(&1u16.to_le_bytes());
+    sb[88..90].copy_from_slice(&(inode_size as u16).to_le_bytes());
+    sb[104..120].copy_from_slice(&uuid);
+    if opts.bit64 {
+        sb[254..256].copy_from_slice(&(desc_size as u16).to_le_bytes());
+    }
+    if inode_size > INODE_SIZE as u64 {
+        // Extra inode fields all inodes have, and new ones should get
+        sb[348..350].copy_from_slice(&EXTRA_ISIZE.to_le_bytes());
+        sb[350..352].copy_from_slice(&EXTRA_ISIZE.to_le_bytes());
+    }
+    if opts.metadata_csum {
+        // crc32c
+        sb[0x175] = 1;
+    }
+
+    if let Some(label) = &opts.label {
+        if label.len() > 16 {
+            anyhow::bail!("ext2 volume label `{label}` is longer than 16 bytes");
+        }
+        sb[120..120 + label.len()].copy_from_slice(label.as_bytes());
+    }
+
+    if let Some((_, inode)) = table.iter().find(|(ino, _)| *ino == JOURNAL_INODE) {
+        // Backup of the journal inode block map, so e2fsck can recover it
+        sb[253] = 1;
+        for (i, b) in inode.map.i_block.iter().enumerate() {
+            sb[268 + i * 4..272 + i * 4].copy_from_slice(&b.to_le_bytes());
+        }
+        sb[328..332].copy_from_slice(&((inode.size >> 32) as u32).to_le_bytes());
+        sb[332..336].copy_from_slice(&(inode.size as u32).to_le_bytes());
+    }
+
+    // Group metadata
+    for g in 0..layout.groups {
+        let start = layout.group_start(g);
+
+        if layout.has_super(g) {
+            let mut sb = sb;
+            sb[90..92].copy_from_slice(&(g as u16).to_le_bytes());
+            if opts.metadata_csum {
+                let crc = csum(!0, &sb[..0x3fc]);
+                sb[0x3fc..].copy_from_slice(&crc.to_le_bytes());
+            }
+            if start == 0 {
+                out.seek(SeekFrom::Start(1024))?;
+                out.write_all(&sb)?;
+            } else {
+                let mut block = vec![0u8; block_size as usize];
+                block[..1024].copy_from_slice(&sb);
+                write_at(out, start, block_size, &block)?;
+            }
+            write_at(out, start + 1, block_size, &gdt)?;
+        }
+
+        let (blocks, inodes) = &bitmaps[g as usize];
+        write_at(out, layout.block_bitmap(g), block_size, blocks)?;
+        write_at(out, layout.inode_bitmap(g), block_size, inodes)?;
+
+        write_at(out, layout.inode_table(g), block_size, &tables[g as usize])?;
+    }
+
+    for (_, inode) in &table {
+        for (block, data) in &inode.map.indirect {
+            write_at(out, *block as u64, block_size, data)?;
+        }
+    }
+
+    for (ino, blocks, data) in &mut dir_blocks {
+        if opts.metadata_csum {
+            set_dir_csums(data, block_size as usize, seed, *ino);
+        }
+        for (block, chunk) in blocks.iter().zip(data.chunks(block_size as usize)) {
+            write_at(out, *block as u64, block_size, chunk)?;
+        }
+    }
+
+    if let Some(blocks) = journal {
+        let mut jsb = vec![0u8; block_size as usize];
+        let mut put = |off: usize, v: u32| jsb[off..off + 4].copy_from_slice(&v.to_be_bytes());
+        put(0, 0xc03b3998);
+        // Superblock v2
+        put(4, 4);
+        put(12, block_size as u32);
+        put(16, blocks.len() as u32);
+        put(20, 1);
+        put(24, 1);
+        put(64, 1);
+        jsb[48..64].copy_from_slice(&uuid);
+
+        write_blocks(
+            out,
+            &blocks,
+            block_size,
+            &mut jsb.as_slice().chain(io::repeat(0)),
+        )?;
+    }
+
+    for (idx, node) in tree.nodes.iter().enumerate() {
+        let Some(slot) = slots[idx] else {
+            // Hard links to a file written before
+            if let Kind::File { len, .. } = &node.kind {
+                on_file(&node.path, *len);
+            }
+            continue;
+        };
+        let (_, inode) = &table[slot];
+        match &node.kind {
+            Kind::File { source, len } => {
+                let mut reader = source.open()?.take(*len).chain(io::repeat(0));
+                write_blocks(out, &inode.map.data, block_size, &mut reader)?;
+                on_file(&node.path, *len);
+            }
+            Kind::Symlink(target) => {
+                let mut reader = target.as_bytes().chain(io::repeat(0));
+                write_blocks(out, &inode.map.data, block_size, &mut reader)?;
+            }
+            Kind::Dir(_) => {}
+        }
+    }
+
+    out.flush()?;
+
+    Ok(())
+}
+
+/// Copy `blocks.len()` blocks from `reader`, writing contiguous runs at once.
+fn write_blocks<W: Write + Seek>(
+    out: &mut W,
+    blocks: &[u32],
+    block_size: u64,
+    reader: &mut impl Read,
+) -> io::Result<()> {
+    const RUN: usize = 256;
+    let mut buf = vec![0u8; RUN * block_size as usize];
+    let mut i = 0;
+
+    while i < blocks.len() {
+        let mut run = 1;
+        while run < RUN && i + run < blocks.len() && blocks[i + run] == blocks[i] + run as u32 {
+            run += 1;
+        }
+
+        let chunk = &mut buf[..run * block_size as usize];
+        reader.read_exact(chunk)?;
+        write_at(out, blocks[i] as u64, block_size, chunk)?;
+
+        i += run;
+    }
+
+    Ok(())
+}
+
+impl crate::FsWriter for Options {
+    fn estimate_size(&self, tree: &Tree) -> anyhow::Result<u64> {
+        estimate_size(tree, self)
+    }
+
+    fn write(
+        &self,
+        mut out: &mut dyn fatfs::ReadWriteSeek,
+        size: u64,
+        tree: &Tree,
+        ctx: &crate::BuildContext,
+        on_file: &mut dyn FnMut(&Path, u64),
+    ) -> anyhow::Result<()> {
+        write(&mut out, size, tree, self, ctx, on_file)
+    }
+}
@@ -0,0 +1,163 @@
+//! Unpacking of an existing image into a host directory, or a tree to build another image from.
+
+use crate::tree::{Kind, Tree};
+use crate::{image, FileSource, InputImage};
+use chrono::{DateTime, Local, TimeZone};
+use clap::Args;
+use fatfs::{Dir, ReadWriteSeek};
+use log::*;
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+
+#[derive(Args, Debug)]
+pub struct ExtractArgs {
+    /// Image to unpack
+    #[arg(short, long)]
+    image: PathBuf,
+    /// Directory to unpack into, created if missing
+    #[arg(short, long)]
+    output_dir: PathBuf,
+    /// Partition to unpack. Defaults to the first one
+    #[arg(short, long)]
+    partition: Option<usize>,
+}
+
+#[derive(Default, Debug)]
+struct Stats {
+    files: u64,
+    dirs: u64,
+}
+
+/// Time of a FAT timestamp, which is in local time.
+fn local_time(time: fatfs::DateTime) -> Option<DateTime<Local>> {
+    let date = chrono::NaiveDate::from_ymd_opt(
+        time.date.year.into(),
+        time.date.month.into(),
+        time.date.day.into(),
+    )?;
+    let time = date.and_hms_milli_opt(
+        time.time.hour.into(),
+        time.time.min.into(),
+        time.time.sec.into(),
+        time.time.millis.into(),
+    )?;
+    Local.from_local_datetime(&time).earliest()
+}
+
+fn extract_dir<T: ReadWriteSeek>(dir: &Dir<T>, out: &Path, stats: &mut Stats) -> io::Result<()> {
+    for entry in dir.iter() {
+        let entry = entry?;
+        let name = entry.file_name();
+
+        if name == "." || name == ".." {
+            continue;
+        }
+
+        let path = out.join(&name);
+
+        if entry.is_dir() {
+            info!("DIR: {}", path.display());
+            fs::create_dir_all(&path)?;
+            extract_dir(&entry.to_dir(), &path, stats)?;
+            stats.dirs += 1;
+        } else {
+            info!("FILE: {}", path.display());
+            let mut file = File::create(&path)?;
+            io::copy(&mut entry.to_file(), &mut file)?;
+            if let Some(mtime) = local_time(entry.modified()) {
+                file.set_modified(mtime.into())?;
+            }
+            stats.files += 1;
+        }
+    }
+
+    Ok(())
+}
+
+/// Copy the contents of a partition of the image at `path`, the first one if `partition` is not
+/// given, into `out`.
+fn unpack(path: &Path, partition: Option<usize>, out: &Path) -> anyhow::Result<Stats> {
+    let part = image::open_partition(path, false, partition)?;
+    let fs = image::open_fat(part)?;
+
+    fs::create_dir_all(out)?;
+
+    let mut stats = Stats::default();
+    extract_dir(&fs.root_dir(), out, &mut stats)?;
+
+    Ok(stats)
+}
+
+fn read_dir<T: ReadWriteSeek>(
+    dir: &Dir<T>,
+    path: &Path,
+    tree: &mut Tree,
+    time: i64,
+    stats: &mut Stats,
+) -> anyhow::Result<()> {
+    for entry in dir.iter() {
+        let entry = entry?;
+        let name = entry.file_name();
+
+        if name == "." || name == ".." {
+            continue;
+        }
+
+        let path = path.join(&name);
+        let mtime = local_time(entry.modified()).map_or(time, |t| t.timestamp());
+
+        if entry.is_dir() {
+            tree.insert(&path, Kind::Dir(vec![]), 0o755, mtime)?;
+            read_dir(&entry.to_dir(), &path, tree, time, stats)?;
+            stats.dirs += 1;
+        } else {
+            let mut data = vec![];
+            entry.to_file().read_to_end(&mut data)?;
+            let kind = Kind::File {
+                len: data.len() as u64,
+                source: FileSource::Data(data.into()),
+            };
+            tree.insert(&path, kind, 0o644, mtime)?;
+            stats.files += 1;
+        }
+    }
+
+    Ok(())
+}
+
+/// Read a partition of an image given with --input-image into memory, to build another image
+/// from. Entries without a valid timestamp, and the root, take `time`.
+pub fn read(input: &InputImage, time: i64) -> anyhow::Result<Tree> {
+    let mut stats = Stats::default();
+    let read = |stats: &mut Stats| {
+        let part = image::open_partition(&input.path, false, input.partition)?;
+        let fs = image::open_fat(part)?;
+
+        let mut tree = Tree::new(0o755, time);
+        read_dir(&fs.root_dir(), Path::new(""), &mut tree, time, stats)?;
+        anyhow::Ok(tree)
+    };
+    let tree = read(&mut stats).map_err(|e| anyhow::anyhow!("cannot read {input}: {e}"))?;
+
+    debug!(
+        "Read {} files and {} directories of {input}",
+        stats.files, stats.dirs
+    );
+
+    Ok(tree)
+}
+
+/// Copy the contents of a partition of the image into a directory.
+pub fn run(args: &ExtractArgs) -> anyhow::Result<()> {
+    let stats = unpack(&args.image, args.partition, &args.output_dir)?;
+
+    println!(
+        "{} files and {} directories extracted to {}",
+        stats.files,
+        stats.dirs,
+        args.output_dir.display()
+    );
+
+    Ok(())
+}
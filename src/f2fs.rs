@@ -0,0 +1,1048 @@
+//! F2FS images, for flash behind a translation layer such as eMMC, SD cards and Android data
+//! partitions.
+//!
+//! The superblocks take the first segment. Then come both checkpoint packs, the SIT, NAT and SSA
+//! areas, sized for the main area as mkfs.f2fs sizes them, and the main area. Node blocks fill the
+//! first segments of the main area and data blocks the segments after them. The warm logs carry
+//! on in the last of each, the other four logs in free segments. Directory entries are hashed
+//! into dentry blocks the way the kernel adds them.
+
+use crate::tree::{Kind, Tree};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+const MAGIC: u32 = 0xF2F5_2010;
+const MAJOR_VERSION: u16 = 1;
+const MINOR_VERSION: u16 = 16;
+
+const BLOCK_SIZE: u64 = 4096;
+const LOG_BLOCKS_PER_SEG: u32 = 9;
+const BLOCKS_PER_SEG: u64 = 1 << LOG_BLOCKS_PER_SEG;
+/// Copies of the superblock, at this offset in each of the first two blocks
+const SUPER_OFFSET: u64 = 1024;
+/// The checkpoint area starts after the segment of the superblocks
+const SEGMENT0: u64 = BLOCKS_PER_SEG;
+const CP_SEGMENTS: u64 = 2;
+/// Fewest segments the kernel mounts
+const MIN_SEGMENTS: u64 = 9;
+
+const NODE_INO: u32 = 1;
+const META_INO: u32 = 2;
+const ROOT_INO: u32 = 3;
+
+const SIT_ENTRY_SIZE: usize = 74;
+const SIT_ENTRIES_PER_BLOCK: u64 = 55;
+/// Valid block count in the low bits of a SIT entry, the segment type above
+const SIT_VBLOCKS_SHIFT: u16 = 10;
+const NAT_ENTRY_SIZE: usize = 9;
+const NAT_ENTRIES_PER_BLOCK: u64 = 455;
+const SUMMARY_SIZE: usize = 7;
+const SUMMARY_FOOTER: usize = 4091;
+const SUM_TYPE_DATA: u8 = 0;
+const SUM_TYPE_NODE: u8 = 1;
+
+/// Bitmaps of the valid SIT and NAT copies follow the fixed part of the checkpoint, and its CRC
+/// is in the last word
+const CP_BITMAP_OFFSET: usize = 192;
+const CP_CRC_OFFSET: usize = 4092;
+/// Checkpoint, three data and three node summaries, and the checkpoint again
+const CP_PACK_BLOCKS: u32 = 8;
+/// Cleanly unmounted, with the summaries of the node logs in the pack
+const CP_UMOUNT: u32 = 0x1;
+const CHECKPOINT_VERSION: u64 = 1;
+
+const ADDRS_PER_INODE: u64 = 923;
+const ADDRS_PER_BLOCK: u64 = 1018;
+const NIDS_PER_BLOCK: u64 = 1018;
+const INODE_ADDR_OFFSET: usize = 360;
+const INODE_NID_OFFSET: usize = 4052;
+const FOOTER_OFFSET: usize = 4072;
+/// Node footer flag of nodes of anything but directories
+const COLD_NODE: u32 = 0x1;
+const OFFSET_BIT_SHIFT: u32 = 3;
+
+const DENTRIES_PER_BLOCK: usize = 214;
+const DENTRY_OFFSET: usize = 30;
+const DENTRY_SIZE: usize = 11;
+const NAME_OFFSET: usize = DENTRY_OFFSET + DENTRIES_PER_BLOCK * DENTRY_SIZE;
+const SLOT_LEN: usize = 8;
+const MAX_DIR_HASH_DEPTH: u32 = 63;
+
+// Logs, and the types of segments written by them
+const LOG_HOT_DATA: u16 = 0;
+const LOG_WARM_DATA: u16 = 1;
+const LOG_COLD_DATA: u16 = 2;
+const LOG_HOT_NODE: u16 = 3;
+const LOG_WARM_NODE: u16 = 4;
+const LOG_COLD_NODE: u16 = 5;
+
+/// Segments kept for garbage collection, which are part of the overprovisioned ones that are
+/// not counted as space for users
+const RESERVED_SEGMENTS: u64 = 6;
+const OVERPROVISION_SEGMENTS: u64 = 6;
+
+const FT_REG_FILE: u8 = 1;
+const FT_DIR: u8 = 2;
+const FT_SYMLINK: u8 = 7;
+
+const S_IFDIR: u16 = 0o040000;
+const S_IFREG: u16 = 0o100000;
+const S_IFLNK: u16 = 0o120000;
+
+#[derive(Clone, Debug, Default)]
+pub struct Options {
+    pub label: Option<String>,
+}
+
+/// CRC32 of F2FS metadata: the reflected polynomial seeded with the magic number, without
+/// inversion.
+fn crc32(data: &[u8]) -> u32 {
+    !crc::crc32::update(!MAGIC, &crc::crc32::IEEE_TABLE, data)
+}
+
+fn tea_transform(buf: &mut [u32; 4], input: &[u32; 4]) {
+    let (mut b0, mut b1) = (buf[0], buf[1]);
+    let [a, b, c, d] = *input;
+    let mut sum = 0u32;
+    for _ in 0..16 {
+        sum = sum.wrapping_add(0x9E37_79B9);
+        b0 = b0.wrapping_add(
+            (b1 << 4).wrapping_add(a) ^ b1.wrapping_add(sum) ^ (b1 >> 5).wrapping_add(b),
+        );
+        b1 = b1.wrapping_add(
+            (b0 << 4).wrapping_add(c) ^ b0.wrapping_add(sum) ^ (b0 >> 5).wrapping_add(d),
+        );
+    }
+    buf[0] = buf[0].wrapping_add(b0);
+    buf[1] = buf[1].wrapping_add(b1);
+}
+
+/// Up to 16 bytes of `msg` as words, padded with its length.
+fn str2hashbuf(msg: &[u8]) -> [u32; 4] {
+    let len = msg.len() as u32;
+    let mut pad = len | len << 8;
+    pad |= pad << 16;
+
+    let mut out = [pad; 4];
+    let mut val = pad;
+    let mut n = 0;
+    for (i, &b) in msg.iter().take(16).enumerate() {
+        val = (b as u32).wrapping_add(val << 8);
+        if i % 4 == 3 {
+            out[n] = val;
+            n += 1;
+            val = pad;
+        }
+    }
+    if n < 4 {
+        out[n] = val;
+    }
+    out
+}
+
+/// TEA hash of a name, which decides the dentry block it is in.
+fn dentry_hash(name: &[u8]) -> u32 {
+    if name == b"." || name == b".." {
+        return 0;
+    }
+
+    let mut buf = [0x6745_2301, 0xEFCD_AB89, 0x98BA_DCFE, 0x1032_5476];
+    let mut p = name;
+    loop {
+        tea_transform(&mut buf, &str2hashbuf(p));
+        if p.len() <= 16 {
+            break;
+        }
+        p = &p[16..];
+    }
+    buf[0]
+}
+
+/// Buckets of a level of the directory hash, and blocks in each.
+fn dir_buckets(level: u32) -> (u64, u64) {
+    if level < MAX_DIR_HASH_DEPTH / 2 {
+        (1 << level, 2)
+    } else {
+        (1 << (MAX_DIR_HASH_DEPTH / 2 - 1), 4)
+    }
+}
+
+/// First dentry block of a bucket of a level.
+fn dir_block_index(level: u32, bucket: u64) -> u64 {
+    let before = (0..level)
+        .map(|l| {
+            let (buckets, blocks) = dir_buckets(l);
+            buckets * blocks
+        })
+        .sum::<u64>();
+    before + bucket * dir_buckets(level).1
+}
+
+/// Dentry block filled as the kernel fills them.
+struct DentryBlock(Vec<u8>);
+
+impl DentryBlock {
+    fn new() -> Self {
+        Self(vec![0; BLOCK_SIZE as usize])
+    }
+
+    fn used(&self, slot: usize) -> bool {
+        self.0[slot / 8] & 1 << (slot % 8) != 0
+    }
+
+    /// First of `slots` free slots in a row.
+    fn room(&self, slots: usize) -> Option<usize> {
+        (0..=DENTRIES_PER_BLOCK - slots).find(|&s| (s..s + slots).all(|i| !self.used(i)))
+    }
+
+    fn add(&mut self, slot: usize, name: &[u8], hash: u32, ino: u32, file_type: u8) {
+        let slots = name.len().div_ceil(SLOT_LEN).max(1);
+        for i in slot..slot + slots {
+            self.0[i / 8] |= 1 << (i % 8);
+        }
+        let d = &mut self.0[DENTRY_OFFSET + slot * DENTRY_SIZE..][..DENTRY_SIZE];
+        d[0..4].copy_from_slice(&hash.to_le_bytes());
+        d[4..8].copy_from_slice(&ino.to_le_bytes());
+        d[8..10].copy_from_slice(&(name.len() as u16).to_le_bytes());
+        d[10] = file_type;
+        self.0[NAME_OFFSET + slot * SLOT_LEN..][..name.len()].copy_from_slice(name);
+    }
+}
+
+fn file_type(kind: &Kind) -> u8 {
+    match kind {
+        Kind::Dir(_) => FT_DIR,
+        Kind::File { .. } => FT_REG_FILE,
+        Kind::Symlink(_) => FT_SYMLINK,
+    }
+}
+
+/// Dentry blocks of a directory by index, with the holes the hash levels leave, and the number
+/// of levels in use.
+fn dentry_blocks(tree: &Tree, dir: usize) -> anyhow::Result<(Vec<Option<DentryBlock>>, u32)> {
+    let node = &tree.nodes[dir];
+    let Kind::Dir(children) = &node.kind else {
+        unreachable!("only directories have entries")
+    };
+    let ino = |idx: usize| ROOT_INO + idx as u32;
+
+    let mut first = DentryBlock::new();
+    first.add(0, b".", 0, ino(dir), FT_DIR);
+    first.add(1, b"..", 0, ino(node.parent), FT_DIR);
+    let mut blocks = vec![Some(first)];
+    let mut depth = 1;
+
+    'children: for &c in children {
+        let name = tree.nodes[c].name.as_bytes();
+        let hash = dentry_hash(name);
+        let slots = name.len().div_ceil(SLOT_LEN);
+
+        for level in 0..MAX_DIR_HASH_DEPTH {
+            depth = depth.max(level + 1);
+            let (buckets, bucket_blocks) = dir_buckets(level);
+            let start = dir_block_index(level, hash as u64 % buckets);
+            for index in start..start + bucket_blocks {
+                let index = index as usize;
+                if blocks.len() <= index {
+                    blocks.resize_with(index + 1, || None);
+                }
+                let block = blocks[index].get_or_insert_with(DentryBlock::new);
+                if let Some(slot) = block.room(slots) {
+                    block.add(slot, name, hash, ino(c), file_type(&tree.nodes[c].kind));
+                    continue 'children;
+                }
+            }
+        }
+        anyhow::bail!("{} has too many entries for F2FS", node.path.display());
+    }
+
+    Ok((blocks, depth))
+}
+
+/// Node block of a file other than its inode.
+enum NodeKind {
+    /// Addresses of the data blocks from this index in the file
+    Direct(u64),
+    /// Node IDs of other nodes of the same file, by their position among them
+    Indirect(Vec<usize>),
+}
+
+struct ExtraNode {
+    /// Position among the nodes of the file, which the kernel checks it is at
+    offset: u32,
+    kind: NodeKind,
+}
+
+/// Nodes a file of `blocks` blocks needs after its inode, and which of them its inode points
+/// to.
+fn extra_nodes(blocks: u64) -> anyhow::Result<(Vec<ExtraNode>, [Option<usize>; 5])> {
+    let mut nodes = vec![];
+    let mut top = [None; 5];
+    let mut next = ADDRS_PER_INODE;
+
+    let direct = |nodes: &mut Vec<ExtraNode>, offset: u64, next: &mut u64| {
+        nodes.push(ExtraNode {
+            offset: offset as u32,
+            kind: NodeKind::Direct(*next),
+        });
+        *next += ADDRS_PER_BLOCK;
+        nodes.len() - 1
+    };
+
+    // Two direct nodes
+    for (i, slot) in top.iter_mut().take(2).enumerate() {
+        if next < blocks {
+            *slot = Some(direct(&mut nodes, 1 + i as u64, &mut next));
+        }
+    }
+
+    // Two indirect nodes, each of direct nodes
+    for (i, slot) in top.iter_mut().skip(2).take(2).enumerate() {
+        if next >= blocks {
+            break;
+        }
+        let base = 3 + i as u64 * (NIDS_PER_BLOCK + 1);
+        nodes.push(ExtraNode {
+            offset: base as u32,
+            kind: NodeKind::Indirect(vec![]),
+        });
+        let ind = nodes.len() - 1;
+        *slot = Some(ind);
+        for k in 0..NIDS_PER_BLOCK {
+            if next >= blocks {
+                break;
+            }
+            let child = direct(&mut nodes, base + 1 + k, &mut next);
+            if let NodeKind::Indirect(children) = &mut nodes[ind].kind {
+                children.push(child);
+            }
+        }
+    }
+
+    // A double indirect node
+    if next < blocks {
+        let base = 5 + 2 * NIDS_PER_BLOCK;
+        nodes.push(ExtraNode {
+            offset: base as u32,
+            kind: NodeKind::Indirect(vec![]),
+        });
+        let dind = nodes.len() - 1;
+        top[4] = Some(dind);
+        for i in 0..NIDS_PER_BLOCK {
+            if next >= blocks {
+                break;
+            }
+            let ind_offset = base + 1 + i * (NIDS_PER_BLOCK + 1);
+            nodes.push(ExtraNode {
+                offset: ind_offset as u32,
+                kind: NodeKind::Indirect(vec![]),
+            });
+            let ind = nodes.len() - 1;
+            if let NodeKind::Indirect(children) = &mut nodes[dind].kind {
+                children.push(ind);
+            }
+            for j in 0..NIDS_PER_BLOCK {
+                if next >= blocks {
+                    break;
+                }
+                let child = direct(&mut nodes, ind_offset + 1 + j, &mut next);
+                if let NodeKind::Indirect(children) = &mut nodes[ind].kind {
+                    children.push(child);
+                }
+            }
+        }
+    }
+
+    if next < blocks {
+        anyhow::bail!("file of {blocks} blocks is too large for F2FS");
+    }
+    Ok((nodes, top))
+}
+
+/// Data blocks of a node, relative to the start of the main area.
+enum Blocks {
+    /// The contents of a file or symbolic link, in a row
+    Contiguous { start: u64, count: u64 },
+    /// Dentry blocks by index, with holes where the hash levels have none
+    Sparse(Vec<Option<u64>>),
+}
+
+impl Blocks {
+    /// Blocks of the file, including holes.
+    fn len(&self) -> u64 {
+        match self {
+            Self::Contiguous { count, .. } => *count,
+            Self::Sparse(blocks) => blocks.len() as u64,
+        }
+    }
+
+    fn get(&self, index: u64) -> Option<u64> {
+        match self {
+            Self::Contiguous { start, count } => (index < *count).then_some(start + index),
+            Self::Sparse(blocks) => blocks.get(index as usize).copied().flatten(),
+        }
+    }
+
+    fn allocated(&self) -> u64 {
+        match self {
+            Self::Contiguous { count, .. } => *count,
+            Self::Sparse(blocks) => blocks.iter().flatten().count() as u64,
+        }
+    }
+}
+
+/// Where a log carries on, in segments and blocks from the start of the main area.
+#[derive(Clone, Copy)]
+struct Log {
+    segment: u64,
+    offset: u64,
+}
+
+/// Placement of the nodes and data in the main area, which does not depend on its size.
+struct Layout {
+    /// Node ID of the first node of every file after its inode
+    extra_nid: Vec<u32>,
+    extra: Vec<Vec<ExtraNode>>,
+    /// Extra nodes the inode of every file points to
+    top: Vec<[Option<usize>; 5]>,
+    /// Block of every inode, which its other nodes follow
+    node_block: Vec<u64>,
+    blocks: Vec<Blocks>,
+    /// Dentry blocks and hash levels of every directory
+    dentries: Vec<Vec<Option<DentryBlock>>>,
+    depth: Vec<u32>,
+    node_blocks: u64,
+    data_blocks: u64,
+    next_nid: u32,
+    /// Logs by type
+    logs: [Log; 6],
+    /// Segments in use, including those of the logs
+    segments: u64,
+}
+
+impl Layout {
+    fn new(tree: &Tree) -> anyhow::Result<Self> {
+        for node in &tree.nodes[1..] {
+            if node.name.len() > 255 {
+                anyhow::bail!(
+                    "F2FS names are limited to 255 bytes: {}",
+                    node.path.display()
+                );
+            }
+            if let Kind::Symlink(target) = &node.kind {
+                if target.len() >= BLOCK_SIZE as usize {
+                    anyhow::bail!(
+                        "F2FS symbolic links are limited to {} bytes: {}",
+                        BLOCK_SIZE - 1,
+                        node.path.display()
+                    );
+                }
+            }
+        }
+
+        let count = tree.nodes.len();
+        let mut dentries = Vec::with_capacity(count);
+        let mut depth = vec![0; count];
+        for (idx, node) in tree.nodes.iter().enumerate() {
+            if node.is_dir() {
+                let (blocks, d) = dentry_blocks(tree, idx)?;
+                dentries.push(blocks);
+                depth[idx] = d;
+            } else {
+                dentries.push(vec![]);
+            }
+        }
+
+        // Nodes: every inode followed by the other nodes of its file
+        let mut extra = Vec::with_capacity(count);
+        let mut top = Vec::with_capacity(count);
+        let mut extra_nid = vec![0; count];
+        let mut node_block = vec![0; count];
+        let mut next_nid = ROOT_INO + count as u32;
+        let mut node_blocks = 0;
+        for (idx, node) in tree.nodes.iter().enumerate() {
+            let blocks = match &node.kind {
+                Kind::Dir(_) => dentries[idx].len() as u64,
+                Kind::File { len, .. } => len.div_ceil(BLOCK_SIZE),
+                Kind::Symlink(_) => 1,
+            };
+            let (nodes, t) =
+                extra_nodes(blocks).map_err(|e| anyhow::anyhow!("{}: {e}", node.path.display()))?;
+            extra_nid[idx] = next_nid;
+            next_nid += nodes.len() as u32;
+            node_block[idx] = node_blocks;
+            node_blocks += 1 + nodes.len() as u64;
+            extra.push(nodes);
+            top.push(t);
+        }
+
+        // Data after the node segments
+        let node_segments = node_blocks.div_ceil(BLOCKS_PER_SEG);
+        let data_start = node_segments * BLOCKS_PER_SEG;
+        let mut next = data_start;
+        let mut blocks = Vec::with_capacity(count);
+        for (idx, node) in tree.nodes.iter().enumerate() {
+            blocks.push(match &node.kind {
+                Kind::Dir(_) => Blocks::Sparse(
+                    dentries[idx]
+                        .iter()
+                        .map(|b| {
+                            b.as_ref().map(|_| {
+                                next += 1;
+                                next - 1
+                            })
+                        })
+                        .collect(),
+                ),
+                Kind::File { len, .. } => {
+                    let count = len.div_ceil(BLOCK_SIZE);
+                    next += count;
+                    Blocks::Contiguous {
+                        start: next - count,
+                        count,
+                    }
+                }
+                Kind::Symlink(_) => {
+                    next += 1;
+                    Blocks::Contiguous {
+                        start: next - 1,
+                        count: 1,
+                    }
+                }
+            });
+        }
+        let data_blocks = next - data_start;
+        let data_segments = data_blocks.div_ceil(BLOCKS_PER_SEG);
+
+        // The warm logs carry on after the nodes and data, the others start in free segments
+        let mut free = node_segments + data_segments;
+        let mut carry_on = |blocks: u64, end: u64| {
+            if !blocks.is_multiple_of(BLOCKS_PER_SEG) {
+                Log {
+                    segment: end - 1,
+                    offset: blocks % BLOCKS_PER_SEG,
+                }
+            } else {
+                free += 1;
+                Log {
+                    segment: free - 1,
+                    offset: 0,
+                }
+            }
+        };
+        let warm_node = carry_on(node_blocks, node_segments);
+        let warm_data = carry_on(data_blocks, node_segments + data_segments);
+        let mut logs = [Log {
+            segment: 0,
+            offset: 0,
+        }; 6];
+        for (ty, log) in logs.iter_mut().enumerate() {
+            *log = match ty as u16 {
+                LOG_WARM_NODE => warm_node,
+                LOG_WARM_DATA => warm_data,
+                _ => {
+                    free += 1;
+                    Log {
+                        segment: free - 1,
+                        offset: 0,
+                    }
+                }
+            };
+        }
+
+        Ok(Self {
+            extra_nid,
+            extra,
+            top,
+            node_block,
+            blocks,
+            dentries,
+            depth,
+            node_blocks,
+            data_blocks,
+            next_nid,
+            logs,
+            segments: free,
+        })
+    }
+
+    fn node_segments(&self) -> u64 {
+        self.node_blocks.div_ceil(BLOCKS_PER_SEG)
+    }
+
+    fn data_segments(&self) -> u64 {
+        self.data_blocks.div_ceil(BLOCKS_PER_SEG)
+    }
+}
+
+/// Sizes of the areas after the checkpoint, in segments.
+struct Areas {
+    sit: u64,
+    nat: u64,
+    ssa: u64,
+    main: u64,
+}
+
+impl Areas {
+    /// Areas of a volume of `segments` segments after the superblocks.
+    fn new(segments: u64) -> anyhow::Result<Self> {
+        let avail = segments.saturating_sub(CP_SEGMENTS);
+        // Two copies of the SIT and NAT, and a summary block for every segment
+        let sit = 2 * avail
+            .div_ceil(SIT_ENTRIES_PER_BLOCK)
+            .div_ceil(BLOCKS_PER_SEG)
+            .max(1);
+        let ssa = avail.div_ceil(BLOCKS_PER_SEG).max(1);
+
+        // The checkpoint has a bit for every block of the SIT and NAT, telling which copy is
+        // valid, and the NAT is smaller than mkfs.f2fs would make it when they do not fit
+        let bitmap_segments = (CP_CRC_OFFSET - CP_BITMAP_OFFSET) as u64 * 8 / BLOCKS_PER_SEG;
+        let nat_max = bitmap_segments.saturating_sub(sit / 2);
+        if nat_max == 0 {
+            anyhow::bail!("F2FS images are limited to about 3TiB");
+        }
+        let nat_blocks =
+            (avail.saturating_sub(sit + ssa) * BLOCKS_PER_SEG).div_ceil(NAT_ENTRIES_PER_BLOCK);
+        let nat = 2 * nat_blocks.div_ceil(BLOCKS_PER_SEG).clamp(1, nat_max);
+
+        Ok(Self {
+            sit,
+            nat,
+            ssa,
+            main: avail.saturating_sub(sit + nat + ssa),
+        })
+    }
+
+    fn cp_addr(&self) -> u64 {
+        SEGMENT0
+    }
+
+    fn sit_addr(&self) -> u64 {
+        self.cp_addr() + CP_SEGMENTS * BLOCKS_PER_SEG
+    }
+
+    fn nat_addr(&self) -> u64 {
+        self.sit_addr() + self.sit * BLOCKS_PER_SEG
+    }
+
+    fn ssa_addr(&self) -> u64 {
+        self.nat_addr() + self.nat * BLOCKS_PER_SEG
+    }
+
+    fn main_addr(&self) -> u64 {
+        self.ssa_addr() + self.ssa * BLOCKS_PER_SEG
+    }
+
+    /// Node IDs the NAT has room for.
+    fn max_nid(&self) -> u64 {
+        self.nat / 2 * BLOCKS_PER_SEG * NAT_ENTRIES_PER_BLOCK
+    }
+}
+
+/// Segments after the superblocks, in the smallest volume the tree fits in.
+fn volume_segments(layout: &Layout) -> anyhow::Result<u64> {
+    let needed = layout.segments + OVERPROVISION_SEGMENTS;
+    let mut segments = (CP_SEGMENTS + needed).max(MIN_SEGMENTS);
+    loop {
+        let areas = Areas::new(segments)?;
+        if areas.main >= needed && areas.max_nid() >= layout.next_nid as u64 {
+            return Ok(segments);
+        }
+        segments += 1;
+    }
+}
+
+/// Smallest filesystem size the tree fits in.
+pub fn estimate_size(tree: &Tree) -> anyhow::Result<u64> {
+    let layout = Layout::new(tree)?;
+    Ok((SEGMENT0 + volume_segments(&layout)? * BLOCKS_PER_SEG) * BLOCK_SIZE)
+}
+
+fn write_block<W: Write + Seek>(out: &mut W, block: u64, data: &[u8]) -> io::Result<()> {
+    out.seek(SeekFrom::Start(block * BLOCK_SIZE))?;
+    out.write_all(data)
+}
+
+fn put_u16(d: &mut [u8], at: usize, v: u16) {
+    d[at..at + 2].copy_from_slice(&v.to_le_bytes());
+}
+
+fn put_u32(d: &mut [u8], at: usize, v: u32) {
+    d[at..at + 4].copy_from_slice(&v.to_le_bytes());
+}
+
+fn put_u64(d: &mut [u8], at: usize, v: u64) {
+    d[at..at + 8].copy_from_slice(&v.to_le_bytes());
+}
+
+/// Summary entry of a block: the node it belongs to, and its position in that node.
+fn summary(ssa: &mut [u8], offset: u64, nid: u32, ofs_in_node: u16) {
+    let e = &mut ssa[offset as usize * SUMMARY_SIZE..][..SUMMARY_SIZE];
+    e[0..4].copy_from_slice(&nid.to_le_bytes());
+    e[4] = 0;
+    e[5..7].copy_from_slice(&ofs_in_node.to_le_bytes());
+}
+
+fn footer(d: &mut [u8], nid: u32, ino: u32, offset: u32, cold: bool, next: u64) {
+    put_u32(d, FOOTER_OFFSET, nid);
+    put_u32(d, FOOTER_OFFSET + 4, ino);
+    let flag = offset << OFFSET_BIT_SHIFT | if cold { COLD_NODE } else { 0 };
+    put_u32(d, FOOTER_OFFSET + 8, flag);
+    put_u64(d, FOOTER_OFFSET + 12, CHECKPOINT_VERSION);
+    put_u32(d, FOOTER_OFFSET + 20, next as u32);
+}
+
+/// Write the image into `out`, which spans `size` bytes.
+///
+/// `on_file` is called with the image path and length of every file written.
+pub fn write<W: Write + Seek>(
+    out: &mut W,
+    size: u64,
+    tree: &Tree,
+    opts: &Options,
+    ctx: &crate::BuildContext,
+    on_file: &mut dyn FnMut(&Path, u64),
+) -> anyhow::Result<()> {
+    let layout = Layout::new(tree)?;
+    let block_count = (size / BLOCK_SIZE).min(u32::MAX as u64);
+    let segments = (block_count / BLOCKS_PER_SEG).saturating_sub(1);
+    let areas = Areas::new(segments)?;
+
+    if areas.main < layout.segments + OVERPROVISION_SEGMENTS
+        || areas.max_nid() < layout.next_nid as u64
+        || segments < MIN_SEGMENTS
+    {
+        anyhow::bail!(
+            "F2FS image needs {} bytes, but only {size} are available",
+            (SEGMENT0 + volume_segments(&layout)? * BLOCKS_PER_SEG) * BLOCK_SIZE
+        );
+    }
+
+    let main = areas.main_addr();
+    let ino = |idx: usize| ROOT_INO + idx as u32;
+    let node_segments = layout.node_segments();
+    let used_segments = node_segments + layout.data_segments();
+
+    // Summaries of the segments in use, and where every node is
+    let mut ssa = vec![vec![0u8; BLOCK_SIZE as usize]; layout.segments as usize];
+    for (segment, s) in ssa.iter_mut().enumerate() {
+        let segment = segment as u64;
+        let node = segment < node_segments
+            || layout.logs[3..]
+                .iter()
+                .any(|l| l.segment == segment && segment >= used_segments);
+        s[SUMMARY_FOOTER] = if node { SUM_TYPE_NODE } else { SUM_TYPE_DATA };
+    }
+    let mut nat = vec![(0u32, 0u32); layout.next_nid as usize];
+    nat[NODE_INO as usize] = (NODE_INO, 1);
+    nat[META_INO as usize] = (META_INO, 1);
+
+    let data_summary = |ssa: &mut Vec<Vec<u8>>, block: u64, nid: u32, ofs: u64| {
+        let s = &mut ssa[(block / BLOCKS_PER_SEG) as usize];
+        summary(s, block % BLOCKS_PER_SEG, nid, ofs as u16);
+    };
+
+    // Inodes and the other nodes of every file
+    for (idx, node) in tree.nodes.iter().enumerate() {
+        let blocks = &layout.blocks[idx];
+        let extra = &layout.extra[idx];
+        let cold = !node.is_dir();
+        let nid_of = |i: usize| layout.extra_nid[idx] + i as u32;
+        let block_of = |i: usize| layout.node_block[idx] + 1 + i as u64;
+
+        let mut d = vec![0u8; BLOCK_SIZE as usize];
+        let (mode, links, size) = match &node.kind {
+            Kind::Dir(children) => {
+                let subdirs = children.iter().filter(|&&c| tree.nodes[c].is_dir()).count();
+                (S_IFDIR, 2 + subdirs as u32, blocks.len() * BLOCK_SIZE)
+            }
+            Kind::File { len, .. } => (S_IFREG, 1, *len),
+            Kind::Symlink(target) => (S_IFLNK, 1, target.len() as u64),
+        };
+        put_u16(&mut d, 0, mode | (node.mode & 0o7777) as u16);
+        put_u32(&mut d, 4, node.uid);
+        put_u32(&mut d, 8, node.gid);
+        put_u32(&mut d, 12, links);
+        put_u64(&mut d, 16, size);
+        // Blocks of the file, counting its nodes
+        put_u64(&mut d, 24, 1 + blocks.allocated() + extra.len() as u64);
+        for at in [32, 40, 48] {
+            put_u64(&mut d, at, node.mtime.max(0) as u64);
+        }
+        put_u32(&mut d, 72, layout.depth[idx]);
+        put_u32(&mut d, 84, ino(node.parent));
+        put_u32(&mut d, 88, node.name.len() as u32);
+        d[92..92 + node.name.len()].copy_from_slice(node.name.as_bytes());
+        for i in 0..ADDRS_PER_INODE.min(blocks.len()) {
+            if let Some(b) = blocks.get(i) {
+                put_u32(
+                    &mut d,
+                    INODE_ADDR_OFFSET + 4 * i as usize,
+                    (main + b) as u32,
+                );
+                data_summary(&mut ssa, b, ino(idx), i);
+            }
+        }
+        for (i, t) in layout.top[idx].iter().enumerate() {
+            if let Some(t) = t {
+                put_u32(&mut d, INODE_NID_OFFSET + 4 * i, nid_of(*t));
+            }
+        }
+        let at = layout.node_block[idx];
+        footer(&mut d, ino(idx), ino(idx), 0, cold, main + at + 1);
+        write_block(out, main + at, &d)?;
+        summary(
+            &mut ssa[(at / BLOCKS_PER_SEG) as usize],
+            at % BLOCKS_PER_SEG,
+            ino(idx),
+            0,
+        );
+        nat[ino(idx) as usize] = (ino(idx), (main + at) as u32);
+
+        for (i, n) in extra.iter().enumerate() {
+            let mut d = vec![0u8; BLOCK_SIZE as usize];
+            match &n.kind {
+                NodeKind::Direct(first) => {
+                    for j in 0..ADDRS_PER_BLOCK {
+                        if let Some(b) = blocks.get(first + j) {
+                            put_u32(&mut d, 4 * j as usize, (main + b) as u32);
+                            data_summary(&mut ssa, b, nid_of(i), j);
+                        }
+                    }
+                }
+                NodeKind::Indirect(children) => {
+                    for (j, &c) in children.iter().enumerate() {
+                        put_u32(&mut d, 4 * j, nid_of(c));
+                    }
+                }
+            }
+            let at = block_of(i);
+            footer(&mut d, nid_of(i), ino(idx), n.offset, cold, main + at + 1);
+            write_block(out, main + at, &d)?;
+            summary(
+                &mut ssa[(at / BLOCKS_PER_SEG) as usize],
+                at % BLOCKS_PER_SEG,
+                nid_of(i),
+                0,
+            );
+            nat[nid_of(i) as usize] = (ino(idx), (main + at) as u32);
+        }
+    }
+
+    // The block the warm node log continues at ends the chain of nodes recovery follows
+    let warm_node = layout.logs[LOG_WARM_NODE as usize];
+    write_block(
+        out,
+        main + warm_node.segment * BLOCKS_PER_SEG + warm_node.offset,
+        &[0; BLOCK_SIZE as usize],
+    )?;
+
+    // Dentry blocks, files and symbolic links
+    for (idx, node) in tree.nodes.iter().enumerate() {
+        let blocks = &layout.blocks[idx];
+        match &node.kind {
+            Kind::Dir(_) => {
+                for (i, block) in layout.dentries[idx].iter().enumerate() {
+                    if let (Some(block), Some(b)) = (block, blocks.get(i as u64)) {
+                        write_block(out, main + b, &block.0)?;
+                    }
+                }
+            }
+            Kind::File { source, len } => {
+                if let Some(b) = blocks.get(0) {
+                    out.seek(SeekFrom::Start((main + b) * BLOCK_SIZE))?;
+                    let copied = io::copy(&mut source.open()?.take(*len), out)?;
+                    if copied != *len {
+                        anyhow::bail!("{} changed while being copied", node.path.display());
+                    }
+                }
+                on_file(&node.path, *len);
+            }
+            Kind::Symlink(target) => {
+                let mut d = vec![0u8; BLOCK_SIZE as usize];
+                d[..target.len()].copy_from_slice(target.as_bytes());
+                write_block(out, main + blocks.get(0).unwrap(), &d)?;
+            }
+        }
+    }
+
+    // Segment information table, first copies
+    let seg_type = |segment: u64| -> Option<(u16, u64)> {
+        if segment < node_segments {
+            let valid = (layout.node_blocks - segment * BLOCKS_PER_SEG).min(BLOCKS_PER_SEG);
+            return Some((LOG_WARM_NODE, valid));
+        }
+        if segment < used_segments {
+            let data = segment - node_segments;
+            let valid = (layout.data_blocks - data * BLOCKS_PER_SEG).min(BLOCKS_PER_SEG);
+            return Some((LOG_WARM_DATA, valid));
+        }
+        layout
+            .logs
+            .iter()
+            .position(|l| l.segment == segment)
+            .map(|ty| (ty as u16, 0))
+    };
+    for b in 0..areas.main.div_ceil(SIT_ENTRIES_PER_BLOCK) {
+        let mut d = vec![0u8; BLOCK_SIZE as usize];
+        for e in 0..SIT_ENTRIES_PER_BLOCK {
+            let segment = b * SIT_ENTRIES_PER_BLOCK + e;
+            if let Some((ty, valid)) = seg_type(segment) {
+                let entry = &mut d[e as usize * SIT_ENTRY_SIZE..][..SIT_ENTRY_SIZE];
+                put_u16(entry, 0, valid as u16 | ty << SIT_VBLOCKS_SHIFT);
+                for i in 0..valid as usize {
+                    entry[2 + i / 8] |= 0x80 >> (i % 8);
+                }
+            }
+        }
+        let addr = areas.sit_addr() + b / BLOCKS_PER_SEG * 2 * BLOCKS_PER_SEG + b % BLOCKS_PER_SEG;
+        write_block(out, addr, &d)?;
+    }
+
+    // Node address table, first copies, all of it so that free node IDs read as free
+    for b in 0..areas.nat / 2 * BLOCKS_PER_SEG {
+        let mut d = vec![0u8; BLOCK_SIZE as usize];
+        for e in 0..NAT_ENTRIES_PER_BLOCK {
+            let nid = (b * NAT_ENTRIES_PER_BLOCK + e) as usize;
+            if let Some(&(ino, addr)) = nat.get(nid) {
+                let entry = &mut d[e as usize * NAT_ENTRY_SIZE..][..NAT_ENTRY_SIZE];
+                put_u32(entry, 1, ino);
+                put_u32(entry, 5, addr);
+            }
+        }
+        let addr = areas.nat_addr() + b / BLOCKS_PER_SEG * 2 * BLOCKS_PER_SEG + b % BLOCKS_PER_SEG;
+        write_block(out, addr, &d)?;
+    }
+
+    // Segment summary area, of the segments in use
+    for (segment, s) in ssa.iter().enumerate() {
+        write_block(out, areas.ssa_addr() + segment as u64, s)?;
+    }
+
+    // Checkpoint packs, the same in both, with the summaries of the logs
+    let valid_blocks = layout.node_blocks + layout.data_blocks;
+    let mut cp = vec![0u8; BLOCK_SIZE as usize];
+    put_u64(&mut cp, 0, CHECKPOINT_VERSION);
+    put_u64(
+        &mut cp,
+        8,
+        (areas.main - OVERPROVISION_SEGMENTS) * BLOCKS_PER_SEG,
+    );
+    put_u64(&mut cp, 16, valid_blocks);
+    put_u32(&mut cp, 24, RESERVED_SEGMENTS as u32);
+    put_u32(&mut cp, 28, OVERPROVISION_SEGMENTS as u32);
+    put_u32(&mut cp, 32, (areas.main - layout.segments) as u32);
+    for i in 0..8 {
+        let (node, data) = match i {
+            0..3 => (
+                Some(layout.logs[LOG_HOT_NODE as usize + i]),
+                Some(layout.logs[LOG_HOT_DATA as usize + i]),
+            ),
+            _ => (None, None),
+        };
+        put_u32(
+            &mut cp,
+            36 + 4 * i,
+            node.map_or(u32::MAX, |l| l.segment as u32),
+        );
+        put_u16(&mut cp, 68 + 2 * i, node.map_or(0, |l| l.offset as u16));
+        put_u32(
+            &mut cp,
+            84 + 4 * i,
+            data.map_or(u32::MAX, |l| l.segment as u32),
+        );
+        put_u16(&mut cp, 116 + 2 * i, data.map_or(0, |l| l.offset as u16));
+    }
+    put_u32(&mut cp, 132, CP_UMOUNT);
+    put_u32(&mut cp, 136, CP_PACK_BLOCKS);
+    put_u32(&mut cp, 140, 1);
+    put_u32(&mut cp, 144, layout.node_blocks as u32);
+    put_u32(&mut cp, 148, tree.nodes.len() as u32);
+    put_u32(&mut cp, 152, layout.next_nid);
+    put_u32(&mut cp, 156, (areas.sit / 2 * BLOCKS_PER_SEG / 8) as u32);
+    put_u32(&mut cp, 160, (areas.nat / 2 * BLOCKS_PER_SEG / 8) as u32);
+    put_u32(&mut cp, 164, CP_CRC_OFFSET as u32);
+    let crc = crc32(&cp[..CP_CRC_OFFSET]);
+    put_u32(&mut cp, CP_CRC_OFFSET, crc);
+
+    for pack in 0..2 {
+        let start = areas.cp_addr() + pack * BLOCKS_PER_SEG;
+        write_block(out, start, &cp)?;
+        for (i, ty) in [
+            LOG_HOT_DATA,
+            LOG_WARM_DATA,
+            LOG_COLD_DATA,
+            LOG_HOT_NODE,
+            LOG_WARM_NODE,
+            LOG_COLD_NODE,
+        ]
+        .into_iter()
+        .enumerate()
+        {
+            let log = layout.logs[ty as usize];
+            write_block(out, start + 1 + i as u64, &ssa[log.segment as usize])?;
+        }
+        write_block(out, start + CP_PACK_BLOCKS as u64 - 1, &cp)?;
+    }
+
+    // Superblocks
+    let mut sb = vec![0u8; (BLOCK_SIZE - SUPER_OFFSET) as usize];
+    put_u32(&mut sb, 0, MAGIC);
+    put_u16(&mut sb, 4, MAJOR_VERSION);
+    put_u16(&mut sb, 6, MINOR_VERSION);
+    put_u32(&mut sb, 8, 9);
+    put_u32(&mut sb, 12, 3);
+    put_u32(&mut sb, 16, 12);
+    put_u32(&mut sb, 20, LOG_BLOCKS_PER_SEG);
+    put_u32(&mut sb, 24, 1);
+    put_u32(&mut sb, 28, 1);
+    put_u64(&mut sb, 36, block_count);
+    put_u32(&mut sb, 44, areas.main as u32);
+    put_u32(&mut sb, 48, segments as u32);
+    put_u32(&mut sb, 52, CP_SEGMENTS as u32);
+    put_u32(&mut sb, 56, areas.sit as u32);
+    put_u32(&mut sb, 60, areas.nat as u32);
+    put_u32(&mut sb, 64, areas.ssa as u32);
+    put_u32(&mut sb, 68, areas.main as u32);
+    put_u32(&mut sb, 72, areas.cp_addr() as u32);
+    put_u32(&mut sb, 76, areas.cp_addr() as u32);
+    put_u32(&mut sb, 80, areas.sit_addr() as u32);
+    put_u32(&mut sb, 84, areas.nat_addr() as u32);
+    put_u32(&mut sb, 88, areas.ssa_addr() as u32);
+    put_u32(&mut sb, 92, main as u32);
+    put_u32(&mut sb, 96, ROOT_INO);
+    put_u32(&mut sb, 100, NODE_INO);
+    put_u32(&mut sb, 104, META_INO);
+    ctx.random.fill(&mut sb[108..124])?;
+    let label = opts.label.as_deref().unwrap_or("");
+    for (i, u) in label.encode_utf16().take(511).enumerate() {
+        put_u16(&mut sb, 124 + 2 * i, u);
+    }
+    let version = format!("mkimg {}", env!("CARGO_PKG_VERSION"));
+    sb[1668..1668 + version.len()].copy_from_slice(version.as_bytes());
+    sb[1924..1924 + version.len()].copy_from_slice(version.as_bytes());
+
+    for copy in 0..2 {
+        out.seek(SeekFrom::Start(copy * BLOCK_SIZE + SUPER_OFFSET))?;
+        out.write_all(&sb)?;
+    }
+
+    Ok(())
+}
+
+impl crate::FsWriter for Options {
+    fn estimate_size(&self, tree: &Tree) -> anyhow::Result<u64> {
+        estimate_size(tree)
+    }
+
+    fn write(
+        &self,
+        mut out: &mut dyn fatfs::ReadWriteSeek,
+        size: u64,
+        tree: &Tree,
+        ctx: &crate::BuildContext,
+        on_file: &mut dyn FnMut(&Path, u64),
+    ) -> anyhow::Result<()> {
+        write(&mut out, size, tree, self, ctx, on_file)
+    }
+}
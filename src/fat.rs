@@ -0,0 +1,455 @@
+//! Selection of the FAT variant and cluster size for vfat images.
+//!
+//! fatfs derives the variant from the number of clusters, so an explicit type is honoured by
+//! picking a cluster size that puts the cluster count in the range of that type. The geometry
+//! computation mirrors the one fatfs does while formatting.
+
+use crate::fat_resize::{chain, get_entry, read_at, write_at, Bpb};
+use crate::tree::{Kind, Tree};
+use clap::ValueEnum;
+use std::cell::Cell;
+use std::io::{Read, Seek, Write};
+
+const SECTOR: u64 = 512;
+const DIR_ENTRY: u64 = 32;
+const FATS: u64 = 2;
+/// Root directory entries fatfs reserves on FAT12 and FAT16
+const ROOT_ENTRIES: u64 = 512;
+/// Largest cluster size fatfs picks on its own, and the largest most implementations accept
+const MAX_CLUSTER: u32 = 32 * 1024;
+/// Clusters kept away from the limits of a type, so FAT rounding can not tip it over
+const MARGIN: u64 = 16;
+
+/// FAT variant of vfat images.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FatType {
+    #[value(name = "12")]
+    Fat12,
+    #[value(name = "16")]
+    Fat16,
+    #[value(name = "32")]
+    Fat32,
+    /// Whatever fits the partition size with 512 byte clusters, or else the variant that holds the
+    /// contents with the smallest clusters
+    Auto,
+}
+
+impl FatType {
+    /// Variant to enforce, `None` for `auto`.
+    pub fn fixed(self) -> Option<fatfs::FatType> {
+        match self {
+            Self::Fat12 => Some(fatfs::FatType::Fat12),
+            Self::Fat16 => Some(fatfs::FatType::Fat16),
+            Self::Fat32 => Some(fatfs::FatType::Fat32),
+            Self::Auto => None,
+        }
+    }
+}
+
+pub fn bits(fat: fatfs::FatType) -> u64 {
+    match fat {
+        fatfs::FatType::Fat12 => 12,
+        fatfs::FatType::Fat16 => 16,
+        fatfs::FatType::Fat32 => 32,
+    }
+}
+
+pub fn min_clusters(fat: fatfs::FatType) -> u64 {
+    match fat {
+        fatfs::FatType::Fat12 => 1,
+        fatfs::FatType::Fat16 => 4085,
+        fatfs::FatType::Fat32 => 65525,
+    }
+}
+
+pub fn max_clusters(fat: fatfs::FatType) -> u64 {
+    match fat {
+        fatfs::FatType::Fat12 => 4084,
+        fatfs::FatType::Fat16 => 65524,
+        fatfs::FatType::Fat32 => 0x0FFF_FFF4,
+    }
+}
+
+/// Sectors before the data area, excluding the FATs.
+fn overhead_sectors(fat: fatfs::FatType) -> u64 {
+    match fat {
+        fatfs::FatType::Fat32 => 8,
+        _ => 1 + ROOT_ENTRIES * DIR_ENTRY / SECTOR,
+    }
+}
+
+fn cluster_sizes() -> impl Iterator<Item = u32> {
+    (9..=MAX_CLUSTER.trailing_zeros()).map(|shift| 1 << shift)
+}
+
+/// Number of data clusters fatfs lays out when formatting as `fat`, if the volume is big enough.
+fn data_clusters(fat: fatfs::FatType, total_sectors: u64, bytes_per_cluster: u32) -> Option<u64> {
+    let spc = bytes_per_cluster as u64 / SECTOR;
+    let overhead = overhead_sectors(fat);
+
+    if total_sectors <= overhead + 8 {
+        return None;
+    }
+
+    let t0 = total_sectors - overhead;
+    let sectors_per_fat = (t0 + 2 * spc).div_ceil(spc * SECTOR * 8 / bits(fat) + FATS);
+
+    Some(t0.checked_sub(sectors_per_fat * FATS)? / spc)
+}
+
+/// Variant fatfs ends up with for a volume size and cluster size.
+fn detect(total_sectors: u64, bytes_per_cluster: u32) -> Option<fatfs::FatType> {
+    use fatfs::FatType::*;
+
+    [Fat32, Fat16, Fat12].into_iter().find(|&fat| {
+        data_clusters(fat, total_sectors, bytes_per_cluster)
+            .is_some_and(|n| (min_clusters(fat)..=max_clusters(fat)).contains(&n))
+    })
+}
+
+/// Size of a volume with `clusters` data clusters.
+fn volume_size(fat: fatfs::FatType, bytes_per_cluster: u32, clusters: u64) -> u64 {
+    let fat_bytes = ((clusters + 2) * bits(fat))
+        .div_ceil(8)
+        .next_multiple_of(SECTOR);
+    overhead_sectors(fat) * SECTOR + FATS * fat_bytes + clusters * bytes_per_cluster as u64
+}
+
+/// Directory entries taken by a file name, including its long file name entries.
+fn name_entries(name: &str) -> u64 {
+    1 + (name.encode_utf16().count() as u64).div_ceil(13)
+}
+
+fn root_entries(tree: &Tree) -> u64 {
+    match &tree.nodes[0].kind {
+        Kind::Dir(children) => children
+            .iter()
+            .map(|&c| name_entries(&tree.nodes[c].name))
+            .sum(),
+        Kind::File { .. } | Kind::Symlink(_) => 0,
+    }
+}
+
+/// Clusters needed to store the tree.
+fn needed_clusters(tree: &Tree, fat: fatfs::FatType, bytes_per_cluster: u32) -> u64 {
+    let bytes_per_cluster = bytes_per_cluster as u64;
+
+    tree.nodes
+        .iter()
+        .enumerate()
+        .map(|(idx, node)| match &node.kind {
+            Kind::File { len, .. } => len.div_ceil(bytes_per_cluster),
+            // The root directory has a fixed area outside of the clusters on FAT12 and FAT16
+            Kind::Dir(_) if idx == 0 && fat != fatfs::FatType::Fat32 => 0,
+            Kind::Dir(children) => {
+                // Including . and .., which fatfs writes with a long file name entry each
+                let entries = 4 + children
+                    .iter()
+                    .map(|&c| name_entries(&tree.nodes[c].name))
+                    .sum::<u64>();
+                (entries * DIR_ENTRY).div_ceil(bytes_per_cluster)
+            }
+            Kind::Symlink(_) => unreachable!("FAT has no symbolic links"),
+        })
+        .sum()
+}
+
+fn check_root(tree: &Tree, fat: fatfs::FatType) -> anyhow::Result<()> {
+    let entries = root_entries(tree);
+
+    if fat != fatfs::FatType::Fat32 && entries > ROOT_ENTRIES {
+        anyhow::bail!(
+            "root directory needs {entries} entries, but FAT{} only has room for {ROOT_ENTRIES}",
+            bits(fat)
+        );
+    }
+
+    Ok(())
+}
+
+/// Pick the cluster size to format a partition as `fat`, and check that the tree fits in it.
+pub fn cluster_size(tree: &Tree, fat: fatfs::FatType, partition_size: u64) -> anyhow::Result<u32> {
+    check_root(tree, fat)?;
+
+    let sectors = partition_size / SECTOR;
+
+    let Some(bytes_per_cluster) = cluster_sizes().find(|&c| detect(sectors, c) == Some(fat)) else {
+        anyhow::bail!(
+            "a {partition_size} byte partition can not be formatted as FAT{}, which needs between {} and {} bytes",
+            bits(fat),
+            volume_size(fat, 512, min_clusters(fat) + MARGIN),
+            volume_size(fat, MAX_CLUSTER, max_clusters(fat) - MARGIN),
+        );
+    };
+
+    let needed = needed_clusters(tree, fat, bytes_per_cluster);
+    let available = data_clusters(fat, sectors, bytes_per_cluster).unwrap_or(0);
+
+    if needed > available {
+        anyhow::bail!(
+            "contents need {needed} clusters of {bytes_per_cluster} bytes, but the FAT{} partition only has {available}",
+            bits(fat)
+        );
+    }
+
+    Ok(bytes_per_cluster)
+}
+
+/// Estimate the partition size needed to store the tree as `fat`.
+pub fn estimate_size(tree: &Tree, fat: fatfs::FatType) -> anyhow::Result<u64> {
+    check_root(tree, fat)?;
+
+    let mut size = cluster_sizes()
+        .find_map(|c| {
+            let clusters = needed_clusters(tree, fat, c).max(min_clusters(fat) + MARGIN);
+            (clusters <= max_clusters(fat) - MARGIN).then(|| volume_size(fat, c, clusters))
+        })
+        .ok_or_else(|| {
+            anyhow::anyhow!(
+                "contents do not fit in FAT{} with clusters of up to {MAX_CLUSTER} bytes",
+                bits(fat)
+            )
+        })?;
+
+    // Formatting picks the smallest cluster size that works for the final size, which may have a
+    // bigger FAT than the one estimated for
+    loop {
+        let sectors = size / SECTOR;
+
+        let fits = cluster_sizes()
+            .find(|&c| detect(sectors, c) == Some(fat))
+            .and_then(|c| Some((c, data_clusters(fat, sectors, c)?)));
+
+        match fits {
+            Some((c, available)) if available >= needed_clusters(tree, fat, c) => return Ok(size),
+            Some((c, _)) => size += c as u64,
+            None => anyhow::bail!(
+                "contents do not fit in FAT{} with clusters of up to {MAX_CLUSTER} bytes",
+                bits(fat)
+            ),
+        }
+    }
+}
+
+/// Estimate the partition size needed to store the tree with `auto`, as the smallest of the
+/// variants the tree fits in.
+pub fn estimate_auto_size(tree: &Tree) -> anyhow::Result<u64> {
+    use fatfs::FatType::*;
+
+    [Fat12, Fat16, Fat32]
+        .into_iter()
+        .filter_map(|fat| estimate_size(tree, fat).ok())
+        .min()
+        .ok_or_else(|| anyhow::anyhow!("contents do not fit in any FAT variant"))
+}
+
+/// Variant and cluster size to format a partition with `auto`: the variant fatfs picks with 512
+/// byte clusters if the tree fits in it, or else the one the tree fits in with the smallest
+/// clusters. The latter covers the sizes fatfs can not format on its own.
+pub fn auto_format(tree: &Tree, partition_size: u64) -> anyhow::Result<(fatfs::FatType, u32)> {
+    use fatfs::FatType::*;
+
+    let sectors = partition_size / SECTOR;
+    let fits = |fat| {
+        let c = cluster_size(tree, fat, partition_size).ok()?;
+        Some((fat, c))
+    };
+
+    if let Some((fat, 512)) = detect(sectors, 512).and_then(fits) {
+        return Ok((fat, 512));
+    }
+
+    [Fat12, Fat16, Fat32]
+        .into_iter()
+        .filter_map(fits)
+        .min_by_key(|&(_, c)| c)
+        .ok_or_else(|| {
+            anyhow::anyhow!("contents do not fit in a {partition_size} byte FAT partition")
+        })
+}
+
+/// `time` clamped to the years FAT can represent.
+fn fat_time(time: chrono::NaiveDateTime) -> fatfs::DateTime {
+    use chrono::{Datelike, Timelike};
+
+    let year = time.year().clamp(1980, 2107) as u16;
+    let (date, time) = if year as i32 == time.year() {
+        (
+            fatfs::Date {
+                year,
+                month: time.month() as u16,
+                day: time.day() as u16,
+            },
+            fatfs::Time {
+                hour: time.hour() as u16,
+                min: time.minute() as u16,
+                sec: time.second() as u16,
+                millis: 0,
+            },
+        )
+    } else if year == 1980 {
+        (
+            fatfs::Date {
+                year,
+                month: 1,
+                day: 1,
+            },
+            fatfs::Time {
+                hour: 0,
+                min: 0,
+                sec: 0,
+                millis: 0,
+            },
+        )
+    } else {
+        (
+            fatfs::Date {
+                year,
+                month: 12,
+                day: 31,
+            },
+            fatfs::Time {
+                hour: 23,
+                min: 59,
+                sec: 58,
+                millis: 0,
+            },
+        )
+    };
+
+    fatfs::DateTime { date, time }
+}
+
+/// Time provider stamping files and directories with the time set before creating each of them,
+/// as fatfs stamps them with the current time.
+#[derive(Debug)]
+pub struct FixedTime(Cell<fatfs::DateTime>);
+
+impl FixedTime {
+    /// Provider starting out at `time`. fatfs keeps providers for the whole program, hence the
+    /// leak.
+    pub fn leak(time: chrono::NaiveDateTime) -> &'static Self {
+        Box::leak(Box::new(Self(Cell::new(fat_time(time)))))
+    }
+
+    /// Stamp the entries created from now on with `time`.
+    pub fn set(&self, time: chrono::NaiveDateTime) {
+        self.0.set(fat_time(time));
+    }
+}
+
+impl fatfs::TimeProvider for FixedTime {
+    fn get_current_date(&self) -> fatfs::Date {
+        self.0.get().date
+    }
+
+    fn get_current_date_time(&self) -> fatfs::DateTime {
+        self.0.get()
+    }
+}
+
+/// DOS date and time of an entry, as stored in directory entries.
+fn dos_time(time: fatfs::DateTime) -> [u8; 4] {
+    let t = time.time;
+    let d = time.date;
+    let time = t.hour << 11 | t.min << 5 | (t.sec / 2);
+    let date = ((d.year - 1980) << 9) | d.month << 5 | d.day;
+    let mut raw = [0; 4];
+    raw[..2].copy_from_slice(&time.to_le_bytes());
+    raw[2..].copy_from_slice(&date.to_le_bytes());
+    raw
+}
+
+/// Set the modification times of the entries of `tree` in the FAT filesystem fatfs wrote to the
+/// start of `dev`, to the times of their nodes in `time`.
+///
+/// fatfs stamps directories with the time entries are added to them at, and has no way to set the
+/// times of directories. Entries are matched with the children of their directory in the order
+/// they were created in, which is the order of the tree, as none were removed.
+pub fn stamp_times(
+    dev: &mut (impl Read + Write + Seek),
+    tree: &Tree,
+    time: impl Fn(i64) -> chrono::NaiveDateTime,
+) -> anyhow::Result<()> {
+    let bpb = Bpb::parse(&read_at(dev, 0, 512)?)?;
+    let bps = bpb.bytes_per_sector;
+    let cluster_bytes = bpb.sectors_per_cluster * bps;
+    let variant = bpb.variant();
+
+    let fat = read_at(dev, bpb.reserved * bps, bpb.fat_sectors * bps)?;
+    let entries = (0..bpb.clusters() as usize + 2)
+        .map(|n| get_entry(&fat, variant, n))
+        .collect::<Vec<_>>();
+    let offset = |c: u32| (bpb.data_start() + (c as u64 - 2) * bpb.sectors_per_cluster) * bps;
+
+    let mut children = vec![vec![]; tree.nodes.len()];
+    for (idx, node) in tree.nodes.iter().enumerate().skip(1) {
+        children[node.parent].push(idx);
+    }
+
+    let root = if bpb.fat32() {
+        let boot = read_at(dev, 0, 512)?;
+        Some(u32::from_le_bytes(boot[0x2c..0x30].try_into().unwrap()))
+    } else {
+        None
+    };
+    let mut dirs = vec![(0, root)];
+
+    while let Some((dir, first)) = dirs.pop() {
+        let ranges = match first {
+            None => vec![(bpb.fats_end() * bps, bpb.root_sectors() * bps)],
+            Some(first) => chain(&entries, first)
+                .into_iter()
+                .map(|c| (offset(c), cluster_bytes))
+                .collect(),
+        };
+        let mut nodes = children[dir].iter();
+
+        for (at, len) in ranges {
+            let mut data = read_at(dev, at, len)?;
+            let mut last = false;
+
+            for entry in data.chunks_mut(32) {
+                let attributes = entry[11];
+
+                // Free entries end the directory, and long names, labels and `.` and `..` are
+                // not nodes of the tree
+                if entry[0] == 0 {
+                    last = true;
+                    break;
+                }
+                if entry[0] == 0xe5
+                    || entry[0] == b'.'
+                    || attributes == 0x0f
+                    || attributes & 0x08 != 0
+                {
+                    continue;
+                }
+
+                let Some(&idx) = nodes.next() else {
+                    anyhow::bail!("the FAT directory has more entries than were written to it");
+                };
+                let node = &tree.nodes[idx];
+                entry[22..26].copy_from_slice(&dos_time(fat_time(time(node.mtime))));
+
+                if node.is_dir() {
+                    let high = if bpb.fat32() {
+                        u16::from_le_bytes([entry[0x14], entry[0x15]]) as u32
+                    } else {
+                        0
+                    };
+                    let first = high << 16 | u16::from_le_bytes([entry[0x1a], entry[0x1b]]) as u32;
+                    dirs.push((idx, Some(first)));
+                }
+            }
+
+            write_at(dev, at, &data)?;
+            if last {
+                break;
+            }
+        }
+    }
+
+    dev.flush()?;
+    Ok(())
+}
@@ -0,0 +1,462 @@
+//! In-place resizing of the FAT filesystems of existing images.
+//!
+//! Cluster numbers are relative to the start of the data area, so when the FATs need more
+//! sectors, the root directory and the data area move forward as a whole and no directory entry
+//! or cluster chain changes.
+
+use crate::fat::{bits, max_clusters, min_clusters};
+use log::*;
+use std::io::{Read, Seek, SeekFrom, Write};
+
+/// Bytes moved at a time when shifting the data area
+const CHUNK: u64 = 1 << 20;
+
+/// Fields of the BIOS parameter block that resizing reads or changes.
+#[derive(Clone, Debug)]
+pub struct Bpb {
+    pub bytes_per_sector: u64,
+    pub sectors_per_cluster: u64,
+    pub reserved: u64,
+    fats: u64,
+    root_entries: u64,
+    total_sectors: u64,
+    pub fat_sectors: u64,
+    /// FAT32 only, 0 otherwise
+    fs_info: u64,
+    backup_boot: u64,
+}
+
+impl Bpb {
+    pub fn parse(boot: &[u8]) -> anyhow::Result<Self> {
+        let u16_at = |o: usize| u16::from_le_bytes([boot[o], boot[o + 1]]) as u64;
+        let u32_at = |o: usize| u32::from_le_bytes(boot[o..o + 4].try_into().unwrap()) as u64;
+
+        let fat32 = u16_at(0x16) == 0;
+        let bpb = Self {
+            bytes_per_sector: u16_at(0x0b),
+            sectors_per_cluster: boot[0x0d] as u64,
+            reserved: u16_at(0x0e),
+            fats: boot[0x10] as u64,
+            root_entries: u16_at(0x11),
+            total_sectors: match u16_at(0x13) {
+                0 => u32_at(0x20),
+                n => n,
+            },
+            fat_sectors: if fat32 { u32_at(0x24) } else { u16_at(0x16) },
+            fs_info: if fat32 { u16_at(0x30) } else { 0 },
+            backup_boot: if fat32 { u16_at(0x32) } else { 0 },
+        };
+
+        if bpb.sectors_per_cluster == 0 || bpb.fats == 0 || bpb.data_start() >= bpb.total_sectors {
+            anyhow::bail!("the FAT boot sector is invalid");
+        }
+
+        Ok(bpb)
+    }
+
+    pub fn fat32(&self) -> bool {
+        self.fs_info != 0
+    }
+
+    pub fn root_sectors(&self) -> u64 {
+        (self.root_entries * 32).div_ceil(self.bytes_per_sector)
+    }
+
+    /// First sector of the root directory on FAT12 and FAT16, and of the data area on FAT32.
+    pub fn fats_end(&self) -> u64 {
+        self.reserved + self.fats * self.fat_sectors
+    }
+
+    pub fn data_start(&self) -> u64 {
+        self.fats_end() + self.root_sectors()
+    }
+
+    pub fn clusters(&self) -> u64 {
+        (self.total_sectors - self.data_start()) / self.sectors_per_cluster
+    }
+
+    pub fn variant(&self) -> fatfs::FatType {
+        use fatfs::FatType::*;
+        let clusters = self.clusters();
+        [Fat12, Fat16, Fat32]
+            .into_iter()
+            .find(|&fat| clusters <= max_clusters(fat))
+            .unwrap_or(Fat32)
+    }
+
+    /// Sectors each FAT of `variant` needs for `clusters` data clusters.
+    fn fat_sectors_for(&self, variant: fatfs::FatType, clusters: u64) -> u64 {
+        ((clusters + 2) * bits(variant))
+            .div_ceil(8)
+            .div_ceil(self.bytes_per_sector)
+    }
+
+    /// Write the sizes into a copy of the boot sector.
+    fn store(&self, boot: &mut [u8]) {
+        if !self.fat32() && self.total_sectors <= u16::MAX as u64 {
+            boot[0x13..0x15].copy_from_slice(&(self.total_sectors as u16).to_le_bytes());
+            boot[0x20..0x24].fill(0);
+        } else {
+            boot[0x13..0x15].fill(0);
+            boot[0x20..0x24].copy_from_slice(&(self.total_sectors as u32).to_le_bytes());
+        }
+
+        if self.fat32() {
+            boot[0x24..0x28].copy_from_slice(&(self.fat_sectors as u32).to_le_bytes());
+        } else {
+            boot[0x16..0x18].copy_from_slice(&(self.fat_sectors as u16).to_le_bytes());
+        }
+    }
+}
+
+pub fn read_at(dev: &mut (impl Read + Seek), offset: u64, len: u64) -> std::io::Result<Vec<u8>> {
+    let mut buf = vec![0; len as usize];
+    dev.seek(SeekFrom::Start(offset))?;
+    dev.read_exact(&mut buf)?;
+    Ok(buf)
+}
+
+pub fn write_at(dev: &mut (impl Write + Seek), offset: u64, buf: &[u8]) -> std::io::Result<()> {
+    dev.seek(SeekFrom::Start(offset))?;
+    dev.write_all(buf)
+}
+
+/// Move `len` bytes at `from` to `to`, in the order that copies everything before it is
+/// overwritten.
+fn move_bytes(
+    dev: &mut (impl Read + Write + Seek),
+    from: u64,
+    to: u64,
+    len: u64,
+) -> std::io::Result<()> {
+    let chunks = (0..len.div_ceil(CHUNK)).map(|i| (i * CHUNK, CHUNK.min(len - i * CHUNK)));
+    let chunks = chunks.collect::<Vec<_>>();
+
+    let mut copy = |(at, n): (u64, u64)| {
+        let buf = read_at(dev, from + at, n)?;
+        write_at(dev, to + at, &buf)
+    };
+
+    if to > from {
+        chunks.into_iter().rev().try_for_each(&mut copy)
+    } else {
+        chunks.into_iter().try_for_each(&mut copy)
+    }
+}
+
+pub fn get_entry(fat: &[u8], variant: fatfs::FatType, n: usize) -> u32 {
+    match variant {
+        fatfs::FatType::Fat12 => {
+            let i = n * 3 / 2;
+            let pair = u16::from_le_bytes([fat[i], fat[i + 1]]);
+            (if n.is_multiple_of(2) {
+                pair & 0xfff
+            } else {
+                pair >> 4
+            }) as u32
+        }
+        fatfs::FatType::Fat16 => u16::from_le_bytes([fat[n * 2], fat[n * 2 + 1]]) as u32,
+        fatfs::FatType::Fat32 => {
+            u32::from_le_bytes(fat[n * 4..n * 4 + 4].try_into().unwrap()) & 0x0fff_ffff
+        }
+    }
+}
+
+fn set_entry(fat: &mut [u8], variant: fatfs::FatType, n: usize, value: u32) {
+    match variant {
+        fatfs::FatType::Fat12 => {
+            let i = n * 3 / 2;
+            let pair = u16::from_le_bytes([fat[i], fat[i + 1]]);
+            let pair = if n.is_multiple_of(2) {
+                (pair & 0xf000) | value as u16
+            } else {
+                (pair & 0x000f) | (value as u16) << 4
+            };
+            fat[i..i + 2].copy_from_slice(&pair.to_le_bytes());
+        }
+        fatfs::FatType::Fat16 => {
+            fat[n * 2..n * 2 + 2].copy_from_slice(&(value as u16).to_le_bytes())
+        }
+        fatfs::FatType::Fat32 => fat[n * 4..n * 4 + 4].copy_from_slice(&value.to_le_bytes()),
+    }
+}
+
+/// Write the boot sector, its backup and the FSInfo sectors with the new sizes.
+fn store_boot(
+    dev: &mut (impl Read + Write + Seek),
+    bpb: &Bpb,
+    boot: &mut [u8],
+    free: u64,
+) -> std::io::Result<()> {
+    let bps = bpb.bytes_per_sector;
+
+    bpb.store(boot);
+    write_at(dev, 0, boot)?;
+
+    if bpb.fat32() {
+        if bpb.backup_boot != 0 {
+            write_at(dev, bpb.backup_boot * bps, boot)?;
+        }
+
+        // The backup boot sectors hold a copy of the FSInfo sector at the same offset
+        let mut sectors = vec![bpb.fs_info];
+        if bpb.backup_boot != 0 {
+            sectors.push(bpb.backup_boot + bpb.fs_info);
+        }
+
+        for sector in sectors {
+            let mut info = read_at(dev, sector * bps, 512)?;
+            if info[..4] != *b"RRaA" {
+                continue;
+            }
+
+            // The next free cluster hint is left for the driver to find again
+            info[0x1e8..0x1ec].copy_from_slice(&(free as u32).to_le_bytes());
+            info[0x1ec..0x1f0].copy_from_slice(&u32::MAX.to_le_bytes());
+            write_at(dev, sector * bps, &info)?;
+        }
+    }
+
+    dev.flush()
+}
+
+/// Sizes of the FAT filesystem `old` grown to fill `size` bytes, keeping its variant and cluster
+/// size, or an error if those cannot address that many clusters.
+fn plan_grow(old: &Bpb, size: u64) -> anyhow::Result<Bpb> {
+    let bps = old.bytes_per_sector;
+    let variant = old.variant();
+    let mut new = old.clone();
+    new.total_sectors = size / bps;
+
+    // Bigger FATs take sectors from the data area, so iterate until the sizes agree
+    loop {
+        let available = (new.total_sectors - new.data_start()) / new.sectors_per_cluster;
+        let clusters = available.min(max_clusters(variant));
+        let fat_sectors = new.fat_sectors_for(variant, clusters).max(old.fat_sectors);
+
+        if fat_sectors == new.fat_sectors {
+            if clusters < available {
+                let max = (new.data_start() + clusters * new.sectors_per_cluster) * bps;
+                anyhow::bail!(
+                    "FAT{} with {} byte clusters grows to at most {max} bytes, short of \
+                     {size}; build the image with a larger --fat-type for room to grow",
+                    bits(variant),
+                    new.sectors_per_cluster * bps
+                );
+            }
+            return Ok(new);
+        }
+        new.fat_sectors = fat_sectors;
+    }
+}
+
+/// Check that the FAT filesystem at the start of `dev` can grow to fill `size` bytes, before
+/// anything around it changes.
+pub fn check_grow(dev: &mut (impl Read + Seek), size: u64) -> anyhow::Result<()> {
+    let old = Bpb::parse(&read_at(dev, 0, 512)?)?;
+    if size / old.bytes_per_sector > old.total_sectors {
+        plan_grow(&old, size)?;
+    }
+    Ok(())
+}
+
+/// Grow the FAT filesystem at the start of `dev` to fill `size` bytes, keeping its variant and
+/// cluster size.
+///
+/// Returns the new size of the filesystem.
+pub fn grow(dev: &mut (impl Read + Write + Seek), size: u64) -> anyhow::Result<u64> {
+    let mut boot = read_at(dev, 0, 512)?;
+    let old = Bpb::parse(&boot)?;
+    let bps = old.bytes_per_sector;
+
+    if size / bps <= old.total_sectors {
+        return Ok(old.total_sectors * bps);
+    }
+
+    let variant = old.variant();
+    let new = plan_grow(&old, size)?;
+
+    debug!(
+        "Growing {variant:?} from {} to {} sectors, FATs from {} to {} sectors",
+        old.total_sectors, new.total_sectors, old.fat_sectors, new.fat_sectors
+    );
+
+    let mut fat = read_at(dev, old.reserved * bps, old.fat_sectors * bps)?;
+    let used = (2..old.clusters() as usize + 2)
+        .filter(|&c| get_entry(&fat, variant, c) != 0)
+        .count() as u64;
+
+    if new.fat_sectors != old.fat_sectors {
+        let from = old.fats_end() * bps;
+        let to = new.fats_end() * bps;
+        move_bytes(dev, from, to, old.total_sectors * bps - from)?;
+    }
+
+    // Entries of the new clusters are zero, which marks them free
+    fat.resize((new.fat_sectors * bps) as usize, 0);
+    for i in 0..new.fats {
+        write_at(dev, (new.reserved + i * new.fat_sectors) * bps, &fat)?;
+    }
+
+    store_boot(dev, &new, &mut boot, new.clusters() - used)?;
+
+    Ok(new.total_sectors * bps)
+}
+
+/// Clusters of the chain starting at `first`, following `entries`.
+pub fn chain(entries: &[u32], first: u32) -> Vec<u32> {
+    let mut clusters = vec![];
+    let mut cluster = first;
+
+    // Bounded by the number of entries, in case the chain loops
+    while (2..entries.len() as u32).contains(&cluster) && clusters.len() < entries.len() {
+        clusters.push(cluster);
+        cluster = entries[cluster as usize];
+    }
+
+    clusters
+}
+
+/// Move the used clusters of the FAT filesystem at the start of `dev` to the front of the data
+/// area, and cut the filesystem down to them and `slack` bytes of free space.
+///
+/// The variant is kept, so the filesystem does not get smaller than its minimum cluster count.
+/// Returns the new size of the filesystem.
+pub fn shrink(dev: &mut (impl Read + Write + Seek), slack: u64) -> anyhow::Result<u64> {
+    let mut boot = read_at(dev, 0, 512)?;
+    let old = Bpb::parse(&boot)?;
+    let bps = old.bytes_per_sector;
+    let cluster_bytes = old.sectors_per_cluster * bps;
+    let variant = old.variant();
+
+    // FAT entries, the first two of which are reserved
+    let count = old.clusters() as usize + 2;
+    let fat = read_at(dev, old.reserved * bps, old.fat_sectors * bps)?;
+    let entries = (0..count)
+        .map(|n| get_entry(&fat, variant, n))
+        .collect::<Vec<_>>();
+
+    let used = entries[2..].iter().filter(|&&e| e != 0).count() as u64;
+    let clusters = (used + slack.div_ceil(cluster_bytes))
+        .max(min_clusters(variant))
+        .min(old.clusters());
+
+    if clusters == old.clusters() {
+        return Ok(old.total_sectors * bps);
+    }
+
+    debug!(
+        "Shrinking {variant:?} from {} to {clusters} clusters, {used} of them used",
+        old.clusters()
+    );
+
+    // Clusters past the new end take the lowest free ones before it
+    let end = clusters as usize + 2;
+    let mut map = (0..count as u32).collect::<Vec<_>>();
+    let mut free = (2..end).filter(|&c| entries[c] == 0);
+    for cluster in (end..count).filter(|&c| entries[c] != 0) {
+        map[cluster] = free.next().unwrap() as u32;
+    }
+
+    let offset = |c: u32| (old.data_start() + (c as u64 - 2) * old.sectors_per_cluster) * bps;
+    let remap = |value: u32| match map.get(value as usize) {
+        Some(&moved) if value >= 2 => moved,
+        _ => value,
+    };
+
+    for (cluster, &to) in map.iter().enumerate().skip(end) {
+        if to != cluster as u32 {
+            let data = read_at(dev, offset(cluster as u32), cluster_bytes)?;
+            write_at(dev, offset(to), &data)?;
+        }
+    }
+
+    let mut moved = vec![0; end];
+    moved[..2].copy_from_slice(&entries[..2]);
+    for cluster in (2..count).filter(|&c| entries[c] != 0) {
+        moved[map[cluster] as usize] = remap(entries[cluster]);
+    }
+
+    // Point directory entries at the new first clusters of their files, starting at the root
+    let mut dirs = vec![if old.fat32() {
+        let root = u32::from_le_bytes(boot[0x2c..0x30].try_into().unwrap());
+        let root = remap(root);
+        boot[0x2c..0x30].copy_from_slice(&root.to_le_bytes());
+        Some(root)
+    } else {
+        None
+    }];
+
+    while let Some(dir) = dirs.pop() {
+        let ranges = match dir {
+            None => vec![(old.fats_end() * bps, old.root_sectors() * bps)],
+            Some(first) => chain(&moved, first)
+                .into_iter()
+                .map(|c| (offset(c), cluster_bytes))
+                .collect(),
+        };
+
+        for (at, len) in ranges {
+            let mut data = read_at(dev, at, len)?;
+            let mut last = false;
+
+            for entry in data.chunks_mut(32) {
+                let attributes = entry[11];
+
+                // Free entries end the directory, deleted ones, long names and labels are skipped
+                if entry[0] == 0 {
+                    last = true;
+                    break;
+                }
+                if entry[0] == 0xe5 || attributes == 0x0f || attributes & 0x08 != 0 {
+                    continue;
+                }
+
+                let high = if old.fat32() {
+                    u16::from_le_bytes([entry[0x14], entry[0x15]]) as u32
+                } else {
+                    0
+                };
+                let first = high << 16 | u16::from_le_bytes([entry[0x1a], entry[0x1b]]) as u32;
+                let first = remap(first);
+
+                if old.fat32() {
+                    entry[0x14..0x16].copy_from_slice(&((first >> 16) as u16).to_le_bytes());
+                }
+                entry[0x1a..0x1c].copy_from_slice(&(first as u16).to_le_bytes());
+
+                // `.` and `..` point at directories that are already being walked
+                if attributes & 0x10 != 0 && entry[0] != b'.' && first >= 2 {
+                    dirs.push(Some(first));
+                }
+            }
+
+            write_at(dev, at, &data)?;
+            if last {
+                break;
+            }
+        }
+    }
+
+    let mut new = old.clone();
+    new.fat_sectors = new.fat_sectors_for(variant, clusters).min(old.fat_sectors);
+    new.total_sectors = new.data_start() + clusters * new.sectors_per_cluster;
+
+    // Smaller FATs move the root directory and the remaining data area back
+    if new.fat_sectors != old.fat_sectors {
+        let len = (old.root_sectors() + clusters * old.sectors_per_cluster) * bps;
+        move_bytes(dev, old.fats_end() * bps, new.fats_end() * bps, len)?;
+    }
+
+    let mut fat = vec![0; (new.fat_sectors * bps) as usize];
+    for (n, &value) in moved.iter().enumerate() {
+        set_entry(&mut fat, variant, n, value);
+    }
+    for i in 0..new.fats {
+        write_at(dev, (new.reserved + i * new.fat_sectors) * bps, &fat)?;
+    }
+
+    store_boot(dev, &new, &mut boot, clusters - used)?;
+
+    Ok(new.total_sectors * bps)
+}
@@ -0,0 +1,84 @@
+//! Lists of the files of the input directory to put in the image, as given with --files-from.
+
+use crate::tree::{Kind, Tree};
+use crate::FileSource;
+use log::*;
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Component, Path, PathBuf};
+
+/// Read a NUL separated list from `list`, or from standard input if it is `-`.
+fn read_list(list: &Path) -> anyhow::Result<Vec<PathBuf>> {
+    let mut data = vec![];
+    if list == Path::new("-") {
+        io::stdin().read_to_end(&mut data)?;
+    } else {
+        File::open(list)
+            .and_then(|mut f| f.read_to_end(&mut data))
+            .map_err(|e| anyhow::anyhow!("cannot read {}: {e}", list.display()))?;
+    }
+
+    let mut paths = vec![];
+
+    for entry in data.split(|&b| b == 0).filter(|e| !e.is_empty()) {
+        let name = std::str::from_utf8(entry)
+            .map_err(|_| anyhow::anyhow!("non-UTF-8 path in the file list"))?;
+
+        let mut path = PathBuf::new();
+        for part in Path::new(name).components() {
+            match part {
+                Component::Normal(part) => path.push(part),
+                Component::CurDir => {}
+                _ => anyhow::bail!("`{name}` in the file list is not inside the input directory"),
+            }
+        }
+
+        if !path.as_os_str().is_empty() {
+            paths.push(path);
+        }
+    }
+
+    Ok(paths)
+}
+
+/// Tree of the files of `root` named in `list`, along with the directories leading to them.
+/// Symbolic links are skipped unless `link_follow`. Returns the number of entries.
+pub fn read(root: &Path, list: &Path, link_follow: bool) -> anyhow::Result<(Tree, usize)> {
+    let paths = read_list(list)?;
+    let meta = fs::metadata(root)?;
+    let mut tree = Tree::new(meta.mode() & 0o7777, meta.mtime());
+
+    for path in &paths {
+        // Parents take the modes and times of the originals, outermost first
+        let mut parents = path.ancestors().skip(1).collect::<Vec<_>>();
+        parents.pop();
+        for dir in parents.into_iter().rev() {
+            let meta = fs::metadata(root.join(dir))?;
+            tree.insert(dir, Kind::Dir(vec![]), meta.mode() & 0o7777, meta.mtime())?;
+        }
+
+        let src = root.join(path);
+        let meta = if link_follow {
+            fs::metadata(&src)
+        } else {
+            fs::symlink_metadata(&src)
+        }
+        .map_err(|e| anyhow::anyhow!("cannot read {}: {e}", src.display()))?;
+
+        let kind = if meta.is_dir() {
+            Kind::Dir(vec![])
+        } else if meta.is_symlink() {
+            warn!("Skipping symlink - {}", path.display());
+            continue;
+        } else {
+            Kind::File {
+                source: FileSource::Host(src),
+                len: meta.len(),
+            }
+        };
+        tree.insert(path, kind, meta.mode() & 0o7777, meta.mtime())?;
+    }
+
+    Ok((tree, paths.len()))
+}
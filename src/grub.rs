@@ -0,0 +1,93 @@
+//! Installation of GRUB for BIOS, as done by `grub-bios-setup`.
+//!
+//! `core.img` is embedded into sectors that no partition uses, the gap after the MBR or a BIOS
+//! boot partition on GPT. The block list at the end of its first sector is pointed at the rest of
+//! it, and `boot.img` in the MBR at the first sector. Reed-Solomon codes are not added, as with
+//! `grub-bios-setup --no-rs-codes`.
+
+use anyhow::Context;
+use std::fs::{self, File};
+use std::io::{Seek, SeekFrom, Write};
+use std::path::Path;
+
+const SECTOR_SIZE: u64 = 512;
+
+/// Offsets in `boot.img`
+const KERNEL_SECTOR: usize = 0x5c;
+const BOOT_DRIVE: usize = 0x64;
+const DRIVE_CHECK: usize = 0x66;
+/// The BIOS parameter block, kept from the existing sector
+const BPB: std::ops::Range<usize> = 0x03..0x5a;
+
+/// Segment the second sector of `core.img` is loaded at, after the first at 0x8000.
+const CORE_SEGMENT: u16 = 0x820;
+
+/// Size of a block list entry: start sector, sector count and load segment.
+const BLOCK_LEN: usize = 12;
+
+fn read(dir: &Path, name: &str) -> anyhow::Result<Vec<u8>> {
+    let path = dir.join(name);
+    fs::read(&path).with_context(|| format!("cannot read {}", path.display()))
+}
+
+/// Install GRUB from `boot.img` and `core.img` in `dir`, embedding the latter in the bytes
+/// `start..end` of `file`.
+pub fn install(file: &File, dir: &Path, start: u64, end: u64) -> anyhow::Result<()> {
+    let mut boot = read(dir, "boot.img")?;
+    let mut core = read(dir, "core.img")?;
+
+    if boot.len() != SECTOR_SIZE as usize || boot[510..] != [0x55, 0xaa] {
+        anyhow::bail!("boot.img is not a boot sector");
+    }
+    if core.len() < SECTOR_SIZE as usize {
+        anyhow::bail!("core.img is shorter than a sector");
+    }
+
+    core.resize(core.len().next_multiple_of(SECTOR_SIZE as usize), 0);
+
+    let sectors = core.len() as u64 / SECTOR_SIZE;
+    let first = start / SECTOR_SIZE;
+    let room = (end - start) / SECTOR_SIZE;
+
+    if sectors > room {
+        anyhow::bail!("core.img needs {sectors} sectors, only {room} are free to embed it in");
+    }
+
+    // Clean out the block lists, which run backwards from the end of the first sector
+    let mut block = SECTOR_SIZE as usize - BLOCK_LEN;
+    while core[block + 8..block + 10] != [0, 0] {
+        core[block..block + BLOCK_LEN].fill(0);
+        block = block
+            .checked_sub(BLOCK_LEN)
+            .filter(|&b| b > 0)
+            .context("no terminator in the block lists of core.img")?;
+    }
+
+    // The sectors after the first are contiguous, so a single block list covers them
+    if sectors > 1 {
+        let block = SECTOR_SIZE as usize - BLOCK_LEN;
+        let len = u16::try_from(sectors - 1).context("core.img is too large")?;
+        core[block..block + 8].copy_from_slice(&(first + 1).to_le_bytes());
+        core[block + 8..block + 10].copy_from_slice(&len.to_le_bytes());
+        core[block + 10..block + 12].copy_from_slice(&CORE_SEGMENT.to_le_bytes());
+    }
+
+    boot[KERNEL_SECTOR..KERNEL_SECTOR + 8].copy_from_slice(&first.to_le_bytes());
+    // Take the drive number from the BIOS, and skip the check for buggy BIOSes passing a floppy
+    // drive, as done for hard disks
+    boot[BOOT_DRIVE] = 0xff;
+    boot[DRIVE_CHECK..DRIVE_CHECK + 2].copy_from_slice(&[0x90, 0x90]);
+
+    let mut file = file;
+
+    file.seek(SeekFrom::Start(start))?;
+    file.write_all(&core)?;
+
+    // Up to the disk signature, keeping the BPB area of the existing MBR
+    file.seek(SeekFrom::Start(0))?;
+    file.write_all(&boot[..BPB.start])?;
+    file.seek(SeekFrom::Start(BPB.end as u64))?;
+    file.write_all(&boot[BPB.end..440])?;
+
+    Ok(())
+}
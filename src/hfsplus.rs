@@ -0,0 +1,934 @@
+//! HFS+ images, for media Macs boot from.
+//!
+//! The allocation bitmap, an empty extents overflow B-tree and the catalog B-tree follow the
+//! volume header, and then the data of every file, each in a single extent. Names are stored in
+//! canonical decomposition and the catalog is ordered without regard to case, as Mac OS compares
+//! them. Directories can be blessed, which is how Macs find the folder they boot from.
+
+use crate::tree::{Kind, Tree};
+use std::cmp::Ordering;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+const BLOCK_SIZE: u64 = 4096;
+/// Size of catalog and extents overflow B-tree nodes, a block each
+const NODE_SIZE: usize = 4096;
+
+/// Volume header, and its copy 1024 bytes before the end of the volume
+const HEADER_OFFSET: u64 = 1024;
+const SIGNATURE: &[u8; 2] = b"H+";
+const VERSION: u16 = 4;
+/// Cleanly unmounted, so it mounts without a check
+const ATTR_UNMOUNTED: u32 = 1 << 8;
+/// Seconds from the start of 1904, when HFS times count from, to the Unix epoch
+const HFS_EPOCH: i64 = 2_082_844_800;
+
+const ROOT_PARENT_ID: u32 = 1;
+const ROOT_ID: u32 = 2;
+/// Catalog node IDs below this are reserved
+const FIRST_USER_ID: u32 = 16;
+
+const FOLDER_RECORD: u16 = 1;
+const FILE_RECORD: u16 = 2;
+const FOLDER_THREAD: u16 = 3;
+const FILE_THREAD: u16 = 4;
+/// Files have a thread record
+const THREAD_EXISTS: u16 = 0x2;
+
+const FOLDER_RECORD_SIZE: usize = 88;
+const FILE_RECORD_SIZE: usize = 248;
+
+const NODE_LEAF: i8 = -1;
+const NODE_INDEX: i8 = 0;
+const NODE_HEADER: i8 = 1;
+const NODE_MAP: i8 = 2;
+
+/// Key lengths are 16 bits, and index records have keys as long as those of the leaves
+const BIG_KEYS: u32 = 0x2;
+const VARIABLE_INDEX_KEYS: u32 = 0x4;
+const CATALOG_KEY_MAX: u16 = 516;
+const EXTENTS_KEY_MAX: u16 = 10;
+
+/// Nodes of the extents overflow file, which only has its header node in use
+const EXTENTS_NODES: u32 = 8;
+/// Nodes the map record of the header node keeps track of
+const HEADER_MAP_NODES: u32 = (NODE_SIZE as u32 - 256) * 8;
+/// Nodes a map node keeps track of
+const MAP_NODE_NODES: u32 = (NODE_SIZE as u32 - 18) * 8;
+
+const S_IFDIR: u16 = 0o040000;
+const S_IFREG: u16 = 0o100000;
+const S_IFLNK: u16 = 0o120000;
+
+#[derive(Clone, Debug, Default)]
+pub struct Options {
+    pub label: Option<String>,
+    /// Directory Macs boot from, by path
+    pub blessed: Option<PathBuf>,
+}
+
+/// Canonical decomposition of a UTF-16 code unit, as HFS+ stores names. Hangul syllables are
+/// decomposed algorithmically, the rest by table.
+fn decompose(u: u16, out: &mut Vec<u16>) {
+    const HANGUL: u16 = 0xac00;
+    const HANGUL_COUNT: u16 = 11172;
+
+    if (HANGUL..HANGUL + HANGUL_COUNT).contains(&u) {
+        let s = u - HANGUL;
+        out.push(0x1100 + s / 588);
+        out.push(0x1161 + s % 588 / 28);
+        if !s.is_multiple_of(28) {
+            out.push(0x11a7 + s % 28);
+        }
+        return;
+    }
+
+    match DECOMPOSITIONS.binary_search_by_key(&u, |d| d[0]) {
+        Ok(i) => {
+            let [_, first, second] = DECOMPOSITIONS[i];
+            decompose(first, out);
+            if second != 0 {
+                out.push(second);
+            }
+        }
+        Err(_) => out.push(u),
+    }
+}
+
+/// Name as HFS+ stores it: UTF-16 in canonical decomposition, with `:` as `/`, which is what
+/// Mac OS shows as `:`.
+fn hfs_name(name: &str) -> Vec<u16> {
+    let mut out = vec![];
+    for c in name.chars() {
+        let c = if c == ':' { '/' } else { c };
+        let mut buf = [0u16; 2];
+        match *c.encode_utf16(&mut buf) {
+            [u] => decompose(u, &mut out),
+            ref pair => out.extend_from_slice(pair),
+        }
+    }
+    out
+}
+
+/// Case folded code unit as Mac OS compares names, or 0 for those it ignores. Only the scripts
+/// Mac OS folds are, which are those with case in Unicode 2.0.
+fn fold(u: u16) -> u16 {
+    match u {
+        0x200c..=0x200f | 0x202a..=0x202e | 0x206a..=0x206f | 0xfeff => 0,
+        // Georgian capitals fold to the letters of modern Georgian
+        0x10a0..=0x10c5 => u + 0x30,
+        0x0000..=0x024f
+        | 0x0370..=0x04ff
+        | 0x0531..=0x0556
+        | 0x2160..=0x216f
+        | 0x24b6..=0x24cf
+        | 0xff21..=0xff3a => {
+            let mut lower = char::from_u32(u as u32)
+                .into_iter()
+                .flat_map(char::to_lowercase);
+            match (lower.next(), lower.next()) {
+                (Some(c), None) if (c as u32) < 0x10000 => c as u16,
+                _ => u,
+            }
+        }
+        _ => u,
+    }
+}
+
+/// Order of names in the catalog, which does not tell names apart by case.
+fn compare(a: &[u16], b: &[u16]) -> Ordering {
+    let folded = |s: &[u16]| {
+        s.iter()
+            .map(|&u| fold(u))
+            .filter(|&u| u != 0)
+            .collect::<Vec<_>>()
+    };
+    folded(a).cmp(&folded(b))
+}
+
+/// Seconds since 1904 of a Unix time, clamped to what HFS+ stores.
+fn hfs_time(secs: i64) -> u32 {
+    (secs + HFS_EPOCH).clamp(0, u32::MAX as i64) as u32
+}
+
+fn put_u16(d: &mut [u8], at: usize, v: u16) {
+    d[at..at + 2].copy_from_slice(&v.to_be_bytes());
+}
+
+fn put_u32(d: &mut [u8], at: usize, v: u32) {
+    d[at..at + 4].copy_from_slice(&v.to_be_bytes());
+}
+
+/// Fork data of `len` bytes in a single extent of `blocks` blocks at `start`.
+fn fork(field: &mut [u8], len: u64, blocks: u32, start: u32) {
+    field[0..8].copy_from_slice(&len.to_be_bytes());
+    put_u32(field, 12, blocks);
+    if blocks > 0 {
+        put_u32(field, 16, start);
+        put_u32(field, 20, blocks);
+    }
+}
+
+/// Catalog key of `name` in the folder with ID `parent`.
+fn catalog_key(parent: u32, name: &[u16]) -> Vec<u8> {
+    let mut key = vec![0u8; 8];
+    put_u16(&mut key, 0, 6 + 2 * name.len() as u16);
+    put_u32(&mut key, 2, parent);
+    put_u16(&mut key, 6, name.len() as u16);
+    key.extend(name.iter().flat_map(|u| u.to_be_bytes()));
+    key
+}
+
+/// B-tree record: its key, and then the data, or the node it points to for index records.
+struct Record {
+    key: Vec<u8>,
+    data: Vec<u8>,
+}
+
+/// Node holding `records`, with their offsets from the end.
+fn node(kind: i8, height: u8, links: (u32, u32), records: &[Vec<u8>]) -> Vec<u8> {
+    let mut d = vec![0u8; NODE_SIZE];
+    put_u32(&mut d, 0, links.0);
+    put_u32(&mut d, 4, links.1);
+    d[8] = kind as u8;
+    d[9] = height;
+    put_u16(&mut d, 10, records.len() as u16);
+
+    let mut offset = 14;
+    // Offsets of every record and then of the free space
+    for (i, r) in records.iter().chain([&vec![]]).enumerate() {
+        put_u16(&mut d, NODE_SIZE - 2 * (i + 1), offset as u16);
+        d[offset..offset + r.len()].copy_from_slice(r);
+        offset += r.len();
+    }
+    d
+}
+
+/// Used nodes of a B-tree, after its header node.
+struct BTree {
+    nodes: Vec<Vec<u8>>,
+    root: u32,
+    depth: u16,
+    leaf_records: u32,
+    leaves: u32,
+}
+
+impl BTree {
+    /// Nodes of sorted `records`: the leaves, and then every index level up to the root.
+    fn new(records: Vec<Record>) -> Self {
+        let leaf_records = records.len() as u32;
+        let mut nodes = vec![];
+        let mut records = records;
+        let mut kind = NODE_LEAF;
+        let mut depth = 0;
+        let mut leaves = 0;
+        let mut root = 0;
+
+        while !records.is_empty() {
+            depth += 1;
+
+            // As many records in each node as fit with their offsets
+            let mut groups: Vec<Vec<Record>> = vec![];
+            let mut used = NODE_SIZE;
+            for r in records {
+                let len = r.key.len() + r.data.len() + 2;
+                if used + len > NODE_SIZE - 16 {
+                    groups.push(vec![]);
+                    used = 0;
+                }
+                used += len;
+                groups.last_mut().unwrap().push(r);
+            }
+
+            let first = nodes.len() as u32 + 1;
+            let count = groups.len() as u32;
+            if kind == NODE_LEAF {
+                leaves = count;
+            }
+            records = vec![];
+            for (i, group) in groups.into_iter().enumerate() {
+                let i = i as u32;
+                let next = if i + 1 < count { first + i + 1 } else { 0 };
+                let prev = if i > 0 { first + i - 1 } else { 0 };
+                let data = group
+                    .iter()
+                    .map(|r| [&r.key[..], &r.data[..]].concat())
+                    .collect::<Vec<_>>();
+                nodes.push(node(kind, depth as u8, (next, prev), &data));
+                records.push(Record {
+                    key: group[0].key.clone(),
+                    data: (first + i).to_be_bytes().to_vec(),
+                });
+            }
+
+            if count == 1 {
+                root = first;
+                break;
+            }
+            kind = NODE_INDEX;
+        }
+
+        Self {
+            nodes,
+            root,
+            depth,
+            leaf_records,
+            leaves,
+        }
+    }
+
+    /// Map nodes needed behind the used nodes to keep track of `total` nodes.
+    fn map_nodes(total: u32) -> u32 {
+        total
+            .saturating_sub(HEADER_MAP_NODES)
+            .div_ceil(MAP_NODE_NODES)
+    }
+
+    /// Nodes in use, with the header and map nodes, of a file of `total` nodes.
+    fn used(&self, total: u32) -> u32 {
+        1 + self.nodes.len() as u32 + Self::map_nodes(total)
+    }
+
+    /// Contents of a B-tree file of `total` nodes.
+    fn file(&self, total: u32, key_max: u16, attributes: u32) -> Vec<u8> {
+        let used = self.used(total);
+        let first_map = 1 + self.nodes.len() as u32;
+        let maps = Self::map_nodes(total);
+
+        let mut header = vec![0u8; 106];
+        put_u16(&mut header, 0, self.depth);
+        put_u32(&mut header, 2, self.root);
+        put_u32(&mut header, 6, self.leaf_records);
+        put_u32(&mut header, 10, if self.leaves > 0 { 1 } else { 0 });
+        put_u32(&mut header, 14, self.leaves);
+        put_u16(&mut header, 18, NODE_SIZE as u16);
+        put_u16(&mut header, 20, key_max);
+        put_u32(&mut header, 22, total);
+        put_u32(&mut header, 26, total - used);
+        put_u32(&mut header, 32, NODE_SIZE as u32);
+        put_u32(&mut header, 38, attributes);
+
+        // Bitmap of the nodes in use, continued in the map nodes
+        let mut map = vec![0u8; (HEADER_MAP_NODES + maps * MAP_NODE_NODES) as usize / 8];
+        for i in 0..used as usize {
+            map[i / 8] |= 0x80 >> (i % 8);
+        }
+        let (header_map, rest) = map.split_at(HEADER_MAP_NODES as usize / 8);
+
+        let mut out = node(
+            NODE_HEADER,
+            0,
+            (if maps > 0 { first_map } else { 0 }, 0),
+            &[header, vec![0; 128], header_map.to_vec()],
+        );
+        for n in &self.nodes {
+            out.extend(n);
+        }
+        for (i, chunk) in rest.chunks(MAP_NODE_NODES as usize / 8).enumerate() {
+            let i = i as u32;
+            let next = if i + 1 < maps { first_map + i + 1 } else { 0 };
+            out.extend(node(NODE_MAP, 0, (next, 0), &[chunk.to_vec()]));
+        }
+        out.resize(total as usize * NODE_SIZE, 0);
+        out
+    }
+}
+
+/// Nodes of a catalog file with `used` nodes in use, leaving room to add files.
+fn catalog_nodes(used: u32) -> u32 {
+    let total = used + used.div_ceil(4).max(8);
+    total + BTree::map_nodes(total)
+}
+
+/// Finder type and creator of a file: those of symbolic links, and of the files in the blessed
+/// folder Macs boot from.
+fn finder_info(tree: &Tree, idx: usize, blessed: Option<usize>) -> Option<(&[u8; 4], &[u8; 4])> {
+    let node = &tree.nodes[idx];
+    if let Kind::Symlink(_) = node.kind {
+        return Some((b"slnk", b"rhap"));
+    }
+    if Some(node.parent) != blessed {
+        return None;
+    }
+    match node.name.as_str() {
+        // Open Firmware booting Mac OS X, and Mac OS 9
+        "BootX" => Some((b"tbxi", b"chrp")),
+        "System" => Some((b"zsys", b"MACS")),
+        "Finder" => Some((b"FNDR", b"MACS")),
+        _ => None,
+    }
+}
+
+/// Placement of the special files and data in the volume, in blocks.
+struct Layout {
+    /// Name of every node in its parent directory, and of the volume for the root
+    names: Vec<Vec<u16>>,
+    /// Catalog node ID of every node
+    ids: Vec<u32>,
+    blessed: Option<usize>,
+    bitmap_blocks: u32,
+    extents: u32,
+    catalog: u32,
+    catalog_nodes: u32,
+    /// First block of the data of every file and symbolic link
+    data: Vec<u32>,
+    /// Blocks used from the start of the volume
+    used: u64,
+}
+
+impl Layout {
+    /// Lay out the tree in a volume of `blocks` blocks.
+    fn new(tree: &Tree, opts: &Options, blocks: u64) -> anyhow::Result<Self> {
+        let mut names = vec![vec![]; tree.nodes.len()];
+        names[0] = hfs_name(opts.label.as_deref().unwrap_or("untitled"));
+        if names[0].len() > 255 {
+            anyhow::bail!("HFS+ volume names are limited to 255 UTF-16 characters");
+        }
+        for (idx, node) in tree.nodes.iter().enumerate().skip(1) {
+            names[idx] = hfs_name(&node.name);
+            if names[idx].len() > 255 {
+                anyhow::bail!(
+                    "HFS+ names are limited to 255 UTF-16 characters when decomposed: {}",
+                    node.path.display()
+                );
+            }
+        }
+
+        let ids = (0..tree.nodes.len() as u32)
+            .map(|i| {
+                if i == 0 {
+                    ROOT_ID
+                } else {
+                    FIRST_USER_ID + i - 1
+                }
+            })
+            .collect();
+
+        let blessed = match &opts.blessed {
+            Some(path) => Some(
+                path.strip_prefix("/")
+                    .unwrap_or(path)
+                    .iter()
+                    .try_fold(0, |dir, name| tree.child(dir, name.to_str()?))
+                    .filter(|&idx| tree.nodes[idx].is_dir())
+                    .ok_or_else(|| {
+                        anyhow::anyhow!("blessed folder {} is not a directory", path.display())
+                    })?,
+            ),
+            None => None,
+        };
+
+        let bitmap_blocks = blocks.div_ceil(8).div_ceil(BLOCK_SIZE).max(1) as u32;
+        let extents = 1 + bitmap_blocks;
+        let catalog = extents + EXTENTS_NODES;
+        let mut layout = Self {
+            names,
+            ids,
+            blessed,
+            bitmap_blocks,
+            extents,
+            catalog,
+            catalog_nodes: 0,
+            data: vec![0; tree.nodes.len()],
+            used: 0,
+        };
+
+        // The number of catalog nodes does not depend on where the data is
+        layout.catalog_nodes = catalog_nodes(layout.catalog(tree)?.used(0));
+
+        let mut next = (catalog + layout.catalog_nodes) as u64;
+        for (idx, node) in tree.nodes.iter().enumerate() {
+            let len = match &node.kind {
+                Kind::Dir(_) => continue,
+                Kind::File { len, .. } => *len,
+                Kind::Symlink(target) => target.len() as u64,
+            };
+            layout.data[idx] = next.min(u32::MAX as u64) as u32;
+            next += len.div_ceil(BLOCK_SIZE);
+        }
+        layout.used = next;
+
+        Ok(layout)
+    }
+
+    /// Catalog B-tree: a folder or file record and a thread record for every node.
+    fn catalog(&self, tree: &Tree) -> anyhow::Result<BTree> {
+        let mut records = vec![];
+        for (idx, node) in tree.nodes.iter().enumerate() {
+            let id = self.ids[idx];
+            let parent = if idx == 0 {
+                ROOT_PARENT_ID
+            } else {
+                self.ids[node.parent]
+            };
+            let time = hfs_time(node.mtime);
+            let name = &self.names[idx];
+
+            let (mut d, thread) = match &node.kind {
+                Kind::Dir(children) => {
+                    let mut d = vec![0u8; FOLDER_RECORD_SIZE];
+                    put_u16(&mut d, 0, FOLDER_RECORD);
+                    put_u32(&mut d, 4, children.len() as u32);
+                    put_u16(&mut d, 42, S_IFDIR | (node.mode & 0o7777) as u16);
+                    (d, FOLDER_THREAD)
+                }
+                Kind::File { .. } | Kind::Symlink(_) => {
+                    let (len, file_type) = match &node.kind {
+                        Kind::File { len, .. } => (*len, S_IFREG),
+                        Kind::Symlink(target) => (target.len() as u64, S_IFLNK),
+                        Kind::Dir(_) => unreachable!(),
+                    };
+                    let mut d = vec![0u8; FILE_RECORD_SIZE];
+                    put_u16(&mut d, 0, FILE_RECORD);
+                    put_u16(&mut d, 2, THREAD_EXISTS);
+                    put_u16(&mut d, 42, file_type | (node.mode & 0o7777) as u16);
+                    if let Some((ty, creator)) = finder_info(tree, idx, self.blessed) {
+                        d[48..52].copy_from_slice(ty);
+                        d[52..56].copy_from_slice(creator);
+                    }
+                    let blocks = len.div_ceil(BLOCK_SIZE) as u32;
+                    fork(&mut d[88..168], len, blocks, self.data[idx]);
+                    (d, FILE_THREAD)
+                }
+            };
+            put_u32(&mut d, 8, id);
+            // Created, modified, attributes modified and accessed
+            for at in [12, 16, 20, 24] {
+                put_u32(&mut d, at, time);
+            }
+            put_u32(&mut d, 32, node.uid);
+            put_u32(&mut d, 36, node.gid);
+            records.push((parent, name.clone(), idx, d));
+
+            // Thread record, keyed by the ID, leading back to the parent and name
+            let mut t = vec![0u8; 8];
+            put_u16(&mut t, 0, thread);
+            put_u32(&mut t, 4, parent);
+            t.extend((name.len() as u16).to_be_bytes());
+            t.extend(name.iter().flat_map(|u| u.to_be_bytes()));
+            records.push((id, vec![], idx, t));
+        }
+
+        records.sort_by(|a, b| a.0.cmp(&b.0).then_with(|| compare(&a.1, &b.1)));
+        for pair in records.windows(2) {
+            if pair[0].0 == pair[1].0 && compare(&pair[0].1, &pair[1].1).is_eq() {
+                anyhow::bail!(
+                    "{} and {} differ only in case or normalization, which HFS+ does not tell apart",
+                    tree.nodes[pair[0].2].path.display(),
+                    tree.nodes[pair[1].2].path.display()
+                );
+            }
+        }
+
+        Ok(BTree::new(
+            records
+                .into_iter()
+                .map(|(parent, name, _, data)| Record {
+                    key: catalog_key(parent, &name),
+                    data,
+                })
+                .collect(),
+        ))
+    }
+}
+
+/// Blocks of a volume holding the tree, including the bitmap for them and the last block, which
+/// has the copy of the volume header.
+fn volume_blocks(tree: &Tree, opts: &Options) -> anyhow::Result<u64> {
+    let mut blocks = 0;
+    loop {
+        let used = Layout::new(tree, opts, blocks)?.used + 1;
+        if used <= blocks {
+            return Ok(blocks);
+        }
+        blocks = used;
+    }
+}
+
+/// Smallest filesystem size the tree fits in.
+pub fn estimate_size(tree: &Tree, opts: &Options) -> anyhow::Result<u64> {
+    Ok(volume_blocks(tree, opts)? * BLOCK_SIZE)
+}
+
+/// Write the image into `out`, which spans `size` bytes.
+///
+/// `on_file` is called with the image path and length of every file written.
+pub fn write<W: Write + Seek>(
+    out: &mut W,
+    size: u64,
+    tree: &Tree,
+    opts: &Options,
+    ctx: &crate::BuildContext,
+    on_file: &mut dyn FnMut(&Path, u64),
+) -> anyhow::Result<()> {
+    let blocks = (size / BLOCK_SIZE).min(u32::MAX as u64);
+    let layout = Layout::new(tree, opts, blocks)?;
+
+    if layout.used + 1 > blocks {
+        anyhow::bail!(
+            "HFS+ image needs {} bytes, but only {size} are available",
+            (layout.used + 1) * BLOCK_SIZE
+        );
+    }
+
+    // Allocation bitmap, with the last block taken by the copy of the volume header
+    let mut bitmap = vec![0u8; layout.bitmap_blocks as usize * BLOCK_SIZE as usize];
+    for i in (0..layout.used as usize).chain([blocks as usize - 1]) {
+        bitmap[i / 8] |= 0x80 >> (i % 8);
+    }
+    out.seek(SeekFrom::Start(BLOCK_SIZE))?;
+    out.write_all(&bitmap)?;
+
+    out.seek(SeekFrom::Start(layout.extents as u64 * BLOCK_SIZE))?;
+    out.write_all(&BTree::new(vec![]).file(EXTENTS_NODES, EXTENTS_KEY_MAX, BIG_KEYS))?;
+
+    let catalog = layout.catalog(tree)?;
+    out.seek(SeekFrom::Start(layout.catalog as u64 * BLOCK_SIZE))?;
+    out.write_all(&catalog.file(
+        layout.catalog_nodes,
+        CATALOG_KEY_MAX,
+        BIG_KEYS | VARIABLE_INDEX_KEYS,
+    ))?;
+
+    for (idx, node) in tree.nodes.iter().enumerate() {
+        let start = layout.data[idx] as u64 * BLOCK_SIZE;
+        match &node.kind {
+            Kind::Dir(_) => {}
+            Kind::File { source, len } => {
+                out.seek(SeekFrom::Start(start))?;
+                let copied = io::copy(&mut source.open()?.take(*len), out)?;
+                if copied != *len {
+                    anyhow::bail!("{} changed while being copied", node.path.display());
+                }
+                on_file(&node.path, *len);
+            }
+            Kind::Symlink(target) => {
+                out.seek(SeekFrom::Start(start))?;
+                out.write_all(target.as_bytes())?;
+            }
+        }
+    }
+
+    let files = tree.nodes.iter().filter(|n| !n.is_dir()).count() as u32;
+    let folders = tree.nodes.iter().filter(|n| n.is_dir()).count() as u32 - 1;
+    let time = hfs_time(ctx.time.timestamp());
+
+    let mut d = vec![0u8; 512];
+    d[0..2].copy_from_slice(SIGNATURE);
+    put_u16(&mut d, 2, VERSION);
+    put_u32(&mut d, 4, ATTR_UNMOUNTED);
+    d[8..12].copy_from_slice(b"10.0");
+    // Created, modified and checked
+    for at in [16, 20, 28] {
+        put_u32(&mut d, at, time);
+    }
+    put_u32(&mut d, 32, files);
+    put_u32(&mut d, 36, folders);
+    put_u32(&mut d, 40, BLOCK_SIZE as u32);
+    put_u32(&mut d, 44, blocks as u32);
+    put_u32(&mut d, 48, (blocks - layout.used - 1) as u32);
+    put_u32(&mut d, 52, layout.used as u32);
+    put_u32(&mut d, 56, 64 << 10);
+    put_u32(&mut d, 60, 64 << 10);
+    put_u32(&mut d, 64, FIRST_USER_ID + tree.nodes.len() as u32 - 1);
+    put_u32(&mut d, 68, 1);
+    // Names in Mac OS Roman
+    d[72..80].copy_from_slice(&1u64.to_be_bytes());
+
+    // Finder information: the blessed folder, for Mac OS 9 and X, and what Intel Macs boot
+    if let Some(blessed) = layout.blessed {
+        put_u32(&mut d, 80, layout.ids[blessed]);
+        if let Some(efi) = tree.child(blessed, "boot.efi") {
+            put_u32(&mut d, 84, layout.ids[efi]);
+        }
+        put_u32(&mut d, 100, layout.ids[blessed]);
+    }
+    ctx.random.fill(&mut d[104..112])?;
+
+    let bitmap_len = layout.bitmap_blocks as u64 * BLOCK_SIZE;
+    fork(&mut d[112..192], bitmap_len, layout.bitmap_blocks, 1);
+    let extents_len = EXTENTS_NODES as u64 * NODE_SIZE as u64;
+    fork(&mut d[192..272], extents_len, EXTENTS_NODES, layout.extents);
+    put_u32(&mut d, 192 + 8, extents_len as u32);
+    let catalog_len = layout.catalog_nodes as u64 * NODE_SIZE as u64;
+    fork(
+        &mut d[272..352],
+        catalog_len,
+        layout.catalog_nodes,
+        layout.catalog,
+    );
+    put_u32(&mut d, 272 + 8, catalog_len as u32);
+
+    for offset in [HEADER_OFFSET, size - HEADER_OFFSET] {
+        out.seek(SeekFrom::Start(offset))?;
+        out.write_all(&d)?;
+    }
+
+    Ok(())
+}
+
+impl crate::FsWriter for Options {
+    fn estimate_size(&self, tree: &Tree) -> anyhow::Result<u64> {
+        estimate_size(tree, self)
+    }
+
+    fn write(
+        &self,
+        mut out: &mut dyn fatfs::ReadWriteSeek,
+        size: u64,
+        tree: &Tree,
+        ctx: &crate::BuildContext,
+        on_file: &mut dyn FnMut(&Path, u64),
+    ) -> anyhow::Result<()> {
+        write(&mut out, size, tree, self, ctx, on_file)
+    }
+}
+
+/// Canonical decompositions of the Basic Multilingual Plane HFS+ decomposes, from Unicode 3.2 as
+/// Mac OS uses: each character and the one or two it decomposes to, which may decompose further.
+#[rustfmt::skip]
+const DECOMPOSITIONS: &[[u16; 3]] = &[
+    [0x00c0, 0x0041, 0x0300], [0x00c1, 0x0041, 0x0301], [0x00c2, 0x0041, 0x0302], [0x00c3, 0x0041, 0x0303],
+    [0x00c4, 0x0041, 0x0308], [0x00c5, 0x0041, 0x030a], [0x00c7, 0x0043, 0x0327], [0x00c8, 0x0045, 0x0300],
+    [0x00c9, 0x0045, 0x0301], [0x00ca, 0x0045, 0x0302], [0x00cb, 0x0045, 0x0308], [0x00cc, 0x0049, 0x0300],
+    [0x00cd, 0x0049, 0x0301], [0x00ce, 0x0049, 0x0302], [0x00cf, 0x0049, 0x0308], [0x00d1, 0x004e, 0x0303],
+    [0x00d2, 0x004f, 0x0300], [0x00d3, 0x004f, 0x0301], [0x00d4, 0x004f, 0x0302], [0x00d5, 0x004f, 0x0303],
+    [0x00d6, 0x004f, 0x0308], [0x00d9, 0x0055, 0x0300], [0x00da, 0x0055, 0x0301], [0x00db, 0x0055, 0x0302],
+    [0x00dc, 0x0055, 0x0308], [0x00dd, 0x0059, 0x0301], [0x00e0, 0x0061, 0x0300], [0x00e1, 0x0061, 0x0301],
+    [0x00e2, 0x0061, 0x0302], [0x00e3, 0x0061, 0x0303], [0x00e4, 0x0061, 0x0308], [0x00e5, 0x0061, 0x030a],
+    [0x00e7, 0x0063, 0x0327], [0x00e8, 0x0065, 0x0300], [0x00e9, 0x0065, 0x0301], [0x00ea, 0x0065, 0x0302],
+    [0x00eb, 0x0065, 0x0308], [0x00ec, 0x0069, 0x0300], [0x00ed, 0x0069, 0x0301], [0x00ee, 0x0069, 0x0302],
+    [0x00ef, 0x0069, 0x0308], [0x00f1, 0x006e, 0x0303], [0x00f2, 0x006f, 0x0300], [0x00f3, 0x006f, 0x0301],
+    [0x00f4, 0x006f, 0x0302], [0x00f5, 0x006f, 0x0303], [0x00f6, 0x006f, 0x0308], [0x00f9, 0x0075, 0x0300],
+    [0x00fa, 0x0075, 0x0301], [0x00fb, 0x0075, 0x0302], [0x00fc, 0x0075, 0x0308], [0x00fd, 0x0079, 0x0301],
+    [0x00ff, 0x0079, 0x0308], [0x0100, 0x0041, 0x0304], [0x0101, 0x0061, 0x0304], [0x0102, 0x0041, 0x0306],
+    [0x0103, 0x0061, 0x0306], [0x0104, 0x0041, 0x0328], [0x0105, 0x0061, 0x0328], [0x0106, 0x0043, 0x0301],
+    [0x0107, 0x0063, 0x0301], [0x0108, 0x0043, 0x0302], [0x0109, 0x0063, 0x0302], [0x010a, 0x0043, 0x0307],
+    [0x010b, 0x0063, 0x0307], [0x010c, 0x0043, 0x030c], [0x010d, 0x0063, 0x030c], [0x010e, 0x0044, 0x030c],
+    [0x010f, 0x0064, 0x030c], [0x0112, 0x0045, 0x0304], [0x0113, 0x0065, 0x0304], [0x0114, 0x0045, 0x0306],
+    [0x0115, 0x0065, 0x0306], [0x0116, 0x0045, 0x0307], [0x0117, 0x0065, 0x0307], [0x0118, 0x0045, 0x0328],
+    [0x0119, 0x0065, 0x0328], [0x011a, 0x0045, 0x030c], [0x011b, 0x0065, 0x030c], [0x011c, 0x0047, 0x0302],
+    [0x011d, 0x0067, 0x0302], [0x011e, 0x0047, 0x0306], [0x011f, 0x0067, 0x0306], [0x0120, 0x0047, 0x0307],
+    [0x0121, 0x0067, 0x0307], [0x0122, 0x0047, 0x0327], [0x0123, 0x0067, 0x0327], [0x0124, 0x0048, 0x0302],
+    [0x0125, 0x0068, 0x0302], [0x0128, 0x0049, 0x0303], [0x0129, 0x0069, 0x0303], [0x012a, 0x0049, 0x0304],
+    [0x012b, 0x0069, 0x0304], [0x012c, 0x0049, 0x0306], [0x012d, 0x0069, 0x0306], [0x012e, 0x0049, 0x0328],
+    [0x012f, 0x0069, 0x0328], [0x0130, 0x0049, 0x0307], [0x0134, 0x004a, 0x0302], [0x0135, 0x006a, 0x0302],
+    [0x0136, 0x004b, 0x0327], [0x0137, 0x006b, 0x0327], [0x0139, 0x004c, 0x0301], [0x013a, 0x006c, 0x0301],
+    [0x013b, 0x004c, 0x0327], [0x013c, 0x006c, 0x0327], [0x013d, 0x004c, 0x030c], [0x013e, 0x006c, 0x030c],
+    [0x0143, 0x004e, 0x0301], [0x0144, 0x006e, 0x0301], [0x0145, 0x004e, 0x0327], [0x0146, 0x006e, 0x0327],
+    [0x0147, 0x004e, 0x030c], [0x0148, 0x006e, 0x030c], [0x014c, 0x004f, 0x0304], [0x014d, 0x006f, 0x0304],
+    [0x014e, 0x004f, 0x0306], [0x014f, 0x006f, 0x0306], [0x0150, 0x004f, 0x030b], [0x0151, 0x006f, 0x030b],
+    [0x0154, 0x0052, 0x0301], [0x0155, 0x0072, 0x0301], [0x0156, 0x0052, 0x0327], [0x0157, 0x0072, 0x0327],
+    [0x0158, 0x0052, 0x030c], [0x0159, 0x0072, 0x030c], [0x015a, 0x0053, 0x0301], [0x015b, 0x0073, 0x0301],
+    [0x015c, 0x0053, 0x0302], [0x015d, 0x0073, 0x0302], [0x015e, 0x0053, 0x0327], [0x015f, 0x0073, 0x0327],
+    [0x0160, 0x0053, 0x030c], [0x0161, 0x0073, 0x030c], [0x0162, 0x0054, 0x0327], [0x0163, 0x0074, 0x0327],
+    [0x0164, 0x0054, 0x030c], [0x0165, 0x0074, 0x030c], [0x0168, 0x0055, 0x0303], [0x0169, 0x0075, 0x0303],
+    [0x016a, 0x0055, 0x0304], [0x016b, 0x0075, 0x0304], [0x016c, 0x0055, 0x0306], [0x016d, 0x0075, 0x0306],
+    [0x016e, 0x0055, 0x030a], [0x016f, 0x0075, 0x030a], [0x0170, 0x0055, 0x030b], [0x0171, 0x0075, 0x030b],
+    [0x0172, 0x0055, 0x0328], [0x0173, 0x0075, 0x0328], [0x0174, 0x0057, 0x0302], [0x0175, 0x0077, 0x0302],
+    [0x0176, 0x0059, 0x0302], [0x0177, 0x0079, 0x0302], [0x0178, 0x0059, 0x0308], [0x0179, 0x005a, 0x0301],
+    [0x017a, 0x007a, 0x0301], [0x017b, 0x005a, 0x0307], [0x017c, 0x007a, 0x0307], [0x017d, 0x005a, 0x030c],
+    [0x017e, 0x007a, 0x030c], [0x01a0, 0x004f, 0x031b], [0x01a1, 0x006f, 0x031b], [0x01af, 0x0055, 0x031b],
+    [0x01b0, 0x0075, 0x031b], [0x01cd, 0x0041, 0x030c], [0x01ce, 0x0061, 0x030c], [0x01cf, 0x0049, 0x030c],
+    [0x01d0, 0x0069, 0x030c], [0x01d1, 0x004f, 0x030c], [0x01d2, 0x006f, 0x030c], [0x01d3, 0x0055, 0x030c],
+    [0x01d4, 0x0075, 0x030c], [0x01d5, 0x00dc, 0x0304], [0x01d6, 0x00fc, 0x0304], [0x01d7, 0x00dc, 0x0301],
+    [0x01d8, 0x00fc, 0x0301], [0x01d9, 0x00dc, 0x030c], [0x01da, 0x00fc, 0x030c], [0x01db, 0x00dc, 0x0300],
+    [0x01dc, 0x00fc, 0x0300], [0x01de, 0x00c4, 0x0304], [0x01df, 0x00e4, 0x0304], [0x01e0, 0x0226, 0x0304],
+    [0x01e1, 0x0227, 0x0304], [0x01e2, 0x00c6, 0x0304], [0x01e3, 0x00e6, 0x0304], [0x01e6, 0x0047, 0x030c],
+    [0x01e7, 0x0067, 0x030c], [0x01e8, 0x004b, 0x030c], [0x01e9, 0x006b, 0x030c], [0x01ea, 0x004f, 0x0328],
+    [0x01eb, 0x006f, 0x0328], [0x01ec, 0x01ea, 0x0304], [0x01ed, 0x01eb, 0x0304], [0x01ee, 0x01b7, 0x030c],
+    [0x01ef, 0x0292, 0x030c], [0x01f0, 0x006a, 0x030c], [0x01f4, 0x0047, 0x0301], [0x01f5, 0x0067, 0x0301],
+    [0x01f8, 0x004e, 0x0300], [0x01f9, 0x006e, 0x0300], [0x01fa, 0x00c5, 0x0301], [0x01fb, 0x00e5, 0x0301],
+    [0x01fc, 0x00c6, 0x0301], [0x01fd, 0x00e6, 0x0301], [0x01fe, 0x00d8, 0x0301], [0x01ff, 0x00f8, 0x0301],
+    [0x0200, 0x0041, 0x030f], [0x0201, 0x0061, 0x030f], [0x0202, 0x0041, 0x0311], [0x0203, 0x0061, 0x0311],
+    [0x0204, 0x0045, 0x030f], [0x0205, 0x0065, 0x030f], [0x0206, 0x0045, 0x0311], [0x0207, 0x0065, 0x0311],
+    [0x0208, 0x0049, 0x030f], [0x0209, 0x0069, 0x030f], [0x020a, 0x0049, 0x0311], [0x020b, 0x0069, 0x0311],
+    [0x020c, 0x004f, 0x030f], [0x020d, 0x006f, 0x030f], [0x020e, 0x004f, 0x0311], [0x020f, 0x006f, 0x0311],
+    [0x0210, 0x0052, 0x030f], [0x0211, 0x0072, 0x030f], [0x0212, 0x0052, 0x0311], [0x0213, 0x0072, 0x0311],
+    [0x0214, 0x0055, 0x030f], [0x0215, 0x0075, 0x030f], [0x0216, 0x0055, 0x0311], [0x0217, 0x0075, 0x0311],
+    [0x0218, 0x0053, 0x0326], [0x0219, 0x0073, 0x0326], [0x021a, 0x0054, 0x0326], [0x021b, 0x0074, 0x0326],
+    [0x021e, 0x0048, 0x030c], [0x021f, 0x0068, 0x030c], [0x0226, 0x0041, 0x0307], [0x0227, 0x0061, 0x0307],
+    [0x0228, 0x0045, 0x0327], [0x0229, 0x0065, 0x0327], [0x022a, 0x00d6, 0x0304], [0x022b, 0x00f6, 0x0304],
+    [0x022c, 0x00d5, 0x0304], [0x022d, 0x00f5, 0x0304], [0x022e, 0x004f, 0x0307], [0x022f, 0x006f, 0x0307],
+    [0x0230, 0x022e, 0x0304], [0x0231, 0x022f, 0x0304], [0x0232, 0x0059, 0x0304], [0x0233, 0x0079, 0x0304],
+    [0x0340, 0x0300, 0x0000], [0x0341, 0x0301, 0x0000], [0x0343, 0x0313, 0x0000], [0x0344, 0x0308, 0x0301],
+    [0x0374, 0x02b9, 0x0000], [0x037e, 0x003b, 0x0000], [0x0385, 0x00a8, 0x0301], [0x0386, 0x0391, 0x0301],
+    [0x0387, 0x00b7, 0x0000], [0x0388, 0x0395, 0x0301], [0x0389, 0x0397, 0x0301], [0x038a, 0x0399, 0x0301],
+    [0x038c, 0x039f, 0x0301], [0x038e, 0x03a5, 0x0301], [0x038f, 0x03a9, 0x0301], [0x0390, 0x03ca, 0x0301],
+    [0x03aa, 0x0399, 0x0308], [0x03ab, 0x03a5, 0x0308], [0x03ac, 0x03b1, 0x0301], [0x03ad, 0x03b5, 0x0301],
+    [0x03ae, 0x03b7, 0x0301], [0x03af, 0x03b9, 0x0301], [0x03b0, 0x03cb, 0x0301], [0x03ca, 0x03b9, 0x0308],
+    [0x03cb, 0x03c5, 0x0308], [0x03cc, 0x03bf, 0x0301], [0x03cd, 0x03c5, 0x0301], [0x03ce, 0x03c9, 0x0301],
+    [0x03d3, 0x03d2, 0x0301], [0x03d4, 0x03d2, 0x0308], [0x0400, 0x0415, 0x0300], [0x0401, 0x0415, 0x0308],
+    [0x0403, 0x0413, 0x0301], [0x0407, 0x0406, 0x0308], [0x040c, 0x041a, 0x0301], [0x040d, 0x0418, 0x0300],
+    [0x040e, 0x0423, 0x0306], [0x0419, 0x0418, 0x0306], [0x0439, 0x0438, 0x0306], [0x0450, 0x0435, 0x0300],
+    [0x0451, 0x0435, 0x0308], [0x0453, 0x0433, 0x0301], [0x0457, 0x0456, 0x0308], [0x045c, 0x043a, 0x0301],
+    [0x045d, 0x0438, 0x0300], [0x045e, 0x0443, 0x0306], [0x0476, 0x0474, 0x030f], [0x0477, 0x0475, 0x030f],
+    [0x04c1, 0x0416, 0x0306], [0x04c2, 0x0436, 0x0306], [0x04d0, 0x0410, 0x0306], [0x04d1, 0x0430, 0x0306],
+    [0x04d2, 0x0410, 0x0308], [0x04d3, 0x0430, 0x0308], [0x04d6, 0x0415, 0x0306], [0x04d7, 0x0435, 0x0306],
+    [0x04da, 0x04d8, 0x0308], [0x04db, 0x04d9, 0x0308], [0x04dc, 0x0416, 0x0308], [0x04dd, 0x0436, 0x0308],
+    [0x04de, 0x0417, 0x0308], [0x04df, 0x0437, 0x0308], [0x04e2, 0x0418, 0x0304], [0x04e3, 0x0438, 0x0304],
+    [0x04e4, 0x0418, 0x0308], [0x04e5, 0x0438, 0x0308], [0x04e6, 0x041e, 0x0308], [0x04e7, 0x043e, 0x0308],
+    [0x04ea, 0x04e8, 0x0308], [0x04eb, 0x04e9, 0x0308], [0x04ec, 0x042d, 0x0308], [0x04ed, 0x044d, 0x0308],
+    [0x04ee, 0x0423, 0x0304], [0x04ef, 0x0443, 0x0304], [0x04f0, 0x0423, 0x0308], [0x04f1, 0x0443, 0x0308],
+    [0x04f2, 0x0423, 0x030b], [0x04f3, 0x0443, 0x030b], [0x04f4, 0x0427, 0x0308], [0x04f5, 0x0447, 0x0308],
+    [0x04f8, 0x042b, 0x0308], [0x04f9, 0x044b, 0x0308], [0x0622, 0x0627, 0x0653], [0x0623, 0x0627, 0x0654],
+    [0x0624, 0x0648, 0x0654], [0x0625, 0x0627, 0x0655], [0x0626, 0x064a, 0x0654], [0x06c0, 0x06d5, 0x0654],
+    [0x06c2, 0x06c1, 0x0654], [0x06d3, 0x06d2, 0x0654], [0x0929, 0x0928, 0x093c], [0x0931, 0x0930, 0x093c],
+    [0x0934, 0x0933, 0x093c], [0x0958, 0x0915, 0x093c], [0x0959, 0x0916, 0x093c], [0x095a, 0x0917, 0x093c],
+    [0x095b, 0x091c, 0x093c], [0x095c, 0x0921, 0x093c], [0x095d, 0x0922, 0x093c], [0x095e, 0x092b, 0x093c],
+    [0x095f, 0x092f, 0x093c], [0x09cb, 0x09c7, 0x09be], [0x09cc, 0x09c7, 0x09d7], [0x09dc, 0x09a1, 0x09bc],
+    [0x09dd, 0x09a2, 0x09bc], [0x09df, 0x09af, 0x09bc], [0x0a33, 0x0a32, 0x0a3c], [0x0a36, 0x0a38, 0x0a3c],
+    [0x0a59, 0x0a16, 0x0a3c], [0x0a5a, 0x0a17, 0x0a3c], [0x0a5b, 0x0a1c, 0x0a3c], [0x0a5e, 0x0a2b, 0x0a3c],
+    [0x0b48, 0x0b47, 0x0b56], [0x0b4b, 0x0b47, 0x0b3e], [0x0b4c, 0x0b47, 0x0b57], [0x0b5c, 0x0b21, 0x0b3c],
+    [0x0b5d, 0x0b22, 0x0b3c], [0x0b94, 0x0b92, 0x0bd7], [0x0bca, 0x0bc6, 0x0bbe], [0x0bcb, 0x0bc7, 0x0bbe],
+    [0x0bcc, 0x0bc6, 0x0bd7], [0x0c48, 0x0c46, 0x0c56], [0x0cc0, 0x0cbf, 0x0cd5], [0x0cc7, 0x0cc6, 0x0cd5],
+    [0x0cc8, 0x0cc6, 0x0cd6], [0x0cca, 0x0cc6, 0x0cc2], [0x0ccb, 0x0cca, 0x0cd5], [0x0d4a, 0x0d46, 0x0d3e],
+    [0x0d4b, 0x0d47, 0x0d3e], [0x0d4c, 0x0d46, 0x0d57], [0x0dda, 0x0dd9, 0x0dca], [0x0ddc, 0x0dd9, 0x0dcf],
+    [0x0ddd, 0x0ddc, 0x0dca], [0x0dde, 0x0dd9, 0x0ddf], [0x0f43, 0x0f42, 0x0fb7], [0x0f4d, 0x0f4c, 0x0fb7],
+    [0x0f52, 0x0f51, 0x0fb7], [0x0f57, 0x0f56, 0x0fb7], [0x0f5c, 0x0f5b, 0x0fb7], [0x0f69, 0x0f40, 0x0fb5],
+    [0x0f73, 0x0f71, 0x0f72], [0x0f75, 0x0f71, 0x0f74], [0x0f76, 0x0fb2, 0x0f80], [0x0f78, 0x0fb3, 0x0f80],
+    [0x0f81, 0x0f71, 0x0f80], [0x0f93, 0x0f92, 0x0fb7], [0x0f9d, 0x0f9c, 0x0fb7], [0x0fa2, 0x0fa1, 0x0fb7],
+    [0x0fa7, 0x0fa6, 0x0fb7], [0x0fac, 0x0fab, 0x0fb7], [0x0fb9, 0x0f90, 0x0fb5], [0x1026, 0x1025, 0x102e],
+    [0x1e00, 0x0041, 0x0325], [0x1e01, 0x0061, 0x0325], [0x1e02, 0x0042, 0x0307], [0x1e03, 0x0062, 0x0307],
+    [0x1e04, 0x0042, 0x0323], [0x1e05, 0x0062, 0x0323], [0x1e06, 0x0042, 0x0331], [0x1e07, 0x0062, 0x0331],
+    [0x1e08, 0x00c7, 0x0301], [0x1e09, 0x00e7, 0x0301], [0x1e0a, 0x0044, 0x0307], [0x1e0b, 0x0064, 0x0307],
+    [0x1e0c, 0x0044, 0x0323], [0x1e0d, 0x0064, 0x0323], [0x1e0e, 0x0044, 0x0331], [0x1e0f, 0x0064, 0x0331],
+    [0x1e10, 0x0044, 0x0327], [0x1e11, 0x0064, 0x0327], [0x1e12, 0x0044, 0x032d], [0x1e13, 0x0064, 0x032d],
+    [0x1e14, 0x0112, 0x0300], [0x1e15, 0x0113, 0x0300], [0x1e16, 0x0112, 0x0301], [0x1e17, 0x0113, 0x0301],
+    [0x1e18, 0x0045, 0x032d], [0x1e19, 0x0065, 0x032d], [0x1e1a, 0x0045, 0x0330], [0x1e1b, 0x0065, 0x0330],
+    [0x1e1c, 0x0228, 0x0306], [0x1e1d, 0x0229, 0x0306], [0x1e1e, 0x0046, 0x0307], [0x1e1f, 0x0066, 0x0307],
+    [0x1e20, 0x0047, 0x0304], [0x1e21, 0x0067, 0x0304], [0x1e22, 0x0048, 0x0307], [0x1e23, 0x0068, 0x0307],
+    [0x1e24, 0x0048, 0x0323], [0x1e25, 0x0068, 0x0323], [0x1e26, 0x0048, 0x0308], [0x1e27, 0x0068, 0x0308],
+    [0x1e28, 0x0048, 0x0327], [0x1e29, 0x0068, 0x0327], [0x1e2a, 0x0048, 0x032e], [0x1e2b, 0x0068, 0x032e],
+    [0x1e2c, 0x0049, 0x0330], [0x1e2d, 0x0069, 0x0330], [0x1e2e, 0x00cf, 0x0301], [0x1e2f, 0x00ef, 0x0301],
+    [0x1e30, 0x004b, 0x0301], [0x1e31, 0x006b, 0x0301], [0x1e32, 0x004b, 0x0323], [0x1e33, 0x006b, 0x0323],
+    [0x1e34, 0x004b, 0x0331], [0x1e35, 0x006b, 0x0331], [0x1e36, 0x004c, 0x0323], [0x1e37, 0x006c, 0x0323],
+    [0x1e38, 0x1e36, 0x0304], [0x1e39, 0x1e37, 0x0304], [0x1e3a, 0x004c, 0x0331], [0x1e3b, 0x006c, 0x0331],
+    [0x1e3c, 0x004c, 0x032d], [0x1e3d, 0x006c, 0x032d], [0x1e3e, 0x004d, 0x0301], [0x1e3f, 0x006d, 0x0301],
+    [0x1e40, 0x004d, 0x0307], [0x1e41, 0x006d, 0x0307], [0x1e42, 0x004d, 0x0323], [0x1e43, 0x006d, 0x0323],
+    [0x1e44, 0x004e, 0x0307], [0x1e45, 0x006e, 0x0307], [0x1e46, 0x004e, 0x0323], [0x1e47, 0x006e, 0x0323],
+    [0x1e48, 0x004e, 0x0331], [0x1e49, 0x006e, 0x0331], [0x1e4a, 0x004e, 0x032d], [0x1e4b, 0x006e, 0x032d],
+    [0x1e4c, 0x00d5, 0x0301], [0x1e4d, 0x00f5, 0x0301], [0x1e4e, 0x00d5, 0x0308], [0x1e4f, 0x00f5, 0x0308],
+    [0x1e50, 0x014c, 0x0300], [0x1e51, 0x014d, 0x0300], [0x1e52, 0x014c, 0x0301], [0x1e53, 0x014d, 0x0301],
+    [0x1e54, 0x0050, 0x0301], [0x1e55, 0x0070, 0x0301], [0x1e56, 0x0050, 0x0307], [0x1e57, 0x0070, 0x0307],
+    [0x1e58, 0x0052, 0x0307], [0x1e59, 0x0072, 0x0307], [0x1e5a, 0x0052, 0x0323], [0x1e5b, 0x0072, 0x0323],
+    [0x1e5c, 0x1e5a, 0x0304], [0x1e5d, 0x1e5b, 0x0304], [0x1e5e, 0x0052, 0x0331], [0x1e5f, 0x0072, 0x0331],
+    [0x1e60, 0x0053, 0x0307], [0x1e61, 0x0073, 0x0307], [0x1e62, 0x0053, 0x0323], [0x1e63, 0x0073, 0x0323],
+    [0x1e64, 0x015a, 0x0307], [0x1e65, 0x015b, 0x0307], [0x1e66, 0x0160, 0x0307], [0x1e67, 0x0161, 0x0307],
+    [0x1e68, 0x1e62, 0x0307], [0x1e69, 0x1e63, 0x0307], [0x1e6a, 0x0054, 0x0307], [0x1e6b, 0x0074, 0x0307],
+    [0x1e6c, 0x0054, 0x0323], [0x1e6d, 0x0074, 0x0323], [0x1e6e, 0x0054, 0x0331], [0x1e6f, 0x0074, 0x0331],
+    [0x1e70, 0x0054, 0x032d], [0x1e71, 0x0074, 0x032d], [0x1e72, 0x0055, 0x0324], [0x1e73, 0x0075, 0x0324],
+    [0x1e74, 0x0055, 0x0330], [0x1e75, 0x0075, 0x0330], [0x1e76, 0x0055, 0x032d], [0x1e77, 0x0075, 0x032d],
+    [0x1e78, 0x0168, 0x0301], [0x1e79, 0x0169, 0x0301], [0x1e7a, 0x016a, 0x0308], [0x1e7b, 0x016b, 0x0308],
+    [0x1e7c, 0x0056, 0x0303], [0x1e7d, 0x0076, 0x0303], [0x1e7e, 0x0056, 0x0323], [0x1e7f, 0x0076, 0x0323],
+    [0x1e80, 0x0057, 0x0300], [0x1e81, 0x0077, 0x0300], [0x1e82, 0x0057, 0x0301], [0x1e83, 0x0077, 0x0301],
+    [0x1e84, 0x0057, 0x0308], [0x1e85, 0x0077, 0x0308], [0x1e86, 0x0057, 0x0307], [0x1e87, 0x0077, 0x0307],
+    [0x1e88, 0x0057, 0x0323], [0x1e89, 0x0077, 0x0323], [0x1e8a, 0x0058, 0x0307], [0x1e8b, 0x0078, 0x0307],
+    [0x1e8c, 0x0058, 0x0308], [0x1e8d, 0x0078, 0x0308], [0x1e8e, 0x0059, 0x0307], [0x1e8f, 0x0079, 0x0307],
+    [0x1e90, 0x005a, 0x0302], [0x1e91, 0x007a, 0x0302], [0x1e92, 0x005a, 0x0323], [0x1e93, 0x007a, 0x0323],
+    [0x1e94, 0x005a, 0x0331], [0x1e95, 0x007a, 0x0331], [0x1e96, 0x0068, 0x0331], [0x1e97, 0x0074, 0x0308],
+    [0x1e98, 0x0077, 0x030a], [0x1e99, 0x0079, 0x030a], [0x1e9b, 0x017f, 0x0307], [0x1ea0, 0x0041, 0x0323],
+    [0x1ea1, 0x0061, 0x0323], [0x1ea2, 0x0041, 0x0309], [0x1ea3, 0x0061, 0x0309], [0x1ea4, 0x00c2, 0x0301],
+    [0x1ea5, 0x00e2, 0x0301], [0x1ea6, 0x00c2, 0x0300], [0x1ea7, 0x00e2, 0x0300], [0x1ea8, 0x00c2, 0x0309],
+    [0x1ea9, 0x00e2, 0x0309], [0x1eaa, 0x00c2, 0x0303], [0x1eab, 0x00e2, 0x0303], [0x1eac, 0x1ea0, 0x0302],
+    [0x1ead, 0x1ea1, 0x0302], [0x1eae, 0x0102, 0x0301], [0x1eaf, 0x0103, 0x0301], [0x1eb0, 0x0102, 0x0300],
+    [0x1eb1, 0x0103, 0x0300], [0x1eb2, 0x0102, 0x0309], [0x1eb3, 0x0103, 0x0309], [0x1eb4, 0x0102, 0x0303],
+    [0x1eb5, 0x0103, 0x0303], [0x1eb6, 0x1ea0, 0x0306], [0x1eb7, 0x1ea1, 0x0306], [0x1eb8, 0x0045, 0x0323],
+    [0x1eb9, 0x0065, 0x0323], [0x1eba, 0x0045, 0x0309], [0x1ebb, 0x0065, 0x0309], [0x1ebc, 0x0045, 0x0303],
+    [0x1ebd, 0x0065, 0x0303], [0x1ebe, 0x00ca, 0x0301], [0x1ebf, 0x00ea, 0x0301], [0x1ec0, 0x00ca, 0x0300],
+    [0x1ec1, 0x00ea, 0x0300], [0x1ec2, 0x00ca, 0x0309], [0x1ec3, 0x00ea, 0x0309], [0x1ec4, 0x00ca, 0x0303],
+    [0x1ec5, 0x00ea, 0x0303], [0x1ec6, 0x1eb8, 0x0302], [0x1ec7, 0x1eb9, 0x0302], [0x1ec8, 0x0049, 0x0309],
+    [0x1ec9, 0x0069, 0x0309], [0x1eca, 0x0049, 0x0323], [0x1ecb, 0x0069, 0x0323], [0x1ecc, 0x004f, 0x0323],
+    [0x1ecd, 0x006f, 0x0323], [0x1ece, 0x004f, 0x0309], [0x1ecf, 0x006f, 0x0309], [0x1ed0, 0x00d4, 0x0301],
+    [0x1ed1, 0x00f4, 0x0301], [0x1ed2, 0x00d4, 0x0300], [0x1ed3, 0x00f4, 0x0300], [0x1ed4, 0x00d4, 0x0309],
+    [0x1ed5, 0x00f4, 0x0309], [0x1ed6, 0x00d4, 0x0303], [0x1ed7, 0x00f4, 0x0303], [0x1ed8, 0x1ecc, 0x0302],
+    [0x1ed9, 0x1ecd, 0x0302], [0x1eda, 0x01a0, 0x0301], [0x1edb, 0x01a1, 0x0301], [0x1edc, 0x01a0, 0x0300],
+    [0x1edd, 0x01a1, 0x0300], [0x1ede, 0x01a0, 0x0309], [0x1edf, 0x01a1, 0x0309], [0x1ee0, 0x01a0, 0x0303],
+    [0x1ee1, 0x01a1, 0x0303], [0x1ee2, 0x01a0, 0x0323], [0x1ee3, 0x01a1, 0x0323], [0x1ee4, 0x0055, 0x0323],
+    [0x1ee5, 0x0075, 0x0323], [0x1ee6, 0x0055, 0x0309], [0x1ee7, 0x0075, 0x0309], [0x1ee8, 0x01af, 0x0301],
+    [0x1ee9, 0x01b0, 0x0301], [0x1eea, 0x01af, 0x0300], [0x1eeb, 0x01b0, 0x0300], [0x1eec, 0x01af, 0x0309],
+    [0x1eed, 0x01b0, 0x0309], [0x1eee, 0x01af, 0x0303], [0x1eef, 0x01b0, 0x0303], [0x1ef0, 0x01af, 0x0323],
+    [0x1ef1, 0x01b0, 0x0323], [0x1ef2, 0x0059, 0x0300], [0x1ef3, 0x0079, 0x0300], [0x1ef4, 0x0059, 0x0323],
+    [0x1ef5, 0x0079, 0x0323], [0x1ef6, 0x0059, 0x0309], [0x1ef7, 0x0079, 0x0309], [0x1ef8, 0x0059, 0x0303],
+    [0x1ef9, 0x0079, 0x0303], [0x1f00, 0x03b1, 0x0313], [0x1f01, 0x03b1, 0x0314], [0x1f02, 0x1f00, 0x0300],
+    [0x1f03, 0x1f01, 0x0300], [0x1f04, 0x1f00, 0x0301], [0x1f05, 0x1f01, 0x0301], [0x1f06, 0x1f00, 0x0342],
+    [0x1f07, 0x1f01, 0x0342], [0x1f08, 0x0391, 0x0313], [0x1f09, 0x0391, 0x0314], [0x1f0a, 0x1f08, 0x0300],
+    [0x1f0b, 0x1f09, 0x0300], [0x1f0c, 0x1f08, 0x0301], [0x1f0d, 0x1f09, 0x0301], [0x1f0e, 0x1f08, 0x0342],
+    [0x1f0f, 0x1f09, 0x0342], [0x1f10, 0x03b5, 0x0313], [0x1f11, 0x03b5, 0x0314], [0x1f12, 0x1f10, 0x0300],
+    [0x1f13, 0x1f11, 0x0300], [0x1f14, 0x1f10, 0x0301], [0x1f15, 0x1f11, 0x0301], [0x1f18, 0x0395, 0x0313],
+    [0x1f19, 0x0395, 0x0314], [0x1f1a, 0x1f18, 0x0300], [0x1f1b, 0x1f19, 0x0300], [0x1f1c, 0x1f18, 0x0301],
+    [0x1f1d, 0x1f19, 0x0301], [0x1f20, 0x03b7, 0x0313], [0x1f21, 0x03b7, 0x0314], [0x1f22, 0x1f20, 0x0300],
+    [0x1f23, 0x1f21, 0x0300], [0x1f24, 0x1f20, 0x0301], [0x1f25, 0x1f21, 0x0301], [0x1f26, 0x1f20, 0x0342],
+    [0x1f27, 0x1f21, 0x0342], [0x1f28, 0x0397, 0x0313], [0x1f29, 0x0397, 0x0314], [0x1f2a, 0x1f28, 0x0300],
+    [0x1f2b, 0x1f29, 0x0300], [0x1f2c, 0x1f28, 0x0301], [0x1f2d, 0x1f29, 0x0301], [0x1f2e, 0x1f28, 0x0342],
+    [0x1f2f, 0x1f29, 0x0342], [0x1f30, 0x03b9, 0x0313], [0x1f31, 0x03b9, 0x0314], [0x1f32, 0x1f30, 0x0300],
+    [0x1f33, 0x1f31, 0x0300], [0x1f34, 0x1f30, 0x0301], [0x1f35, 0x1f31, 0x0301], [0x1f36, 0x1f30, 0x0342],
+    [0x1f37, 0x1f31, 0x0342], [0x1f38, 0x0399, 0x0313], [0x1f39, 0x0399, 0x0314], [0x1f3a, 0x1f38, 0x0300],
+    [0x1f3b, 0x1f39, 0x0300], [0x1f3c, 0x1f38, 0x0301], [0x1f3d, 0x1f39, 0x0301], [0x1f3e, 0x1f38, 0x0342],
+    [0x1f3f, 0x1f39, 0x0342], [0x1f40, 0x03bf, 0x0313], [0x1f41, 0x03bf, 0x0314], [0x1f42, 0x1f40, 0x0300],
+    [0x1f43, 0x1f41, 0x0300], [0x1f44, 0x1f40, 0x0301], [0x1f45, 0x1f41, 0x0301], [0x1f48, 0x039f, 0x0313],
+    [0x1f49, 0x039f, 0x0314], [0x1f4a, 0x1f48, 0x0300], [0x1f4b, 0x1f49, 0x0300], [0x1f4c, 0x1f48, 0x0301],
+    [0x1f4d, 0x1f49, 0x0301], [0x1f50, 0x03c5, 0x0313], [0x1f51, 0x03c5, 0x0314], [0x1f52, 0x1f50, 0x0300],
+    [0x1f53, 0x1f51, 0x0300], [0x1f54, 0x1f50, 0x0301], [0x1f55, 0x1f51, 0x0301], [0x1f56, 0x1f50, 0x0342],
+    [0x1f57, 0x1f51, 0x0342], [0x1f59, 0x03a5, 0x0314], [0x1f5b, 0x1f59, 0x0300], [0x1f5d, 0x1f59, 0x0301],
+    [0x1f5f, 0x1f59, 0x0342], [0x1f60, 0x03c9, 0x0313], [0x1f61, 0x03c9, 0x0314], [0x1f62, 0x1f60, 0x0300],
+    [0x1f63, 0x1f61, 0x0300], [0x1f64, 0x1f60, 0x0301], [0x1f65, 0x1f61, 0x0301], [0x1f66, 0x1f60, 0x0342],
+    [0x1f67, 0x1f61, 0x0342], [0x1f68, 0x03a9, 0x0313], [0x1f69, 0x03a9, 0x0314], [0x1f6a, 0x1f68, 0x0300],
+    [0x1f6b, 0x1f69, 0x0300], [0x1f6c, 0x1f68, 0x0301], [0x1f6d, 0x1f69, 0x0301], [0x1f6e, 0x1f68, 0x0342],
+    [0x1f6f, 0x1f69, 0x0342], [0x1f70, 0x03b1, 0x0300], [0x1f71, 0x03ac, 0x0000], [0x1f72, 0x03b5, 0x0300],
+    [0x1f73, 0x03ad, 0x0000], [0x1f74, 0x03b7, 0x0300], [0x1f75, 0x03ae, 0x0000], [0x1f76, 0x03b9, 0x0300],
+    [0x1f77, 0x03af, 0x0000], [0x1f78, 0x03bf, 0x0300], [0x1f79, 0x03cc, 0x0000], [0x1f7a, 0x03c5, 0x0300],
+    [0x1f7b, 0x03cd, 0x0000], [0x1f7c, 0x03c9, 0x0300], [0x1f7d, 0x03ce, 0x0000], [0x1f80, 0x1f00, 0x0345],
+    [0x1f81, 0x1f01, 0x0345], [0x1f82, 0x1f02, 0x0345], [0x1f83, 0x1f03, 0x0345], [0x1f84, 0x1f04, 0x0345],
+    [0x1f85, 0x1f05, 0x0345], [0x1f86, 0x1f06, 0x0345], [0x1f87, 0x1f07, 0x0345], [0x1f88, 0x1f08, 0x0345],
+    [0x1f89, 0x1f09, 0x0345], [0x1f8a, 0x1f0a, 0x0345], [0x1f8b, 0x1f0b, 0x0345], [0x1f8c, 0x1f0c, 0x0345],
+    [0x1f8d, 0x1f0d, 0x0345], [0x1f8e, 0x1f0e, 0x0345], [0x1f8f, 0x1f0f, 0x0345], [0x1f90, 0x1f20, 0x0345],
+    [0x1f91, 0x1f21, 0x0345], [0x1f92, 0x1f22, 0x0345], [0x1f93, 0x1f23, 0x0345], [0x1f94, 0x1f24, 0x0345],
+    [0x1f95, 0x1f25, 0x0345], [0x1f96, 0x1f26, 0x0345], [0x1f97, 0x1f27, 0x0345], [0x1f98, 0x1f28, 0x0345],
+    [0x1f99, 0x1f29, 0x0345], [0x1f9a, 0x1f2a, 0x0345], [0x1f9b, 0x1f2b, 0x0345], [0x1f9c, 0x1f2c, 0x0345],
+    [0x1f9d, 0x1f2d, 0x0345], [0x1f9e, 0x1f2e, 0x0345], [0x1f9f, 0x1f2f, 0x0345], [0x1fa0, 0x1f60, 0x0345],
+    [0x1fa1, 0x1f61, 0x0345], [0x1fa2, 0x1f62, 0x0345], [0x1fa3, 0x1f63, 0x0345], [0x1fa4, 0x1f64, 0x0345],
+    [0x1fa5, 0x1f65, 0x0345], [0x1fa6, 0x1f66, 0x0345], [0x1fa7, 0x1f67, 0x0345], [0x1fa8, 0x1f68, 0x0345],
+    [0x1fa9, 0x1f69, 0x0345], [0x1faa, 0x1f6a, 0x0345], [0x1fab, 0x1f6b, 0x0345], [0x1fac, 0x1f6c, 0x0345],
+    [0x1fad, 0x1f6d, 0x0345], [0x1fae, 0x1f6e, 0x0345], [0x1faf, 0x1f6f, 0x0345], [0x1fb0, 0x03b1, 0x0306],
+    [0x1fb1, 0x03b1, 0x0304], [0x1fb2, 0x1f70, 0x0345], [0x1fb3, 0x03b1, 0x0345], [0x1fb4, 0x03ac, 0x0345],
+    [0x1fb6, 0x03b1, 0x0342], [0x1fb7, 0x1fb6, 0x0345], [0x1fb8, 0x0391, 0x0306], [0x1fb9, 0x0391, 0x0304],
+    [0x1fba, 0x0391, 0x0300], [0x1fbb, 0x0386, 0x0000], [0x1fbc, 0x0391, 0x0345], [0x1fbe, 0x03b9, 0x0000],
+    [0x1fc1, 0x00a8, 0x0342], [0x1fc2, 0x1f74, 0x0345], [0x1fc3, 0x03b7, 0x0345], [0x1fc4, 0x03ae, 0x0345],
+    [0x1fc6, 0x03b7, 0x0342], [0x1fc7, 0x1fc6, 0x0345], [0x1fc8, 0x0395, 0x0300], [0x1fc9, 0x0388, 0x0000],
+    [0x1fca, 0x0397, 0x0300], [0x1fcb, 0x0389, 0x0000], [0x1fcc, 0x0397, 0x0345], [0x1fcd, 0x1fbf, 0x0300],
+    [0x1fce, 0x1fbf, 0x0301], [0x1fcf, 0x1fbf, 0x0342], [0x1fd0, 0x03b9, 0x0306], [0x1fd1, 0x03b9, 0x0304],
+    [0x1fd2, 0x03ca, 0x0300], [0x1fd3, 0x0390, 0x0000], [0x1fd6, 0x03b9, 0x0342], [0x1fd7, 0x03ca, 0x0342],
+    [0x1fd8, 0x0399, 0x0306], [0x1fd9, 0x0399, 0x0304], [0x1fda, 0x0399, 0x0300], [0x1fdb, 0x038a, 0x0000],
+    [0x1fdd, 0x1ffe, 0x0300], [0x1fde, 0x1ffe, 0x0301], [0x1fdf, 0x1ffe, 0x0342], [0x1fe0, 0x03c5, 0x0306],
+    [0x1fe1, 0x03c5, 0x0304], [0x1fe2, 0x03cb, 0x0300], [0x1fe3, 0x03b0, 0x0000], [0x1fe4, 0x03c1, 0x0313],
+    [0x1fe5, 0x03c1, 0x0314], [0x1fe6, 0x03c5, 0x0342], [0x1fe7, 0x03cb, 0x0342], [0x1fe8, 0x03a5, 0x0306],
+    [0x1fe9, 0x03a5, 0x0304], [0x1fea, 0x03a5, 0x0300], [0x1feb, 0x038e, 0x0000], [0x1fec, 0x03a1, 0x0314],
+    [0x1fed, 0x00a8, 0x0300], [0x1fee, 0x0385, 0x0000], [0x1fef, 0x0060, 0x0000], [0x1ff2, 0x1f7c, 0x0345],
+    [0x1ff3, 0x03c9, 0x0345], [0x1ff4, 0x03ce, 0x0345], [0x1ff6, 0x03c9, 0x0342], [0x1ff7, 0x1ff6, 0x0345],
+    [0x1ff8, 0x039f, 0x0300], [0x1ff9, 0x038c, 0x0000], [0x1ffa, 0x03a9, 0x0300], [0x1ffb, 0x038f, 0x0000],
+    [0x1ffc, 0x03a9, 0x0345], [0x1ffd, 0x00b4, 0x0000], [0x304c, 0x304b, 0x3099], [0x304e, 0x304d, 0x3099],
+    [0x3050, 0x304f, 0x3099], [0x3052, 0x3051, 0x3099], [0x3054, 0x3053, 0x3099], [0x3056, 0x3055, 0x3099],
+    [0x3058, 0x3057, 0x3099], [0x305a, 0x3059, 0x3099], [0x305c, 0x305b, 0x3099], [0x305e, 0x305d, 0x3099],
+    [0x3060, 0x305f, 0x3099], [0x3062, 0x3061, 0x3099], [0x3065, 0x3064, 0x3099], [0x3067, 0x3066, 0x3099],
+    [0x3069, 0x3068, 0x3099], [0x3070, 0x306f, 0x3099], [0x3071, 0x306f, 0x309a], [0x3073, 0x3072, 0x3099],
+    [0x3074, 0x3072, 0x309a], [0x3076, 0x3075, 0x3099], [0x3077, 0x3075, 0x309a], [0x3079, 0x3078, 0x3099],
+    [0x307a, 0x3078, 0x309a], [0x307c, 0x307b, 0x3099], [0x307d, 0x307b, 0x309a], [0x3094, 0x3046, 0x3099],
+    [0x309e, 0x309d, 0x3099], [0x30ac, 0x30ab, 0x3099], [0x30ae, 0x30ad, 0x3099], [0x30b0, 0x30af, 0x3099],
+    [0x30b2, 0x30b1, 0x3099], [0x30b4, 0x30b3, 0x3099], [0x30b6, 0x30b5, 0x3099], [0x30b8, 0x30b7, 0x3099],
+    [0x30ba, 0x30b9, 0x3099], [0x30bc, 0x30bb, 0x3099], [0x30be, 0x30bd, 0x3099], [0x30c0, 0x30bf, 0x3099],
+    [0x30c2, 0x30c1, 0x3099], [0x30c5, 0x30c4, 0x3099], [0x30c7, 0x30c6, 0x3099], [0x30c9, 0x30c8, 0x3099],
+    [0x30d0, 0x30cf, 0x3099], [0x30d1, 0x30cf, 0x309a], [0x30d3, 0x30d2, 0x3099], [0x30d4, 0x30d2, 0x309a],
+    [0x30d6, 0x30d5, 0x3099], [0x30d7, 0x30d5, 0x309a], [0x30d9, 0x30d8, 0x3099], [0x30da, 0x30d8, 0x309a],
+    [0x30dc, 0x30db, 0x3099], [0x30dd, 0x30db, 0x309a], [0x30f4, 0x30a6, 0x3099], [0x30f7, 0x30ef, 0x3099],
+    [0x30f8, 0x30f0, 0x3099], [0x30f9, 0x30f1, 0x3099], [0x30fa, 0x30f2, 0x3099], [0x30fe, 0x30fd, 0x3099],
+    [0xfb1d, 0x05d9, 0x05b4], [0xfb1f, 0x05f2, 0x05b7], [0xfb2a, 0x05e9, 0x05c1], [0xfb2b, 0x05e9, 0x05c2],
+    [0xfb2c, 0xfb49, 0x05c1], [0xfb2d, 0xfb49, 0x05c2], [0xfb2e, 0x05d0, 0x05b7], [0xfb2f, 0x05d0, 0x05b8],
+    [0xfb30, 0x05d0, 0x05bc], [0xfb31, 0x05d1, 0x05bc], [0xfb32, 0x05d2, 0x05bc], [0xfb33, 0x05d3, 0x05bc],
+    [0xfb34, 0x05d4, 0x05bc], [0xfb35, 0x05d5, 0x05bc], [0xfb36, 0x05d6, 0x05bc], [0xfb38, 0x05d8, 0x05bc],
+    [0xfb39, 0x05d9, 0x05bc], [0xfb3a, 0x05da, 0x05bc], [0xfb3b, 0x05db, 0x05bc], [0xfb3c, 0x05dc, 0x05bc],
+    [0xfb3e, 0x05de, 0x05bc], [0xfb40, 0x05e0, 0x05bc], [0xfb41, 0x05e1, 0x05bc], [0xfb43, 0x05e3, 0x05bc],
+    [0xfb44, 0x05e4, 0x05bc], [0xfb46, 0x05e6, 0x05bc], [0xfb47, 0x05e7, 0x05bc], [0xfb48, 0x05e8, 0x05bc],
+    [0xfb49, 0x05e9, 0x05bc], [0xfb4a, 0x05ea, 0x05bc], [0xfb4b, 0x05d5, 0x05b9], [0xfb4c, 0x05d1, 0x05bf],
+    [0xfb4d, 0x05db, 0x05bf], [0xfb4e, 0x05e4, 0x05bf],
+];
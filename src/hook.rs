@@ -0,0 +1,35 @@
+//! User commands run before and after the image is built.
+
+use crate::json;
+use log::*;
+use std::path::Path;
+use std::process::Command;
+
+/// Run a hook command through the shell.
+///
+/// The command receives the path of the image being built in `MKIMG_IMAGE`, the final output
+/// path in `MKIMG_OUTPUT` and a JSON description of the build in `MKIMG_SUMMARY`.
+pub fn run(
+    kind: &str,
+    cmd: &str,
+    image: &Path,
+    output: &Path,
+    summary: &json::Value,
+) -> anyhow::Result<()> {
+    info!("Running {kind} hook: {cmd}");
+
+    let status = Command::new("sh")
+        .arg("-c")
+        .arg(cmd)
+        .env("MKIMG_HOOK", kind)
+        .env("MKIMG_IMAGE", image)
+        .env("MKIMG_OUTPUT", output)
+        .env("MKIMG_SUMMARY", summary.to_string())
+        .status()?;
+
+    if !status.success() {
+        anyhow::bail!("{kind} hook `{cmd}` failed ({status})");
+    }
+
+    Ok(())
+}
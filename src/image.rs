@@ -0,0 +1,219 @@
+//! Access to partitions of images that already exist.
+
+use crate::PartitionTable;
+use anyhow::{anyhow, bail};
+use fscommon::StreamSlice;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+/// Partition found in an existing image.
+#[derive(Clone, Copy, Debug)]
+pub struct Partition {
+    /// 1-based partition number
+    pub number: usize,
+    pub start: u64,
+    pub len: u64,
+}
+
+/// Whether the sector looks like the boot sector of a FAT filesystem rather than an MBR.
+fn is_fat_boot_sector(sector: &[u8]) -> bool {
+    let bytes_per_sector = u16::from_le_bytes([sector[11], sector[12]]);
+    matches!(sector[0], 0xeb | 0xe9)
+        && matches!(bytes_per_sector, 512 | 1024 | 2048 | 4096)
+        && (&sector[0x36..0x39] == b"FAT" || &sector[0x52..0x55] == b"FAT")
+}
+
+/// Name of the filesystem at the start of `part`, from its magic numbers.
+pub fn detect_filesystem(part: &mut (impl Read + Seek)) -> std::io::Result<Option<&'static str>> {
+    let mut head = vec![0u8; 0x8800];
+    part.seek(SeekFrom::Start(0))?;
+    let n = part.read(&mut head)?;
+    head.truncate(n);
+
+    let at = |offset: usize, magic: &[u8]| head.get(offset..offset + magic.len()) == Some(magic);
+
+    Ok(Some(match () {
+        _ if head.len() >= 512 && is_fat_boot_sector(&head) => "FAT",
+        _ if at(3, b"EXFAT   ") => "exFAT",
+        _ if at(0x438, &[0x53, 0xef]) => "ext2",
+        _ if at(0, b"XFSB") => "XFS",
+        _ if at(0, b"hsqs") => "squashfs",
+        _ if at(0x400, &[0xe2, 0xe1, 0xf5, 0xe0]) => "EROFS",
+        _ if at(0, b"-rom1fs-") => "romfs",
+        _ if at(0, &[0x45, 0x3d, 0xcd, 0x28]) => "cramfs",
+        _ if at(0, &[0x85, 0x19]) => "JFFS2",
+        _ if at(0, b"UBI#") => "UBI",
+        _ if at(0x400, b"H+\0\x04") => "HFS+",
+        _ if at(0x400, &[0x10, 0x20, 0xf5, 0xf2]) => "F2FS",
+        _ if at(8, b"littlefs") => "littlefs",
+        _ if at(0x8001, b"CD001") => "ISO9660",
+        _ if at(0x8001, b"BEA01") => "UDF",
+        _ => return Ok(None),
+    }))
+}
+
+/// Open the FAT filesystem of a partition, naming the filesystem found instead if it is not one.
+pub fn open_fat<T: Read + Write + Seek>(
+    mut part: T,
+) -> anyhow::Result<fatfs::FileSystem<fscommon::BufStream<T>>> {
+    match detect_filesystem(&mut part)? {
+        Some("FAT") => {}
+        Some(name) => bail!("the partition holds {name}, only FAT filesystems can be read"),
+        None => bail!("the partition holds no known filesystem"),
+    }
+    part.seek(SeekFrom::Start(0))?;
+
+    Ok(fatfs::FileSystem::new(
+        fscommon::BufStream::new(part),
+        fatfs::FsOptions::new(),
+    )?)
+}
+
+/// Size of the logical sectors of an image, 512 or 4096 bytes.
+///
+/// GPT images are recognized by their header at LBA 1. For MBRs, which do not record it, 4096 is
+/// only chosen when the first partition holds a known filesystem at 4096 byte sectors and not at
+/// 512 byte ones.
+pub fn sector_size(file: &mut File) -> anyhow::Result<u64> {
+    let mut head = vec![0u8; 4096 + 512];
+    file.seek(SeekFrom::Start(0))?;
+    let n = file.read(&mut head)?;
+    head.truncate(n);
+    if head.len() < 512 {
+        return Ok(512);
+    }
+
+    let at = |offset: usize| head.get(offset..offset + 8) == Some(b"EFI PART");
+    if at(512) {
+        return Ok(512);
+    } else if at(4096) {
+        return Ok(4096);
+    }
+
+    let first = (0..4)
+        .map(|i| &head[446 + i * 16..462 + i * 16])
+        .find(|entry| !matches!(entry[4], 0 | 0x05 | 0x0f | 0x85))
+        .map(|entry| u32::from_le_bytes(entry[8..12].try_into().unwrap()) as u64);
+    let Some(first) = first else {
+        return Ok(512);
+    };
+
+    let len = file.metadata()?.len();
+    let mut holds_filesystem = |sector: u64| -> anyhow::Result<bool> {
+        let start = first * sector;
+        if start >= len {
+            return Ok(false);
+        }
+        let mut part = StreamSlice::new(&mut *file, start, len)?;
+        Ok(detect_filesystem(&mut part)?.is_some())
+    };
+
+    Ok(if !holds_filesystem(512)? && holds_filesystem(4096)? {
+        4096
+    } else {
+        512
+    })
+}
+
+/// Detect the partition table of an image and list its partitions.
+///
+/// Images without a partition table are reported as a single partition spanning the whole file.
+pub fn read_partitions(file: &mut File) -> anyhow::Result<(PartitionTable, Vec<Partition>)> {
+    let len = file.metadata()?.len();
+    let mut sector = [0u8; 512];
+
+    file.seek(SeekFrom::Start(0))?;
+    file.read_exact(&mut sector)?;
+
+    let whole = vec![Partition {
+        number: 1,
+        start: 0,
+        len,
+    }];
+
+    if sector[510..] != [0x55, 0xaa] || is_fat_boot_sector(&sector) {
+        return Ok((PartitionTable::None, whole));
+    }
+
+    let sector_size = self::sector_size(file)?;
+
+    // Protective MBR entry covering a GPT disk, which hybrid MBRs keep after mirrored entries
+    if (0..4).any(|i| sector[446 + i * 16 + 4] == 0xee) {
+        let lb_size = crate::table::lb_size(sector_size);
+        let header = gpt::header::read_header_from_arbitrary_device(file, lb_size)?;
+        let parts = gpt::partition::file_read_partitions(file, &header, lb_size)?;
+
+        let parts = parts
+            .iter()
+            .filter(|(_, p)| p.is_used())
+            .map(|(&n, p)| {
+                Ok(Partition {
+                    number: n as usize,
+                    start: p.bytes_start(lb_size)?,
+                    len: p.bytes_len(lb_size)?,
+                })
+            })
+            .collect::<std::io::Result<Vec<_>>>()?;
+
+        // Hybrid MBRs mirror partitions in entries besides the protective one
+        let mirrored = (0..4).any(|i| !matches!(sector[446 + i * 16 + 4], 0 | 0xee));
+        let kind = if mirrored {
+            PartitionTable::Hybrid
+        } else {
+            PartitionTable::Gpt
+        };
+
+        return Ok((kind, parts));
+    }
+
+    let mbr = mbrman::MBR::read_from(file, sector_size as u32)?;
+
+    let parts = mbr_partitions(&mbr)
+        .map(|(n, p)| Partition {
+            number: n,
+            start: p.starting_lba as u64 * sector_size,
+            len: p.sectors as u64 * sector_size,
+        })
+        .collect();
+
+    Ok((PartitionTable::Mbr, parts))
+}
+
+/// Partitions of an MBR and their numbers, the primary ones and then the logical ones from 5,
+/// without the extended partition holding those.
+pub fn mbr_partitions(
+    mbr: &mbrman::MBR,
+) -> impl Iterator<Item = (usize, &mbrman::MBRPartitionEntry)> {
+    // The iterator of mbrman yields the logical partitions after the primary entries
+    mbr.iter().filter(|(_, p)| p.is_used() && !p.is_extended())
+}
+
+/// Open a partition of an image, the first one if `number` is not given.
+pub fn open_partition(
+    path: &Path,
+    writable: bool,
+    number: Option<usize>,
+) -> anyhow::Result<StreamSlice<File>> {
+    let mut file = OpenOptions::new().read(true).write(writable).open(path)?;
+    let (_, parts) = read_partitions(&mut file)?;
+
+    let part = match number {
+        Some(n) => parts
+            .iter()
+            .find(|p| p.number == n)
+            .ok_or_else(|| anyhow!("no partition {n} in {}", path.display()))?,
+        None => parts
+            .first()
+            .ok_or_else(|| anyhow!("no partitions in {}", path.display()))?,
+    };
+
+    if part.start + part.len > file.metadata()?.len() {
+        bail!(
+            "partition {} extends past the end of the image",
+            part.number
+        );
+    }
+
+    Ok(StreamSlice::new(file, part.start, part.start + part.len)?)
+}
@@ -0,0 +1,761 @@
+//! ISO9660 images, with Joliet extensions for long and Unicode file names.
+//!
+//! The primary hierarchy uses level 1 (8.3) names for compatibility, while the Joliet
+//! supplementary hierarchy carries the original names. Both point at the same file data.
+//!
+//! El Torito boot images are files of the tree, referenced from a boot catalog. BIOS images are
+//! loaded without emulation and get a boot info table, as isolinux and GRUB expect. EFI images
+//! are FAT filesystem images.
+
+use crate::tree::{Kind, Tree};
+use chrono::{DateTime, Datelike, TimeZone, Timelike, Utc};
+use log::*;
+use std::collections::{HashSet, VecDeque};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+const SECTOR_SIZE: u64 = 2048;
+/// System area, followed by the volume descriptors
+const FIRST_DESCRIPTOR: u64 = 16;
+/// Primary, Joliet and terminator descriptors, and the boot record if there are boot images
+const DESCRIPTORS: u64 = 3;
+
+const FLAG_DIRECTORY: u8 = 0x2;
+
+/// Longest Joliet name, in UCS-2 characters
+const JOLIET_NAME_MAX: usize = 64;
+
+/// Sectors of 512 bytes the BIOS loads of a boot image, the rest is loaded by the image itself
+const BIOS_LOAD_SECTORS: u16 = 4;
+
+const PLATFORM_X86: u8 = 0;
+const PLATFORM_EFI: u8 = 0xef;
+
+#[derive(Clone, Debug, Default)]
+pub struct Options {
+    pub label: Option<String>,
+    /// Identifiers of the volume descriptors, besides the label
+    pub volume_set: Option<String>,
+    pub publisher: Option<String>,
+    pub preparer: Option<String>,
+    /// `MKIMG` if not set
+    pub application: Option<String>,
+    /// Path of the El Torito BIOS boot image inside the image
+    pub eltorito_bios: Option<PathBuf>,
+    /// Paths of the El Torito EFI boot images inside the image, such as the ESPs of several
+    /// architectures
+    pub eltorito_efi: Vec<PathBuf>,
+}
+
+impl Options {
+    fn is_bootable(&self) -> bool {
+        self.eltorito_bios.is_some() || !self.eltorito_efi.is_empty()
+    }
+}
+
+/// Identifiers of one directory hierarchy.
+struct Hierarchy {
+    /// Identifier of every node, `[0]` for the root
+    ids: Vec<Vec<u8>>,
+    /// Children of every directory, in identifier order
+    children: Vec<Vec<usize>>,
+    /// Directories in path table order
+    dirs: Vec<usize>,
+}
+
+impl Hierarchy {
+    fn new(tree: &Tree, ids: Vec<Vec<u8>>, key: impl Fn(&[u8]) -> Vec<u8>) -> Self {
+        let children = tree
+            .nodes
+            .iter()
+            .map(|node| match &node.kind {
+                Kind::Dir(children) => {
+                    let mut children = children.clone();
+                    children.sort_by_cached_key(|&c| key(&ids[c]));
+                    children
+                }
+                Kind::File { .. } | Kind::Symlink(_) => vec![],
+            })
+            .collect::<Vec<_>>();
+
+        // Path table order is by level, then parent, then identifier, which is what a breadth
+        // first walk over sorted children yields
+        let mut dirs = vec![];
+        let mut queue = VecDeque::from([0]);
+        while let Some(dir) = queue.pop_front() {
+            dirs.push(dir);
+            queue.extend(children[dir].iter().filter(|&&c| tree.nodes[c].is_dir()));
+        }
+
+        Self {
+            ids,
+            children,
+            dirs,
+        }
+    }
+
+    fn path_table_size(&self) -> u64 {
+        self.dirs
+            .iter()
+            .map(|&d| (8 + self.ids[d].len()).next_multiple_of(2) as u64)
+            .sum()
+    }
+
+    /// Size of the extent of a directory, with records not crossing sector boundaries.
+    fn dir_size(&self, dir: usize) -> u64 {
+        let mut size = 0;
+
+        for len in [34, 34]
+            .into_iter()
+            .chain(self.children[dir].iter().map(|&c| record_len(&self.ids[c])))
+        {
+            if size % SECTOR_SIZE + len > SECTOR_SIZE {
+                size = size.next_multiple_of(SECTOR_SIZE);
+            }
+            size += len;
+        }
+
+        size.next_multiple_of(SECTOR_SIZE)
+    }
+
+    fn path_table(&self, tree: &Tree, extents: &[u64], big_endian: bool) -> Vec<u8> {
+        let mut number = vec![0u16; extents.len()];
+        for (i, &d) in self.dirs.iter().enumerate() {
+            number[d] = i as u16 + 1;
+        }
+
+        let mut out = vec![];
+        for &d in &self.dirs {
+            let id = &self.ids[d];
+            let parent = number[tree.nodes[d].parent];
+            out.push(id.len() as u8);
+            out.push(0);
+            if big_endian {
+                out.extend((extents[d] as u32).to_be_bytes());
+                out.extend(parent.to_be_bytes());
+            } else {
+                out.extend((extents[d] as u32).to_le_bytes());
+                out.extend(parent.to_le_bytes());
+            }
+            out.extend(id);
+            if id.len() % 2 == 1 {
+                out.push(0);
+            }
+        }
+        out
+    }
+}
+
+fn record_len(id: &[u8]) -> u64 {
+    (33 + id.len()).next_multiple_of(2) as u64
+}
+
+fn both_u16(v: u16) -> [u8; 4] {
+    let [a, b] = v.to_le_bytes();
+    [a, b, b, a]
+}
+
+fn both_u32(v: u32) -> [u8; 8] {
+    let le = v.to_le_bytes();
+    let be = v.to_be_bytes();
+    [le[0], le[1], le[2], le[3], be[0], be[1], be[2], be[3]]
+}
+
+/// Seven byte date and time of directory records, in UTC.
+fn record_time(secs: i64) -> [u8; 7] {
+    let t = Utc
+        .timestamp_opt(secs, 0)
+        .single()
+        .unwrap_or_default()
+        .max(Utc.with_ymd_and_hms(1900, 1, 1, 0, 0, 0).unwrap());
+    [
+        (t.year() - 1900).min(255) as u8,
+        t.month() as u8,
+        t.day() as u8,
+        t.hour() as u8,
+        t.minute() as u8,
+        t.second() as u8,
+        0,
+    ]
+}
+
+/// Seventeen byte date and time of volume descriptors, in UTC.
+fn volume_time(t: &DateTime<Utc>) -> [u8; 17] {
+    let mut out = [0u8; 17];
+    out[..16].copy_from_slice(t.format("%Y%m%d%H%M%S00").to_string().as_bytes());
+    out
+}
+
+fn record(id: &[u8], extent: u64, len: u64, mtime: i64, dir: bool) -> Vec<u8> {
+    let mut r = vec![0u8; record_len(id) as usize];
+    r[0] = r.len() as u8;
+    r[2..10].copy_from_slice(&both_u32(extent as u32));
+    r[10..18].copy_from_slice(&both_u32(len as u32));
+    r[18..25].copy_from_slice(&record_time(mtime));
+    r[25] = if dir { FLAG_DIRECTORY } else { 0 };
+    r[28..32].copy_from_slice(&both_u16(1));
+    r[32] = id.len() as u8;
+    r[33..33 + id.len()].copy_from_slice(id);
+    r
+}
+
+fn d_chars(s: &str) -> String {
+    s.chars()
+        .map(|c| match c.to_ascii_uppercase() {
+            c @ ('A'..='Z' | '0'..='9' | '_') => c,
+            _ => '_',
+        })
+        .collect()
+}
+
+/// Characters allowed in the text identifiers of the primary volume descriptor.
+fn a_chars(s: &str) -> String {
+    s.chars()
+        .map(|c| match c.to_ascii_uppercase() {
+            c @ ('A'..='Z' | '0'..='9' | '_' | ' ' | '!' | '"' | '%'..='?') => c,
+            _ => '_',
+        })
+        .collect()
+}
+
+/// Level 1 identifiers, made unique within every directory.
+fn primary_ids(tree: &Tree) -> Vec<Vec<u8>> {
+    let mut ids = vec![vec![]; tree.nodes.len()];
+    ids[0] = vec![0];
+
+    for node in &tree.nodes {
+        let Kind::Dir(children) = &node.kind else {
+            continue;
+        };
+
+        let mut taken = HashSet::new();
+
+        for &c in children {
+            let child = &tree.nodes[c];
+            let (base, ext) = match child.name.rsplit_once('.') {
+                Some((base, ext)) if !child.is_dir() && !base.is_empty() => {
+                    (d_chars(base), d_chars(ext))
+                }
+                _ => (d_chars(&child.name), String::new()),
+            };
+            let ext = &ext[..ext.len().min(3)];
+
+            let id = |base: &str| match child.is_dir() {
+                true => base.to_owned(),
+                false => format!("{base}.{ext};1"),
+            };
+
+            let mut name = id(&base[..base.len().min(8)]);
+            let mut n = 1;
+            while !taken.insert(name.clone()) {
+                // Level 1 has no `~`, so uniqueness comes from a trailing number alone
+                let suffix = n.to_string();
+                name = id(&format!(
+                    "{}{suffix}",
+                    &base[..base.len().min(8 - suffix.len())]
+                ));
+                n += 1;
+            }
+
+            ids[c] = name.into_bytes();
+        }
+    }
+
+    ids
+}
+
+/// Sort key of level 1 identifiers: name, then extension.
+fn primary_key(id: &[u8]) -> Vec<u8> {
+    let s = String::from_utf8_lossy(id);
+    let s = s.split(';').next().unwrap_or_default();
+    let (base, ext) = s.split_once('.').unwrap_or((s, ""));
+    format!("{base:<8}{ext:<3}").into_bytes()
+}
+
+fn joliet_ids(tree: &Tree) -> anyhow::Result<Vec<Vec<u8>>> {
+    let mut ids = vec![vec![0]];
+
+    for node in &tree.nodes[1..] {
+        let mut units = vec![];
+        for c in node.name.chars() {
+            let c = match c {
+                '*' | '/' | ':' | ';' | '?' | '\\' => '_',
+                c => c,
+            };
+            let mut buf = [0u16; 2];
+            match c.encode_utf16(&mut buf) {
+                [u] => units.push(*u),
+                _ => anyhow::bail!(
+                    "{} has a character outside the Basic Multilingual Plane, which Joliet can not store",
+                    node.path.display()
+                ),
+            }
+        }
+
+        if units.len() > JOLIET_NAME_MAX {
+            anyhow::bail!(
+                "Joliet names are limited to {JOLIET_NAME_MAX} characters: {}",
+                node.path.display()
+            );
+        }
+
+        if !node.is_dir() {
+            units.extend(";1".encode_utf16());
+        }
+
+        ids.push(units.iter().flat_map(|u| u.to_be_bytes()).collect());
+    }
+
+    Ok(ids)
+}
+
+/// Placement of everything in the image, in sectors.
+struct Layout {
+    primary: Hierarchy,
+    joliet: Hierarchy,
+    path_tables: [u64; 4],
+    primary_extents: Vec<u64>,
+    joliet_extents: Vec<u64>,
+    /// Extent of every file, shared by both hierarchies
+    files: Vec<u64>,
+    /// Sector of the El Torito boot catalog
+    catalog: Option<u64>,
+    sectors: u64,
+}
+
+impl Layout {
+    fn new(tree: &Tree, opts: &Options) -> anyhow::Result<Self> {
+        for node in &tree.nodes {
+            if let Kind::File { len, .. } = &node.kind {
+                if *len > u32::MAX as u64 {
+                    anyhow::bail!(
+                        "{} is larger than the 4 GiB ISO9660 supports",
+                        node.path.display()
+                    );
+                }
+            }
+        }
+
+        let primary = Hierarchy::new(tree, primary_ids(tree), primary_key);
+        let joliet = Hierarchy::new(tree, joliet_ids(tree)?, <[u8]>::to_vec);
+
+        let mut next = FIRST_DESCRIPTOR + DESCRIPTORS + opts.is_bootable() as u64;
+        let mut alloc = |bytes: u64| {
+            let start = next;
+            next += bytes.div_ceil(SECTOR_SIZE);
+            start
+        };
+
+        let catalog = opts.is_bootable().then(|| alloc(SECTOR_SIZE));
+
+        let primary_table = primary.path_table_size();
+        let joliet_table = joliet.path_table_size();
+        let path_tables = [
+            alloc(primary_table),
+            alloc(primary_table),
+            alloc(joliet_table),
+            alloc(joliet_table),
+        ];
+
+        let mut extents = |h: &Hierarchy| {
+            let mut extents = vec![0; tree.nodes.len()];
+            for &d in &h.dirs {
+                extents[d] = alloc(h.dir_size(d));
+            }
+            extents
+        };
+        let primary_extents = extents(&primary);
+        let joliet_extents = extents(&joliet);
+
+        let files = tree
+            .nodes
+            .iter()
+            .map(|node| match &node.kind {
+                Kind::File { len, .. } => alloc(*len),
+                Kind::Dir(_) => 0,
+                Kind::Symlink(_) => unreachable!("ISO9660 has no symbolic links"),
+            })
+            .collect();
+
+        Ok(Self {
+            primary,
+            joliet,
+            path_tables,
+            primary_extents,
+            joliet_extents,
+            files,
+            catalog,
+            sectors: next,
+        })
+    }
+
+    fn dir_extent(&self, tree: &Tree, h: &Hierarchy, extents: &[u64], dir: usize) -> Vec<u8> {
+        let mut out = vec![];
+        let mut push = |r: Vec<u8>| {
+            if out.len() as u64 % SECTOR_SIZE + r.len() as u64 > SECTOR_SIZE {
+                out.resize((out.len() as u64).next_multiple_of(SECTOR_SIZE) as usize, 0);
+            }
+            out.extend(r);
+        };
+
+        let node = &tree.nodes[dir];
+        let parent = node.parent;
+        push(record(
+            &[0],
+            extents[dir],
+            h.dir_size(dir),
+            node.mtime,
+            true,
+        ));
+        push(record(
+            &[1],
+            extents[parent],
+            h.dir_size(parent),
+            tree.nodes[parent].mtime,
+            true,
+        ));
+
+        for &c in &h.children[dir] {
+            let child = &tree.nodes[c];
+            push(match &child.kind {
+                Kind::Dir(_) => record(&h.ids[c], extents[c], h.dir_size(c), child.mtime, true),
+                Kind::File { len, .. } => {
+                    record(&h.ids[c], self.files[c], *len, child.mtime, false)
+                }
+                Kind::Symlink(_) => unreachable!("ISO9660 has no symbolic links"),
+            });
+        }
+
+        out.resize(h.dir_size(dir) as usize, 0);
+        out
+    }
+}
+
+/// Size of the image holding the tree.
+pub fn estimate_size(tree: &Tree, opts: &Options) -> anyhow::Result<u64> {
+    Ok(Layout::new(tree, opts)?.sectors * SECTOR_SIZE)
+}
+
+/// Node of a boot image, given by its path inside the image.
+fn boot_image(tree: &Tree, path: &Path) -> anyhow::Result<usize> {
+    let path = path.strip_prefix("/").unwrap_or(path);
+
+    match tree.nodes.iter().position(|n| n.path == path) {
+        Some(idx) if !tree.nodes[idx].is_dir() => Ok(idx),
+        Some(_) => anyhow::bail!("boot image {} is a directory", path.display()),
+        None => anyhow::bail!("boot image {} is not in the image", path.display()),
+    }
+}
+
+/// Boot catalog entry for the image at `extent`, loading `sectors` virtual 512 byte sectors.
+fn boot_entry(extent: u64, sectors: u16) -> [u8; 32] {
+    let mut e = [0u8; 32];
+    // Bootable, without emulation, at the default segment
+    e[0] = 0x88;
+    e[6..8].copy_from_slice(&sectors.to_le_bytes());
+    e[8..12].copy_from_slice(&(extent as u32).to_le_bytes());
+    e
+}
+
+/// Boot catalog, with the BIOS image as the default entry and the EFI image in a section of
+/// its own. An EFI image alone is the default entry.
+fn boot_catalog(tree: &Tree, layout: &Layout, opts: &Options) -> anyhow::Result<Vec<u8>> {
+    let mut entries = vec![];
+
+    if let Some(path) = &opts.eltorito_bios {
+        let idx = boot_image(tree, path)?;
+        entries.push((
+            PLATFORM_X86,
+            boot_entry(layout.files[idx], BIOS_LOAD_SECTORS),
+        ));
+    }
+
+    for path in &opts.eltorito_efi {
+        let idx = boot_image(tree, path)?;
+        let Kind::File { len, .. } = tree.nodes[idx].kind else {
+            unreachable!()
+        };
+        // The whole FAT image, where it fits the count
+        let sectors = len.div_ceil(512).min(u16::MAX as u64) as u16;
+        entries.push((PLATFORM_EFI, boot_entry(layout.files[idx], sectors)));
+    }
+
+    let mut catalog = vec![0u8; SECTOR_SIZE as usize];
+
+    // The validation and default entries, and a section header for the rest
+    if (entries.len() + 2) * 32 > catalog.len() {
+        anyhow::bail!("too many El Torito boot images for the boot catalog");
+    }
+
+    // Validation entry, of which the 16-bit words sum up to zero
+    let validation = &mut catalog[..32];
+    validation[0] = 1;
+    validation[1] = entries[0].0;
+    validation[30..32].copy_from_slice(&[0x55, 0xaa]);
+    let sum = validation.chunks(2).fold(0u16, |sum, w| {
+        sum.wrapping_add(u16::from_le_bytes([w[0], w[1]]))
+    });
+    validation[28..30].copy_from_slice(&0u16.wrapping_sub(sum).to_le_bytes());
+
+    catalog[32..64].copy_from_slice(&entries[0].1);
+
+    // The default entry is the BIOS image if there is one, and EFI images follow in a final
+    // section of their own
+    let rest = &entries[1..];
+    if let Some((platform, _)) = rest.first() {
+        catalog[64] = 0x91;
+        catalog[65] = *platform;
+        catalog[66..68].copy_from_slice(&(rest.len() as u16).to_le_bytes());
+        for (i, (_, entry)) in rest.iter().enumerate() {
+            catalog[96 + i * 32..128 + i * 32].copy_from_slice(entry);
+        }
+    }
+
+    Ok(catalog)
+}
+
+/// Fill in the boot info table at offset 8 of a BIOS boot image, which locates the image on the
+/// disc for the code that loads the rest of it.
+fn boot_info_table(data: &mut [u8], extent: u64) -> anyhow::Result<()> {
+    if data.len() < 64 {
+        anyhow::bail!("BIOS boot image is too short for a boot info table");
+    }
+
+    let checksum = data[64..]
+        .chunks(4)
+        .map(|w| {
+            let mut b = [0u8; 4];
+            b[..w.len()].copy_from_slice(w);
+            u32::from_le_bytes(b)
+        })
+        .fold(0u32, u32::wrapping_add);
+
+    data[8..12].copy_from_slice(&(FIRST_DESCRIPTOR as u32).to_le_bytes());
+    data[12..16].copy_from_slice(&(extent as u32).to_le_bytes());
+    let len = data.len() as u32;
+    data[16..20].copy_from_slice(&len.to_le_bytes());
+    data[20..24].copy_from_slice(&checksum.to_le_bytes());
+    data[24..64].fill(0);
+
+    Ok(())
+}
+
+fn write_at<W: Write + Seek>(out: &mut W, sector: u64, data: &[u8]) -> io::Result<()> {
+    out.seek(SeekFrom::Start(sector * SECTOR_SIZE))?;
+    out.write_all(data)
+}
+
+/// Fill a space padded identifier field.
+fn fill(field: &mut [u8], value: &[u8], pad: &[u8]) {
+    for (i, b) in field.iter_mut().enumerate() {
+        *b = value.get(i).copied().unwrap_or(pad[i % pad.len()]);
+    }
+}
+
+/// Write the image into `out`, which spans `size` bytes.
+///
+/// `on_file` is called with the image path and length of every file written.
+pub fn write<W: Write + Seek>(
+    out: &mut W,
+    size: u64,
+    tree: &Tree,
+    opts: &Options,
+    ctx: &crate::BuildContext,
+    on_file: &mut dyn FnMut(&Path, u64),
+) -> anyhow::Result<()> {
+    let layout = Layout::new(tree, opts)?;
+    let boot = opts.is_bootable() as u64;
+    let bios_image = match &opts.eltorito_bios {
+        Some(path) => Some(boot_image(tree, path)?),
+        None => None,
+    };
+
+    if layout.sectors * SECTOR_SIZE > size {
+        anyhow::bail!(
+            "ISO9660 image needs {} bytes, but only {size} are available",
+            layout.sectors * SECTOR_SIZE
+        );
+    }
+
+    let label = opts.label.as_deref().unwrap_or("CDROM");
+    if label.len() > 32 {
+        anyhow::bail!("ISO9660 volume label is longer than 32 characters");
+    }
+    let joliet_label = label.encode_utf16().collect::<Vec<_>>();
+    if joliet_label.len() > 16 {
+        warn!("Joliet volume label is truncated to 16 characters");
+    }
+    let joliet_label = joliet_label
+        .iter()
+        .take(16)
+        .flat_map(|u| u.to_be_bytes())
+        .collect::<Vec<_>>();
+
+    // Volume set, publisher, data preparer and application identifiers, at the offsets of the
+    // 128 byte fields. The volume set is limited to the same characters as the label
+    let identifiers = [
+        (
+            190,
+            "volume set",
+            opts.volume_set.as_deref(),
+            d_chars as fn(&str) -> String,
+        ),
+        (318, "publisher", opts.publisher.as_deref(), a_chars),
+        (446, "data preparer", opts.preparer.as_deref(), a_chars),
+        (
+            574,
+            "application",
+            Some(opts.application.as_deref().unwrap_or("MKIMG")),
+            a_chars,
+        ),
+    ];
+    for (_, name, value, _) in identifiers {
+        let Some(value) = value else { continue };
+        if value.len() > 128 {
+            anyhow::bail!("ISO9660 {name} identifier is longer than 128 characters");
+        }
+        if value.encode_utf16().count() > 64 {
+            warn!("Joliet {name} identifier is truncated to 64 characters");
+        }
+    }
+
+    let time = volume_time(&ctx.time);
+
+    let hierarchies = [
+        (&layout.primary, &layout.primary_extents, 0),
+        (&layout.joliet, &layout.joliet_extents, 2),
+    ];
+
+    for (joliet, (h, extents, table)) in hierarchies.into_iter().enumerate() {
+        let joliet = joliet == 1;
+
+        let mut d = vec![0u8; SECTOR_SIZE as usize];
+        d[0] = if joliet { 2 } else { 1 };
+        d[1..6].copy_from_slice(b"CD001");
+        d[6] = 1;
+
+        // Identifiers are a-characters, or UCS-2 on Joliet
+        let pad: &[u8] = if joliet { &[0, b' '] } else { b" " };
+        let text = |s: &str| match joliet {
+            true => s.encode_utf16().flat_map(|u| u.to_be_bytes()).collect(),
+            false => s.as_bytes().to_vec(),
+        };
+
+        fill(&mut d[8..40], &[], pad);
+        match joliet {
+            true => fill(&mut d[40..72], &joliet_label, pad),
+            false => fill(&mut d[40..72], d_chars(label).as_bytes(), pad),
+        }
+        d[80..88].copy_from_slice(&both_u32(layout.sectors as u32));
+        if joliet {
+            // UCS-2 level 3
+            d[88..91].copy_from_slice(b"%/E");
+        }
+        d[120..124].copy_from_slice(&both_u16(1));
+        d[124..128].copy_from_slice(&both_u16(1));
+        d[128..132].copy_from_slice(&both_u16(SECTOR_SIZE as u16));
+        d[132..140].copy_from_slice(&both_u32(h.path_table_size() as u32));
+        d[140..144].copy_from_slice(&(layout.path_tables[table] as u32).to_le_bytes());
+        d[148..152].copy_from_slice(&(layout.path_tables[table + 1] as u32).to_be_bytes());
+        d[156..190].copy_from_slice(&record(
+            &[0],
+            extents[0],
+            h.dir_size(0),
+            tree.nodes[0].mtime,
+            true,
+        ));
+        for (off, _, value, chars) in identifiers {
+            let value = value.unwrap_or_default();
+            let mut value = match joliet {
+                true => text(value),
+                false => chars(value).into_bytes(),
+            };
+            value.truncate(128);
+            fill(&mut d[off..off + 128], &value, pad);
+        }
+        fill(&mut d[702..813], &[], pad);
+        d[813..830].copy_from_slice(&time);
+        d[830..847].copy_from_slice(&time);
+        d[847..863].fill(b'0');
+        d[864..881].copy_from_slice(&time);
+        d[881] = 1;
+
+        // The boot record follows the primary descriptor
+        write_at(out, FIRST_DESCRIPTOR + joliet as u64 * (1 + boot), &d)?;
+
+        write_at(
+            out,
+            layout.path_tables[table],
+            &h.path_table(tree, extents, false),
+        )?;
+        write_at(
+            out,
+            layout.path_tables[table + 1],
+            &h.path_table(tree, extents, true),
+        )?;
+
+        for &dir in &h.dirs {
+            write_at(out, extents[dir], &layout.dir_extent(tree, h, extents, dir))?;
+        }
+    }
+
+    if let Some(catalog) = layout.catalog {
+        let mut d = vec![0u8; SECTOR_SIZE as usize];
+        d[1..6].copy_from_slice(b"CD001");
+        d[6] = 1;
+        d[7..30].copy_from_slice(b"EL TORITO SPECIFICATION");
+        d[71..75].copy_from_slice(&(catalog as u32).to_le_bytes());
+        write_at(out, FIRST_DESCRIPTOR + 1, &d)?;
+
+        write_at(out, catalog, &boot_catalog(tree, &layout, opts)?)?;
+    }
+
+    let mut d = vec![0u8; SECTOR_SIZE as usize];
+    d[0] = 255;
+    d[1..6].copy_from_slice(b"CD001");
+    d[6] = 1;
+    write_at(out, FIRST_DESCRIPTOR + 2 + boot, &d)?;
+
+    for (idx, node) in tree.nodes.iter().enumerate() {
+        if let Kind::File { source, len } = &node.kind {
+            if *len > 0 {
+                out.seek(SeekFrom::Start(layout.files[idx] * SECTOR_SIZE))?;
+                let copied = if bios_image == Some(idx) {
+                    let mut data = vec![];
+                    source.open()?.take(*len).read_to_end(&mut data)?;
+                    boot_info_table(&mut data, layout.files[idx])?;
+                    out.write_all(&data)?;
+                    data.len() as u64
+                } else {
+                    io::copy(&mut source.open()?.take(*len), out)?
+                };
+                if copied != *len {
+                    anyhow::bail!("{} changed while being copied", node.path.display());
+                }
+                // Pad the last sector
+                let tail = (SECTOR_SIZE - len % SECTOR_SIZE) % SECTOR_SIZE;
+                out.write_all(&vec![0; tail as usize])?;
+            }
+            on_file(&node.path, *len);
+        }
+    }
+
+    Ok(())
+}
+
+impl crate::FsWriter for Options {
+    fn estimate_size(&self, tree: &Tree) -> anyhow::Result<u64> {
+        estimate_size(tree, self)
+    }
+
+    fn write(
+        &self,
+        mut out: &mut dyn fatfs::ReadWriteSeek,
+        size: u64,
+        tree: &Tree,
+        ctx: &crate::BuildContext,
+        on_file: &mut dyn FnMut(&Path, u64),
+    ) -> anyhow::Result<()> {
+        write(&mut out, size, tree, self, ctx, on_file)
+    }
+}
@@ -0,0 +1,149 @@
+//! MBR over an ISO9660 image, as written by `isohybrid`, so that the image boots from a USB stick
+//! as well as from a CD.
+//!
+//! The first partition spans the whole image, starting at its first sector. The El Torito EFI
+//! boot image gets a partition of its own, for firmware looking for an ESP. Boot code such as
+//! `isohdpfx.bin` from syslinux loads the El Torito BIOS boot image, of which the 512 byte sector
+//! is stored in the code area at offset 432.
+
+use log::*;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+
+const CD_SECTOR: u64 = 2048;
+const SECTOR_SIZE: u32 = 512;
+
+/// Sector of the El Torito boot record, after the primary volume descriptor.
+const BOOT_RECORD: u64 = 17;
+
+/// Offset of the BIOS boot image sector in the MBR code.
+const BOOT_IMAGE_LBA: usize = 432;
+
+/// MBR types of the filesystem and EFI image partitions, as used by `isohybrid`.
+const ISO_TYPE: u8 = 0x17;
+const EFI_TYPE: u8 = 0xef;
+
+const PLATFORM_X86: u8 = 0;
+const PLATFORM_EFI: u8 = 0xef;
+
+/// BIOS and EFI boot images found in the boot catalog, as the sector and the 512 byte sector
+/// count of each.
+#[derive(Default, Debug)]
+struct BootImages {
+    bios: Option<(u32, u16)>,
+    efi: Option<(u32, u16)>,
+}
+
+fn read_sector(file: &mut &File, sector: u64) -> anyhow::Result<Vec<u8>> {
+    let mut buf = vec![0u8; CD_SECTOR as usize];
+    file.seek(SeekFrom::Start(sector * CD_SECTOR))?;
+    file.read_exact(&mut buf)?;
+    Ok(buf)
+}
+
+fn boot_images(file: &mut &File) -> anyhow::Result<BootImages> {
+    let mut images = BootImages::default();
+
+    let record = read_sector(file, BOOT_RECORD)?;
+    if record[0] != 0 || &record[1..6] != b"CD001" || &record[7..30] != b"EL TORITO SPECIFICATION" {
+        return Ok(images);
+    }
+
+    let catalog = u32::from_le_bytes(record[71..75].try_into().unwrap());
+    let catalog = read_sector(file, catalog as u64)?;
+    if catalog[0] != 1 || catalog[30..32] != [0x55, 0xaa] {
+        anyhow::bail!("invalid El Torito boot catalog");
+    }
+
+    let mut add = |platform: u8, entry: &[u8]| {
+        // Only bootable entries
+        if entry[0] != 0x88 {
+            return;
+        }
+        let lba = u32::from_le_bytes(entry[8..12].try_into().unwrap());
+        let count = u16::from_le_bytes([entry[6], entry[7]]);
+        let image = match platform {
+            PLATFORM_X86 => &mut images.bios,
+            PLATFORM_EFI => &mut images.efi,
+            _ => return,
+        };
+        image.get_or_insert((lba, count));
+    };
+
+    add(catalog[1], &catalog[32..64]);
+
+    // Sections, each a header followed by its entries, until the final header
+    let mut offset = 64;
+    while offset + 32 <= catalog.len() && matches!(catalog[offset], 0x90 | 0x91) {
+        let (last, platform) = (catalog[offset] == 0x91, catalog[offset + 1]);
+        let count = u16::from_le_bytes([catalog[offset + 2], catalog[offset + 3]]) as usize;
+        offset += 32;
+
+        for _ in 0..count {
+            if offset + 32 > catalog.len() {
+                break;
+            }
+            add(platform, &catalog[offset..offset + 32]);
+            offset += 32;
+        }
+
+        if last {
+            break;
+        }
+    }
+
+    Ok(images)
+}
+
+fn entry(sys: u8, boot: bool, start: u32, sectors: u32) -> mbrman::MBRPartitionEntry {
+    mbrman::MBRPartitionEntry {
+        boot: if boot {
+            mbrman::BOOT_ACTIVE
+        } else {
+            mbrman::BOOT_INACTIVE
+        },
+        first_chs: mbrman::CHS::empty(),
+        sys,
+        last_chs: mbrman::CHS::empty(),
+        starting_lba: start,
+        sectors,
+    }
+}
+
+/// Write the MBR over the ISO9660 image of `size` bytes in `file`, with `code` in its code area.
+pub fn install(
+    file: &File,
+    size: u64,
+    code: Option<&[u8]>,
+    signature: [u8; 4],
+) -> anyhow::Result<()> {
+    let mut file = file;
+    let images = boot_images(&mut file)?;
+    debug!("El Torito boot images: {images:?}");
+
+    let sectors = u32::try_from(size / SECTOR_SIZE as u64)
+        .map_err(|_| anyhow::anyhow!("the image is too large for an MBR"))?;
+    let to_sectors = |lba: u32| lba * (CD_SECTOR / SECTOR_SIZE as u64) as u32;
+
+    let mut mbr = mbrman::MBR::new_from(&mut file, SECTOR_SIZE, signature)?;
+
+    if let Some(code) = code {
+        mbr.header.bootstrap_code[..code.len()].copy_from_slice(code);
+
+        match images.bios {
+            Some((lba, _)) => mbr.header.bootstrap_code[BOOT_IMAGE_LBA..BOOT_IMAGE_LBA + 4]
+                .copy_from_slice(&to_sectors(lba).to_le_bytes()),
+            None => warn!("no El Torito BIOS boot image for the MBR boot code to load"),
+        }
+    }
+
+    mbr[1] = entry(ISO_TYPE, true, 0, sectors);
+
+    if let Some((lba, count)) = images.efi {
+        mbr[2] = entry(EFI_TYPE, false, to_sectors(lba), count as u32);
+    }
+
+    mbr.write_into(&mut file)?;
+
+    Ok(())
+}
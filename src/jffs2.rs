@@ -0,0 +1,347 @@
+//! JFFS2 images, little endian, for NOR flash.
+//!
+//! Every erase block starts with a clean marker, nodes never cross into the next block, and
+//! space without nodes is left as 0xff like erased flash. File data is split into nodes of a
+//! page, compressed with zlib unless that does not make them smaller. The target of a symbolic
+//! link is the uncompressed data of its only inode node, as the kernel reads it.
+
+use crate::compress;
+use crate::tree::{Kind, Tree};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+const MAGIC: u16 = 0x1985;
+const NODETYPE_DIRENT: u16 = 0xE001;
+const NODETYPE_INODE: u16 = 0xE002;
+const NODETYPE_CLEANMARKER: u16 = 0x2003;
+
+const HEADER_SIZE: usize = 12;
+const DIRENT_SIZE: usize = 40;
+const INODE_SIZE: usize = 68;
+/// Data of a file is split into nodes of this size
+const PAGE_SIZE: u64 = 4096;
+/// Smallest erase block, which fits the largest node
+const MIN_ERASE_BLOCK: u64 = 8 << 10;
+
+const COMPR_NONE: u8 = 0;
+const COMPR_ZLIB: u8 = 6;
+
+const DT_DIR: u8 = 4;
+const DT_REG: u8 = 8;
+const DT_LNK: u8 = 10;
+
+const S_IFMT: u32 = 0o170000;
+const S_IFDIR: u32 = 0o040000;
+const S_IFREG: u32 = 0o100000;
+const S_IFLNK: u32 = 0o120000;
+
+/// Inode number of the root directory
+const ROOT_INO: u32 = 1;
+
+#[derive(Clone, Debug)]
+pub struct Options {
+    pub erase_block: u64,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            erase_block: 64 << 10,
+        }
+    }
+}
+
+/// CRC32 as JFFS2 computes it, without inverting it before and after.
+fn crc(data: &[u8]) -> u32 {
+    !crc::crc32::update(!0, &crc::crc32::IEEE_TABLE, data)
+}
+
+fn header(node: &mut [u8], nodetype: u16) {
+    node[0..2].copy_from_slice(&MAGIC.to_le_bytes());
+    node[2..4].copy_from_slice(&nodetype.to_le_bytes());
+    let len = node.len() as u32;
+    node[4..8].copy_from_slice(&len.to_le_bytes());
+    let sum = crc(&node[0..8]);
+    node[8..12].copy_from_slice(&sum.to_le_bytes());
+}
+
+/// Places nodes into erase blocks, measuring the image when there is nothing to write to.
+struct Output<'a, W> {
+    out: Option<&'a mut W>,
+    pos: u64,
+    limit: u64,
+    erase_block: u64,
+}
+
+impl<W: Write> Output<'_, W> {
+    fn raw(&mut self, data: &[u8]) -> io::Result<()> {
+        if let Some(out) = &mut self.out {
+            if self.pos + data.len() as u64 > self.limit {
+                return Err(io::Error::other(format!(
+                    "JFFS2 image does not fit in {} bytes",
+                    self.limit
+                )));
+            }
+            out.write_all(data)?;
+        }
+        self.pos += data.len() as u64;
+        Ok(())
+    }
+
+    /// Fill the rest of the current erase block as erased flash.
+    fn finish_block(&mut self) -> io::Result<()> {
+        let rest = self.pos.next_multiple_of(self.erase_block) - self.pos;
+        self.raw(&vec![0xff; rest as usize])
+    }
+
+    fn node(&mut self, node: &[u8]) -> io::Result<()> {
+        let len = (node.len() as u64).next_multiple_of(4);
+
+        if self.pos % self.erase_block + len > self.erase_block {
+            self.finish_block()?;
+        }
+
+        if self.pos.is_multiple_of(self.erase_block) {
+            let mut marker = [0u8; HEADER_SIZE];
+            header(&mut marker, NODETYPE_CLEANMARKER);
+            self.raw(&marker)?;
+        }
+
+        self.raw(node)?;
+        self.raw(&[0; 3][..(len - node.len() as u64) as usize])
+    }
+}
+
+struct Inode<'a> {
+    ino: u32,
+    version: u32,
+    mode: u32,
+    uid: u32,
+    gid: u32,
+    isize: u64,
+    mtime: u32,
+    offset: u64,
+    data: &'a [u8],
+}
+
+fn inode_node(i: &Inode) -> Vec<u8> {
+    let (compr, data) = match compress::zlib(i.data, compress::DEFAULT_LEVEL) {
+        _ if i.mode & S_IFMT == S_IFLNK => (COMPR_NONE, i.data.to_vec()),
+        z if !i.data.is_empty() && z.len() < i.data.len() => (COMPR_ZLIB, z),
+        _ => (COMPR_NONE, i.data.to_vec()),
+    };
+
+    let mut n = vec![0u8; INODE_SIZE + data.len()];
+    header(&mut n, NODETYPE_INODE);
+    n[12..16].copy_from_slice(&i.ino.to_le_bytes());
+    n[16..20].copy_from_slice(&i.version.to_le_bytes());
+    n[20..24].copy_from_slice(&i.mode.to_le_bytes());
+    n[24..26].copy_from_slice(&(i.uid as u16).to_le_bytes());
+    n[26..28].copy_from_slice(&(i.gid as u16).to_le_bytes());
+    n[28..32].copy_from_slice(&(i.isize as u32).to_le_bytes());
+    for t in [32, 36, 40] {
+        n[t..t + 4].copy_from_slice(&i.mtime.to_le_bytes());
+    }
+    n[44..48].copy_from_slice(&(i.offset as u32).to_le_bytes());
+    n[48..52].copy_from_slice(&(data.len() as u32).to_le_bytes());
+    n[52..56].copy_from_slice(&(i.data.len() as u32).to_le_bytes());
+    n[56] = compr;
+    n[60..64].copy_from_slice(&crc(&data).to_le_bytes());
+    let sum = crc(&n[..60]);
+    n[64..68].copy_from_slice(&sum.to_le_bytes());
+    n[INODE_SIZE..].copy_from_slice(&data);
+    n
+}
+
+fn dirent_node(pino: u32, version: u32, ino: u32, mctime: u32, name: &str, dtype: u8) -> Vec<u8> {
+    let mut n = vec![0u8; DIRENT_SIZE + name.len()];
+    header(&mut n, NODETYPE_DIRENT);
+    n[12..16].copy_from_slice(&pino.to_le_bytes());
+    n[16..20].copy_from_slice(&version.to_le_bytes());
+    n[20..24].copy_from_slice(&ino.to_le_bytes());
+    n[24..28].copy_from_slice(&mctime.to_le_bytes());
+    n[28] = name.len() as u8;
+    n[29] = dtype;
+    let sum = crc(&n[..32]);
+    n[32..36].copy_from_slice(&sum.to_le_bytes());
+    n[36..40].copy_from_slice(&crc(name.as_bytes()).to_le_bytes());
+    n[DIRENT_SIZE..].copy_from_slice(name.as_bytes());
+    n
+}
+
+/// Lay out, and with `out` write the image. Returns the used size, in whole erase blocks.
+fn build<W: Write + Seek>(
+    mut out: Option<&mut W>,
+    size: u64,
+    tree: &Tree,
+    opts: &Options,
+    on_file: &mut dyn FnMut(&Path, u64),
+) -> anyhow::Result<u64> {
+    if opts.erase_block < MIN_ERASE_BLOCK || !opts.erase_block.is_power_of_two() {
+        anyhow::bail!(
+            "JFFS2 erase block size must be a power of two of at least {MIN_ERASE_BLOCK} bytes"
+        );
+    }
+
+    for node in &tree.nodes[1..] {
+        if node.name.len() > 254 {
+            anyhow::bail!(
+                "JFFS2 names are limited to 254 bytes: {}",
+                node.path.display()
+            );
+        }
+        if let Kind::File { len, .. } = node.kind {
+            if len > u32::MAX as u64 {
+                anyhow::bail!("JFFS2 files are limited to 4GiB: {}", node.path.display());
+            }
+        }
+    }
+
+    if let Some(out) = &mut out {
+        out.seek(SeekFrom::Start(0))?;
+    }
+    let mut o = Output {
+        out,
+        pos: 0,
+        limit: size,
+        erase_block: opts.erase_block,
+    };
+
+    // Nodes of an inode are told apart by version, directory entries belong to their parent
+    let mut versions = vec![0u32; tree.nodes.len()];
+    let mut version = |idx: usize| {
+        versions[idx] += 1;
+        versions[idx]
+    };
+    let ino = |idx: usize| ROOT_INO + idx as u32;
+    let mut page = vec![0u8; PAGE_SIZE as usize];
+
+    for (idx, node) in tree.nodes.iter().enumerate() {
+        let mtime = node.mtime.clamp(0, u32::MAX as i64) as u32;
+
+        if idx > 0 {
+            let dtype = match node.kind {
+                Kind::Dir(_) => DT_DIR,
+                Kind::File { .. } => DT_REG,
+                Kind::Symlink(_) => DT_LNK,
+            };
+            let v = version(node.parent);
+            o.node(&dirent_node(
+                ino(node.parent),
+                v,
+                ino(idx),
+                mtime,
+                &node.name,
+                dtype,
+            ))?;
+        }
+
+        let mut inode = Inode {
+            ino: ino(idx),
+            version: 0,
+            mode: node.mode,
+            uid: node.uid,
+            gid: node.gid,
+            isize: 0,
+            mtime,
+            offset: 0,
+            data: &[],
+        };
+
+        match &node.kind {
+            Kind::Dir(_) => {
+                inode.mode |= S_IFDIR;
+                inode.version = version(idx);
+                o.node(&inode_node(&inode))?;
+            }
+            Kind::File { source, len } => {
+                inode.mode |= S_IFREG;
+                inode.isize = *len;
+
+                if *len == 0 {
+                    inode.version = version(idx);
+                    o.node(&inode_node(&inode))?;
+                }
+
+                let mut file = source.open()?;
+                let mut offset = 0;
+                while offset < *len {
+                    let n = (len - offset).min(PAGE_SIZE) as usize;
+                    file.read_exact(&mut page[..n]).map_err(|e| {
+                        anyhow::anyhow!("{} changed while being copied: {e}", node.path.display())
+                    })?;
+
+                    o.node(&inode_node(&Inode {
+                        version: version(idx),
+                        offset,
+                        data: &page[..n],
+                        ..inode
+                    }))?;
+                    offset += n as u64;
+                }
+
+                on_file(&node.path, *len);
+            }
+            Kind::Symlink(target) => {
+                inode.mode |= S_IFLNK;
+                inode.isize = target.len() as u64;
+                inode.version = version(idx);
+                inode.data = target.as_bytes();
+                o.node(&inode_node(&inode))?;
+            }
+        }
+    }
+
+    o.finish_block()?;
+    let used = o.pos;
+
+    // The rest of the partition is free space
+    if o.out.is_some() {
+        let mut remaining = size - used;
+        let erased = vec![0xff; 1 << 20];
+        while remaining > 0 {
+            let n = remaining.min(erased.len() as u64) as usize;
+            o.raw(&erased[..n])?;
+            remaining -= n as u64;
+        }
+    }
+
+    Ok(used)
+}
+
+/// Smallest filesystem size the tree fits in.
+pub fn estimate_size(tree: &Tree, opts: &Options) -> anyhow::Result<u64> {
+    build::<io::Cursor<Vec<u8>>>(None, u64::MAX, tree, opts, &mut |_, _| ())
+}
+
+/// Write the image into `out`, which spans `size` bytes.
+///
+/// `on_file` is called with the image path and length of every file written.
+pub fn write<W: Write + Seek>(
+    out: &mut W,
+    size: u64,
+    tree: &Tree,
+    opts: &Options,
+    on_file: &mut dyn FnMut(&Path, u64),
+) -> anyhow::Result<()> {
+    build(Some(out), size, tree, opts, on_file)?;
+
+    Ok(())
+}
+
+impl crate::FsWriter for Options {
+    fn estimate_size(&self, tree: &Tree) -> anyhow::Result<u64> {
+        estimate_size(tree, self)
+    }
+
+    fn write(
+        &self,
+        mut out: &mut dyn fatfs::ReadWriteSeek,
+        size: u64,
+        tree: &Tree,
+        _ctx: &crate::BuildContext,
+        on_file: &mut dyn FnMut(&Path, u64),
+    ) -> anyhow::Result<()> {
+        write(&mut out, size, tree, self, on_file)
+    }
+}
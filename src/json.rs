@@ -0,0 +1,173 @@
+//! Minimal JSON serialization, enough to describe builds to external tools.
+
+use std::fmt;
+
+#[derive(Clone, Debug)]
+pub enum Value {
+    Null,
+    Bool(bool),
+    Int(i64),
+    Str(String),
+    Array(Vec<Value>),
+    Object(Vec<(String, Value)>),
+}
+
+impl Value {
+    /// Create an empty object to be filled with [`Value::with`].
+    pub fn object() -> Self {
+        Self::Object(vec![])
+    }
+
+    /// Append a key to an object. Does nothing on other value types.
+    pub fn with(mut self, key: &str, value: impl Into<Value>) -> Self {
+        if let Self::Object(fields) = &mut self {
+            fields.push((key.into(), value.into()));
+        }
+        self
+    }
+}
+
+impl From<bool> for Value {
+    fn from(v: bool) -> Self {
+        Self::Bool(v)
+    }
+}
+
+impl From<u64> for Value {
+    fn from(v: u64) -> Self {
+        Self::Int(v as i64)
+    }
+}
+
+impl From<u32> for Value {
+    fn from(v: u32) -> Self {
+        Self::Int(v.into())
+    }
+}
+
+impl From<usize> for Value {
+    fn from(v: usize) -> Self {
+        Self::Int(v as i64)
+    }
+}
+
+impl From<i64> for Value {
+    fn from(v: i64) -> Self {
+        Self::Int(v)
+    }
+}
+
+impl From<&str> for Value {
+    fn from(v: &str) -> Self {
+        Self::Str(v.into())
+    }
+}
+
+impl From<String> for Value {
+    fn from(v: String) -> Self {
+        Self::Str(v)
+    }
+}
+
+impl<T: Into<Value>> From<Option<T>> for Value {
+    fn from(v: Option<T>) -> Self {
+        v.map(Into::into).unwrap_or(Self::Null)
+    }
+}
+
+impl<T: Into<Value>> From<Vec<T>> for Value {
+    fn from(v: Vec<T>) -> Self {
+        Self::Array(v.into_iter().map(Into::into).collect())
+    }
+}
+
+fn write_str(f: &mut fmt::Formatter, s: &str) -> fmt::Result {
+    f.write_str("\"")?;
+    for c in s.chars() {
+        match c {
+            '"' => f.write_str("\\\"")?,
+            '\\' => f.write_str("\\\\")?,
+            '\n' => f.write_str("\\n")?,
+            '\r' => f.write_str("\\r")?,
+            '\t' => f.write_str("\\t")?,
+            c if (c as u32) < 0x20 => write!(f, "\\u{:04x}", c as u32)?,
+            c => write!(f, "{c}")?,
+        }
+    }
+    f.write_str("\"")
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Null => f.write_str("null"),
+            Self::Bool(v) => write!(f, "{v}"),
+            Self::Int(v) => write!(f, "{v}"),
+            Self::Str(v) => write_str(f, v),
+            Self::Array(values) => {
+                f.write_str("[")?;
+                for (i, v) in values.iter().enumerate() {
+                    if i > 0 {
+                        f.write_str(",")?;
+                    }
+                    write!(f, "{v}")?;
+                }
+                f.write_str("]")
+            }
+            Self::Object(fields) => {
+                f.write_str("{")?;
+                for (i, (k, v)) in fields.iter().enumerate() {
+                    if i > 0 {
+                        f.write_str(",")?;
+                    }
+                    write_str(f, k)?;
+                    write!(f, ":{v}")?;
+                }
+                f.write_str("}")
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scalars() {
+        assert_eq!(Value::Null.to_string(), "null");
+        assert_eq!(Value::from(true).to_string(), "true");
+        assert_eq!(Value::from(-42i64).to_string(), "-42");
+        assert_eq!(Value::from(u64::from(u32::MAX)).to_string(), "4294967295");
+        assert_eq!(Value::from(None::<u32>).to_string(), "null");
+        assert_eq!(Value::from(Some("a")).to_string(), "\"a\"");
+    }
+
+    #[test]
+    fn string_escapes() {
+        let s = "quote \" backslash \\ newline \n cr \r tab \t bell \u{7} unicode é";
+        assert_eq!(
+            Value::from(s).to_string(),
+            r#""quote \" backslash \\ newline \n cr \r tab \t bell \u0007 unicode é""#
+        );
+    }
+
+    #[test]
+    fn nesting() {
+        let value = Value::object()
+            .with("name", "disk.img")
+            .with("sizes", vec![1u64, 2])
+            .with("empty", Vec::<u64>::new())
+            .with("nested", Value::object().with("k\"ey", Value::object()));
+        assert_eq!(
+            value.to_string(),
+            r#"{"name":"disk.img","sizes":[1,2],"empty":[],"nested":{"k\"ey":{}}}"#
+        );
+    }
+
+    #[test]
+    fn with_ignores_non_objects() {
+        let value = Value::from(vec![1u32]).with("key", 1u32);
+        assert_eq!(value.to_string(), "[1]");
+    }
+}
@@ -0,0 +1,469 @@
+//! Partitions of the image, from the command line or a TOML layout manifest.
+//!
+//! Partitions may also be given on the command line as `NAME:FILESYSTEM:SIZE:DIR`, for example
+//! `esp:vfat:128M:./esp`. `FILESYSTEM` may be `none` to leave the partition unformatted, without
+//! a directory, and `SIZE` may be `auto` to estimate it. The partition type is the alias matching
+//! the name, such as `esp` or `root`, and `generic` for any other name.
+//!
+//! A manifest lists partitions in disk order as `[[partition]]` tables:
+//!
+//! ```toml
+//! [[partition]]
+//! name = "esp"
+//! type = "esp"
+//! filesystem = "vfat"
+//! size = "128M"
+//! input = "esp"
+//!
+//! [[partition]]
+//! name = "root"
+//! type = "root-x86-64"
+//! filesystem = "ext2"
+//! input = "rootfs"
+//! ```
+//!
+//! Instead of a filesystem and its input, a partition may take an existing image, which is copied
+//! into it as is and sizes it unless `size` is set:
+//!
+//! ```toml
+//! [[partition]]
+//! name = "esp"
+//! type = "esp"
+//! content = { image = "esp.img" }
+//! ```
+//!
+//! btrfs partitions may be split into subvolumes, each filled from its own directory, or left
+//! empty without `input`. The one marked `default` is mounted when no subvolume is asked for,
+//! and the partition itself needs no `input` then:
+//!
+//! ```toml
+//! [[partition]]
+//! name = "root"
+//! type = "root-x86-64"
+//! filesystem = "btrfs"
+//! subvolumes = [
+//!     { name = "@", input = "rootfs", default = true },
+//!     { name = "@home", input = "home" },
+//!     { name = "@snapshots" },
+//! ]
+//! ```
+//!
+//! Small files can be written into a partition from the manifest, to the partition with the
+//! filesystem if there is only one, or the one named by `partition`. A partition with files needs
+//! no `input`, and holds only those then:
+//!
+//! ```toml
+//! [[files]]
+//! partition = "esp"
+//! path = "/loader/loader.conf"
+//! content = """
+//! timeout 3
+//! default arch.conf
+//! """
+//! ```
+
+use crate::part_type::{self, GptType};
+use crate::size::PartitionSize;
+use crate::toml::{self, Value};
+use crate::{ExtraFile, FileSource, Filesystem};
+use anyhow::Context;
+use clap::ValueEnum;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+/// A partition, and what goes into it.
+#[derive(Clone, Debug)]
+pub struct Partition {
+    /// Name in the partition table
+    pub name: String,
+    /// Filesystem to format it with, left unformatted if not set
+    pub filesystem: Option<Filesystem>,
+    /// Estimated from the contents if not set
+    pub size: Option<PartitionSize>,
+    pub gpt_type: GptType,
+    /// Unique GPT partition GUID, random if not set
+    pub uuid: Option<String>,
+    /// MBR system ID, derived from the GPT type and filesystem if not set
+    pub mbr_type: Option<u8>,
+    /// Whether an MBR partition is primary rather than logical. If not set, partitions from the
+    /// fourth on are logical when there are more than four
+    pub primary: Option<bool>,
+    pub bootable: bool,
+    /// GPT attribute bits, in addition to the bootable one
+    pub attributes: u64,
+    pub input_dir: Option<PathBuf>,
+    /// Image copied into the partition instead of formatting it
+    pub image: Option<PathBuf>,
+    /// Volume label of the filesystem, which may contain template placeholders
+    pub label: Option<String>,
+    /// Files added to the filesystem in addition to its input
+    pub files: Vec<ExtraFile>,
+    /// btrfs subvolumes, made in the order they are listed
+    pub subvolumes: Vec<Subvolume>,
+}
+
+/// A btrfs subvolume, and what goes into it.
+#[derive(Clone, Debug)]
+pub struct Subvolume {
+    /// Path in the filesystem, such as `@home`
+    pub path: PathBuf,
+    /// Left empty if not set
+    pub input_dir: Option<PathBuf>,
+    /// Whether it is mounted instead of the top level when no subvolume is asked for
+    pub default: bool,
+}
+
+impl Partition {
+    /// Check that subvolumes are only given for btrfs, at distinct paths inside the filesystem,
+    /// and with at most one default.
+    pub fn check_subvolumes(&self) -> anyhow::Result<()> {
+        if self.subvolumes.is_empty() {
+            return Ok(());
+        }
+        if !matches!(self.filesystem, Some(Filesystem::Btrfs)) {
+            anyhow::bail!("subvolumes only apply to btrfs");
+        }
+
+        for (i, sub) in self.subvolumes.iter().enumerate() {
+            let normal = sub
+                .path
+                .components()
+                .all(|c| matches!(c, std::path::Component::Normal(_)));
+            if !normal || sub.path.as_os_str().is_empty() {
+                anyhow::bail!(
+                    "subvolume path `{}` must be relative, without `.` or `..`",
+                    sub.path.display()
+                );
+            }
+            if self.subvolumes[..i].iter().any(|s| s.path == sub.path) {
+                anyhow::bail!("subvolume `{}` is given twice", sub.path.display());
+            }
+        }
+
+        if self.subvolumes.iter().filter(|s| s.default).count() > 1 {
+            anyhow::bail!("only one subvolume can be the default");
+        }
+        Ok(())
+    }
+}
+
+fn parse_filesystem(s: &str) -> anyhow::Result<Filesystem> {
+    let fs =
+        Filesystem::from_str(s, true).map_err(|_| anyhow::anyhow!("unknown filesystem `{s}`"))?;
+    if fs.is_archive() {
+        anyhow::bail!("archives cannot be written into partitions");
+    }
+    Ok(fs)
+}
+
+impl FromStr for Partition {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        let mut fields = s.splitn(4, ':');
+        let (Some(name), Some(fs), Some(size)) = (fields.next(), fields.next(), fields.next())
+        else {
+            return Err(format!("expected NAME:FILESYSTEM:SIZE:DIR, got `{s}`"));
+        };
+        let dir = fields.next().filter(|d| !d.is_empty());
+
+        if name.is_empty() {
+            return Err(format!("empty partition name in `{s}`"));
+        }
+
+        let filesystem = match fs {
+            "none" => None,
+            fs => Some(parse_filesystem(fs).map_err(|e| e.to_string())?),
+        };
+
+        let size = match size {
+            "auto" => None,
+            size => Some(size.parse()?),
+        };
+
+        match (filesystem, dir) {
+            (Some(_), None) => return Err(format!("partition `{name}` requires a directory")),
+            (None, Some(_)) => {
+                return Err(format!("unformatted partition `{name}` takes no directory"))
+            }
+            _ => {}
+        }
+
+        Ok(Self {
+            name: name.into(),
+            filesystem,
+            size,
+            gpt_type: name.parse().unwrap_or_else(|_| "generic".parse().unwrap()),
+            uuid: None,
+            mbr_type: None,
+            primary: None,
+            bootable: false,
+            attributes: 0,
+            input_dir: dir.map(PathBuf::from),
+            image: None,
+            label: None,
+            files: vec![],
+            subvolumes: vec![],
+        })
+    }
+}
+
+impl fmt::Display for Partition {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let fs = self
+            .filesystem
+            .and_then(|fs| fs.to_possible_value())
+            .map_or("none".into(), |v| v.get_name().to_owned());
+        let size = self.size.map_or("auto".into(), |s| s.to_string());
+        write!(f, "{}:{fs}:{size}", self.name)?;
+        if let Some(dir) = &self.input_dir {
+            write!(f, ":{}", dir.display())?;
+        }
+        Ok(())
+    }
+}
+
+fn partition(table: &[(String, Value)], number: usize, base: &Path) -> anyhow::Result<Partition> {
+    let mut part = Partition {
+        name: format!("part{number}"),
+        filesystem: None,
+        size: None,
+        gpt_type: "generic".parse().unwrap(),
+        uuid: None,
+        mbr_type: None,
+        primary: None,
+        bootable: false,
+        attributes: 0,
+        input_dir: None,
+        image: None,
+        label: None,
+        files: vec![],
+        subvolumes: vec![],
+    };
+
+    for (key, value) in table {
+        match (key.as_str(), value) {
+            ("name", Value::Str(s)) => part.name = s.clone(),
+            ("filesystem", Value::Str(s)) => part.filesystem = Some(parse_filesystem(s)?),
+            ("size", Value::Str(s)) => part.size = Some(s.parse().map_err(anyhow::Error::msg)?),
+            ("size", Value::Int(v)) => {
+                let v = u64::try_from(*v).context("negative size")?;
+                part.size = Some(PartitionSize::Bytes(v));
+            }
+            ("type", Value::Str(s)) => part.gpt_type = s.parse().map_err(anyhow::Error::msg)?,
+            ("mbr_type", Value::Str(s)) => {
+                part.mbr_type = Some(part_type::parse_mbr_type(s).map_err(anyhow::Error::msg)?)
+            }
+            ("mbr_type", Value::Int(id)) => {
+                part.mbr_type = Some(u8::try_from(*id).context("MBR type does not fit in a byte")?)
+            }
+            ("uuid", Value::Str(s)) => {
+                part.uuid = Some(part_type::parse_guid(s).map_err(anyhow::Error::msg)?)
+            }
+            ("bootable", Value::Bool(b)) => part.bootable = *b,
+            ("primary", Value::Bool(b)) => part.primary = Some(*b),
+            ("attributes", Value::Array(items)) => {
+                for item in items {
+                    let attr = match item {
+                        Value::Str(s) => s.clone(),
+                        Value::Int(bit) => bit.to_string(),
+                        v => anyhow::bail!("GPT attributes are names or bit numbers, not {v}"),
+                    };
+                    part.attributes |=
+                        part_type::parse_attribute(&attr).map_err(anyhow::Error::msg)?;
+                }
+            }
+            ("input", Value::Str(s)) => part.input_dir = Some(base.join(s)),
+            ("label", Value::Str(s)) => part.label = Some(s.clone()),
+            ("subvolumes", Value::Array(items)) => {
+                for (i, item) in items.iter().enumerate() {
+                    let Value::Table(table) = item else {
+                        anyhow::bail!("subvolume {} is not a table", i + 1);
+                    };
+                    let sub =
+                        subvolume(table, base).with_context(|| format!("subvolume {}", i + 1))?;
+                    part.subvolumes.push(sub);
+                }
+            }
+            ("content", Value::Table(content)) => {
+                for (key, value) in content {
+                    match (key.as_str(), value) {
+                        ("image", Value::Str(s)) => part.image = Some(base.join(s)),
+                        ("image", v) => anyhow::bail!("`content.image` must be a string, not {v}"),
+                        _ => anyhow::bail!("unknown key `content.{key}`"),
+                    }
+                }
+            }
+            ("name" | "filesystem" | "type" | "uuid" | "input" | "label", v) => {
+                anyhow::bail!("`{key}` must be a string, not {v}")
+            }
+            ("size" | "mbr_type", v) => {
+                anyhow::bail!("`{key}` must be a string or an integer, not {v}")
+            }
+            ("bootable" | "primary", v) => anyhow::bail!("`{key}` must be a boolean, not {v}"),
+            ("attributes" | "subvolumes", v) => {
+                anyhow::bail!("`{key}` must be an array, not {v}")
+            }
+            ("content", v) => anyhow::bail!("`content` must be a table, not {v}"),
+            _ => anyhow::bail!("unknown key `{key}`"),
+        }
+    }
+
+    match (part.filesystem, &part.input_dir) {
+        _ if part.image.is_some() && (part.filesystem.is_some() || part.input_dir.is_some()) => {
+            anyhow::bail!("`content` takes the place of `filesystem` and `input`")
+        }
+        (None, Some(_)) => anyhow::bail!("`input` requires a filesystem"),
+        _ => Ok(part),
+    }
+}
+
+/// Subvolume of an entry of `subvolumes`.
+fn subvolume(table: &[(String, Value)], base: &Path) -> anyhow::Result<Subvolume> {
+    let mut path = None;
+    let mut sub = Subvolume {
+        path: PathBuf::new(),
+        input_dir: None,
+        default: false,
+    };
+
+    for (key, value) in table {
+        match (key.as_str(), value) {
+            ("name", Value::Str(s)) => path = Some(PathBuf::from(s.trim_start_matches('/'))),
+            ("input", Value::Str(s)) => sub.input_dir = Some(base.join(s)),
+            ("default", Value::Bool(b)) => sub.default = *b,
+            ("name" | "input", v) => anyhow::bail!("`{key}` must be a string, not {v}"),
+            ("default", v) => anyhow::bail!("`default` must be a boolean, not {v}"),
+            _ => anyhow::bail!("unknown key `{key}`"),
+        }
+    }
+
+    sub.path = path.ok_or_else(|| anyhow::anyhow!("`name` is required"))?;
+    Ok(sub)
+}
+
+/// File of a `[[files]]` table, and the name of the partition it goes into if given.
+fn file(table: &[(String, Value)]) -> anyhow::Result<(Option<String>, ExtraFile)> {
+    let mut partition = None;
+    let mut dest = None;
+    let mut content = None;
+
+    for (key, value) in table {
+        match (key.as_str(), value) {
+            ("partition", Value::Str(s)) => partition = Some(s.clone()),
+            ("path", Value::Str(s)) => dest = Some(s.trim_start_matches('/').into()),
+            ("content", Value::Str(s)) => content = Some(s.as_bytes().into()),
+            ("partition" | "path" | "content", v) => {
+                anyhow::bail!("`{key}` must be a string, not {v}")
+            }
+            _ => anyhow::bail!("unknown key `{key}`"),
+        }
+    }
+
+    match (dest, content) {
+        (Some(dest), Some(content)) => Ok((
+            partition,
+            ExtraFile {
+                dest,
+                source: FileSource::Data(content),
+            },
+        )),
+        (None, _) => anyhow::bail!("`path` is required"),
+        (_, None) => anyhow::bail!("`content` is required"),
+    }
+}
+
+/// Partition of `parts` a file goes into, by name or the only one with a filesystem.
+fn file_partition<'a>(
+    parts: &'a mut [Partition],
+    name: Option<&str>,
+) -> anyhow::Result<&'a mut Partition> {
+    let mut formatted = parts.iter_mut().filter(|p| p.filesystem.is_some());
+    let part = match name {
+        Some(name) => formatted
+            .find(|p| p.name == name)
+            .ok_or_else(|| anyhow::anyhow!("no partition `{name}` with a filesystem"))?,
+        None => match (formatted.next(), formatted.next()) {
+            (Some(part), None) => part,
+            (None, _) => anyhow::bail!("no partition with a filesystem"),
+            (Some(_), Some(_)) => {
+                anyhow::bail!("`partition` is required with several formatted partitions")
+            }
+        },
+    };
+    Ok(part)
+}
+
+/// Read the partitions of a manifest. Input directories are relative to the manifest.
+pub fn load(path: &Path) -> anyhow::Result<Vec<Partition>> {
+    let src = fs::read_to_string(path)
+        .with_context(|| format!("cannot read layout {}", path.display()))?;
+    let root = toml::parse(&src).map_err(|e| anyhow::anyhow!("{}: {e}", path.display()))?;
+    let base = path.parent().unwrap_or(Path::new(""));
+
+    let mut parts = vec![];
+    let mut files = vec![];
+
+    for (key, value) in root {
+        let items = match (key.as_str(), value) {
+            ("partition", Value::Array(items)) => items,
+            ("files", Value::Array(items)) => {
+                for item in items {
+                    let number = files.len() + 1;
+                    let Value::Table(table) = item else {
+                        anyhow::bail!("{}: file {number} is not a table", path.display());
+                    };
+                    let file = file(&table)
+                        .with_context(|| format!("{}: file {number}", path.display()))?;
+                    files.push(file);
+                }
+                continue;
+            }
+            ("partition", _) => {
+                anyhow::bail!(
+                    "{}: partitions must be [[partition]] tables",
+                    path.display()
+                )
+            }
+            ("files", _) => anyhow::bail!("{}: files must be [[files]] tables", path.display()),
+            _ => anyhow::bail!("{}: unknown key `{key}`", path.display()),
+        };
+
+        for item in items {
+            let number = parts.len() + 1;
+            let Value::Table(table) = item else {
+                anyhow::bail!("{}: partition {number} is not a table", path.display());
+            };
+            let part = partition(&table, number, base)
+                .with_context(|| format!("{}: partition {number}", path.display()))?;
+            parts.push(part);
+        }
+    }
+
+    if parts.is_empty() {
+        anyhow::bail!("{}: no [[partition]] tables", path.display());
+    }
+
+    for (number, (name, file)) in files.into_iter().enumerate() {
+        file_partition(&mut parts, name.as_deref())
+            .with_context(|| format!("{}: file {}", path.display(), number + 1))?
+            .files
+            .push(file);
+    }
+
+    // Partitions filled only with [[files]] or subvolumes start out empty
+    for (number, part) in parts.iter().enumerate() {
+        let empty = part.input_dir.is_none() && part.subvolumes.is_empty() && part.files.is_empty();
+        if part.filesystem.is_some() && empty {
+            anyhow::bail!(
+                "{}: partition {}: `input` is required with a filesystem and no [[files]]",
+                path.display(),
+                number + 1
+            );
+        }
+    }
+
+    Ok(parts)
+}
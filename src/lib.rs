@@ -0,0 +1,2688 @@
+//! Creation of disk images from directory trees.
+//!
+//! The `mkimg` binary is a thin front-end over [`run`], parsing a [`Cli`]. Images are described with [`Args`], the
+//! same options the command line takes, or assembled with [`DiskImageBuilder`], and built by
+//! [`ImageBuilder`]. Partitions are described by [`PartitionSpec`], and the filesystems written
+//! from a [`tree::Tree`] implement [`FsWriter`].
+
+use chrono::Utc;
+use clap::{Parser, Subcommand, ValueEnum};
+use fatfs::*;
+use log::*;
+use std::fs::{self, File, Metadata, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+mod add_partition;
+mod blockdev;
+mod bmap;
+pub mod btrfs;
+mod build_info;
+pub mod builder;
+pub mod cargo;
+mod checksum;
+pub mod compress;
+mod convert_table;
+pub mod cpio;
+pub mod cramfs;
+mod disk;
+pub mod erofs;
+pub mod exfat;
+pub mod ext2;
+mod extract;
+pub mod f2fs;
+mod fat;
+mod fat_resize;
+mod files_from;
+mod grub;
+pub mod hfsplus;
+mod hook;
+mod image;
+pub mod iso9660;
+mod isohybrid;
+pub mod jffs2;
+mod json;
+pub mod layout;
+mod list;
+pub mod littlefs;
+mod output;
+mod part_type;
+mod partmap;
+mod progress;
+mod qcow2;
+mod random;
+mod resize;
+mod rm;
+pub mod romfs;
+mod sha256;
+mod shrink;
+mod size;
+mod sparse;
+mod split;
+mod split_partitions;
+pub mod squashfs;
+mod sync;
+mod syslinux;
+mod systemd_boot;
+mod table;
+pub mod tar;
+mod template;
+mod toml;
+pub mod tree;
+pub mod ubifs;
+pub mod udf;
+mod validate;
+mod vdi;
+mod verify;
+mod vfat;
+mod vhd;
+mod vhdx;
+mod vmdk;
+mod wks;
+mod wrap;
+pub mod xfs;
+mod zip;
+
+use size::PartitionSize;
+
+pub use builder::DiskImageBuilder;
+pub use layout::Partition as PartitionSpec;
+
+// Without a subcommand, the options of `create` are taken at the top level, as they were before
+// there were subcommands
+#[derive(Parser, Debug)]
+#[command(author, version, about, long_about = None)]
+#[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
+pub struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+    #[command(flatten)]
+    create: Args,
+}
+
+#[derive(Parser, Debug)]
+#[command(author, version, about, long_about = None)]
+pub struct Args {
+    /// Directory root to convert to an image
+    #[arg(
+        short,
+        long,
+        required_unless_present_any = [
+            "no_filesystem", "layout", "from_wks", "partition", "input_image", "input_tar",
+            "input_zip", "btrfs_subvolume"
+        ]
+    )]
+    input_dir: Option<PathBuf>,
+    /// Take the contents from a FAT partition of another image instead of a directory, as
+    /// `IMAGE[:PARTITION]`. Defaults to the first partition
+    #[arg(long, value_name = "IMAGE[:PARTITION]", conflicts_with_all = ["input_dir", "layout", "partition"])]
+    input_image: Option<InputImage>,
+    /// Take the contents from a tar archive instead of a directory, keeping the modes, owners,
+    /// times and symbolic links recorded in it. `.gz`, `.zst` and `.xz` archives are decompressed
+    /// with their tool, into memory
+    #[arg(
+        long,
+        value_name = "ARCHIVE",
+        conflicts_with_all = ["input_dir", "input_image", "layout", "partition"]
+    )]
+    input_tar: Option<PathBuf>,
+    /// Take the contents from a zip archive instead of a directory, keeping the times of its
+    /// entries
+    #[arg(
+        long,
+        value_name = "ARCHIVE",
+        conflicts_with_all = ["input_dir", "input_image", "input_tar", "layout", "partition"]
+    )]
+    input_zip: Option<PathBuf>,
+    /// Only put the files of the input directory named in this NUL separated list in the image,
+    /// with paths relative to the directory. `-` reads the list from standard input
+    #[arg(
+        long,
+        value_name = "LIST",
+        requires = "input_dir",
+        conflicts_with_all = ["input_image", "input_tar", "input_zip"]
+    )]
+    files_from: Option<PathBuf>,
+    /// Partition table to use. Image size may be extended to fit it
+    #[arg(value_enum, short, long, default_value = "none")]
+    partition_table: PartitionTable,
+    /// Build the partitions described by a TOML manifest, instead of a single one from the input
+    /// directory
+    #[arg(
+        long,
+        value_name = "FILE",
+        conflicts_with_all = [
+            "input_dir", "filesystem", "no_filesystem", "size", "gpt_type", "part_label",
+            "part_uuid", "mbr_type", "bootable", "gpt_attribute", "label"
+        ]
+    )]
+    layout: Option<PathBuf>,
+    /// Build the partitions of a Yocto wic kickstart file, with the partition table of its
+    /// `bootloader --ptable` line, or MBR
+    #[arg(
+        long,
+        value_name = "FILE",
+        conflicts_with_all = [
+            "layout", "partition", "input_dir", "filesystem", "no_filesystem", "size", "gpt_type",
+            "part_label", "part_uuid", "mbr_type", "bootable", "gpt_attribute", "label"
+        ]
+    )]
+    from_wks: Option<PathBuf>,
+    /// Directory of the `rootfs` source of --from-wks, or `NAME=DIR` for the `--rootfs-dir NAME`
+    /// of a partition or another source plugin. May be repeated
+    #[arg(long, value_name = "[NAME=]DIR", requires = "from_wks", value_parser = parse_rootfs_dir)]
+    rootfs_dir: Vec<(String, PathBuf)>,
+    /// Add a partition, as `NAME:FILESYSTEM:SIZE:DIR`, instead of a single one from the input
+    /// directory. `FILESYSTEM` may be `none` and `SIZE` may be `auto`. May be repeated
+    #[arg(
+        long,
+        value_name = "NAME:FS:SIZE:DIR",
+        conflicts_with_all = [
+            "layout", "input_dir", "filesystem", "no_filesystem", "size", "gpt_type", "part_label",
+            "part_uuid", "mbr_type", "bootable", "gpt_attribute", "label"
+        ]
+    )]
+    partition: Vec<layout::Partition>,
+    /// Filesystem for the image
+    #[arg(value_enum, short, long, default_value = "vfat")]
+    filesystem: Filesystem,
+    /// FAT variant of vfat images. An explicit type picks the cluster size to match, and the
+    /// estimated size grows to the minimum of FAT16 and FAT32 if needed
+    #[arg(value_enum, long, default_value = "auto")]
+    fat_type: fat::FatType,
+    /// Compressor of squashfs images [default: gzip], archives [default: none] and qcow2 output
+    /// [default: none], which only supports gzip. erofs images are not compressed. The built-in
+    /// gzip and lz4 favour speed over ratio, gzip only using fixed Huffman codes, while zstd and
+    /// xz run the `zstd` and `xz` tools
+    #[arg(value_enum, long)]
+    compression: Option<compress::Compression>,
+    /// Effort spent on --compression, from 1 (fastest) to 9 (smallest), or 19 for zstd
+    /// [default: 6]
+    #[arg(long, value_name = "LEVEL", value_parser = clap::value_parser!(u8).range(1..=19))]
+    compression_level: Option<u8>,
+    /// Size of squashfs data blocks, a power of two from 4K to 1M [default: 128K]. Larger blocks
+    /// compress better, smaller ones are faster to read at random
+    #[arg(long, value_name = "SIZE", value_parser = parse_squashfs_block_size)]
+    squashfs_block_size: Option<u32>,
+    /// Turn features of ext2 and ext3 images on, or off with a `^` prefix, as a comma separated
+    /// list like `mke2fs -O`: `has_journal`, `huge_file`, `metadata_csum` and `64bit`. Drivers
+    /// such as those of older bootloaders refuse filesystems with features they do not know
+    #[arg(
+        long,
+        value_name = "[^]FEATURE,...",
+        value_delimiter = ',',
+        value_parser = ext2::parse_feature
+    )]
+    ext_features: Vec<(ext2::Feature, bool)>,
+    /// Size of ext2 and ext3 inodes: 128, 256, 512 or 1024 bytes [default: 128]. Inodes of more
+    /// than 128 bytes also record the creation time
+    #[arg(long, value_name = "BYTES", value_parser = parse_ext_inode_size)]
+    ext_inode_size: Option<u32>,
+    /// Bytes of ext2 and ext3 filesystem for each inode [default: 16K]. Smaller ratios leave room
+    /// for more files to be added later
+    #[arg(long, value_name = "BYTES", value_parser = parse_ext_bytes_per_inode)]
+    ext_bytes_per_inode: Option<u64>,
+    /// Make a btrfs subvolume at PATH of the image, filled from DIR or left empty, such as
+    /// `@home=./home`. The input directory, if given, fills the top level. May be repeated
+    #[arg(
+        long,
+        value_name = "PATH[=DIR]",
+        value_parser = parse_subvolume,
+        conflicts_with_all = ["layout", "partition", "from_wks"]
+    )]
+    btrfs_subvolume: Vec<layout::Subvolume>,
+    /// Subvolume of --btrfs-subvolume mounted when none is asked for, instead of the top level
+    #[arg(long, value_name = "PATH", requires = "btrfs_subvolume")]
+    btrfs_default_subvolume: Option<PathBuf>,
+    /// Block size of littlefs images, the erase block size of the flash, a power of two of at
+    /// least 128 bytes [default: 4K]. The block count follows from --size
+    #[arg(long, value_name = "SIZE", value_parser = parse_littlefs_block_size)]
+    littlefs_block_size: Option<u32>,
+    /// Smallest unit littlefs images are programmed in, which commits are padded to [default: 16].
+    /// Devices programming in larger units rewrite the metadata before their first write
+    #[arg(long, value_name = "SIZE", value_parser = parse_littlefs_prog_size)]
+    littlefs_prog_size: Option<u32>,
+    /// Physical erase block size of the NAND flash of UBIFS images [default: 128K]. UBI takes
+    /// its headers from the start of every block, leaving the logical erase blocks of UBIFS
+    #[arg(long, value_name = "SIZE", value_parser = parse_ubi_peb_size)]
+    ubi_peb_size: Option<u32>,
+    /// Smallest unit the flash of UBIFS images is written in, the NAND page size, or 1 for NOR
+    /// flash [default: 2048]
+    #[arg(long, value_name = "SIZE", value_parser = parse_ubi_io_size)]
+    ubi_min_io_size: Option<u32>,
+    /// Sub-page size of the NAND flash of UBIFS images, which the UBI headers are written in
+    /// [default: --ubi-min-io-size]
+    #[arg(long, value_name = "SIZE", value_parser = parse_ubi_io_size)]
+    ubi_sub_page_size: Option<u32>,
+    /// Number of the UBIFS volume in the UBI volume table [default: 0]. --label names it,
+    /// `rootfs` otherwise
+    #[arg(long, value_name = "ID")]
+    ubi_volume_id: Option<u32>,
+    /// Logical block size of UDF images: 512, 1024, 2048 or 4096 bytes. It should be the sector
+    /// size of the medium, 2048 for optical discs [default: --sector-size]
+    #[arg(long, value_name = "BYTES", value_parser = parse_udf_block_size)]
+    udf_block_size: Option<u32>,
+    /// Directory of HFS+ images that Macs boot from, as the bless tool sets it. In it, `BootX`
+    /// and the `System` and `Finder` of Mac OS 9 are given the Finder types they boot by
+    #[arg(long, value_name = "PATH")]
+    hfsplus_bless: Option<PathBuf>,
+    /// Only write the partition table, leaving the partition unformatted. An existing output
+    /// file or device is updated in place, and its size is used unless `--image-size` is set
+    #[arg(
+        long,
+        conflicts_with_all = [
+            "input_dir", "input_image", "input_tar", "input_zip", "label", "build_info", "aliases"
+        ]
+    )]
+    no_filesystem: bool,
+    /// Format and fill partition N of the existing output image, made by another tool, instead
+    /// of writing a partition table. The partition table and the rest of the image are left as
+    /// they are
+    #[arg(
+        long,
+        value_name = "N",
+        conflicts_with_all = [
+            "layout", "partition", "no_filesystem", "partition_table", "size", "image_size",
+            "expand_last", "growable", "first_partition_offset", "mbr_bootcode", "bootloader",
+            "raw_writes", "preset", "isohybrid", "pad_to_erase_block", "fill_byte"
+        ]
+    )]
+    into_partition: Option<usize>,
+    /// Output image path. May contain placeholders such as `{date}`, `{git-short}` or `{env:NAME}`
+    #[arg(short, long, required = true)]
+    output_path: Option<PathBuf>,
+    /// Set partition size, in bytes (`64M`), as a percentage of the image size (`30%`) or
+    /// everything that remains of it (`rest`). If not set, is estimated automatically
+    #[arg(short, long)]
+    size: Option<PartitionSize>,
+    /// Set total image size. Required for relative partition sizes
+    #[arg(long, value_parser = size::parse_bytes)]
+    image_size: Option<u64>,
+    /// Let the last partition absorb all space left in the image after the others and alignment
+    #[arg(long, requires = "image_size", conflicts_with = "size")]
+    expand_last: bool,
+    /// Lay the image out for growing on first boot: the partition ends right before the backup
+    /// GPT, without any padding, so growpart/resizefs can extend it to the device size
+    #[arg(long, conflicts_with = "image_size")]
+    growable: bool,
+    /// Compact the FAT filesystem of the last partition once it is written, and cut the
+    /// partition and the image down to its contents and SLACK bytes of free space
+    #[arg(
+        long,
+        value_name = "SLACK",
+        num_args = 0..=1,
+        default_missing_value = shrink::DEFAULT_SLACK,
+        value_parser = size::parse_bytes,
+        conflicts_with_all = ["image_size", "growable", "gpt_backup_at", "no_filesystem", "into_partition"]
+    )]
+    minimize: Option<u64>,
+    /// Where the backup GPT header goes: `end-of-medium` writes it at the end of the image, while
+    /// a size places it at the end of a medium of that size, leaving it out of the output file
+    #[arg(
+        long,
+        value_name = "SIZE|end-of-medium",
+        default_value = "end-of-medium"
+    )]
+    gpt_backup_at: GptBackupAt,
+    /// GPT partition type, as a type GUID or a Discoverable Partitions Specification alias: `esp`,
+    /// `xbootldr`, `swap`, `home`, `srv`, `var`, `tmp`, `generic`, `root[-ARCH]`, `usr[-ARCH]`,
+    /// `bios-boot` for GRUB or `hfsplus` for Macs. Aliases may have a `linux-` prefix, and
+    /// `linux-fs` is `generic`
+    #[arg(long, value_name = "GUID|ALIAS", default_value = "esp")]
+    gpt_type: part_type::GptType,
+    /// Name of the GPT partition
+    #[arg(long, value_name = "NAME", default_value = "EFI")]
+    part_label: String,
+    /// Unique GUID of the GPT partition, as referenced by `root=PARTUUID=`. Random if not set
+    #[arg(long, value_name = "GUID", value_parser = part_type::parse_guid)]
+    part_uuid: Option<String>,
+    /// MBR system ID of the partition, as a hexadecimal byte or `fat12`, `fat16`, `fat16-lba`,
+    /// `fat32`, `fat32-lba`, `ntfs`, `exfat`, `linux`, `linux-swap`, `linux-lvm`, `linux-raid`,
+    /// `hfsplus` or `esp`
+    #[arg(long, value_name = "ID|ALIAS", default_value = "esp", value_parser = part_type::parse_mbr_type)]
+    mbr_type: u8,
+    /// Architecture for `root` and `usr` partition types. Defaults to the host architecture
+    #[arg(value_enum, long)]
+    arch: Option<part_type::Arch>,
+    /// Whether image should be bootable. Sets the MBR active flag, or the legacy BIOS bootable
+    /// attribute on GPT
+    #[arg(short, long)]
+    bootable: bool,
+    /// Set a GPT attribute of the partition: `platform-required`, `no-block-io`,
+    /// `legacy-bios-bootable`, `read-only`, `hidden`, `no-automount` or a bit number. May be
+    /// repeated
+    #[arg(long, value_name = "ATTR", value_parser = part_type::parse_attribute)]
+    gpt_attribute: Vec<u64>,
+    /// Align the start of partitions to a multiple of this size, in bytes. Must be a multiple of
+    /// the sector size
+    #[arg(long, value_name = "SIZE", default_value = "1M", value_parser = size::parse_bytes)]
+    align: u64,
+    /// Start the first partition at this offset, or the next alignment boundary after it, leaving
+    /// the space before it for a bootloader written separately
+    #[arg(long, value_name = "SIZE", value_parser = size::parse_bytes)]
+    first_partition_offset: Option<u64>,
+    /// Copy boot code from this file into the first 440 bytes of the MBR, or of the protective MBR
+    /// of a GPT. A whole 512 byte boot sector may be given, of which only the code is used
+    #[arg(long, value_name = "FILE")]
+    mbr_bootcode: Option<PathBuf>,
+    /// Install a BIOS bootloader and its boot code in the MBR. syslinux goes into the bootable FAT
+    /// partition, or the first one, which is marked bootable. Its MBR code is left out if
+    /// --mbr-bootcode is given
+    #[arg(
+        value_enum,
+        long,
+        requires = "bootloader_dir",
+        conflicts_with = "no_filesystem"
+    )]
+    bootloader: Option<Bootloader>,
+    /// Directory with the bootloader files. For syslinux: `ldlinux.sys`, `ldlinux.bss`, `mbr.bin`
+    /// or `gptmbr.bin` for GPT, and `ldlinux.c32` with syslinux 5 and later. For grub-bios:
+    /// `boot.img` and `core.img`, which is embedded after the MBR or in the `bios-boot` partition
+    #[arg(long, value_name = "DIR", requires = "bootloader")]
+    bootloader_dir: Option<PathBuf>,
+    /// Write a file at a byte offset of the image, as `FILE@OFFSET`, such as U-Boot SPL at `32K`.
+    /// It may not overlap the partition table or partitions. May be repeated
+    #[arg(long = "raw-write", value_name = "FILE@OFFSET", value_parser = parse_raw_write)]
+    raw_writes: Vec<RawWrite>,
+    /// Set up the ESP for a UEFI boot loader. It is the `esp` FAT partition, or the first FAT one,
+    /// which becomes a bootable ESP
+    #[arg(value_enum, long, conflicts_with = "no_filesystem")]
+    preset: Option<Preset>,
+    /// Add an XBOOTLDR partition after the ESP, formatted as FAT and populated from this
+    /// directory. It takes the --kernel and its boot entry, for ESPs too small to hold kernels
+    #[arg(
+        long,
+        value_name = "DIR",
+        requires = "preset",
+        conflicts_with_all = ["layout", "partition", "from_wks"]
+    )]
+    xbootldr_dir: Option<PathBuf>,
+    /// Copy a kernel into the root of the ESP, or of the `xbootldr` FAT partition if there is
+    /// one, with a boot entry for it
+    #[arg(long, value_name = "FILE", requires = "preset")]
+    kernel: Option<PathBuf>,
+    /// Copy an initrd next to the --kernel, for its boot entry
+    #[arg(long, value_name = "FILE", requires = "kernel")]
+    initrd: Option<PathBuf>,
+    /// Kernel command line of the --kernel boot entry. May contain the same placeholders as the
+    /// output path
+    #[arg(long, value_name = "ARGS", requires = "kernel")]
+    kernel_cmdline: Option<String>,
+    /// Title of the --kernel boot entry [default: Linux]. May contain the same placeholders as the
+    /// output path
+    #[arg(long, value_name = "TITLE", requires = "kernel")]
+    kernel_title: Option<String>,
+    /// Logical sector size of the target device, 512 or 4096 bytes. Partition tables and FAT
+    /// filesystems are laid out in sectors of this size
+    #[arg(long, value_name = "BYTES", default_value = "512", value_parser = parse_sector_size)]
+    sector_size: u64,
+    /// MBR disk signature as hexadecimal, such as `0x1234abcd`, or `random`
+    #[arg(long, value_name = "HEX|random", default_value = "random")]
+    disk_id: DiskId,
+    /// Follow symlinks of the input directory, instead of storing them as links, or leaving them
+    /// out of filesystems without links
+    #[arg(short, long)]
+    link_follow: bool,
+    /// Copy directory entries in the order the host lists them instead of sorted by name. Image
+    /// layouts then depend on the host filesystem
+    #[arg(long)]
+    unsorted: bool,
+    /// Place a host file at an additional path in the image, as `SRC=DEST`. On ext2 and ext3 it is
+    /// a hard link to the file, elsewhere a copy. May be repeated
+    #[arg(long = "alias", value_name = "SRC=DEST", value_parser = parse_alias)]
+    aliases: Vec<Alias>,
+    /// Volume label of the filesystem. May contain the same placeholders as the output path
+    #[arg(long)]
+    label: Option<String>,
+    /// Build time, in seconds since the epoch. Stamped on FAT files, filesystem and image
+    /// headers and build info, and used by the `{date}` and `{time}` placeholders. Defaults to
+    /// `SOURCE_DATE_EPOCH` if it is set, and the current time otherwise
+    #[arg(long, value_name = "SECONDS")]
+    timestamp: Option<i64>,
+    /// Derive volume serials, disk signatures and GUIDs from SEED, or from a hash of the
+    /// configuration and input without one, so that repeated builds are byte-identical
+    #[arg(long, value_name = "SEED", num_args = 0..=1, default_missing_value = "")]
+    deterministic: Option<String>,
+    /// Write a generated file with build time, mkimg version and config/input hashes at this
+    /// path inside the image
+    #[arg(long, value_name = "PATH")]
+    build_info: Option<PathBuf>,
+    /// Make iso9660 images bootable from BIOS with this no-emulation boot image, such as
+    /// `isolinux.bin`, given by its path inside the image. A boot info table is filled in
+    #[arg(long, value_name = "PATH")]
+    eltorito_bios: Option<PathBuf>,
+    /// Make iso9660 images bootable from UEFI with this FAT image holding the ESP, given by its
+    /// path inside the image. May be repeated, such as for the ESPs of several architectures,
+    /// which firmware picks from in order
+    #[arg(long, value_name = "PATH")]
+    eltorito_efi: Vec<PathBuf>,
+    /// Volume set identifier of iso9660 images. The volume identifier is the --label
+    #[arg(long, value_name = "ID")]
+    iso_volume_set: Option<String>,
+    /// Publisher identifier of iso9660 images
+    #[arg(long, value_name = "ID")]
+    iso_publisher: Option<String>,
+    /// Data preparer identifier of iso9660 images
+    #[arg(long, value_name = "ID")]
+    iso_preparer: Option<String>,
+    /// Application identifier of iso9660 images [default: MKIMG]
+    #[arg(long, value_name = "ID")]
+    iso_application: Option<String>,
+    /// Write an MBR over iso9660 images, as isohybrid does, so that they also boot when written
+    /// to a USB stick. The EFI boot image gets a partition of its own, and --mbr-bootcode, such
+    /// as `isohdpfx.bin` from syslinux, is pointed at the BIOS boot image
+    #[arg(long)]
+    isohybrid: bool,
+    /// Pad the image to a multiple of this size, such as the erase block size of NOR flash. Also
+    /// the erase block size JFFS2 images are laid out for [default: 64K]
+    #[arg(long, value_name = "SIZE", value_parser = size::parse_bytes)]
+    pad_to_erase_block: Option<u64>,
+    /// Fill space not written by the partition table or filesystem with a byte value or
+    /// `random` data. If not set, that space is left sparse and reads as zeroes
+    #[arg(long, alias = "fill", value_name = "BYTE|random")]
+    fill_byte: Option<Fill>,
+    /// Store a digest of the image, as `crc32|sha256[,OFFSET]`. Appended to the image unless an
+    /// offset is given, in which case the digest field is zeroed while computing it
+    #[arg(long, value_name = "ALGO[,OFFSET]")]
+    append_checksum: Option<checksum::Checksum>,
+    /// Write a bmaptool block map next to the output, at `<OUTPUT_PATH>.bmap`
+    #[arg(long)]
+    bmap: bool,
+    /// Write a partition map for flashing scripts next to the output, at
+    /// `<OUTPUT_PATH>.partmap.json`, `.uboot.txt` or `.fastboot.sh`. May be repeated
+    #[arg(value_enum, long, value_name = "FORMAT")]
+    emit_partmap: Vec<partmap::Format>,
+    /// Format of the output file
+    #[arg(value_enum, long, default_value = "raw")]
+    output_format: output::OutputFormat,
+    /// Allocation of vhd, vhdx, vmdk and vdi output [default: dynamic]
+    #[arg(value_enum, long)]
+    subformat: Option<output::Subformat>,
+    /// Compress the output file with `gzip`, `zstd` or `xz`, at an optional level such as
+    /// `zstd:19`. The program of that name must be installed. The output path should carry the
+    /// suffix of the compressor. Archives, and Intel HEX, S-record and UF2 output, are piped into
+    /// the program as they are written. Other images are written at random offsets, so they are
+    /// built whole beside the output first, in a temporary file of the full uncompressed size
+    #[arg(long, value_name = "COMPRESSOR[:LEVEL]")]
+    compress: Option<output::Compress>,
+    /// Threads to run --compress on, 0 for one per CPU. gzip output is only compressed in
+    /// parallel if `pigz` is installed
+    #[arg(long, value_name = "N", default_value = "0", requires = "compress")]
+    compress_threads: usize,
+    /// Write the output in pieces of at most SIZE bytes, at `<OUTPUT_PATH>.000`, `.001`, …,
+    /// which `mkimg join` puts back together
+    #[arg(long, value_name = "SIZE", value_parser = size::parse_bytes)]
+    split: Option<u64>,
+    /// Address of the first image byte, for formats targeting flash programmers
+    #[arg(long, value_parser = size::parse_bytes, default_value = "0")]
+    base_address: u64,
+    /// UF2 family ID, as hexadecimal or a name such as `rp2040`
+    #[arg(long, value_parser = output::parse_family_id)]
+    family_id: Option<u32>,
+    /// Allow overwriting the block device given as the output path. Mounted devices are refused
+    /// regardless
+    #[arg(long)]
+    yes_i_know: bool,
+    /// Shell command to run before the image is built
+    #[arg(long, value_name = "CMD")]
+    pre_hook: Option<String>,
+    /// Shell command to run after the image is built, before it is moved to the output path
+    #[arg(long, value_name = "CMD")]
+    post_hook: Option<String>,
+    /// Emit newline-delimited JSON progress events to `fd://N`, `unix://PATH` or a file
+    #[arg(long, value_name = "TARGET")]
+    progress_json: Option<progress::Target>,
+    /// Print `cargo:rerun-if-changed` lines for all inputs, for use from a build script
+    #[arg(long)]
+    cargo_rerun_if_changed: bool,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Create an image from a directory, as when no subcommand is given
+    Create(Box<Args>),
+    /// Compare the contents of an existing image against a directory
+    #[command(visible_alias = "verify")]
+    VerifyContent(verify::VerifyArgs),
+    /// Update an existing image to match a directory, only writing what changed
+    #[command(visible_alias = "update")]
+    Sync(sync::SyncArgs),
+    /// Unpack a partition of an existing image into a directory
+    Extract(extract::ExtractArgs),
+    /// List the files in a partition of an existing image
+    List(list::ListArgs),
+    /// Remove files and directories from an existing image
+    Rm(rm::RmArgs),
+    /// Append a partition to an existing image, formatting and filling it
+    AddPartition(add_partition::AddPartitionArgs),
+    /// Grow an existing image, along with its last partition and FAT filesystem
+    Resize(resize::ResizeArgs),
+    /// Shrink an existing image to the contents of the FAT filesystem in its last partition
+    Shrink(shrink::ShrinkArgs),
+    /// Convert the partition table of an existing image between MBR and GPT, keeping the
+    /// partitions where they are
+    ConvertTable(convert_table::ConvertTableArgs),
+    /// Wrap a filesystem image without a partition table into a partitioned disk image
+    Wrap(wrap::WrapArgs),
+    /// Reassemble an image written with --split
+    Join(split::JoinArgs),
+    /// Write each partition of an image to its own file, along with a layout manifest
+    SplitPartitions(split_partitions::SplitPartitionsArgs),
+}
+
+impl Args {
+    fn input_dir(&self) -> &Path {
+        self.input_dir
+            .as_deref()
+            .expect("input directory is required")
+    }
+
+    /// Partitions to build, from the layout manifest, `--partition` or the single partition
+    /// options. Labels of the layout and `--partition` are expanded at `time`, as --label is.
+    fn partitions(&self, time: &chrono::DateTime<Utc>) -> anyhow::Result<Vec<layout::Partition>> {
+        let expand = |mut parts: Vec<layout::Partition>| {
+            for part in &mut parts {
+                if let Some(label) = &part.label {
+                    part.label = Some(template::expand(label, time)?);
+                }
+            }
+            anyhow::Ok(parts)
+        };
+
+        if let Some(path) = &self.layout {
+            return expand(layout::load(path)?);
+        }
+
+        if !self.partition.is_empty() {
+            return expand(self.partition.clone());
+        }
+
+        let mut parts = vec![layout::Partition {
+            name: self.part_label.clone(),
+            filesystem: (!self.no_filesystem).then_some(self.filesystem),
+            size: self.size,
+            gpt_type: self.gpt_type.clone(),
+            uuid: self.part_uuid.clone(),
+            mbr_type: Some(self.mbr_type),
+            primary: None,
+            bootable: self.bootable,
+            attributes: self.gpt_attribute.iter().fold(0, |a, b| a | b),
+            input_dir: self.input_dir.clone(),
+            image: None,
+            label: self.label.clone(),
+            files: vec![],
+            subvolumes: self
+                .btrfs_subvolume
+                .iter()
+                .map(|s| layout::Subvolume {
+                    default: self.btrfs_default_subvolume.as_ref() == Some(&s.path),
+                    ..s.clone()
+                })
+                .collect(),
+        }];
+
+        if let Some(dir) = &self.xbootldr_dir {
+            parts.push(layout::Partition {
+                name: "xbootldr".into(),
+                filesystem: Some(Filesystem::Vfat),
+                size: None,
+                gpt_type: "xbootldr".parse().unwrap(),
+                uuid: None,
+                mbr_type: None,
+                primary: None,
+                bootable: false,
+                attributes: 0,
+                input_dir: Some(dir.clone()),
+                image: None,
+                label: None,
+                files: vec![],
+                subvolumes: vec![],
+            });
+        }
+
+        Ok(parts)
+    }
+
+    fn output_path(&self) -> &Path {
+        self.output_path
+            .as_deref()
+            .expect("output path is required")
+    }
+
+    fn jffs2_options(&self) -> jffs2::Options {
+        jffs2::Options {
+            erase_block: self
+                .pad_to_erase_block
+                .unwrap_or(jffs2::Options::default().erase_block),
+        }
+    }
+
+    fn littlefs_options(&self) -> littlefs::Options {
+        let default = littlefs::Options::default();
+        littlefs::Options {
+            block_size: self.littlefs_block_size.unwrap_or(default.block_size),
+            prog_size: self.littlefs_prog_size.unwrap_or(default.prog_size),
+        }
+    }
+
+    fn ubifs_options(&self, label: Option<String>) -> ubifs::Options {
+        let default = ubifs::Options::default();
+        let min_io_size = self.ubi_min_io_size.unwrap_or(default.min_io_size);
+        ubifs::Options {
+            label,
+            peb_size: self.ubi_peb_size.unwrap_or(default.peb_size),
+            min_io_size,
+            sub_page_size: self.ubi_sub_page_size.unwrap_or(min_io_size),
+            volume_id: self.ubi_volume_id.unwrap_or(default.volume_id),
+        }
+    }
+
+    fn iso9660_options(&self, part: &layout::Partition) -> iso9660::Options {
+        iso9660::Options {
+            label: part.label.clone(),
+            eltorito_bios: self.eltorito_bios.clone(),
+            eltorito_efi: self.eltorito_efi.clone(),
+            volume_set: self.iso_volume_set.clone(),
+            publisher: self.iso_publisher.clone(),
+            preparer: self.iso_preparer.clone(),
+            application: self.iso_application.clone(),
+        }
+    }
+
+    /// Cluster size of vfat images without an explicit FAT type, at least a sector.
+    fn fat_cluster_size(&self) -> u64 {
+        (FAT_BYTES_PER_CLUSTER as u64).max(self.sector_size)
+    }
+
+    fn squashfs_options(&self) -> squashfs::Options {
+        squashfs::Options {
+            compression: self.compression.unwrap_or(compress::Compression::Gzip),
+            level: self.compression_level(),
+            block_size: self.squashfs_block_size.unwrap_or(128 << 10),
+        }
+    }
+
+    fn ext2_options(&self, fs: Filesystem, label: Option<String>) -> ext2::Options {
+        let mut opts = ext2::Options {
+            journal: matches!(fs, Filesystem::Ext3),
+            inode_size: self
+                .ext_inode_size
+                .unwrap_or(ext2::Options::default().inode_size),
+            bytes_per_inode: self
+                .ext_bytes_per_inode
+                .unwrap_or(ext2::Options::default().bytes_per_inode),
+            label,
+            ..Default::default()
+        };
+        for &(feature, on) in &self.ext_features {
+            opts.set(feature, on);
+        }
+        opts
+    }
+
+    fn compression_level(&self) -> u8 {
+        self.compression_level.unwrap_or(compress::DEFAULT_LEVEL)
+    }
+
+    /// Whether the output is written straight into the target, which may be a device: partition
+    /// tables alone, and partitions of existing images.
+    fn in_place(&self) -> bool {
+        self.no_filesystem || self.into_partition.is_some()
+    }
+
+    /// Whether the archive is piped into `--compress` as it is written, which takes nothing to be
+    /// done to the uncompressed image afterwards.
+    fn streams_archive(&self) -> bool {
+        self.compress.is_some()
+            && self.compression.is_none()
+            && self.filesystem.is_archive()
+            && !self.output_format.needs_conversion()
+            && self.minimize.is_none()
+            && self.append_checksum.is_none()
+            && !self.bmap
+    }
+
+    /// Round an image size up to the erase block size.
+    fn pad(&self, size: u64) -> u64 {
+        match self.pad_to_erase_block {
+            Some(block) if block > 0 => size.div_ceil(block) * block,
+            _ => size,
+        }
+    }
+
+    /// Host files and directories the image is built from, given the partitions to build.
+    fn input_paths<'a>(&'a self, parts: &'a [layout::Partition]) -> Vec<&'a Path> {
+        let stdin = Path::new("-");
+        self.layout
+            .iter()
+            .chain(&self.from_wks)
+            .chain(&self.input_tar)
+            .chain(&self.input_zip)
+            .chain(self.input_image.as_ref().map(|i| &i.path))
+            .chain(self.files_from.iter().filter(|p| *p != stdin))
+            .chain(
+                parts
+                    .iter()
+                    .flat_map(|p| p.input_dir.iter().chain(&p.image))
+                    .chain(
+                        parts
+                            .iter()
+                            .flat_map(|p| p.subvolumes.iter().flat_map(|s| &s.input_dir)),
+                    ),
+            )
+            .chain(self.aliases.iter().map(|a| &a.src))
+            .chain(&self.mbr_bootcode)
+            .chain(&self.bootloader_dir)
+            .chain(self.kernel.iter().chain(&self.initrd))
+            .chain(self.raw_writes.iter().map(|r| &r.path))
+            .map(PathBuf::as_path)
+            .collect()
+    }
+
+    /// Resolve template placeholders in all arguments that accept them.
+    fn expand_templates(&mut self, time: &chrono::DateTime<Utc>) -> anyhow::Result<()> {
+        if let Some(path) = self.output_path().to_str() {
+            self.output_path = Some(template::expand(path, time)?.into());
+        }
+
+        if let Some(label) = &self.label {
+            self.label = Some(template::expand(label, time)?);
+        }
+
+        if let Some(cmdline) = &self.kernel_cmdline {
+            self.kernel_cmdline = Some(template::expand(cmdline, time)?);
+        }
+
+        if let Some(title) = &self.kernel_title {
+            self.kernel_title = Some(template::expand(title, time)?);
+        }
+
+        Ok(())
+    }
+
+    /// Describe the requested build for hooks.
+    fn to_json(&self) -> json::Value {
+        self.config_json()
+            .with("output_path", self.output_path().display().to_string())
+            .with(
+                "output_format",
+                format!("{:?}", self.output_format).to_lowercase(),
+            )
+            .with("compress", self.compress.map(|c| c.to_string()))
+            .with("split", self.split)
+            .with("minimize", self.minimize)
+            .with(
+                "subformat",
+                self.subformat.map(|s| format!("{s:?}").to_lowercase()),
+            )
+    }
+
+    /// Options that affect image contents, excluding where it gets written.
+    fn config_json(&self) -> json::Value {
+        let aliases = self
+            .aliases
+            .iter()
+            .map(|a| format!("{}={}", a.src.display(), a.dest.display()))
+            .collect::<Vec<_>>();
+        let raw_writes = self
+            .raw_writes
+            .iter()
+            .map(|r| format!("{}@{:#x}", r.path.display(), r.offset))
+            .collect::<Vec<_>>();
+
+        json::Value::object()
+            .with(
+                "input_dir",
+                self.input_dir.as_ref().map(|p| p.display().to_string()),
+            )
+            .with(
+                "input_image",
+                self.input_image.as_ref().map(|i| i.to_string()),
+            )
+            .with(
+                "input_tar",
+                self.input_tar.as_ref().map(|p| p.display().to_string()),
+            )
+            .with(
+                "input_zip",
+                self.input_zip.as_ref().map(|p| p.display().to_string()),
+            )
+            .with(
+                "files_from",
+                self.files_from.as_ref().map(|p| p.display().to_string()),
+            )
+            .with(
+                "layout",
+                self.layout.as_ref().map(|p| p.display().to_string()),
+            )
+            .with(
+                "from_wks",
+                self.from_wks.as_ref().map(|p| p.display().to_string()),
+            )
+            .with(
+                "partition",
+                self.partition
+                    .iter()
+                    .map(|p| p.to_string())
+                    .collect::<Vec<_>>(),
+            )
+            .with(
+                "partition_table",
+                format!("{:?}", self.partition_table).to_lowercase(),
+            )
+            .with(
+                "filesystem",
+                format!("{:?}", self.filesystem).to_lowercase(),
+            )
+            .with(
+                "fat_type",
+                self.fat_type
+                    .to_possible_value()
+                    .map(|v| v.get_name().to_owned()),
+            )
+            .with(
+                "compression",
+                self.compression
+                    .and_then(|c| c.to_possible_value())
+                    .map(|v| v.get_name().to_owned()),
+            )
+            .with("compression_level", self.compression_level.map(u32::from))
+            .with("squashfs_block_size", self.squashfs_block_size)
+            .with("littlefs_block_size", self.littlefs_block_size)
+            .with("littlefs_prog_size", self.littlefs_prog_size)
+            .with("ubi_peb_size", self.ubi_peb_size)
+            .with("ubi_min_io_size", self.ubi_min_io_size)
+            .with("ubi_sub_page_size", self.ubi_sub_page_size)
+            .with("ubi_volume_id", self.ubi_volume_id)
+            .with("udf_block_size", self.udf_block_size)
+            .with(
+                "hfsplus_bless",
+                self.hfsplus_bless.as_ref().map(|p| p.display().to_string()),
+            )
+            .with(
+                "ext_features",
+                self.ext_features
+                    .iter()
+                    .map(|(f, on)| format!("{}{}", if *on { "" } else { "^" }, f.name()))
+                    .collect::<Vec<_>>(),
+            )
+            .with("ext_inode_size", self.ext_inode_size)
+            .with("ext_bytes_per_inode", self.ext_bytes_per_inode)
+            .with(
+                "btrfs_subvolumes",
+                self.btrfs_subvolume
+                    .iter()
+                    .map(|s| match &s.input_dir {
+                        Some(dir) => format!("{}={}", s.path.display(), dir.display()),
+                        None => s.path.display().to_string(),
+                    })
+                    .collect::<Vec<_>>(),
+            )
+            .with(
+                "btrfs_default_subvolume",
+                self.btrfs_default_subvolume
+                    .as_ref()
+                    .map(|p| p.display().to_string()),
+            )
+            .with("no_filesystem", self.no_filesystem)
+            .with("into_partition", self.into_partition)
+            .with("gpt_type", self.gpt_type.to_string())
+            .with("part_label", self.part_label.as_str())
+            .with("part_uuid", self.part_uuid.clone())
+            .with("mbr_type", format!("{:#04x}", self.mbr_type))
+            .with("size", self.size.map(|s| s.to_string()))
+            .with("image_size", self.image_size)
+            .with("expand_last", self.expand_last)
+            .with("growable", self.growable)
+            .with("pad_to_erase_block", self.pad_to_erase_block)
+            .with("fill_byte", self.fill_byte.map(|f| format!("{f:?}")))
+            .with(
+                "append_checksum",
+                self.append_checksum.map(|c| format!("{c:?}")),
+            )
+            .with("gpt_backup_at", format!("{:?}", self.gpt_backup_at))
+            .with("sector_size", self.sector_size)
+            .with("disk_id", format!("{:?}", self.disk_id))
+            .with("unsorted", self.unsorted)
+            .with("align", self.align)
+            .with("first_partition_offset", self.first_partition_offset)
+            .with("bootloader", self.bootloader.map(|b| format!("{b:?}")))
+            .with("raw_writes", raw_writes)
+            .with("preset", self.preset.map(|p| format!("{p:?}")))
+            .with("kernel_cmdline", self.kernel_cmdline.clone())
+            .with("kernel_title", self.kernel_title.clone())
+            .with(
+                "mbr_bootcode",
+                self.mbr_bootcode.as_ref().map(|p| p.display().to_string()),
+            )
+            .with("bootable", self.bootable)
+            .with(
+                "gpt_attributes",
+                format!("{:#x}", self.gpt_attribute.iter().fold(0, |a, b| a | b)),
+            )
+            .with("label", self.label.clone())
+            .with("timestamp", self.timestamp)
+            .with("deterministic", self.deterministic.clone())
+            .with(
+                "eltorito_bios",
+                self.eltorito_bios.as_ref().map(|p| p.display().to_string()),
+            )
+            .with(
+                "eltorito_efi",
+                self.eltorito_efi
+                    .iter()
+                    .map(|p| p.display().to_string())
+                    .collect::<Vec<_>>(),
+            )
+            .with("iso_volume_set", self.iso_volume_set.clone())
+            .with("iso_publisher", self.iso_publisher.clone())
+            .with("iso_preparer", self.iso_preparer.clone())
+            .with("iso_application", self.iso_application.clone())
+            .with("isohybrid", self.isohybrid)
+            .with("aliases", aliases)
+            .with(
+                "build_info",
+                self.build_info.as_ref().map(|p| p.display().to_string()),
+            )
+    }
+}
+
+/// State shared by everything writing one image, resolved once when the build starts.
+#[derive(Debug)]
+pub struct BuildContext {
+    /// Time stamp of generated files and filesystem metadata
+    pub time: chrono::DateTime<Utc>,
+    /// Whether `time` was fixed with `--timestamp` or `SOURCE_DATE_EPOCH` rather than taken from
+    /// the clock
+    pub fixed_time: bool,
+    /// Source of disk IDs, GUIDs, UUIDs and random fill, seeded by `--deterministic`
+    pub(crate) random: random::Random,
+    /// Whether directories are walked by name rather than in listing order, unset by `--unsorted`
+    pub sorted: bool,
+    /// Contents read from an input archive, in place of an input directory
+    pub(crate) input: Option<tree::Tree>,
+}
+
+impl BuildContext {
+    /// Context of a build at `timestamp`, or at `SOURCE_DATE_EPOCH` or the current time without
+    /// one.
+    pub fn new(timestamp: Option<i64>) -> anyhow::Result<Self> {
+        let fixed = template::fixed_time(timestamp)?;
+        Ok(Self {
+            time: fixed.unwrap_or_else(Utc::now),
+            fixed_time: fixed.is_some(),
+            random: random::Random::default(),
+            sorted: true,
+            input: None,
+        })
+    }
+}
+
+/// Facts gathered while building the image.
+#[derive(Default, Debug)]
+pub struct BuildSummary {
+    pub image_size: u64,
+    pub partitions: Vec<partmap::Partition>,
+    pub files: u64,
+    pub dirs: u64,
+}
+
+impl BuildSummary {
+    fn to_json(&self, args: &Args) -> json::Value {
+        let first = self.partitions.first();
+        let partitions = self
+            .partitions
+            .iter()
+            .map(|p| {
+                json::Value::object()
+                    .with("number", p.number)
+                    .with("name", p.name.as_str())
+                    .with("start", p.start)
+                    .with("size", p.len)
+            })
+            .collect::<Vec<_>>();
+
+        args.to_json()
+            .with("image_size", self.image_size)
+            .with("partition_start", first.map(|p| p.start))
+            .with("partition_size", first.map(|p| p.len))
+            .with("partitions", partitions)
+            .with("files", self.files)
+            .with("dirs", self.dirs)
+    }
+}
+
+/// Host file placed at an additional destination inside the image.
+#[derive(Clone, Debug)]
+struct Alias {
+    src: PathBuf,
+    dest: PathBuf,
+}
+
+/// Host file written at a fixed offset of the image, outside of any partition.
+#[derive(Clone, Debug)]
+struct RawWrite {
+    path: PathBuf,
+    offset: u64,
+}
+
+#[derive(Clone, Copy, Debug)]
+enum Fill {
+    Byte(u8),
+    Random,
+}
+
+impl std::str::FromStr for Fill {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        if s == "random" {
+            return Ok(Self::Random);
+        }
+
+        let v = size::parse_bytes(s)?;
+        u8::try_from(v)
+            .map(Self::Byte)
+            .map_err(|_| format!("`{s}` does not fit in a byte"))
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+enum DiskId {
+    Id(u32),
+    Random,
+}
+
+impl DiskId {
+    /// Signature bytes as stored in the MBR, so that `0x1234abcd` is listed as such by fdisk.
+    fn signature(self, random: &random::Random) -> io::Result<[u8; 4]> {
+        match self {
+            Self::Id(id) => Ok(id.to_le_bytes()),
+            Self::Random => {
+                let mut b = [0u8; 4];
+                random.fill(&mut b)?;
+                Ok(b)
+            }
+        }
+    }
+}
+
+impl std::str::FromStr for DiskId {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        if s == "random" {
+            return Ok(Self::Random);
+        }
+
+        let hex = s.strip_prefix("0x").unwrap_or(s);
+        u32::from_str_radix(hex, 16)
+            .map(Self::Id)
+            .map_err(|_| format!("disk ID must be 32-bit hexadecimal or `random`, not `{s}`"))
+    }
+}
+
+fn parse_sector_size(s: &str) -> Result<u64, String> {
+    match size::parse_bytes(s)? {
+        size @ (512 | 4096) => Ok(size),
+        _ => Err(format!("sector size must be 512 or 4096 bytes, not `{s}`")),
+    }
+}
+
+fn parse_squashfs_block_size(s: &str) -> Result<u32, String> {
+    match size::parse_bytes(s)? {
+        size if size.is_power_of_two() && (4 << 10..=1 << 20).contains(&size) => Ok(size as u32),
+        _ => Err(format!(
+            "squashfs blocks are a power of two from 4K to 1M, not `{s}`"
+        )),
+    }
+}
+
+fn parse_littlefs_block_size(s: &str) -> Result<u32, String> {
+    match size::parse_bytes(s)? {
+        size if size.is_power_of_two() && (128..=u32::MAX as u64).contains(&size) => {
+            Ok(size as u32)
+        }
+        _ => Err(format!(
+            "littlefs blocks are a power of two of at least 128 bytes, not `{s}`"
+        )),
+    }
+}
+
+fn parse_littlefs_prog_size(s: &str) -> Result<u32, String> {
+    match size::parse_bytes(s)? {
+        size if size.is_power_of_two() && size <= u32::MAX as u64 => Ok(size as u32),
+        _ => Err(format!(
+            "littlefs program sizes are a power of two, not `{s}`"
+        )),
+    }
+}
+
+fn parse_ubi_peb_size(s: &str) -> Result<u32, String> {
+    match size::parse_bytes(s)? {
+        size if size.is_power_of_two() && (16 << 10..=u32::MAX as u64).contains(&size) => {
+            Ok(size as u32)
+        }
+        _ => Err(format!(
+            "UBI erase blocks are a power of two of at least 16K, not `{s}`"
+        )),
+    }
+}
+
+fn parse_ubi_io_size(s: &str) -> Result<u32, String> {
+    match size::parse_bytes(s)? {
+        size if size.is_power_of_two() && size <= 64 << 10 => Ok(size as u32),
+        _ => Err(format!(
+            "UBI I/O sizes are a power of two up to 64K, not `{s}`"
+        )),
+    }
+}
+
+fn parse_udf_block_size(s: &str) -> Result<u32, String> {
+    match size::parse_bytes(s)? {
+        size @ (512 | 1024 | 2048 | 4096) => Ok(size as u32),
+        _ => Err(format!(
+            "UDF blocks are 512, 1024, 2048 or 4096 bytes, not `{s}`"
+        )),
+    }
+}
+
+fn parse_ext_inode_size(s: &str) -> Result<u32, String> {
+    match size::parse_bytes(s)? {
+        size @ (128 | 256 | 512 | 1024) => Ok(size as u32),
+        _ => Err(format!(
+            "ext inodes are 128, 256, 512 or 1024 bytes, not `{s}`"
+        )),
+    }
+}
+
+fn parse_subvolume(s: &str) -> Result<layout::Subvolume, String> {
+    let (path, dir) = match s.split_once('=') {
+        Some((path, dir)) if !dir.is_empty() => (path, Some(dir.into())),
+        Some(_) => return Err(format!("expected PATH=DIR, got `{s}`")),
+        None => (s, None),
+    };
+    Ok(layout::Subvolume {
+        path: path.trim_start_matches('/').into(),
+        input_dir: dir,
+        default: false,
+    })
+}
+
+fn parse_ext_bytes_per_inode(s: &str) -> Result<u64, String> {
+    match size::parse_bytes(s)? {
+        size if (1 << 10..=64 << 20).contains(&size) => Ok(size),
+        _ => Err(format!(
+            "bytes per inode must be between 1K and 64M, not `{s}`"
+        )),
+    }
+}
+
+fn parse_rootfs_dir(s: &str) -> Result<(String, PathBuf), String> {
+    match s.split_once('=') {
+        Some((name, dir)) if !name.is_empty() && !dir.is_empty() => Ok((name.into(), dir.into())),
+        Some(_) => Err(format!("expected NAME=DIR, got `{s}`")),
+        None => Ok((String::new(), s.into())),
+    }
+}
+
+fn parse_alias(s: &str) -> Result<Alias, String> {
+    let (src, dest) = s
+        .split_once('=')
+        .ok_or_else(|| format!("expected SRC=DEST, got `{s}`"))?;
+
+    let dest = dest.trim_start_matches('/');
+
+    if src.is_empty() || dest.is_empty() {
+        return Err(format!("empty path in alias `{s}`"));
+    }
+
+    Ok(Alias {
+        src: src.into(),
+        dest: dest.into(),
+    })
+}
+
+fn parse_raw_write(s: &str) -> Result<RawWrite, String> {
+    let (path, offset) = s
+        .rsplit_once('@')
+        .ok_or_else(|| format!("expected FILE@OFFSET, got `{s}`"))?;
+
+    if path.is_empty() {
+        return Err(format!("empty path in raw write `{s}`"));
+    }
+
+    Ok(RawWrite {
+        path: path.into(),
+        offset: size::parse_bytes(offset)?,
+    })
+}
+
+/// File placed in the image in addition to the contents of the input directory.
+///
+/// ext2 and ext3 give the destinations of the same source one inode, as hard links. Other
+/// filesystems store a copy of the data for every destination, such as FAT, which has no way to
+/// share clusters between directory entries.
+#[derive(Clone, Debug)]
+pub struct ExtraFile {
+    /// '/' separated path inside the image
+    pub dest: PathBuf,
+    pub source: FileSource,
+}
+
+#[derive(Clone, Debug)]
+pub enum FileSource {
+    Host(PathBuf),
+    /// Generated or read into memory, shared by the trees of every pass over the input
+    Data(Arc<[u8]>),
+    /// `len` bytes at `offset` of a host file, such as an entry of an uncompressed archive
+    Range {
+        path: PathBuf,
+        offset: u64,
+        len: u64,
+    },
+}
+
+impl FileSource {
+    fn len(&self) -> io::Result<u64> {
+        match self {
+            Self::Host(path) => Ok(fs::metadata(path)?.len()),
+            Self::Data(data) => Ok(data.len() as u64),
+            Self::Range { len, .. } => Ok(*len),
+        }
+    }
+
+    fn open(&self) -> io::Result<Box<dyn Read + '_>> {
+        match self {
+            Self::Host(path) => Ok(Box::new(File::open(path)?)),
+            Self::Data(data) => Ok(Box::new(&data[..])),
+            Self::Range { path, offset, len } => {
+                let mut file = File::open(path)?;
+                file.seek(SeekFrom::Start(*offset))?;
+                Ok(Box::new(file.take(*len)))
+            }
+        }
+    }
+}
+
+impl std::fmt::Display for FileSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::Host(path) => write!(f, "{}", path.display()),
+            Self::Data(data) => write!(f, "<{} bytes>", data.len()),
+            Self::Range { path, offset, .. } => write!(f, "{}@{offset}", path.display()),
+        }
+    }
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum PartitionTable {
+    #[value(alias("gpt"))]
+    Gpt,
+    #[value(alias("mbr"))]
+    Mbr,
+    /// GPT, with the first three partitions mirrored into MBR entries for legacy firmware
+    Hybrid,
+    #[value(alias("none"))]
+    None,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum Bootloader {
+    /// syslinux, from `ldlinux.sys` and its boot sector `ldlinux.bss`
+    Syslinux,
+    /// GRUB for BIOS, from `boot.img` and `core.img`
+    GrubBios,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum Preset {
+    /// systemd-boot, from `EFI/systemd` of the input. `EFI/BOOT` and `loader/loader.conf` are
+    /// added if missing
+    SystemdBoot,
+}
+
+#[derive(Clone, Copy, Debug)]
+enum GptBackupAt {
+    EndOfMedium,
+    /// Size of the medium the image will be written to
+    MediumSize(u64),
+}
+
+impl std::str::FromStr for GptBackupAt {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s {
+            "end-of-medium" => Ok(Self::EndOfMedium),
+            _ => size::parse_bytes(s).map(Self::MediumSize),
+        }
+    }
+}
+
+/// Partition of another image to take the contents from, as `IMAGE[:PARTITION]`.
+#[derive(Clone, Debug)]
+pub struct InputImage {
+    path: PathBuf,
+    /// The first partition if not set
+    partition: Option<usize>,
+}
+
+impl std::str::FromStr for InputImage {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        let (path, partition) = match s.rsplit_once(':') {
+            Some((path, number)) if !path.is_empty() => match number.parse() {
+                Ok(0) => return Err("partitions are numbered from 1".into()),
+                Ok(number) => (path, Some(number)),
+                Err(_) => (s, None),
+            },
+            _ => (s, None),
+        };
+
+        Ok(Self {
+            path: path.into(),
+            partition,
+        })
+    }
+}
+
+impl std::fmt::Display for InputImage {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.path.display())?;
+        if let Some(number) = self.partition {
+            write!(f, ":{number}")?;
+        }
+        Ok(())
+    }
+}
+
+impl PartitionTable {
+    /// Bytes taken by the partition table before the first and after the last partition.
+    fn reserved(&self, sector: u64) -> (u64, u64) {
+        // 128 GPT entries of 128 bytes
+        let entries = 0x4000 / sector;
+
+        match self {
+            Self::None => (0, 0),
+            // MBR sector in front of the partitions
+            Self::Mbr => (sector, 0),
+            // Protective MBR, header and entries in front, backup header and entries at the end
+            Self::Gpt | Self::Hybrid => ((2 + entries) * sector, (1 + entries) * sector),
+        }
+    }
+}
+
+/// Filesystem written from a directory tree into a partition.
+pub trait FsWriter {
+    /// Smallest size that holds the filesystem with the contents of `tree`.
+    fn estimate_size(&self, tree: &tree::Tree) -> anyhow::Result<u64>;
+
+    /// Write the filesystem with the contents of `tree` into `out`, which spans `size` bytes.
+    ///
+    /// `on_file` is called with the image path and length of every file written.
+    fn write(
+        &self,
+        out: &mut dyn ReadWriteSeek,
+        size: u64,
+        tree: &tree::Tree,
+        ctx: &BuildContext,
+        on_file: &mut dyn FnMut(&Path, u64),
+    ) -> anyhow::Result<()>;
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+pub enum Filesystem {
+    #[value(alias("vfat"), alias("fat32"))]
+    Vfat,
+    Ext2,
+    /// ext2 with a journal
+    Ext3,
+    Exfat,
+    /// ISO9660 with Joliet names, for CD/DVD images
+    Iso9660,
+    /// Compressed read-only filesystem
+    Squashfs,
+    /// Read-only filesystem, uncompressed
+    Erofs,
+    /// Minimal read-only filesystem, without permissions or times
+    Romfs,
+    /// Compressed read-only filesystem for legacy bootloaders, with files up to 16MiB
+    Cramfs,
+    /// Flash filesystem for NOR flash. Free space is filled with 0xff, as erased flash reads
+    Jffs2,
+    /// XFS, for large root filesystems
+    Xfs,
+    /// btrfs on a single device, with checksums of all data and metadata
+    Btrfs,
+    /// UDF, readable by Windows, macOS and Linux, for removable media with files over 4GiB
+    Udf,
+    /// HFS+, for media Macs boot from. Names are not told apart by case
+    Hfsplus,
+    /// F2FS, for the data partitions of Android and embedded devices on eMMC and SD cards
+    F2fs,
+    /// littlefs for microcontroller flash, written without a partition table. Free space is
+    /// filled with 0xff, as erased flash reads
+    Littlefs,
+    /// UBIFS in a UBI image for raw NAND flash, written without a partition table. The volume
+    /// grows over the rest of the flash when it is first attached
+    Ubifs,
+    /// newc cpio archive to be unpacked by the kernel, written without a partition table
+    Initramfs,
+    /// ustar archive, with pax headers for long names and large files
+    Tar,
+}
+
+impl Filesystem {
+    /// Whether the output is an archive rather than a block image.
+    fn is_archive(&self) -> bool {
+        matches!(self, Self::Initramfs | Self::Tar)
+    }
+
+    /// Whether symbolic links can be stored, rather than being left out.
+    fn has_symlinks(&self) -> bool {
+        !matches!(
+            self,
+            Self::Vfat | Self::Exfat | Self::Iso9660 | Self::Littlefs
+        )
+    }
+
+    /// Writer of filesystems written from a tree, which are all but FAT and archives.
+    fn writer(&self, args: &Args, part: &layout::Partition) -> Option<Box<dyn FsWriter>> {
+        let label = part.label.clone();
+
+        Some(match self {
+            Self::Ext2 | Self::Ext3 => Box::new(args.ext2_options(*self, label)),
+            Self::Exfat => Box::new(exfat::Options { label }),
+            Self::Iso9660 => Box::new(args.iso9660_options(part)),
+            Self::Erofs => Box::new(erofs::Options { label }),
+            Self::Romfs => Box::new(romfs::Options { label }),
+            Self::Cramfs => Box::new(cramfs::Options { label }),
+            Self::Xfs => Box::new(xfs::Options { label }),
+            Self::Btrfs => Box::new(btrfs::Options {
+                label,
+                subvolumes: part.subvolumes.iter().map(|s| s.path.clone()).collect(),
+                default_subvolume: part
+                    .subvolumes
+                    .iter()
+                    .find(|s| s.default)
+                    .map(|s| s.path.clone()),
+            }),
+            Self::Jffs2 => Box::new(args.jffs2_options()),
+            Self::Littlefs => Box::new(args.littlefs_options()),
+            Self::Ubifs => Box::new(args.ubifs_options(label)),
+            Self::Udf => Box::new(udf::Options {
+                label,
+                block_size: args.udf_block_size.unwrap_or(args.sector_size as u32),
+            }),
+            Self::Hfsplus => Box::new(hfsplus::Options {
+                label,
+                blessed: args.hfsplus_bless.clone(),
+            }),
+            Self::F2fs => Box::new(f2fs::Options { label }),
+            Self::Squashfs => Box::new(args.squashfs_options()),
+            Self::Vfat | Self::Initramfs | Self::Tar => return None,
+        })
+    }
+
+    fn estimate_size(
+        &self,
+        args: &Args,
+        part: &layout::Partition,
+        extra_files: &[ExtraFile],
+        ctx: &BuildContext,
+    ) -> anyhow::Result<u64> {
+        if let Some(writer) = self.writer(args, part) {
+            let tree = content_tree(args, part, extra_files, ctx)?;
+            return writer.estimate_size(&tree);
+        }
+
+        Ok(match self {
+            Self::Initramfs | Self::Tar => unreachable!("archives are not sized"),
+            Self::Vfat if args.fat_type.fixed().is_some() => {
+                let tree = content_tree(args, part, extra_files, ctx)?;
+                fat::estimate_size(&tree, args.fat_type.fixed().unwrap())?
+            }
+            Self::Vfat if args.sector_size == 512 => {
+                let tree = content_tree(args, part, extra_files, ctx)?;
+                fat::estimate_auto_size(&tree)?
+            }
+            Self::Vfat => {
+                // Estimate size for fat32 images. They will be sufficient for smaller images.
+                let mut number_of_fats = 3;
+                let mut dir_entries = 1u64;
+
+                let cluster = args.fat_cluster_size();
+                let dir_entry_count = cluster / 32;
+                let dir_entry_align = dir_entry_count - 1;
+
+                let tree = content_tree(args, part, &[], ctx)?;
+                // Entries of each directory, including . and .. but for the root
+                let mut counted = vec![0; tree.nodes.len()];
+                counted[0] = 1;
+
+                for (idx, node) in tree.nodes.iter().enumerate().skip(1) {
+                    // Long file name
+                    let lfn_entries = (node.name.len() as u64).div_ceil(13);
+                    counted[node.parent] += 1 + lfn_entries;
+
+                    match &node.kind {
+                        tree::Kind::Dir(_) => counted[idx] += 3,
+                        tree::Kind::File { len, .. } => {
+                            // Number of FAT
+                            number_of_fats += len.div_ceil(cluster);
+                        }
+                        tree::Kind::Symlink(_) => unreachable!("FAT has no symbolic links"),
+                    }
+                }
+
+                for (node, counted_entries) in tree.nodes.iter().zip(counted) {
+                    if node.is_dir() {
+                        // Final dir entry alignment
+                        dir_entries = (dir_entries + dir_entry_align) & !dir_entry_align;
+                        dir_entries += (counted_entries + dir_entry_align) & !dir_entry_align;
+                    }
+                }
+
+                for file in extra_files {
+                    number_of_fats += file.source.len()?.div_ceil(cluster);
+
+                    // Every path component may need a new directory, and its own long file name
+                    // entries. Assume the worst and give each one a full cluster.
+                    let components = file.dest.components().count() as u64;
+                    dir_entries += components * dir_entry_count;
+                }
+
+                // fatrs implementation reserves 8 sectors
+                let reserved_sectors = args.sector_size * 8;
+
+                let size = number_of_fats * cluster;
+
+                number_of_fats += 3;
+
+                debug!(
+                    r"
+    size: {size:x}
+    number_of_fats: {number_of_fats:x}
+    dir_entries: {dir_entries}"
+                );
+
+                size + number_of_fats * 4 * 2 + reserved_sectors + dir_entries * 32
+            }
+            _ => unreachable!("sized by its writer"),
+        })
+    }
+}
+
+const FAT_BYTES_PER_CLUSTER: usize = 512;
+
+/// Visit the entries of `cur_path` below `root` by name if `sorted`, so the layout of images does
+/// not vary between hosts, or in the order of `fs::read_dir` otherwise. Symbolic links are passed
+/// to `file_cb` with the metadata of the link itself unless `link_follow`.
+#[allow(clippy::too_many_arguments)]
+fn walk_dir<T>(
+    root: &Path,
+    cur_path: &Path,
+    link_follow: bool,
+    sorted: bool,
+    mut cur_entry: T,
+    dir_cb: &mut impl FnMut(&Path, &Path, &mut T, &Metadata) -> io::Result<T>,
+    file_cb: &mut impl FnMut(&Path, &Path, &mut T, &Metadata) -> io::Result<()>,
+    close_cb: &mut impl FnMut(&Path, T) -> io::Result<()>,
+) -> io::Result<()> {
+    let mut entries = fs::read_dir(cur_path)?.collect::<io::Result<Vec<_>>>()?;
+    if sorted {
+        entries.sort_by_key(|e| e.file_name());
+    }
+
+    for entry in entries {
+        let metadata = entry.metadata()?;
+        let path = entry.path();
+        if let Ok(short_path) = path.strip_prefix(root) {
+            if metadata.is_dir() {
+                let new_entry = dir_cb(&path, short_path, &mut cur_entry, &metadata)?;
+                walk_dir(
+                    root,
+                    &path,
+                    link_follow,
+                    sorted,
+                    new_entry,
+                    dir_cb,
+                    file_cb,
+                    close_cb,
+                )?;
+            } else if link_follow && metadata.is_symlink() {
+                file_cb(&path, short_path, &mut cur_entry, &fs::metadata(&path)?)?;
+            } else {
+                file_cb(&path, short_path, &mut cur_entry, &metadata)?;
+            }
+        } else {
+            error!("walk_dir: {path:?}");
+        }
+    }
+
+    close_cb(cur_path, cur_entry)?;
+
+    Ok(())
+}
+
+/// Partition a bootloader is installed into: the bootable FAT one, or the first.
+fn boot_partition(parts: &[layout::Partition]) -> Option<usize> {
+    let is_vfat = |p: &layout::Partition| matches!(p.filesystem, Some(Filesystem::Vfat));
+    parts
+        .iter()
+        .position(|p| p.bootable && is_vfat(p))
+        .or_else(|| parts.iter().position(is_vfat))
+}
+
+/// Partition set up as the ESP by --preset: the `esp` FAT one, or the first other than XBOOTLDR.
+fn esp_partition(parts: &[layout::Partition]) -> Option<usize> {
+    let is_vfat = |p: &layout::Partition| matches!(p.filesystem, Some(Filesystem::Vfat));
+    let esp = "esp".parse().unwrap();
+    let xbootldr = xbootldr_partition(parts);
+    parts
+        .iter()
+        .position(|p| p.gpt_type == esp && is_vfat(p))
+        .or_else(|| (0..parts.len()).find(|&i| is_vfat(&parts[i]) && Some(i) != xbootldr))
+}
+
+/// Partition --preset puts the kernel and its boot entry into instead of the ESP: the `xbootldr`
+/// FAT one.
+fn xbootldr_partition(parts: &[layout::Partition]) -> Option<usize> {
+    let xbootldr = "xbootldr".parse().unwrap();
+    parts
+        .iter()
+        .position(|p| p.gpt_type == xbootldr && matches!(p.filesystem, Some(Filesystem::Vfat)))
+}
+
+/// BIOS boot partition GRUB is embedded in on GPT.
+fn bios_boot_partition(parts: &[layout::Partition]) -> Option<usize> {
+    parts
+        .iter()
+        .position(|p| p.gpt_type == "bios-boot".parse().unwrap())
+}
+
+/// Contents of the formatted partition `part`, its input directory or the input archive or image,
+/// with `extra_files` added to them.
+fn content_tree(
+    args: &Args,
+    part: &layout::Partition,
+    extra_files: &[ExtraFile],
+    ctx: &BuildContext,
+) -> anyhow::Result<tree::Tree> {
+    let time = ctx.time.timestamp();
+    let mut tree = match (&part.input_dir, &ctx.input) {
+        (Some(dir), _) => tree::Tree::build(dir, args.link_follow, &[], ctx)?,
+        (None, Some(input)) => input.clone(),
+        // Partitions of subvolumes or [[files]] only, or of kickstart files without a source
+        (None, None) => tree::Tree::new(0o755, time),
+    };
+
+    // Subvolumes nested in others go in after them, and extra files after all of them
+    let mut subvolumes = part.subvolumes.iter().collect::<Vec<_>>();
+    subvolumes.sort_by_key(|s| s.path.components().count());
+    for sub in subvolumes {
+        let contents = match &sub.input_dir {
+            Some(dir) => tree::Tree::build(dir, args.link_follow, &[], ctx)?,
+            None => tree::Tree::new(0o755, time),
+        };
+        tree.graft(&sub.path, contents)
+            .map_err(|e| anyhow::anyhow!("subvolume `{}`: {e}", sub.path.display()))?;
+    }
+    tree.add_extra(extra_files, time)?;
+
+    Ok(match part.filesystem {
+        Some(fs) if !fs.has_symlinks() => tree.without_symlinks(),
+        _ => tree,
+    })
+}
+
+/// Read the archive given with --input-tar.
+fn read_tar(path: &Path, time: i64) -> anyhow::Result<(tree::Tree, u64)> {
+    let read = |input: &mut dyn Read, archive| {
+        tar::read(&mut io::BufReader::new(input), archive, time)
+            .map_err(|e| anyhow::anyhow!("cannot read {}: {e}", path.display()))
+    };
+
+    match output::Compressor::from_suffix(path) {
+        Some(compressor) => compressor.decompress(path, |input| read(input, None)),
+        None => read(
+            &mut File::open(path)
+                .map_err(|e| anyhow::anyhow!("cannot open {}: {e}", path.display()))?,
+            Some(path),
+        ),
+    }
+}
+
+/// Read the input archive, image or file list into memory, to build the image from.
+fn read_input(args: &Args, time: i64) -> anyhow::Result<tree::Tree> {
+    if let Some(path) = &args.input_tar {
+        let (tree, entries) = read_tar(path, time)?;
+        debug!("Read {entries} entries of {}", path.display());
+        return Ok(tree);
+    }
+
+    if let Some(input) = &args.input_image {
+        return extract::read(input, time);
+    }
+
+    if let Some(list) = &args.files_from {
+        let (tree, entries) = files_from::read(args.input_dir(), list, args.link_follow)?;
+        debug!(
+            "Read {entries} listed entries of {}",
+            args.input_dir().display()
+        );
+        return Ok(tree);
+    }
+
+    let path = args.input_zip.as_deref().unwrap();
+    let (tree, entries) = zip::read(path, time)
+        .map_err(|e| anyhow::anyhow!("cannot read {}: {e}", path.display()))?;
+    debug!("Read {entries} entries of {}", path.display());
+    Ok(tree)
+}
+
+/// Size of the image a partition takes its contents from.
+fn content_size(part: &layout::Partition) -> anyhow::Result<u64> {
+    let path = part.image.as_deref().unwrap();
+    let meta =
+        fs::metadata(path).map_err(|e| anyhow::anyhow!("cannot read {}: {e}", path.display()))?;
+    Ok(meta.len())
+}
+
+/// Copy the image at `path` into the partition at `start`.
+fn copy_content(
+    file: &File,
+    part: &layout::Partition,
+    path: &Path,
+    start: u64,
+    len: u64,
+) -> anyhow::Result<()> {
+    let mut image =
+        File::open(path).map_err(|e| anyhow::anyhow!("cannot open {}: {e}", path.display()))?;
+    let size = image.metadata()?.len();
+
+    if size > len {
+        anyhow::bail!(
+            "{} is {size} bytes, more than the {len} of partition `{}`",
+            path.display(),
+            part.name
+        );
+    }
+
+    debug!("Copying {} to {start:x}", path.display());
+
+    let mut file = file;
+    file.seek(io::SeekFrom::Start(start))?;
+    io::copy(&mut image, &mut file)?;
+
+    Ok(())
+}
+
+/// Read MBR boot code, which ends where the disk signature starts.
+fn mbr_bootcode(path: &Path) -> anyhow::Result<Vec<u8>> {
+    let mut code = fs::read(path)
+        .map_err(|e| anyhow::anyhow!("cannot read boot code {}: {e}", path.display()))?;
+
+    match code.len() {
+        0..=440 => {}
+        // A boot sector, with its partition table and signature left out
+        512 => code.truncate(440),
+        len => anyhow::bail!(
+            "boot code {} is {len} bytes, more than the 440 bytes that fit in an MBR",
+            path.display()
+        ),
+    }
+
+    Ok(code)
+}
+
+/// MBR entry for a partition at `start`, spanning `len` bytes.
+fn mbr_entry(
+    part: &layout::Partition,
+    start: u64,
+    len: u64,
+    sector: u64,
+    sys: u8,
+) -> anyhow::Result<mbrman::MBRPartitionEntry> {
+    let lba = |bytes: u64| {
+        u32::try_from(bytes / sector)
+            .map_err(|_| anyhow::anyhow!("partition `{}` does not fit in an MBR", part.name))
+    };
+
+    Ok(mbrman::MBRPartitionEntry {
+        boot: if part.bootable {
+            mbrman::BOOT_ACTIVE
+        } else {
+            mbrman::BOOT_INACTIVE
+        },
+        first_chs: mbrman::CHS::empty(),
+        sys,
+        last_chs: mbrman::CHS::empty(),
+        starting_lba: lba(start)?,
+        sectors: lba(len)?,
+    })
+}
+
+/// MBR type of a partition, unless it is set, from its GPT type and filesystem.
+fn mbr_type(part: &layout::Partition) -> u8 {
+    if let Some(id) = part.mbr_type {
+        return id;
+    }
+
+    match (&part.gpt_type, part.filesystem) {
+        (part_type::GptType::Fixed("esp", _), _) => 0xef,
+        (part_type::GptType::Fixed("xbootldr", _), _) => 0xea,
+        (part_type::GptType::Fixed("swap", _), _) => 0x82,
+        // FAT32 and exFAT (NTFS type), with LBA addressing
+        (_, Some(Filesystem::Vfat)) => 0x0c,
+        (_, Some(Filesystem::Exfat)) => 0x07,
+        (_, Some(Filesystem::Hfsplus)) => 0xaf,
+        _ => 0x83,
+    }
+}
+
+/// Seed of `--deterministic` without one, hashing the configuration and the contents of the
+/// partitions.
+fn content_seed(
+    args: &Args,
+    ctx: &BuildContext,
+    parts: &[layout::Partition],
+) -> anyhow::Result<Vec<u8>> {
+    let mut seed = args.config_json().to_string();
+
+    for part in parts {
+        if part.filesystem.is_some() && part.image.is_none() {
+            seed += &build_info::hash_tree(&content_tree(args, part, &[], ctx)?)?;
+        }
+        if let Some(image) = &part.image {
+            let digest = sha256::Sha256::new()
+                .read_from(File::open(image)?)?
+                .finish();
+            seed += &sha256::hex(&digest);
+        }
+    }
+
+    Ok(seed.into_bytes())
+}
+
+/// Random version 4 GUID, for partitions of a new GPT.
+fn random_guid(random: &random::Random) -> io::Result<String> {
+    let mut b = [0u8; 16];
+    random.fill(&mut b)?;
+    b[6] = (b[6] & 0x0f) | 0x40;
+    b[8] = (b[8] & 0x3f) | 0x80;
+
+    let hex = |b: &[u8]| b.iter().map(|b| format!("{b:02x}")).collect::<String>();
+    Ok(format!(
+        "{}-{}-{}-{}-{}",
+        hex(&b[..4]),
+        hex(&b[4..6]),
+        hex(&b[6..8]),
+        hex(&b[8..10]),
+        hex(&b[10..])
+    ))
+}
+
+/// Path the image is built at, before being atomically moved to `output`.
+fn temp_output_path(output: &Path, suffix: &str) -> PathBuf {
+    let name = output
+        .file_name()
+        .map(|n| n.to_string_lossy())
+        .unwrap_or_default();
+    output.with_file_name(format!(".{name}.mkimg-{suffix}"))
+}
+
+/// Run a command line of the `mkimg` binary.
+pub fn run(cli: Cli) -> anyhow::Result<()> {
+    match cli.command {
+        Some(Command::Create(args)) => ImageBuilder::new(*args).build().map(drop),
+        Some(Command::VerifyContent(args)) => verify::run(&args),
+        Some(Command::Sync(args)) => sync::run(&args),
+        Some(Command::Extract(args)) => extract::run(&args),
+        Some(Command::List(args)) => list::run(&args),
+        Some(Command::Rm(args)) => rm::run(&args),
+        Some(Command::AddPartition(args)) => add_partition::run(&args),
+        Some(Command::Resize(args)) => resize::run(&args),
+        Some(Command::Shrink(args)) => shrink::run(&args),
+        Some(Command::ConvertTable(args)) => convert_table::run(&args),
+        Some(Command::Wrap(args)) => wrap::run(&args),
+        Some(Command::Join(args)) => split::run(&args),
+        Some(Command::SplitPartitions(args)) => split_partitions::run(&args),
+        None => ImageBuilder::new(cli.create).build().map(drop),
+    }
+}
+
+/// Builds the image described by a set of [`Args`].
+pub struct ImageBuilder {
+    args: Args,
+}
+
+impl ImageBuilder {
+    pub fn new(args: Args) -> Self {
+        Self { args }
+    }
+
+    /// Build the image and move it to the output path.
+    pub fn build(self) -> anyhow::Result<BuildSummary> {
+        let mut args = self.args;
+
+        let mut ctx = BuildContext::new(args.timestamp)?;
+        args.expand_templates(&ctx.time)?;
+        ctx.sorted = !args.unsorted;
+
+        if let Some(path) = &args.from_wks {
+            let ks = wks::load(path, &args.rootfs_dir.iter().cloned().collect())?;
+            if let PartitionTable::None = args.partition_table {
+                args.partition_table = match ks.gpt {
+                    Some(true) => PartitionTable::Gpt,
+                    _ => PartitionTable::Mbr,
+                };
+            }
+            if let Some(align) = ks.align {
+                args.align = args.align.max(align);
+            }
+            args.partition = ks.partitions;
+        }
+
+        let mut parts = args.partitions(&ctx.time)?;
+
+        if args.cargo_rerun_if_changed {
+            cargo::print_rerun_if_changed(args.input_paths(&parts));
+        }
+
+        validate::check(&args, &mut parts)?;
+        let in_place = args.in_place();
+
+        let device = blockdev::is_block_device(args.output_path())
+            .then(|| blockdev::Device::open(args.output_path()))
+            .transpose()?;
+
+        if let Some(device) = &device {
+            eprintln!("Target device: {}", device.describe());
+            device.check_unused()?;
+
+            if !in_place
+                && (args.output_format.needs_conversion()
+                    || args.compress.is_some()
+                    || args.split.is_some()
+                    || args.bmap
+                    || !args.emit_partmap.is_empty())
+            {
+                anyhow::bail!(
+                "block devices only take raw images, without conversion, --compress, --split, --bmap or --emit-partmap"
+            );
+            }
+
+            if !args.yes_i_know {
+                anyhow::bail!(
+                    "{} would be overwritten, pass --yes-i-know to proceed",
+                    args.output_path().display()
+                );
+            }
+        }
+
+        let existed = args.output_path().exists();
+
+        // Images for devices are built in the temporary directory, not next to the device node
+        let image_path = match &device {
+            _ if in_place => args.output_path().to_owned(),
+            Some(_) => temp_output_path(
+                &std::env::temp_dir().join(args.output_path().file_name().unwrap_or_default()),
+                "tmp",
+            ),
+            None => temp_output_path(args.output_path(), "tmp"),
+        };
+
+        let raw_path = if args.output_format.needs_conversion() {
+            temp_output_path(args.output_path(), "raw")
+        } else {
+            image_path.clone()
+        };
+
+        let mut progress = progress::Progress::open(args.progress_json.as_ref())
+            .map_err(|e| anyhow::anyhow!("cannot open progress target: {e}"))?;
+
+        if let Some(cmd) = &args.pre_hook {
+            progress.phase("pre-hook");
+            hook::run("pre", cmd, &image_path, args.output_path(), &args.to_json())?;
+        }
+
+        // Archives, input images and file lists are read into memory in place of a directory
+        let read = args.input_tar.is_some()
+            || args.input_image.is_some()
+            || args.input_zip.is_some()
+            || args.files_from.is_some();
+        if read {
+            progress.phase("read");
+            ctx.input = Some(read_input(&args, ctx.time.timestamp())?);
+            parts[0].input_dir = None;
+        }
+
+        if let Some(seed) = &args.deterministic {
+            let seed = match seed.as_str() {
+                "" => content_seed(&args, &ctx, &parts)?,
+                seed => seed.as_bytes().to_vec(),
+            };
+            ctx.random = random::Random::seeded(&seed);
+        }
+
+        let ret = build(&args, &ctx, &parts, &raw_path, &mut progress).and_then(|mut summary| {
+            if let Some(slack) = args.minimize {
+                progress.phase("minimize");
+                let (size, number, len) = shrink::shrink(&raw_path, slack)?;
+                summary.image_size = size;
+                for part in summary
+                    .partitions
+                    .iter_mut()
+                    .filter(|p| p.number as usize == number)
+                {
+                    part.len = len;
+                }
+            }
+
+            if let Some(checksum) = &args.append_checksum {
+                progress.phase("checksum");
+                checksum.apply(&raw_path)?;
+            }
+
+            // Converted images are written from the raw one, which is removed afterwards
+            if !in_place && !args.output_format.needs_conversion() && args.compress.is_none() {
+                let freed = sparse::punch_zeroes(&raw_path)?;
+                debug!("Punched {freed} bytes of zeroes");
+            }
+
+            let bmap = if args.bmap {
+                if args.output_format.needs_conversion() {
+                    anyhow::bail!("block maps can only be generated for raw images");
+                }
+                progress.phase("bmap");
+                Some(bmap::generate(&raw_path)?)
+            } else {
+                None
+            };
+
+            let image_name = args
+                .output_path()
+                .file_name()
+                .map(|n| n.to_string_lossy().into_owned())
+                .unwrap_or_default();
+
+            // Archives and the formats written front to back are piped into the compressor.
+            // Anything else seeks, and is compressed from a whole uncompressed copy
+            let mut compressed = args.streams_archive();
+
+            if raw_path != image_path {
+                progress.phase("convert");
+                let opts = output::Options {
+                    base_address: args.base_address,
+                    family_id: args.family_id,
+                    compress: args.compression.map(|_| args.compression_level()),
+                    subformat: args.subformat.unwrap_or_default(),
+                    name: image_name.clone(),
+                };
+                let format = args.output_format;
+                match &args.compress {
+                    Some(compress) if format.is_sequential() => {
+                        compress.stream(&image_path, args.compress_threads, &[], |out| {
+                            format.write_sequential(&raw_path, out, &opts)
+                        })?;
+                        compressed = true;
+                    }
+                    _ => format.convert(&raw_path, &image_path, &opts, &ctx)?,
+                }
+                fs::remove_file(&raw_path)?;
+            }
+
+            if let Some(compress) = args.compress.filter(|_| !compressed) {
+                progress.phase("compress");
+                let uncompressed = temp_output_path(args.output_path(), "uncompressed");
+                fs::rename(&image_path, &uncompressed)?;
+                let ret = compress.apply(&uncompressed, &image_path, args.compress_threads);
+                fs::remove_file(&uncompressed)?;
+                ret?;
+            }
+
+            if let Some(cmd) = &args.post_hook {
+                progress.phase("post-hook");
+                let summary = summary.to_json(&args);
+                hook::run("post", cmd, &image_path, args.output_path(), &summary)?;
+            }
+            if let Some(bmap) = bmap {
+                let mut bmap_path = args.output_path().as_os_str().to_owned();
+                bmap_path.push(".bmap");
+                fs::write(bmap_path, bmap)?;
+            }
+
+            for format in &args.emit_partmap {
+                if format.needs_raw() && args.output_format.needs_conversion() {
+                    anyhow::bail!("only JSON partition maps can be generated for converted images");
+                }
+                let mut map_path = args.output_path().as_os_str().to_owned();
+                map_path.push(format.suffix());
+                let map = format.generate(
+                    &image_name,
+                    summary.image_size,
+                    args.sector_size,
+                    &summary.partitions,
+                );
+                fs::write(map_path, map)?;
+            }
+
+            if let Some(size) = args.split {
+                progress.phase("split");
+                split::split(&image_path, args.output_path(), size)?;
+                fs::remove_file(&image_path)?;
+            } else if in_place {
+                // Already written into the output itself, there is nothing to move
+            } else if let Some(device) = &device {
+                progress.phase("write");
+                device.write(&image_path)?;
+                fs::remove_file(&image_path)?;
+            } else {
+                fs::rename(&image_path, args.output_path())?;
+            }
+            Ok(summary)
+        });
+
+        if ret.is_err() && !(in_place && existed) {
+            let _ = fs::remove_file(&raw_path);
+            let _ = fs::remove_file(&image_path);
+        }
+
+        progress.finish(ret.as_ref().err().map(|e| format!("{e:#}")));
+
+        ret
+    }
+}
+
+fn build(
+    args: &Args,
+    ctx: &BuildContext,
+    parts: &[layout::Partition],
+    image_path: &Path,
+    progress: &mut progress::Progress,
+) -> anyhow::Result<BuildSummary> {
+    let mut summary = BuildSummary::default();
+
+    let mut extra_files = args
+        .aliases
+        .iter()
+        .map(|a| ExtraFile {
+            dest: a.dest.clone(),
+            source: FileSource::Host(a.src.clone()),
+        })
+        .collect::<Vec<_>>();
+
+    if parts.len() > 1 && !extra_files.is_empty() {
+        anyhow::bail!("--alias and --build-info only apply to single partition images");
+    }
+
+    if let Some(path) = &args.build_info {
+        let tree = content_tree(args, &parts[0], &[], ctx)?;
+        let data = build_info::generate(&args.config_json(), &tree, &ctx.time)?;
+        extra_files.push(ExtraFile {
+            dest: path.strip_prefix("/").unwrap_or(path).into(),
+            source: FileSource::Data(data.into()),
+        });
+    }
+
+    // Files added to the input of each partition, with those of the layout and the bootloader in
+    // their partition
+    let mut part_files = parts
+        .iter()
+        .map(|part| [&extra_files[..], &part.files].concat())
+        .collect::<Vec<_>>();
+
+    if let Some(bootloader) = args.bootloader {
+        let dir = args.bootloader_dir.as_deref().unwrap();
+        if let Bootloader::Syslinux = bootloader {
+            part_files[boot_partition(parts).unwrap()].extend(syslinux::files(dir)?);
+        }
+    }
+
+    if let Some(Preset::SystemdBoot) = args.preset {
+        let target = esp_partition(parts).unwrap();
+        let kernel = args.kernel.as_deref().map(|kernel| systemd_boot::Kernel {
+            kernel,
+            initrd: args.initrd.as_deref(),
+            cmdline: args.kernel_cmdline.as_deref(),
+            title: args.kernel_title.as_deref(),
+        });
+        let tree = content_tree(args, &parts[target], &part_files[target], ctx)?;
+        part_files[target].extend(systemd_boot::files(&tree)?);
+        if let Some(kernel) = kernel {
+            let boot = xbootldr_partition(parts).unwrap_or(target);
+            part_files[boot].extend(systemd_boot::kernel_files(kernel)?);
+        }
+    }
+
+    if progress.enabled() && !args.no_filesystem {
+        let mut total = 0;
+        for part in parts.iter().filter(|p| p.filesystem.is_some()) {
+            total += content_tree(args, part, &[], ctx)?.file_bytes();
+        }
+        for extra in part_files.iter().flatten() {
+            total += extra.source.len()?;
+        }
+        progress.set_total(total);
+    }
+
+    if args.filesystem.is_archive() {
+        return write_archive(args, ctx, &parts[0], image_path, &extra_files, progress);
+    }
+
+    if let Some(number) = args.into_partition {
+        return write_into_partition(
+            args,
+            ctx,
+            &parts[0],
+            number,
+            image_path,
+            &part_files[0],
+            progress,
+        );
+    }
+
+    progress.phase("layout");
+
+    let mut file = OpenOptions::new()
+        .create(true)
+        .truncate(!args.no_filesystem)
+        .read(true)
+        .write(true)
+        .open(image_path)?;
+
+    let image_size = match args.image_size {
+        Some(size) => Some(size),
+        None if args.no_filesystem => {
+            let len = file.seek(io::SeekFrom::End(0))?;
+            file.rewind()?;
+            Some(len).filter(|&l| l > 0)
+        }
+        None => None,
+    };
+
+    let placement = disk::place(args, ctx, parts, &part_files, image_size)?;
+    let file = disk::write_table(args, ctx, parts, &placement, file)?;
+    let disk::Placement {
+        numbers,
+        placed,
+        head,
+        tail,
+        total_size,
+    } = placement;
+    let sector = args.sector_size;
+
+    if let Some(path) = &args.mbr_bootcode {
+        let code = mbr_bootcode(path)?;
+        let mut file = &file;
+        file.rewind()?;
+        file.write_all(&code)?;
+    }
+
+    if !args.raw_writes.is_empty() {
+        let mut used = vec![(0, head, "the partition table".to_owned())];
+
+        if tail > 0 && matches!(args.gpt_backup_at, GptBackupAt::EndOfMedium) {
+            used.push((total_size - tail, total_size, "the backup GPT".to_owned()));
+        }
+
+        for ((part, &(start, len)), &number) in parts.iter().zip(&placed).zip(&numbers) {
+            used.push((start, start + len, format!("partition `{}`", part.name)));
+
+            if matches!(args.partition_table, PartitionTable::Mbr) && number > 4 {
+                let ebr = start - sector;
+                used.push((ebr, start, format!("the EBR of partition `{}`", part.name)));
+            }
+        }
+
+        write_raw(args, &file, total_size, used)?;
+    }
+
+    summary.image_size = total_size;
+
+    for ((part, &(start, len)), &number) in parts.iter().zip(&placed).zip(&numbers) {
+        let name = match args.partition_table {
+            PartitionTable::Gpt | PartitionTable::Hybrid => part.name.clone(),
+            _ => format!("part{number}"),
+        };
+
+        summary.partitions.push(partmap::Partition {
+            number,
+            name,
+            start,
+            len,
+        });
+    }
+
+    if args.no_filesystem {
+        return Ok(summary);
+    }
+
+    progress.phase("format");
+
+    for ((part, &(start, len)), files) in parts.iter().zip(&placed).zip(&part_files) {
+        if let Some(path) = &part.image {
+            copy_content(&file, part, path, start, len)?;
+            continue;
+        }
+        if part.filesystem.is_none() {
+            continue;
+        }
+
+        let fs_slice = fscommon::StreamSlice::new(file.try_clone()?, start, start + len)?;
+
+        format(
+            args,
+            ctx,
+            part,
+            Box::new(fs_slice),
+            len,
+            files,
+            &mut summary,
+            progress,
+        )?;
+    }
+
+    if let Some(bootloader) = args.bootloader {
+        progress.phase("bootloader");
+
+        let dir = args.bootloader_dir.as_deref().unwrap();
+
+        let mbr_code = match bootloader {
+            Bootloader::Syslinux => {
+                let (start, len) = placed[boot_partition(parts).unwrap()];
+                syslinux::install(&file, start, len, dir)?;
+
+                match args.partition_table {
+                    PartitionTable::None => None,
+                    PartitionTable::Gpt => Some("gptmbr.bin"),
+                    PartitionTable::Mbr | PartitionTable::Hybrid => Some("mbr.bin"),
+                }
+            }
+            Bootloader::GrubBios => {
+                let (start, end) = match args.partition_table {
+                    PartitionTable::Gpt | PartitionTable::Hybrid => {
+                        let (start, len) = placed[bios_boot_partition(parts).unwrap()];
+                        (start, start + len)
+                    }
+                    // The gap between the MBR and the first partition, or the EBR before it
+                    _ => {
+                        let starts = placed.iter().zip(&numbers);
+                        let first = starts.map(|(p, &n)| if n > 4 { p.0 - sector } else { p.0 });
+                        (sector, first.min().unwrap())
+                    }
+                };
+                grub::install(&file, dir, start, end)?;
+
+                None
+            }
+        };
+
+        if let Some(name) = mbr_code.filter(|_| args.mbr_bootcode.is_none()) {
+            let code = mbr_bootcode(&dir.join(name))?;
+            let mut file = &file;
+            file.rewind()?;
+            file.write_all(&code)?;
+        }
+    }
+
+    if args.isohybrid {
+        let code = args.mbr_bootcode.as_deref().map(mbr_bootcode).transpose()?;
+        isohybrid::install(
+            &file,
+            total_size,
+            code.as_deref(),
+            args.disk_id.signature(&ctx.random)?,
+        )?;
+    }
+
+    progress.phase("finish");
+
+    Ok(summary)
+}
+
+/// Write the --raw-write files, checking that they stay clear of the `used` ranges and each
+/// other.
+fn write_raw(
+    args: &Args,
+    file: &File,
+    total_size: u64,
+    mut used: Vec<(u64, u64, String)>,
+) -> anyhow::Result<()> {
+    for raw in &args.raw_writes {
+        let mut src = File::open(&raw.path)
+            .map_err(|e| anyhow::anyhow!("cannot open {}: {e}", raw.path.display()))?;
+        let start = raw.offset;
+        let end = start.checked_add(src.metadata()?.len());
+
+        let Some(end) = end.filter(|&end| end <= total_size) else {
+            anyhow::bail!(
+                "{} at {start:#x} ends past the {total_size:#x} byte image",
+                raw.path.display()
+            );
+        };
+
+        if let Some((_, _, what)) = used.iter().find(|&&(s, e, _)| start < e && s < end) {
+            anyhow::bail!(
+                "{} at {start:#x}..{end:#x} overlaps {what}",
+                raw.path.display()
+            );
+        }
+
+        debug!("Raw write {}: {start:x}..{end:x}", raw.path.display());
+
+        let mut file = file;
+        file.seek(io::SeekFrom::Start(start))?;
+        io::copy(&mut src, &mut file)?;
+
+        used.push((start, end, raw.path.display().to_string()));
+    }
+
+    Ok(())
+}
+
+/// Pack the input directory into an archive file, which is the whole image.
+fn write_archive(
+    args: &Args,
+    ctx: &BuildContext,
+    part: &layout::Partition,
+    image_path: &Path,
+    extra_files: &[ExtraFile],
+    progress: &mut progress::Progress,
+) -> anyhow::Result<BuildSummary> {
+    let tree = content_tree(args, part, extra_files, ctx)?;
+
+    if args.label.is_some() {
+        warn!("archives have no volume label, ignoring --label");
+    }
+
+    progress.phase("format");
+
+    let mut on_file = |path: &Path, len| {
+        info!("FILE: {}", path.display());
+        progress.file(path, len);
+    };
+
+    let mut write = |out: &mut dyn Write| match args.filesystem {
+        Filesystem::Initramfs => cpio::write(out, &tree, &mut on_file),
+        Filesystem::Tar => tar::write(out, &tree, &mut on_file),
+        _ => unreachable!("not an archive"),
+    };
+
+    let size = match (&args.compress, args.compression) {
+        (Some(compress), None) if args.streams_archive() => {
+            compress.stream(image_path, args.compress_threads, &[], write)?
+        }
+        (_, None) => {
+            let mut file = io::BufWriter::new(File::create(image_path)?);
+            let size = write(&mut file)?;
+            file.flush()?;
+            size
+        }
+        (_, Some(compression)) => {
+            compression.compress_file(image_path, args.compression_level(), write)?;
+            fs::metadata(image_path)?.len()
+        }
+    };
+
+    progress.phase("finish");
+
+    Ok(BuildSummary {
+        image_size: size,
+        partitions: vec![partmap::Partition {
+            number: 1,
+            name: "part1".into(),
+            start: 0,
+            len: size,
+        }],
+        files: tree.files(),
+        dirs: tree.dirs(),
+    })
+}
+
+/// Format partition `number` of the existing image and fill it, leaving the rest of the image as
+/// it is.
+fn write_into_partition(
+    args: &Args,
+    ctx: &BuildContext,
+    part: &layout::Partition,
+    number: usize,
+    image_path: &Path,
+    extra_files: &[ExtraFile],
+    progress: &mut progress::Progress,
+) -> anyhow::Result<BuildSummary> {
+    let mut file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(image_path)
+        .map_err(|e| anyhow::anyhow!("cannot open {}: {e}", image_path.display()))?;
+
+    let (_, found) = image::read_partitions(&mut file)?;
+    let image_size = file.seek(io::SeekFrom::End(0))?;
+
+    let target = found
+        .iter()
+        .find(|p| p.number == number)
+        .ok_or_else(|| anyhow::anyhow!("no partition {number} in {}", image_path.display()))?;
+
+    if target.start + target.len > image_size {
+        anyhow::bail!("partition {number} extends past the end of the image");
+    }
+
+    debug!(
+        "Partition {number}: start {:x} size {:x}",
+        target.start, target.len
+    );
+
+    let mut summary = BuildSummary {
+        image_size,
+        partitions: found
+            .iter()
+            .map(|p| partmap::Partition {
+                number: p.number as u32,
+                name: format!("part{}", p.number),
+                start: p.start,
+                len: p.len,
+            })
+            .collect(),
+        ..Default::default()
+    };
+
+    progress.phase("format");
+
+    let fs_slice = fscommon::StreamSlice::new(file, target.start, target.start + target.len)?;
+    format(
+        args,
+        ctx,
+        part,
+        Box::new(fs_slice),
+        target.len,
+        extra_files,
+        &mut summary,
+        progress,
+    )?;
+
+    progress.phase("finish");
+
+    Ok(summary)
+}
+
+/// Format a partition with its filesystem and copy its input directory into it.
+#[allow(clippy::too_many_arguments)]
+fn format(
+    args: &Args,
+    ctx: &BuildContext,
+    part: &layout::Partition,
+    fs_slice: Box<dyn ReadWriteSeek>,
+    size: u64,
+    extra_files: &[ExtraFile],
+    summary: &mut BuildSummary,
+    progress: &mut progress::Progress,
+) -> anyhow::Result<()> {
+    let filesystem = part.filesystem.expect("partition has a filesystem");
+
+    if args.sector_size > 512
+        && matches!(
+            filesystem,
+            Filesystem::Ext2
+                | Filesystem::Ext3
+                | Filesystem::Exfat
+                | Filesystem::Iso9660
+                | Filesystem::Romfs
+                | Filesystem::Xfs
+        )
+    {
+        warn!(
+            "{filesystem:?} is laid out for 512 byte sectors, and may not mount from a device with {} byte sectors",
+            args.sector_size
+        );
+    }
+
+    match filesystem {
+        Filesystem::Vfat => vfat::write(
+            args,
+            ctx,
+            part,
+            fs_slice,
+            size,
+            extra_files,
+            summary,
+            progress,
+        )?,
+        Filesystem::Initramfs | Filesystem::Tar => {
+            unreachable!("archives are written by write_archive")
+        }
+        _ => {
+            let tree = content_tree(args, part, extra_files, ctx)?;
+            let writer = filesystem.writer(args, part).unwrap();
+
+            match filesystem {
+                Filesystem::Jffs2 if part.label.is_some() => {
+                    warn!("JFFS2 has no volume label, ignoring --label")
+                }
+                Filesystem::Squashfs if part.label.is_some() => {
+                    warn!("squashfs has no volume label, ignoring --label")
+                }
+                Filesystem::Littlefs if part.label.is_some() => {
+                    warn!("littlefs has no volume label, ignoring --label")
+                }
+                _ => {}
+            }
+
+            let mut fs_slice = fs_slice;
+            writer.write(&mut *fs_slice, size, &tree, ctx, &mut |path, len| {
+                info!("FILE: {}", path.display());
+                progress.file(path, len);
+            })?;
+
+            summary.files += tree.files();
+            summary.dirs += tree.dirs();
+        }
+    }
+
+    Ok(())
+}
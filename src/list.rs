@@ -0,0 +1,131 @@
+//! Listing of the files inside an existing image.
+
+use crate::{image, json};
+use clap::Args;
+use fatfs::{Dir, DirEntry, FileAttributes, ReadWriteSeek};
+use std::io::{self, Write};
+use std::path::PathBuf;
+
+#[derive(Args, Debug)]
+pub struct ListArgs {
+    /// Image to list
+    image: PathBuf,
+    /// Partition to list. Defaults to the first one
+    #[arg(short, long)]
+    partition: Option<usize>,
+    /// Print the attributes, size and modification time of every entry
+    #[arg(short, long)]
+    long: bool,
+    /// Print the entries as a JSON array
+    #[arg(long, conflicts_with = "long")]
+    json: bool,
+}
+
+const ATTRIBUTES: &[(FileAttributes, char, &str)] = &[
+    (FileAttributes::DIRECTORY, 'd', "directory"),
+    (FileAttributes::READ_ONLY, 'r', "read-only"),
+    (FileAttributes::HIDDEN, 'h', "hidden"),
+    (FileAttributes::SYSTEM, 's', "system"),
+    (FileAttributes::ARCHIVE, 'a', "archive"),
+];
+
+fn timestamp(time: fatfs::DateTime) -> String {
+    let (d, t) = (time.date, time.time);
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+        d.year, d.month, d.day, t.hour, t.min, t.sec
+    )
+}
+
+/// Collect all entries under a FAT directory, with their '/' separated paths.
+fn entries<'a, T: ReadWriteSeek>(
+    dir: &Dir<'a, T>,
+    prefix: &str,
+    out: &mut Vec<(String, DirEntry<'a, T>)>,
+) -> io::Result<()> {
+    for entry in dir.iter() {
+        let entry = entry?;
+        let name = entry.file_name();
+
+        if name == "." || name == ".." {
+            continue;
+        }
+
+        let path = format!("{prefix}{name}");
+        let sub = entry.is_dir().then(|| entry.to_dir());
+        out.push((path.clone(), entry));
+
+        if let Some(sub) = sub {
+            entries(&sub, &format!("{path}/"), out)?;
+        }
+    }
+
+    Ok(())
+}
+
+fn print<T: ReadWriteSeek>(
+    args: &ListArgs,
+    list: &[(String, DirEntry<T>)],
+    out: &mut impl Write,
+) -> io::Result<()> {
+    if args.json {
+        let list = list
+            .iter()
+            .map(|(path, e)| {
+                let attributes = ATTRIBUTES
+                    .iter()
+                    .filter(|(a, ..)| e.attributes().contains(*a))
+                    .map(|&(.., name)| name)
+                    .collect::<Vec<_>>();
+
+                json::Value::object()
+                    .with("path", path.as_str())
+                    .with("type", if e.is_dir() { "dir" } else { "file" })
+                    .with("size", (!e.is_dir()).then(|| e.len()))
+                    .with("modified", timestamp(e.modified()))
+                    .with("attributes", attributes)
+            })
+            .collect::<Vec<_>>();
+
+        writeln!(out, "{}", json::Value::from(list))?;
+        return out.flush();
+    }
+
+    for (path, e) in list {
+        let suffix = if e.is_dir() { "/" } else { "" };
+
+        if !args.long {
+            writeln!(out, "{path}{suffix}")?;
+            continue;
+        }
+
+        let attributes = ATTRIBUTES
+            .iter()
+            .map(|&(a, c, _)| if e.attributes().contains(a) { c } else { '-' })
+            .collect::<String>();
+        let size = if e.is_dir() { 0 } else { e.len() };
+
+        writeln!(
+            out,
+            "{attributes} {size:>12} {} {path}{suffix}",
+            timestamp(e.modified())
+        )?;
+    }
+
+    out.flush()
+}
+
+/// Print the files and directories in a partition of the image.
+pub fn run(args: &ListArgs) -> anyhow::Result<()> {
+    let part = image::open_partition(&args.image, false, args.partition)?;
+    let fs = image::open_fat(part)?;
+
+    let mut list = vec![];
+    entries(&fs.root_dir(), "", &mut list)?;
+
+    // The listing is often piped into tools that stop reading once they found what they need
+    match print(args, &list, &mut io::BufWriter::new(io::stdout().lock())) {
+        Err(e) if e.kind() == io::ErrorKind::BrokenPipe => Ok(()),
+        ret => Ok(ret?),
+    }
+}
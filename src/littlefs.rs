@@ -0,0 +1,455 @@
+//! littlefs images, on-disk version 2.0, for microcontroller flash.
+//!
+//! Every directory is a list of metadata pairs, two blocks of which the first holds a single
+//! commit and the second is left erased. A commit is a log of big endian tags, each XORed with
+//! the one before it, ending in a CRC tag padded to the program size. The root directory starts
+//! in blocks 0 and 1 with the superblock as its first entry, and the last pair of each directory
+//! points to the first of the next, which is the list the allocator walks to find used blocks.
+//!
+//! Files of up to an eighth of a block are stored inline in their directory, others in blocks of
+//! a skip-list, where block `i` starts with pointers to blocks `i - 2^k` for `2^k` dividing `i`.
+//! There are no symbolic links, permissions or times. Space without data is left as 0xff, as
+//! erased flash reads.
+
+use crate::tree::{Kind, Tree};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+const VERSION: u32 = 0x0002_0000;
+const MAGIC: &[u8; 8] = b"littlefs";
+const NAME_MAX: usize = 255;
+const FILE_MAX: u32 = i32::MAX as u32;
+const ATTR_MAX: u32 = 0x3fe;
+/// Smallest block size littlefs accepts
+const MIN_BLOCK_SIZE: u32 = 128;
+
+const TYPE_REG: u32 = 0x001;
+const TYPE_DIR: u32 = 0x002;
+const TYPE_SUPERBLOCK: u32 = 0x0ff;
+const TYPE_DIRSTRUCT: u32 = 0x200;
+const TYPE_INLINESTRUCT: u32 = 0x201;
+const TYPE_CTZSTRUCT: u32 = 0x202;
+const TYPE_CRC: u32 = 0x500;
+const TYPE_SOFTTAIL: u32 = 0x600;
+const TYPE_HARDTAIL: u32 = 0x601;
+
+/// Id of tags that do not belong to an entry
+const NO_ID: u32 = 0x3ff;
+/// Largest size a tag can describe
+const MAX_TAG_SIZE: u64 = 0x3fe;
+/// Bytes of a tag
+const TAG: u64 = 4;
+
+#[derive(Clone, Debug)]
+pub struct Options {
+    /// Erase block size of the flash
+    pub block_size: u32,
+    /// Commits are padded to a multiple of this, the smallest unit the flash is programmed in
+    pub prog_size: u32,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            block_size: 4096,
+            prog_size: 16,
+        }
+    }
+}
+
+fn tag(ty: u32, id: u32, size: u64) -> u32 {
+    ty << 20 | id << 10 | size as u32
+}
+
+/// CRC32 as littlefs computes it, starting from `crc` without inverting it at the end.
+fn crc(crc: u32, data: &[u8]) -> u32 {
+    !crc::crc32::update(!crc, &crc::crc32::IEEE_TABLE, data)
+}
+
+/// Pointers at the start of block `i` of a file.
+fn ctz_pointers(i: u64) -> u64 {
+    if i == 0 {
+        0
+    } else {
+        i.trailing_zeros() as u64 + 1
+    }
+}
+
+/// Blocks of a file of `len` bytes stored outside its directory.
+fn ctz_blocks(len: u64, block_size: u64) -> u64 {
+    let (mut blocks, mut remaining) = (0, len);
+    while remaining > 0 {
+        remaining -= remaining.min(block_size - TAG * ctz_pointers(blocks));
+        blocks += 1;
+    }
+    blocks
+}
+
+/// Bytes of the tags of an entry in its directory.
+fn entry_len(tree: &Tree, idx: usize, inline_max: u64) -> u64 {
+    let node = &tree.nodes[idx];
+    let data = match node.kind {
+        Kind::File { len, .. } if len <= inline_max => len,
+        _ => 8,
+    };
+    TAG + node.name.len() as u64 + TAG + data
+}
+
+/// Length of a commit holding `len` bytes of tags, including the revision count, the tail and
+/// the CRC.
+fn commit_len(len: u64, prog_size: u64) -> u64 {
+    (4 + len + TAG + 8 + TAG + 4).next_multiple_of(prog_size)
+}
+
+struct Pair {
+    /// Directory the pair belongs to
+    dir: usize,
+    /// Entries of the pair, in the order of their ids
+    entries: Vec<usize>,
+    /// Block holding the commit, which the erased block follows
+    block: u32,
+}
+
+struct Layout {
+    /// Metadata pairs, in the order of the list threading them
+    pairs: Vec<Pair>,
+    /// First metadata block of each directory
+    dir_block: Vec<u32>,
+    /// First data block of each file stored outside its directory
+    file_block: Vec<u32>,
+    inline_max: u64,
+    blocks: u64,
+}
+
+impl Layout {
+    fn new(tree: &Tree, opts: &Options) -> anyhow::Result<Self> {
+        let block_size = opts.block_size as u64;
+        let prog_size = opts.prog_size as u64;
+        if opts.block_size < MIN_BLOCK_SIZE || !opts.block_size.is_power_of_two() {
+            anyhow::bail!(
+                "littlefs block size must be a power of two of at least {MIN_BLOCK_SIZE} bytes"
+            );
+        }
+        if !opts.prog_size.is_power_of_two() || opts.prog_size > opts.block_size {
+            anyhow::bail!("littlefs program size must be a power of two up to the block size");
+        }
+
+        for node in &tree.nodes[1..] {
+            if node.name.len() > NAME_MAX {
+                anyhow::bail!(
+                    "littlefs names are limited to {NAME_MAX} bytes: {}",
+                    node.path.display()
+                );
+            }
+            match node.kind {
+                Kind::File { len, .. } if len > FILE_MAX as u64 => {
+                    anyhow::bail!(
+                        "littlefs files are limited to 2GiB: {}",
+                        node.path.display()
+                    )
+                }
+                Kind::Symlink(_) => {
+                    anyhow::bail!("littlefs has no symbolic links: {}", node.path.display())
+                }
+                _ => {}
+            }
+        }
+
+        let inline_max = MAX_TAG_SIZE.min(block_size / 8);
+        let superblock_len = TAG + MAGIC.len() as u64 + TAG + 24;
+
+        // Fill each pair up to half a block, leaving room for commits appended on the device
+        let mut pairs = vec![];
+        for (idx, node) in tree.nodes.iter().enumerate() {
+            let Kind::Dir(children) = &node.kind else {
+                continue;
+            };
+
+            let mut pair = Pair {
+                dir: idx,
+                entries: vec![],
+                block: 0,
+            };
+            let mut len = if idx == 0 { superblock_len } else { 0 };
+
+            for &c in children {
+                let entry = entry_len(tree, c, inline_max);
+                if !pair.entries.is_empty() && commit_len(len + entry, prog_size) > block_size / 2 {
+                    pairs.push(pair);
+                    pair = Pair {
+                        dir: idx,
+                        entries: vec![],
+                        block: 0,
+                    };
+                    len = 0;
+                }
+                if commit_len(len + entry, prog_size) > block_size {
+                    anyhow::bail!(
+                        "{} does not fit in a littlefs block of {block_size} bytes",
+                        tree.nodes[c].path.display()
+                    );
+                }
+                pair.entries.push(c);
+                len += entry;
+            }
+            pairs.push(pair);
+        }
+
+        let mut dir_block = vec![0; tree.nodes.len()];
+        let mut next = 0u64;
+        for i in 0..pairs.len() {
+            pairs[i].block = next as u32;
+            if i == 0 || pairs[i - 1].dir != pairs[i].dir {
+                dir_block[pairs[i].dir] = pairs[i].block;
+            }
+            next += 2;
+        }
+
+        let mut file_block = vec![0; tree.nodes.len()];
+        for (idx, node) in tree.nodes.iter().enumerate() {
+            if let Kind::File { len, .. } = node.kind {
+                if len > inline_max {
+                    file_block[idx] = next as u32;
+                    next += ctz_blocks(len, block_size);
+                }
+            }
+        }
+
+        if next > u32::MAX as u64 {
+            anyhow::bail!("littlefs images are limited to 2^32 blocks");
+        }
+
+        Ok(Self {
+            pairs,
+            dir_block,
+            file_block,
+            inline_max,
+            blocks: next,
+        })
+    }
+}
+
+/// Smallest filesystem size the tree fits in.
+pub fn estimate_size(tree: &Tree, opts: &Options) -> anyhow::Result<u64> {
+    Ok(Layout::new(tree, opts)?.blocks * opts.block_size as u64)
+}
+
+/// A commit to a metadata block.
+struct Commit {
+    block: Vec<u8>,
+    off: usize,
+    /// Previous tag, which the next one is XORed with
+    ptag: u32,
+    crc: u32,
+}
+
+impl Commit {
+    fn new(block_size: u32, rev: u32) -> Self {
+        let mut c = Self {
+            block: vec![0xff; block_size as usize],
+            off: 0,
+            ptag: !0,
+            crc: !0,
+        };
+        c.prog(&rev.to_le_bytes());
+        c
+    }
+
+    fn prog(&mut self, data: &[u8]) {
+        self.block[self.off..self.off + data.len()].copy_from_slice(data);
+        self.crc = crc(self.crc, data);
+        self.off += data.len();
+    }
+
+    fn attr(&mut self, tag: u32, data: &[u8]) {
+        self.prog(&(tag ^ self.ptag).to_be_bytes());
+        self.prog(data);
+        self.ptag = tag;
+    }
+
+    /// End the commit with CRC tags padding it to a multiple of `prog_size`. The padding is not
+    /// covered by the CRC, and left erased.
+    fn finish(mut self, prog_size: u32) -> Vec<u8> {
+        let end = (self.off as u64 + TAG + 4).next_multiple_of(prog_size as u64) as usize;
+
+        while self.off < end {
+            let off = self.off + TAG as usize;
+            let mut noff = (end - off).min(MAX_TAG_SIZE as usize) + off;
+            if noff < end {
+                noff = noff.min(end - 2 * TAG as usize);
+            }
+
+            // The word after the commit is erased, so the next tag reads as invalid
+            let tag = tag(TYPE_CRC, NO_ID, (noff - off) as u64);
+            let stored = (tag ^ self.ptag).to_be_bytes();
+            self.crc = crc(self.crc, &stored);
+            self.block[self.off..off].copy_from_slice(&stored);
+            self.block[off..off + 4].copy_from_slice(&self.crc.to_le_bytes());
+
+            self.off = noff;
+            self.ptag = tag;
+            self.crc = !0;
+        }
+
+        self.block
+    }
+}
+
+fn write_erased<W: Write>(out: &mut W, len: u64) -> io::Result<()> {
+    io::copy(&mut io::repeat(0xff).take(len), out)?;
+    Ok(())
+}
+
+/// Write the image into `out`, which spans `size` bytes.
+///
+/// `on_file` is called with the image path and length of every file written.
+pub fn write<W: Write + Seek>(
+    out: &mut W,
+    size: u64,
+    tree: &Tree,
+    opts: &Options,
+    on_file: &mut dyn FnMut(&Path, u64),
+) -> anyhow::Result<()> {
+    let layout = Layout::new(tree, opts)?;
+    let block_size = opts.block_size as u64;
+    let block_count = (size / block_size).min(u32::MAX as u64);
+
+    if layout.blocks > block_count {
+        anyhow::bail!(
+            "littlefs image needs {} blocks of {block_size} bytes, but only {block_count} are available",
+            layout.blocks
+        );
+    }
+
+    out.seek(SeekFrom::Start(0))?;
+
+    for (i, pair) in layout.pairs.iter().enumerate() {
+        let mut commit = Commit::new(opts.block_size, 1);
+        let mut id = 0;
+
+        if i == 0 {
+            let mut sb = Vec::with_capacity(24);
+            for v in [
+                VERSION,
+                opts.block_size,
+                block_count as u32,
+                NAME_MAX as u32,
+                FILE_MAX,
+                ATTR_MAX,
+            ] {
+                sb.extend(v.to_le_bytes());
+            }
+            commit.attr(tag(TYPE_SUPERBLOCK, 0, MAGIC.len() as u64), MAGIC);
+            commit.attr(tag(TYPE_INLINESTRUCT, 0, sb.len() as u64), &sb);
+            id += 1;
+        }
+
+        for &c in &pair.entries {
+            let node = &tree.nodes[c];
+            let name = node.name.as_bytes();
+
+            match &node.kind {
+                Kind::Dir(_) => {
+                    let block = layout.dir_block[c];
+                    let mut pair = [0u8; 8];
+                    pair[0..4].copy_from_slice(&block.to_le_bytes());
+                    pair[4..8].copy_from_slice(&(block + 1).to_le_bytes());
+                    commit.attr(tag(TYPE_DIR, id, name.len() as u64), name);
+                    commit.attr(tag(TYPE_DIRSTRUCT, id, 8), &pair);
+                }
+                Kind::File { source, len } if *len <= layout.inline_max => {
+                    let mut data = vec![0; *len as usize];
+                    source.open()?.read_exact(&mut data).map_err(|e| {
+                        anyhow::anyhow!("{} changed while being copied: {e}", node.path.display())
+                    })?;
+                    commit.attr(tag(TYPE_REG, id, name.len() as u64), name);
+                    commit.attr(tag(TYPE_INLINESTRUCT, id, *len), &data);
+                }
+                Kind::File { len, .. } => {
+                    let head = layout.file_block[c] as u64 + ctz_blocks(*len, block_size) - 1;
+                    let mut ctz = [0u8; 8];
+                    ctz[0..4].copy_from_slice(&(head as u32).to_le_bytes());
+                    ctz[4..8].copy_from_slice(&(*len as u32).to_le_bytes());
+                    commit.attr(tag(TYPE_REG, id, name.len() as u64), name);
+                    commit.attr(tag(TYPE_CTZSTRUCT, id, 8), &ctz);
+                }
+                Kind::Symlink(_) => unreachable!("littlefs has no symbolic links"),
+            }
+            id += 1;
+        }
+
+        // Pairs of the same directory are split by a hard tail, directories linked by a soft one
+        if let Some(next) = layout.pairs.get(i + 1) {
+            let ty = if next.dir == pair.dir {
+                TYPE_HARDTAIL
+            } else {
+                TYPE_SOFTTAIL
+            };
+            let mut tail = [0u8; 8];
+            tail[0..4].copy_from_slice(&next.block.to_le_bytes());
+            tail[4..8].copy_from_slice(&(next.block + 1).to_le_bytes());
+            commit.attr(tag(ty, NO_ID, 8), &tail);
+        }
+
+        out.write_all(&commit.finish(opts.prog_size))?;
+        write_erased(out, block_size)?;
+    }
+
+    let mut block = vec![0u8; block_size as usize];
+    for (idx, node) in tree.nodes.iter().enumerate() {
+        let Kind::File { source, len } = &node.kind else {
+            continue;
+        };
+        if *len <= layout.inline_max {
+            on_file(&node.path, *len);
+            continue;
+        }
+
+        let first = layout.file_block[idx] as u64;
+        let mut file = source.open()?;
+        let mut remaining = *len;
+        let mut i = 0;
+        while remaining > 0 {
+            let pointers = ctz_pointers(i);
+            for k in 0..pointers {
+                let target = (first + i - (1 << k)) as u32;
+                let at = (TAG * k) as usize;
+                block[at..at + 4].copy_from_slice(&target.to_le_bytes());
+            }
+
+            let start = (TAG * pointers) as usize;
+            let n = remaining.min(block_size - start as u64) as usize;
+            file.read_exact(&mut block[start..start + n]).map_err(|e| {
+                anyhow::anyhow!("{} changed while being copied: {e}", node.path.display())
+            })?;
+            block[start + n..].fill(0xff);
+            out.write_all(&block)?;
+
+            remaining -= n as u64;
+            i += 1;
+        }
+
+        on_file(&node.path, *len);
+    }
+
+    write_erased(out, size - layout.blocks * block_size)?;
+
+    Ok(())
+}
+
+impl crate::FsWriter for Options {
+    fn estimate_size(&self, tree: &Tree) -> anyhow::Result<u64> {
+        estimate_size(tree, self)
+    }
+
+    fn write(
+        &self,
+        mut out: &mut dyn fatfs::ReadWriteSeek,
+        size: u64,
+        tree: &Tree,
+        _ctx: &crate::BuildContext,
+        on_file: &mut dyn FnMut(&Path, u64),
+    ) -> anyhow::Result<()> {
+        write(&mut out, size, tree, self, on_file)
+    }
+}
@@ -0,0 +1,563 @@
+//! Conversion of the raw image into the final output format.
+
+use crate::{qcow2, vdi, vhd, vhdx, vmdk};
+use clap::ValueEnum;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufWriter, Read, Write};
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::str::FromStr;
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OutputFormat {
+    /// Plain disk image
+    Raw,
+    /// Intel HEX
+    Ihex,
+    /// Motorola S-record
+    Srec,
+    /// USB Flashing Format
+    Uf2,
+    /// QEMU disk image, allocating only clusters that are not all zeroes
+    Qcow2,
+    /// Virtual PC and Hyper-V disk image, which Azure takes as a fixed image of a whole number
+    /// of MiB
+    Vhd,
+    /// Hyper-V disk image
+    Vhdx,
+    /// VMware disk image
+    Vmdk,
+    /// VirtualBox disk image
+    Vdi,
+}
+
+/// Allocation of disk image containers.
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Subformat {
+    /// Allocate every block, so the container is as large as the disk
+    Fixed,
+    /// Allocate only blocks that are not all zeroes. Monolithic sparse for vmdk
+    #[default]
+    Dynamic,
+    /// Compressed and written front to back, for vmdk images in OVAs
+    StreamOptimized,
+}
+
+/// Options for formats that address the image in a target memory map, and for disk image
+/// containers.
+#[derive(Clone, Debug)]
+pub struct Options {
+    pub base_address: u64,
+    /// UF2 family ID of the target board
+    pub family_id: Option<u32>,
+    /// Level to compress the clusters of qcow2 images at, if they are compressed
+    pub compress: Option<u8>,
+    pub subformat: Subformat,
+    /// File name of the output, which vmdk images refer to themselves by
+    pub name: String,
+}
+
+/// Compressor of the whole output file, run as its command line tool.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Compressor {
+    Gzip,
+    Zstd,
+    Xz,
+}
+
+impl Compressor {
+    /// Compressor of a file, from the suffix of its name such as `.gz` or `.tzst`.
+    pub fn from_suffix(path: &Path) -> Option<Self> {
+        let name = path.file_name()?.to_str()?;
+        let (_, suffix) = name.rsplit_once('.')?;
+
+        match suffix {
+            "gz" | "tgz" => Some(Self::Gzip),
+            "zst" | "tzst" => Some(Self::Zstd),
+            "xz" | "txz" => Some(Self::Xz),
+            _ => None,
+        }
+    }
+
+    /// Decompress `src` with the tool, handing its output to `read` as it streams.
+    pub fn decompress<T>(
+        self,
+        src: &Path,
+        read: impl FnOnce(&mut dyn Read) -> anyhow::Result<T>,
+    ) -> anyhow::Result<T> {
+        let tool = self.to_possible_value().unwrap();
+        let tool = tool.get_name();
+
+        let mut child = Command::new(tool)
+            .args(["-d", "-c"])
+            .stdin(File::open(src)?)
+            .stdout(Stdio::piped())
+            .spawn()
+            .map_err(|e| anyhow::anyhow!("unable to run {tool}: {e}"))?;
+
+        let ret = read(child.stdout.as_mut().unwrap());
+        drop(child.stdout.take());
+        let status = child.wait()?;
+
+        if !status.success() {
+            anyhow::bail!("{tool} failed ({status})");
+        }
+
+        ret
+    }
+}
+
+/// Compression of the output file, as `COMPRESSOR[:LEVEL]`.
+#[derive(Clone, Copy, Debug)]
+pub struct Compress {
+    pub compressor: Compressor,
+    /// Level passed to the tool, which picks its default if not set
+    pub level: Option<u32>,
+}
+
+impl FromStr for Compress {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        let (name, level) = match s.split_once(':') {
+            Some((name, level)) => {
+                let level = level
+                    .parse()
+                    .map_err(|_| format!("invalid compression level `{level}`"))?;
+                (name, Some(level))
+            }
+            None => (s, None),
+        };
+
+        Ok(Self {
+            compressor: Compressor::from_str(name, true)
+                .map_err(|_| format!("unknown compressor `{name}`"))?,
+            level,
+        })
+    }
+}
+
+impl fmt::Display for Compress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = self.compressor.to_possible_value().unwrap();
+        write!(f, "{}", name.get_name())?;
+        if let Some(level) = self.level {
+            write!(f, ":{level}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Whether the program `name` is in a directory of the `PATH`.
+pub fn on_path(name: &str) -> bool {
+    std::env::var_os("PATH")
+        .is_some_and(|path| std::env::split_paths(&path).any(|dir| dir.join(name).is_file()))
+}
+
+impl Compress {
+    /// Program compressing on `threads` threads, and its thread option.
+    fn tool(&self, threads: usize) -> (&'static str, Option<String>) {
+        match self.compressor {
+            Compressor::Gzip if threads != 1 && on_path("pigz") => {
+                ("pigz", (threads > 0).then(|| format!("-p{threads}")))
+            }
+            Compressor::Gzip => ("gzip", None),
+            Compressor::Zstd => ("zstd", Some(format!("-T{threads}"))),
+            Compressor::Xz => ("xz", Some(format!("-T{threads}"))),
+        }
+    }
+
+    /// Check that the compressor is installed, before spending a build on it.
+    pub fn check(&self, threads: usize) -> anyhow::Result<()> {
+        let (tool, _) = self.tool(threads);
+        if !on_path(tool) {
+            anyhow::bail!("--compress {self} runs `{tool}`, which is not in the PATH");
+        }
+        Ok(())
+    }
+
+    /// Command compressing its standard input to its standard output on `threads` threads.
+    fn command(&self, threads: usize) -> (&'static str, Command) {
+        let (tool, thread_arg) = self.tool(threads);
+
+        let mut cmd = Command::new(tool);
+        if let Some(level) = self.level {
+            cmd.arg(format!("-{level}"));
+        }
+        cmd.args(thread_arg).args(["-q", "-c"]);
+        (tool, cmd)
+    }
+
+    /// Compress `src` into `dst` on `threads` threads, 0 for one per CPU. gzip output is only
+    /// compressed in parallel by `pigz`, if it is installed.
+    pub fn apply(&self, src: &Path, dst: &Path, threads: usize) -> anyhow::Result<()> {
+        let (tool, mut cmd) = self.command(threads);
+
+        let status = cmd
+            .stdin(File::open(src)?)
+            .stdout(File::create(dst)?)
+            .status()
+            .map_err(|e| anyhow::anyhow!("unable to run {tool}: {e}"))?;
+
+        if !status.success() {
+            anyhow::bail!("{tool} failed ({status})");
+        }
+
+        Ok(())
+    }
+
+    /// Compress what `write` writes into `dst` as it streams, with `args` added to the command
+    /// line.
+    pub fn stream<T>(
+        &self,
+        dst: &Path,
+        threads: usize,
+        args: &[String],
+        write: impl FnOnce(&mut dyn Write) -> anyhow::Result<T>,
+    ) -> anyhow::Result<T> {
+        let (tool, mut cmd) = self.command(threads);
+
+        let mut child = cmd
+            .args(args)
+            .stdin(Stdio::piped())
+            .stdout(File::create(dst)?)
+            .spawn()
+            .map_err(|e| anyhow::anyhow!("unable to run {tool}: {e}"))?;
+
+        let mut stdin = BufWriter::new(child.stdin.take().unwrap());
+        let ret = write(&mut stdin).and_then(|ret| Ok(stdin.flush().map(|_| ret)?));
+        drop(stdin);
+        let status = child.wait()?;
+
+        if !status.success() {
+            anyhow::bail!("{tool} failed ({status})");
+        }
+
+        ret
+    }
+
+    /// Compress `data` in memory with `args` added to the command line. One thread is used, so
+    /// the output does not depend on the host.
+    pub fn compress(&self, data: &[u8], args: &[String]) -> anyhow::Result<Vec<u8>> {
+        let (tool, mut cmd) = self.command(1);
+
+        let mut child = cmd
+            .args(args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .map_err(|e| anyhow::anyhow!("unable to run {tool}: {e}"))?;
+
+        // The input is written from another thread, as the tool may fill the pipe of its output
+        // before taking all of it
+        let mut stdin = child.stdin.take().unwrap();
+        let (written, output) = std::thread::scope(|s| {
+            let writer = s.spawn(move || stdin.write_all(data));
+            let output = child.wait_with_output();
+            (writer.join().unwrap(), output)
+        });
+        let output = output?;
+
+        if !output.status.success() {
+            anyhow::bail!("{tool} failed ({})", output.status);
+        }
+        written?;
+
+        Ok(output.stdout)
+    }
+}
+
+/// Parse a UF2 family ID, either as hexadecimal or as a known family name.
+pub fn parse_family_id(s: &str) -> Result<u32, String> {
+    let id = match s.to_ascii_lowercase().as_str() {
+        "rp2040" => 0xe48bff56,
+        "rp2350-arm-s" => 0xe48bff59,
+        "samd21" => 0x68ed2b88,
+        "samd51" => 0x55114460,
+        "nrf52840" => 0xada52840,
+        "stm32f4" => 0x57755a57,
+        "esp32s2" => 0xbfdd4eee,
+        "esp32s3" => 0xc47e5767,
+        s => {
+            let hex = s.strip_prefix("0x").unwrap_or(s);
+            return u32::from_str_radix(hex, 16).map_err(|_| format!("unknown UF2 family `{s}`"));
+        }
+    };
+
+    Ok(id)
+}
+
+impl OutputFormat {
+    /// Whether the image has to be built at a separate path and converted afterwards.
+    pub fn needs_conversion(&self) -> bool {
+        *self != Self::Raw
+    }
+
+    /// Whether images of the format can be allocated as `subformat`.
+    pub fn supports(&self, subformat: Subformat) -> bool {
+        match self {
+            Self::Vhd | Self::Vhdx | Self::Vdi => subformat != Subformat::StreamOptimized,
+            Self::Vmdk => subformat != Subformat::Fixed,
+            _ => false,
+        }
+    }
+
+    /// Whether the format is written front to back, so it can be piped into a compressor rather
+    /// than a file.
+    pub fn is_sequential(&self) -> bool {
+        matches!(self, Self::Raw | Self::Ihex | Self::Srec | Self::Uf2)
+    }
+
+    /// Write the raw image into `out` in a format written front to back.
+    pub fn write_sequential(
+        &self,
+        raw: &Path,
+        mut out: &mut dyn Write,
+        opts: &Options,
+    ) -> anyhow::Result<()> {
+        let len = std::fs::metadata(raw)?.len();
+
+        let fits = opts
+            .base_address
+            .checked_add(len)
+            .is_some_and(|end| end <= 1 << 32);
+        if !fits && matches!(self, Self::Ihex | Self::Srec | Self::Uf2) {
+            anyhow::bail!(
+                "image does not fit in 32-bit address space at base {:#x}",
+                opts.base_address
+            );
+        }
+
+        let mut input = io::BufReader::new(File::open(raw)?);
+        let base = opts.base_address as u32;
+
+        match self {
+            Self::Raw => {
+                io::copy(&mut input, out)?;
+            }
+            Self::Ihex => write_ihex(input, &mut out, base)?,
+            Self::Srec => write_srec(input, &mut out, base, len)?,
+            Self::Uf2 => write_uf2(input, &mut out, base, len, opts.family_id)?,
+            _ => unreachable!("{self:?} is not written front to back"),
+        }
+
+        Ok(())
+    }
+
+    pub fn convert(
+        &self,
+        raw: &Path,
+        out: &Path,
+        opts: &Options,
+        ctx: &crate::BuildContext,
+    ) -> anyhow::Result<()> {
+        let len = std::fs::metadata(raw)?.len();
+        let input = io::BufReader::new(File::open(raw)?);
+        let mut output = BufWriter::new(File::create(out)?);
+
+        match self {
+            Self::Raw | Self::Ihex | Self::Srec | Self::Uf2 => {
+                self.write_sequential(raw, &mut output, opts)?
+            }
+            Self::Qcow2 => qcow2::write(input, &mut output, len, opts.compress)?,
+            Self::Vhd => vhd::write(
+                input,
+                &mut output,
+                len,
+                opts.subformat == Subformat::Dynamic,
+                ctx,
+            )?,
+            Self::Vhdx => vhdx::write(
+                input,
+                &mut output,
+                len,
+                opts.subformat == Subformat::Dynamic,
+                ctx,
+            )?,
+            Self::Vmdk => vmdk::write(
+                input,
+                &mut output,
+                len,
+                &opts.name,
+                opts.subformat == Subformat::StreamOptimized,
+                ctx,
+            )?,
+            Self::Vdi => vdi::write(
+                input,
+                &mut output,
+                len,
+                opts.subformat == Subformat::Dynamic,
+                ctx,
+            )?,
+        }
+
+        output.flush()?;
+
+        Ok(())
+    }
+}
+
+/// Call `cb` with consecutive chunks of at most `size` bytes and their offsets.
+pub fn for_each_chunk(
+    mut input: impl Read,
+    size: usize,
+    mut cb: impl FnMut(u64, &[u8]) -> io::Result<()>,
+) -> io::Result<()> {
+    let mut buf = vec![0; size];
+    let mut off = 0;
+
+    loop {
+        let mut filled = 0;
+
+        while filled < size {
+            match input.read(&mut buf[filled..])? {
+                0 => break,
+                n => filled += n,
+            }
+        }
+
+        if filled == 0 {
+            return Ok(());
+        }
+
+        cb(off, &buf[..filled])?;
+        off += filled as u64;
+    }
+}
+
+fn ihex_record(out: &mut impl Write, addr: u16, ty: u8, data: &[u8]) -> io::Result<()> {
+    let mut sum = (data.len() as u8)
+        .wrapping_add((addr >> 8) as u8)
+        .wrapping_add(addr as u8)
+        .wrapping_add(ty);
+
+    write!(out, ":{:02X}{addr:04X}{ty:02X}", data.len())?;
+
+    for b in data {
+        sum = sum.wrapping_add(*b);
+        write!(out, "{b:02X}")?;
+    }
+
+    writeln!(out, "{:02X}", sum.wrapping_neg())
+}
+
+fn write_ihex(input: impl Read, out: &mut impl Write, base: u32) -> io::Result<()> {
+    let mut upper = None;
+
+    for_each_chunk(input, 16, |off, data| {
+        let addr = base + off as u32;
+
+        // Chunks never cross a 64k boundary as long as the base is 16 byte aligned. Split them
+        // otherwise.
+        let split = (0x10000 - (addr & 0xffff) as usize).min(data.len());
+
+        // Images may end at 4 GiB, so the address after the split is only computed if there is
+        // anything to place there
+        let (first, rest) = data.split_at(split);
+        let second = (!rest.is_empty()).then(|| (addr + split as u32, rest));
+
+        for (addr, data) in std::iter::once((addr, first)).chain(second) {
+            if upper != Some(addr >> 16) {
+                upper = Some(addr >> 16);
+                ihex_record(out, 0, 4, &((addr >> 16) as u16).to_be_bytes())?;
+            }
+
+            ihex_record(out, addr as u16, 0, data)?;
+        }
+
+        Ok(())
+    })?;
+
+    ihex_record(out, 0, 1, &[])
+}
+
+fn srec_record(
+    out: &mut impl Write,
+    ty: u8,
+    addr: u32,
+    addr_len: usize,
+    data: &[u8],
+) -> io::Result<()> {
+    let count = (addr_len + data.len() + 1) as u8;
+    let addr_bytes = &addr.to_be_bytes()[4 - addr_len..];
+
+    let mut sum = count;
+    write!(out, "S{ty}{count:02X}")?;
+
+    for b in addr_bytes.iter().chain(data) {
+        sum = sum.wrapping_add(*b);
+        write!(out, "{b:02X}")?;
+    }
+
+    writeln!(out, "{:02X}", !sum)
+}
+
+fn write_srec(input: impl Read, out: &mut impl Write, base: u32, len: u64) -> io::Result<()> {
+    let end = base as u64 + len;
+
+    // Use the shortest address field that covers the whole image
+    let (data_ty, term_ty, addr_len) = if end <= 1 << 16 {
+        (1, 9, 2)
+    } else if end <= 1 << 24 {
+        (2, 8, 3)
+    } else {
+        (3, 7, 4)
+    };
+
+    srec_record(out, 0, 0, 2, b"mkimg")?;
+
+    let mut records = 0u32;
+
+    for_each_chunk(input, 32, |off, data| {
+        records += 1;
+        srec_record(out, data_ty, base + off as u32, addr_len, data)
+    })?;
+
+    if records <= 0xffff {
+        srec_record(out, 5, records, 2, &[])?;
+    } else {
+        srec_record(out, 6, records, 3, &[])?;
+    }
+
+    srec_record(out, term_ty, base, addr_len, &[])
+}
+
+const UF2_PAYLOAD: usize = 256;
+
+fn write_uf2(
+    input: impl Read,
+    out: &mut impl Write,
+    base: u32,
+    len: u64,
+    family_id: Option<u32>,
+) -> io::Result<()> {
+    let num_blocks = len.div_ceil(UF2_PAYLOAD as u64) as u32;
+    let mut block_no = 0;
+
+    for_each_chunk(input, UF2_PAYLOAD, |off, data| {
+        let mut block = [0u8; 512];
+
+        let words = [
+            0x0a324655,
+            0x9e5d5157,
+            // Family ID present flag
+            if family_id.is_some() { 0x2000 } else { 0 },
+            base + off as u32,
+            UF2_PAYLOAD as u32,
+            block_no,
+            num_blocks,
+            family_id.unwrap_or(0),
+        ];
+
+        for (b, w) in block.chunks_exact_mut(4).zip(words) {
+            b.copy_from_slice(&w.to_le_bytes());
+        }
+
+        block[32..32 + data.len()].copy_from_slice(data);
+        block[508..].copy_from_slice(&0x0ab16f30u32.to_le_bytes());
+
+        block_no += 1;
+        out.write_all(&block)
+    })
+}
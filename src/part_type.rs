@@ -0,0 +1,248 @@
+//! GPT partition types, including the aliases of the Discoverable Partitions Specification, and
+//! MBR system IDs.
+
+use clap::ValueEnum;
+use std::fmt;
+use std::str::FromStr;
+
+/// Architecture the per-architecture partition types are resolved for.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Arch {
+    X86,
+    #[value(name = "x86-64")]
+    X86_64,
+    Arm,
+    Arm64,
+    Ia64,
+    Loongarch64,
+    Riscv32,
+    Riscv64,
+    Ppc64le,
+    S390x,
+}
+
+impl Arch {
+    /// Architecture mkimg is running on, if it has discoverable partition types.
+    pub fn host() -> Option<Self> {
+        let arch = match std::env::consts::ARCH {
+            "x86" => Self::X86,
+            "x86_64" => Self::X86_64,
+            "arm" => Self::Arm,
+            "aarch64" => Self::Arm64,
+            "loongarch64" => Self::Loongarch64,
+            "riscv32" => Self::Riscv32,
+            "riscv64" => Self::Riscv64,
+            "powerpc64" if cfg!(target_endian = "little") => Self::Ppc64le,
+            "s390x" => Self::S390x,
+            _ => return None,
+        };
+
+        Some(arch)
+    }
+
+    fn root(&self) -> &'static str {
+        match self {
+            Self::X86 => "44479540-F297-41B2-9AF7-D131D5F0458A",
+            Self::X86_64 => "4F68BCE3-E8CD-4DB1-96E7-FBCAF984B709",
+            Self::Arm => "69DAD710-2CE4-4E3C-B16C-21A1D49ABED3",
+            Self::Arm64 => "B921B045-1DF0-41C3-AF44-4C6F280D3FAE",
+            Self::Ia64 => "993D8D3D-F80E-4225-855A-9DAF8ED7EA97",
+            Self::Loongarch64 => "77055800-792C-4F94-B39A-98C91B762BB6",
+            Self::Riscv32 => "60D5A7FE-8E7D-435C-B714-3DD8162144E1",
+            Self::Riscv64 => "72EC70A6-CF74-40E6-BD49-4BDA08E8F224",
+            Self::Ppc64le => "C31C45E6-3F39-412E-80FB-4809C4980599",
+            Self::S390x => "5EEAD9A9-FE09-4A1E-A1D7-520D00531306",
+        }
+    }
+
+    fn usr(&self) -> &'static str {
+        match self {
+            Self::X86 => "75250D76-8CC6-458E-BD66-BD47CC81A812",
+            Self::X86_64 => "8484680C-9521-48C6-9C11-B0720656F69E",
+            Self::Arm => "7D0359A3-02B3-4F0A-865C-654403E70625",
+            Self::Arm64 => "B0E01050-EE5F-4390-949A-9101B17104E9",
+            Self::Ia64 => "4301D2A6-4E3B-4B2A-BB94-9E0B2C4225EA",
+            Self::Loongarch64 => "E611C702-575C-4CBE-9A46-434FA0BF7E3F",
+            Self::Riscv32 => "B933FB22-5C3F-4F91-AF90-E2BB0FA50702",
+            Self::Riscv64 => "BEAEC34B-8442-439B-A40B-984381ED097D",
+            Self::Ppc64le => "15BB03AF-77E7-4D4A-B12B-C0D084F7491C",
+            Self::S390x => "8A4F5770-50AA-4ED3-874A-99B710DB6FEA",
+        }
+    }
+}
+
+/// Partition type, given as an alias such as `esp` or `root-arm64`, or as a type GUID.
+///
+/// `root` and `usr` without an architecture are resolved for the one given with `--arch`.
+/// Aliases may also be written with a `linux-` prefix, as in `linux-root-x86-64`, and `linux-fs`
+/// is the same as `generic`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum GptType {
+    Fixed(&'static str, &'static str),
+    Root(Option<Arch>),
+    Usr(Option<Arch>),
+    Guid(&'static str),
+}
+
+const FIXED: &[(&str, &str)] = &[
+    ("esp", "C12A7328-F81F-11D2-BA4B-00A0C93EC93B"),
+    ("xbootldr", "BC13C2FF-59E6-4262-A352-B275FD6F7172"),
+    ("swap", "0657FD6D-A4AB-43C4-84E5-0933C84B4F4F"),
+    ("home", "933AC7E1-2EB4-4F13-B844-0E14E2AEF915"),
+    ("srv", "3B8F8425-20E0-4F3B-907F-1A25A76F98E8"),
+    ("var", "4D21B016-B534-45C2-A9FB-5C16E091FD2D"),
+    ("tmp", "7EC6F557-3BC5-4ACA-B293-16EF5DF639D1"),
+    ("generic", "0FC63DAF-8483-4772-8E79-3D69D8477DE4"),
+    ("bios-boot", "21686148-6449-6E6F-744E-656564454649"),
+    ("hfsplus", "48465300-0000-11AA-AA11-00306543ECAC"),
+];
+
+impl GptType {
+    /// Resolve the type GUID, using `arch` for architecture dependent types.
+    pub fn guid(&self, arch: Option<Arch>) -> anyhow::Result<&'static str> {
+        let arch = || {
+            arch.ok_or_else(|| anyhow::anyhow!("--arch is required for partition type `{self}`"))
+        };
+
+        Ok(match self {
+            Self::Fixed(_, guid) | Self::Guid(guid) => guid,
+            Self::Root(a) => a.map_or_else(arch, Ok)?.root(),
+            Self::Usr(a) => a.map_or_else(arch, Ok)?.usr(),
+        })
+    }
+
+    pub fn to_type(&self, arch: Option<Arch>) -> anyhow::Result<gpt::partition_types::Type> {
+        Ok(gpt::partition_types::Type {
+            guid: self.guid(arch)?,
+            os: gpt::partition_types::OperatingSystem::None,
+        })
+    }
+}
+
+impl FromStr for GptType {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        let s = s.to_ascii_lowercase();
+
+        if is_guid(&s) {
+            // The GPT library takes type GUIDs with a static lifetime, and types are only parsed
+            // from the command line and layout once
+            return Ok(Self::Guid(Box::leak(
+                s.to_ascii_uppercase().into_boxed_str(),
+            )));
+        }
+
+        let s = match s.strip_prefix("linux-") {
+            Some("fs") => "generic".to_owned(),
+            Some(alias) => alias.to_owned(),
+            None => s,
+        };
+
+        if let Some((name, guid)) = FIXED.iter().find(|(name, _)| *name == s) {
+            return Ok(Self::Fixed(name, guid));
+        }
+
+        let arch = |a: &str| {
+            <Arch as ValueEnum>::from_str(a, true)
+                .map_err(|_| format!("unknown architecture `{a}`"))
+        };
+
+        match s.split_once('-') {
+            _ if s == "root" => Ok(Self::Root(None)),
+            _ if s == "usr" => Ok(Self::Usr(None)),
+            Some(("root", a)) => Ok(Self::Root(Some(arch(a)?))),
+            Some(("usr", a)) => Ok(Self::Usr(Some(arch(a)?))),
+            _ => Err(format!("unknown partition type `{s}`")),
+        }
+    }
+}
+
+/// MBR system IDs by name.
+const MBR_TYPES: &[(&str, u8)] = &[
+    ("fat12", 0x01),
+    ("fat16", 0x06),
+    ("ntfs", 0x07),
+    ("exfat", 0x07),
+    ("fat32", 0x0b),
+    ("fat32-lba", 0x0c),
+    ("fat16-lba", 0x0e),
+    ("linux-swap", 0x82),
+    ("linux", 0x83),
+    ("linux-lvm", 0x8e),
+    ("linux-raid", 0xfd),
+    ("esp", 0xef),
+    ("xbootldr", 0xea),
+    ("hfsplus", 0xaf),
+];
+
+/// Parse an MBR system ID, as a name or a hexadecimal byte such as `0x83`.
+pub fn parse_mbr_type(s: &str) -> Result<u8, String> {
+    let s = s.to_ascii_lowercase();
+
+    if let Some((_, id)) = MBR_TYPES.iter().find(|(name, _)| *name == s) {
+        return Ok(*id);
+    }
+
+    u8::from_str_radix(s.strip_prefix("0x").unwrap_or(&s), 16)
+        .map_err(|_| format!("unknown MBR partition type `{s}`"))
+}
+
+/// GPT partition attributes by name, with the bit they set.
+const ATTRIBUTES: &[(&str, u8)] = &[
+    ("platform-required", 0),
+    ("no-block-io", 1),
+    ("legacy-bios-bootable", 2),
+    ("read-only", 60),
+    ("hidden", 62),
+    ("no-automount", 63),
+];
+
+/// Parse a GPT attribute name, or the number of its bit, into its mask.
+pub fn parse_attribute(s: &str) -> Result<u64, String> {
+    if let Some((_, bit)) = ATTRIBUTES.iter().find(|(name, _)| *name == s) {
+        return Ok(1 << bit);
+    }
+
+    match s.parse::<u8>() {
+        Ok(bit) if bit < 64 => Ok(1 << bit),
+        _ => Err(format!("unknown GPT attribute `{s}`")),
+    }
+}
+
+/// Check a GUID given in its `XXXXXXXX-XXXX-XXXX-XXXX-XXXXXXXXXXXX` form, such as a partition
+/// GUID.
+pub fn parse_guid(s: &str) -> Result<String, String> {
+    if is_guid(s) {
+        Ok(s.to_ascii_uppercase())
+    } else {
+        Err(format!("`{s}` is not a GUID"))
+    }
+}
+
+/// Whether `s` is a GUID in its `XXXXXXXX-XXXX-XXXX-XXXX-XXXXXXXXXXXX` form.
+fn is_guid(s: &str) -> bool {
+    let groups = s.split('-').collect::<Vec<_>>();
+
+    groups.iter().map(|g| g.len()).eq([8, 4, 4, 4, 12])
+        && groups
+            .iter()
+            .all(|g| g.bytes().all(|b| b.is_ascii_hexdigit()))
+}
+
+impl fmt::Display for GptType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let arch = |a: &Option<Arch>| {
+            a.and_then(|a| a.to_possible_value())
+                .map(|v| format!("-{}", v.get_name()))
+                .unwrap_or_default()
+        };
+
+        match self {
+            Self::Fixed(name, _) => f.write_str(name),
+            Self::Guid(guid) => f.write_str(guid),
+            Self::Root(a) => write!(f, "root{}", arch(a)),
+            Self::Usr(a) => write!(f, "usr{}", arch(a)),
+        }
+    }
+}
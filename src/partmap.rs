@@ -0,0 +1,155 @@
+//! Partition maps for flashing scripts, describing where each partition lives in the image.
+
+use crate::json;
+use clap::ValueEnum;
+use std::fmt::Write;
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    /// JSON description of all partitions
+    Json,
+    /// U-Boot script writing the image with `mmc write`
+    Uboot,
+    /// Shell script flashing each partition with fastboot
+    Fastboot,
+}
+
+#[derive(Clone, Debug)]
+pub struct Partition {
+    pub number: u32,
+    pub name: String,
+    pub start: u64,
+    pub len: u64,
+}
+
+impl Format {
+    /// Suffix appended to the output path to get the partition map path.
+    pub fn suffix(&self) -> &'static str {
+        match self {
+            Self::Json => ".partmap.json",
+            Self::Uboot => ".uboot.txt",
+            Self::Fastboot => ".fastboot.sh",
+        }
+    }
+
+    /// Whether the map refers to byte offsets in the output file itself.
+    pub fn needs_raw(&self) -> bool {
+        *self != Self::Json
+    }
+
+    /// Describe the partitions of an image, with offsets in sectors of `sector_size` bytes.
+    pub fn generate(
+        &self,
+        image: &str,
+        image_size: u64,
+        sector_size: u64,
+        partitions: &[Partition],
+    ) -> String {
+        match self {
+            Self::Json => json(image, image_size, sector_size, partitions),
+            Self::Uboot => uboot(image, image_size, sector_size, partitions),
+            Self::Fastboot => fastboot(image, sector_size, partitions),
+        }
+    }
+}
+
+fn json(image: &str, image_size: u64, sector_size: u64, partitions: &[Partition]) -> String {
+    let partitions = partitions
+        .iter()
+        .map(|p| {
+            json::Value::object()
+                .with("number", p.number)
+                .with("name", p.name.as_str())
+                .with("start", p.start)
+                .with("size", p.len)
+                .with("start_sector", p.start / sector_size)
+                .with("sectors", p.len / sector_size)
+        })
+        .collect::<Vec<_>>();
+
+    let map = json::Value::object()
+        .with("image", image)
+        .with("image_size", image_size)
+        .with("sector_size", sector_size)
+        .with("partitions", partitions);
+
+    format!("{map}\n")
+}
+
+/// Regions of the image not covered by any partition, such as the partition tables.
+fn gaps(image_size: u64, partitions: &[Partition]) -> Vec<(u64, u64)> {
+    let mut sorted = partitions
+        .iter()
+        .map(|p| (p.start, p.len))
+        .collect::<Vec<_>>();
+    sorted.sort();
+
+    let mut gaps = vec![];
+    let mut pos = 0;
+
+    for (start, len) in sorted {
+        if start > pos {
+            gaps.push((pos, start - pos));
+        }
+        pos = pos.max(start + len);
+    }
+
+    if image_size > pos {
+        gaps.push((pos, image_size - pos));
+    }
+
+    gaps
+}
+
+fn uboot(image: &str, image_size: u64, sector_size: u64, partitions: &[Partition]) -> String {
+    let mut out = String::new();
+
+    let _ = writeln!(out, "# Write {image} with partitions in place");
+    let _ = writeln!(
+        out,
+        "# Load it first, e.g. `load mmc 0:1 ${{loadaddr}} {image}`"
+    );
+
+    let mut writes = gaps(image_size, partitions)
+        .into_iter()
+        .map(|(start, len)| ("(table)".to_string(), start, len))
+        .chain(partitions.iter().map(|p| (p.name.clone(), p.start, p.len)))
+        .collect::<Vec<_>>();
+    writes.sort_by_key(|(_, start, _)| *start);
+
+    for (name, start, len) in writes {
+        let _ = writeln!(out, "echo Writing {name}");
+        let _ = writeln!(out, "setexpr mkimg_addr ${{loadaddr}} + {start:#x}");
+        let _ = writeln!(
+            out,
+            "mmc write ${{mkimg_addr}} {:#x} {:#x}",
+            start / sector_size,
+            len.div_ceil(sector_size)
+        );
+    }
+
+    out
+}
+
+fn fastboot(image: &str, sector_size: u64, partitions: &[Partition]) -> String {
+    let mut out = String::new();
+
+    let _ = writeln!(out, "#!/bin/sh");
+    let _ = writeln!(out, "# Flash the partitions of {image} with fastboot");
+    let _ = writeln!(out, "set -e");
+    let _ = writeln!(out, "cd \"$(dirname \"$0\")\"");
+
+    for p in partitions {
+        let part_image = format!("{image}.{}.img", p.name);
+        let _ = writeln!(
+            out,
+            "dd if='{image}' of='{part_image}' bs={sector_size} skip={} count={}",
+            p.start / sector_size,
+            p.len / sector_size
+        );
+        let _ = writeln!(out, "fastboot flash '{}' '{part_image}'", p.name);
+        let _ = writeln!(out, "rm -f '{part_image}'");
+    }
+
+    out
+}
@@ -0,0 +1,121 @@
+//! Newline-delimited JSON progress events for GUIs and CI dashboards.
+
+use crate::json;
+use log::*;
+use std::fs::File;
+use std::io::{self, Write};
+use std::os::unix::io::FromRawFd;
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+use std::str::FromStr;
+
+/// Where progress events are written to.
+#[derive(Clone, Debug)]
+pub enum Target {
+    /// An already open file descriptor, as `fd://N`.
+    Fd(i32),
+    /// A listening Unix socket, as `unix://PATH`.
+    Unix(String),
+    /// A regular file or FIFO.
+    Path(String),
+}
+
+impl FromStr for Target {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        if let Some(fd) = s.strip_prefix("fd://") {
+            fd.parse()
+                .map(Self::Fd)
+                .map_err(|_| format!("invalid file descriptor `{fd}`"))
+        } else if let Some(path) = s.strip_prefix("unix://") {
+            Ok(Self::Unix(path.into()))
+        } else {
+            Ok(Self::Path(s.into()))
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct Progress {
+    out: Option<Box<dyn Write>>,
+    bytes_done: u64,
+    bytes_total: u64,
+}
+
+impl Progress {
+    pub fn open(target: Option<&Target>) -> io::Result<Self> {
+        let out: Option<Box<dyn Write>> = match target {
+            None => None,
+            Some(Target::Fd(fd)) => {
+                // The caller hands the descriptor over, just like `>&3` in a shell
+                if unsafe { libc::fcntl(*fd, libc::F_GETFD) } < 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                Some(Box::new(unsafe { File::from_raw_fd(*fd) }))
+            }
+            Some(Target::Unix(path)) => Some(Box::new(UnixStream::connect(path)?)),
+            Some(Target::Path(path)) => Some(Box::new(File::create(path)?)),
+        };
+
+        Ok(Self {
+            out,
+            ..Default::default()
+        })
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.out.is_some()
+    }
+
+    /// Set the number of bytes that will be copied into the image.
+    pub fn set_total(&mut self, bytes_total: u64) {
+        self.bytes_total = bytes_total;
+    }
+
+    /// Report the start of a build phase.
+    pub fn phase(&mut self, phase: &str) {
+        self.emit(
+            json::Value::object()
+                .with("event", "phase")
+                .with("phase", phase),
+        );
+    }
+
+    /// Report a file that has been copied into the image.
+    pub fn file(&mut self, path: &Path, len: u64) {
+        self.bytes_done += len;
+        self.emit(
+            json::Value::object()
+                .with("event", "file")
+                .with("phase", "copy")
+                .with("file", path.to_string_lossy().as_ref()),
+        );
+    }
+
+    /// Report the end of the build, successful or not.
+    pub fn finish(&mut self, error: Option<String>) {
+        self.emit(
+            json::Value::object()
+                .with("event", "done")
+                .with("success", error.is_none())
+                .with("error", error),
+        );
+    }
+
+    fn emit(&mut self, event: json::Value) {
+        let Some(out) = &mut self.out else {
+            return;
+        };
+
+        let event = event
+            .with("bytes_done", self.bytes_done)
+            .with("bytes_total", self.bytes_total);
+
+        // A reader going away must not fail the build
+        if let Err(e) = writeln!(out, "{event}").and_then(|_| out.flush()) {
+            warn!("Disabling progress events: {e}");
+            self.out = None;
+        }
+    }
+}
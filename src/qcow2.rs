@@ -0,0 +1,172 @@
+//! qcow2 images, as read by QEMU.
+//!
+//! Guest clusters that read as zeroes are left unallocated. The others are written one after
+//! another after the header, followed by the L2 tables, the L1 table and the refcounts, so the
+//! image is written in a single pass over the raw one. Compressed clusters are raw deflate
+//! streams, packed without alignment.
+
+use crate::compress;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+
+const CLUSTER_BITS: u32 = 16;
+const CLUSTER_SIZE: u64 = 1 << CLUSTER_BITS;
+
+/// 16-bit refcounts
+const REFCOUNT_ORDER: u32 = 4;
+const REFCOUNTS_PER_BLOCK: u64 = CLUSTER_SIZE / 2;
+
+const L2_ENTRIES: u64 = CLUSTER_SIZE / 8;
+
+const HEADER_LEN: usize = 104;
+
+/// The cluster is referenced once, and may be written in place
+const OFLAG_COPIED: u64 = 1 << 63;
+const OFLAG_COMPRESSED: u64 = 1 << 62;
+
+/// Bits of a compressed cluster descriptor holding its offset, the rest holding its sectors
+const COMPRESSED_OFFSET_BITS: u32 = 62 - (CLUSTER_BITS - 8);
+
+struct Writer<W> {
+    out: W,
+    /// Offset of the next byte written
+    pos: u64,
+    /// Refcount of every host cluster written so far
+    refcounts: Vec<u16>,
+}
+
+impl<W: Write> Writer<W> {
+    /// Reference the host clusters covering `len` bytes at `pos`.
+    fn reference(&mut self, pos: u64, len: u64) {
+        let end = (pos + len).div_ceil(CLUSTER_SIZE) as usize;
+        if self.refcounts.len() < end {
+            self.refcounts.resize(end, 0);
+        }
+        for refcount in &mut self.refcounts[(pos / CLUSTER_SIZE) as usize..end] {
+            *refcount += 1;
+        }
+    }
+
+    fn write(&mut self, data: &[u8]) -> io::Result<u64> {
+        let pos = self.pos;
+        self.out.write_all(data)?;
+        self.pos += data.len() as u64;
+        self.reference(pos, data.len() as u64);
+        Ok(pos)
+    }
+
+    /// Write `data` at the start of a cluster.
+    fn write_clusters(&mut self, data: &[u8]) -> io::Result<u64> {
+        let pad = self.pos.next_multiple_of(CLUSTER_SIZE) - self.pos;
+        self.out.write_all(&vec![0; pad as usize])?;
+        self.pos += pad;
+        self.write(data)
+    }
+}
+
+fn be_table(entries: &[u64]) -> Vec<u8> {
+    entries.iter().flat_map(|e| e.to_be_bytes()).collect()
+}
+
+/// Write the raw image of `len` bytes from `input` as a qcow2 image, compressing clusters at
+/// `compress` level if that makes them smaller.
+pub fn write(
+    input: impl Read,
+    out: &mut (impl Write + Seek),
+    len: u64,
+    compress: Option<u8>,
+) -> io::Result<()> {
+    let mut w = Writer {
+        out: &mut *out,
+        pos: 0,
+        refcounts: vec![],
+    };
+
+    // The header is filled in once the tables are placed
+    w.write(&[0; CLUSTER_SIZE as usize])?;
+
+    let clusters = len.div_ceil(CLUSTER_SIZE);
+    let mut l2 = vec![0u64; clusters as usize];
+
+    crate::output::for_each_chunk(input, CLUSTER_SIZE as usize, |off, data| {
+        if data.iter().all(|&b| b == 0) {
+            return Ok(());
+        }
+
+        // Compressed clusters always inflate to a whole cluster
+        let mut cluster = data.to_vec();
+        cluster.resize(CLUSTER_SIZE as usize, 0);
+
+        let compressed = compress
+            .map(|level| compress::deflate(&cluster, level))
+            .filter(|c| (c.len() as u64) < CLUSTER_SIZE);
+
+        l2[(off / CLUSTER_SIZE) as usize] = match compressed {
+            Some(c) => {
+                let pos = w.write(&c)?;
+                let sectors = (pos + c.len() as u64 - 1) / 512 - pos / 512;
+                OFLAG_COMPRESSED | sectors << COMPRESSED_OFFSET_BITS | pos
+            }
+            None => OFLAG_COPIED | w.write_clusters(&cluster)?,
+        };
+
+        Ok(())
+    })?;
+
+    let mut l1 = vec![0u64; clusters.div_ceil(L2_ENTRIES) as usize];
+
+    for (i, table) in l2.chunks(L2_ENTRIES as usize).enumerate() {
+        if table.iter().any(|&e| e != 0) {
+            let mut table = table.to_vec();
+            table.resize(L2_ENTRIES as usize, 0);
+            l1[i] = OFLAG_COPIED | w.write_clusters(&be_table(&table))?;
+        }
+    }
+
+    let l1_offset = w.write_clusters(&be_table(&l1))?;
+
+    // The refcount blocks count themselves and the table pointing at them
+    let used = w.pos.div_ceil(CLUSTER_SIZE);
+    let (mut table_clusters, mut blocks) = (0, 0);
+    loop {
+        let total = used + table_clusters + blocks;
+        let needed = total.div_ceil(REFCOUNTS_PER_BLOCK);
+        let needed_table = (needed * 8).div_ceil(CLUSTER_SIZE);
+        if (needed, needed_table) == (blocks, table_clusters) {
+            break;
+        }
+        (blocks, table_clusters) = (needed, needed_table);
+    }
+
+    let table_offset = w.pos.next_multiple_of(CLUSTER_SIZE);
+    let first_block = table_offset + table_clusters * CLUSTER_SIZE;
+
+    let mut table: Vec<u64> = (0..blocks)
+        .map(|i| first_block + i * CLUSTER_SIZE)
+        .collect();
+    table.resize((table_clusters * CLUSTER_SIZE / 8) as usize, 0);
+    w.write_clusters(&be_table(&table))?;
+    w.reference(first_block, blocks * CLUSTER_SIZE);
+
+    let mut refcounts = std::mem::take(&mut w.refcounts);
+    refcounts.resize((blocks * REFCOUNTS_PER_BLOCK) as usize, 0);
+    let refcounts: Vec<u8> = refcounts.iter().flat_map(|r| r.to_be_bytes()).collect();
+    w.write_clusters(&refcounts)?;
+
+    let mut header = [0u8; HEADER_LEN + 8];
+    header[0..4].copy_from_slice(b"QFI\xfb");
+    header[4..8].copy_from_slice(&3u32.to_be_bytes());
+    header[20..24].copy_from_slice(&CLUSTER_BITS.to_be_bytes());
+    header[24..32].copy_from_slice(&len.to_be_bytes());
+    header[36..40].copy_from_slice(&(l1.len() as u32).to_be_bytes());
+    header[40..48].copy_from_slice(&l1_offset.to_be_bytes());
+    header[48..56].copy_from_slice(&table_offset.to_be_bytes());
+    header[56..60].copy_from_slice(&(table_clusters as u32).to_be_bytes());
+    header[96..100].copy_from_slice(&REFCOUNT_ORDER.to_be_bytes());
+    header[100..104].copy_from_slice(&(HEADER_LEN as u32).to_be_bytes());
+    // An end of header extensions follows
+
+    out.seek(SeekFrom::Start(0))?;
+    out.write_all(&header)?;
+
+    Ok(())
+}
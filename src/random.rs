@@ -0,0 +1,43 @@
+//! Random identifiers and fill data, taken from `/dev/urandom` or, for reproducible builds, from
+//! a seed.
+//!
+//! A seeded generator yields SHA-256 digests of the seed and a running counter, so the same seed
+//! gives the same bytes for the same sequence of requests.
+
+use crate::sha256;
+use std::cell::Cell;
+use std::fs::File;
+use std::io::{self, Read};
+
+/// Source of random bytes for one build.
+#[derive(Debug, Default)]
+pub struct Random {
+    /// Digest of the seed and the number of blocks generated from it, `None` for `/dev/urandom`
+    seed: Option<([u8; 32], Cell<u64>)>,
+}
+
+impl Random {
+    /// Generator deriving everything from `seed`.
+    pub fn seeded(seed: &[u8]) -> Self {
+        Self {
+            seed: Some((sha256::digest(seed), Cell::new(0))),
+        }
+    }
+
+    /// Fill `buf` with random bytes.
+    pub fn fill(&self, buf: &mut [u8]) -> io::Result<()> {
+        match &self.seed {
+            Some((seed, counter)) => {
+                for chunk in buf.chunks_mut(32) {
+                    let mut h = sha256::Sha256::new();
+                    h.update(seed);
+                    h.update(&counter.get().to_le_bytes());
+                    chunk.copy_from_slice(&h.finish()[..chunk.len()]);
+                    counter.set(counter.get() + 1);
+                }
+                Ok(())
+            }
+            None => File::open("/dev/urandom")?.read_exact(buf),
+        }
+    }
+}
@@ -0,0 +1,81 @@
+//! Growing of existing images, along with their last partition and its filesystem.
+
+use crate::table::{self, Gpt};
+use crate::{fat_resize, image, size, PartitionTable};
+use clap::Args;
+use log::*;
+use std::fs::OpenOptions;
+use std::io::{Seek, SeekFrom};
+use std::path::PathBuf;
+
+#[derive(Args, Debug)]
+pub struct ResizeArgs {
+    /// Image to resize
+    #[arg(long)]
+    image: PathBuf,
+    /// New size of the image, in bytes (`8G`). Images can only grow
+    #[arg(short, long, value_parser = size::parse_bytes)]
+    size: u64,
+}
+
+pub fn run(args: &ResizeArgs) -> anyhow::Result<()> {
+    let mut file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(&args.image)
+        .map_err(|e| anyhow::anyhow!("cannot open {}: {e}", args.image.display()))?;
+
+    let (kind, parts) = image::read_partitions(&mut file)?;
+    let old_size = file.seek(SeekFrom::End(0))?;
+    let sector = image::sector_size(&mut file)?;
+    let size = args.size / sector * sector;
+
+    if size < old_size {
+        anyhow::bail!(
+            "{} is {old_size} bytes, more than {size}; images can only grow",
+            args.image.display()
+        );
+    }
+
+    // A FAT filesystem that cannot fill the partition is found before anything changes
+    if let Some(last) = parts.iter().max_by_key(|p| p.start + p.len) {
+        let mut part =
+            fscommon::StreamSlice::new(file.try_clone()?, last.start, last.start + last.len)?;
+
+        if image::detect_filesystem(&mut part)? == Some("FAT") {
+            let end = match kind {
+                PartitionTable::Gpt | PartitionTable::Hybrid => size - Gpt::read(&mut file)?.tail(),
+                _ => size,
+            };
+            fat_resize::check_grow(&mut part, end - last.start)
+                .map_err(|e| anyhow::anyhow!("partition {}: {e}", last.number))?;
+        }
+    }
+
+    let (number, start, len) = table::resize_last_partition(&mut file, kind, size)?;
+
+    info!("Partition {number}: start {start:x} size {len:x}");
+
+    let mut part = fscommon::StreamSlice::new(file, start, start + len)?;
+
+    match image::detect_filesystem(&mut part)? {
+        Some("FAT") => {
+            let fs_size = fat_resize::grow(&mut part, len)?;
+            println!(
+                "{} resized to {size} bytes, partition {number} and its FAT filesystem to {fs_size} bytes",
+                args.image.display()
+            );
+        }
+        found => {
+            if let Some(name) = found {
+                warn!("Partition {number} holds {name}, only FAT filesystems are grown");
+            }
+            println!(
+                "{} resized to {size} bytes, partition {number} to {len} bytes",
+                args.image.display()
+            );
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,71 @@
+//! Removal of files and directories from an existing image.
+
+use crate::{image, sync};
+use clap::Args;
+use log::*;
+use std::path::PathBuf;
+
+#[derive(Args, Debug)]
+pub struct RmArgs {
+    /// Image to remove from
+    #[arg(long)]
+    image: PathBuf,
+    /// '/' separated paths inside the image. Directories are removed with everything in them
+    #[arg(required = true)]
+    paths: Vec<String>,
+    /// Partition to remove from. Defaults to the first one
+    #[arg(short, long)]
+    partition: Option<usize>,
+    /// Skip paths that are not in the image instead of failing
+    #[arg(short, long)]
+    force: bool,
+}
+
+pub fn run(args: &RmArgs) -> anyhow::Result<()> {
+    let part = image::open_partition(&args.image, true, args.partition)?;
+    let fs = image::open_fat(part)?;
+    let root = fs.root_dir();
+
+    let mut removed = 0;
+
+    for path in &args.paths {
+        let trimmed = path.trim_matches('/');
+        if trimmed.is_empty() {
+            anyhow::bail!("refusing to remove the root directory");
+        }
+
+        let (parent, name) = match trimmed.rsplit_once('/') {
+            Some((parent, name)) => (root.open_dir(parent).ok(), name),
+            None => (Some(root.clone()), trimmed),
+        };
+
+        // FAT names are case insensitive
+        let entry = parent.as_ref().and_then(|dir| {
+            dir.iter()
+                .filter_map(Result::ok)
+                .find(|e| e.file_name().eq_ignore_ascii_case(name))
+        });
+
+        let (Some(dir), Some(entry)) = (parent, entry) else {
+            if args.force {
+                continue;
+            }
+            anyhow::bail!("{path} is not in the image");
+        };
+
+        info!("REMOVE: {trimmed}");
+        if entry.is_dir() {
+            sync::remove_all(&dir, &entry.file_name())?;
+        } else {
+            dir.remove(&entry.file_name())?;
+        }
+        removed += 1;
+    }
+
+    std::mem::drop(root);
+    fs.unmount()?;
+
+    println!("{removed} removed");
+
+    Ok(())
+}
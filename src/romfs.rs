@@ -0,0 +1,262 @@
+//! romfs images.
+//!
+//! Every directory is a linked list of file headers, starting with `.` and `..` hard links, and
+//! file data directly follows its header, as does the target of a symbolic link. Permissions
+//! other than the executable bit, owners and times are not stored.
+
+use crate::tree::{Kind, Tree};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+const MAGIC: &[u8; 8] = b"-rom1fs-";
+/// Headers, names and data are aligned to this
+const ALIGN: u64 = 16;
+/// The superblock checksum covers this much of the image
+const CHECKSUM_LEN: usize = 512;
+/// Images are padded to a multiple of this
+const PAD: u64 = 1024;
+
+const TYPE_HARD_LINK: u32 = 0;
+const TYPE_DIR: u32 = 1;
+const TYPE_FILE: u32 = 2;
+const TYPE_SYMLINK: u32 = 3;
+const EXEC: u32 = 8;
+
+#[derive(Clone, Debug, Default)]
+pub struct Options {
+    pub label: Option<String>,
+}
+
+fn align(v: u64) -> u64 {
+    v.div_ceil(ALIGN) * ALIGN
+}
+
+/// Size of a file header including its name.
+fn header_len(name: &str) -> u64 {
+    ALIGN + align(name.len() as u64 + 1)
+}
+
+/// Checksum making the sum of big endian words equal 0.
+fn checksum(data: &[u8]) -> u32 {
+    let sum = data
+        .chunks(4)
+        .map(|w| u32::from_be_bytes(w.try_into().unwrap()))
+        .fold(0u32, u32::wrapping_add);
+    sum.wrapping_neg()
+}
+
+/// Offsets of the file headers.
+struct Layout {
+    /// Header of `.` in the directory, for directories
+    dot: Vec<u64>,
+    /// Header of each node in the directory containing it, `.` for the root
+    entry: Vec<u64>,
+    size: u64,
+}
+
+impl Layout {
+    fn new(tree: &Tree, label: &str) -> anyhow::Result<Self> {
+        let mut dot = vec![0; tree.nodes.len()];
+        let mut entry = vec![0; tree.nodes.len()];
+        let mut next = header_len(label);
+
+        // The root directory comes first, so its `.` is at the root offset the kernel expects
+        for (idx, node) in tree.nodes.iter().enumerate() {
+            let Kind::Dir(children) = &node.kind else {
+                continue;
+            };
+
+            dot[idx] = next;
+            next += header_len(".") + header_len("..");
+
+            for &c in children {
+                let child = &tree.nodes[c];
+                entry[c] = next;
+                next += header_len(&child.name);
+                match &child.kind {
+                    Kind::File { len, .. } => next += align(*len),
+                    Kind::Symlink(target) => next += align(target.len() as u64),
+                    Kind::Dir(_) => {}
+                }
+            }
+        }
+        entry[0] = dot[0];
+
+        let size = next.div_ceil(PAD) * PAD;
+        if size > u32::MAX as u64 {
+            anyhow::bail!("romfs images are limited to 4GiB");
+        }
+
+        Ok(Self { dot, entry, size })
+    }
+}
+
+/// Smallest filesystem size the tree fits in.
+pub fn estimate_size(tree: &Tree, opts: &Options) -> anyhow::Result<u64> {
+    Ok(Layout::new(tree, opts.label.as_deref().unwrap_or_default())?.size)
+}
+
+/// Writes sequentially, keeping a copy of the start of the image for the superblock checksum.
+struct Output<'a, W> {
+    inner: &'a mut W,
+    pos: u64,
+    head: [u8; CHECKSUM_LEN],
+}
+
+impl<W: Write> Write for Output<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        if self.pos < CHECKSUM_LEN as u64 {
+            let start = self.pos as usize;
+            let len = n.min(CHECKSUM_LEN - start);
+            self.head[start..start + len].copy_from_slice(&buf[..len]);
+        }
+        self.pos += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+fn write_header<W: Write>(
+    out: &mut W,
+    name: &str,
+    next: u64,
+    kind: u32,
+    spec: u64,
+    size: u64,
+) -> io::Result<()> {
+    let mut h = vec![0u8; header_len(name) as usize];
+    h[0..4].copy_from_slice(&(next as u32 | kind).to_be_bytes());
+    h[4..8].copy_from_slice(&(spec as u32).to_be_bytes());
+    h[8..12].copy_from_slice(&(size as u32).to_be_bytes());
+    h[16..16 + name.len()].copy_from_slice(name.as_bytes());
+
+    let sum = checksum(&h);
+    h[12..16].copy_from_slice(&sum.to_be_bytes());
+    out.write_all(&h)
+}
+
+/// Write the image into `out`, which spans `size` bytes.
+///
+/// `on_file` is called with the image path and length of every file written.
+pub fn write<W: Write + Seek>(
+    out: &mut W,
+    size: u64,
+    tree: &Tree,
+    opts: &Options,
+    on_file: &mut dyn FnMut(&Path, u64),
+) -> anyhow::Result<()> {
+    let label = opts.label.as_deref().unwrap_or_default();
+    let layout = Layout::new(tree, label)?;
+
+    if layout.size > size {
+        anyhow::bail!(
+            "romfs image needs {} bytes, but only {size} are available",
+            layout.size
+        );
+    }
+
+    out.seek(SeekFrom::Start(0))?;
+    let mut out = Output {
+        inner: out,
+        pos: 0,
+        head: [0; CHECKSUM_LEN],
+    };
+
+    // Superblock, with the checksum filled in at the end
+    let mut sb = vec![0u8; header_len(label) as usize];
+    sb[0..8].copy_from_slice(MAGIC);
+    sb[8..12].copy_from_slice(&(layout.size as u32).to_be_bytes());
+    sb[16..16 + label.len()].copy_from_slice(label.as_bytes());
+    out.write_all(&sb)?;
+
+    for (idx, node) in tree.nodes.iter().enumerate() {
+        let Kind::Dir(children) = &node.kind else {
+            continue;
+        };
+
+        let dot = layout.dot[idx];
+        let dotdot = dot + header_len(".");
+        let first = children.first().map_or(0, |&c| layout.entry[c]);
+
+        if idx == 0 {
+            write_header(&mut out, ".", dotdot, TYPE_DIR | EXEC, dot, 0)?;
+        } else {
+            write_header(&mut out, ".", dotdot, TYPE_HARD_LINK, layout.entry[idx], 0)?;
+        }
+        write_header(
+            &mut out,
+            "..",
+            first,
+            TYPE_HARD_LINK,
+            layout.entry[node.parent],
+            0,
+        )?;
+
+        for (i, &c) in children.iter().enumerate() {
+            let child = &tree.nodes[c];
+            let next = children.get(i + 1).map_or(0, |&n| layout.entry[n]);
+            let exec = if child.mode & 0o111 != 0 { EXEC } else { 0 };
+
+            match &child.kind {
+                Kind::Dir(_) => {
+                    write_header(
+                        &mut out,
+                        &child.name,
+                        next,
+                        TYPE_DIR | EXEC,
+                        layout.dot[c],
+                        0,
+                    )?;
+                }
+                Kind::File { source, len } => {
+                    write_header(&mut out, &child.name, next, TYPE_FILE | exec, 0, *len)?;
+
+                    let copied = io::copy(&mut source.open()?.take(*len), &mut out)?;
+                    if copied != *len {
+                        anyhow::bail!("{} changed while being copied", child.path.display());
+                    }
+                    out.write_all(&[0; ALIGN as usize][..(align(*len) - len) as usize])?;
+
+                    on_file(&child.path, *len);
+                }
+                Kind::Symlink(target) => {
+                    let len = target.len() as u64;
+                    write_header(&mut out, &child.name, next, TYPE_SYMLINK, 0, len)?;
+                    out.write_all(target.as_bytes())?;
+                    out.write_all(&[0; ALIGN as usize][..(align(len) - len) as usize])?;
+                }
+            }
+        }
+    }
+
+    let padding = layout.size - out.pos;
+    io::copy(&mut io::repeat(0).take(padding), &mut out)?;
+
+    let sum = checksum(&out.head[..CHECKSUM_LEN.min(layout.size as usize)]);
+    let inner = out.inner;
+    inner.seek(SeekFrom::Start(12))?;
+    inner.write_all(&sum.to_be_bytes())?;
+
+    Ok(())
+}
+
+impl crate::FsWriter for Options {
+    fn estimate_size(&self, tree: &Tree) -> anyhow::Result<u64> {
+        estimate_size(tree, self)
+    }
+
+    fn write(
+        &self,
+        mut out: &mut dyn fatfs::ReadWriteSeek,
+        size: u64,
+        tree: &Tree,
+        _ctx: &crate::BuildContext,
+        on_file: &mut dyn FnMut(&Path, u64),
+    ) -> anyhow::Result<()> {
+        write(&mut out, size, tree, self, on_file)
+    }
+}
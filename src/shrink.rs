@@ -0,0 +1,80 @@
+//! Shrinking of existing images down to the contents of their last partition.
+
+use crate::table::{self, Gpt};
+use crate::{fat_resize, image, size, PartitionTable};
+use clap::Args;
+use log::*;
+use std::fs::OpenOptions;
+use std::path::{Path, PathBuf};
+
+/// Free space left in minimized filesystems, unless given
+pub const DEFAULT_SLACK: &str = "1M";
+
+#[derive(Args, Debug)]
+pub struct ShrinkArgs {
+    /// Image to shrink
+    #[arg(long)]
+    image: PathBuf,
+    /// Free space to leave in the filesystem, in bytes
+    #[arg(long, value_name = "SIZE", default_value = DEFAULT_SLACK, value_parser = size::parse_bytes)]
+    slack: u64,
+}
+
+/// Compact the FAT filesystem of the last partition of the image at `path`, and cut the partition
+/// and the image down to it with `slack` bytes of free space.
+///
+/// Returns the new image size, and the number and new size of the partition.
+pub fn shrink(path: &Path, slack: u64) -> anyhow::Result<(u64, usize, u64)> {
+    let mut file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(path)
+        .map_err(|e| anyhow::anyhow!("cannot open {}: {e}", path.display()))?;
+
+    let (kind, parts) = image::read_partitions(&mut file)?;
+
+    let last = parts
+        .iter()
+        .max_by_key(|p| p.start + p.len)
+        .ok_or_else(|| anyhow::anyhow!("no partitions in {}", path.display()))?;
+
+    let mut part =
+        fscommon::StreamSlice::new(file.try_clone()?, last.start, last.start + last.len)?;
+
+    match image::detect_filesystem(&mut part)? {
+        Some("FAT") => {}
+        Some(name) => anyhow::bail!(
+            "partition {} holds {name}, only FAT filesystems can be shrunk",
+            last.number
+        ),
+        None => anyhow::bail!("partition {} holds no known filesystem", last.number),
+    }
+
+    let sector = image::sector_size(&mut file)?;
+    let len = fat_resize::shrink(&mut part, slack)?.next_multiple_of(sector);
+
+    let size = match kind {
+        PartitionTable::None => len,
+        PartitionTable::Gpt | PartitionTable::Hybrid => {
+            last.start + len + Gpt::read(&mut file)?.tail()
+        }
+        PartitionTable::Mbr => last.start + len,
+    };
+
+    debug!("Partition {}: size {:x} -> {len:x}", last.number, last.len);
+
+    let (number, _, len) = table::resize_last_partition(&mut file, kind, size)?;
+
+    Ok((size, number, len))
+}
+
+pub fn run(args: &ShrinkArgs) -> anyhow::Result<()> {
+    let (size, number, len) = shrink(&args.image, args.slack)?;
+
+    println!(
+        "{} shrunk to {size} bytes, partition {number} to {len} bytes",
+        args.image.display()
+    );
+
+    Ok(())
+}
@@ -0,0 +1,73 @@
+//! Parsing of human readable sizes.
+
+use std::str::FromStr;
+
+/// Parse a byte count with an optional binary suffix, such as `512`, `64K`, `1.5M` or `8GiB`.
+/// Hexadecimal values with a `0x` prefix are accepted as well.
+pub fn parse_bytes(s: &str) -> Result<u64, String> {
+    let s = s.trim();
+
+    if let Some(hex) = s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        return u64::from_str_radix(hex, 16).map_err(|_| format!("invalid size `{s}`"));
+    }
+    let split = s
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(s.len());
+    let (num, suffix) = s.split_at(split);
+
+    let mult: u64 = match suffix.trim().to_ascii_lowercase().as_str() {
+        "" | "b" => 1,
+        "k" | "kb" | "kib" => 1 << 10,
+        "m" | "mb" | "mib" => 1 << 20,
+        "g" | "gb" | "gib" => 1 << 30,
+        "t" | "tb" | "tib" => 1 << 40,
+        _ => return Err(format!("unknown size suffix in `{s}`")),
+    };
+
+    if let Ok(num) = num.parse::<u64>() {
+        num.checked_mul(mult)
+            .ok_or_else(|| format!("size `{s}` is too large"))
+    } else {
+        let num = num
+            .parse::<f64>()
+            .map_err(|_| format!("invalid size `{s}`"))?;
+        Ok((num * mult as f64) as u64)
+    }
+}
+
+/// Size of a partition, relative to the whole image or absolute.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PartitionSize {
+    Bytes(u64),
+    /// Percentage of the declared image size
+    Percent(u64),
+    /// Everything left in the declared image
+    Rest,
+}
+
+impl FromStr for PartitionSize {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        if s == "rest" {
+            Ok(Self::Rest)
+        } else if let Some(p) = s.strip_suffix('%') {
+            match p.trim().parse::<u64>() {
+                Ok(p @ 1..=100) => Ok(Self::Percent(p)),
+                _ => Err(format!("invalid percentage `{s}`")),
+            }
+        } else {
+            parse_bytes(s).map(Self::Bytes)
+        }
+    }
+}
+
+impl std::fmt::Display for PartitionSize {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::Bytes(b) => write!(f, "{b}"),
+            Self::Percent(p) => write!(f, "{p}%"),
+            Self::Rest => write!(f, "rest"),
+        }
+    }
+}
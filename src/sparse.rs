@@ -0,0 +1,129 @@
+//! Holes in image files, which read as zeroes without taking up space.
+
+use log::*;
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+
+/// Granularity of punched holes, the block size of most filesystems
+const BLOCK_SIZE: u64 = 4096;
+
+/// Byte ranges of the file that hold data, according to the filesystem it's stored on.
+///
+/// Regions never written by the build are holes, since the image is extended with `set_len`.
+pub fn data_ranges(file: &File, len: u64) -> io::Result<Vec<(u64, u64)>> {
+    let fd = file.as_raw_fd();
+    let mut ranges = vec![];
+    let mut pos = 0;
+
+    while pos < len {
+        // SAFETY: lseek on a valid descriptor has no memory safety requirements
+        let start = unsafe { libc::lseek(fd, pos as libc::off_t, libc::SEEK_DATA) };
+
+        if start < 0 {
+            let err = io::Error::last_os_error();
+            return match err.raw_os_error() {
+                // No more data until the end of the file
+                Some(libc::ENXIO) => Ok(ranges),
+                // Filesystem without hole reporting, everything is data
+                Some(libc::EINVAL) if ranges.is_empty() => Ok(vec![(0, len)]),
+                _ => Err(err),
+            };
+        }
+
+        // SAFETY: see above
+        let end = unsafe { libc::lseek(fd, start, libc::SEEK_HOLE) };
+
+        if end < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        ranges.push((start as u64, (end as u64).min(len)));
+        pos = end as u64;
+    }
+
+    Ok(ranges)
+}
+
+fn punch_hole(file: &File, start: u64, len: u64) -> io::Result<()> {
+    // SAFETY: fallocate on a valid descriptor has no memory safety requirements
+    let ret = unsafe {
+        libc::fallocate(
+            file.as_raw_fd(),
+            libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE,
+            start as libc::off_t,
+            len as libc::off_t,
+        )
+    };
+
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+/// Turn blocks of zeroes the build wrote into the image file at `path` into holes, returning the
+/// bytes freed. Devices and filesystems without hole punching are left alone.
+pub fn punch_zeroes(path: &Path) -> io::Result<u64> {
+    let mut file = File::options().read(true).write(true).open(path)?;
+    let meta = file.metadata()?;
+
+    if !meta.is_file() {
+        return Ok(0);
+    }
+
+    let len = meta.len();
+    let mut buf = vec![0; 256 * BLOCK_SIZE as usize];
+    let mut runs = vec![];
+
+    for (start, end) in data_ranges(&file, len)? {
+        // Whole blocks only, partial ones at the edges keep their data
+        let (start, end) = (
+            start.next_multiple_of(BLOCK_SIZE),
+            end / BLOCK_SIZE * BLOCK_SIZE,
+        );
+        let mut pos = start;
+        let mut zeroes = None;
+
+        file.seek(SeekFrom::Start(start))?;
+
+        while pos < end {
+            let n = (end - pos).min(buf.len() as u64) as usize;
+            let chunk = &mut buf[..n];
+            file.read_exact(chunk)?;
+
+            for block in chunk.chunks(BLOCK_SIZE as usize) {
+                match (block.iter().all(|&b| b == 0), zeroes) {
+                    (true, None) => zeroes = Some(pos),
+                    (false, Some(from)) => {
+                        runs.push((from, pos));
+                        zeroes = None;
+                    }
+                    _ => {}
+                }
+                pos += block.len() as u64;
+            }
+        }
+
+        if let Some(from) = zeroes {
+            runs.push((from, end));
+        }
+    }
+
+    let mut freed = 0;
+
+    for (start, end) in runs {
+        match punch_hole(&file, start, end - start) {
+            Ok(()) => freed += end - start,
+            Err(e) if e.raw_os_error() == Some(libc::EOPNOTSUPP) => {
+                debug!("Cannot punch holes: {e}");
+                break;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+
+    Ok(freed)
+}
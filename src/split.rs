@@ -0,0 +1,94 @@
+//! Images split into numbered pieces, for media and uploads with per-file size limits.
+
+use crate::sparse;
+use clap::Args;
+use log::*;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+
+/// Pieces are numbered with 3 digits
+const MAX_PIECES: u64 = 1000;
+
+#[derive(Args, Debug)]
+pub struct JoinArgs {
+    /// Image to reassemble, from the pieces at `<IMAGE>.000`, `<IMAGE>.001`, …
+    image: PathBuf,
+    /// Where to write the image. Defaults to IMAGE
+    #[arg(short, long)]
+    output: Option<PathBuf>,
+    /// Remove the pieces once the image is written
+    #[arg(long)]
+    remove: bool,
+}
+
+/// Path of piece `index` of `image`.
+pub fn piece_path(image: &Path, index: u64) -> PathBuf {
+    let mut path = image.as_os_str().to_owned();
+    path.push(format!(".{index:03}"));
+    PathBuf::from(path)
+}
+
+/// Split `src` into pieces of at most `size` bytes next to `image`, removing any pieces a
+/// previous, larger image left behind.
+pub fn split(src: &Path, image: &Path, size: u64) -> anyhow::Result<u64> {
+    let mut input = File::open(src)?;
+    let len = input.metadata()?.len();
+    let pieces = len.div_ceil(size).max(1);
+
+    if pieces > MAX_PIECES {
+        anyhow::bail!("splitting {len} bytes into pieces of {size} would take {pieces} pieces, more than {MAX_PIECES}");
+    }
+
+    for index in 0..pieces {
+        let path = piece_path(image, index);
+        let mut out = File::create(&path)?;
+        io::copy(&mut (&mut input).take(size), &mut out)?;
+        drop(out);
+        sparse::punch_zeroes(&path)?;
+    }
+
+    for index in pieces..MAX_PIECES {
+        match fs::remove_file(piece_path(image, index)) {
+            Err(e) if e.kind() == io::ErrorKind::NotFound => break,
+            ret => ret?,
+        }
+    }
+
+    info!("Split {} into {pieces} pieces", image.display());
+
+    Ok(pieces)
+}
+
+/// Concatenate the pieces of an image split by `--split`.
+pub fn run(args: &JoinArgs) -> anyhow::Result<()> {
+    let output = args.output.as_deref().unwrap_or(&args.image);
+
+    let pieces: Vec<PathBuf> = (0..MAX_PIECES)
+        .map(|i| piece_path(&args.image, i))
+        .take_while(|p| p.exists())
+        .collect();
+
+    if pieces.is_empty() {
+        anyhow::bail!("{} not found", piece_path(&args.image, 0).display());
+    }
+
+    let mut out = File::create(output)?;
+    for piece in &pieces {
+        io::copy(&mut File::open(piece)?, &mut out)?;
+    }
+    out.flush()?;
+    drop(out);
+
+    sparse::punch_zeroes(output)?;
+
+    if args.remove {
+        for piece in &pieces {
+            fs::remove_file(piece)?;
+        }
+    }
+
+    info!("Joined {} pieces into {}", pieces.len(), output.display());
+
+    Ok(())
+}
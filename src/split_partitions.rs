@@ -0,0 +1,153 @@
+//! Disassembly of disk images into one image file per partition, with a layout manifest.
+
+use crate::table::Gpt;
+use crate::toml::Value;
+use crate::{image, PartitionTable};
+use clap::Args;
+use log::*;
+use std::fmt::Write as _;
+use std::fs::{self, File};
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::PathBuf;
+
+/// Name of the manifest written next to the partition images
+const MANIFEST: &str = "layout.toml";
+
+#[derive(Args, Debug)]
+pub struct SplitPartitionsArgs {
+    /// Disk image to split
+    image: PathBuf,
+    /// Directory to write `p1.img`, `p2.img`, … and `layout.toml` to. Created if missing
+    #[arg(short, long)]
+    output: PathBuf,
+}
+
+/// Manifest fields of each partition, besides its size and content, from the partition table.
+fn table_fields(
+    file: &mut File,
+    kind: PartitionTable,
+) -> anyhow::Result<Vec<Vec<(String, Value)>>> {
+    let field = |key: &str, value: Value| (key.to_owned(), value);
+    let mut fields = vec![];
+
+    match kind {
+        PartitionTable::Gpt | PartitionTable::Hybrid => {
+            let bootable = gpt::partition::PartitionAttributes::BOOTABLE.bits();
+
+            for part in Gpt::read(file)?.partitions.values() {
+                let mut table = vec![
+                    field("name", Value::Str(part.name.clone())),
+                    field("type", Value::Str(part.part_type_guid.guid.to_owned())),
+                    field(
+                        "uuid",
+                        Value::Str(part.part_guid.to_string().to_uppercase()),
+                    ),
+                ];
+                if part.flags & bootable != 0 {
+                    table.push(field("bootable", Value::Bool(true)));
+                }
+
+                let attributes = (0..64)
+                    .filter(|bit| (part.flags & !bootable) & (1 << bit) != 0)
+                    .map(Value::Int)
+                    .collect::<Vec<_>>();
+                if !attributes.is_empty() {
+                    table.push(field("attributes", Value::Array(attributes)));
+                }
+
+                fields.push(table);
+            }
+        }
+        _ => {
+            let sector = image::sector_size(file)?;
+            let mbr = mbrman::MBR::read_from(file, sector as u32)?;
+
+            for (number, p) in image::mbr_partitions(&mbr) {
+                let mut table = vec![
+                    field("name", Value::Str(format!("part{number}"))),
+                    field("mbr_type", Value::Str(format!("{:#04x}", p.sys))),
+                    // Logical partitions are numbered from 5
+                    field("primary", Value::Bool(number < 5)),
+                ];
+                if p.boot == mbrman::BOOT_ACTIVE {
+                    table.push(field("bootable", Value::Bool(true)));
+                }
+
+                fields.push(table);
+            }
+        }
+    }
+
+    Ok(fields)
+}
+
+pub fn run(args: &SplitPartitionsArgs) -> anyhow::Result<()> {
+    let mut file = File::open(&args.image)
+        .map_err(|e| anyhow::anyhow!("cannot open {}: {e}", args.image.display()))?;
+
+    let (kind, parts) = image::read_partitions(&mut file)?;
+    if matches!(kind, PartitionTable::None) {
+        anyhow::bail!("{} has no partition table to split", args.image.display());
+    }
+
+    // Layouts list partitions in disk order, which GPT numbers need not follow
+    let mut parts = parts
+        .into_iter()
+        .zip(table_fields(&mut file, kind)?)
+        .collect::<Vec<_>>();
+    parts.sort_by_key(|(p, _)| p.start);
+
+    fs::create_dir_all(&args.output)
+        .map_err(|e| anyhow::anyhow!("cannot create {}: {e}", args.output.display()))?;
+
+    let mut manifest = format!(
+        "# Partitions of {}. Offsets are not kept, partitions are placed again when assembled\n",
+        args.image.display()
+    );
+    let sector = image::sector_size(&mut file)?;
+    if sector != 512 {
+        writeln!(
+            manifest,
+            "# Sectors are {sector} bytes, assemble with --sector-size {sector}"
+        )?;
+    }
+
+    for (part, fields) in parts.iter() {
+        let name = format!("p{}.img", part.number);
+        let path = args.output.join(&name);
+
+        debug!(
+            "Partition {}: start {:x} size {:x} -> {}",
+            part.number,
+            part.start,
+            part.len,
+            path.display()
+        );
+
+        file.seek(SeekFrom::Start(part.start))?;
+        let mut out = File::create(&path)
+            .map_err(|e| anyhow::anyhow!("cannot create {}: {e}", path.display()))?;
+        io::copy(&mut (&mut file).take(part.len), &mut out)?;
+
+        writeln!(manifest, "\n# {name}, at offset {}", part.start)?;
+        writeln!(manifest, "[[partition]]")?;
+        for (key, value) in fields {
+            writeln!(manifest, "{key} = {value}")?;
+        }
+        writeln!(manifest, "size = {}", part.len)?;
+        writeln!(manifest, "content = {{ image = {:?} }}", name)?;
+    }
+
+    let path = args.output.join(MANIFEST);
+    fs::write(&path, manifest)
+        .map_err(|e| anyhow::anyhow!("cannot write {}: {e}", path.display()))?;
+
+    println!(
+        "{} partitions of {} written to {}",
+        parts.len(),
+        args.image.display(),
+        args.output.display()
+    );
+
+    Ok(())
+}
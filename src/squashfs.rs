@@ -0,0 +1,555 @@
+//! SquashFS 4.0 images.
+//!
+//! Files are stored as full blocks followed by a partial one, without fragments, which keeps
+//! the layout a single pass over the tree. Blocks that do not shrink are stored uncompressed.
+//! Data blocks are 128K unless chosen otherwise, metadata blocks always 8K.
+//! Symbolic links keep their target in the inode.
+
+use crate::compress::Compression;
+use crate::tree::{Kind, Tree};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+const MAGIC: u32 = 0x7371_7368;
+const METADATA_SIZE: usize = 8192;
+const SUPERBLOCK_SIZE: u64 = 96;
+/// Images are padded to this, as mksquashfs does for block devices
+const PAD: u64 = 4096;
+
+const FLAG_NO_FRAGMENTS: u16 = 0x0010;
+const FLAG_NO_XATTRS: u16 = 0x0200;
+const FLAG_COMPRESSOR_OPTIONS: u16 = 0x0400;
+
+const METADATA_UNCOMPRESSED: u16 = 0x8000;
+const BLOCK_UNCOMPRESSED: u32 = 1 << 24;
+
+const TYPE_DIR: u16 = 1;
+const TYPE_FILE: u16 = 2;
+const TYPE_SYMLINK: u16 = 3;
+const TYPE_EXT_DIR: u16 = 8;
+const TYPE_EXT_FILE: u16 = 9;
+
+const NONE: u32 = u32::MAX;
+
+#[derive(Clone, Debug)]
+pub struct Options {
+    pub compression: Compression,
+    pub level: u8,
+    /// Size of data blocks, a power of two from 4K to 1M
+    pub block_size: u32,
+}
+
+impl Compression {
+    fn squashfs_id(self) -> u16 {
+        match self {
+            Self::Gzip => 1,
+            Self::Xz => 4,
+            Self::Lz4 => 5,
+            Self::Zstd => 6,
+        }
+    }
+}
+
+/// Writes a stream of metadata blocks, such as the inode or directory table.
+struct Metadata {
+    compression: Compression,
+    level: u8,
+    out: Vec<u8>,
+    block: Vec<u8>,
+}
+
+impl Metadata {
+    fn new(compression: Compression, level: u8) -> Self {
+        Self {
+            compression,
+            level,
+            out: vec![],
+            block: vec![],
+        }
+    }
+
+    /// Location of the next byte, as the start of its block and the offset inside of it.
+    fn position(&self) -> (u32, u16) {
+        (self.out.len() as u32, self.block.len() as u16)
+    }
+
+    fn reference(&self) -> u64 {
+        let (block, offset) = self.position();
+        ((block as u64) << 16) | offset as u64
+    }
+
+    fn write(&mut self, mut data: &[u8]) -> anyhow::Result<()> {
+        while !data.is_empty() {
+            let n = (METADATA_SIZE - self.block.len()).min(data.len());
+            self.block.extend_from_slice(&data[..n]);
+            data = &data[n..];
+            if self.block.len() == METADATA_SIZE {
+                self.flush()?;
+            }
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> anyhow::Result<()> {
+        if self.block.is_empty() {
+            return Ok(());
+        }
+
+        match self.compression.compress(&self.block, self.level)? {
+            Some(c) => {
+                self.out.extend((c.len() as u16).to_le_bytes());
+                self.out.extend(c);
+            }
+            None => {
+                self.out
+                    .extend((self.block.len() as u16 | METADATA_UNCOMPRESSED).to_le_bytes());
+                self.out.append(&mut self.block);
+            }
+        }
+
+        self.block.clear();
+        Ok(())
+    }
+
+    fn finish(mut self) -> anyhow::Result<Vec<u8>> {
+        self.flush()?;
+        Ok(self.out)
+    }
+}
+
+/// Tracks the write position, so the same code can measure and write the image.
+struct Output<'a, W> {
+    out: Option<&'a mut W>,
+    pos: u64,
+    limit: u64,
+}
+
+impl<W: Write + Seek> Output<'_, W> {
+    fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        if let Some(out) = &mut self.out {
+            if self.pos + data.len() as u64 > self.limit {
+                return Err(io::Error::other(format!(
+                    "squashfs image does not fit in {} bytes",
+                    self.limit
+                )));
+            }
+            out.write_all(data)?;
+        }
+        self.pos += data.len() as u64;
+        Ok(())
+    }
+}
+
+/// Inode number of every node, assigned in the order the inodes are written.
+fn inode_numbers(tree: &Tree, sorted: &[Vec<usize>]) -> Vec<u32> {
+    fn visit(tree: &Tree, sorted: &[Vec<usize>], idx: usize, next: &mut u32, out: &mut [u32]) {
+        for &c in &sorted[idx] {
+            if tree.nodes[c].is_dir() {
+                visit(tree, sorted, c, next, out);
+            } else {
+                *next += 1;
+                out[c] = *next;
+            }
+        }
+        *next += 1;
+        out[idx] = *next;
+    }
+
+    let mut out = vec![0; tree.nodes.len()];
+    visit(tree, sorted, 0, &mut 0, &mut out);
+    out
+}
+
+struct Writer<'a> {
+    tree: &'a Tree,
+    sorted: Vec<Vec<usize>>,
+    numbers: Vec<u32>,
+    /// Location of the data blocks and their sizes of every file
+    blocks: Vec<(u64, Vec<u32>)>,
+    /// Inode reference of every node
+    refs: Vec<u64>,
+    /// Owners in the id table, sorted
+    ids: Vec<u32>,
+    inodes: Metadata,
+    dirs: Metadata,
+}
+
+impl Writer<'_> {
+    fn header(&self, idx: usize, kind: u16) -> Vec<u8> {
+        let node = &self.tree.nodes[idx];
+        let mut h = vec![];
+        h.extend(kind.to_le_bytes());
+        h.extend((node.mode as u16 & 0o7777).to_le_bytes());
+        for id in [node.uid, node.gid] {
+            let index = self.ids.binary_search(&id).unwrap() as u16;
+            h.extend(index.to_le_bytes());
+        }
+        h.extend((node.mtime.clamp(0, u32::MAX as i64) as u32).to_le_bytes());
+        h.extend(self.numbers[idx].to_le_bytes());
+        h
+    }
+
+    fn file_inode(&mut self, idx: usize, len: u64) -> anyhow::Result<()> {
+        let (start, sizes) = &self.blocks[idx];
+
+        let mut inode = if *start > u32::MAX as u64 || len > u32::MAX as u64 {
+            let mut i = self.header(idx, TYPE_EXT_FILE);
+            i.extend(start.to_le_bytes());
+            i.extend(len.to_le_bytes());
+            // Sparse bytes, link count, fragment, fragment offset and xattr
+            i.extend(0u64.to_le_bytes());
+            i.extend(1u32.to_le_bytes());
+            i.extend(NONE.to_le_bytes());
+            i.extend(0u32.to_le_bytes());
+            i.extend(NONE.to_le_bytes());
+            i
+        } else {
+            let mut i = self.header(idx, TYPE_FILE);
+            i.extend((*start as u32).to_le_bytes());
+            i.extend(NONE.to_le_bytes());
+            i.extend(0u32.to_le_bytes());
+            i.extend((len as u32).to_le_bytes());
+            i
+        };
+
+        for size in sizes {
+            inode.extend(size.to_le_bytes());
+        }
+
+        self.refs[idx] = self.inodes.reference();
+        self.inodes.write(&inode)
+    }
+
+    fn symlink_inode(&mut self, idx: usize, target: &str) -> anyhow::Result<()> {
+        let mut inode = self.header(idx, TYPE_SYMLINK);
+        inode.extend(1u32.to_le_bytes());
+        inode.extend((target.len() as u32).to_le_bytes());
+        inode.extend(target.as_bytes());
+
+        self.refs[idx] = self.inodes.reference();
+        self.inodes.write(&inode)
+    }
+
+    /// Directory listing, split into runs whose inodes share a metadata block.
+    fn listing(&self, idx: usize) -> Vec<u8> {
+        let mut out = vec![];
+        let children = &self.sorted[idx];
+        let mut i = 0;
+
+        while i < children.len() {
+            let block = self.refs[children[i]] >> 16;
+            let base = self.numbers[children[i]];
+
+            let run = children[i..]
+                .iter()
+                .take(256)
+                .take_while(|&&c| {
+                    self.refs[c] >> 16 == block
+                        && (self.numbers[c] as i64 - base as i64).abs() <= i16::MAX as i64
+                })
+                .count();
+
+            out.extend((run as u32 - 1).to_le_bytes());
+            out.extend((block as u32).to_le_bytes());
+            out.extend(base.to_le_bytes());
+
+            for &c in &children[i..i + run] {
+                let node = &self.tree.nodes[c];
+                let kind = match node.kind {
+                    Kind::Dir(_) => TYPE_DIR,
+                    Kind::File { .. } => TYPE_FILE,
+                    Kind::Symlink(_) => TYPE_SYMLINK,
+                };
+                out.extend((self.refs[c] as u16).to_le_bytes());
+                out.extend(((self.numbers[c] as i64 - base as i64) as i16).to_le_bytes());
+                out.extend(kind.to_le_bytes());
+                out.extend((node.name.len() as u16 - 1).to_le_bytes());
+                out.extend(node.name.as_bytes());
+            }
+
+            i += run;
+        }
+
+        out
+    }
+
+    fn dir_inode(&mut self, idx: usize) -> anyhow::Result<()> {
+        for c in self.sorted[idx].clone() {
+            match &self.tree.nodes[c].kind {
+                Kind::Dir(_) => self.dir_inode(c)?,
+                Kind::File { len, .. } => self.file_inode(c, *len)?,
+                Kind::Symlink(target) => self.symlink_inode(c, target)?,
+            }
+        }
+
+        let listing = self.listing(idx);
+        let (start, offset) = self.dirs.position();
+        self.dirs.write(&listing)?;
+
+        let node = &self.tree.nodes[idx];
+        let links = 2 + self.sorted[idx]
+            .iter()
+            .filter(|&&c| self.tree.nodes[c].is_dir())
+            .count() as u32;
+        // The root's parent is past the last inode
+        let parent = match idx {
+            0 => self.numbers[0] + 1,
+            _ => self.numbers[node.parent],
+        };
+        // Listing sizes include 3 bytes for the implied . and .. entries
+        let size = listing.len() + 3;
+
+        let inode = if size > u16::MAX as usize {
+            let mut i = self.header(idx, TYPE_EXT_DIR);
+            i.extend(links.to_le_bytes());
+            i.extend((size as u32).to_le_bytes());
+            i.extend(start.to_le_bytes());
+            i.extend(parent.to_le_bytes());
+            // No directory index
+            i.extend(0u16.to_le_bytes());
+            i.extend(offset.to_le_bytes());
+            i.extend(NONE.to_le_bytes());
+            i
+        } else {
+            let mut i = self.header(idx, TYPE_DIR);
+            i.extend(start.to_le_bytes());
+            i.extend(links.to_le_bytes());
+            i.extend((size as u16).to_le_bytes());
+            i.extend(offset.to_le_bytes());
+            i.extend(parent.to_le_bytes());
+            i
+        };
+
+        self.refs[idx] = self.inodes.reference();
+        self.inodes.write(&inode)
+    }
+}
+
+/// Lay out the image, writing it to `out` if given, which spans `size` bytes, with `time` as its
+/// creation time. Returns the size of the image.
+fn build<W: Write + Seek>(
+    mut out: Option<&mut W>,
+    size: u64,
+    tree: &Tree,
+    opts: &Options,
+    time: i64,
+    on_file: &mut dyn FnMut(&Path, u64),
+) -> anyhow::Result<u64> {
+    let (compression, level, block_size) = (opts.compression, opts.level, opts.block_size);
+
+    let sorted = tree
+        .nodes
+        .iter()
+        .map(|node| match &node.kind {
+            Kind::Dir(children) => {
+                let mut children = children.clone();
+                children.sort_by(|&a, &b| tree.nodes[a].name.cmp(&tree.nodes[b].name));
+                children
+            }
+            Kind::File { .. } | Kind::Symlink(_) => vec![],
+        })
+        .collect::<Vec<_>>();
+
+    let ids = tree
+        .nodes
+        .iter()
+        .flat_map(|n| [n.uid, n.gid])
+        .collect::<std::collections::BTreeSet<_>>()
+        .into_iter()
+        .collect::<Vec<_>>();
+    if ids.len() > u16::MAX as usize {
+        anyhow::bail!("squashfs images are limited to {} owners", u16::MAX);
+    }
+
+    for node in &tree.nodes[1..] {
+        if node.name.len() > 256 {
+            anyhow::bail!(
+                "squashfs names are limited to 256 bytes: {}",
+                node.path.display()
+            );
+        }
+    }
+
+    // Compressor options go right after the superblock
+    let mut options = Metadata::new(compression, level);
+    if compression == Compression::Lz4 {
+        // Legacy LZ4 format, no flags
+        let mut o = vec![];
+        o.extend(1u32.to_le_bytes());
+        o.extend(0u32.to_le_bytes());
+        options.write(&o)?;
+    }
+    let options = options.finish()?;
+
+    if let Some(out) = &mut out {
+        out.seek(SeekFrom::Start(SUPERBLOCK_SIZE))?;
+    }
+    let mut o = Output {
+        out: out.as_deref_mut(),
+        pos: SUPERBLOCK_SIZE,
+        limit: size,
+    };
+    o.write(&options)?;
+
+    // Data blocks
+    let mut blocks = vec![(0, vec![]); tree.nodes.len()];
+    let mut block = vec![0u8; block_size as usize];
+
+    for (idx, node) in tree.nodes.iter().enumerate() {
+        let Kind::File { source, len } = &node.kind else {
+            continue;
+        };
+
+        let start = o.pos;
+        let mut sizes = vec![];
+        let mut reader = source.open()?.take(*len);
+        let mut remaining = *len;
+
+        while remaining > 0 {
+            let n = remaining.min(block_size as u64) as usize;
+            reader
+                .read_exact(&mut block[..n])
+                .map_err(|e| match e.kind() {
+                    io::ErrorKind::UnexpectedEof => {
+                        anyhow::anyhow!("{} changed while being copied", node.path.display())
+                    }
+                    _ => e.into(),
+                })?;
+
+            match compression.compress(&block[..n], level)? {
+                Some(c) => {
+                    o.write(&c)?;
+                    sizes.push(c.len() as u32);
+                }
+                None => {
+                    o.write(&block[..n])?;
+                    sizes.push(n as u32 | BLOCK_UNCOMPRESSED);
+                }
+            }
+
+            remaining -= n as u64;
+        }
+
+        blocks[idx] = (start, sizes);
+        if o.out.is_some() {
+            on_file(&node.path, *len);
+        }
+    }
+
+    // Inode and directory tables
+    let numbers = inode_numbers(tree, &sorted);
+    let mut w = Writer {
+        tree,
+        sorted,
+        numbers,
+        blocks,
+        refs: vec![0; tree.nodes.len()],
+        ids,
+        inodes: Metadata::new(compression, level),
+        dirs: Metadata::new(compression, level),
+    };
+    w.dir_inode(0)?;
+
+    let root = w.refs[0];
+    let inode_count = tree.nodes.len() as u32;
+
+    let inode_table = o.pos;
+    o.write(&w.inodes.finish()?)?;
+    let dir_table = o.pos;
+    o.write(&w.dirs.finish()?)?;
+
+    // Id table, and the index of its metadata blocks, each of which is filled before the next
+    let mut ids = Metadata::new(compression, level);
+    let mut id_blocks = vec![];
+    let ids_start = o.pos;
+    for chunk in w.ids.chunks(METADATA_SIZE / 4) {
+        id_blocks.push(ids_start + ids.position().0 as u64);
+        for id in chunk {
+            ids.write(&id.to_le_bytes())?;
+        }
+    }
+    o.write(&ids.finish()?)?;
+    let id_table = o.pos;
+    for block in &id_blocks {
+        o.write(&block.to_le_bytes())?;
+    }
+    let id_count = w.ids.len() as u16;
+
+    let bytes_used = o.pos;
+    let padded = bytes_used.next_multiple_of(PAD);
+    o.write(&vec![0; (padded - bytes_used) as usize])?;
+
+    if let Some(out) = out {
+        let mut sb = vec![];
+        sb.extend(MAGIC.to_le_bytes());
+        sb.extend(inode_count.to_le_bytes());
+        sb.extend((time.clamp(0, u32::MAX as i64) as u32).to_le_bytes());
+        sb.extend(block_size.to_le_bytes());
+        // Fragments
+        sb.extend(0u32.to_le_bytes());
+        sb.extend(compression.squashfs_id().to_le_bytes());
+        sb.extend((block_size.trailing_zeros() as u16).to_le_bytes());
+        let mut flags = FLAG_NO_FRAGMENTS | FLAG_NO_XATTRS;
+        if !options.is_empty() {
+            flags |= FLAG_COMPRESSOR_OPTIONS;
+        }
+        sb.extend(flags.to_le_bytes());
+        // Id count, version 4.0
+        sb.extend(id_count.to_le_bytes());
+        sb.extend(4u16.to_le_bytes());
+        sb.extend(0u16.to_le_bytes());
+        sb.extend(root.to_le_bytes());
+        sb.extend(bytes_used.to_le_bytes());
+        sb.extend(id_table.to_le_bytes());
+        // No xattr table
+        sb.extend(u64::MAX.to_le_bytes());
+        sb.extend(inode_table.to_le_bytes());
+        sb.extend(dir_table.to_le_bytes());
+        // No fragment and export tables
+        sb.extend(u64::MAX.to_le_bytes());
+        sb.extend(u64::MAX.to_le_bytes());
+
+        out.seek(SeekFrom::Start(0))?;
+        out.write_all(&sb)?;
+    }
+
+    Ok(padded)
+}
+
+/// Size of the image, found by compressing the tree without writing it.
+pub fn estimate_size(tree: &Tree, opts: &Options) -> anyhow::Result<u64> {
+    build::<io::Cursor<Vec<u8>>>(None, u64::MAX, tree, opts, 0, &mut |_, _| ())
+}
+
+/// Write the image into `out`, which spans `size` bytes.
+///
+/// `on_file` is called with the image path and length of every file written.
+pub fn write<W: Write + Seek>(
+    out: &mut W,
+    size: u64,
+    tree: &Tree,
+    opts: &Options,
+    ctx: &crate::BuildContext,
+    on_file: &mut dyn FnMut(&Path, u64),
+) -> anyhow::Result<()> {
+    build(Some(out), size, tree, opts, ctx.time.timestamp(), on_file)?;
+
+    Ok(())
+}
+
+impl crate::FsWriter for Options {
+    fn estimate_size(&self, tree: &Tree) -> anyhow::Result<u64> {
+        estimate_size(tree, self)
+    }
+
+    fn write(
+        &self,
+        mut out: &mut dyn fatfs::ReadWriteSeek,
+        size: u64,
+        tree: &Tree,
+        ctx: &crate::BuildContext,
+        on_file: &mut dyn FnMut(&Path, u64),
+    ) -> anyhow::Result<()> {
+        write(&mut out, size, tree, self, ctx, on_file)
+    }
+}
@@ -0,0 +1,240 @@
+//! In-place update of an existing image from a directory tree.
+
+use crate::{image, walk_dir};
+use clap::Args;
+use fatfs::{Dir, FileSystem, FsOptions, ReadWriteSeek};
+use log::*;
+use std::collections::HashSet;
+use std::fs::{File, Metadata};
+use std::io::{self, Read};
+use std::path::PathBuf;
+
+#[derive(Args, Debug)]
+pub struct SyncArgs {
+    /// Image to update
+    #[arg(required_unless_present = "image_flag")]
+    image: Option<PathBuf>,
+    /// Image to update, instead of giving it as the first argument
+    #[arg(
+        long = "image",
+        id = "image_flag",
+        value_name = "IMAGE",
+        conflicts_with = "image"
+    )]
+    image_flag: Option<PathBuf>,
+    /// Directory to bring the image in line with
+    #[arg(short, long)]
+    input_dir: PathBuf,
+    /// Partition to update. Defaults to the first one
+    #[arg(short, long)]
+    partition: Option<usize>,
+    /// Whether to follow symlinks in the input directory or skip them
+    #[arg(short, long)]
+    link_follow: bool,
+    /// Keep files in the image that are not present in the input directory
+    #[arg(long)]
+    no_delete: bool,
+}
+
+/// Directory in the image, along with the names the input directory has in it.
+struct SyncDir<'a, T: ReadWriteSeek> {
+    dir: Dir<'a, T>,
+    /// Lower case, as FAT names are case insensitive
+    seen: HashSet<String>,
+}
+
+#[derive(Default, Debug)]
+struct Stats {
+    added: u64,
+    updated: u64,
+    unchanged: u64,
+}
+
+/// Host modification time in the resolution FAT stores it in.
+fn fat_mtime(metadata: &Metadata) -> Option<fatfs::DateTime> {
+    let mtime = chrono::DateTime::<chrono::Local>::from(metadata.modified().ok()?);
+    let mut mtime = fatfs::DateTime::from(mtime);
+    mtime.time.sec &= !1;
+    mtime.time.millis = 0;
+    Some(mtime)
+}
+
+fn same_mtime(a: &fatfs::DateTime, b: &fatfs::DateTime) -> bool {
+    a.date == b.date
+        && (a.time.hour, a.time.min, a.time.sec) == (b.time.hour, b.time.min, b.time.sec)
+}
+
+/// Whether two readers produce the same bytes.
+fn same_content(mut a: impl Read, mut b: impl Read) -> io::Result<bool> {
+    let mut buf_a = vec![0; 0x10000];
+    let mut buf_b = vec![0; 0x10000];
+
+    loop {
+        let n = a.read(&mut buf_a)?;
+
+        if n == 0 {
+            return Ok(b.read(&mut buf_b[..1])? == 0);
+        }
+
+        b.read_exact(&mut buf_b[..n])?;
+
+        if buf_a[..n] != buf_b[..n] {
+            return Ok(false);
+        }
+    }
+}
+
+/// Remove a directory inside `dir` along with everything in it.
+pub fn remove_all<T: ReadWriteSeek>(dir: &Dir<T>, name: &str) -> io::Result<()> {
+    let sub = dir.open_dir(name)?;
+
+    let names = sub
+        .iter()
+        .map(|e| e.map(|e| (e.file_name(), e.is_dir())))
+        .collect::<io::Result<Vec<_>>>()?;
+
+    for (child, is_dir) in names {
+        if child == "." || child == ".." {
+            continue;
+        }
+        if is_dir {
+            remove_all(&sub, &child)?;
+        } else {
+            sub.remove(&child)?;
+        }
+    }
+
+    dir.remove(name)
+}
+
+/// Remove entries of the image directory that were not found in the input directory.
+fn prune<T: ReadWriteSeek>(dir: &SyncDir<T>, removed: &mut u64) -> io::Result<()> {
+    let stale = dir
+        .dir
+        .iter()
+        .map(|e| e.map(|e| (e.file_name(), e.is_dir())))
+        .filter(|e| match e {
+            Ok((name, _)) => {
+                name != "." && name != ".." && !dir.seen.contains(&name.to_lowercase())
+            }
+            Err(_) => true,
+        })
+        .collect::<io::Result<Vec<_>>>()?;
+
+    for (name, is_dir) in stale {
+        info!("REMOVE: {name}");
+        if is_dir {
+            remove_all(&dir.dir, &name)?;
+        } else {
+            dir.dir.remove(&name)?;
+        }
+        *removed += 1;
+    }
+
+    Ok(())
+}
+
+pub fn run(args: &SyncArgs) -> anyhow::Result<()> {
+    let path = args.image.as_ref().or(args.image_flag.as_ref()).unwrap();
+    let part = image::open_partition(path, true, args.partition)?;
+    let fs = FileSystem::new(fscommon::BufStream::new(part), FsOptions::new())?;
+
+    let mut stats = Stats::default();
+    let mut removed = 0;
+
+    let root = SyncDir {
+        dir: fs.root_dir(),
+        seen: HashSet::new(),
+    };
+
+    walk_dir(
+        &args.input_dir,
+        &args.input_dir,
+        args.link_follow,
+        true,
+        root,
+        &mut |_, short_path, parent: &mut SyncDir<_>, _| {
+            let name = short_path.file_name().unwrap().to_str().unwrap();
+            parent.seen.insert(name.to_lowercase());
+
+            // A file may be replaced by a directory of the same name
+            if let Ok(file) = parent.dir.open_file(name) {
+                std::mem::drop(file);
+                parent.dir.remove(name)?;
+            }
+
+            Ok(SyncDir {
+                dir: parent.dir.create_dir(name)?,
+                seen: HashSet::new(),
+            })
+        },
+        &mut |path, short_path, parent: &mut SyncDir<_>, metadata| {
+            if metadata.is_symlink() {
+                warn!("Skipping symlink - {}", short_path.display());
+                return Ok(());
+            }
+            let name = short_path.file_name().unwrap().to_str().unwrap();
+            parent.seen.insert(name.to_lowercase());
+
+            let mtime = fat_mtime(metadata);
+
+            let existing = parent
+                .dir
+                .iter()
+                .filter_map(Result::ok)
+                .find(|e| e.file_name().eq_ignore_ascii_case(name));
+
+            match &existing {
+                Some(e) if e.is_dir() => remove_all(&parent.dir, &e.file_name())?,
+                Some(e) if e.len() == metadata.len() => {
+                    let unchanged = match &mtime {
+                        Some(mtime) if same_mtime(&e.modified(), mtime) => true,
+                        _ => same_content(File::open(path)?, e.to_file())?,
+                    };
+
+                    if unchanged {
+                        stats.unchanged += 1;
+                        return Ok(());
+                    }
+                }
+                _ => {}
+            }
+
+            let mut file = parent.dir.create_file(name)?;
+
+            if existing.is_some() {
+                info!("UPDATE: {}", short_path.display());
+                stats.updated += 1;
+            } else {
+                info!("ADD: {}", short_path.display());
+                stats.added += 1;
+            }
+
+            file.truncate()?;
+            io::copy(&mut File::open(path)?, &mut file)?;
+
+            if let Some(mtime) = mtime {
+                // Deprecated only in favor of an API that 0.3 does not have yet
+                #[allow(deprecated)]
+                file.set_modified(mtime);
+            }
+
+            Ok(())
+        },
+        &mut |_, dir| {
+            if !args.no_delete {
+                prune(&dir, &mut removed)?;
+            }
+            Ok(())
+        },
+    )?;
+
+    fs.unmount()?;
+
+    println!(
+        "{} added, {} updated, {} unchanged, {} removed",
+        stats.added, stats.updated, stats.unchanged, removed
+    );
+
+    Ok(())
+}
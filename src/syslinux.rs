@@ -0,0 +1,349 @@
+//! Installation of syslinux into a FAT volume, as done by the `syslinux` installer.
+//!
+//! `ldlinux.sys` is copied into the root directory along with the input, followed by two sectors
+//! of the auxiliary data vector (ADV). Its patch area then gets the sectors it occupies, and the
+//! boot sector code from `ldlinux.bss` replaces that of the volume, keeping its BPB, with the
+//! first of them patched in.
+
+use crate::{ExtraFile, FileSource};
+use anyhow::Context;
+use fscommon::StreamSlice;
+use log::*;
+use std::fs::{self, File};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+const SECTOR_SIZE: usize = 512;
+
+const LDLINUX_MAGIC: u32 = 0x3eb202fe;
+
+const ADV_MAGIC1: u32 = 0x5a2d2fa5;
+const ADV_MAGIC2: u32 = 0xa3041767;
+const ADV_MAGIC3: u32 = 0xdd28bf64;
+
+/// Where the boot sector code loads `ldlinux.sys` after its first sector.
+const LOAD_ADDRESS: u32 = 0x8000;
+
+fn get_16(b: &[u8], off: usize) -> usize {
+    u16::from_le_bytes([b[off], b[off + 1]]) as usize
+}
+
+fn get_32(b: &[u8], off: usize) -> u32 {
+    u32::from_le_bytes(b[off..off + 4].try_into().unwrap())
+}
+
+fn set_16(b: &mut [u8], off: usize, v: u16) {
+    b[off..off + 2].copy_from_slice(&v.to_le_bytes());
+}
+
+fn set_32(b: &mut [u8], off: usize, v: u32) {
+    b[off..off + 4].copy_from_slice(&v.to_le_bytes());
+}
+
+fn set_64(b: &mut [u8], off: usize, v: u64) {
+    b[off..off + 8].copy_from_slice(&v.to_le_bytes());
+}
+
+/// An empty ADV, stored twice.
+fn empty_adv() -> Vec<u8> {
+    let mut adv = vec![0; 2 * SECTOR_SIZE];
+    for copy in adv.chunks_mut(SECTOR_SIZE) {
+        set_32(copy, 0, ADV_MAGIC1);
+        // Negative checksum of the all-zero payload
+        set_32(copy, 4, ADV_MAGIC2);
+        set_32(copy, SECTOR_SIZE - 4, ADV_MAGIC3);
+    }
+    adv
+}
+
+/// Describe `sectors` loaded one after another from `LOAD_ADDRESS` as extents, which may not
+/// reach 64K in length or cross a 64K boundary in memory.
+fn write_extents(ex: &mut [u8], sectors: &[u64]) {
+    ex.fill(0);
+
+    let mut extents = vec![];
+    let mut addr = LOAD_ADDRESS;
+    let mut base = addr;
+    let (mut lba, mut len) = (0, 0u32);
+
+    for &sect in sectors {
+        let bytes = (len + 1) * SECTOR_SIZE as u32;
+        if len > 0
+            && sect == lba + len as u64
+            && bytes < 0x10000
+            && (addr ^ (base + bytes - 1)) & 0xffff0000 == 0
+        {
+            len += 1;
+        } else {
+            if len > 0 {
+                extents.push((lba, len));
+            }
+            base = addr;
+            lba = sect;
+            len = 1;
+        }
+        addr += SECTOR_SIZE as u32;
+    }
+
+    if len > 0 {
+        extents.push((lba, len));
+    }
+
+    for ((lba, len), ex) in extents.into_iter().zip(ex.chunks_mut(10)) {
+        set_64(ex, 0, lba);
+        set_16(ex, 8, len as u16);
+    }
+}
+
+/// Patch the sector locations of `ldlinux.sys` into it and into the boot sector code.
+///
+/// `sectors` holds those of the image followed by the two ADV sectors.
+fn patch(image: &mut [u8], bootsect: &mut [u8], sectors: &[u64]) -> anyhow::Result<()> {
+    let patch_area = (0..image.len() - 24)
+        .step_by(4)
+        .find(|&off| get_32(image, off) == LDLINUX_MAGIC)
+        .context("no patch area in ldlinux.sys")?;
+
+    let epa = get_16(image, patch_area + 22);
+    let field = |off| get_16(image, epa + off);
+    let (adv_ptrs, sect_ptrs, sect_ptr_count) = (field(0), field(10), field(12));
+    let (sect1_ptr0, sect1_ptr1) = (field(14), field(16));
+
+    let data_sectors = image.len() / SECTOR_SIZE;
+    if sectors.len() > sect_ptr_count {
+        anyhow::bail!(
+            "ldlinux.sys has room for {sect_ptr_count} sectors, it needs {}",
+            sectors.len()
+        );
+    }
+
+    // The boot sector loads the first one itself
+    set_32(bootsect, sect1_ptr0, sectors[0] as u32);
+    set_32(bootsect, sect1_ptr1, (sectors[0] >> 32) as u32);
+
+    set_16(image, patch_area + 8, data_sectors as u16);
+    set_16(image, patch_area + 10, 2);
+    set_32(image, patch_area + 12, (image.len() / 4) as u32);
+
+    write_extents(
+        &mut image[sect_ptrs..sect_ptrs + sect_ptr_count * 10],
+        &sectors[1..data_sectors],
+    );
+
+    set_64(image, adv_ptrs, sectors[data_sectors]);
+    set_64(image, adv_ptrs + 8, sectors[data_sectors + 1]);
+
+    set_32(image, patch_area + 16, 0);
+    let checksum = image
+        .chunks(4)
+        .fold(LDLINUX_MAGIC, |sum, dw| sum.wrapping_sub(get_32(dw, 0)));
+    set_32(image, patch_area + 16, checksum);
+
+    Ok(())
+}
+
+/// Geometry of a FAT volume, from its boot sector.
+struct Fat {
+    sectors_per_cluster: u64,
+    fat_start: u64,
+    fat_sectors: u64,
+    root_start: u64,
+    root_entries: u64,
+    data_start: u64,
+    /// FAT12, FAT16 or FAT32
+    bits: u32,
+    root_cluster: u32,
+}
+
+impl Fat {
+    fn new(vbr: &[u8]) -> anyhow::Result<Self> {
+        if get_16(vbr, 11) != SECTOR_SIZE {
+            anyhow::bail!("syslinux requires 512 byte sectors");
+        }
+
+        let sectors_per_cluster = vbr[13] as u64;
+        let reserved = get_16(vbr, 14) as u64;
+        let fats = vbr[16] as u64;
+        let root_entries = get_16(vbr, 17) as u64;
+        let total = match get_16(vbr, 19) {
+            0 => get_32(vbr, 32) as u64,
+            n => n as u64,
+        };
+        let fat_sectors = match get_16(vbr, 22) {
+            0 => get_32(vbr, 36) as u64,
+            n => n as u64,
+        };
+
+        let root_start = reserved + fats * fat_sectors;
+        let data_start = root_start + (root_entries * 32).div_ceil(SECTOR_SIZE as u64);
+        let clusters = (total - data_start) / sectors_per_cluster;
+
+        let bits = match clusters {
+            0..4085 => 12,
+            4085..65525 => 16,
+            _ => 32,
+        };
+
+        Ok(Self {
+            sectors_per_cluster,
+            fat_start: reserved,
+            fat_sectors,
+            root_start,
+            root_entries,
+            data_start,
+            bits,
+            root_cluster: get_32(vbr, 44),
+        })
+    }
+
+    /// Clusters of the chain starting at `first`.
+    fn chain(&self, fat: &[u8], first: u32) -> anyhow::Result<Vec<u32>> {
+        let mut clusters = vec![];
+        let mut cluster = first;
+
+        loop {
+            if cluster < 2 || clusters.len() > fat.len() {
+                anyhow::bail!("broken cluster chain");
+            }
+            clusters.push(cluster);
+
+            let c = cluster as usize;
+            cluster = match self.bits {
+                12 if c % 2 == 1 => (get_16(fat, c + c / 2) >> 4) as u32,
+                12 => (get_16(fat, c + c / 2) & 0xfff) as u32,
+                16 => get_16(fat, c * 2) as u32,
+                _ => get_32(fat, c * 4) & 0x0fffffff,
+            };
+
+            let end = match self.bits {
+                12 => 0xff8,
+                16 => 0xfff8,
+                _ => 0x0ffffff8,
+            };
+            if cluster >= end {
+                return Ok(clusters);
+            }
+        }
+    }
+
+    fn cluster_sectors(&self, cluster: u32) -> std::ops::Range<u64> {
+        let first = self.data_start + (cluster as u64 - 2) * self.sectors_per_cluster;
+        first..first + self.sectors_per_cluster
+    }
+}
+
+fn read_at<T: Read + Seek>(vol: &mut T, sector: u64, len: usize) -> io::Result<Vec<u8>> {
+    let mut buf = vec![0; len];
+    vol.seek(SeekFrom::Start(sector * SECTOR_SIZE as u64))?;
+    vol.read_exact(&mut buf)?;
+    Ok(buf)
+}
+
+/// Sectors of `LDLINUX.SYS` in the root directory, relative to the start of the volume.
+fn file_sectors<T: Read + Seek>(vol: &mut T, fat: &Fat) -> anyhow::Result<Vec<u64>> {
+    let table = read_at(vol, fat.fat_start, (fat.fat_sectors as usize) * SECTOR_SIZE)?;
+
+    let root = match fat.bits {
+        32 => {
+            let mut root = vec![];
+            for cluster in fat.chain(&table, fat.root_cluster)? {
+                let sectors = fat.cluster_sectors(cluster);
+                let len = sectors.end - sectors.start;
+                root.extend(read_at(vol, sectors.start, len as usize * SECTOR_SIZE)?);
+            }
+            root
+        }
+        _ => read_at(vol, fat.root_start, fat.root_entries as usize * 32)?,
+    };
+
+    let entry = root
+        .chunks(32)
+        .take_while(|e| e[0] != 0)
+        .find(|e| &e[..11] == b"LDLINUX SYS" && e[11] & 0x18 == 0)
+        .context("ldlinux.sys is not in the root directory")?;
+
+    let first = (get_16(entry, 20) as u32) << 16 | get_16(entry, 26) as u32;
+
+    Ok(fat
+        .chain(&table, first)?
+        .into_iter()
+        .flat_map(|c| fat.cluster_sectors(c))
+        .collect())
+}
+
+fn read(dir: &Path, name: &str) -> anyhow::Result<Vec<u8>> {
+    let path = dir.join(name);
+    fs::read(&path).with_context(|| format!("cannot read {}", path.display()))
+}
+
+/// `ldlinux.sys` padded to whole sectors.
+fn boot_image(dir: &Path) -> anyhow::Result<Vec<u8>> {
+    let mut image = read(dir, "ldlinux.sys")?;
+    image.resize(image.len().next_multiple_of(SECTOR_SIZE), 0);
+    Ok(image)
+}
+
+/// Files copied into the root of the volume, with `ldlinux.sys` still to be patched by
+/// [`install`].
+pub fn files(dir: &Path) -> anyhow::Result<Vec<ExtraFile>> {
+    let mut ldlinux = boot_image(dir)?;
+    ldlinux.extend(empty_adv());
+
+    let mut files = vec![ExtraFile {
+        dest: "ldlinux.sys".into(),
+        source: FileSource::Data(ldlinux.into()),
+    }];
+
+    // Loaded by ldlinux.sys of syslinux 5 and later
+    let module = dir.join("ldlinux.c32");
+    if module.exists() {
+        files.push(ExtraFile {
+            dest: "ldlinux.c32".into(),
+            source: FileSource::Host(module),
+        });
+    }
+
+    Ok(files)
+}
+
+/// Make the FAT volume `start..start + len` of `file`, with [`files`] copied in, boot syslinux.
+pub fn install(file: &File, start: u64, len: u64, dir: &Path) -> anyhow::Result<()> {
+    let mut image = boot_image(dir)?;
+    let mut bootsect = read(dir, "ldlinux.bss")?;
+
+    if bootsect.len() != SECTOR_SIZE || bootsect[510..] != [0x55, 0xaa] {
+        anyhow::bail!("ldlinux.bss is not a boot sector");
+    }
+
+    let mut vol = StreamSlice::new(file.try_clone()?, start, start + len)?;
+
+    let mut vbr = read_at(&mut vol, 0, SECTOR_SIZE)?;
+    let fat = Fat::new(&vbr)?;
+
+    let sectors = file_sectors(&mut vol, &fat)?;
+    let data_sectors = image.len() / SECTOR_SIZE;
+    let sectors = sectors
+        .get(..data_sectors + 2)
+        .context("ldlinux.sys is shorter than written")?;
+
+    debug!("ldlinux.sys sectors: {sectors:x?}");
+
+    patch(&mut image, &mut bootsect, sectors)?;
+
+    for (chunk, &sector) in image.chunks(SECTOR_SIZE).zip(sectors) {
+        vol.seek(SeekFrom::Start(sector * SECTOR_SIZE as u64))?;
+        vol.write_all(chunk)?;
+    }
+
+    // Jump and OEM name, and the code after the FAT32 BPB, which also fits FAT12 and FAT16
+    vbr[..11].copy_from_slice(&bootsect[..11]);
+    vbr[90..510].copy_from_slice(&bootsect[90..510]);
+    // The boot sector code adds these to the sectors it loads. fatfs leaves them zero.
+    let hidden = u32::try_from(start / SECTOR_SIZE as u64).context("volume starts too far")?;
+    set_32(&mut vbr, 28, hidden);
+
+    vol.seek(SeekFrom::Start(0))?;
+    vol.write_all(&vbr)?;
+
+    Ok(())
+}
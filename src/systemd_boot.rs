@@ -0,0 +1,109 @@
+//! ESP layout for systemd-boot, like the one `bootctl install` sets up.
+
+use crate::tree::{Kind, Tree};
+use crate::{ExtraFile, FileSource};
+use anyhow::Context;
+use std::path::Path;
+
+/// Kernel and initrd copied into the ESP or XBOOTLDR partition, with a boot entry for them.
+#[derive(Clone, Debug)]
+pub struct Kernel<'a> {
+    pub kernel: &'a Path,
+    pub initrd: Option<&'a Path>,
+    pub cmdline: Option<&'a str>,
+    /// Title of the boot entry, `Linux` if not set
+    pub title: Option<&'a str>,
+}
+
+fn file_name(path: &Path) -> anyhow::Result<&str> {
+    path.file_name()
+        .and_then(|n| n.to_str())
+        .ok_or_else(|| anyhow::anyhow!("non-UTF-8 file name {path:?}"))
+}
+
+fn data(dest: &str, data: String) -> ExtraFile {
+    ExtraFile {
+        dest: dest.into(),
+        source: FileSource::Data(data.into_bytes().into()),
+    }
+}
+
+/// Files added to the ESP built from `tree`.
+///
+/// The systemd-boot binary must be in `EFI/systemd`. It is copied to the removable media path
+/// in `EFI/BOOT`, and `loader/loader.conf` is created, unless the input has them. Names are
+/// matched ignoring case, as FAT does.
+pub fn files(tree: &Tree) -> anyhow::Result<Vec<ExtraFile>> {
+    let (name, loader) = tree
+        .find_ignore_case("EFI/systemd")
+        .and_then(|dir| match &tree.nodes[dir].kind {
+            Kind::Dir(children) => Some(children),
+            _ => None,
+        })
+        .into_iter()
+        .flatten()
+        .map(|&c| &tree.nodes[c])
+        .find_map(|node| {
+            let name = node.name.to_ascii_lowercase();
+            match &node.kind {
+                Kind::File { source, .. }
+                    if name.starts_with("systemd-boot") && name.ends_with(".efi") =>
+                {
+                    Some((node.name.to_ascii_uppercase(), source.clone()))
+                }
+                _ => None,
+            }
+        })
+        .context("no systemd-boot binary in EFI/systemd of the input")?;
+
+    let mut files = vec![];
+
+    // systemd-bootx64.efi is booted as BOOTX64.EFI by firmware without a boot entry for it
+    let fallback = format!("EFI/BOOT/BOOT{}", &name["SYSTEMD-BOOT".len()..]);
+    if tree.find_ignore_case(&fallback).is_none() {
+        files.push(ExtraFile {
+            dest: fallback.into(),
+            source: loader,
+        });
+    }
+
+    if tree.find_ignore_case("loader/loader.conf").is_none() {
+        files.push(data(
+            "loader/loader.conf",
+            "#timeout 3\n#console-mode keep\n".into(),
+        ));
+    }
+
+    Ok(files)
+}
+
+/// The kernel and initrd in the root of their partition, and the boot entry for them in
+/// `loader/entries`. systemd-boot reads the entries of the ESP and the XBOOTLDR partition, with
+/// paths relative to the partition of each entry.
+pub fn kernel_files(kernel: Kernel) -> anyhow::Result<Vec<ExtraFile>> {
+    let name = file_name(kernel.kernel)?;
+    let title = kernel.title.unwrap_or("Linux");
+    let mut entry = format!("title {title}\nlinux /{name}\n");
+
+    let mut files = vec![ExtraFile {
+        dest: name.into(),
+        source: FileSource::Host(kernel.kernel.to_owned()),
+    }];
+
+    if let Some(initrd) = kernel.initrd {
+        let name = file_name(initrd)?;
+        entry.push_str(&format!("initrd /{name}\n"));
+        files.push(ExtraFile {
+            dest: name.into(),
+            source: FileSource::Host(initrd.to_owned()),
+        });
+    }
+
+    if let Some(cmdline) = kernel.cmdline {
+        entry.push_str(&format!("options {cmdline}\n"));
+    }
+
+    files.push(data("loader/entries/linux.conf", entry));
+
+    Ok(files)
+}
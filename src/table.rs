@@ -0,0 +1,256 @@
+//! Changes to the partition tables of existing images.
+
+use crate::{image, PartitionTable};
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom, Write};
+
+/// Alignment of partitions added to existing images
+pub const ALIGN: u64 = 1 << 20;
+
+/// Logical block size of the gpt crate for sectors of `sector` bytes.
+pub fn lb_size(sector: u64) -> gpt::disk::LogicalBlockSize {
+    match sector {
+        4096 => gpt::disk::LogicalBlockSize::Lb4096,
+        _ => gpt::disk::LogicalBlockSize::Lb512,
+    }
+}
+
+/// Bytes taken by the 128 entries of 128 bytes new tables have
+const ENTRIES: u64 = 0x4000;
+
+/// GPT of an existing image, along with its used entries.
+pub struct Gpt {
+    /// `None` for a table that is not on disk yet
+    header: Option<gpt::header::Header>,
+    /// Bytes per logical sector
+    pub sector: u64,
+    pub partitions: BTreeMap<u32, gpt::partition::Partition>,
+}
+
+impl Gpt {
+    /// Table with a random disk GUID, to be written to an image without one.
+    pub fn new(partitions: BTreeMap<u32, gpt::partition::Partition>, sector: u64) -> Self {
+        Self {
+            header: None,
+            sector,
+            partitions,
+        }
+    }
+
+    pub fn read(file: &mut File) -> anyhow::Result<Self> {
+        let sector = image::sector_size(file)?;
+        let header = gpt::header::read_header_from_arbitrary_device(file, lb_size(sector))?;
+        let partitions = gpt::partition::file_read_partitions(file, &header, lb_size(sector))?
+            .into_iter()
+            .filter(|(_, p)| p.is_used())
+            .collect();
+
+        Ok(Self {
+            header: Some(header),
+            sector,
+            partitions,
+        })
+    }
+
+    /// Byte offset of the first sector partitions may start at.
+    pub fn first_usable(&self) -> u64 {
+        match &self.header {
+            Some(header) => header.first_usable * self.sector,
+            // Protective MBR, header and entries
+            None => 2 * self.sector + ENTRIES.next_multiple_of(self.sector),
+        }
+    }
+
+    /// Byte offset past the end of the last partition, or of the table if there are none.
+    pub fn end(&self) -> u64 {
+        self.partitions
+            .values()
+            .map(|p| (p.last_lba + 1) * self.sector)
+            .max()
+            .unwrap_or_else(|| self.first_usable())
+    }
+
+    /// Bytes the backup header and entries take at the end of the image.
+    pub fn tail(&self) -> u64 {
+        let entries = match &self.header {
+            Some(header) => header.num_parts as u64 * header.part_size as u64,
+            None => ENTRIES,
+        };
+        self.sector + entries.next_multiple_of(self.sector)
+    }
+
+    /// Zero the backup header and entries, if they are inside the image.
+    fn erase_backup(&self, file: &mut File) -> anyhow::Result<()> {
+        let Some(header) = &self.header else {
+            return Ok(());
+        };
+
+        let end = (header.backup_lba + 1) * self.sector;
+        if end <= file.seek(SeekFrom::End(0))? {
+            file.seek(SeekFrom::Start(end - self.tail()))?;
+            file.write_all(&vec![0; self.tail() as usize])?;
+        }
+
+        Ok(())
+    }
+
+    /// Zero both copies of the table, leaving the MBR in place.
+    pub fn erase(&self, file: &mut File) -> anyhow::Result<()> {
+        self.erase_backup(file)?;
+
+        file.seek(SeekFrom::Start(self.sector))?;
+        file.write_all(&vec![0; (self.first_usable() - self.sector) as usize])?;
+
+        Ok(())
+    }
+
+    /// Rewrite the table for an image resized to `disk_size` bytes, with the backup header at its
+    /// new end. The disk GUID and the entries are kept.
+    pub fn write(&self, file: &mut File, disk_size: u64) -> anyhow::Result<()> {
+        let old_size = file.seek(SeekFrom::End(0))?;
+
+        // The old backup would otherwise linger in the middle of the image
+        self.erase_backup(file)?;
+
+        file.set_len(disk_size)?;
+        extend_protective_mbr(file, old_size, disk_size, self.sector)?;
+
+        let mut gdisk = gpt::GptConfig::new()
+            .initialized(false)
+            .writable(true)
+            .logical_block_size(lb_size(self.sector))
+            .create_from_device(
+                Box::new(file.try_clone()?),
+                Some(match &self.header {
+                    Some(header) => header.disk_guid,
+                    None => crate::random_guid(&Default::default())?.parse()?,
+                }),
+            )?;
+        gdisk.update_partitions(self.partitions.clone())?;
+        gdisk.write()?;
+
+        Ok(())
+    }
+}
+
+/// Resize the image to `size` bytes and move the end of its last partition along with the end
+/// of the image, after the backup GPT if there is one.
+///
+/// Returns the number and the new byte range of the partition.
+pub fn resize_last_partition(
+    file: &mut File,
+    kind: PartitionTable,
+    size: u64,
+) -> anyhow::Result<(usize, u64, u64)> {
+    Ok(match kind {
+        PartitionTable::None => {
+            file.set_len(size)?;
+            (1, 0, size)
+        }
+        PartitionTable::Gpt | PartitionTable::Hybrid => {
+            let mut gpt = Gpt::read(file)?;
+            let (tail, sector) = (gpt.tail(), gpt.sector);
+
+            let (&number, last) = gpt
+                .partitions
+                .iter_mut()
+                .max_by_key(|(_, p)| p.last_lba)
+                .ok_or_else(|| anyhow::anyhow!("the image has no partitions"))?;
+
+            let start = last.first_lba * sector;
+            last.last_lba = (size - tail) / sector - 1;
+            let len = (last.last_lba + 1) * sector - start;
+
+            gpt.write(file, size)?;
+
+            if matches!(kind, PartitionTable::Hybrid) {
+                let mut mbr = mbrman::MBR::read_from(file, sector as u32)?;
+                let mirrored = mbr
+                    .iter_mut()
+                    .find(|(_, p)| p.is_used() && p.starting_lba as u64 * sector == start);
+
+                if let Some((_, entry)) = mirrored {
+                    entry.sectors = u32::try_from(len / sector).unwrap_or(u32::MAX);
+                    mbr.write_into(file)?;
+                }
+            }
+
+            (number as usize, start, len)
+        }
+        PartitionTable::Mbr => {
+            let sector = image::sector_size(file)?;
+            let mut mbr = mbrman::MBR::read_from(file, sector as u32)?;
+
+            let (number, end) = image::mbr_partitions(&mbr)
+                .map(|(n, p)| (n, p.starting_lba as u64 + p.sectors as u64))
+                .max_by_key(|&(_, end)| end)
+                .ok_or_else(|| anyhow::anyhow!("the image has no partitions"))?;
+            let sectors = size / sector;
+            let fits = |sectors: u64| {
+                u32::try_from(sectors)
+                    .map_err(|_| anyhow::anyhow!("{size} bytes do not fit in an MBR"))
+            };
+
+            let (_, extended) = mbr.header.iter_mut().find(|(_, p)| p.is_extended()).unzip();
+
+            if number >= 5 {
+                // The extended partition ends with its last logical partition, as does the entry
+                // of the EBR before linking to it
+                let extended = extended.expect("logical partitions have an extended partition");
+                extended.sectors = fits(sectors - extended.starting_lba as u64)?;
+
+                let logical = &mut mbr.logical_partitions[number - 5];
+                if let Some(ebr_sectors) = &mut logical.ebr_sectors {
+                    *ebr_sectors = fits(sectors - logical.absolute_ebr_lba as u64)?;
+                }
+            } else if extended.is_some_and(|p| p.starting_lba as u64 + p.sectors as u64 > end) {
+                anyhow::bail!("the last partition is an extended one, which cannot be resized");
+            }
+
+            let last = mbr.get_mut(number).unwrap();
+            let start = last.starting_lba as u64 * sector;
+            last.sectors = fits(sectors - last.starting_lba as u64)?;
+            let len = last.sectors as u64 * sector;
+
+            file.set_len(size)?;
+            mbr.write_into(file)?;
+
+            (number, start, len)
+        }
+    })
+}
+
+/// Stretch the protective MBR entry of a GPT that covered the whole image to its new size.
+///
+/// Hybrid MBRs only cover the GPT structures at the start with it, and are left as they are.
+fn extend_protective_mbr(
+    file: &mut File,
+    old_size: u64,
+    disk_size: u64,
+    sector: u64,
+) -> anyhow::Result<()> {
+    let mut mbr = [0u8; 512];
+    file.seek(SeekFrom::Start(0))?;
+    file.read_exact(&mut mbr)?;
+
+    let old_sectors = u32::try_from(old_size / sector).unwrap_or(u32::MAX);
+
+    for i in 0..4 {
+        let entry = &mut mbr[446 + i * 16..446 + (i + 1) * 16];
+        let start = u32::from_le_bytes(entry[8..12].try_into().unwrap());
+        let len = u32::from_le_bytes(entry[12..16].try_into().unwrap());
+
+        if entry[4] != 0xee || (len != u32::MAX && start.saturating_add(len) < old_sectors) {
+            continue;
+        }
+
+        let len = u32::try_from(disk_size / sector - start as u64).unwrap_or(u32::MAX);
+        entry[12..16].copy_from_slice(&len.to_le_bytes());
+    }
+
+    file.seek(SeekFrom::Start(0))?;
+    file.write_all(&mbr)?;
+
+    Ok(())
+}
@@ -0,0 +1,451 @@
+//! tar archives in the ustar format, with pax extended headers for what ustar cannot hold.
+//!
+//! Archives are also read back, along with the GNU long name entries of other tools.
+
+use crate::tree::{Kind, Tree};
+use crate::FileSource;
+use log::*;
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use std::path::{Component, Path, PathBuf};
+
+const BLOCK_SIZE: usize = 512;
+
+const NAME_LEN: usize = 100;
+const PREFIX_LEN: usize = 155;
+/// Largest value of the 12 byte octal size field
+const MAX_SIZE: u64 = 0o77777777777;
+/// Largest value of the 8 byte octal uid and gid fields
+const MAX_ID: u32 = 0o7777777;
+
+const TYPE_FILE: u8 = b'0';
+const TYPE_SYMLINK: u8 = b'2';
+const TYPE_DIR: u8 = b'5';
+const TYPE_PAX: u8 = b'x';
+
+fn octal(field: &mut [u8], value: u64) {
+    let digits = field.len() - 1;
+    field[..digits].copy_from_slice(format!("{value:0digits$o}").as_bytes());
+}
+
+/// Split a path into the ustar prefix and name fields, if it fits.
+fn split_name(path: &str) -> Option<(&str, &str)> {
+    if path.len() <= NAME_LEN {
+        return Some(("", path));
+    }
+
+    path.match_indices('/')
+        .map(|(i, _)| (&path[..i], &path[i + 1..]))
+        .find(|(prefix, name)| {
+            prefix.len() <= PREFIX_LEN && name.len() <= NAME_LEN && !name.is_empty()
+        })
+}
+
+/// Last bytes of a path too long for ustar, cut at a character boundary.
+fn tail(path: &str) -> &str {
+    let start = path
+        .char_indices()
+        .map(|(i, _)| i)
+        .find(|&i| path.len() - i <= NAME_LEN)
+        .unwrap_or(0);
+    &path[start..]
+}
+
+fn header(
+    name: &str,
+    prefix: &str,
+    mode: u32,
+    size: u64,
+    mtime: u64,
+    kind: u8,
+) -> [u8; BLOCK_SIZE] {
+    let mut h = [0u8; BLOCK_SIZE];
+
+    h[..name.len()].copy_from_slice(name.as_bytes());
+    octal(&mut h[100..108], mode as u64);
+    // Owned by root, unless set before sealing
+    octal(&mut h[108..116], 0);
+    octal(&mut h[116..124], 0);
+    octal(&mut h[124..136], size);
+    octal(&mut h[136..148], mtime);
+    h[156] = kind;
+    h[257..263].copy_from_slice(b"ustar\0");
+    h[263..265].copy_from_slice(b"00");
+    h[345..345 + prefix.len()].copy_from_slice(prefix.as_bytes());
+
+    h
+}
+
+/// Fill in the checksum, once every other field is set.
+fn seal(mut h: [u8; BLOCK_SIZE]) -> [u8; BLOCK_SIZE] {
+    // The checksum is computed with its own field set to spaces
+    h[148..156].fill(b' ');
+    let sum = h.iter().map(|&b| b as u64).sum::<u64>();
+    h[148..155].copy_from_slice(format!("{sum:06o}\0").as_bytes());
+
+    h
+}
+
+/// pax record, which starts with its own length in decimal.
+fn pax_record(key: &str, value: &str) -> String {
+    let rest = key.len() + value.len() + 3;
+    let mut len = rest + 1;
+    while (rest + len.to_string().len()) != len {
+        len = rest + len.to_string().len();
+    }
+    format!("{len} {key}={value}\n")
+}
+
+fn padding(len: u64) -> usize {
+    (len.wrapping_neg() % BLOCK_SIZE as u64) as usize
+}
+
+/// Write the tree as an archive, with paths relative to the root. Returns the archive size.
+///
+/// `on_file` is called with the image path and length of every file written.
+pub fn write<W: Write + ?Sized>(
+    out: &mut W,
+    tree: &Tree,
+    on_file: &mut dyn FnMut(&Path, u64),
+) -> anyhow::Result<u64> {
+    let mut written = 0;
+
+    // Parents come before their children
+    for node in &tree.nodes[1..] {
+        let mut path = node.path.to_str().unwrap().to_owned();
+        let (kind, size) = match &node.kind {
+            Kind::Dir(_) => {
+                path.push('/');
+                (TYPE_DIR, 0)
+            }
+            Kind::File { len, .. } => (TYPE_FILE, *len),
+            Kind::Symlink(_) => (TYPE_SYMLINK, 0),
+        };
+        let mtime = node.mtime.max(0) as u64;
+
+        let mut records = String::new();
+        let (prefix, name) = match split_name(&path) {
+            Some(split) => split,
+            None => {
+                records.push_str(&pax_record("path", &path));
+                // The pax path takes precedence over the truncated one
+                ("", tail(&path))
+            }
+        };
+        if size > MAX_SIZE {
+            records.push_str(&pax_record("size", &size.to_string()));
+        }
+        for (key, id) in [("uid", node.uid), ("gid", node.gid)] {
+            if id > MAX_ID {
+                records.push_str(&pax_record(key, &id.to_string()));
+            }
+        }
+        let link = match &node.kind {
+            Kind::Symlink(target) if target.len() > NAME_LEN => {
+                records.push_str(&pax_record("linkpath", target));
+                tail(target)
+            }
+            Kind::Symlink(target) => target,
+            _ => "",
+        };
+
+        if !records.is_empty() {
+            let len = records.len() as u64;
+            out.write_all(&seal(header(
+                "././@PaxHeader",
+                "",
+                0o644,
+                len,
+                mtime,
+                TYPE_PAX,
+            )))?;
+            out.write_all(records.as_bytes())?;
+            out.write_all(&[0; BLOCK_SIZE][..padding(len)])?;
+            written += (BLOCK_SIZE + records.len() + padding(len)) as u64;
+        }
+
+        let mut h = header(name, prefix, node.mode, size.min(MAX_SIZE), mtime, kind);
+        octal(&mut h[108..116], node.uid.min(MAX_ID) as u64);
+        octal(&mut h[116..124], node.gid.min(MAX_ID) as u64);
+        h[157..157 + link.len()].copy_from_slice(link.as_bytes());
+        out.write_all(&seal(h))?;
+        written += BLOCK_SIZE as u64;
+
+        if let Kind::File { source, len } = &node.kind {
+            let copied = io::copy(&mut source.open()?.take(*len), out)?;
+            if copied != *len {
+                anyhow::bail!("{} changed while being copied", node.path.display());
+            }
+            out.write_all(&[0; BLOCK_SIZE][..padding(*len)])?;
+            written += *len + padding(*len) as u64;
+
+            on_file(&node.path, *len);
+        }
+    }
+
+    // End of archive
+    out.write_all(&[0; 2 * BLOCK_SIZE])?;
+    written += 2 * BLOCK_SIZE as u64;
+
+    Ok(written)
+}
+
+const TYPE_OLD_FILE: u8 = b'\0';
+const TYPE_HARD_LINK: u8 = b'1';
+const TYPE_CONTIGUOUS: u8 = b'7';
+const TYPE_GLOBAL_PAX: u8 = b'g';
+/// GNU long name and long link name of the next entry
+const TYPE_GNU_NAME: u8 = b'L';
+const TYPE_GNU_LINK: u8 = b'K';
+
+/// Numeric header field, in octal or in the GNU base-256 encoding.
+fn parse_number(field: &[u8]) -> anyhow::Result<u64> {
+    if field[0] & 0x80 != 0 {
+        return Ok(field[1..]
+            .iter()
+            .fold((field[0] & 0x7f) as u64, |v, &b| (v << 8) | b as u64));
+    }
+
+    let text = std::str::from_utf8(field)?.trim_matches(|c: char| c == '\0' || c == ' ');
+    if text.is_empty() {
+        return Ok(0);
+    }
+    u64::from_str_radix(text, 8).map_err(|_| anyhow::anyhow!("invalid number `{text}` in header"))
+}
+
+fn parse_str(field: &[u8]) -> String {
+    let end = field.iter().position(|&b| b == 0).unwrap_or(field.len());
+    String::from_utf8_lossy(&field[..end]).into_owned()
+}
+
+fn read_data<R: Read + ?Sized>(input: &mut R, len: u64) -> io::Result<Vec<u8>> {
+    let mut data = vec![0; len as usize];
+    input.read_exact(&mut data)?;
+    skip(input, padding(len) as u64)?;
+    Ok(data)
+}
+
+fn skip<R: Read + ?Sized>(input: &mut R, len: u64) -> io::Result<()> {
+    io::copy(&mut input.take(len), &mut io::sink())?;
+    Ok(())
+}
+
+/// Path of an entry inside the archive, refusing ones that would end up outside of it. Empty for
+/// the root.
+pub fn entry_path(name: &str) -> anyhow::Result<PathBuf> {
+    let mut path = PathBuf::new();
+
+    for part in Path::new(name).components() {
+        match part {
+            Component::Normal(part) => path.push(part),
+            Component::CurDir | Component::RootDir => {}
+            _ => anyhow::bail!("entry `{name}` points outside of the archive"),
+        }
+    }
+
+    Ok(path)
+}
+
+/// Reader keeping track of the offset in the archive.
+struct Counted<'a, R: ?Sized> {
+    inner: &'a mut R,
+    pos: u64,
+}
+
+impl<R: Read + ?Sized> Read for Counted<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+/// Read an archive into a tree, keeping owners, modes and modification times. Directories that
+/// have no entry of their own get the time of the first entry in them. Returns the tree and the
+/// number of entries.
+///
+/// The data of files stays in `archive` if it is given, being the uncompressed archive `input`
+/// reads, and is read into memory otherwise.
+pub fn read<R: Read + ?Sized>(
+    input: &mut R,
+    archive: Option<&Path>,
+    time: i64,
+) -> anyhow::Result<(Tree, u64)> {
+    let mut input = Counted {
+        inner: input,
+        pos: 0,
+    };
+    let input = &mut input;
+    let mut tree = Tree::new(0o755, time);
+    let mut entries = 0;
+    let mut long_name = None;
+    let mut long_link = None;
+    let mut pax = HashMap::<String, String>::new();
+
+    loop {
+        // Some archivers leave out the end of archive blocks
+        let mut h = [0u8; BLOCK_SIZE];
+        let len = io::copy(&mut input.take(BLOCK_SIZE as u64), &mut &mut h[..])?;
+        if len == 0 || h.iter().all(|&b| b == 0) {
+            break;
+        }
+        if len < BLOCK_SIZE as u64 {
+            anyhow::bail!("archive ends in the middle of a header");
+        }
+
+        let stored = parse_number(&h[148..156])?;
+        h[148..156].fill(b' ');
+        if h.iter().map(|&b| b as u64).sum::<u64>() != stored {
+            anyhow::bail!("bad header checksum, this is not a tar archive");
+        }
+
+        let size = match pax.get("size") {
+            Some(size) => size.parse()?,
+            None => parse_number(&h[124..136])?,
+        };
+
+        match h[156] {
+            TYPE_PAX => {
+                let data = read_data(input, size)?;
+                pax = pax_records(&data)?;
+                continue;
+            }
+            TYPE_GLOBAL_PAX => {
+                skip(input, size + padding(size) as u64)?;
+                continue;
+            }
+            TYPE_GNU_NAME => {
+                long_name = Some(parse_str(&read_data(input, size)?));
+                continue;
+            }
+            TYPE_GNU_LINK => {
+                long_link = Some(parse_str(&read_data(input, size)?));
+                continue;
+            }
+            _ => {}
+        }
+
+        let name = match (pax.remove("path"), long_name.take()) {
+            (Some(name), _) | (None, Some(name)) => name,
+            _ if &h[257..262] == b"ustar" && h[345] != 0 => {
+                format!("{}/{}", parse_str(&h[345..500]), parse_str(&h[..100]))
+            }
+            _ => parse_str(&h[..100]),
+        };
+        let link = pax
+            .remove("linkpath")
+            .or(long_link.take())
+            .unwrap_or_else(|| parse_str(&h[157..257]));
+        let mode = parse_number(&h[100..108])? as u32 & 0o7777;
+        let mtime = match pax.remove("mtime") {
+            // Fractions of a second are dropped
+            Some(mtime) => mtime.split('.').next().unwrap_or_default().parse()?,
+            None => parse_number(&h[136..148])? as i64,
+        };
+        let uid = match pax.remove("uid") {
+            Some(uid) => uid.parse()?,
+            None => parse_number(&h[108..116])? as u32,
+        };
+        let gid = match pax.remove("gid") {
+            Some(gid) => gid.parse()?,
+            None => parse_number(&h[116..124])? as u32,
+        };
+        pax.clear();
+
+        let path = entry_path(&name)?;
+        let is_dir = match h[156] {
+            TYPE_FILE | TYPE_OLD_FILE | TYPE_CONTIGUOUS => name.ends_with('/'),
+            kind => kind == TYPE_DIR,
+        };
+
+        let kind = match h[156] {
+            _ if is_dir => Kind::Dir(vec![]),
+            _ if path.as_os_str().is_empty() => {
+                anyhow::bail!("entry `{name}` is not a directory, but the root of the archive")
+            }
+            TYPE_FILE | TYPE_OLD_FILE | TYPE_CONTIGUOUS => {
+                let source = match archive {
+                    Some(archive) => {
+                        let offset = input.pos;
+                        skip(input, size)?;
+                        if input.pos - offset != size {
+                            anyhow::bail!("archive ends in the middle of `{name}`");
+                        }
+                        FileSource::Range {
+                            path: archive.to_owned(),
+                            offset,
+                            len: size,
+                        }
+                    }
+                    None => {
+                        let mut data = vec![];
+                        input.take(size).read_to_end(&mut data)?;
+                        if data.len() as u64 != size {
+                            anyhow::bail!("archive ends in the middle of `{name}`");
+                        }
+                        FileSource::Data(data.into())
+                    }
+                };
+                skip(input, padding(size) as u64)?;
+                let idx = tree.insert(&path, Kind::File { source, len: size }, mode, mtime)?;
+                tree.nodes[idx].uid = uid;
+                tree.nodes[idx].gid = gid;
+                entries += 1;
+                continue;
+            }
+            TYPE_SYMLINK => Kind::Symlink(link),
+            TYPE_HARD_LINK => {
+                let target = entry_path(&link)?;
+                match tree.nodes.iter().find(|n| n.path == target) {
+                    Some(node) if !node.is_dir() => node.kind.clone(),
+                    _ => anyhow::bail!("hard link `{name}` has no target"),
+                }
+            }
+            kind => {
+                warn!(
+                    "Skipping `{name}`, entries of type `{}` cannot go into images",
+                    kind as char
+                );
+                skip(input, size + padding(size) as u64)?;
+                continue;
+            }
+        };
+
+        skip(input, size + padding(size) as u64)?;
+        entries += 1;
+
+        let idx = if path.as_os_str().is_empty() {
+            tree.nodes[0].mode = mode;
+            tree.nodes[0].mtime = mtime;
+            0
+        } else {
+            tree.insert(&path, kind, mode, mtime)?
+        };
+        tree.nodes[idx].uid = uid;
+        tree.nodes[idx].gid = gid;
+    }
+
+    Ok((tree, entries))
+}
+
+/// Key-value records of a pax extended header.
+fn pax_records(data: &[u8]) -> anyhow::Result<HashMap<String, String>> {
+    let mut records = HashMap::new();
+    let mut rest = data;
+
+    while !rest.is_empty() && rest[0] != 0 {
+        let bad = || anyhow::anyhow!("malformed pax extended header");
+        let space = rest.iter().position(|&b| b == b' ').ok_or_else(bad)?;
+        let len: usize = std::str::from_utf8(&rest[..space])?.parse()?;
+        if len <= space || len > rest.len() {
+            return Err(bad());
+        }
+
+        let record = std::str::from_utf8(&rest[space + 1..len - 1])?;
+        let (key, value) = record.split_once('=').ok_or_else(bad)?;
+        records.insert(key.to_owned(), value.to_owned());
+        rest = &rest[len..];
+    }
+
+    Ok(records)
+}
@@ -0,0 +1,90 @@
+//! Placeholders resolved at build time in user supplied strings.
+//!
+//! Supported placeholders:
+//!
+//! - `{date}` - build date as `YYYYMMDD`
+//! - `{time}` - build time as `HHMMSS`
+//! - `{git-short}` - abbreviated commit hash of the git repository in the working directory
+//! - `{env:NAME}` - value of the `NAME` environment variable
+//!
+//! `{{` and `}}` produce literal braces. The build time is the one given with `--timestamp`, or
+//! `SOURCE_DATE_EPOCH`.
+
+use anyhow::{anyhow, bail, Context};
+use chrono::{DateTime, TimeZone, Utc};
+use std::process::Command;
+
+/// Time stamp fixed with `--timestamp` or, without it, `SOURCE_DATE_EPOCH`. `None` if neither
+/// is set, and builds use the current time.
+pub fn fixed_time(timestamp: Option<i64>) -> anyhow::Result<Option<DateTime<Utc>>> {
+    let secs = match (timestamp, std::env::var("SOURCE_DATE_EPOCH")) {
+        (Some(secs), _) => secs,
+        (None, Ok(epoch)) => epoch
+            .trim()
+            .parse::<i64>()
+            .context("invalid SOURCE_DATE_EPOCH")?,
+        (None, Err(_)) => return Ok(None),
+    };
+
+    Utc.timestamp_opt(secs, 0)
+        .single()
+        .map(Some)
+        .ok_or_else(|| anyhow!("timestamp {secs} out of range"))
+}
+
+fn git_short() -> anyhow::Result<String> {
+    let out = Command::new("git")
+        .args(["rev-parse", "--short", "HEAD"])
+        .output()
+        .context("unable to run git")?;
+
+    if !out.status.success() {
+        bail!(
+            "git rev-parse failed: {}",
+            String::from_utf8_lossy(&out.stderr).trim()
+        );
+    }
+
+    Ok(String::from_utf8_lossy(&out.stdout).trim().to_string())
+}
+
+fn resolve(var: &str, time: &DateTime<Utc>) -> anyhow::Result<String> {
+    if let Some(name) = var.strip_prefix("env:") {
+        return std::env::var(name).with_context(|| format!("environment variable {name} not set"));
+    }
+
+    match var {
+        "date" => Ok(time.format("%Y%m%d").to_string()),
+        "time" => Ok(time.format("%H%M%S").to_string()),
+        "git-short" => git_short(),
+        _ => bail!("unknown template variable {{{var}}}"),
+    }
+}
+
+/// Substitute all placeholders in `s`, with `time` as the build time.
+pub fn expand(s: &str, time: &DateTime<Utc>) -> anyhow::Result<String> {
+    let mut out = String::with_capacity(s.len());
+    let mut rest = s;
+
+    while let Some(i) = rest.find(['{', '}']) {
+        out.push_str(&rest[..i]);
+        let (c, tail) = (rest.as_bytes()[i], &rest[i + 1..]);
+
+        if tail.as_bytes().first() == Some(&c) {
+            out.push(c as char);
+            rest = &tail[1..];
+        } else if c == b'}' {
+            bail!("unmatched `}}` in `{s}`");
+        } else {
+            let end = tail
+                .find('}')
+                .ok_or_else(|| anyhow!("unterminated placeholder in `{s}`"))?;
+            out.push_str(&resolve(&tail[..end], time)?);
+            rest = &tail[end + 1..];
+        }
+    }
+
+    out.push_str(rest);
+
+    Ok(out)
+}
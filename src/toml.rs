@@ -0,0 +1,664 @@
+//! Minimal TOML parsing, enough for layout manifests.
+//!
+//! Supports tables, arrays of tables, dotted keys, strings including multi-line ones, integers,
+//! booleans, arrays and inline tables. Floats and dates are rejected.
+
+use std::fmt;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value {
+    Str(String),
+    Int(i64),
+    Bool(bool),
+    Array(Vec<Value>),
+    Table(Vec<(String, Value)>),
+}
+
+impl Value {
+    /// Name of the value type, for error messages.
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Self::Str(_) => "string",
+            Self::Int(_) => "integer",
+            Self::Bool(_) => "boolean",
+            Self::Array(_) => "array",
+            Self::Table(_) => "table",
+        }
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Str(s) => write!(f, "{s:?}"),
+            Self::Int(v) => write!(f, "{v}"),
+            Self::Bool(v) => write!(f, "{v}"),
+            Self::Array(values) => {
+                f.write_str("[")?;
+                for (i, v) in values.iter().enumerate() {
+                    if i > 0 {
+                        f.write_str(", ")?;
+                    }
+                    write!(f, "{v}")?;
+                }
+                f.write_str("]")
+            }
+            Self::Table(fields) => {
+                f.write_str("{")?;
+                for (i, (k, v)) in fields.iter().enumerate() {
+                    if i > 0 {
+                        f.write_str(",")?;
+                    }
+                    write!(f, " {k} = {v}")?;
+                }
+                f.write_str(" }")
+            }
+        }
+    }
+}
+
+type Table = Vec<(String, Value)>;
+
+/// Find the table at `path`, creating missing ones. Arrays of tables resolve to their last table.
+fn table_at<'a>(mut table: &'a mut Table, path: &[String]) -> Result<&'a mut Table, String> {
+    for key in path {
+        let idx = match table.iter().position(|(k, _)| k == key) {
+            Some(idx) => idx,
+            None => {
+                table.push((key.clone(), Value::Table(vec![])));
+                table.len() - 1
+            }
+        };
+
+        table = match &mut table[idx].1 {
+            Value::Table(inner) => inner,
+            Value::Array(items) => match items.last_mut() {
+                Some(Value::Table(inner)) => inner,
+                _ => return Err(format!("`{key}` is not an array of tables")),
+            },
+            v => {
+                return Err(format!(
+                    "`{key}` is not a table but a value of type {}",
+                    v.type_name()
+                ))
+            }
+        };
+    }
+
+    Ok(table)
+}
+
+fn insert(table: &mut Table, key: &[String], value: Value) -> Result<(), String> {
+    let (last, parents) = key.split_last().unwrap();
+    let table = table_at(table, parents)?;
+
+    if table.iter().any(|(k, _)| k == last) {
+        return Err(format!("duplicate key `{}`", key.join(".")));
+    }
+    table.push((last.clone(), value));
+    Ok(())
+}
+
+struct Parser<'a> {
+    src: &'a str,
+    pos: usize,
+}
+
+impl Parser<'_> {
+    fn peek(&self) -> Option<char> {
+        self.src[self.pos..].chars().next()
+    }
+
+    fn bump(&mut self) -> Option<char> {
+        let c = self.peek()?;
+        self.pos += c.len_utf8();
+        Some(c)
+    }
+
+    fn eat(&mut self, s: &str) -> bool {
+        if self.src[self.pos..].starts_with(s) {
+            self.pos += s.len();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect(&mut self, c: char) -> Result<(), String> {
+        match self.bump() {
+            Some(n) if n == c => Ok(()),
+            Some(n) => Err(format!("expected `{c}`, found `{n}`")),
+            None => Err(format!("expected `{c}`, found the end of the file")),
+        }
+    }
+
+    /// Skip spaces and a comment up to the end of the line.
+    fn skip_space(&mut self) {
+        while matches!(self.peek(), Some(' ' | '\t')) {
+            self.pos += 1;
+        }
+        if self.peek() == Some('#') {
+            while !matches!(self.peek(), None | Some('\n')) {
+                self.bump();
+            }
+        }
+    }
+
+    /// Skip spaces, comments and line breaks.
+    fn skip_lines(&mut self) {
+        loop {
+            self.skip_space();
+            if !(self.eat("\n") || self.eat("\r\n")) {
+                break;
+            }
+        }
+    }
+
+    fn end_of_line(&mut self) -> Result<(), String> {
+        self.skip_space();
+        if self.peek().is_none() || self.eat("\n") || self.eat("\r\n") {
+            Ok(())
+        } else {
+            Err(format!("unexpected `{}`", self.peek().unwrap()))
+        }
+    }
+
+    fn key(&mut self) -> Result<Vec<String>, String> {
+        let mut parts = vec![];
+
+        loop {
+            self.skip_space();
+            let part = match self.peek() {
+                Some('"') => self.basic_string()?,
+                Some('\'') => self.literal_string()?,
+                _ => {
+                    let start = self.pos;
+                    while matches!(self.peek(), Some(c) if c.is_ascii_alphanumeric() || c == '_' || c == '-')
+                    {
+                        self.pos += 1;
+                    }
+                    if start == self.pos {
+                        return Err("expected a key".into());
+                    }
+                    self.src[start..self.pos].to_owned()
+                }
+            };
+            parts.push(part);
+
+            self.skip_space();
+            if !self.eat(".") {
+                return Ok(parts);
+            }
+        }
+    }
+
+    /// Character of an escape sequence, after its `\`.
+    fn escape(&mut self) -> Result<char, String> {
+        Ok(match self.bump() {
+            Some('"') => '"',
+            Some('\\') => '\\',
+            Some('n') => '\n',
+            Some('t') => '\t',
+            Some('r') => '\r',
+            Some(u @ ('u' | 'U')) => {
+                let len = if u == 'u' { 4 } else { 8 };
+                let hex = self
+                    .src
+                    .get(self.pos..self.pos + len)
+                    .ok_or("unterminated unicode escape")?;
+                self.pos += len;
+                u32::from_str_radix(hex, 16)
+                    .ok()
+                    .and_then(char::from_u32)
+                    .ok_or_else(|| format!("invalid unicode escape `\\{u}{hex}`"))?
+            }
+            Some(c) => return Err(format!("invalid escape `\\{c}`")),
+            None => return Err("unterminated string".into()),
+        })
+    }
+
+    fn basic_string(&mut self) -> Result<String, String> {
+        self.expect('"')?;
+        let mut s = String::new();
+
+        loop {
+            match self.bump() {
+                Some('"') => return Ok(s),
+                Some('\\') => s.push(self.escape()?),
+                Some('\n') => {
+                    // Report the line of the string rather than the next one
+                    self.pos -= 1;
+                    return Err("unterminated string".into());
+                }
+                None => return Err("unterminated string".into()),
+                Some(c) => s.push(c),
+            }
+        }
+    }
+
+    fn literal_string(&mut self) -> Result<String, String> {
+        self.expect('\'')?;
+        let start = self.pos;
+
+        loop {
+            match self.bump() {
+                Some('\'') => return Ok(self.src[start..self.pos - 1].to_owned()),
+                Some('\n') => {
+                    // Report the line of the string rather than the next one
+                    self.pos -= 1;
+                    return Err("unterminated string".into());
+                }
+                None => return Err("unterminated string".into()),
+                Some(_) => {}
+            }
+        }
+    }
+
+    /// Whether the closing `quotes` of a multi-line string follow, consuming them along with up
+    /// to two more quote characters, which belong to the string and are pushed to `s`.
+    fn multiline_end(&mut self, quotes: &str, s: &mut String) -> bool {
+        if !self.eat(quotes) {
+            return false;
+        }
+        let quote = quotes.chars().next().unwrap();
+        for _ in 0..2 {
+            if self.peek() == Some(quote) {
+                self.bump();
+                s.push(quote);
+            }
+        }
+        true
+    }
+
+    /// `"""` string, after its opening quotes. A line break right after them is left out, and a
+    /// `\` at the end of a line removes the line break and the whitespace after it.
+    fn multiline_basic_string(&mut self) -> Result<String, String> {
+        let _ = self.eat("\n") || self.eat("\r\n");
+        let mut s = String::new();
+
+        loop {
+            if self.multiline_end("\"\"\"", &mut s) {
+                return Ok(s);
+            }
+            match self.bump() {
+                Some('\\') if matches!(self.peek(), Some(' ' | '\t' | '\r' | '\n')) => {
+                    while matches!(self.peek(), Some(' ' | '\t')) {
+                        self.bump();
+                    }
+                    if !(self.eat("\n") || self.eat("\r\n")) {
+                        return Err("invalid escape `\\ `".into());
+                    }
+                    while matches!(self.peek(), Some(' ' | '\t' | '\r' | '\n')) {
+                        self.bump();
+                    }
+                }
+                Some('\\') => s.push(self.escape()?),
+                Some(c) => s.push(c),
+                None => return Err("unterminated string".into()),
+            }
+        }
+    }
+
+    /// `'''` string, after its opening quotes, taken as is apart from a line break right after
+    /// them.
+    fn multiline_literal_string(&mut self) -> Result<String, String> {
+        let _ = self.eat("\n") || self.eat("\r\n");
+        let mut s = String::new();
+
+        loop {
+            if self.multiline_end("'''", &mut s) {
+                return Ok(s);
+            }
+            match self.bump() {
+                Some(c) => s.push(c),
+                None => return Err("unterminated string".into()),
+            }
+        }
+    }
+
+    fn integer(&mut self) -> Result<i64, String> {
+        let start = self.pos;
+        while matches!(self.peek(), Some(c) if c.is_ascii_alphanumeric() || "+-_.:".contains(c)) {
+            self.pos += 1;
+        }
+        let text = &self.src[start..self.pos];
+        let digits = text.replace('_', "");
+
+        let (neg, digits) = match digits.strip_prefix('-') {
+            Some(d) => (true, d),
+            None => (false, digits.strip_prefix('+').unwrap_or(&digits)),
+        };
+        let (radix, digits) = match digits.get(..2) {
+            Some("0x") => (16, &digits[2..]),
+            Some("0o") => (8, &digits[2..]),
+            Some("0b") => (2, &digits[2..]),
+            _ => (10, digits),
+        };
+
+        i64::from_str_radix(digits, radix)
+            .map(|v| if neg { -v } else { v })
+            .map_err(|_| format!("invalid value `{text}`, only integers are supported"))
+    }
+
+    fn value(&mut self) -> Result<Value, String> {
+        match self.peek() {
+            _ if self.eat("\"\"\"") => Ok(Value::Str(self.multiline_basic_string()?)),
+            _ if self.eat("'''") => Ok(Value::Str(self.multiline_literal_string()?)),
+            Some('"') => Ok(Value::Str(self.basic_string()?)),
+            Some('\'') => Ok(Value::Str(self.literal_string()?)),
+            Some('[') => {
+                self.bump();
+                let mut values = vec![];
+                loop {
+                    self.skip_lines();
+                    if self.eat("]") {
+                        return Ok(Value::Array(values));
+                    }
+                    values.push(self.value()?);
+                    self.skip_lines();
+                    if !self.eat(",") {
+                        self.skip_lines();
+                        self.expect(']')?;
+                        return Ok(Value::Array(values));
+                    }
+                }
+            }
+            Some('{') => {
+                self.bump();
+                let mut table = vec![];
+                self.skip_space();
+                if self.eat("}") {
+                    return Ok(Value::Table(table));
+                }
+                loop {
+                    let key = self.key()?;
+                    self.expect('=')?;
+                    self.skip_space();
+                    insert(&mut table, &key, self.value()?)?;
+                    self.skip_space();
+                    if !self.eat(",") {
+                        self.expect('}')?;
+                        return Ok(Value::Table(table));
+                    }
+                }
+            }
+            _ if self.eat("true") => Ok(Value::Bool(true)),
+            _ if self.eat("false") => Ok(Value::Bool(false)),
+            Some(_) => Ok(Value::Int(self.integer()?)),
+            None => Err("expected a value".into()),
+        }
+    }
+
+    fn document(&mut self) -> Result<Table, String> {
+        let mut root = vec![];
+        let mut current = vec![];
+
+        loop {
+            self.skip_lines();
+            if self.peek().is_none() {
+                return Ok(root);
+            }
+
+            if self.eat("[[") {
+                let key = self.key()?;
+                self.expect(']')?;
+                self.expect(']')?;
+
+                let (last, parents) = key.split_last().unwrap();
+                let parent = table_at(&mut root, parents)?;
+                match parent.iter_mut().find(|(k, _)| k == last) {
+                    Some((_, Value::Array(items))) => items.push(Value::Table(vec![])),
+                    Some(_) => return Err(format!("`{}` is not an array", key.join("."))),
+                    None => parent.push((last.clone(), Value::Array(vec![Value::Table(vec![])]))),
+                }
+                current = key;
+            } else if self.eat("[") {
+                let key = self.key()?;
+                self.expect(']')?;
+                table_at(&mut root, &key)?;
+                current = key;
+            } else {
+                let key = self.key()?;
+                self.expect('=')?;
+                self.skip_space();
+                let value = self.value()?;
+                insert(table_at(&mut root, &current)?, &key, value)?;
+            }
+
+            self.end_of_line()?;
+        }
+    }
+}
+
+/// Parse a document into its root table.
+pub fn parse(src: &str) -> Result<Vec<(String, Value)>, String> {
+    let mut parser = Parser { src, pos: 0 };
+
+    parser.document().map_err(|e| {
+        let line = src[..parser.pos.min(src.len())].matches('\n').count() + 1;
+        format!("line {line}: {e}")
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn s(v: &str) -> Value {
+        Value::Str(v.into())
+    }
+
+    fn table(fields: &[(&str, Value)]) -> Value {
+        Value::Table(
+            fields
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.clone()))
+                .collect(),
+        )
+    }
+
+    /// Error of `src`, which must not parse.
+    fn error(src: &str) -> String {
+        parse(src).expect_err(src)
+    }
+
+    #[test]
+    fn layout_manifest() {
+        let src = r#"
+# Disk layout
+[[partition]]
+name = "esp"
+type = "esp"
+filesystem = "vfat"
+size = "128M"
+input = "esp"
+attributes = ["required", 60]
+content = { image = "esp.img" }
+
+[[partition]]
+name = "root"   # the rest of the disk
+size = 1_048_576
+bootable = true
+primary = false
+mbr_type = 0x83
+
+[[files]]
+partition = "esp"
+path = "/loader/loader.conf"
+content = """
+timeout 3
+default arch.conf
+"""
+"#;
+        let root = parse(src).unwrap();
+        assert_eq!(
+            root,
+            [
+                (
+                    "partition".into(),
+                    Value::Array(vec![
+                        table(&[
+                            ("name", s("esp")),
+                            ("type", s("esp")),
+                            ("filesystem", s("vfat")),
+                            ("size", s("128M")),
+                            ("input", s("esp")),
+                            (
+                                "attributes",
+                                Value::Array(vec![s("required"), Value::Int(60)])
+                            ),
+                            ("content", table(&[("image", s("esp.img"))])),
+                        ]),
+                        table(&[
+                            ("name", s("root")),
+                            ("size", Value::Int(1 << 20)),
+                            ("bootable", Value::Bool(true)),
+                            ("primary", Value::Bool(false)),
+                            ("mbr_type", Value::Int(0x83)),
+                        ]),
+                    ])
+                ),
+                (
+                    "files".into(),
+                    Value::Array(vec![table(&[
+                        ("partition", s("esp")),
+                        ("path", s("/loader/loader.conf")),
+                        ("content", s("timeout 3\ndefault arch.conf\n")),
+                    ])])
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn strings() {
+        let src = r#"
+basic = "tab\t quote\" \u00e9 \U0001F600"
+literal = 'C:\path\'
+multi = """\
+    one \
+    two"""
+quotes = """a "quoted" word"""""
+raw = '''
+\n stays'''
+"#;
+        let root = parse(src).unwrap();
+        let get = |key: &str| root.iter().find(|(k, _)| k == key).unwrap().1.clone();
+        assert_eq!(get("basic"), s("tab\t quote\" \u{e9} \u{1f600}"));
+        assert_eq!(get("literal"), s("C:\\path\\"));
+        assert_eq!(get("multi"), s("one two"));
+        assert_eq!(get("quotes"), s("a \"quoted\" word\"\""));
+        assert_eq!(get("raw"), s("\\n stays"));
+    }
+
+    #[test]
+    fn integers() {
+        let root = parse("a = -12\nb = +7\nc = 0o17\nd = 0b101\ne = 0xff_ff").unwrap();
+        let values: Vec<_> = root.into_iter().map(|(_, v)| v).collect();
+        assert_eq!(
+            values,
+            [-12, 7, 0o17, 0b101, 0xffff].map(Value::Int).to_vec()
+        );
+    }
+
+    #[test]
+    fn tables_and_dotted_keys() {
+        let src = "top.inner = 1\n\"quoted key\" = 2\n[a.b]\nc = { d.e = 3 }\n[x]\ny = []\n";
+        assert_eq!(
+            parse(src).unwrap(),
+            [
+                ("top".into(), table(&[("inner", Value::Int(1))])),
+                ("quoted key".into(), Value::Int(2)),
+                (
+                    "a".into(),
+                    table(&[(
+                        "b",
+                        table(&[("c", table(&[("d", table(&[("e", Value::Int(3))]))]))])
+                    )])
+                ),
+                ("x".into(), table(&[("y", Value::Array(vec![]))])),
+            ]
+        );
+    }
+
+    #[test]
+    fn multi_line_arrays() {
+        let src = "list = [\n  \"a\", # first\n  \"b\",\n]\nempty = {}\n";
+        assert_eq!(
+            parse(src).unwrap(),
+            [
+                ("list".into(), Value::Array(vec![s("a"), s("b")])),
+                ("empty".into(), table(&[])),
+            ]
+        );
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!(
+            error("size = 1.5"),
+            "line 1: invalid value `1.5`, only integers are supported"
+        );
+        assert_eq!(
+            error("date = 1979-05-27"),
+            "line 1: invalid value `1979-05-27`, only integers are supported"
+        );
+        assert_eq!(
+            error("name = \"esp\"\nname = \"root\""),
+            "line 2: duplicate key `name`"
+        );
+        assert_eq!(error("name = \"esp"), "line 1: unterminated string");
+        assert_eq!(error("name = 'esp\n'"), "line 1: unterminated string");
+        assert_eq!(error("c = \"\"\"open"), "line 1: unterminated string");
+        assert_eq!(error("e = \"\\q\""), "line 1: invalid escape `\\q`");
+        assert_eq!(
+            error("e = \"\\uzzzz\""),
+            "line 1: invalid unicode escape `\\uzzzz`"
+        );
+        assert_eq!(
+            error("name"),
+            "line 1: expected `=`, found the end of the file"
+        );
+        assert_eq!(error("= 1"), "line 1: expected a key");
+        assert_eq!(error("a = 1 b = 2"), "line 1: unexpected `b`");
+        assert_eq!(error("a ="), "line 1: expected a value");
+        assert_eq!(
+            error("a = [1, 2"),
+            "line 1: expected `]`, found the end of the file"
+        );
+        assert_eq!(
+            error("a = { b = 1"),
+            "line 1: expected `}`, found the end of the file"
+        );
+    }
+
+    #[test]
+    fn table_errors() {
+        assert_eq!(
+            error("partition = 1\n[[partition]]"),
+            "line 2: `partition` is not an array"
+        );
+        assert_eq!(
+            error("partition = 1\n[partition]"),
+            "line 2: `partition` is not a table but a value of type integer"
+        );
+        assert_eq!(
+            error("partition = [1]\n[partition.x]"),
+            "line 2: `partition` is not an array of tables"
+        );
+        assert_eq!(
+            error("[[partition]]\nname = \"a\"\nname = \"b\""),
+            "line 3: duplicate key `name`"
+        );
+    }
+
+    #[test]
+    fn display() {
+        let v = table(&[(
+            "a",
+            Value::Array(vec![s("x"), Value::Int(1), Value::Bool(true)]),
+        )]);
+        assert_eq!(v.to_string(), "{ a = [\"x\", 1, true] }");
+        assert_eq!(v.type_name(), "table");
+    }
+}
@@ -0,0 +1,364 @@
+//! In-memory view of the files going into the image, for filesystems written all at once.
+
+use crate::{walk_dir, ExtraFile, FileSource};
+use log::*;
+use std::collections::HashMap;
+use std::fs::{self, Metadata};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+#[derive(Clone, Debug)]
+pub enum Kind {
+    /// Indices of the entries in [`Tree::nodes`]
+    Dir(Vec<usize>),
+    File {
+        source: FileSource,
+        len: u64,
+    },
+    /// Symbolic link to the path
+    Symlink(String),
+}
+
+#[derive(Clone, Debug)]
+pub struct Node {
+    pub name: String,
+    /// Path inside the image, relative to the root
+    pub path: PathBuf,
+    /// Index of the parent directory, 0 (itself) for the root
+    pub parent: usize,
+    pub kind: Kind,
+    /// Permission bits
+    pub mode: u32,
+    /// Owner, root for files of the host and those added to the image
+    pub uid: u32,
+    pub gid: u32,
+    pub mtime: i64,
+}
+
+impl Node {
+    pub fn is_dir(&self) -> bool {
+        matches!(self.kind, Kind::Dir(_))
+    }
+
+    pub fn is_file(&self) -> bool {
+        matches!(self.kind, Kind::File { .. })
+    }
+}
+
+/// Directory tree, with the root at index 0.
+#[derive(Clone, Debug)]
+pub struct Tree {
+    pub nodes: Vec<Node>,
+}
+
+fn name_of(path: &Path) -> anyhow::Result<String> {
+    path.file_name()
+        .and_then(|n| n.to_str())
+        .map(Into::into)
+        .ok_or_else(|| anyhow::anyhow!("non-UTF-8 file name {path:?}"))
+}
+
+impl Tree {
+    /// Tree with only the root directory.
+    pub fn new(mode: u32, mtime: i64) -> Self {
+        Self {
+            nodes: vec![Node {
+                name: String::new(),
+                path: PathBuf::new(),
+                parent: 0,
+                kind: Kind::Dir(vec![]),
+                mode,
+                uid: 0,
+                gid: 0,
+                mtime,
+            }],
+        }
+    }
+
+    /// Tree of the host directory `input_dir`, with `extra_files` added.
+    pub fn build(
+        input_dir: &Path,
+        link_follow: bool,
+        extra_files: &[ExtraFile],
+        ctx: &crate::BuildContext,
+    ) -> anyhow::Result<Self> {
+        let root_meta = fs::metadata(input_dir)?;
+        let mut tree = Self::new(root_meta.mode() & 0o7777, root_meta.mtime());
+
+        let mut dirs = vec![];
+        let mut files = vec![];
+
+        walk_dir(
+            input_dir,
+            input_dir,
+            link_follow,
+            ctx.sorted,
+            (),
+            &mut |_, short_path, _, metadata| {
+                dirs.push((short_path.to_owned(), metadata.clone()));
+                Ok(())
+            },
+            &mut |path, short_path, _, metadata| {
+                files.push((path.to_owned(), short_path.to_owned(), metadata.clone()));
+                Ok(())
+            },
+            &mut |_, _| Ok(()),
+        )?;
+
+        let mut dir_index = HashMap::from([(PathBuf::new(), 0)]);
+
+        // Parents are always visited before their children
+        for (path, metadata) in dirs {
+            let parent = dir_index[path.parent().unwrap_or(Path::new(""))];
+            let idx = tree.push(parent, &path, Kind::Dir(vec![]), &metadata)?;
+            dir_index.insert(path, idx);
+        }
+
+        // Symbolic links are kept for the filesystems that store them, and left out of the others
+        // by `without_symlinks`
+        for (host, path, metadata) in files {
+            let parent = dir_index[path.parent().unwrap_or(Path::new(""))];
+            let kind = if metadata.is_symlink() {
+                let target = fs::read_link(&host)?;
+                let target = target
+                    .to_str()
+                    .ok_or_else(|| anyhow::anyhow!("non-UTF-8 symlink target {target:?}"))?;
+                Kind::Symlink(target.into())
+            } else {
+                Kind::File {
+                    source: FileSource::Host(host),
+                    len: metadata.len(),
+                }
+            };
+            tree.push(parent, &path, kind, &metadata)?;
+        }
+
+        tree.add_extra(extra_files, ctx.time.timestamp())?;
+
+        Ok(tree)
+    }
+
+    /// Add `extra_files` at `time`, replacing the files of the tree at their paths.
+    pub fn add_extra(&mut self, extra_files: &[ExtraFile], time: i64) -> anyhow::Result<()> {
+        for extra in extra_files {
+            let kind = Kind::File {
+                source: extra.source.clone(),
+                len: extra.source.len()?,
+            };
+            self.insert(&extra.dest, kind, 0o644, time)?;
+        }
+        Ok(())
+    }
+
+    fn push(
+        &mut self,
+        parent: usize,
+        path: &Path,
+        kind: Kind,
+        metadata: &Metadata,
+    ) -> anyhow::Result<usize> {
+        let idx = self.nodes.len();
+
+        self.nodes.push(Node {
+            name: name_of(path)?,
+            path: path.to_owned(),
+            parent,
+            kind,
+            mode: metadata.mode() & 0o7777,
+            uid: 0,
+            gid: 0,
+            mtime: metadata.mtime(),
+        });
+
+        if let Kind::Dir(children) = &mut self.nodes[parent].kind {
+            children.push(idx);
+        }
+
+        Ok(idx)
+    }
+
+    /// Find a direct child of the directory at `dir` by name.
+    pub fn child(&self, dir: usize, name: &str) -> Option<usize> {
+        match &self.nodes[dir].kind {
+            Kind::Dir(children) => children
+                .iter()
+                .copied()
+                .find(|&c| self.nodes[c].name == name),
+            Kind::File { .. } | Kind::Symlink(_) => None,
+        }
+    }
+
+    /// Put an entry with `mode` and `mtime` at `path`, creating the directories leading to it.
+    /// An entry already there is replaced, except for a directory by another one, which only
+    /// takes the new mode and time. Returns the index of the entry.
+    pub fn insert(
+        &mut self,
+        path: &Path,
+        kind: Kind,
+        mode: u32,
+        mtime: i64,
+    ) -> anyhow::Result<usize> {
+        let mut dir = 0;
+        let mut cur = PathBuf::new();
+
+        let components = path.iter().collect::<Vec<_>>();
+        if components.is_empty() {
+            anyhow::bail!("entries cannot replace the root directory");
+        }
+
+        for (i, component) in components.iter().enumerate() {
+            cur.push(component);
+            let name = name_of(&cur)?;
+            let last = i == components.len() - 1;
+
+            dir = match self.child(dir, &name) {
+                Some(idx) if last => {
+                    let node = &mut self.nodes[idx];
+                    match (&node.kind, &kind) {
+                        (Kind::Dir(_), Kind::Dir(_)) => {}
+                        (Kind::Dir(_), _) => anyhow::bail!("{} is a directory", cur.display()),
+                        _ => node.kind = kind,
+                    }
+                    node.mode = mode;
+                    node.mtime = mtime;
+                    return Ok(idx);
+                }
+                Some(idx) if self.nodes[idx].is_dir() => idx,
+                Some(_) => anyhow::bail!("{} is not a directory", cur.display()),
+                None => {
+                    let idx = self.nodes.len();
+                    self.nodes.push(Node {
+                        name,
+                        path: cur.clone(),
+                        parent: dir,
+                        kind: Kind::Dir(vec![]),
+                        mode: 0o755,
+                        uid: 0,
+                        gid: 0,
+                        mtime,
+                    });
+                    if let Kind::Dir(children) = &mut self.nodes[dir].kind {
+                        children.push(idx);
+                    }
+                    idx
+                }
+            };
+        }
+
+        let node = &mut self.nodes[dir];
+        node.kind = kind;
+        node.mode = mode;
+        Ok(dir)
+    }
+
+    /// Put the contents of `other` at `path`, creating the directories leading to it. The
+    /// directory at `path` takes the mode, owner and time of the root of `other`, and must be
+    /// empty if it exists. Returns its index.
+    pub fn graft(&mut self, path: &Path, other: Tree) -> anyhow::Result<usize> {
+        let existing = path
+            .iter()
+            .try_fold(0, |dir, name| self.child(dir, name.to_str()?));
+        match existing.map(|idx| &self.nodes[idx].kind) {
+            Some(Kind::Dir(children)) if !children.is_empty() => {
+                anyhow::bail!("{} is not empty", path.display())
+            }
+            Some(Kind::File { .. } | Kind::Symlink(_)) => {
+                anyhow::bail!("{} is not a directory", path.display())
+            }
+            _ => {}
+        }
+
+        let mut nodes = other.nodes.into_iter();
+        let root = nodes.next().expect("trees have a root");
+        let at = self.insert(path, Kind::Dir(vec![]), root.mode, root.mtime)?;
+        self.nodes[at].uid = root.uid;
+        self.nodes[at].gid = root.gid;
+
+        // The other nodes follow the existing ones, in the same order
+        let base = self.nodes.len() - 1;
+        let index = |idx: usize| if idx == 0 { at } else { base + idx };
+        for mut node in nodes {
+            node.parent = index(node.parent);
+            node.path = path.join(&node.path);
+            if let Kind::Dir(children) = &mut node.kind {
+                children.iter_mut().for_each(|c| *c = index(*c));
+            }
+            self.nodes.push(node);
+        }
+        if let Kind::Dir(children) = root.kind {
+            self.nodes[at].kind = Kind::Dir(children.into_iter().map(index).collect());
+        }
+
+        Ok(at)
+    }
+
+    /// The tree without its symbolic links, for filesystems that cannot store them.
+    pub fn without_symlinks(self) -> Self {
+        if !self
+            .nodes
+            .iter()
+            .any(|n| matches!(n.kind, Kind::Symlink(_)))
+        {
+            return self;
+        }
+
+        let mut tree = Self { nodes: vec![] };
+        // Index of every node in the new tree, whose parents come before their children as well
+        let mut index = vec![0; self.nodes.len()];
+
+        for (idx, mut node) in self.nodes.into_iter().enumerate() {
+            match &mut node.kind {
+                Kind::Symlink(_) => {
+                    warn!("Skipping symlink - {}", node.path.display());
+                    continue;
+                }
+                Kind::Dir(children) => children.clear(),
+                Kind::File { .. } => {}
+            }
+
+            let new = tree.nodes.len();
+            index[idx] = new;
+            node.parent = index[node.parent];
+            if idx != 0 {
+                if let Kind::Dir(children) = &mut tree.nodes[node.parent].kind {
+                    children.push(new);
+                }
+            }
+            tree.nodes.push(node);
+        }
+
+        tree
+    }
+
+    pub fn files(&self) -> u64 {
+        self.nodes.iter().filter(|n| !n.is_dir()).count() as u64
+    }
+
+    /// Total length of the files.
+    pub fn file_bytes(&self) -> u64 {
+        self.nodes
+            .iter()
+            .map(|n| match n.kind {
+                Kind::File { len, .. } => len,
+                _ => 0,
+            })
+            .sum()
+    }
+
+    /// Find the entry at `path`, ignoring the case of its names as FAT does.
+    pub fn find_ignore_case(&self, path: &str) -> Option<usize> {
+        path.split('/')
+            .try_fold(0, |dir, name| match &self.nodes[dir].kind {
+                Kind::Dir(children) => children
+                    .iter()
+                    .copied()
+                    .find(|&c| self.nodes[c].name.eq_ignore_ascii_case(name)),
+                _ => None,
+            })
+    }
+
+    /// Number of directories, excluding the root.
+    pub fn dirs(&self) -> u64 {
+        self.nodes.iter().filter(|n| n.is_dir()).count() as u64 - 1
+    }
+}
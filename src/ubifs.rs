@@ -0,0 +1,1283 @@
+//! UBI images holding a single UBIFS volume, for raw NAND flash.
+//!
+//! Every physical erase block (PEB) starts with an erase counter header, and if it holds a
+//! logical erase block (LEB) of a volume, a volume identifier header at the offset UBI derives
+//! from the sub-page size. Both are big endian. The first two PEBs hold the volume table, which
+//! names the UBIFS volume. It spans all PEBs but those UBI keeps for itself, and grows over the
+//! rest of larger flash when UBI first attaches it. LEBs without data are left unmapped, their
+//! PEBs erased apart from the erase counter header.
+//!
+//! The volume is laid out as mkfs.ubifs lays it out: the superblock, two master nodes, the log
+//! holding a single commit start node, the LEB properties tree (LPT), an empty orphan area and
+//! the main area. That holds the inode, directory entry and data nodes, then an empty LEB for
+//! garbage collection and the index, a B+tree of the keys of all nodes. Data is compressed with
+//! zlib within the 2K window the kernel inflates with. The superblock has the kernel rewrite
+//! LEBs with free space on the first mount, as flashers program erased pages like any other.
+
+use crate::compress;
+use crate::tree::{Kind, Node, Tree};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+const EC_MAGIC: u32 = 0x5542_4923;
+const VID_MAGIC: u32 = 0x5542_4921;
+const UBI_VERSION: u8 = 1;
+const UBI_HEADER_SIZE: u32 = 64;
+const VID_DYNAMIC: u8 = 1;
+/// Compatibility of the layout volume: UBI implementations not knowing it refuse the flash
+const COMPAT_REJECT: u8 = 5;
+
+const LAYOUT_VOLUME_ID: u32 = 0x7fff_efff;
+/// PEBs of the layout volume, each holding a copy of the volume table
+const LAYOUT_VOLUME_EBS: u64 = 2;
+const VTBL_RECORD_SIZE: usize = 172;
+const MAX_VOLUMES: usize = 128;
+const MAX_VOLUME_NAME: usize = 127;
+const VTBL_AUTORESIZE: u8 = 0x01;
+
+/// PEBs UBI keeps for wear levelling and for changing LEBs atomically
+const UBI_RESERVED_PEBS: u64 = 2;
+/// PEBs UBI keeps per 1024 for replacing blocks going bad
+const BAD_PEBS_PER_1024: u64 = 20;
+/// Empty LEBs left beyond those written, for the journal heads and garbage collection
+const FREE_LEBS: u64 = 9;
+
+const NODE_MAGIC: u32 = 0x0610_1831;
+const INO_NODE: u8 = 0;
+const DATA_NODE: u8 = 1;
+const DENT_NODE: u8 = 2;
+const PAD_NODE: u8 = 5;
+const SB_NODE: u8 = 6;
+const MST_NODE: u8 = 7;
+const IDX_NODE: u8 = 9;
+const CS_NODE: u8 = 10;
+
+const INO_NODE_SIZE: usize = 160;
+const DATA_NODE_SIZE: usize = 48;
+const DENT_NODE_SIZE: usize = 56;
+const PAD_NODE_SIZE: usize = 28;
+const SB_NODE_SIZE: usize = 4096;
+const MST_NODE_SIZE: usize = 512;
+const CS_NODE_SIZE: usize = 32;
+const IDX_NODE_SIZE: usize = 28;
+const REF_NODE_SIZE: u64 = 64;
+const BRANCH_SIZE: usize = 20;
+/// Filler of gaps too small for a padding node
+const PADDING_BYTE: u8 = 0xce;
+
+/// Data of a file is split into nodes of this size
+const BLOCK_SIZE: usize = 4096;
+/// Longest symbolic link target, held by its inode node
+const MAX_INO_DATA: usize = 4096;
+const MAX_NAME: usize = 255;
+/// Largest node, and smallest useful write, for the dark and dead space watermarks
+const MAX_NODE_SIZE: u32 = (INO_NODE_SIZE + MAX_INO_DATA) as u32;
+const MIN_WRITE_SIZE: u32 = DATA_NODE_SIZE as u32 + 8;
+/// Compressed data is only kept if it saves this much
+const MIN_COMPRESS_DIFF: usize = 64;
+/// Window of the kernel's zlib decompressor
+const ZLIB_WINDOW: usize = 1 << 11;
+const MIN_LEB_SIZE: u32 = 15 << 10;
+
+const SB_LEBS: u32 = 1;
+const MST_LEBS: u32 = 2;
+const LOG_LNUM: u32 = SB_LEBS + MST_LEBS;
+const MIN_LOG_LEBS: u32 = 2;
+const MIN_LPT_LEBS: u32 = 2;
+const ORPH_LEBS: u32 = 1;
+const MIN_MAIN_LEBS: u32 = 9;
+/// Journal heads: one for data, and the garbage collection and base heads
+const JHEADS: u64 = 3;
+const FANOUT: usize = 8;
+const LSAVE_CNT: u32 = 256;
+const FMT_VERSION: u32 = 4;
+const TIME_GRAN: u32 = 1_000_000_000;
+
+const FLG_BIGLPT: u32 = 0x02;
+const FLG_SPACE_FIXUP: u32 = 0x04;
+const MST_NO_ORPHS: u32 = 0x02;
+/// Compress the data of an inode
+const COMPR_FL: u32 = 0x01;
+const COMPR_NONE: u16 = 0;
+const COMPR_ZLIB: u16 = 2;
+
+const KEY_DATA: u32 = 1;
+const KEY_DENT: u32 = 2;
+const KEY_TYPE_SHIFT: u32 = 29;
+const KEY_HASH_MASK: u32 = (1 << KEY_TYPE_SHIFT) - 1;
+
+const ITYPE_REG: u8 = 0;
+const ITYPE_DIR: u8 = 1;
+const ITYPE_LNK: u8 = 2;
+
+const S_IFDIR: u32 = 0o040000;
+const S_IFREG: u32 = 0o100000;
+const S_IFLNK: u32 = 0o120000;
+
+const ROOT_INO: u32 = 1;
+/// Inode number of the first file, those below are reserved
+const FIRST_INO: u32 = 64;
+
+const LPT_FANOUT: u32 = 4;
+const LPT_CRC_BITS: u32 = 16;
+const LPT_TYPE_BITS: u32 = 4;
+const LPT_PNODE: u32 = 0;
+const LPT_NNODE: u32 = 1;
+const LPT_LTAB: u32 = 2;
+const LPT_LSAVE: u32 = 3;
+
+/// Volume name written without a label
+const DEFAULT_VOLUME_NAME: &str = "rootfs";
+
+#[derive(Clone, Debug)]
+pub struct Options {
+    /// Name of the volume in the UBI volume table
+    pub label: Option<String>,
+    pub peb_size: u32,
+    /// Smallest unit the flash is written in, its page size
+    pub min_io_size: u32,
+    /// Unit of the UBI headers, smaller than a page on NAND flash with sub-pages
+    pub sub_page_size: u32,
+    /// Number of the volume in the volume table
+    pub volume_id: u32,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            label: None,
+            peb_size: 128 << 10,
+            min_io_size: 2048,
+            sub_page_size: 2048,
+            volume_id: 0,
+        }
+    }
+}
+
+/// CRC32 of UBI headers and UBIFS nodes, seeded with all ones and not inverted at the end.
+fn crc32(data: &[u8]) -> u32 {
+    !crc::crc32::update(0, &crc::crc32::IEEE_TABLE, data)
+}
+
+/// CRC16 of LPT nodes, seeded with all ones and not inverted at the end.
+fn crc16(data: &[u8]) -> u16 {
+    !crc::crc16::update(0, &crc::crc16::USB_TABLE, data)
+}
+
+/// Where UBI puts its headers and the data of a LEB in every PEB.
+struct Ubi {
+    peb_size: u64,
+    /// Minimum I/O size of UBIFS, at least 8 bytes
+    min_io: u32,
+    vid_hdr_offset: u32,
+    data_offset: u32,
+    leb_size: u32,
+    vtbl_slots: usize,
+    volume_id: u32,
+    name: String,
+}
+
+impl Ubi {
+    fn new(opts: &Options) -> anyhow::Result<Self> {
+        if !opts.sub_page_size.is_power_of_two()
+            || !opts.min_io_size.is_power_of_two()
+            || opts.sub_page_size > opts.min_io_size
+        {
+            anyhow::bail!("UBI sub-pages must be a power of two up to the minimum I/O size");
+        }
+
+        let vid_hdr_offset = UBI_HEADER_SIZE.next_multiple_of(opts.sub_page_size);
+        let data_offset = (vid_hdr_offset + UBI_HEADER_SIZE).next_multiple_of(opts.min_io_size);
+        let leb_size = opts.peb_size.saturating_sub(data_offset);
+        if leb_size < MIN_LEB_SIZE || !opts.peb_size.is_multiple_of(opts.min_io_size) {
+            anyhow::bail!(
+                "UBIFS needs LEBs of at least {MIN_LEB_SIZE} bytes, but PEBs of {} bytes with a \
+                 minimum I/O size of {} leave {leb_size}",
+                opts.peb_size,
+                opts.min_io_size
+            );
+        }
+
+        let vtbl_slots = (leb_size as usize / VTBL_RECORD_SIZE).min(MAX_VOLUMES);
+        if opts.volume_id as usize >= vtbl_slots {
+            anyhow::bail!("UBI volume IDs go up to {}", vtbl_slots - 1);
+        }
+
+        let name = opts.label.as_deref().unwrap_or(DEFAULT_VOLUME_NAME);
+        if name.is_empty() || name.len() > MAX_VOLUME_NAME {
+            anyhow::bail!("UBI volume names are 1 to {MAX_VOLUME_NAME} bytes: {name}");
+        }
+
+        Ok(Self {
+            peb_size: opts.peb_size as u64,
+            min_io: opts.min_io_size.max(8),
+            vid_hdr_offset,
+            data_offset,
+            leb_size,
+            vtbl_slots,
+            volume_id: opts.volume_id,
+            name: name.into(),
+        })
+    }
+
+    fn ec_header(&self, image_seq: u32) -> [u8; 64] {
+        let mut h = [0u8; 64];
+        h[0..4].copy_from_slice(&EC_MAGIC.to_be_bytes());
+        h[4] = UBI_VERSION;
+        h[16..20].copy_from_slice(&self.vid_hdr_offset.to_be_bytes());
+        h[20..24].copy_from_slice(&self.data_offset.to_be_bytes());
+        h[24..28].copy_from_slice(&image_seq.to_be_bytes());
+        let sum = crc32(&h[..60]);
+        h[60..64].copy_from_slice(&sum.to_be_bytes());
+        h
+    }
+
+    fn vid_header(vol_id: u32, lnum: u32, sqnum: u64) -> [u8; 64] {
+        let mut h = [0u8; 64];
+        h[0..4].copy_from_slice(&VID_MAGIC.to_be_bytes());
+        h[4] = UBI_VERSION;
+        h[5] = VID_DYNAMIC;
+        if vol_id == LAYOUT_VOLUME_ID {
+            h[7] = COMPAT_REJECT;
+        }
+        h[8..12].copy_from_slice(&vol_id.to_be_bytes());
+        h[12..16].copy_from_slice(&lnum.to_be_bytes());
+        h[40..48].copy_from_slice(&sqnum.to_be_bytes());
+        let sum = crc32(&h[..60]);
+        h[60..64].copy_from_slice(&sum.to_be_bytes());
+        h
+    }
+
+    /// Write PEB `peb`, holding `data` as LEB `lnum` of volume `vol_id` if given one.
+    fn write_peb<W: Write + Seek>(
+        &self,
+        out: &mut W,
+        peb: u64,
+        image_seq: u32,
+        leb: Option<(u32, u32)>,
+        data: &[u8],
+    ) -> io::Result<()> {
+        let mut buf = vec![0xff; self.peb_size as usize];
+        buf[..64].copy_from_slice(&self.ec_header(image_seq));
+        if let Some((vol_id, lnum)) = leb {
+            // Every mapped PEB is written once, so its number orders them
+            let vid = Self::vid_header(vol_id, lnum, peb);
+            buf[self.vid_hdr_offset as usize..][..64].copy_from_slice(&vid);
+            buf[self.data_offset as usize..][..data.len()].copy_from_slice(data);
+        }
+        out.seek(SeekFrom::Start(peb * self.peb_size))?;
+        out.write_all(&buf)
+    }
+
+    /// Volume table, with the UBIFS volume of `reserved_pebs` LEBs set to grow.
+    fn volume_table(&self, reserved_pebs: u32) -> Vec<u8> {
+        let mut vtbl = vec![0u8; self.vtbl_slots * VTBL_RECORD_SIZE];
+        for (i, r) in vtbl.chunks_mut(VTBL_RECORD_SIZE).enumerate() {
+            if i == self.volume_id as usize {
+                r[0..4].copy_from_slice(&reserved_pebs.to_be_bytes());
+                r[4..8].copy_from_slice(&1u32.to_be_bytes());
+                r[12] = VID_DYNAMIC;
+                r[14..16].copy_from_slice(&(self.name.len() as u16).to_be_bytes());
+                r[16..][..self.name.len()].copy_from_slice(self.name.as_bytes());
+                r[144] = VTBL_AUTORESIZE;
+            }
+            let sum = crc32(&r[..168]);
+            r[168..172].copy_from_slice(&sum.to_be_bytes());
+        }
+        vtbl
+    }
+}
+
+/// Fill in the common header of a node, and its CRC.
+fn finish_node(node: &mut [u8], node_type: u8, sqnum: u64) {
+    let len = node.len() as u32;
+    node[0..4].copy_from_slice(&NODE_MAGIC.to_le_bytes());
+    node[8..16].copy_from_slice(&sqnum.to_le_bytes());
+    node[16..20].copy_from_slice(&len.to_le_bytes());
+    node[20] = node_type;
+    let sum = crc32(&node[8..]);
+    node[4..8].copy_from_slice(&sum.to_le_bytes());
+}
+
+/// Pad the nodes in the first `len` bytes of `leb` to the minimum I/O size, with a padding
+/// node if there is room for one. Returns the padded length.
+fn pad(leb: &mut [u8], len: usize, min_io: u32) -> usize {
+    let alen = len.next_multiple_of(8);
+    let wlen = alen.next_multiple_of(min_io as usize);
+    leb[len..alen].fill(0xff);
+
+    let pad_len = wlen - alen;
+    if pad_len >= PAD_NODE_SIZE {
+        let node = &mut leb[alen..alen + PAD_NODE_SIZE];
+        node.fill(0);
+        node[24..28].copy_from_slice(&((pad_len - PAD_NODE_SIZE) as u32).to_le_bytes());
+        finish_node(node, PAD_NODE, 0);
+        leb[alen + PAD_NODE_SIZE..wlen].fill(0);
+    } else {
+        leb[alen..wlen].fill(PADDING_BYTE);
+    }
+    wlen
+}
+
+/// Key of a node: the inode number, and the type with the block number or name hash.
+type Key = (u32, u32);
+
+/// Hash of directory entry names, with 0 to 2 left for `.`, `..` and the end of a listing.
+fn name_hash(name: &[u8]) -> u32 {
+    let mut a = 0u32;
+    for &c in name {
+        // Bytes are signed chars
+        let c = c as i8 as i32;
+        a = a.wrapping_add((c << 4) as u32);
+        a = a.wrapping_add((c >> 4) as u32);
+        a = a.wrapping_mul(11);
+    }
+    match a & KEY_HASH_MASK {
+        a @ 0..=2 => a + 3,
+        a => a,
+    }
+}
+
+fn put_key(node: &mut [u8], key: Key) {
+    node[24..28].copy_from_slice(&key.0.to_le_bytes());
+    node[28..32].copy_from_slice(&key.1.to_le_bytes());
+}
+
+/// Where a node of the main area went, for the index.
+#[derive(Clone, Copy)]
+struct Branch {
+    key: Key,
+    lnum: u32,
+    offs: u32,
+    len: u32,
+}
+
+/// Free and dirty space of a LEB of the main area, and whether it holds the index.
+#[derive(Clone, Copy)]
+struct Lprops {
+    free: u32,
+    dirty: u32,
+    index: bool,
+}
+
+/// Places nodes into the LEBs of the main area, measuring it when there is nothing to write to.
+struct Main<'a, W> {
+    out: Option<&'a mut W>,
+    ubi: &'a Ubi,
+    image_seq: u32,
+    /// PEBs of the image, which the main area has to fit in
+    pebs: u64,
+    /// LEB number of the first LEB of the main area
+    first: u32,
+    leb: Vec<u8>,
+    offs: usize,
+    /// Whether the current LEB holds index nodes
+    index: bool,
+    /// Properties of the LEBs finished so far
+    lprops: Vec<Lprops>,
+    sqnum: u64,
+}
+
+impl<W: Write + Seek> Main<'_, W> {
+    fn lnum(&self) -> u32 {
+        self.first + self.lprops.len() as u32
+    }
+
+    fn node(&mut self, node_type: u8, key: Key, mut node: Vec<u8>) -> io::Result<Branch> {
+        if self.offs + node.len() > self.leb.len() {
+            self.flush()?;
+        }
+
+        self.sqnum += 1;
+        if node_type == INO_NODE {
+            // Inodes are created by their first node
+            node[40..48].copy_from_slice(&self.sqnum.to_le_bytes());
+        }
+        finish_node(&mut node, node_type, self.sqnum);
+        self.leb[self.offs..][..node.len()].copy_from_slice(&node);
+
+        let branch = Branch {
+            key,
+            lnum: self.lnum(),
+            offs: self.offs as u32,
+            len: node.len() as u32,
+        };
+        self.offs = (self.offs + node.len()).next_multiple_of(8);
+        Ok(branch)
+    }
+
+    /// Pad the current LEB and write it out, unless it is empty.
+    fn flush(&mut self) -> io::Result<()> {
+        if self.offs == 0 {
+            return Ok(());
+        }
+
+        let len = pad(&mut self.leb, self.offs, self.ubi.min_io);
+        let lnum = self.lnum();
+        let peb = LAYOUT_VOLUME_EBS + lnum as u64;
+        if let Some(out) = &mut self.out {
+            if peb >= self.pebs {
+                return Err(io::Error::other(format!(
+                    "UBI image does not fit in {} PEBs",
+                    self.pebs
+                )));
+            }
+            let leb = Some((self.ubi.volume_id, lnum));
+            self.ubi
+                .write_peb(out, peb, self.image_seq, leb, &self.leb[..len])?;
+        }
+
+        // Space after the padding is free, the padding itself dirty
+        let leb_size = self.ubi.leb_size;
+        let free = leb_size - len as u32;
+        self.lprops.push(Lprops {
+            free,
+            dirty: leb_size - free - self.offs.next_multiple_of(8) as u32,
+            index: self.index,
+        });
+        self.leb.fill(0xff);
+        self.offs = 0;
+        Ok(())
+    }
+
+    /// Leave an empty LEB, returning its number.
+    fn empty(&mut self) -> io::Result<u32> {
+        self.flush()?;
+        let lnum = self.lnum();
+        self.lprops.push(Lprops {
+            free: self.ubi.leb_size,
+            dirty: 0,
+            index: false,
+        });
+        Ok(lnum)
+    }
+}
+
+/// Main area as written, and what the master node records of it.
+struct MainArea {
+    lprops: Vec<Lprops>,
+    gc_lnum: u32,
+    root: Branch,
+    /// Where the next index node goes
+    ihead: (u32, u32),
+    index_size: u64,
+    highest_inum: u32,
+    sqnum: u64,
+}
+
+fn ino_node(node: &Node, inum: u32, mode: u32, size: u64, nlink: u32, data: &[u8]) -> Vec<u8> {
+    let mut n = vec![0u8; INO_NODE_SIZE + data.len()];
+    put_key(&mut n, (inum, 0));
+    n[48..56].copy_from_slice(&size.to_le_bytes());
+    for t in [56, 64, 72] {
+        n[t..t + 8].copy_from_slice(&node.mtime.to_le_bytes());
+    }
+    n[92..96].copy_from_slice(&nlink.to_le_bytes());
+    n[96..100].copy_from_slice(&node.uid.to_le_bytes());
+    n[100..104].copy_from_slice(&node.gid.to_le_bytes());
+    n[104..108].copy_from_slice(&(mode | node.mode).to_le_bytes());
+    n[108..112].copy_from_slice(&COMPR_FL.to_le_bytes());
+    n[112..116].copy_from_slice(&(data.len() as u32).to_le_bytes());
+    n[132..134].copy_from_slice(&COMPR_ZLIB.to_le_bytes());
+    n[INO_NODE_SIZE..].copy_from_slice(data);
+    n
+}
+
+fn dent_node(parent: u32, inum: u32, itype: u8, name: &str) -> Vec<u8> {
+    let mut n = vec![0u8; DENT_NODE_SIZE + name.len() + 1];
+    put_key(&mut n, dent_key(parent, name));
+    n[40..48].copy_from_slice(&(inum as u64).to_le_bytes());
+    n[49] = itype;
+    n[50..52].copy_from_slice(&(name.len() as u16).to_le_bytes());
+    n[DENT_NODE_SIZE..][..name.len()].copy_from_slice(name.as_bytes());
+    n
+}
+
+fn dent_key(parent: u32, name: &str) -> Key {
+    (
+        parent,
+        KEY_DENT << KEY_TYPE_SHIFT | name_hash(name.as_bytes()),
+    )
+}
+
+fn data_node(key: Key, data: &[u8]) -> Vec<u8> {
+    let z = compress::deflate_window(data, compress::DEFAULT_LEVEL, ZLIB_WINDOW);
+    let (compr, payload) = if z.len() + MIN_COMPRESS_DIFF < data.len() {
+        (COMPR_ZLIB, &z[..])
+    } else {
+        (COMPR_NONE, data)
+    };
+
+    let mut n = vec![0u8; DATA_NODE_SIZE + payload.len()];
+    put_key(&mut n, key);
+    n[40..44].copy_from_slice(&(data.len() as u32).to_le_bytes());
+    n[44..46].copy_from_slice(&compr.to_le_bytes());
+    n[DATA_NODE_SIZE..].copy_from_slice(payload);
+    n
+}
+
+/// Write the main area from LEB `first` on, or only measure it without `out`.
+fn build<W: Write + Seek>(
+    out: Option<&mut W>,
+    ubi: &Ubi,
+    image_seq: u32,
+    pebs: u64,
+    first: u32,
+    tree: &Tree,
+    on_file: &mut dyn FnMut(&Path, u64),
+) -> anyhow::Result<MainArea> {
+    for node in &tree.nodes[1..] {
+        if node.name.len() > MAX_NAME {
+            anyhow::bail!(
+                "UBIFS names are limited to {MAX_NAME} bytes: {}",
+                node.path.display()
+            );
+        }
+        match &node.kind {
+            Kind::Symlink(target) if target.len() > MAX_INO_DATA => {
+                anyhow::bail!(
+                    "UBIFS symbolic links are limited to {MAX_INO_DATA} bytes: {}",
+                    node.path.display()
+                );
+            }
+            Kind::File { len, .. } if *len > (BLOCK_SIZE as u64) << KEY_TYPE_SHIFT => {
+                anyhow::bail!("UBIFS files are limited to 2TiB: {}", node.path.display());
+            }
+            _ => {}
+        }
+    }
+
+    let mut m = Main {
+        out,
+        ubi,
+        image_seq,
+        pebs,
+        first,
+        leb: vec![0xff; ubi.leb_size as usize],
+        offs: 0,
+        index: false,
+        lprops: vec![],
+        sqnum: 0,
+    };
+    let inum = |idx: usize| match idx {
+        0 => ROOT_INO,
+        _ => FIRST_INO - 1 + idx as u32,
+    };
+    let mut leaves = vec![];
+    let mut block = vec![0u8; BLOCK_SIZE];
+
+    for (idx, node) in tree.nodes.iter().enumerate() {
+        let ino = inum(idx);
+        match &node.kind {
+            Kind::Dir(children) => {
+                let size = INO_NODE_SIZE
+                    + children
+                        .iter()
+                        .map(|&c| {
+                            (DENT_NODE_SIZE + tree.nodes[c].name.len() + 1).next_multiple_of(8)
+                        })
+                        .sum::<usize>();
+                let subdirs = children.iter().filter(|&&c| tree.nodes[c].is_dir()).count();
+                let n = ino_node(node, ino, S_IFDIR, size as u64, 2 + subdirs as u32, &[]);
+                leaves.push(m.node(INO_NODE, (ino, 0), n)?);
+
+                for &c in children {
+                    let child = &tree.nodes[c];
+                    let itype = match child.kind {
+                        Kind::Dir(_) => ITYPE_DIR,
+                        Kind::File { .. } => ITYPE_REG,
+                        Kind::Symlink(_) => ITYPE_LNK,
+                    };
+                    let n = dent_node(ino, inum(c), itype, &child.name);
+                    leaves.push(m.node(DENT_NODE, dent_key(ino, &child.name), n)?);
+                }
+            }
+            Kind::File { source, len } => {
+                let n = ino_node(node, ino, S_IFREG, *len, 1, &[]);
+                leaves.push(m.node(INO_NODE, (ino, 0), n)?);
+
+                let mut file = source.open()?;
+                let mut offset = 0;
+                while offset < *len {
+                    let n = (len - offset).min(BLOCK_SIZE as u64) as usize;
+                    file.read_exact(&mut block[..n]).map_err(|e| {
+                        anyhow::anyhow!("{} changed while being copied: {e}", node.path.display())
+                    })?;
+
+                    // Blocks of zeros are holes
+                    if block[..n].iter().any(|&b| b != 0) {
+                        let key = (
+                            ino,
+                            KEY_DATA << KEY_TYPE_SHIFT | (offset / BLOCK_SIZE as u64) as u32,
+                        );
+                        leaves.push(m.node(DATA_NODE, key, data_node(key, &block[..n]))?);
+                    }
+                    offset += n as u64;
+                }
+
+                on_file(&node.path, *len);
+            }
+            Kind::Symlink(target) => {
+                let target = target.as_bytes();
+                let n = ino_node(node, ino, S_IFLNK, target.len() as u64, 1, target);
+                leaves.push(m.node(INO_NODE, (ino, 0), n)?);
+            }
+        }
+    }
+
+    m.flush()?;
+    let gc_lnum = m.empty()?;
+
+    // The index, bottom up, in LEBs of its own
+    leaves.sort_by_key(|b| b.key);
+    m.index = true;
+    let mut level = leaves;
+    let mut height = 0u16;
+    let mut index_size = 0;
+    let root = loop {
+        let mut above = vec![];
+        for chunk in level.chunks(FANOUT) {
+            let mut n = vec![0u8; IDX_NODE_SIZE + chunk.len() * BRANCH_SIZE];
+            n[24..26].copy_from_slice(&(chunk.len() as u16).to_le_bytes());
+            n[26..28].copy_from_slice(&height.to_le_bytes());
+            for (b, br) in n[IDX_NODE_SIZE..].chunks_mut(BRANCH_SIZE).zip(chunk) {
+                b[0..4].copy_from_slice(&br.lnum.to_le_bytes());
+                b[4..8].copy_from_slice(&br.offs.to_le_bytes());
+                b[8..12].copy_from_slice(&br.len.to_le_bytes());
+                b[12..16].copy_from_slice(&br.key.0.to_le_bytes());
+                b[16..20].copy_from_slice(&br.key.1.to_le_bytes());
+            }
+            index_size += n.len().next_multiple_of(8) as u64;
+            above.push(m.node(IDX_NODE, chunk[0].key, n)?);
+        }
+        if above.len() == 1 {
+            break above[0];
+        }
+        level = above;
+        height += 1;
+    };
+    let ihead = (
+        m.lnum(),
+        m.offs.next_multiple_of(m.ubi.min_io as usize) as u32,
+    );
+    m.flush()?;
+
+    Ok(MainArea {
+        lprops: m.lprops,
+        gc_lnum,
+        root,
+        ihead,
+        index_size,
+        highest_inum: inum(tree.nodes.len() - 1).max(FIRST_INO - 1),
+        sqnum: m.sqnum,
+    })
+}
+
+/// Sizes of the parts of the LPT, for a main area of up to `main_lebs` LEBs.
+struct Lpt {
+    lebs: u32,
+    big: bool,
+    hght: u32,
+    pnode_cnt: u32,
+    space_bits: u32,
+    lpt_lnum_bits: u32,
+    lpt_offs_bits: u32,
+    lpt_spc_bits: u32,
+    pcnt_bits: u32,
+    lnum_bits: u32,
+    pnode_sz: usize,
+    nnode_sz: usize,
+    ltab_sz: usize,
+    lsave_sz: usize,
+    /// Size of the whole tree with the space lost at the end of LEBs
+    sz: u64,
+}
+
+/// Position of the highest set bit, counted from 1.
+fn fls(x: u32) -> u32 {
+    32 - x.leading_zeros()
+}
+
+impl Lpt {
+    fn calc(
+        leb_size: u32,
+        min_io: u32,
+        max_leb_cnt: u32,
+        main_lebs: u32,
+        lebs: u32,
+        big: bool,
+    ) -> Self {
+        let pnode_cnt = main_lebs.div_ceil(LPT_FANOUT);
+        let mut hght = 1;
+        let mut n = LPT_FANOUT;
+        while n < pnode_cnt {
+            hght += 1;
+            n *= LPT_FANOUT;
+        }
+
+        let mut nnode_cnt = pnode_cnt.div_ceil(LPT_FANOUT);
+        let mut n = nnode_cnt;
+        for _ in 1..hght {
+            n = n.div_ceil(LPT_FANOUT);
+            nnode_cnt += n;
+        }
+
+        let space_bits = fls(leb_size) - 3;
+        let lpt_lnum_bits = fls(lebs);
+        let lpt_offs_bits = fls(leb_size - 1);
+        let lpt_spc_bits = fls(leb_size);
+        let pcnt_bits = fls(max_leb_cnt.div_ceil(LPT_FANOUT) - 1);
+        let lnum_bits = fls(max_leb_cnt - 1);
+
+        let head = LPT_CRC_BITS + LPT_TYPE_BITS;
+        let num = if big { pcnt_bits } else { 0 };
+        let bytes = |bits: u32| bits.div_ceil(8) as usize;
+        let pnode_sz = bytes(head + num + (space_bits * 2 + 1) * LPT_FANOUT);
+        let nnode_sz = bytes(head + num + (lpt_lnum_bits + lpt_offs_bits) * LPT_FANOUT);
+        let ltab_sz = bytes(head + lebs * lpt_spc_bits * 2);
+        let lsave_sz = bytes(head + lnum_bits * LSAVE_CNT);
+
+        let mut sz = pnode_cnt as u64 * pnode_sz as u64
+            + nnode_cnt as u64 * nnode_sz as u64
+            + ltab_sz as u64;
+        if big {
+            sz += lsave_sz as u64;
+        }
+
+        // Nodes do not cross LEBs, and the last is padded to the minimum I/O size
+        let wastage = pnode_sz.max(nnode_sz) as u64;
+        let mut rest = sz + wastage;
+        let mut total_wastage = wastage;
+        while rest > leb_size as u64 {
+            rest = rest + wastage - leb_size as u64;
+            total_wastage += wastage;
+        }
+        total_wastage += rest.next_multiple_of(min_io as u64) - rest;
+
+        Self {
+            lebs,
+            big,
+            hght,
+            pnode_cnt,
+            space_bits,
+            lpt_lnum_bits,
+            lpt_offs_bits,
+            lpt_spc_bits,
+            pcnt_bits,
+            lnum_bits,
+            pnode_sz,
+            nnode_sz,
+            ltab_sz,
+            lsave_sz,
+            sz: sz + total_wastage,
+        }
+    }
+
+    /// LPT of a volume with `lebs` LEBs for the LPT and the main area, in as few LEBs as leave
+    /// room for it to be rewritten four times over.
+    fn new(leb_size: u32, min_io: u32, max_leb_cnt: u32, lebs: u32) -> Option<Self> {
+        let main_lebs = lebs.checked_sub(MIN_LPT_LEBS).filter(|&m| m > 0)?;
+        let mut lpt = Self::calc(
+            leb_size,
+            min_io,
+            max_leb_cnt,
+            main_lebs,
+            MIN_LPT_LEBS,
+            false,
+        );
+        if lpt.sz > leb_size as u64 {
+            lpt = Self::calc(leb_size, min_io, max_leb_cnt, main_lebs, MIN_LPT_LEBS, true);
+        }
+
+        for _ in 0..64 {
+            let needed = (lpt.sz * 4).div_ceil(leb_size as u64) as u32;
+            if needed > lpt.lebs {
+                let main_lebs = lebs.checked_sub(needed).filter(|&m| m > 0)?;
+                lpt = Self::calc(leb_size, min_io, max_leb_cnt, main_lebs, needed, lpt.big);
+                continue;
+            }
+            return (lpt.ltab_sz <= leb_size as usize).then_some(lpt);
+        }
+        None
+    }
+}
+
+/// Bit-packed LPT node, filled from the lowest bit of every byte.
+struct Packed {
+    buf: Vec<u8>,
+    pos: usize,
+}
+
+impl Packed {
+    fn new(len: usize, node_type: u32) -> Self {
+        let mut p = Self {
+            buf: vec![0; len],
+            pos: LPT_CRC_BITS as usize,
+        };
+        p.put(node_type, LPT_TYPE_BITS);
+        p
+    }
+
+    fn put(&mut self, value: u32, bits: u32) {
+        for i in 0..bits {
+            if value >> i & 1 != 0 {
+                self.buf[self.pos / 8] |= 1 << (self.pos % 8);
+            }
+            self.pos += 1;
+        }
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        let sum = crc16(&self.buf[2..]);
+        self.buf[..2].copy_from_slice(&sum.to_le_bytes());
+        self.buf
+    }
+}
+
+/// Number of an nnode of the LPT, from its row below the root and column.
+fn nnode_num(mut row: u32, mut col: u32) -> u32 {
+    let mut num = 1;
+    while row > 0 {
+        num = num << 2 | (col & (LPT_FANOUT - 1));
+        col >>= 2;
+        row -= 1;
+    }
+    num
+}
+
+/// Places LPT nodes into LEBs, tracking their free and dirty space in the LPT's own table.
+struct LptOut {
+    leb_size: u32,
+    min_io: u32,
+    first: u32,
+    lebs: Vec<Vec<u8>>,
+    buf: Vec<u8>,
+    ltab: Vec<(u32, u32)>,
+}
+
+impl LptOut {
+    fn lnum(&self) -> u32 {
+        self.first + self.lebs.len() as u32
+    }
+
+    /// Make room for a node of `len` bytes, returning where it goes.
+    fn reserve(&mut self, len: usize) -> (u32, u32) {
+        if self.buf.len() + len > self.leb_size as usize {
+            self.flush();
+        }
+        (self.lnum(), self.buf.len() as u32)
+    }
+
+    fn node(&mut self, node: Vec<u8>) -> (u32, u32) {
+        let pos = self.reserve(node.len());
+        self.buf.extend(node);
+        pos
+    }
+
+    /// Pad the current LEB to the minimum I/O size and start the next.
+    fn flush(&mut self) {
+        let len = self.buf.len();
+        let alen = len.next_multiple_of(self.min_io as usize);
+        self.ltab[self.lebs.len()] = (self.leb_size - alen as u32, (alen - len) as u32);
+        self.buf.resize(alen, 0xff);
+        self.lebs.push(std::mem::take(&mut self.buf));
+    }
+}
+
+/// LEBs of the LPT, and where its root, table and save table went.
+struct LptArea {
+    lebs: Vec<Vec<u8>>,
+    root: (u32, u32),
+    nhead: (u32, u32),
+    ltab: (u32, u32),
+    lsave: (u32, u32),
+}
+
+/// Lay out the LPT for the properties of the main area, as mkfs.ubifs does: the pnodes of all
+/// LEBs the volume may grow to, the nnodes above them row by row, and the tables.
+fn lpt_area(g: &Geometry, main_first: u32, lprops: &[Lprops]) -> LptArea {
+    let lpt = &g.lpt;
+    let leb_size = g.leb_size;
+    let mut o = LptOut {
+        leb_size,
+        min_io: g.min_io,
+        first: g.lpt_first(),
+        lebs: vec![],
+        buf: vec![],
+        ltab: vec![(leb_size, 0); lpt.lebs as usize],
+    };
+    let num = |p: &mut Packed, num: u32| {
+        if lpt.big {
+            p.put(num, lpt.pcnt_bits);
+        }
+    };
+
+    let mut below = vec![];
+    for i in 0..lpt.pnode_cnt {
+        let mut p = Packed::new(lpt.pnode_sz, LPT_PNODE);
+        num(&mut p, i);
+        for j in 0..LPT_FANOUT {
+            let lp = lprops
+                .get((i * LPT_FANOUT + j) as usize)
+                .copied()
+                .unwrap_or(Lprops {
+                    free: leb_size,
+                    dirty: 0,
+                    index: false,
+                });
+            p.put(lp.free >> 3, lpt.space_bits);
+            p.put(lp.dirty >> 3, lpt.space_bits);
+            p.put(lp.index as u32, 1);
+        }
+        below.push(o.node(p.finish()));
+    }
+
+    let mut row = lpt.hght - 1;
+    let root = loop {
+        let mut above = vec![];
+        for (col, chunk) in below.chunks(LPT_FANOUT as usize).enumerate() {
+            let mut p = Packed::new(lpt.nnode_sz, LPT_NNODE);
+            num(&mut p, nnode_num(row, col as u32));
+            for j in 0..LPT_FANOUT as usize {
+                // Missing branches point past the last LPT LEB
+                let (lnum, offs) = chunk.get(j).copied().unwrap_or((o.first + lpt.lebs, 0));
+                p.put(lnum - o.first, lpt.lpt_lnum_bits);
+                p.put(offs, lpt.lpt_offs_bits);
+            }
+            above.push(o.node(p.finish()));
+        }
+        if above.len() == 1 {
+            break above[0];
+        }
+        below = above;
+        row -= 1;
+    };
+
+    let mut lsave = (0, 0);
+    if lpt.big {
+        let mut p = Packed::new(lpt.lsave_sz, LPT_LSAVE);
+        for i in 0..LSAVE_CNT {
+            let lnum = if (i as usize) < lprops.len() {
+                main_first + i
+            } else {
+                main_first
+            };
+            p.put(lnum, lpt.lnum_bits);
+        }
+        lsave = o.node(p.finish());
+    }
+
+    // The table of the LPT LEBs includes its own
+    let ltab = o.reserve(lpt.ltab_sz);
+    let len = ltab.1 as usize + lpt.ltab_sz;
+    let alen = len.next_multiple_of(g.min_io as usize);
+    o.ltab[o.lebs.len()] = (leb_size - alen as u32, (alen - len) as u32);
+    let mut p = Packed::new(lpt.ltab_sz, LPT_LTAB);
+    for &(free, dirty) in &o.ltab {
+        p.put(free, lpt.lpt_spc_bits);
+        p.put(dirty, lpt.lpt_spc_bits);
+    }
+    o.buf.extend(p.finish());
+    let nhead = (o.lnum(), alen as u32);
+    o.flush();
+
+    LptArea {
+        lebs: o.lebs,
+        root,
+        nhead,
+        ltab,
+        lsave,
+    }
+}
+
+/// Sizes of the areas of a UBIFS volume that may grow to `max_leb_cnt` LEBs.
+struct Geometry {
+    leb_size: u32,
+    min_io: u32,
+    max_leb_cnt: u32,
+    max_bud_bytes: u64,
+    log_lebs: u32,
+    lpt: Lpt,
+}
+
+impl Geometry {
+    fn new(ubi: &Ubi, max_leb_cnt: u32) -> Option<Self> {
+        let (leb_size, min_io) = (ubi.leb_size, ubi.min_io);
+        let leb = leb_size as u64;
+
+        // The journal is an eighth of the main area, from 4 LEBs up to 8MiB
+        let lebs = max_leb_cnt
+            .checked_sub(SB_LEBS + MST_LEBS + ORPH_LEBS + MIN_LOG_LEBS + MIN_LPT_LEBS)?;
+        let max_bud_bytes = ((lebs / 8) as u64 * leb).min(8 << 20).max(4 * leb);
+
+        // The log holds a reference to every bud, and a commit, with LEBs to spare
+        let buds = max_bud_bytes.div_ceil(leb);
+        let log_size = REF_NODE_SIZE.next_multiple_of(min_io as u64) * buds
+            + (CS_NODE_SIZE as u64 + REF_NODE_SIZE * JHEADS).next_multiple_of(min_io as u64);
+        let log_lebs = log_size.div_ceil(leb) as u32 + 3;
+
+        let lebs = max_leb_cnt.checked_sub(SB_LEBS + MST_LEBS + log_lebs + ORPH_LEBS)?;
+        let lpt = Lpt::new(leb_size, min_io, max_leb_cnt, lebs)?;
+
+        Some(Self {
+            leb_size,
+            min_io,
+            max_leb_cnt,
+            max_bud_bytes,
+            log_lebs,
+            lpt,
+        })
+    }
+
+    fn lpt_first(&self) -> u32 {
+        LOG_LNUM + self.log_lebs
+    }
+
+    fn main_first(&self) -> u32 {
+        self.lpt_first() + self.lpt.lebs + ORPH_LEBS
+    }
+
+    /// Fewest LEBs the kernel mounts the volume with.
+    fn min_leb_cnt(&self) -> u32 {
+        let min = self.main_first() + JHEADS as u32 + 6;
+        min.max(self.main_first() + MIN_MAIN_LEBS)
+    }
+}
+
+/// Layout and LEB count of the UBIFS volume in an image of `pebs` PEBs with `used` LEBs of main
+/// area, if it fits with room to spare in the PEBs UBI leaves to volumes.
+fn fit(ubi: &Ubi, pebs: u64, used: u32) -> Option<(Geometry, u32)> {
+    let max_leb_cnt = pebs.checked_sub(LAYOUT_VOLUME_EBS + UBI_RESERVED_PEBS)?;
+    let g = Geometry::new(ubi, u32::try_from(max_leb_cnt).ok()?)?;
+    let leb_cnt = max_leb_cnt - pebs * BAD_PEBS_PER_1024 / 1024;
+    let needed = (g.main_first() + used).max(g.min_leb_cnt()) as u64;
+    (needed + FREE_LEBS <= leb_cnt).then_some((g, leb_cnt as u32))
+}
+
+/// Fewest PEBs holding `used` LEBs of main area.
+fn pebs_needed(ubi: &Ubi, used: u32) -> u64 {
+    (LAYOUT_VOLUME_EBS + UBI_RESERVED_PEBS..)
+        .find(|&pebs| fit(ubi, pebs, used).is_some())
+        .unwrap()
+}
+
+/// Smallest filesystem size the tree fits in.
+pub fn estimate_size(tree: &Tree, opts: &Options) -> anyhow::Result<u64> {
+    let ubi = Ubi::new(opts)?;
+    let main = build::<io::Cursor<Vec<u8>>>(None, &ubi, 0, u64::MAX, 0, tree, &mut |_, _| ())?;
+    Ok(pebs_needed(&ubi, main.lprops.len() as u32) * ubi.peb_size)
+}
+
+/// Write the image into `out`, which spans `size` bytes.
+///
+/// `on_file` is called with the image path and length of every file written.
+pub fn write<W: Write + Seek>(
+    out: &mut W,
+    size: u64,
+    tree: &Tree,
+    opts: &Options,
+    ctx: &crate::BuildContext,
+    on_file: &mut dyn FnMut(&Path, u64),
+) -> anyhow::Result<()> {
+    let ubi = Ubi::new(opts)?;
+    let pebs = size / ubi.peb_size;
+    let Some(g) = pebs
+        .checked_sub(LAYOUT_VOLUME_EBS + UBI_RESERVED_PEBS)
+        .and_then(|lebs| Geometry::new(&ubi, u32::try_from(lebs).ok()?))
+    else {
+        anyhow::bail!("UBI image of {size} bytes is too small for UBIFS");
+    };
+
+    let mut random = [0u8; 20];
+    ctx.random.fill(&mut random)?;
+    let image_seq = u32::from_le_bytes(random[..4].try_into().unwrap());
+    let main_first = g.main_first();
+    let mut main = build(
+        Some(&mut *out),
+        &ubi,
+        image_seq,
+        pebs,
+        main_first,
+        tree,
+        on_file,
+    )?;
+
+    let used = main.lprops.len() as u32;
+    let Some((_, leb_cnt)) = fit(&ubi, pebs, used) else {
+        anyhow::bail!(
+            "UBIFS image needs {} bytes, but only {size} are available",
+            pebs_needed(&ubi, used) * ubi.peb_size
+        );
+    };
+    main.lprops.resize(
+        (leb_cnt - main_first) as usize,
+        Lprops {
+            free: ubi.leb_size,
+            dirty: 0,
+            index: false,
+        },
+    );
+
+    let lpt = lpt_area(&g, main_first, &main.lprops);
+
+    // Space statistics, as the kernel keeps them
+    let leb_size = ubi.leb_size;
+    let dead_wm = MIN_WRITE_SIZE.next_multiple_of(ubi.min_io);
+    let dark_wm = MAX_NODE_SIZE.next_multiple_of(ubi.min_io);
+    let (mut free, mut dirty, mut used_bytes, mut dead, mut dark) = (0u64, 0u64, 0u64, 0u64, 0u64);
+    let (mut empty_lebs, mut idx_lebs) = (0u32, 0u32);
+    for lp in &main.lprops {
+        free += lp.free as u64;
+        dirty += lp.dirty as u64;
+        if lp.free == leb_size {
+            empty_lebs += 1;
+        }
+        if lp.index {
+            idx_lebs += 1;
+            continue;
+        }
+        let spc = lp.free + lp.dirty;
+        if spc < dead_wm {
+            dead += spc as u64;
+        } else {
+            dark += match spc {
+                spc if spc < dark_wm => spc,
+                spc if spc - dark_wm < MIN_WRITE_SIZE => spc - MIN_WRITE_SIZE,
+                _ => dark_wm,
+            } as u64;
+        }
+        used_bytes += (leb_size - spc) as u64;
+    }
+
+    let mut sqnum = main.sqnum;
+    let mut next_sqnum = || {
+        sqnum += 1;
+        sqnum
+    };
+
+    let mut sb = vec![0u8; SB_NODE_SIZE];
+    let mut flags = FLG_SPACE_FIXUP;
+    if g.lpt.big {
+        flags |= FLG_BIGLPT;
+    }
+    sb[28..32].copy_from_slice(&flags.to_le_bytes());
+    sb[32..36].copy_from_slice(&ubi.min_io.to_le_bytes());
+    sb[36..40].copy_from_slice(&leb_size.to_le_bytes());
+    sb[40..44].copy_from_slice(&leb_cnt.to_le_bytes());
+    sb[44..48].copy_from_slice(&g.max_leb_cnt.to_le_bytes());
+    sb[48..56].copy_from_slice(&g.max_bud_bytes.to_le_bytes());
+    sb[56..60].copy_from_slice(&g.log_lebs.to_le_bytes());
+    sb[60..64].copy_from_slice(&g.lpt.lebs.to_le_bytes());
+    sb[64..68].copy_from_slice(&ORPH_LEBS.to_le_bytes());
+    sb[68..72].copy_from_slice(&(JHEADS as u32 - 2).to_le_bytes());
+    sb[72..76].copy_from_slice(&(FANOUT as u32).to_le_bytes());
+    sb[76..80].copy_from_slice(&LSAVE_CNT.to_le_bytes());
+    sb[80..84].copy_from_slice(&FMT_VERSION.to_le_bytes());
+    sb[84..86].copy_from_slice(&COMPR_ZLIB.to_le_bytes());
+    sb[104..108].copy_from_slice(&TIME_GRAN.to_le_bytes());
+    sb[108..124].copy_from_slice(&random[4..]);
+    finish_node(&mut sb, SB_NODE, next_sqnum());
+
+    let mut mst = vec![0u8; MST_NODE_SIZE];
+    let fields32 = [
+        (44, LOG_LNUM),
+        (48, main.root.lnum),
+        (52, main.root.offs),
+        (56, main.root.len),
+        (60, main.gc_lnum),
+        (64, main.ihead.0),
+        (68, main.ihead.1),
+        (120, lpt.root.0),
+        (124, lpt.root.1),
+        (128, lpt.nhead.0),
+        (132, lpt.nhead.1),
+        (136, lpt.ltab.0),
+        (140, lpt.ltab.1),
+        (144, lpt.lsave.0),
+        (148, lpt.lsave.1),
+        (152, main_first),
+        (156, empty_lebs),
+        (160, idx_lebs),
+        (164, leb_cnt),
+    ];
+    for (offset, value) in fields32 {
+        mst[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+    }
+    mst[24..32].copy_from_slice(&(main.highest_inum as u64).to_le_bytes());
+    mst[40..44].copy_from_slice(&MST_NO_ORPHS.to_le_bytes());
+    let fields64 = [
+        (72, main.index_size),
+        (80, free),
+        (88, dirty),
+        (96, used_bytes),
+        (104, dead),
+        (112, dark),
+    ];
+    for (offset, value) in fields64 {
+        mst[offset..offset + 8].copy_from_slice(&value.to_le_bytes());
+    }
+    finish_node(&mut mst, MST_NODE, next_sqnum());
+
+    // The log starts with the commit the image was made by
+    let mut cs = vec![0u8; CS_NODE_SIZE];
+    finish_node(&mut cs, CS_NODE, next_sqnum());
+
+    let mut write_leb = |lnum: u32, node: &[u8]| -> io::Result<()> {
+        let mut leb = vec![0xff; leb_size as usize];
+        leb[..node.len()].copy_from_slice(node);
+        let len = pad(&mut leb, node.len(), ubi.min_io);
+        let peb = LAYOUT_VOLUME_EBS + lnum as u64;
+        let vol = Some((ubi.volume_id, lnum));
+        ubi.write_peb(out, peb, image_seq, vol, &leb[..len])
+    };
+    write_leb(0, &sb)?;
+    write_leb(1, &mst)?;
+    write_leb(2, &mst)?;
+    write_leb(LOG_LNUM, &cs)?;
+    for (i, leb) in lpt.lebs.iter().enumerate() {
+        write_leb(g.lpt_first() + i as u32, leb)?;
+    }
+
+    let vtbl = ubi.volume_table(leb_cnt);
+    for lnum in 0..LAYOUT_VOLUME_EBS as u32 {
+        let vol = Some((LAYOUT_VOLUME_ID, lnum));
+        ubi.write_peb(out, lnum as u64, image_seq, vol, &vtbl)?;
+    }
+
+    // LEBs without nodes are left unmapped, and with the PEBs beyond the volume, erased
+    let mut mapped = vec![false; leb_cnt as usize];
+    mapped[..=LOG_LNUM as usize].fill(true);
+    let lpt_first = g.lpt_first() as usize;
+    mapped[lpt_first..lpt_first + lpt.lebs.len()].fill(true);
+    for (i, lp) in main.lprops.iter().enumerate() {
+        mapped[main_first as usize + i] = lp.free < leb_size;
+    }
+    for peb in LAYOUT_VOLUME_EBS..pebs {
+        let lnum = (peb - LAYOUT_VOLUME_EBS) as usize;
+        if !mapped.get(lnum).copied().unwrap_or(false) {
+            ubi.write_peb(out, peb, image_seq, None, &[])?;
+        }
+    }
+    let tail = size - pebs * ubi.peb_size;
+    out.seek(SeekFrom::Start(pebs * ubi.peb_size))?;
+    out.write_all(&vec![0xff; tail as usize])?;
+
+    Ok(())
+}
+
+impl crate::FsWriter for Options {
+    fn estimate_size(&self, tree: &Tree) -> anyhow::Result<u64> {
+        estimate_size(tree, self)
+    }
+
+    fn write(
+        &self,
+        mut out: &mut dyn fatfs::ReadWriteSeek,
+        size: u64,
+        tree: &Tree,
+        ctx: &crate::BuildContext,
+        on_file: &mut dyn FnMut(&Path, u64),
+    ) -> anyhow::Result<()> {
+        write(&mut out, size, tree, self, ctx, on_file)
+    }
+}
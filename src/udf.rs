@@ -0,0 +1,738 @@
+//! UDF 2.01 images, for removable media too large for FAT.
+//!
+//! The volume recognition sequence and the anchors at block 256 and the last block lead to the
+//! volume descriptors and a single partition, in which the file set descriptor is followed by
+//! the unallocated space bitmap, every file entry and directory, and then file data. Contents
+//! that fit in the block of their file entry are stored in it. Names are OSTA compressed Unicode,
+//! 8 bits per character unless they need 16.
+
+use crate::tree::{Kind, Tree};
+use chrono::{DateTime, Datelike, TimeZone, Timelike, Utc};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+/// UDF revision, as BCD
+const REVISION: u16 = 0x0201;
+/// Version of descriptors recorded with NSR03
+const DESCRIPTOR_VERSION: u16 = 3;
+
+/// Volume recognition sequence, after the 32K system area
+const VRS_OFFSET: u64 = 32 << 10;
+/// Block of the first anchor, the other is in the last block
+const ANCHOR: u32 = 256;
+/// Length of each volume descriptor sequence, in blocks
+const VDS_BLOCKS: u32 = 16;
+const MAIN_VDS: u32 = ANCHOR + 1;
+const RESERVE_VDS: u32 = MAIN_VDS + VDS_BLOCKS;
+/// Logical volume integrity descriptor and its terminator
+const INTEGRITY: u32 = RESERVE_VDS + VDS_BLOCKS;
+const PARTITION_START: u32 = INTEGRITY + 2;
+
+const TAG_PVD: u16 = 1;
+const TAG_AVDP: u16 = 2;
+const TAG_IUVD: u16 = 4;
+const TAG_PD: u16 = 5;
+const TAG_LVD: u16 = 6;
+const TAG_USD: u16 = 7;
+const TAG_TD: u16 = 8;
+const TAG_LVID: u16 = 9;
+const TAG_FSD: u16 = 256;
+const TAG_FID: u16 = 257;
+const TAG_FE: u16 = 261;
+const TAG_SBD: u16 = 264;
+
+const FILE_TYPE_DIR: u8 = 4;
+const FILE_TYPE_FILE: u8 = 5;
+const FILE_TYPE_SYMLINK: u8 = 12;
+
+const FID_DIRECTORY: u8 = 0x2;
+const FID_PARENT: u8 = 0x8;
+
+/// Allocation descriptors are short_ad, or the data is in the file entry
+const ICB_SHORT_AD: u16 = 0;
+const ICB_IN_ICB: u16 = 3;
+
+const ACCESS_OVERWRITABLE: u32 = 4;
+
+/// Fixed part of a file entry, before its allocation descriptors
+const FE_SIZE: u64 = 176;
+const SHORT_AD_SIZE: u64 = 8;
+/// Unique IDs below this are reserved, the root has 0
+const FIRST_UNIQUE_ID: u64 = 16;
+
+#[derive(Clone, Debug)]
+pub struct Options {
+    pub label: Option<String>,
+    /// Logical block size, which should match the sector size of the medium
+    pub block_size: u32,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            label: None,
+            block_size: 512,
+        }
+    }
+}
+
+/// CRC-ITU-T of descriptors: polynomial 0x1021, starting at 0.
+fn crc16(data: &[u8]) -> u16 {
+    let mut crc = 0u16;
+    for &b in data {
+        crc ^= (b as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                crc << 1 ^ 0x1021
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+/// Fill in the descriptor tag at the start of `d`, with a CRC of the rest of it.
+fn tag(d: &mut [u8], id: u16, location: u32) {
+    d[0..2].copy_from_slice(&id.to_le_bytes());
+    d[2..4].copy_from_slice(&DESCRIPTOR_VERSION.to_le_bytes());
+    d[6..8].copy_from_slice(&1u16.to_le_bytes());
+    let crc = crc16(&d[16..]);
+    let len = (d.len() - 16) as u16;
+    d[8..10].copy_from_slice(&crc.to_le_bytes());
+    d[10..12].copy_from_slice(&len.to_le_bytes());
+    d[12..16].copy_from_slice(&location.to_le_bytes());
+    d[4] = d[..16]
+        .iter()
+        .enumerate()
+        .filter(|&(i, _)| i != 4)
+        .fold(0u8, |sum, (_, &b)| sum.wrapping_add(b));
+}
+
+/// OSTA compressed Unicode of `s`: 8 bits per character if they all fit, 16 otherwise.
+fn cs0(s: &str) -> Option<Vec<u8>> {
+    if s.chars().all(|c| (c as u32) < 0x100) {
+        Some([8].into_iter().chain(s.chars().map(|c| c as u8)).collect())
+    } else {
+        let mut out = vec![16];
+        for c in s.chars() {
+            let mut buf = [0u16; 2];
+            match c.encode_utf16(&mut buf) {
+                [u] => out.extend(u.to_be_bytes()),
+                _ => return None,
+            }
+        }
+        Some(out)
+    }
+}
+
+/// Fixed length field holding a CS0 string, with its length in the last byte. Characters that do
+/// not fit are dropped.
+fn dstring(field: &mut [u8], s: &str) {
+    let mut chars = s.chars().collect::<Vec<_>>();
+    let value = loop {
+        match cs0(&chars.iter().collect::<String>()) {
+            Some(v) if v.len() < field.len() => break v,
+            Some(_) => {
+                chars.pop();
+            }
+            None => chars.retain(|c| c.len_utf16() == 1),
+        }
+    };
+    field.fill(0);
+    if value.len() > 1 {
+        field[..value.len()].copy_from_slice(&value);
+        *field.last_mut().unwrap() = value.len() as u8;
+    }
+}
+
+/// Character set of CS0 strings.
+fn charspec(field: &mut [u8]) {
+    field[0] = 0;
+    field[1..24].copy_from_slice(b"OSTA Compressed Unicode");
+}
+
+/// Entity identifier, with the UDF revision at the start of the suffix.
+fn regid(field: &mut [u8], id: &[u8], udf_suffix: bool) {
+    field[1..1 + id.len()].copy_from_slice(id);
+    if udf_suffix {
+        field[24..26].copy_from_slice(&REVISION.to_le_bytes());
+    }
+}
+
+fn implementation_id(field: &mut [u8]) {
+    regid(field, b"*mkimg", false);
+}
+
+fn domain_id(field: &mut [u8]) {
+    regid(field, b"*OSTA UDF Compliant", true);
+}
+
+fn timestamp(field: &mut [u8], t: &DateTime<Utc>) {
+    // Local time, at an offset of 0 minutes from UTC
+    field[0..2].copy_from_slice(&(1u16 << 12).to_le_bytes());
+    field[2..4].copy_from_slice(&(t.year().clamp(1, 9999) as u16).to_le_bytes());
+    field[4] = t.month() as u8;
+    field[5] = t.day() as u8;
+    field[6] = t.hour() as u8;
+    field[7] = t.minute() as u8;
+    field[8] = t.second() as u8;
+    field[9..12].fill(0);
+}
+
+fn unix_timestamp(field: &mut [u8], secs: i64) {
+    timestamp(
+        field,
+        &Utc.timestamp_opt(secs, 0).single().unwrap_or_default(),
+    );
+}
+
+fn extent_ad(field: &mut [u8], len: u64, location: u32) {
+    field[0..4].copy_from_slice(&(len as u32).to_le_bytes());
+    field[4..8].copy_from_slice(&location.to_le_bytes());
+}
+
+/// long_ad of an extent in the partition, with the unique ID FIDs keep of the file entry.
+fn long_ad(field: &mut [u8], len: u64, block: u32, unique_id: u64) {
+    field[0..4].copy_from_slice(&(len as u32).to_le_bytes());
+    field[4..8].copy_from_slice(&block.to_le_bytes());
+    field[8..10].fill(0);
+    field[10..12].fill(0);
+    field[12..16].copy_from_slice(&(unique_id as u32).to_le_bytes());
+}
+
+/// Permission bits of a mode, without the change attribute and delete bits.
+fn permissions(mode: u32) -> u32 {
+    (mode & 0o7) | (mode & 0o70) << 2 | (mode & 0o700) << 4
+}
+
+fn fid_len(name_len: usize) -> u64 {
+    (38 + name_len).next_multiple_of(4) as u64
+}
+
+/// Path components of a symbolic link to `target`.
+fn symlink_data(target: &str) -> Option<Vec<u8>> {
+    let mut out = vec![];
+    if target.starts_with('/') {
+        out.extend([2, 0, 0, 0]);
+    }
+    for part in target.split('/').filter(|p| !p.is_empty()) {
+        let (ty, id) = match part {
+            ".." => (3, vec![]),
+            "." => (4, vec![]),
+            name => (5, cs0(name)?),
+        };
+        if id.len() > 255 {
+            return None;
+        }
+        out.extend([ty, id.len() as u8, 0, 0]);
+        out.extend(id);
+    }
+    Some(out)
+}
+
+/// Blocks of the unallocated space bitmap of a partition of `len` blocks.
+fn bitmap_blocks(len: u64, block_size: u64) -> u64 {
+    (24 + len.div_ceil(8)).div_ceil(block_size)
+}
+
+/// Placement of the file entries and data in the partition, in blocks relative to its start.
+struct Layout {
+    /// Name of every node in its parent directory, in CS0
+    names: Vec<Vec<u8>>,
+    /// Contents of every node, for directories and symbolic links
+    data: Vec<Vec<u8>>,
+    /// Length of the contents of every node
+    len: Vec<u64>,
+    /// Block of every file entry
+    entry: Vec<u32>,
+    /// First data block of every node with contents outside its file entry
+    extent: Vec<u32>,
+    unique_id: Vec<u64>,
+    bitmap: u64,
+    /// Blocks used in the partition, from its start
+    used: u64,
+}
+
+impl Layout {
+    /// Lay out the tree in a partition of `partition` blocks.
+    fn new(tree: &Tree, opts: &Options, partition: u64) -> anyhow::Result<Self> {
+        if !matches!(opts.block_size, 512 | 1024 | 2048 | 4096) {
+            anyhow::bail!("UDF blocks are 512, 1024, 2048 or 4096 bytes");
+        }
+
+        let block_size = opts.block_size as u64;
+        let inline_max = block_size - FE_SIZE;
+        // Longest extent a short_ad describes, in whole blocks
+        let extent_max = ((1 << 30) - 1) / block_size * block_size;
+        let extents_max = inline_max / SHORT_AD_SIZE;
+
+        let mut names = vec![vec![]; tree.nodes.len()];
+        for (idx, node) in tree.nodes.iter().enumerate().skip(1) {
+            names[idx] = match cs0(&node.name) {
+                Some(name) if name.len() <= 255 => name,
+                Some(_) => anyhow::bail!(
+                    "UDF names are limited to 254 bytes, or 127 characters outside Latin-1: {}",
+                    node.path.display()
+                ),
+                None => anyhow::bail!(
+                    "{} has a character outside the Basic Multilingual Plane, which UDF can not store",
+                    node.path.display()
+                ),
+            };
+        }
+
+        let mut data = vec![vec![]; tree.nodes.len()];
+        let mut len = vec![0; tree.nodes.len()];
+        for (idx, node) in tree.nodes.iter().enumerate() {
+            match &node.kind {
+                Kind::Dir(children) => {
+                    len[idx] = fid_len(0)
+                        + children
+                            .iter()
+                            .map(|&c| fid_len(names[c].len()))
+                            .sum::<u64>();
+                }
+                Kind::File { len: l, .. } => {
+                    len[idx] = *l;
+                    if l.div_ceil(extent_max) > extents_max {
+                        anyhow::bail!(
+                            "{} is too large for UDF with {block_size} byte blocks",
+                            node.path.display()
+                        );
+                    }
+                }
+                Kind::Symlink(target) => {
+                    data[idx] = symlink_data(target).ok_or_else(|| {
+                        anyhow::anyhow!("{} links to a name UDF can not store", node.path.display())
+                    })?;
+                    len[idx] = data[idx].len() as u64;
+                }
+            }
+        }
+
+        // File set descriptor and its terminator, then the bitmap
+        let bitmap = 2;
+        let mut next = bitmap + bitmap_blocks(partition, block_size);
+        let mut alloc = |blocks: u64| {
+            let start = next;
+            next += blocks;
+            start as u32
+        };
+
+        // File entries, each directory followed by its contents if they do not fit in it
+        let mut entry = vec![0; tree.nodes.len()];
+        let mut extent = vec![0; tree.nodes.len()];
+        for (idx, node) in tree.nodes.iter().enumerate() {
+            entry[idx] = alloc(1);
+            if !node.is_file() && len[idx] > inline_max {
+                extent[idx] = alloc(len[idx].div_ceil(block_size));
+            }
+        }
+        for (idx, node) in tree.nodes.iter().enumerate() {
+            if node.is_file() && len[idx] > inline_max {
+                extent[idx] = alloc(len[idx].div_ceil(block_size));
+            }
+        }
+
+        let unique_id = (0..tree.nodes.len() as u64)
+            .map(|i| if i == 0 { 0 } else { FIRST_UNIQUE_ID + i - 1 })
+            .collect();
+
+        Ok(Self {
+            names,
+            data,
+            len,
+            entry,
+            extent,
+            unique_id,
+            bitmap,
+            used: next,
+        })
+    }
+
+    /// Contents of a directory: the parent entry and one for every child.
+    fn dir_data(&self, tree: &Tree, dir: usize, opts: &Options, start: u32) -> Vec<u8> {
+        let block_size = opts.block_size as usize;
+        let node = &tree.nodes[dir];
+        let Kind::Dir(children) = &node.kind else {
+            unreachable!("only directories have entries")
+        };
+
+        let mut out = vec![];
+        for (c, name, flags) in [(node.parent, &[][..], FID_DIRECTORY | FID_PARENT)]
+            .into_iter()
+            .chain(children.iter().map(|&c| {
+                let flags = if tree.nodes[c].is_dir() {
+                    FID_DIRECTORY
+                } else {
+                    0
+                };
+                (c, &self.names[c][..], flags)
+            }))
+        {
+            let mut fid = vec![0u8; fid_len(name.len()) as usize];
+            fid[16..18].copy_from_slice(&1u16.to_le_bytes());
+            fid[18] = flags;
+            fid[19] = name.len() as u8;
+            long_ad(
+                &mut fid[20..36],
+                opts.block_size as u64,
+                self.entry[c],
+                self.unique_id[c],
+            );
+            fid[38..38 + name.len()].copy_from_slice(name);
+            // Located at the block it starts in
+            tag(&mut fid, TAG_FID, start + (out.len() / block_size) as u32);
+            out.extend(fid);
+        }
+        out
+    }
+}
+
+/// Blocks of a partition holding the tree, including the space bitmap for it.
+fn partition_blocks(tree: &Tree, opts: &Options) -> anyhow::Result<u64> {
+    let mut partition = 0;
+    loop {
+        let used = Layout::new(tree, opts, partition)?.used;
+        if used <= partition {
+            return Ok(partition);
+        }
+        partition = used;
+    }
+}
+
+/// Smallest filesystem size the tree fits in.
+pub fn estimate_size(tree: &Tree, opts: &Options) -> anyhow::Result<u64> {
+    let blocks = PARTITION_START as u64 + partition_blocks(tree, opts)? + 1;
+    Ok(blocks * opts.block_size as u64)
+}
+
+fn write_block<W: Write + Seek>(
+    out: &mut W,
+    block_size: u64,
+    block: u64,
+    d: &[u8],
+) -> io::Result<()> {
+    out.seek(SeekFrom::Start(block * block_size))?;
+    out.write_all(d)
+}
+
+/// Write the image into `out`, which spans `size` bytes.
+///
+/// `on_file` is called with the image path and length of every file written.
+pub fn write<W: Write + Seek>(
+    out: &mut W,
+    size: u64,
+    tree: &Tree,
+    opts: &Options,
+    ctx: &crate::BuildContext,
+    on_file: &mut dyn FnMut(&Path, u64),
+) -> anyhow::Result<()> {
+    let block_size = opts.block_size as u64;
+    let blocks = (size / block_size).min(u32::MAX as u64);
+    // The partition spans everything between the integrity sequence and the last anchor
+    let partition = blocks.saturating_sub(PARTITION_START as u64 + 1);
+    let layout = Layout::new(tree, opts, partition)?;
+
+    if layout.used > partition {
+        anyhow::bail!(
+            "UDF image needs {} bytes, but only {size} are available",
+            (PARTITION_START as u64 + layout.used + 1) * block_size
+        );
+    }
+
+    let label = opts.label.as_deref().unwrap_or("UDF Volume");
+    let inline_max = block_size - FE_SIZE;
+    let extent_max = ((1 << 30) - 1) / block_size * block_size;
+    let last = blocks as u32 - 1;
+    let part = |block: u32| (PARTITION_START + block) as u64;
+
+    let mut serial = [0u8; 8];
+    ctx.random.fill(&mut serial)?;
+    let volume_set = serial
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect::<String>();
+
+    // Volume recognition sequence, each descriptor in 2K or a block if those are larger
+    let stride = block_size.max(2048);
+    for (i, id) in [b"BEA01", b"NSR03", b"TEA01"].into_iter().enumerate() {
+        let mut d = vec![0u8; 2048];
+        d[1..6].copy_from_slice(id);
+        d[6] = 1;
+        out.seek(SeekFrom::Start(VRS_OFFSET + i as u64 * stride))?;
+        out.write_all(&d)?;
+    }
+
+    // Anchors
+    for location in [ANCHOR, last] {
+        let mut d = vec![0u8; 512];
+        extent_ad(&mut d[16..24], VDS_BLOCKS as u64 * block_size, MAIN_VDS);
+        extent_ad(&mut d[24..32], VDS_BLOCKS as u64 * block_size, RESERVE_VDS);
+        tag(&mut d, TAG_AVDP, location);
+        write_block(out, block_size, location as u64, &d)?;
+    }
+
+    // Main and reserve volume descriptor sequences, the same but for their locations
+    for start in [MAIN_VDS, RESERVE_VDS] {
+        let mut seq = 0;
+        let mut next = || {
+            seq += 1;
+            (seq - 1, start + seq - 1)
+        };
+
+        let (n, at) = next();
+        let mut d = vec![0u8; 512];
+        d[16..20].copy_from_slice(&n.to_le_bytes());
+        dstring(&mut d[24..56], label);
+        d[56..58].copy_from_slice(&1u16.to_le_bytes());
+        d[58..60].copy_from_slice(&1u16.to_le_bytes());
+        d[60..62].copy_from_slice(&2u16.to_le_bytes());
+        d[62..64].copy_from_slice(&3u16.to_le_bytes());
+        d[64..68].copy_from_slice(&1u32.to_le_bytes());
+        d[68..72].copy_from_slice(&1u32.to_le_bytes());
+        dstring(&mut d[72..200], &format!("{volume_set}{label}"));
+        charspec(&mut d[200..264]);
+        charspec(&mut d[264..328]);
+        timestamp(&mut d[376..388], &ctx.time);
+        implementation_id(&mut d[388..420]);
+        tag(&mut d, TAG_PVD, at);
+        write_block(out, block_size, at as u64, &d)?;
+
+        let (n, at) = next();
+        let mut d = vec![0u8; 512];
+        d[16..20].copy_from_slice(&n.to_le_bytes());
+        regid(&mut d[20..52], b"*UDF LV Info", true);
+        charspec(&mut d[52..116]);
+        dstring(&mut d[116..244], label);
+        implementation_id(&mut d[352..384]);
+        tag(&mut d, TAG_IUVD, at);
+        write_block(out, block_size, at as u64, &d)?;
+
+        let (n, at) = next();
+        let mut d = vec![0u8; 512];
+        d[16..20].copy_from_slice(&n.to_le_bytes());
+        d[20..22].copy_from_slice(&1u16.to_le_bytes());
+        regid(&mut d[24..56], b"+NSR03", false);
+        // Partition header, with only the unallocated space bitmap
+        let bitmap_len = bitmap_blocks(partition, block_size) * block_size;
+        d[64..68].copy_from_slice(&(bitmap_len as u32).to_le_bytes());
+        d[68..72].copy_from_slice(&(layout.bitmap as u32).to_le_bytes());
+        d[184..188].copy_from_slice(&ACCESS_OVERWRITABLE.to_le_bytes());
+        d[188..192].copy_from_slice(&PARTITION_START.to_le_bytes());
+        d[192..196].copy_from_slice(&(partition as u32).to_le_bytes());
+        implementation_id(&mut d[196..228]);
+        tag(&mut d, TAG_PD, at);
+        write_block(out, block_size, at as u64, &d)?;
+
+        let (n, at) = next();
+        let mut d = vec![0u8; 446];
+        d[16..20].copy_from_slice(&n.to_le_bytes());
+        charspec(&mut d[20..84]);
+        dstring(&mut d[84..212], label);
+        d[212..216].copy_from_slice(&opts.block_size.to_le_bytes());
+        domain_id(&mut d[216..248]);
+        // File set descriptor
+        long_ad(&mut d[248..264], block_size, 0, 0);
+        d[264..268].copy_from_slice(&6u32.to_le_bytes());
+        d[268..272].copy_from_slice(&1u32.to_le_bytes());
+        implementation_id(&mut d[272..304]);
+        extent_ad(&mut d[432..440], 2 * block_size, INTEGRITY);
+        // Type 1 map of partition 0 on volume 1
+        d[440..446].copy_from_slice(&[1, 6, 1, 0, 0, 0]);
+        tag(&mut d, TAG_LVD, at);
+        write_block(out, block_size, at as u64, &d)?;
+
+        let (n, at) = next();
+        let mut d = vec![0u8; 24];
+        d[16..20].copy_from_slice(&n.to_le_bytes());
+        tag(&mut d, TAG_USD, at);
+        write_block(out, block_size, at as u64, &d)?;
+
+        let (_, at) = next();
+        let mut d = vec![0u8; 512];
+        tag(&mut d, TAG_TD, at);
+        write_block(out, block_size, at as u64, &d)?;
+    }
+
+    // Logical volume integrity, closed
+    let files = tree.nodes.iter().filter(|n| !n.is_dir()).count() as u32;
+    let dirs = tree.nodes.iter().filter(|n| n.is_dir()).count() as u32;
+    let mut d = vec![0u8; 134];
+    timestamp(&mut d[16..28], &ctx.time);
+    d[28..32].copy_from_slice(&1u32.to_le_bytes());
+    let next_unique_id = FIRST_UNIQUE_ID + tree.nodes.len() as u64;
+    d[40..48].copy_from_slice(&next_unique_id.to_le_bytes());
+    d[72..76].copy_from_slice(&1u32.to_le_bytes());
+    d[76..80].copy_from_slice(&46u32.to_le_bytes());
+    d[80..84].copy_from_slice(&((partition - layout.used) as u32).to_le_bytes());
+    d[84..88].copy_from_slice(&(partition as u32).to_le_bytes());
+    implementation_id(&mut d[88..120]);
+    d[120..124].copy_from_slice(&files.to_le_bytes());
+    d[124..128].copy_from_slice(&dirs.to_le_bytes());
+    for (i, revision) in [REVISION; 3].into_iter().enumerate() {
+        d[128 + i * 2..130 + i * 2].copy_from_slice(&revision.to_le_bytes());
+    }
+    tag(&mut d, TAG_LVID, INTEGRITY);
+    write_block(out, block_size, INTEGRITY as u64, &d)?;
+
+    let mut d = vec![0u8; 512];
+    tag(&mut d, TAG_TD, INTEGRITY + 1);
+    write_block(out, block_size, INTEGRITY as u64 + 1, &d)?;
+
+    // File set descriptor and its terminator
+    let mut d = vec![0u8; 512];
+    timestamp(&mut d[16..28], &ctx.time);
+    d[28..30].copy_from_slice(&3u16.to_le_bytes());
+    d[30..32].copy_from_slice(&3u16.to_le_bytes());
+    d[32..36].copy_from_slice(&1u32.to_le_bytes());
+    d[36..40].copy_from_slice(&1u32.to_le_bytes());
+    charspec(&mut d[48..112]);
+    dstring(&mut d[112..240], label);
+    charspec(&mut d[240..304]);
+    dstring(&mut d[304..336], label);
+    long_ad(&mut d[400..416], block_size, layout.entry[0], 0);
+    domain_id(&mut d[416..448]);
+    tag(&mut d, TAG_FSD, 0);
+    write_block(out, block_size, part(0), &d)?;
+
+    let mut d = vec![0u8; 512];
+    tag(&mut d, TAG_TD, 1);
+    write_block(out, block_size, part(1), &d)?;
+
+    // Space bitmap, where set bits are free blocks
+    let mut used = vec![false; partition as usize];
+    used[..layout.used as usize].fill(true);
+
+    let mut d = vec![0u8; (24 + partition.div_ceil(8)) as usize];
+    d[16..20].copy_from_slice(&(partition as u32).to_le_bytes());
+    d[20..24].copy_from_slice(&(partition.div_ceil(8) as u32).to_le_bytes());
+    for (i, &u) in used.iter().enumerate() {
+        if !u {
+            d[24 + i / 8] |= 1 << (i % 8);
+        }
+    }
+    // Only the fixed part is covered by the CRC
+    let mut head = d[..24].to_vec();
+    tag(&mut head, TAG_SBD, layout.bitmap as u32);
+    d[..24].copy_from_slice(&head);
+    write_block(out, block_size, part(layout.bitmap as u32), &d)?;
+
+    // File entries, with directory contents and symbolic links
+    for (idx, node) in tree.nodes.iter().enumerate() {
+        let len = layout.len[idx];
+        let (file_type, link_count, data) = match &node.kind {
+            Kind::Dir(children) => {
+                let start = match len > inline_max {
+                    true => layout.extent[idx],
+                    false => layout.entry[idx],
+                };
+                let subdirs = children.iter().filter(|&&c| tree.nodes[c].is_dir()).count();
+                (
+                    FILE_TYPE_DIR,
+                    1 + subdirs as u16,
+                    Some(layout.dir_data(tree, idx, opts, start)),
+                )
+            }
+            Kind::File { .. } => (FILE_TYPE_FILE, 1, None),
+            Kind::Symlink(_) => (FILE_TYPE_SYMLINK, 1, Some(layout.data[idx].clone())),
+        };
+
+        let inline = len <= inline_max;
+        let mut ads = vec![];
+        if !inline {
+            let mut remaining = len;
+            let mut block = layout.extent[idx];
+            while remaining > 0 {
+                let n = remaining.min(extent_max);
+                ads.extend((n as u32).to_le_bytes());
+                ads.extend(block.to_le_bytes());
+                block += (n / block_size) as u32;
+                remaining -= n;
+            }
+        }
+
+        let mut d = vec![0u8; FE_SIZE as usize];
+        // ICB tag: strategy 4, one entry
+        d[20..22].copy_from_slice(&4u16.to_le_bytes());
+        d[24..26].copy_from_slice(&1u16.to_le_bytes());
+        d[27] = file_type;
+        let flags = if inline { ICB_IN_ICB } else { ICB_SHORT_AD };
+        d[34..36].copy_from_slice(&flags.to_le_bytes());
+        d[36..40].copy_from_slice(&node.uid.to_le_bytes());
+        d[40..44].copy_from_slice(&node.gid.to_le_bytes());
+        d[44..48].copy_from_slice(&permissions(node.mode).to_le_bytes());
+        d[48..50].copy_from_slice(&link_count.to_le_bytes());
+        d[56..64].copy_from_slice(&len.to_le_bytes());
+        let recorded = if inline { 0 } else { len.div_ceil(block_size) };
+        d[64..72].copy_from_slice(&recorded.to_le_bytes());
+        for t in [72, 84, 96] {
+            unix_timestamp(&mut d[t..t + 12], node.mtime);
+        }
+        d[108..112].copy_from_slice(&1u32.to_le_bytes());
+        implementation_id(&mut d[128..160]);
+        d[160..168].copy_from_slice(&layout.unique_id[idx].to_le_bytes());
+
+        match (inline, &data) {
+            (true, Some(data)) => {
+                d[172..176].copy_from_slice(&(data.len() as u32).to_le_bytes());
+                d.extend(data);
+            }
+            (true, None) => {
+                let Kind::File { source, len } = &node.kind else {
+                    unreachable!()
+                };
+                let start = d.len();
+                d.resize(start + *len as usize, 0);
+                source.open()?.read_exact(&mut d[start..]).map_err(|e| {
+                    anyhow::anyhow!("{} changed while being copied: {e}", node.path.display())
+                })?;
+                d[172..176].copy_from_slice(&(*len as u32).to_le_bytes());
+            }
+            (false, _) => {
+                d[172..176].copy_from_slice(&(ads.len() as u32).to_le_bytes());
+                d.extend(&ads);
+            }
+        }
+        tag(&mut d, TAG_FE, layout.entry[idx]);
+        write_block(out, block_size, part(layout.entry[idx]), &d)?;
+
+        match (inline, &node.kind, data) {
+            (false, _, Some(data)) => {
+                write_block(out, block_size, part(layout.extent[idx]), &data)?;
+            }
+            (false, Kind::File { source, len }, None) => {
+                out.seek(SeekFrom::Start(part(layout.extent[idx]) * block_size))?;
+                let copied = io::copy(&mut source.open()?.take(*len), out)?;
+                if copied != *len {
+                    anyhow::bail!("{} changed while being copied", node.path.display());
+                }
+            }
+            _ => {}
+        }
+
+        if let Kind::File { len, .. } = node.kind {
+            on_file(&node.path, len);
+        }
+    }
+
+    Ok(())
+}
+
+impl crate::FsWriter for Options {
+    fn estimate_size(&self, tree: &Tree) -> anyhow::Result<u64> {
+        estimate_size(tree, self)
+    }
+
+    fn write(
+        &self,
+        mut out: &mut dyn fatfs::ReadWriteSeek,
+        size: u64,
+        tree: &Tree,
+        ctx: &crate::BuildContext,
+        on_file: &mut dyn FnMut(&Path, u64),
+    ) -> anyhow::Result<()> {
+        write(&mut out, size, tree, self, ctx, on_file)
+    }
+}
@@ -0,0 +1,320 @@
+//! Checks of the arguments against each other and the partitions they describe, before anything
+//! is written.
+
+use crate::{
+    bios_boot_partition, boot_partition, compress, esp_partition, ext2, fat, layout, output, Args,
+    Bootloader, Filesystem, PartitionTable,
+};
+use clap::ValueEnum;
+
+/// Check that the arguments apply to each other and to `parts`, and mark the partitions the
+/// bootloader and --preset install into as bootable.
+pub fn check(args: &Args, parts: &mut [layout::Partition]) -> anyhow::Result<()> {
+    let in_place = args.in_place();
+
+    if in_place && (args.output_format.needs_conversion() || args.compress.is_some()) {
+        anyhow::bail!(
+            "--no-filesystem and --into-partition write the output in place and require uncompressed raw output"
+        );
+    }
+
+    if in_place && args.split.is_some() {
+        anyhow::bail!(
+            "--no-filesystem and --into-partition write the output in place and cannot split it"
+        );
+    }
+
+    let last_is_vfat = matches!(
+        parts.last().and_then(|p| p.filesystem),
+        Some(Filesystem::Vfat)
+    );
+    if args.minimize.is_some() && !last_is_vfat {
+        anyhow::bail!("--minimize only shrinks a vfat filesystem in the last partition");
+    }
+
+    if args.split == Some(0) {
+        anyhow::bail!("--split needs a size of at least one byte");
+    }
+
+    if args.bootloader.is_some() && args.sector_size != 512 {
+        anyhow::bail!("--bootloader requires 512 byte sectors");
+    }
+
+    match args.bootloader {
+        Some(Bootloader::Syslinux) => {
+            let target = boot_partition(parts).ok_or_else(|| {
+                anyhow::anyhow!("--bootloader syslinux requires a vfat partition to install into")
+            })?;
+            parts[target].bootable = true;
+        }
+        Some(Bootloader::GrubBios) => {
+            if args.mbr_bootcode.is_some() {
+                anyhow::bail!("--bootloader grub-bios writes its own MBR boot code");
+            }
+            match args.partition_table {
+                PartitionTable::None => {
+                    anyhow::bail!("--bootloader grub-bios requires a partition table")
+                }
+                PartitionTable::Gpt | PartitionTable::Hybrid
+                    if bios_boot_partition(parts).is_none() =>
+                {
+                    anyhow::bail!("--bootloader grub-bios requires a `bios-boot` partition on GPT")
+                }
+                _ => {}
+            }
+        }
+        None => {}
+    }
+
+    if args.preset.is_some() {
+        let target = esp_partition(parts)
+            .ok_or_else(|| anyhow::anyhow!("--preset requires a vfat partition for the ESP"))?;
+        parts[target].gpt_type = "esp".parse().unwrap();
+        parts[target].bootable = true;
+    }
+
+    let bootable = parts.iter().filter(|p| p.bootable).collect::<Vec<_>>();
+    if bootable.len() > 1
+        && matches!(
+            args.partition_table,
+            PartitionTable::Mbr | PartitionTable::Hybrid
+        )
+    {
+        let names = bootable
+            .iter()
+            .map(|p| format!("`{}`", p.name))
+            .collect::<Vec<_>>();
+        anyhow::bail!(
+            "only one MBR partition can be active, but {} are bootable",
+            names.join(", ")
+        );
+    }
+
+    let has_filesystem =
+        |f: fn(&Filesystem) -> bool| parts.iter().any(|p| p.filesystem.as_ref().is_some_and(f));
+
+    let is_gpt = matches!(
+        args.partition_table,
+        PartitionTable::Gpt | PartitionTable::Hybrid
+    );
+
+    if !is_gpt && parts.iter().any(|p| p.uuid.is_some()) {
+        anyhow::bail!("partition GUIDs only apply to GPT partition tables");
+    }
+
+    if !is_gpt && parts.iter().any(|p| p.attributes != 0) {
+        anyhow::bail!("partition attributes only apply to GPT partition tables");
+    }
+
+    if !args.btrfs_subvolume.is_empty() && !has_filesystem(|f| matches!(f, Filesystem::Btrfs)) {
+        anyhow::bail!("--btrfs-subvolume only applies to btrfs images");
+    }
+
+    if let Some(path) = &args.btrfs_default_subvolume {
+        if !args.btrfs_subvolume.iter().any(|s| &s.path == path) {
+            anyhow::bail!(
+                "--btrfs-default-subvolume `{}` is not one of --btrfs-subvolume",
+                path.display()
+            );
+        }
+    }
+
+    for (i, part) in parts.iter().enumerate() {
+        part.check_subvolumes()
+            .map_err(|e| anyhow::anyhow!("partition `{}`: {e}", part.name))?;
+        if part.uuid.is_some() && parts[..i].iter().any(|p| p.uuid == part.uuid) {
+            anyhow::bail!("partition `{}` reuses the GUID of another one", part.name);
+        }
+    }
+
+    if args.align == 0 || !args.align.is_multiple_of(args.sector_size) {
+        anyhow::bail!(
+            "--align must be a multiple of the {} byte sector size",
+            args.sector_size
+        );
+    }
+
+    if args
+        .first_partition_offset
+        .is_some_and(|offset| offset % args.sector_size != 0)
+    {
+        anyhow::bail!(
+            "--first-partition-offset must be a multiple of the {} byte sector size",
+            args.sector_size
+        );
+    }
+
+    if args.isohybrid
+        && (!matches!(args.partition_table, PartitionTable::None)
+            || parts.len() > 1
+            || !matches!(parts[0].filesystem, Some(Filesystem::Iso9660)))
+    {
+        anyhow::bail!("--isohybrid only applies to iso9660 images without a partition table");
+    }
+
+    if args.mbr_bootcode.is_some()
+        && matches!(args.partition_table, PartitionTable::None)
+        && !args.isohybrid
+    {
+        anyhow::bail!("--mbr-bootcode requires a partition table");
+    }
+
+    if args.fat_type != fat::FatType::Auto && args.sector_size != 512 {
+        anyhow::bail!("--fat-type requires 512 byte sectors");
+    }
+
+    if args.fat_type != fat::FatType::Auto && !has_filesystem(|f| matches!(f, Filesystem::Vfat)) {
+        anyhow::bail!("--fat-type only applies to vfat images");
+    }
+
+    if args.compression.is_some()
+        && args.output_format != output::OutputFormat::Qcow2
+        && !has_filesystem(|f| matches!(f, Filesystem::Squashfs) || f.is_archive())
+    {
+        anyhow::bail!("--compression only applies to squashfs images, archives and qcow2 output");
+    }
+
+    if args.compression_level.is_some()
+        && args.compression.is_none()
+        && !has_filesystem(|f| matches!(f, Filesystem::Squashfs))
+    {
+        anyhow::bail!("--compression-level needs --compression or a squashfs image");
+    }
+
+    if let Some(compression) = args.compression {
+        compression.check()?;
+    }
+
+    let compression = args.compression.unwrap_or(compress::Compression::Gzip);
+    if args
+        .compression_level
+        .is_some_and(|level| level > compression.max_level())
+    {
+        anyhow::bail!(
+            "--compression-level of {} goes up to {}",
+            compression.to_possible_value().unwrap().get_name(),
+            compression.max_level()
+        );
+    }
+
+    if let Some(compress) = &args.compress {
+        compress.check(args.compress_threads)?;
+    }
+
+    if args.squashfs_block_size.is_some() && !has_filesystem(|f| matches!(f, Filesystem::Squashfs))
+    {
+        anyhow::bail!("--squashfs-block-size only applies to squashfs images");
+    }
+
+    if (args.littlefs_block_size.is_some() || args.littlefs_prog_size.is_some())
+        && !has_filesystem(|f| matches!(f, Filesystem::Littlefs))
+    {
+        anyhow::bail!(
+            "--littlefs-block-size and --littlefs-prog-size only apply to littlefs images"
+        );
+    }
+
+    if (args.ubi_peb_size.is_some()
+        || args.ubi_min_io_size.is_some()
+        || args.ubi_sub_page_size.is_some()
+        || args.ubi_volume_id.is_some())
+        && !has_filesystem(|f| matches!(f, Filesystem::Ubifs))
+    {
+        anyhow::bail!(
+            "--ubi-peb-size, --ubi-min-io-size, --ubi-sub-page-size and --ubi-volume-id only \
+             apply to UBIFS images"
+        );
+    }
+
+    if args.udf_block_size.is_some() && !has_filesystem(|f| matches!(f, Filesystem::Udf)) {
+        anyhow::bail!("--udf-block-size only applies to UDF images");
+    }
+
+    if args.hfsplus_bless.is_some() && !has_filesystem(|f| matches!(f, Filesystem::Hfsplus)) {
+        anyhow::bail!("--hfsplus-bless only applies to HFS+ images");
+    }
+
+    if has_filesystem(|f| matches!(f, Filesystem::Littlefs))
+        && !matches!(args.partition_table, PartitionTable::None)
+    {
+        anyhow::bail!("littlefs images are written without a partition table");
+    }
+
+    if has_filesystem(|f| matches!(f, Filesystem::Ubifs))
+        && !matches!(args.partition_table, PartitionTable::None)
+    {
+        anyhow::bail!("UBI images are written without a partition table");
+    }
+
+    if (!args.ext_features.is_empty()
+        || args.ext_inode_size.is_some()
+        || args.ext_bytes_per_inode.is_some())
+        && !has_filesystem(|f| matches!(f, Filesystem::Ext2 | Filesystem::Ext3))
+    {
+        anyhow::bail!(
+            "--ext-features, --ext-inode-size and --ext-bytes-per-inode only apply to ext2 and ext3 images"
+        );
+    }
+
+    let inode_size = args
+        .ext_inode_size
+        .unwrap_or(ext2::Options::default().inode_size);
+    if args
+        .ext_bytes_per_inode
+        .is_some_and(|bytes| bytes <= inode_size as u64)
+    {
+        anyhow::bail!(
+            "--ext-bytes-per-inode must be larger than the {inode_size} byte inodes, or the inode tables leave no room for data"
+        );
+    }
+
+    if let Some(subformat) = args.subformat {
+        if !args.output_format.supports(subformat) {
+            anyhow::bail!(
+                "--subformat {} does not apply to {} output",
+                subformat.to_possible_value().unwrap().get_name(),
+                args.output_format.to_possible_value().unwrap().get_name()
+            );
+        }
+    }
+
+    if args.output_format == output::OutputFormat::Qcow2
+        && args
+            .compression
+            .is_some_and(|c| c != compress::Compression::Gzip)
+    {
+        anyhow::bail!("qcow2 images can only be compressed with gzip");
+    }
+
+    if (args.eltorito_bios.is_some() || !args.eltorito_efi.is_empty())
+        && !has_filesystem(|f| matches!(f, Filesystem::Iso9660))
+    {
+        anyhow::bail!("El Torito boot images only apply to iso9660 images");
+    }
+
+    if (args.iso_volume_set.is_some()
+        || args.iso_publisher.is_some()
+        || args.iso_preparer.is_some()
+        || args.iso_application.is_some())
+        && !has_filesystem(|f| matches!(f, Filesystem::Iso9660))
+    {
+        anyhow::bail!(
+            "--iso-volume-set, --iso-publisher, --iso-preparer and --iso-application only apply to iso9660 images"
+        );
+    }
+
+    if args.filesystem.is_archive()
+        && (!matches!(args.partition_table, PartitionTable::None)
+            || args.size.is_some()
+            || args.image_size.is_some()
+            || args.no_filesystem)
+    {
+        anyhow::bail!("archives are written as they are, without a partition table or size");
+    }
+
+    if args.filesystem.is_archive() && args.into_partition.is_some() {
+        anyhow::bail!("archives cannot be written into partitions");
+    }
+
+    Ok(())
+}
@@ -0,0 +1,88 @@
+//! VDI images, for VirtualBox.
+//!
+//! A block map follows the header, pointing at the blocks in the order they are stored. Dynamic
+//! images store only the blocks that are not all zeroes.
+
+use std::io::{Read, Seek, SeekFrom, Write};
+
+const SECTOR_SIZE: u64 = 512;
+const BLOCK_SIZE: u64 = 1 << 20;
+
+const SIGNATURE: u32 = 0xbeda107f;
+const VERSION: u32 = 0x00010001;
+/// Size of the header after the version, as written by VirtualBox
+const HEADER_SIZE: u32 = 0x180;
+const BLOCK_MAP_OFFSET: u64 = 0x200;
+
+const TYPE_DYNAMIC: u32 = 1;
+const TYPE_FIXED: u32 = 2;
+
+const UNALLOCATED: u32 = u32::MAX;
+
+fn random_uuid(random: &crate::random::Random) -> std::io::Result<[u8; 16]> {
+    let mut u = [0u8; 16];
+    random.fill(&mut u)?;
+    u[7] = (u[7] & 0x0f) | 0x40;
+    u[8] = (u[8] & 0x3f) | 0x80;
+    Ok(u)
+}
+
+/// Write the raw image of `len` bytes from `input` as a fixed or dynamic VDI.
+pub fn write(
+    input: impl Read,
+    out: &mut (impl Write + Seek),
+    len: u64,
+    dynamic: bool,
+    ctx: &crate::BuildContext,
+) -> anyhow::Result<()> {
+    let blocks = len.div_ceil(BLOCK_SIZE);
+    let data_offset = (BLOCK_MAP_OFFSET + blocks * 4).next_multiple_of(SECTOR_SIZE);
+
+    if blocks > UNALLOCATED as u64 - 1 {
+        anyhow::bail!("VDI images are limited to {} blocks", UNALLOCATED - 1);
+    }
+
+    let mut map = vec![UNALLOCATED; blocks as usize];
+    let mut allocated = 0u32;
+
+    out.seek(SeekFrom::Start(data_offset))?;
+
+    crate::output::for_each_chunk(input, BLOCK_SIZE as usize, |off, data| {
+        if dynamic && data.iter().all(|&b| b == 0) {
+            return Ok(());
+        }
+
+        map[(off / BLOCK_SIZE) as usize] = allocated;
+        allocated += 1;
+
+        out.write_all(data)?;
+        out.write_all(&vec![0; BLOCK_SIZE as usize - data.len()])
+    })?;
+
+    let mut h = [0u8; BLOCK_MAP_OFFSET as usize];
+    h[..40].copy_from_slice(b"<<< Oracle VM VirtualBox Disk Image >>>\n");
+    h[64..68].copy_from_slice(&SIGNATURE.to_le_bytes());
+    h[68..72].copy_from_slice(&VERSION.to_le_bytes());
+    h[72..76].copy_from_slice(&HEADER_SIZE.to_le_bytes());
+    let ty = if dynamic { TYPE_DYNAMIC } else { TYPE_FIXED };
+    h[76..80].copy_from_slice(&ty.to_le_bytes());
+    h[340..344].copy_from_slice(&(BLOCK_MAP_OFFSET as u32).to_le_bytes());
+    h[344..348].copy_from_slice(&(data_offset as u32).to_le_bytes());
+    // No legacy geometry, only the sector size
+    h[360..364].copy_from_slice(&(SECTOR_SIZE as u32).to_le_bytes());
+    h[368..376].copy_from_slice(&len.to_le_bytes());
+    h[376..380].copy_from_slice(&(BLOCK_SIZE as u32).to_le_bytes());
+    h[384..388].copy_from_slice(&(blocks as u32).to_le_bytes());
+    h[388..392].copy_from_slice(&allocated.to_le_bytes());
+    h[392..408].copy_from_slice(&random_uuid(&ctx.random)?);
+    h[408..424].copy_from_slice(&random_uuid(&ctx.random)?);
+
+    let mut map: Vec<u8> = map.iter().flat_map(|e| e.to_le_bytes()).collect();
+    map.resize((data_offset - BLOCK_MAP_OFFSET) as usize, 0);
+
+    out.seek(SeekFrom::Start(0))?;
+    out.write_all(&h)?;
+    out.write_all(&map)?;
+
+    Ok(())
+}
@@ -0,0 +1,165 @@
+//! Comparison of an existing image against a directory tree.
+
+use crate::{image, sha256, walk_dir};
+use clap::Args;
+use fatfs::{Dir, FileSystem, FsOptions, ReadWriteSeek};
+use log::*;
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io;
+use std::path::PathBuf;
+
+#[derive(Args, Debug)]
+pub struct VerifyArgs {
+    /// Image to check
+    #[arg(required_unless_present = "image_flag")]
+    image: Option<PathBuf>,
+    /// Image to check, instead of giving it as the first argument
+    #[arg(
+        long = "image",
+        id = "image_flag",
+        value_name = "IMAGE",
+        conflicts_with = "image"
+    )]
+    image_flag: Option<PathBuf>,
+    /// Directory the image contents are expected to match
+    #[arg(short, long)]
+    input_dir: PathBuf,
+    /// Partition to check. Defaults to the first one
+    #[arg(short, long)]
+    partition: Option<usize>,
+    /// Whether to follow symlinks in the input directory or skip them
+    #[arg(short, long)]
+    link_follow: bool,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum Entry {
+    Dir,
+    File { size: u64, digest: [u8; 32] },
+}
+
+/// Collect all entries under a FAT directory, keyed by their '/' separated path.
+fn image_tree<T: ReadWriteSeek>(
+    dir: &Dir<T>,
+    prefix: &str,
+    out: &mut BTreeMap<String, Entry>,
+) -> io::Result<()> {
+    for entry in dir.iter() {
+        let entry = entry?;
+        let name = entry.file_name();
+
+        if name == "." || name == ".." {
+            continue;
+        }
+
+        let path = format!("{prefix}{name}");
+
+        if entry.is_dir() {
+            image_tree(&entry.to_dir(), &format!("{path}/"), out)?;
+            out.insert(path, Entry::Dir);
+        } else {
+            let digest = sha256::Sha256::new().read_from(entry.to_file())?.finish();
+            out.insert(
+                path,
+                Entry::File {
+                    size: entry.len(),
+                    digest,
+                },
+            );
+        }
+    }
+
+    Ok(())
+}
+
+fn host_tree(args: &VerifyArgs) -> io::Result<BTreeMap<String, Entry>> {
+    let mut dirs = vec![];
+    let mut files = vec![];
+
+    walk_dir(
+        &args.input_dir,
+        &args.input_dir,
+        args.link_follow,
+        true,
+        (),
+        &mut |_, short_path, _, _| {
+            dirs.push(short_path.to_path_buf());
+            Ok(())
+        },
+        &mut |path, short_path, _, metadata| {
+            if metadata.is_symlink() {
+                warn!("Skipping symlink - {}", short_path.display());
+                return Ok(());
+            }
+            let digest = sha256::Sha256::new().read_from(File::open(path)?)?.finish();
+            let entry = Entry::File {
+                size: metadata.len(),
+                digest,
+            };
+            files.push((short_path.to_path_buf(), entry));
+            Ok(())
+        },
+        &mut |_, _| Ok(()),
+    )?;
+
+    let to_key = |p: PathBuf| {
+        p.components()
+            .map(|c| c.as_os_str().to_string_lossy())
+            .collect::<Vec<_>>()
+            .join("/")
+    };
+
+    Ok(dirs
+        .into_iter()
+        .map(|d| (d, Entry::Dir))
+        .chain(files)
+        .map(|(p, e)| (to_key(p), e))
+        .collect())
+}
+
+/// Report differences between the image and the directory. Fails if there are any.
+pub fn run(args: &VerifyArgs) -> anyhow::Result<()> {
+    let path = args.image.as_ref().or(args.image_flag.as_ref()).unwrap();
+    let part = image::open_partition(path, false, args.partition)?;
+    let fs = FileSystem::new(fscommon::BufStream::new(part), FsOptions::new())?;
+
+    let mut image = BTreeMap::new();
+    image_tree(&fs.root_dir(), "", &mut image)?;
+
+    let host = host_tree(args)?;
+
+    let mut differences = 0;
+
+    for (path, entry) in &host {
+        let problem = match (entry, image.get(path)) {
+            (_, None) => "missing from image",
+            (Entry::Dir, Some(Entry::Dir)) => continue,
+            (Entry::Dir, Some(_)) => "is a file in the image",
+            (Entry::File { .. }, Some(Entry::Dir)) => "is a directory in the image",
+            (Entry::File { size: a, .. }, Some(Entry::File { size: b, .. })) if a != b => {
+                "size differs"
+            }
+            (a, Some(b)) if a != b => "content differs",
+            _ => continue,
+        };
+
+        differences += 1;
+        println!("{path}: {problem}");
+    }
+
+    for path in image.keys().filter(|p| !host.contains_key(*p)) {
+        differences += 1;
+        println!("{path}: not in input directory");
+    }
+
+    info!("Compared {} entries", host.len());
+
+    if differences > 0 {
+        anyhow::bail!("image differs from input directory in {differences} entries");
+    }
+
+    println!("Image matches input directory");
+
+    Ok(())
+}
@@ -0,0 +1,132 @@
+//! Writing of vfat images through fatfs.
+
+use crate::{
+    content_tree, fat, layout, progress, tree, Args, BuildContext, BuildSummary, ExtraFile,
+};
+use chrono::{TimeZone, Utc};
+use fatfs::{format_volume, FileSystem, FormatVolumeOptions, FsOptions, ReadWriteSeek};
+use log::*;
+use std::io::{self, Read};
+
+/// Format a FAT filesystem and copy the input directory into it.
+#[allow(clippy::too_many_arguments)]
+pub fn write(
+    args: &Args,
+    ctx: &BuildContext,
+    part: &layout::Partition,
+    fs_slice: Box<dyn ReadWriteSeek>,
+    size: u64,
+    extra_files: &[ExtraFile],
+    summary: &mut BuildSummary,
+    progress: &mut progress::Progress,
+) -> anyhow::Result<()> {
+    let mut buf_stream = fscommon::BufStream::new(fs_slice);
+
+    let format = match args.fat_type.fixed() {
+        Some(fat_type) => {
+            let tree = content_tree(args, part, extra_files, ctx)?;
+            Some((fat_type, fat::cluster_size(&tree, fat_type, size)?))
+        }
+        None if args.sector_size == 512 => {
+            let tree = content_tree(args, part, extra_files, ctx)?;
+            Some(fat::auto_format(&tree, size)?)
+        }
+        None => None,
+    };
+
+    let mut format_options = match format {
+        Some((fat_type, cluster_size)) => {
+            debug!("{fat_type:?} cluster size: {cluster_size}");
+            FormatVolumeOptions::new()
+                .fat_type(fat_type)
+                .bytes_per_cluster(cluster_size)
+        }
+        None => FormatVolumeOptions::new().bytes_per_cluster(args.fat_cluster_size() as u32),
+    };
+
+    let mut volume_id = [0u8; 4];
+    ctx.random.fill(&mut volume_id)?;
+    format_options = format_options
+        .bytes_per_sector(args.sector_size as u16)
+        .volume_id(u32::from_le_bytes(volume_id));
+
+    if let Some(label) = &part.label {
+        format_options = format_options.volume_label(fat_volume_label(label)?);
+    }
+
+    format_volume(&mut buf_stream, format_options)?;
+
+    // FAT keeps local times, and reproducible images the ones in UTC
+    let naive = |time: chrono::DateTime<Utc>| {
+        if ctx.fixed_time {
+            time.naive_utc()
+        } else {
+            time.with_timezone(&chrono::Local).naive_local()
+        }
+    };
+    let node_time = |mtime| naive(Utc.timestamp_opt(mtime, 0).single().unwrap_or(ctx.time));
+    let time = fat::FixedTime::leak(naive(ctx.time));
+    let fs_options = FsOptions::new().time_provider(time);
+
+    let fs = FileSystem::new(&mut buf_stream, fs_options)?;
+
+    if let Some((fat_type, _)) = format {
+        if fs.fat_type() != fat_type {
+            anyhow::bail!("formatted as {:?} instead of {fat_type:?}", fs.fat_type());
+        }
+    }
+
+    let root_dir = fs.root_dir();
+
+    progress.phase("copy");
+
+    let tree = content_tree(args, part, extra_files, ctx)?;
+    // Directories of the tree, which come before their entries
+    let mut dirs = vec![None; tree.nodes.len()];
+    dirs[0] = Some(root_dir.clone());
+
+    for (idx, node) in tree.nodes.iter().enumerate().skip(1) {
+        let parent_dir = dirs[node.parent].as_ref().unwrap();
+        // fatfs stamps entries with the current time as it creates and writes them, and
+        // directories again as entries are added to them, which `stamp_times` undoes
+        time.set(node_time(node.mtime));
+
+        match &node.kind {
+            tree::Kind::Dir(_) => {
+                summary.dirs += 1;
+                info!("DIR: {}", node.name);
+                dirs[idx] = Some(parent_dir.create_dir(&node.name)?);
+            }
+            tree::Kind::File { source, len } => {
+                summary.files += 1;
+                info!("FILE {}: {}", summary.files, node.name);
+                let mut file = parent_dir.create_file(&node.name)?;
+                let copied = io::copy(&mut source.open()?.take(*len), &mut file)?;
+                if copied != *len {
+                    anyhow::bail!("{} changed while being copied", node.path.display());
+                }
+                progress.file(&node.path, *len);
+            }
+            tree::Kind::Symlink(_) => unreachable!("FAT has no symbolic links"),
+        }
+    }
+
+    std::mem::drop(dirs);
+    std::mem::drop(root_dir);
+    fs.unmount()?;
+
+    fat::stamp_times(&mut buf_stream, &tree, node_time)?;
+
+    Ok(())
+}
+
+/// Convert a label to the space padded, upper case form stored in the FAT boot sector.
+fn fat_volume_label(label: &str) -> anyhow::Result<[u8; 11]> {
+    if label.len() > 11 || !label.bytes().all(|b| b.is_ascii_graphic() || b == b' ') {
+        anyhow::bail!("FAT volume label must be at most 11 printable ASCII characters: `{label}`");
+    }
+
+    let mut out = [b' '; 11];
+    out[..label.len()].copy_from_slice(label.to_ascii_uppercase().as_bytes());
+    Ok(out)
+}
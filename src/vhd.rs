@@ -0,0 +1,158 @@
+//! VHD images, for Hyper-V, Azure and Virtual PC.
+//!
+//! Fixed images are the raw image followed by a footer. Dynamic images hold the blocks that are
+//! not all zeroes after a block allocation table, and sizes are taken from the footer rather than
+//! its CHS geometry, as Hyper-V does.
+
+use std::io::{self, Read, Seek, SeekFrom, Write};
+
+const SECTOR_SIZE: u64 = 512;
+const BLOCK_SIZE: u64 = 2 << 20;
+
+/// Largest disk the format describes
+const MAX_SIZE: u64 = 2040 << 30;
+
+const FOOTER_LEN: usize = 512;
+const DYNAMIC_HEADER_LEN: usize = 1024;
+
+/// Seconds from the Unix epoch to the VHD epoch, 2000-01-01
+const VHD_EPOCH: i64 = 946684800;
+
+const DISK_FIXED: u32 = 2;
+const DISK_DYNAMIC: u32 = 3;
+
+const UNUSED: u32 = u32::MAX;
+
+/// Ones' complement of the sum of all bytes.
+fn checksum(data: &[u8]) -> u32 {
+    !data.iter().fold(0u32, |sum, &b| sum.wrapping_add(b as u32))
+}
+
+/// CHS geometry of a disk of `size` bytes, as computed in the VHD specification.
+fn geometry(size: u64) -> (u16, u8, u8) {
+    let sectors = (size / SECTOR_SIZE).min(65535 * 16 * 255);
+
+    let (spt, heads) = if sectors >= 65535 * 16 * 63 {
+        (255, 16)
+    } else {
+        let mut spt = 17;
+        let mut heads = (sectors / spt).div_ceil(1024).max(4);
+
+        if sectors / spt >= heads * 1024 || heads > 16 {
+            (spt, heads) = (31, 16);
+        }
+        if sectors / spt >= heads * 1024 {
+            (spt, heads) = (63, 16);
+        }
+
+        (spt, heads)
+    };
+
+    ((sectors / spt / heads) as u16, heads as u8, spt as u8)
+}
+
+fn footer(
+    len: u64,
+    disk_type: u32,
+    data_offset: u64,
+    ctx: &crate::BuildContext,
+) -> anyhow::Result<[u8; FOOTER_LEN]> {
+    let time = ctx.time.timestamp() - VHD_EPOCH;
+    let (cylinders, heads, spt) = geometry(len);
+
+    let mut f = [0u8; FOOTER_LEN];
+    f[0..8].copy_from_slice(b"conectix");
+    // Reserved feature bit that is always set
+    f[8..12].copy_from_slice(&2u32.to_be_bytes());
+    f[12..16].copy_from_slice(&0x10000u32.to_be_bytes());
+    f[16..24].copy_from_slice(&data_offset.to_be_bytes());
+    f[24..28].copy_from_slice(&(time.max(0) as u32).to_be_bytes());
+    f[28..32].copy_from_slice(b"mkim");
+    f[32..36].copy_from_slice(&0x10000u32.to_be_bytes());
+    f[36..40].copy_from_slice(b"Wi2k");
+    f[40..48].copy_from_slice(&len.to_be_bytes());
+    f[48..56].copy_from_slice(&len.to_be_bytes());
+    f[56..58].copy_from_slice(&cylinders.to_be_bytes());
+    f[58] = heads;
+    f[59] = spt;
+    f[60..64].copy_from_slice(&disk_type.to_be_bytes());
+    ctx.random.fill(&mut f[68..84])?;
+
+    let sum = checksum(&f);
+    f[64..68].copy_from_slice(&sum.to_be_bytes());
+
+    Ok(f)
+}
+
+/// Write the raw image of `len` bytes from `input` as a fixed or dynamic VHD.
+pub fn write(
+    mut input: impl Read,
+    out: &mut (impl Write + Seek),
+    len: u64,
+    dynamic: bool,
+    ctx: &crate::BuildContext,
+) -> anyhow::Result<()> {
+    if len > MAX_SIZE {
+        anyhow::bail!("VHD images are limited to {MAX_SIZE} bytes, the image is {len}");
+    }
+    if !len.is_multiple_of(SECTOR_SIZE) {
+        anyhow::bail!("VHD images are whole sectors, the image is {len} bytes");
+    }
+
+    if !dynamic {
+        io::copy(&mut input, out)?;
+        out.write_all(&footer(len, DISK_FIXED, u64::MAX, ctx)?)?;
+        return Ok(());
+    }
+
+    let footer = footer(len, DISK_DYNAMIC, FOOTER_LEN as u64, ctx)?;
+    let blocks = len.div_ceil(BLOCK_SIZE);
+    let bat_offset = (FOOTER_LEN + DYNAMIC_HEADER_LEN) as u64;
+    let bat_len = (blocks * 4).next_multiple_of(SECTOR_SIZE);
+
+    let mut header = [0u8; DYNAMIC_HEADER_LEN];
+    header[0..8].copy_from_slice(b"cxsparse");
+    header[8..16].copy_from_slice(&u64::MAX.to_be_bytes());
+    header[16..24].copy_from_slice(&bat_offset.to_be_bytes());
+    header[24..28].copy_from_slice(&0x10000u32.to_be_bytes());
+    header[28..32].copy_from_slice(&(blocks as u32).to_be_bytes());
+    header[32..36].copy_from_slice(&(BLOCK_SIZE as u32).to_be_bytes());
+    let sum = checksum(&header);
+    header[36..40].copy_from_slice(&sum.to_be_bytes());
+
+    // Every sector of an allocated block is marked present in its bitmap
+    let bitmap = vec![0xff; (BLOCK_SIZE / SECTOR_SIZE / 8).next_multiple_of(SECTOR_SIZE) as usize];
+
+    let mut bat = vec![UNUSED; blocks as usize];
+    let mut pos = bat_offset + bat_len;
+
+    out.seek(SeekFrom::Start(pos))?;
+
+    crate::output::for_each_chunk(input, BLOCK_SIZE as usize, |off, data| {
+        if data.iter().all(|&b| b == 0) {
+            return Ok(());
+        }
+
+        bat[(off / BLOCK_SIZE) as usize] = (pos / SECTOR_SIZE) as u32;
+
+        let mut block = data.to_vec();
+        block.resize(BLOCK_SIZE as usize, 0);
+        out.write_all(&bitmap)?;
+        out.write_all(&block)?;
+        pos += (bitmap.len() + block.len()) as u64;
+
+        Ok(())
+    })?;
+
+    out.write_all(&footer)?;
+
+    let mut bat: Vec<u8> = bat.iter().flat_map(|e| e.to_be_bytes()).collect();
+    bat.resize(bat_len as usize, 0xff);
+
+    out.seek(SeekFrom::Start(0))?;
+    out.write_all(&footer)?;
+    out.write_all(&header)?;
+    out.write_all(&bat)?;
+
+    Ok(())
+}
@@ -0,0 +1,242 @@
+//! VHDX images, for Hyper-V.
+//!
+//! The headers, an empty log, the metadata and the block allocation table take the first 4 MiB,
+//! followed by the payload blocks. Fixed images allocate every block, dynamic ones only those that
+//! are not all zeroes.
+
+use std::io::{Read, Seek, SeekFrom, Write};
+
+const MIB: u64 = 1 << 20;
+
+const BLOCK_SIZE: u64 = 32 * MIB;
+const LOGICAL_SECTOR_SIZE: u64 = 512;
+const PHYSICAL_SECTOR_SIZE: u32 = 4096;
+
+/// Payload blocks described by one sector bitmap block
+const CHUNK_RATIO: u64 = (1 << 23) * LOGICAL_SECTOR_SIZE / BLOCK_SIZE;
+
+const MAX_SIZE: u64 = 64 << 40;
+
+const HEADER_OFFSETS: [u64; 2] = [64 << 10, 128 << 10];
+const HEADER_LEN: usize = 4 << 10;
+const REGION_TABLE_OFFSETS: [u64; 2] = [192 << 10, 256 << 10];
+const REGION_TABLE_LEN: usize = 64 << 10;
+
+const LOG_OFFSET: u64 = MIB;
+const LOG_LEN: u32 = MIB as u32;
+const METADATA_OFFSET: u64 = 2 * MIB;
+const METADATA_LEN: u32 = MIB as u32;
+const BAT_OFFSET: u64 = 3 * MIB;
+
+const BAT_REGION: &str = "2DC27766-F623-4200-9D64-115E9BFD4A08";
+const METADATA_REGION: &str = "8B7CA206-4790-4B9A-B8FE-575F050F886E";
+
+const FILE_PARAMETERS: &str = "CAA16737-FA36-4D43-B3B6-33F0AA44E76B";
+const VIRTUAL_DISK_SIZE: &str = "2FA54224-CD1B-4876-B211-5DBED83BF4B8";
+const VIRTUAL_DISK_ID: &str = "BECA12AB-B2E6-4523-93EF-C309E000C746";
+const LOGICAL_SECTOR: &str = "8141BF1D-A96F-4709-BA47-F233A8FAAB5F";
+const PHYSICAL_SECTOR: &str = "CDA348C7-445D-4471-9CC9-E9885251C556";
+
+/// Metadata item flags
+const IS_VIRTUAL_DISK: u32 = 1 << 1;
+const IS_REQUIRED: u32 = 1 << 2;
+
+/// File parameter flag keeping all blocks allocated, for fixed images
+const LEAVE_BLOCKS_ALLOCATED: u32 = 1;
+
+const PAYLOAD_BLOCK_NOT_PRESENT: u64 = 0;
+const PAYLOAD_BLOCK_FULLY_PRESENT: u64 = 6;
+
+/// GUID in its mixed-endian on-disk form.
+fn guid(s: &str) -> [u8; 16] {
+    let hex: Vec<u8> = s
+        .split('-')
+        .flat_map(|field| {
+            (0..field.len())
+                .step_by(2)
+                .map(|i| u8::from_str_radix(&field[i..i + 2], 16).unwrap())
+        })
+        .collect();
+
+    let mut g = [0u8; 16];
+    g.copy_from_slice(&hex);
+    g[0..4].reverse();
+    g[4..6].reverse();
+    g[6..8].reverse();
+    g
+}
+
+fn random_guid(random: &crate::random::Random) -> std::io::Result<[u8; 16]> {
+    let mut g = [0u8; 16];
+    random.fill(&mut g)?;
+    g[7] = (g[7] & 0x0f) | 0x40;
+    g[8] = (g[8] & 0x3f) | 0x80;
+    Ok(g)
+}
+
+/// Store the CRC-32C of `data` at offset 4, which is zero while computing it.
+fn set_checksum(data: &mut [u8]) {
+    let sum = crc::crc32::checksum_castagnoli(data);
+    data[4..8].copy_from_slice(&sum.to_le_bytes());
+}
+
+fn header(sequence: u64, file_write: [u8; 16], data_write: [u8; 16]) -> Vec<u8> {
+    let mut h = vec![0u8; HEADER_LEN];
+    h[0..4].copy_from_slice(b"head");
+    h[8..16].copy_from_slice(&sequence.to_le_bytes());
+    h[16..32].copy_from_slice(&file_write);
+    h[32..48].copy_from_slice(&data_write);
+    // No log GUID, so there is nothing to replay
+    h[66..68].copy_from_slice(&1u16.to_le_bytes());
+    h[68..72].copy_from_slice(&LOG_LEN.to_le_bytes());
+    h[72..80].copy_from_slice(&LOG_OFFSET.to_le_bytes());
+    set_checksum(&mut h);
+    h
+}
+
+fn region_table(bat_len: u32) -> Vec<u8> {
+    let mut t = vec![0u8; REGION_TABLE_LEN];
+    t[0..4].copy_from_slice(b"regi");
+    t[8..12].copy_from_slice(&2u32.to_le_bytes());
+
+    let regions = [
+        (BAT_REGION, BAT_OFFSET, bat_len),
+        (METADATA_REGION, METADATA_OFFSET, METADATA_LEN),
+    ];
+    for (entry, (id, offset, len)) in t[16..].chunks_mut(32).zip(regions) {
+        entry[0..16].copy_from_slice(&guid(id));
+        entry[16..24].copy_from_slice(&offset.to_le_bytes());
+        entry[24..28].copy_from_slice(&len.to_le_bytes());
+        // Required
+        entry[28..32].copy_from_slice(&1u32.to_le_bytes());
+    }
+
+    set_checksum(&mut t);
+    t
+}
+
+fn metadata(len: u64, dynamic: bool, random: &crate::random::Random) -> std::io::Result<Vec<u8>> {
+    let mut params = (BLOCK_SIZE as u32).to_le_bytes().to_vec();
+    let flags = if dynamic { 0 } else { LEAVE_BLOCKS_ALLOCATED };
+    params.extend(flags.to_le_bytes());
+
+    let items: [(&str, u32, Vec<u8>); 5] = [
+        (FILE_PARAMETERS, IS_REQUIRED, params),
+        (
+            VIRTUAL_DISK_SIZE,
+            IS_VIRTUAL_DISK | IS_REQUIRED,
+            len.to_le_bytes().to_vec(),
+        ),
+        (
+            VIRTUAL_DISK_ID,
+            IS_VIRTUAL_DISK | IS_REQUIRED,
+            random_guid(random)?.to_vec(),
+        ),
+        (
+            LOGICAL_SECTOR,
+            IS_VIRTUAL_DISK | IS_REQUIRED,
+            (LOGICAL_SECTOR_SIZE as u32).to_le_bytes().to_vec(),
+        ),
+        (
+            PHYSICAL_SECTOR,
+            IS_VIRTUAL_DISK | IS_REQUIRED,
+            PHYSICAL_SECTOR_SIZE.to_le_bytes().to_vec(),
+        ),
+    ];
+
+    let mut m = vec![0u8; METADATA_LEN as usize];
+    m[0..8].copy_from_slice(b"metadata");
+    m[10..12].copy_from_slice(&(items.len() as u16).to_le_bytes());
+
+    // Items follow the 64 KiB table
+    let mut offset = 64 << 10;
+    for (i, (id, flags, data)) in items.iter().enumerate() {
+        let entry = &mut m[32 + i * 32..64 + i * 32];
+        entry[0..16].copy_from_slice(&guid(id));
+        entry[16..20].copy_from_slice(&(offset as u32).to_le_bytes());
+        entry[20..24].copy_from_slice(&(data.len() as u32).to_le_bytes());
+        entry[24..28].copy_from_slice(&flags.to_le_bytes());
+
+        m[offset..offset + data.len()].copy_from_slice(data);
+        offset += data.len();
+    }
+
+    Ok(m)
+}
+
+/// Write the raw image of `len` bytes from `input` as a fixed or dynamic VHDX.
+pub fn write(
+    input: impl Read,
+    out: &mut (impl Write + Seek),
+    len: u64,
+    dynamic: bool,
+    ctx: &crate::BuildContext,
+) -> anyhow::Result<()> {
+    if len > MAX_SIZE {
+        anyhow::bail!("VHDX images are limited to {MAX_SIZE} bytes, the image is {len}");
+    }
+    if !len.is_multiple_of(LOGICAL_SECTOR_SIZE) {
+        anyhow::bail!("VHDX images are whole sectors, the image is {len} bytes");
+    }
+
+    let blocks = len.div_ceil(BLOCK_SIZE);
+    // A sector bitmap entry follows every chunk of payload blocks, even though there are none
+    // without a parent disk
+    let entries = blocks + blocks.saturating_sub(1) / CHUNK_RATIO;
+    let bat_len = (entries * 8).next_multiple_of(MIB).max(MIB);
+    let mut bat = vec![0u64; entries as usize];
+
+    let mut pos = BAT_OFFSET + bat_len;
+    out.seek(SeekFrom::Start(pos))?;
+
+    crate::output::for_each_chunk(input, BLOCK_SIZE as usize, |off, data| {
+        let block = off / BLOCK_SIZE;
+        let entry = &mut bat[(block + block / CHUNK_RATIO) as usize];
+
+        if dynamic && data.iter().all(|&b| b == 0) {
+            *entry = PAYLOAD_BLOCK_NOT_PRESENT;
+            return Ok(());
+        }
+
+        *entry = PAYLOAD_BLOCK_FULLY_PRESENT | pos;
+
+        out.write_all(data)?;
+        out.write_all(&vec![0; BLOCK_SIZE as usize - data.len()])?;
+        pos += BLOCK_SIZE;
+
+        Ok(())
+    })?;
+
+    out.seek(SeekFrom::Start(0))?;
+    out.write_all(b"vhdxfile")?;
+    let creator: Vec<u8> = "mkimg"
+        .encode_utf16()
+        .flat_map(|c| c.to_le_bytes())
+        .collect();
+    out.write_all(&creator)?;
+
+    let (file_write, data_write) = (random_guid(&ctx.random)?, random_guid(&ctx.random)?);
+    for (sequence, offset) in HEADER_OFFSETS.into_iter().enumerate() {
+        out.seek(SeekFrom::Start(offset))?;
+        out.write_all(&header(sequence as u64, file_write, data_write))?;
+    }
+
+    let table = region_table(bat_len as u32);
+    for offset in REGION_TABLE_OFFSETS {
+        out.seek(SeekFrom::Start(offset))?;
+        out.write_all(&table)?;
+    }
+
+    out.seek(SeekFrom::Start(LOG_OFFSET))?;
+    out.write_all(&vec![0; LOG_LEN as usize])?;
+
+    out.seek(SeekFrom::Start(METADATA_OFFSET))?;
+    out.write_all(&metadata(len, dynamic, &ctx.random)?)?;
+
+    out.seek(SeekFrom::Start(BAT_OFFSET))?;
+    let mut bat: Vec<u8> = bat.iter().flat_map(|e| e.to_le_bytes()).collect();
+    bat.resize(bat_len as usize, 0);
+    out.write_all(&bat)?;
+
+    Ok(())
+}
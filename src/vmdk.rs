@@ -0,0 +1,243 @@
+//! VMDK images, for VMware.
+//!
+//! Monolithic sparse images hold the grains that are not all zeroes after the grain directory
+//! and tables. Stream optimized images, as packaged into OVAs, are written front to back, with
+//! compressed grains followed by the tables and a footer, and cannot be written to in place.
+
+use crate::compress;
+use std::io::{Read, Seek, SeekFrom, Write};
+
+const SECTOR_SIZE: u64 = 512;
+const GRAIN_SECTORS: u64 = 128;
+const GRAIN_SIZE: u64 = GRAIN_SECTORS * SECTOR_SIZE;
+const GT_ENTRIES: u64 = 512;
+const GT_SECTORS: u64 = GT_ENTRIES * 4 / SECTOR_SIZE;
+
+const DESCRIPTOR_OFFSET: u64 = 1;
+const DESCRIPTOR_SECTORS: u64 = 20;
+
+/// Newline detection characters are valid
+const FLAG_NEWLINE_TEST: u32 = 1;
+const FLAG_COMPRESSED: u32 = 1 << 16;
+const FLAG_MARKERS: u32 = 1 << 17;
+
+const COMPRESSION_DEFLATE: u16 = 1;
+
+/// The grain directory is found through the footer
+const GD_AT_END: u64 = u64::MAX;
+
+const MARKER_EOS: u32 = 0;
+const MARKER_GT: u32 = 1;
+const MARKER_GD: u32 = 2;
+const MARKER_FOOTER: u32 = 3;
+
+struct Header {
+    version: u32,
+    flags: u32,
+    capacity: u64,
+    gd_offset: u64,
+    overhead: u64,
+    compress: bool,
+}
+
+impl Header {
+    fn to_bytes(&self) -> [u8; SECTOR_SIZE as usize] {
+        let mut h = [0u8; SECTOR_SIZE as usize];
+        h[0..4].copy_from_slice(b"KDMV");
+        h[4..8].copy_from_slice(&self.version.to_le_bytes());
+        h[8..12].copy_from_slice(&self.flags.to_le_bytes());
+        h[12..20].copy_from_slice(&self.capacity.to_le_bytes());
+        h[20..28].copy_from_slice(&GRAIN_SECTORS.to_le_bytes());
+        h[28..36].copy_from_slice(&DESCRIPTOR_OFFSET.to_le_bytes());
+        h[36..44].copy_from_slice(&DESCRIPTOR_SECTORS.to_le_bytes());
+        h[44..48].copy_from_slice(&(GT_ENTRIES as u32).to_le_bytes());
+        h[56..64].copy_from_slice(&self.gd_offset.to_le_bytes());
+        h[64..72].copy_from_slice(&self.overhead.to_le_bytes());
+        h[73..77].copy_from_slice(b"\n \r\n");
+        if self.compress {
+            h[77..79].copy_from_slice(&COMPRESSION_DEFLATE.to_le_bytes());
+        }
+        h
+    }
+}
+
+fn descriptor(
+    name: &str,
+    capacity: u64,
+    create_type: &str,
+    random: &crate::random::Random,
+) -> anyhow::Result<Vec<u8>> {
+    let mut cid = [0u8; 4];
+    random.fill(&mut cid)?;
+
+    let cylinders = (capacity / (16 * 63)).min(16383);
+    let text = format!(
+        "# Disk DescriptorFile\n\
+         version=1\n\
+         CID={:08x}\n\
+         parentCID=ffffffff\n\
+         createType=\"{create_type}\"\n\
+         \n\
+         # Extent description\n\
+         RW {capacity} SPARSE \"{name}\"\n\
+         \n\
+         # The Disk Data Base\n\
+         #DDB\n\
+         \n\
+         ddb.virtualHWVersion = \"4\"\n\
+         ddb.geometry.cylinders = \"{cylinders}\"\n\
+         ddb.geometry.heads = \"16\"\n\
+         ddb.geometry.sectors = \"63\"\n\
+         ddb.adapterType = \"ide\"\n",
+        u32::from_le_bytes(cid)
+    );
+
+    let mut data = text.into_bytes();
+    if data.len() as u64 > DESCRIPTOR_SECTORS * SECTOR_SIZE {
+        anyhow::bail!("VMDK descriptor for {name} is too long");
+    }
+    data.resize((DESCRIPTOR_SECTORS * SECTOR_SIZE) as usize, 0);
+    Ok(data)
+}
+
+fn le_table(entries: &[u32]) -> Vec<u8> {
+    entries.iter().flat_map(|e| e.to_le_bytes()).collect()
+}
+
+/// Metadata marker sector, for tables of `sectors` sectors following it.
+fn marker(sectors: u64, ty: u32) -> [u8; SECTOR_SIZE as usize] {
+    let mut m = [0u8; SECTOR_SIZE as usize];
+    m[0..8].copy_from_slice(&sectors.to_le_bytes());
+    m[12..16].copy_from_slice(&ty.to_le_bytes());
+    m
+}
+
+/// Write the raw image of `len` bytes from `input` as a VMDK named `name`, monolithic sparse or
+/// stream optimized.
+pub fn write(
+    input: impl Read,
+    out: &mut (impl Write + Seek),
+    len: u64,
+    name: &str,
+    stream: bool,
+    ctx: &crate::BuildContext,
+) -> anyhow::Result<()> {
+    if !len.is_multiple_of(SECTOR_SIZE) {
+        anyhow::bail!("VMDK images are whole sectors, the image is {len} bytes");
+    }
+
+    let capacity = len / SECTOR_SIZE;
+    let grains = capacity.div_ceil(GRAIN_SECTORS);
+    let tables = grains.div_ceil(GT_ENTRIES);
+    let mut gts = vec![0u32; (tables * GT_ENTRIES) as usize];
+
+    let create_type = if stream {
+        "streamOptimized"
+    } else {
+        "monolithicSparse"
+    };
+    let descriptor = descriptor(name, capacity, create_type, &ctx.random)?;
+
+    let gd_offset = DESCRIPTOR_OFFSET + DESCRIPTOR_SECTORS;
+    let gd_sectors = (tables * 4).div_ceil(SECTOR_SIZE);
+    let gts_offset = gd_offset + gd_sectors;
+
+    let mut header = if stream {
+        Header {
+            version: 3,
+            flags: FLAG_NEWLINE_TEST | FLAG_COMPRESSED | FLAG_MARKERS,
+            capacity,
+            gd_offset: GD_AT_END,
+            overhead: GRAIN_SECTORS,
+            compress: true,
+        }
+    } else {
+        Header {
+            version: 1,
+            flags: FLAG_NEWLINE_TEST,
+            capacity,
+            gd_offset,
+            overhead: (gts_offset + tables * GT_SECTORS).next_multiple_of(GRAIN_SECTORS),
+            compress: false,
+        }
+    };
+
+    out.seek(SeekFrom::Start(header.overhead * SECTOR_SIZE))?;
+    let mut pos = header.overhead;
+
+    crate::output::for_each_chunk(input, GRAIN_SIZE as usize, |off, data| {
+        if data.iter().all(|&b| b == 0) {
+            return Ok(());
+        }
+
+        let mut grain = data.to_vec();
+        grain.resize(GRAIN_SIZE as usize, 0);
+
+        gts[(off / GRAIN_SIZE) as usize] = pos as u32;
+
+        if stream {
+            // Grain marker, with the guest sector and the compressed length
+            let compressed = compress::zlib(&grain, compress::DEFAULT_LEVEL);
+            let mut block = (off / SECTOR_SIZE).to_le_bytes().to_vec();
+            block.extend((compressed.len() as u32).to_le_bytes());
+            block.extend(compressed);
+            block.resize(
+                (block.len() as u64).next_multiple_of(SECTOR_SIZE) as usize,
+                0,
+            );
+            out.write_all(&block)?;
+            pos += block.len() as u64 / SECTOR_SIZE;
+        } else {
+            out.write_all(&grain)?;
+            pos += GRAIN_SECTORS;
+        }
+
+        Ok(())
+    })?;
+
+    if pos > u32::MAX as u64 {
+        anyhow::bail!("VMDK image is too large for its grain tables");
+    }
+
+    let mut gd = vec![0u32; tables as usize];
+
+    if stream {
+        // Tables of grains that are all zeroes are left out
+        for (i, table) in gts.chunks(GT_ENTRIES as usize).enumerate() {
+            if table.iter().all(|&e| e == 0) {
+                continue;
+            }
+            out.write_all(&marker(GT_SECTORS, MARKER_GT))?;
+            gd[i] = (pos + 1) as u32;
+            out.write_all(&le_table(table))?;
+            pos += 1 + GT_SECTORS;
+        }
+
+        let mut table = le_table(&gd);
+        table.resize((gd_sectors * SECTOR_SIZE) as usize, 0);
+        out.write_all(&marker(gd_sectors, MARKER_GD))?;
+        out.write_all(&table)?;
+        header.gd_offset = pos + 1;
+
+        out.write_all(&marker(1, MARKER_FOOTER))?;
+        out.write_all(&header.to_bytes())?;
+        out.write_all(&marker(0, MARKER_EOS))?;
+
+        header.gd_offset = GD_AT_END;
+    } else {
+        for (i, entry) in gd.iter_mut().enumerate() {
+            *entry = (gts_offset + i as u64 * GT_SECTORS) as u32;
+        }
+
+        out.seek(SeekFrom::Start(gd_offset * SECTOR_SIZE))?;
+        out.write_all(&le_table(&gd))?;
+        out.seek(SeekFrom::Start(gts_offset * SECTOR_SIZE))?;
+        out.write_all(&le_table(&gts))?;
+    }
+
+    out.seek(SeekFrom::Start(0))?;
+    out.write_all(&header.to_bytes())?;
+    out.write_all(&descriptor)?;
+
+    Ok(())
+}
@@ -0,0 +1,288 @@
+//! The common subset of wic kickstart files from Yocto, as given with --from-wks.
+//!
+//! `part` lines become partitions in the order they are listed, and `bootloader --ptable` picks
+//! the partition table:
+//!
+//! ```text
+//! part /boot --source bootimg-efi --fstype=vfat --label boot --active --align 1024 --size 64M
+//! part / --source rootfs --fstype=ext4 --label root --align 1024
+//! bootloader --ptable gpt
+//! ```
+//!
+//! The `rootfs` source takes the directory given with `--rootfs-dir`, or the one named by the
+//! `--rootfs-dir` of the partition. Other source plugins take the directory given for their name,
+//! such as `--rootfs-dir bootimg-efi=./esp`, except `rawcopy`, whose `file` source parameter is
+//! copied into the partition. Partitions without a source are formatted empty, or left
+//! unformatted without `--fstype`. ext4 is written as ext3, which ext4 drivers mount.
+
+use crate::layout::Partition;
+use crate::size::{self, PartitionSize};
+use crate::Filesystem;
+use log::*;
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Partitions and table of a kickstart file.
+pub struct Kickstart {
+    pub partitions: Vec<Partition>,
+    /// `Some(true)` for GPT, `Some(false)` for MBR, as given with `bootloader --ptable`
+    pub gpt: Option<bool>,
+    /// Largest partition alignment asked for, in bytes
+    pub align: Option<u64>,
+}
+
+/// Split a line into words like a shell, keeping quoted spaces.
+fn words(line: &str) -> anyhow::Result<Vec<String>> {
+    let mut words = vec![];
+    let mut word = None::<String>;
+    let mut quote = None;
+
+    for c in line.chars() {
+        match (quote, c) {
+            (Some(q), c) if c == q => quote = None,
+            (Some(_), c) => word.get_or_insert_with(String::new).push(c),
+            (None, '"' | '\'') => {
+                quote = Some(c);
+                word.get_or_insert_with(String::new);
+            }
+            (None, c) if c.is_whitespace() => words.extend(word.take()),
+            (None, c) => word.get_or_insert_with(String::new).push(c),
+        }
+    }
+
+    if quote.is_some() {
+        anyhow::bail!("unterminated quote");
+    }
+    words.extend(word);
+    Ok(words)
+}
+
+/// Options of a line as `(name, value)`, with values given as `--name=value` or `--name value`.
+type Options<'a> = Vec<(&'a str, Option<&'a str>)>;
+
+/// Words before the first option of a line, and its options.
+fn options(words: &[String]) -> anyhow::Result<(Vec<&str>, Options<'_>)> {
+    let first = words
+        .iter()
+        .position(|w| w.starts_with("--"))
+        .unwrap_or(words.len());
+    let args = words[..first].iter().map(String::as_str).collect();
+
+    let mut opts = vec![];
+    let mut rest = words[first..].iter().peekable();
+    while let Some(word) = rest.next() {
+        let Some(opt) = word.strip_prefix("--") else {
+            anyhow::bail!("unexpected `{word}`");
+        };
+        match opt.split_once('=') {
+            Some((name, value)) => opts.push((name, Some(value))),
+            None => {
+                let value = rest.next_if(|w| !w.starts_with("--"));
+                opts.push((opt, value.map(String::as_str)));
+            }
+        }
+    }
+
+    Ok((args, opts))
+}
+
+/// Size in MiB, or with a `k`, `M` or `G` suffix.
+fn parse_size(s: &str) -> anyhow::Result<u64> {
+    match s.ends_with(|c: char| c.is_ascii_digit()) {
+        true => size::parse_bytes(&format!("{s}M")),
+        false => size::parse_bytes(s),
+    }
+    .map_err(anyhow::Error::msg)
+}
+
+fn parse_fstype(s: &str) -> anyhow::Result<Option<Filesystem>> {
+    Ok(Some(match s {
+        "vfat" | "msdos" => Filesystem::Vfat,
+        "ext2" => Filesystem::Ext2,
+        "ext3" => Filesystem::Ext3,
+        "ext4" => {
+            warn!("Writing the ext4 partition as ext3");
+            Filesystem::Ext3
+        }
+        "squashfs" => Filesystem::Squashfs,
+        "erofs" => Filesystem::Erofs,
+        "btrfs" => Filesystem::Btrfs,
+        "swap" => return Ok(None),
+        _ => anyhow::bail!("unsupported --fstype `{s}`"),
+    }))
+}
+
+/// Partition of a `part` line, and the alignment it asks for.
+fn partition(
+    words: &[String],
+    number: usize,
+    base: &Path,
+    dirs: &HashMap<String, PathBuf>,
+    disk: &mut Option<String>,
+) -> anyhow::Result<(Partition, Option<u64>)> {
+    let (args, opts) = options(words)?;
+    let mountpoint = match args[..] {
+        [] => None,
+        [mountpoint] => Some(mountpoint),
+        _ => anyhow::bail!("`part` takes a single mount point"),
+    };
+
+    let mut part = Partition {
+        name: match mountpoint {
+            Some("/") => "root".into(),
+            Some(m) => m.rsplit('/').next().unwrap_or(m).into(),
+            None => format!("part{number}"),
+        },
+        filesystem: None,
+        size: None,
+        gpt_type: "generic".parse().unwrap(),
+        uuid: None,
+        mbr_type: None,
+        primary: None,
+        bootable: false,
+        attributes: 0,
+        input_dir: None,
+        image: None,
+        label: None,
+        files: vec![],
+        subvolumes: vec![],
+    };
+    let mut source = None;
+    let mut source_params = None;
+    let mut rootfs_dir = None;
+    let mut part_name = None;
+    let mut swap = false;
+    let mut align = None;
+
+    for (name, value) in opts {
+        let value = || value.ok_or_else(|| anyhow::anyhow!("--{name} needs a value"));
+        match name {
+            "source" => source = Some(value()?),
+            "sourceparams" => source_params = Some(value()?),
+            "rootfs-dir" => rootfs_dir = Some(value()?),
+            "fstype" => {
+                part.filesystem = parse_fstype(value()?)?;
+                swap = part.filesystem.is_none();
+            }
+            "label" => part.label = Some(value()?.into()),
+            "part-name" => part_name = Some(value()?),
+            "part-type" => part.gpt_type = value()?.parse().map_err(anyhow::Error::msg)?,
+            "uuid" => part.uuid = Some(value()?.into()),
+            "system-id" => {
+                part.mbr_type =
+                    Some(crate::part_type::parse_mbr_type(value()?).map_err(anyhow::Error::msg)?)
+            }
+            "size" | "fixed-size" => part.size = Some(PartitionSize::Bytes(parse_size(value()?)?)),
+            "align" => align = Some(value()?.parse::<u64>()? << 10),
+            "active" => part.bootable = true,
+            "ondisk" | "ondrive" => match (disk.as_deref(), value()?) {
+                (Some(d), v) if d != v => anyhow::bail!("partitions on more than one disk"),
+                (_, v) => *disk = Some(v.into()),
+            },
+            // Only used to write the fstab of the root filesystem
+            "use-uuid" | "use-label" | "no-fstab-update" => {}
+            "extra-space" | "overhead-factor" | "fsoptions" | "mkfs-extraopts" | "fsuuid"
+            | "exclude-path" | "include-path" => {
+                warn!("Ignoring --{name} of partition {}", part.name)
+            }
+            _ => anyhow::bail!("unsupported option --{name}"),
+        }
+    }
+
+    if let Some(name) = part_name {
+        part.name = name.into();
+    }
+    if swap {
+        part.gpt_type = "swap".parse().unwrap();
+    } else if matches!(part.filesystem, Some(Filesystem::Vfat)) && source == Some("bootimg-efi") {
+        part.gpt_type = "esp".parse().unwrap();
+    }
+
+    let dir = |name: &str| {
+        dirs.get(name).cloned().ok_or_else(|| match name {
+            "" => anyhow::anyhow!("the rootfs source needs --rootfs-dir"),
+            name => anyhow::anyhow!("no --rootfs-dir given for `{name}`"),
+        })
+    };
+
+    match source {
+        None => {}
+        Some("rawcopy") => {
+            let file = source_params
+                .and_then(|p| p.split(',').find_map(|p| p.strip_prefix("file=")))
+                .ok_or_else(|| anyhow::anyhow!("rawcopy needs --sourceparams=\"file=...\""))?;
+            part.filesystem = None;
+            part.image = Some(base.join(file));
+        }
+        Some(_) if part.filesystem.is_none() => {
+            anyhow::bail!("partition {} has a source but no --fstype", part.name)
+        }
+        Some("rootfs") => {
+            part.input_dir = Some(match rootfs_dir {
+                Some(d) => dirs.get(d).cloned().unwrap_or_else(|| base.join(d)),
+                None => dir("")?,
+            })
+        }
+        Some(plugin) => part.input_dir = Some(dir(plugin)?),
+    }
+
+    Ok((part, align))
+}
+
+/// Read a kickstart file. `dirs` holds the directories of `--rootfs-dir` by name, with the
+/// default one under an empty name. Paths in the file are relative to it.
+pub fn load(path: &Path, dirs: &HashMap<String, PathBuf>) -> anyhow::Result<Kickstart> {
+    let src = fs::read_to_string(path)
+        .map_err(|e| anyhow::anyhow!("cannot read {}: {e}", path.display()))?;
+    let base = path.parent().unwrap_or(Path::new(""));
+
+    let mut ks = Kickstart {
+        partitions: vec![],
+        gpt: None,
+        align: None,
+    };
+    let mut disk = None;
+
+    for (i, line) in src.lines().enumerate() {
+        let err = |e: anyhow::Error| anyhow::anyhow!("{}:{}: {e}", path.display(), i + 1);
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let words = words(line).map_err(err)?;
+        match words[0].as_str() {
+            "part" | "partition" => {
+                let number = ks.partitions.len() + 1;
+                let (part, align) =
+                    partition(&words[1..], number, base, dirs, &mut disk).map_err(err)?;
+                ks.partitions.push(part);
+                ks.align = ks.align.max(align);
+            }
+            "bootloader" => {
+                let (_, opts) = options(&words[1..]).map_err(err)?;
+                for (name, value) in opts {
+                    match (name, value) {
+                        ("ptable", Some("gpt")) => ks.gpt = Some(true),
+                        ("ptable", Some("msdos")) => ks.gpt = Some(false),
+                        ("ptable", v) => {
+                            return Err(err(anyhow::anyhow!(
+                                "unknown partition table `{}`",
+                                v.unwrap_or_default()
+                            )))
+                        }
+                        _ => debug!("Ignoring bootloader --{name}"),
+                    }
+                }
+            }
+            directive => return Err(err(anyhow::anyhow!("unsupported `{directive}`"))),
+        }
+    }
+
+    if ks.partitions.is_empty() {
+        anyhow::bail!("{} has no partitions", path.display());
+    }
+
+    Ok(ks)
+}
@@ -0,0 +1,173 @@
+//! Wrapping of bare filesystem images into partitioned disk images.
+
+use crate::table::{Gpt, ALIGN};
+use crate::{image, layout, part_type, DiskId, Filesystem, PartitionTable};
+use clap::Args;
+use log::*;
+use std::collections::BTreeMap;
+use std::fs::{File, OpenOptions};
+use std::io::{Seek, SeekFrom};
+use std::path::PathBuf;
+
+/// Sector size of the disk images made
+const SECTOR: u64 = 512;
+
+#[derive(Args, Debug)]
+pub struct WrapArgs {
+    /// Filesystem image without a partition table, such as one made with `-p none`
+    #[arg(long)]
+    image: PathBuf,
+    /// Partition table of the disk image
+    #[arg(value_enum, short, long, default_value = "gpt")]
+    partition_table: PartitionTable,
+    /// Output disk image path
+    #[arg(short, long)]
+    output_path: PathBuf,
+    /// GPT partition type, as a type GUID or an alias such as `esp`, `generic` or `root[-ARCH]`
+    #[arg(long, value_name = "GUID|ALIAS", default_value = "esp")]
+    gpt_type: part_type::GptType,
+    /// Name of the GPT partition
+    #[arg(long, value_name = "NAME", default_value = "EFI")]
+    part_label: String,
+    /// MBR system ID of the partition. Derived from the GPT type and filesystem if not set
+    #[arg(long, value_name = "ID|ALIAS", value_parser = part_type::parse_mbr_type)]
+    mbr_type: Option<u8>,
+    /// Architecture for `root` and `usr` partition types. Defaults to the host architecture
+    #[arg(value_enum, long)]
+    arch: Option<part_type::Arch>,
+    /// Set the MBR active flag, or the legacy BIOS bootable attribute on GPT
+    #[arg(short, long)]
+    bootable: bool,
+}
+
+pub fn run(args: &WrapArgs) -> anyhow::Result<()> {
+    if matches!(args.partition_table, PartitionTable::None) {
+        anyhow::bail!("wrapping needs a partition table other than none");
+    }
+
+    let mut input = File::open(&args.image)
+        .map_err(|e| anyhow::anyhow!("cannot open {}: {e}", args.image.display()))?;
+
+    if !matches!(image::read_partitions(&mut input)?.0, PartitionTable::None) {
+        anyhow::bail!("{} already has a partition table", args.image.display());
+    }
+
+    let found = image::detect_filesystem(&mut input)?;
+    if found.is_none() {
+        warn!("{} holds no known filesystem", args.image.display());
+    }
+
+    let part = layout::Partition {
+        name: args.part_label.clone(),
+        filesystem: match found {
+            Some("FAT") => Some(Filesystem::Vfat),
+            Some("exFAT") => Some(Filesystem::Exfat),
+            _ => None,
+        },
+        size: None,
+        gpt_type: args.gpt_type.clone(),
+        uuid: None,
+        mbr_type: args.mbr_type,
+        primary: None,
+        bootable: args.bootable,
+        attributes: 0,
+        input_dir: None,
+        image: None,
+        label: None,
+        files: vec![],
+        subvolumes: vec![],
+    };
+
+    let start = ALIGN;
+    let len = input.seek(SeekFrom::End(0))?.next_multiple_of(SECTOR);
+
+    let mut file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(&args.output_path)
+        .map_err(|e| anyhow::anyhow!("cannot create {}: {e}", args.output_path.display()))?;
+
+    match args.partition_table {
+        PartitionTable::Gpt | PartitionTable::Hybrid => {
+            let mut gpt = Gpt::new(BTreeMap::new(), SECTOR);
+            let disk_size = (start + len + gpt.tail()).next_multiple_of(ALIGN);
+
+            file.set_len(disk_size)?;
+            let mbr = gpt::mbr::ProtectiveMBR::with_lb_size(
+                u32::try_from(disk_size / SECTOR - 1).unwrap_or(u32::MAX),
+            );
+            mbr.overwrite_lba0(&mut file)?;
+
+            let flags = if part.bootable {
+                gpt::partition::PartitionAttributes::BOOTABLE.bits()
+            } else {
+                0
+            };
+
+            gpt.partitions.insert(
+                1,
+                gpt::partition::Partition {
+                    part_type_guid: part
+                        .gpt_type
+                        .to_type(args.arch.or_else(part_type::Arch::host))?,
+                    part_guid: crate::random_guid(&Default::default())?.parse()?,
+                    first_lba: start / SECTOR,
+                    last_lba: (start + len) / SECTOR - 1,
+                    flags,
+                    name: part.name.clone(),
+                },
+            );
+
+            gpt.write(&mut file, disk_size)?;
+
+            if matches!(args.partition_table, PartitionTable::Hybrid) {
+                let mut mbr = mbrman::MBR::new_from(
+                    &mut file,
+                    SECTOR as u32,
+                    DiskId::Random.signature(&Default::default())?,
+                )?;
+
+                mbr[1] = crate::mbr_entry(&part, start, len, SECTOR, crate::mbr_type(&part))?;
+                // Protective entry after the mirrored one, over the GPT header and entries
+                mbr[2] = mbrman::MBRPartitionEntry {
+                    boot: mbrman::BOOT_INACTIVE,
+                    first_chs: mbrman::CHS::empty(),
+                    sys: 0xee,
+                    last_chs: mbrman::CHS::empty(),
+                    starting_lba: 1,
+                    sectors: (gpt.first_usable() / SECTOR - 1) as u32,
+                };
+
+                mbr.write_into(&mut file)?;
+            }
+        }
+        PartitionTable::Mbr => {
+            file.set_len(start + len)?;
+
+            let mut mbr = mbrman::MBR::new_from(
+                &mut file,
+                SECTOR as u32,
+                DiskId::Random.signature(&Default::default())?,
+            )?;
+            mbr[1] = crate::mbr_entry(&part, start, len, SECTOR, crate::mbr_type(&part))?;
+            mbr.write_into(&mut file)?;
+        }
+        PartitionTable::None => unreachable!(),
+    }
+
+    debug!("Partition 1: start {start:x} size {len:x}");
+
+    input.rewind()?;
+    file.seek(SeekFrom::Start(start))?;
+    std::io::copy(&mut input, &mut file)?;
+
+    println!(
+        "{} wrapped into {}, partition 1 at offset {start}",
+        args.image.display(),
+        args.output_path.display()
+    );
+
+    Ok(())
+}
@@ -0,0 +1,1331 @@
+//! XFS v5 images.
+//!
+//! Uses 4K blocks and 512 byte inodes. All inodes live in allocation group 0, right after the
+//! internal log, and the data of files and directories is allocated sequentially behind them,
+//! continuing into the following allocation groups. Directories use the smallest of the short
+//! form, block, leaf and node formats they fit in. Symbolic links are kept in the inode, or in
+//! a block of their own when the target does not fit.
+
+use crate::tree::{Kind, Tree};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+const BLOCK_SIZE: u64 = 4096;
+const SECTOR_SIZE: usize = 512;
+const INODE_SIZE: usize = 512;
+const INODES_PER_BLOCK: u64 = BLOCK_SIZE / INODE_SIZE as u64;
+const CHUNK_INODES: u64 = 64;
+const CHUNK_BLOCKS: u64 = CHUNK_INODES / INODES_PER_BLOCK;
+/// Inode clusters, which chunks are aligned to
+const INODE_ALIGN: u64 = 4;
+/// Space for the data fork after the inode core
+const LITERAL_SIZE: usize = INODE_SIZE - 176;
+const MAX_INODE_EXTENTS: usize = LITERAL_SIZE / 16;
+const MAX_EXTENT_BLOCKS: u64 = (1 << 21) - 1;
+
+const LOG_BLOCKS: u64 = 1024;
+const LOG_MAGIC: u32 = 0xFEED_BABE;
+/// Size of the log record header, which takes up a sector
+const LOG_HEADER_LEN: usize = 328;
+const LOG_HEADER_CYCLE_SIZE: u32 = 32 << 10;
+const LOG_CLIENT: u8 = 0xaa;
+const LOG_UNMOUNT_TRANS: u8 = 0x20;
+const LOG_UNMOUNT_TYPE: u16 = 0x556e;
+/// Operation header and unmount type
+const LOG_UNMOUNT_LEN: usize = 12 + 8;
+/// Blocks handed to the free list of every allocation group
+const AGFL_BLOCKS: u64 = 4;
+const AGFL_SIZE: usize = (SECTOR_SIZE - 36) / 4;
+/// Allocation groups are between 16MiB and 1TiB, but the last one may be shorter
+const MIN_AG_BLOCKS: u64 = (16 << 20) / BLOCK_SIZE;
+const MAX_AG_BLOCKS: u64 = (1 << 40) / BLOCK_SIZE;
+const MIN_LAST_AG_BLOCKS: u64 = 64;
+
+const SB_MAGIC: u32 = 0x5846_5342;
+const AGF_MAGIC: u32 = 0x5841_4746;
+const AGI_MAGIC: u32 = 0x5841_4749;
+const AGFL_MAGIC: u32 = 0x5841_464C;
+const BNO_MAGIC: u32 = 0x4142_3342;
+const CNT_MAGIC: u32 = 0x4142_3343;
+const IBT_MAGIC: u32 = 0x4941_4233;
+const INODE_MAGIC: u16 = 0x494E;
+const DIR_BLOCK_MAGIC: u32 = 0x5844_4233;
+const DIR_DATA_MAGIC: u32 = 0x5844_4433;
+const DIR_FREE_MAGIC: u32 = 0x5844_4633;
+const DIR_LEAF1_MAGIC: u16 = 0x3DF1;
+const DIR_LEAFN_MAGIC: u16 = 0x3DFF;
+const DA_NODE_MAGIC: u16 = 0x3EBE;
+const SYMLINK_MAGIC: u32 = 0x5853_4C4D;
+/// Header of remote symbolic link blocks
+const SYMLINK_HDR: usize = 56;
+const MAX_SYMLINK_LEN: usize = 1024;
+
+/// NLINK, ALIGN, LOGV2, EXTFLG, DIRV2 and MOREBITS in a version 5 superblock
+const SB_VERSION: u16 = 0xB4A5;
+/// LAZYSBCOUNT, ATTR2, PROJID32 and CRC
+const SB_FEATURES2: u32 = 0x18A;
+const SB_INCOMPAT_FTYPE: u32 = 0x1;
+
+const NULL_AGBLOCK: u32 = u32::MAX;
+const NULL_AGINO: u32 = u32::MAX;
+const NULL_FSINO: u64 = u64::MAX;
+
+const FMT_LOCAL: u8 = 1;
+const FMT_EXTENTS: u8 = 2;
+
+const FT_REG_FILE: u8 = 1;
+const FT_DIR: u8 = 2;
+const FT_SYMLINK: u8 = 7;
+
+const S_IFDIR: u16 = 0o040000;
+const S_IFREG: u16 = 0o100000;
+const S_IFLNK: u16 = 0o120000;
+
+/// Directory block numbers of the leaf and free index sections
+const DIR_LEAF_DABLK: u64 = (32 << 30) / BLOCK_SIZE;
+const DIR_FREE_DABLK: u64 = (64 << 30) / BLOCK_SIZE;
+const DIR_HDR_SIZE: usize = 64;
+/// Offset of the first entry after `.` and `..`
+const DIR_FIRST_OFFSET: usize = DIR_HDR_SIZE + 16 + 16;
+const LEAF_ENTRIES: usize = (BLOCK_SIZE as usize - DIR_HDR_SIZE) / 8;
+const FREE_ENTRIES: usize = (BLOCK_SIZE as usize - DIR_HDR_SIZE) / 2;
+
+/// Short form btree block header, with CRC
+const SBTREE_HDR: usize = 56;
+
+#[derive(Clone, Debug, Default)]
+pub struct Options {
+    pub label: Option<String>,
+}
+
+const fn crc32c_table() -> [u32; 256] {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut c = i as u32;
+        let mut k = 0;
+        while k < 8 {
+            c = if c & 1 != 0 {
+                0x82F6_3B78 ^ (c >> 1)
+            } else {
+                c >> 1
+            };
+            k += 1;
+        }
+        table[i] = c;
+        i += 1;
+    }
+    table
+}
+
+static CRC32C: [u32; 256] = crc32c_table();
+
+fn crc32c_update(crc: u32, data: &[u8]) -> u32 {
+    data.iter().fold(crc, |c, &b| {
+        CRC32C[((c ^ b as u32) & 0xff) as usize] ^ (c >> 8)
+    })
+}
+
+fn crc32c(data: &[u8]) -> u32 {
+    !crc32c_update(!0, data)
+}
+
+/// Compute the checksum of a metadata structure into its little endian field at `off`.
+fn set_crc(buf: &mut [u8], off: usize) {
+    buf[off..off + 4].fill(0);
+    let crc = crc32c(buf);
+    buf[off..off + 4].copy_from_slice(&crc.to_le_bytes());
+}
+
+fn be16(buf: &mut [u8], off: usize, v: u16) {
+    buf[off..off + 2].copy_from_slice(&v.to_be_bytes());
+}
+
+fn be32(buf: &mut [u8], off: usize, v: u32) {
+    buf[off..off + 4].copy_from_slice(&v.to_be_bytes());
+}
+
+fn be64(buf: &mut [u8], off: usize, v: u64) {
+    buf[off..off + 8].copy_from_slice(&v.to_be_bytes());
+}
+
+/// Name hash of directory entries.
+fn da_hash(name: &[u8]) -> u32 {
+    let n = |i: usize| name[i] as u32;
+    let mut hash = 0u32;
+    let mut i = 0;
+    while name.len() - i >= 4 {
+        hash = (n(i) << 21) ^ (n(i + 1) << 14) ^ (n(i + 2) << 7) ^ n(i + 3) ^ hash.rotate_left(28);
+        i += 4;
+    }
+    match name.len() - i {
+        3 => (n(i) << 14) ^ (n(i + 1) << 7) ^ n(i + 2) ^ hash.rotate_left(21),
+        2 => (n(i) << 7) ^ n(i + 1) ^ hash.rotate_left(14),
+        1 => n(i) ^ hash.rotate_left(7),
+        _ => hash,
+    }
+}
+
+/// Blocks of a short form btree holding `recs` records, at least a single empty leaf.
+fn btree_blocks(recs: u64, leaf_max: u64, node_max: u64) -> u64 {
+    let mut level = recs.div_ceil(leaf_max).max(1);
+    let mut total = level;
+    while level > 1 {
+        level = level.div_ceil(node_max);
+        total += level;
+    }
+    total
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Geometry {
+    dblocks: u64,
+    agcount: u64,
+    agblocks: u64,
+    agblklog: u32,
+}
+
+impl Geometry {
+    /// Split `dblocks` into allocation groups, the first of which holds at least `ag0` blocks.
+    fn new(dblocks: u64, ag0: u64) -> anyhow::Result<Self> {
+        let mut agcount = if dblocks >= 4 * MIN_AG_BLOCKS { 4 } else { 1 };
+        agcount = agcount.max(dblocks.div_ceil(MAX_AG_BLOCKS));
+
+        loop {
+            let agblocks = dblocks.div_ceil(agcount);
+            let last = dblocks - (agcount - 1) * agblocks;
+
+            if agcount > 1 && (agblocks < ag0.max(MIN_AG_BLOCKS) || last < MIN_LAST_AG_BLOCKS) {
+                agcount -= 1;
+                continue;
+            }
+            if agblocks > MAX_AG_BLOCKS || agblocks < ag0.max(MIN_AG_BLOCKS) {
+                anyhow::bail!(
+                    "XFS needs at least {} bytes",
+                    ag0.max(MIN_AG_BLOCKS) * BLOCK_SIZE
+                );
+            }
+
+            return Ok(Self {
+                dblocks,
+                agcount,
+                agblocks,
+                agblklog: 32 - (agblocks as u32 - 1).leading_zeros(),
+            });
+        }
+    }
+
+    fn ag_len(&self, agno: u64) -> u64 {
+        self.agblocks.min(self.dblocks - agno * self.agblocks)
+    }
+
+    fn fsbno(&self, agno: u64, agbno: u64) -> u64 {
+        (agno << self.agblklog) | agbno
+    }
+
+    /// Byte offset of a filesystem block.
+    fn offset(&self, fsbno: u64) -> u64 {
+        let agno = fsbno >> self.agblklog;
+        let agbno = fsbno & ((1 << self.agblklog) - 1);
+        (agno * self.agblocks + agbno) * BLOCK_SIZE
+    }
+
+    /// Sector address of a block inside of an allocation group.
+    fn daddr(&self, agno: u64, agbno: u64) -> u64 {
+        (agno * self.agblocks + agbno) * BLOCK_SIZE / 512
+    }
+}
+
+/// Mapping of file blocks starting at `offset` to filesystem blocks.
+#[derive(Clone, Copy, Debug)]
+struct Extent {
+    offset: u64,
+    block: u64,
+    len: u64,
+}
+
+/// Map runs of file blocks, as `(offset, len)`, onto allocated runs of filesystem blocks.
+fn map_extents(logical: &[(u64, u64)], physical: &[(u64, u64)]) -> Vec<Extent> {
+    let mut out = vec![];
+    let mut phys = physical.iter().copied();
+    let mut cur = phys.next();
+
+    for &(mut offset, mut len) in logical {
+        while len > 0 {
+            let (block, avail) = cur.unwrap();
+            let n = len.min(avail).min(MAX_EXTENT_BLOCKS);
+            out.push(Extent {
+                offset,
+                block,
+                len: n,
+            });
+            offset += n;
+            len -= n;
+            cur = if avail == n {
+                phys.next()
+            } else {
+                Some((block + n, avail - n))
+            };
+        }
+    }
+
+    out
+}
+
+struct DirEntry<'a> {
+    name: &'a [u8],
+    ino: u64,
+    ftype: u8,
+}
+
+/// Size of an entry in a directory data block.
+fn entry_size(name_len: usize) -> usize {
+    (8 + 1 + name_len + 1 + 2).next_multiple_of(8)
+}
+
+enum Dir {
+    /// Short form directory, stored in the inode
+    Local(Vec<u8>),
+    /// Directory blocks by their block number in the directory, and the size of the data section
+    Blocks(Vec<(u64, Vec<u8>)>, u64),
+}
+
+/// Common header of the directory data, block and free index blocks.
+fn dir3_header(block: &mut [u8], magic: u32, owner: u64, uuid: &[u8; 16]) {
+    be32(block, 0, magic);
+    block[24..40].copy_from_slice(uuid);
+    be64(block, 40, owner);
+}
+
+/// Common header of the directory leaf and node blocks.
+fn da3_header(block: &mut [u8], magic: u16, forw: u64, back: u64, owner: u64, uuid: &[u8; 16]) {
+    be32(block, 0, forw as u32);
+    be32(block, 4, back as u32);
+    be16(block, 8, magic);
+    block[32..48].copy_from_slice(uuid);
+    be64(block, 48, owner);
+}
+
+/// Fill in the location and checksum of a directory block once it is placed.
+fn finish_dir_block(block: &mut [u8], daddr: u64) {
+    let magic = u32::from_be_bytes(block[0..4].try_into().unwrap());
+    if [DIR_BLOCK_MAGIC, DIR_DATA_MAGIC, DIR_FREE_MAGIC].contains(&magic) {
+        be64(block, 8, daddr);
+        set_crc(block, 4);
+    } else {
+        be64(block, 16, daddr);
+        set_crc(block, 12);
+    }
+}
+
+/// Data block holding `entries` at the given offsets, with free space up to `end`.
+fn data_block(
+    magic: u32,
+    entries: &[(&DirEntry, usize)],
+    end: usize,
+    owner: u64,
+    uuid: &[u8; 16],
+) -> (Vec<u8>, u16) {
+    let mut block = vec![0u8; BLOCK_SIZE as usize];
+    dir3_header(&mut block, magic, owner, uuid);
+
+    let mut pos = DIR_HDR_SIZE;
+    for &(e, off) in entries {
+        be64(&mut block, off, e.ino);
+        block[off + 8] = e.name.len() as u8;
+        block[off + 9..off + 9 + e.name.len()].copy_from_slice(e.name);
+        block[off + 9 + e.name.len()] = e.ftype;
+        let size = entry_size(e.name.len());
+        be16(&mut block, off + size - 2, off as u16);
+        pos = off + size;
+    }
+
+    // All free space is a single region at the end
+    let free = end - pos;
+    if free > 0 {
+        be16(&mut block, pos, 0xffff);
+        be16(&mut block, pos + 2, free as u16);
+        be16(&mut block, end - 2, pos as u16);
+        be16(&mut block, 48, pos as u16);
+        be16(&mut block, 50, free as u16);
+    }
+
+    (block, free as u16)
+}
+
+/// Lay out a directory in the smallest format it fits in.
+fn dir(children: &[DirEntry], ino: u64, parent: u64, uuid: &[u8; 16]) -> anyhow::Result<Dir> {
+    // Short form, with 4 byte inode numbers as every inode is in the first allocation group
+    let sf_size = 6 + children.iter().map(|e| 8 + e.name.len()).sum::<usize>();
+    if sf_size <= LITERAL_SIZE {
+        let mut sf = vec![0u8; sf_size];
+        sf[0] = children.len() as u8;
+        be32(&mut sf, 2, parent as u32);
+
+        let (mut pos, mut offset) = (6, DIR_FIRST_OFFSET);
+        for e in children {
+            sf[pos] = e.name.len() as u8;
+            be16(&mut sf, pos + 1, offset as u16);
+            sf[pos + 3..pos + 3 + e.name.len()].copy_from_slice(e.name);
+            sf[pos + 3 + e.name.len()] = e.ftype;
+            be32(&mut sf, pos + 4 + e.name.len(), e.ino as u32);
+            pos += 8 + e.name.len();
+            offset += entry_size(e.name.len());
+        }
+
+        return Ok(Dir::Local(sf));
+    }
+
+    let dot = [
+        DirEntry {
+            name: b".",
+            ino,
+            ftype: FT_DIR,
+        },
+        DirEntry {
+            name: b"..",
+            ino: parent,
+            ftype: FT_DIR,
+        },
+    ];
+    let entries = dot.iter().chain(children).collect::<Vec<_>>();
+
+    // Single block, with the hash index at its end
+    let used = DIR_HDR_SIZE
+        + entries
+            .iter()
+            .map(|e| entry_size(e.name.len()))
+            .sum::<usize>()
+        + entries.len() * 8
+        + 8;
+    if used <= BLOCK_SIZE as usize {
+        let mut placed = vec![];
+        let mut off = DIR_HDR_SIZE;
+        for e in &entries {
+            placed.push((*e, off));
+            off += entry_size(e.name.len());
+        }
+
+        let leaf_start = BLOCK_SIZE as usize - 8 - entries.len() * 8;
+        let (mut block, _) = data_block(DIR_BLOCK_MAGIC, &placed, leaf_start, ino, uuid);
+
+        let mut leaf = placed
+            .iter()
+            .map(|(e, off)| (da_hash(e.name), *off as u32 / 8))
+            .collect::<Vec<_>>();
+        leaf.sort();
+        for (i, (hash, addr)) in leaf.iter().enumerate() {
+            be32(&mut block, leaf_start + i * 8, *hash);
+            be32(&mut block, leaf_start + i * 8 + 4, *addr);
+        }
+        be32(&mut block, BLOCK_SIZE as usize - 8, entries.len() as u32);
+
+        return Ok(Dir::Blocks(vec![(0, block)], BLOCK_SIZE));
+    }
+
+    // Data blocks, then a hash index in the leaf section
+    let mut data: Vec<Vec<(&DirEntry, usize)>> = vec![vec![]];
+    let mut off = DIR_HDR_SIZE;
+    for e in &entries {
+        let size = entry_size(e.name.len());
+        if off + size > BLOCK_SIZE as usize {
+            data.push(vec![]);
+            off = DIR_HDR_SIZE;
+        }
+        data.last_mut().unwrap().push((*e, off));
+        off += size;
+    }
+
+    let mut blocks = vec![];
+    let mut bests = vec![];
+    let mut leaf = vec![];
+    for (db, placed) in data.iter().enumerate() {
+        let (block, best) = data_block(DIR_DATA_MAGIC, placed, BLOCK_SIZE as usize, ino, uuid);
+        blocks.push((db as u64, block));
+        bests.push(best);
+        for (e, off) in placed {
+            let addr = (db as u64 * BLOCK_SIZE + *off as u64) / 8;
+            leaf.push((da_hash(e.name), addr as u32));
+        }
+    }
+    leaf.sort();
+    let size = data.len() as u64 * BLOCK_SIZE;
+
+    let write_leaf = |block: &mut [u8], ents: &[(u32, u32)]| {
+        be16(block, 56, ents.len() as u16);
+        for (i, (hash, addr)) in ents.iter().enumerate() {
+            be32(block, DIR_HDR_SIZE + i * 8, *hash);
+            be32(block, DIR_HDR_SIZE + i * 8 + 4, *addr);
+        }
+    };
+
+    if DIR_HDR_SIZE + leaf.len() * 8 + bests.len() * 2 + 4 <= BLOCK_SIZE as usize {
+        let mut block = vec![0u8; BLOCK_SIZE as usize];
+        da3_header(&mut block, DIR_LEAF1_MAGIC, 0, 0, ino, uuid);
+        write_leaf(&mut block, &leaf);
+
+        let tail = BLOCK_SIZE as usize - 4;
+        let start = tail - bests.len() * 2;
+        for (i, best) in bests.iter().enumerate() {
+            be16(&mut block, start + i * 2, *best);
+        }
+        be32(&mut block, tail, bests.len() as u32);
+
+        blocks.push((DIR_LEAF_DABLK, block));
+        return Ok(Dir::Blocks(blocks, size));
+    }
+
+    // Node format: a single node block over leaf blocks, and free index blocks
+    let leaves = leaf.chunks(LEAF_ENTRIES).collect::<Vec<_>>();
+    if leaves.len() > LEAF_ENTRIES {
+        anyhow::bail!("directory has too many entries for XFS images");
+    }
+
+    let mut node = vec![0u8; BLOCK_SIZE as usize];
+    da3_header(&mut node, DA_NODE_MAGIC, 0, 0, ino, uuid);
+    be16(&mut node, 56, leaves.len() as u16);
+    be16(&mut node, 58, 1);
+
+    let mut leaf_blocks = vec![];
+    for (i, ents) in leaves.iter().enumerate() {
+        let dablk = DIR_LEAF_DABLK + 1 + i as u64;
+        let forw = if i + 1 < leaves.len() { dablk + 1 } else { 0 };
+        let back = if i > 0 { dablk - 1 } else { 0 };
+
+        let mut block = vec![0u8; BLOCK_SIZE as usize];
+        da3_header(&mut block, DIR_LEAFN_MAGIC, forw, back, ino, uuid);
+        write_leaf(&mut block, ents);
+        leaf_blocks.push((dablk, block));
+
+        be32(&mut node, DIR_HDR_SIZE + i * 8, ents.last().unwrap().0);
+        be32(&mut node, DIR_HDR_SIZE + i * 8 + 4, dablk as u32);
+    }
+    blocks.push((DIR_LEAF_DABLK, node));
+    blocks.extend(leaf_blocks);
+
+    for (i, chunk) in bests.chunks(FREE_ENTRIES).enumerate() {
+        let mut block = vec![0u8; BLOCK_SIZE as usize];
+        dir3_header(&mut block, DIR_FREE_MAGIC, ino, uuid);
+        be32(&mut block, 48, (i * FREE_ENTRIES) as u32);
+        be32(&mut block, 52, chunk.len() as u32);
+        be32(&mut block, 56, chunk.len() as u32);
+        for (j, best) in chunk.iter().enumerate() {
+            be16(&mut block, DIR_HDR_SIZE + j * 2, *best);
+        }
+        blocks.push((DIR_FREE_DABLK + i as u64, block));
+    }
+
+    Ok(Dir::Blocks(blocks, size))
+}
+
+/// Hands out filesystem blocks in order, skipping the headers of every allocation group.
+struct Allocator<'a> {
+    geo: &'a Geometry,
+    headers: &'a [u64],
+    agno: u64,
+    agbno: u64,
+    /// Used blocks of every allocation group, as `(agbno, len)`
+    used: Vec<Vec<(u64, u64)>>,
+    /// Blocks that did not fit
+    overflow: u64,
+}
+
+impl Allocator<'_> {
+    fn mark(&mut self, agno: u64, agbno: u64, len: u64) {
+        let used = &mut self.used[agno as usize];
+        match used.last_mut() {
+            Some((start, l)) if *start + *l == agbno => *l += len,
+            _ => used.push((agbno, len)),
+        }
+    }
+
+    /// Allocate `n` blocks, as runs of `(fsbno, len)` that do not cross allocation groups.
+    fn alloc(&mut self, mut n: u64) -> Vec<(u64, u64)> {
+        let mut out = vec![];
+
+        while n > 0 {
+            if self.agno >= self.geo.agcount {
+                self.overflow += n;
+                break;
+            }
+
+            let avail = self.geo.ag_len(self.agno) - self.agbno;
+            if avail == 0 {
+                self.agno += 1;
+                if let Some(&header) = self.headers.get(self.agno as usize) {
+                    self.agbno = header;
+                }
+                continue;
+            }
+
+            let take = n.min(avail);
+            out.push((self.geo.fsbno(self.agno, self.agbno), take));
+            self.mark(self.agno, self.agbno, take);
+            self.agbno += take;
+            n -= take;
+        }
+
+        out
+    }
+}
+
+struct Layout {
+    geo: Geometry,
+    /// Blocks at the start of every allocation group taken by its headers and btrees
+    headers: Vec<u64>,
+    log_start: u64,
+    chunk_start: u64,
+    chunks: u64,
+    /// Inode number of every node
+    inos: Vec<u64>,
+    dirs: Vec<Option<Dir>>,
+    extents: Vec<Vec<Extent>>,
+    used: Vec<Vec<(u64, u64)>>,
+    overflow: u64,
+}
+
+/// Inode slots taken before the tree: the realtime bitmap and summary after the root
+const RESERVED_INODES: u64 = 2;
+
+impl Layout {
+    fn new(tree: &Tree, dblocks: u64, uuid: &[u8; 16]) -> anyhow::Result<Self> {
+        for node in &tree.nodes[1..] {
+            if node.name.len() > 255 {
+                anyhow::bail!(
+                    "XFS names are limited to 255 bytes: {}",
+                    node.path.display()
+                );
+            }
+        }
+
+        let inodes = tree.nodes.len() as u64 + RESERVED_INODES;
+        let chunks = inodes.div_ceil(CHUNK_INODES);
+        let inobt_blocks = btree_blocks(chunks, 252, 505);
+
+        // Superblock and friends, the free space btrees, the inode btree and the free list
+        let header = |agno: u64| {
+            let inobt = if agno == 0 { inobt_blocks } else { 1 };
+            3 + inobt + AGFL_BLOCKS
+        };
+        let log_start = header(0);
+        let chunk_start = (log_start + LOG_BLOCKS).next_multiple_of(CHUNK_BLOCKS);
+        let ag0 = chunk_start + chunks * CHUNK_BLOCKS;
+
+        let geo = Geometry::new(dblocks, ag0)?;
+        let headers = (0..geo.agcount).map(header).collect::<Vec<_>>();
+
+        let slot = |idx: usize| {
+            if idx == 0 {
+                0
+            } else {
+                idx as u64 + RESERVED_INODES
+            }
+        };
+        let inos = (0..tree.nodes.len())
+            .map(|idx| chunk_start * INODES_PER_BLOCK + slot(idx))
+            .collect::<Vec<_>>();
+
+        let mut alloc = Allocator {
+            geo: &geo,
+            headers: &headers,
+            agno: 0,
+            agbno: ag0,
+            used: vec![vec![]; geo.agcount as usize],
+            overflow: 0,
+        };
+        for (agno, &h) in headers.iter().enumerate() {
+            alloc.mark(agno as u64, 0, h);
+        }
+        alloc.mark(0, log_start, LOG_BLOCKS);
+        alloc.mark(0, chunk_start, chunks * CHUNK_BLOCKS);
+
+        let mut dirs = vec![];
+        let mut extents = vec![];
+
+        for (idx, node) in tree.nodes.iter().enumerate() {
+            match &node.kind {
+                Kind::Dir(children) => {
+                    let mut children = children
+                        .iter()
+                        .map(|&c| DirEntry {
+                            name: tree.nodes[c].name.as_bytes(),
+                            ino: inos[c],
+                            ftype: match tree.nodes[c].kind {
+                                Kind::Dir(_) => FT_DIR,
+                                Kind::File { .. } => FT_REG_FILE,
+                                Kind::Symlink(_) => FT_SYMLINK,
+                            },
+                        })
+                        .collect::<Vec<_>>();
+                    children.sort_by(|a, b| a.name.cmp(b.name));
+
+                    let d = dir(&children, inos[idx], inos[node.parent], uuid)?;
+                    let mut e = vec![];
+
+                    if let Dir::Blocks(blocks, _) = &d {
+                        let physical = alloc.alloc(blocks.len() as u64);
+                        if alloc.overflow == 0 {
+                            // Blocks are sorted, and contiguous within each section
+                            let mut logical: Vec<(u64, u64)> = vec![];
+                            for (dablk, _) in blocks {
+                                match logical.last_mut() {
+                                    Some((start, len)) if *start + *len == *dablk => *len += 1,
+                                    _ => logical.push((*dablk, 1)),
+                                }
+                            }
+                            e = map_extents(&logical, &physical);
+                        }
+                    }
+
+                    dirs.push(Some(d));
+                    extents.push(e);
+                }
+                Kind::File { len, .. } => {
+                    let blocks = len.div_ceil(BLOCK_SIZE);
+                    let physical = alloc.alloc(blocks);
+                    let e = if alloc.overflow == 0 {
+                        map_extents(&[(0, blocks)], &physical)
+                    } else {
+                        vec![]
+                    };
+
+                    dirs.push(None);
+                    extents.push(e);
+                }
+                Kind::Symlink(target) => {
+                    if target.len() > MAX_SYMLINK_LEN {
+                        anyhow::bail!(
+                            "{} links to a target longer than {MAX_SYMLINK_LEN} bytes",
+                            node.path.display()
+                        );
+                    }
+                    let mut e = vec![];
+                    if target.len() > LITERAL_SIZE {
+                        let physical = alloc.alloc(1);
+                        if alloc.overflow == 0 {
+                            e = map_extents(&[(0, 1)], &physical);
+                        }
+                    }
+
+                    dirs.push(None);
+                    extents.push(e);
+                }
+            }
+
+            if extents[idx].len() > MAX_INODE_EXTENTS {
+                anyhow::bail!(
+                    "{} needs more than {MAX_INODE_EXTENTS} extents",
+                    node.path.display()
+                );
+            }
+        }
+
+        let used = alloc.used;
+        let overflow = alloc.overflow;
+
+        Ok(Self {
+            geo,
+            headers,
+            log_start,
+            chunk_start,
+            chunks,
+            inos,
+            dirs,
+            extents,
+            used,
+            overflow,
+        })
+    }
+
+    /// Free extents of an allocation group, as `(agbno, len)`.
+    fn free(&self, agno: u64) -> Vec<(u32, u32)> {
+        let mut used = self.used[agno as usize].clone();
+        used.sort();
+
+        let mut free = vec![];
+        let mut pos = 0;
+        for (start, len) in used.into_iter().chain([(self.geo.ag_len(agno), 0)]) {
+            if start > pos {
+                free.push((pos as u32, (start - pos) as u32));
+            }
+            pos = pos.max(start + len);
+        }
+        free
+    }
+}
+
+/// Smallest filesystem size the tree fits in.
+pub fn estimate_size(tree: &Tree) -> anyhow::Result<u64> {
+    let content = tree
+        .nodes
+        .iter()
+        .map(|n| match &n.kind {
+            Kind::File { len, .. } => len.div_ceil(BLOCK_SIZE),
+            Kind::Dir(_) => 1,
+            Kind::Symlink(target) => (target.len() > LITERAL_SIZE) as u64,
+        })
+        .sum::<u64>();
+    let chunks = (tree.nodes.len() as u64 + RESERVED_INODES).div_ceil(CHUNK_INODES);
+    let inobt = btree_blocks(chunks, 252, 505);
+
+    // Leave some free space, so the filesystem can be used
+    let mut blocks = content + content / 16 + chunks * CHUNK_BLOCKS + inobt + LOG_BLOCKS + 256;
+    blocks = blocks.max(MIN_AG_BLOCKS);
+
+    loop {
+        let layout = Layout::new(tree, blocks, &[0; 16])?;
+        if layout.overflow == 0 {
+            return Ok(blocks * BLOCK_SIZE);
+        }
+        blocks += layout.overflow.max(blocks / 16);
+    }
+}
+
+/// Short form btree blocks over `recs`, placed from `first` on. Returns the blocks and levels.
+fn btree(
+    magic: u32,
+    recs: &[Vec<u8>],
+    key_len: usize,
+    first: u64,
+    agno: u64,
+    geo: &Geometry,
+    uuid: &[u8; 16],
+) -> (Vec<(u64, Vec<u8>)>, u32) {
+    let leaf_max = (BLOCK_SIZE as usize - SBTREE_HDR) / recs.first().map_or(1, |r| r.len());
+    let node_max = (BLOCK_SIZE as usize - SBTREE_HDR) / (key_len + 4);
+    let mut out = vec![];
+    let mut next = first;
+    let mut level = 0u16;
+
+    // Record or key-pointer pairs of the current level
+    let mut items = recs.iter().map(|r| (r.clone(), 0)).collect::<Vec<_>>();
+
+    loop {
+        let max = if level == 0 { leaf_max } else { node_max };
+        let groups = if items.is_empty() {
+            vec![&items[..]]
+        } else {
+            items.chunks(max).collect()
+        };
+        let start = next;
+        let mut parents = vec![];
+
+        for (i, group) in groups.iter().enumerate() {
+            let agbno = start + i as u64;
+            let mut b = vec![0u8; BLOCK_SIZE as usize];
+            be32(&mut b, 0, magic);
+            be16(&mut b, 4, level);
+            be16(&mut b, 6, group.len() as u16);
+            let left = if i > 0 {
+                agbno as u32 - 1
+            } else {
+                NULL_AGBLOCK
+            };
+            let right = if i + 1 < groups.len() {
+                agbno as u32 + 1
+            } else {
+                NULL_AGBLOCK
+            };
+            be32(&mut b, 8, left);
+            be32(&mut b, 12, right);
+            be64(&mut b, 16, geo.daddr(agno, agbno));
+            b[32..48].copy_from_slice(uuid);
+            be32(&mut b, 48, agno as u32);
+
+            for (j, (item, ptr)) in group.iter().enumerate() {
+                if level == 0 {
+                    let off = SBTREE_HDR + j * item.len();
+                    b[off..off + item.len()].copy_from_slice(item);
+                } else {
+                    let off = SBTREE_HDR + j * key_len;
+                    b[off..off + key_len].copy_from_slice(&item[..key_len]);
+                    be32(&mut b, SBTREE_HDR + node_max * key_len + j * 4, *ptr as u32);
+                }
+            }
+            set_crc(&mut b, 52);
+
+            if let Some((first, _)) = group.first() {
+                parents.push((first[..key_len].to_vec(), agbno));
+            }
+            out.push((agbno, b));
+        }
+
+        next += groups.len() as u64;
+        level += 1;
+
+        if groups.len() == 1 {
+            return (out, level as u32);
+        }
+        items = parents;
+    }
+}
+
+struct Inode {
+    mode: u16,
+    uid: u32,
+    gid: u32,
+    nlink: u32,
+    size: u64,
+    nblocks: u64,
+    mtime: i64,
+    format: u8,
+    nextents: u32,
+}
+
+fn dinode(ino: u64, uuid: &[u8; 16], inode: Option<&Inode>, fork: &[u8]) -> Vec<u8> {
+    let mut d = vec![0u8; INODE_SIZE];
+    be16(&mut d, 0, INODE_MAGIC);
+    d[4] = 3;
+    be32(&mut d, 96, NULL_AGINO);
+    be64(&mut d, 152, ino);
+    d[160..176].copy_from_slice(uuid);
+
+    if let Some(i) = inode {
+        let sec = i.mtime.clamp(i32::MIN as i64, i32::MAX as i64) as u32;
+        be16(&mut d, 2, i.mode);
+        d[5] = i.format;
+        be32(&mut d, 8, i.uid);
+        be32(&mut d, 12, i.gid);
+        be32(&mut d, 16, i.nlink);
+        for t in [32, 40, 48, 144] {
+            be32(&mut d, t, sec);
+        }
+        be64(&mut d, 56, i.size);
+        be64(&mut d, 64, i.nblocks);
+        be32(&mut d, 76, i.nextents);
+        d[83] = FMT_EXTENTS;
+        be32(&mut d, 92, 1);
+        d[176..176 + fork.len()].copy_from_slice(fork);
+    }
+
+    set_crc(&mut d, 100);
+    d
+}
+
+fn extent_list(extents: &[Extent]) -> Vec<u8> {
+    let mut out = vec![];
+    for e in extents {
+        out.extend(((e.offset << 9) | (e.block >> 43)).to_be_bytes());
+        out.extend(((e.block << 21) | e.len).to_be_bytes());
+    }
+    out
+}
+
+/// Log record of a single unmount operation, in the first cycle at the start of the log.
+fn unmount_record(uuid: &[u8; 16]) -> Vec<u8> {
+    let mut rec = vec![0u8; 2 * SECTOR_SIZE];
+    let (header, data) = rec.split_at_mut(SECTOR_SIZE);
+
+    // Operation header, then the unmount type
+    be32(data, 0, 0xb0c0_d0d0);
+    be32(data, 4, 8);
+    data[8] = LOG_CLIENT;
+    data[9] = LOG_UNMOUNT_TRANS;
+    be16(data, 12, LOG_UNMOUNT_TYPE);
+
+    let lsn = 1 << 32;
+    be32(header, 0, LOG_MAGIC);
+    be32(header, 4, 1);
+    be32(header, 8, 2);
+    be32(header, 12, LOG_UNMOUNT_LEN as u32);
+    be64(header, 16, lsn);
+    be64(header, 24, lsn);
+    be32(header, 36, u32::MAX);
+    be32(header, 40, 1);
+    // The first word of every data sector is replaced by the cycle, and kept in the header
+    header[44..48].copy_from_slice(&data[0..4]);
+    be32(data, 0, 1);
+    be32(header, 300, 1);
+    header[304..320].copy_from_slice(uuid);
+    be32(header, 320, LOG_HEADER_CYCLE_SIZE);
+
+    let crc = crc32c_update(!0, &header[..LOG_HEADER_LEN]);
+    let crc = !crc32c_update(crc, &data[..LOG_UNMOUNT_LEN]);
+    header[32..36].copy_from_slice(&crc.to_le_bytes());
+
+    rec
+}
+
+fn write_at<W: Write + Seek>(out: &mut W, offset: u64, data: &[u8]) -> io::Result<()> {
+    out.seek(SeekFrom::Start(offset))?;
+    out.write_all(data)
+}
+
+/// Write the image into `out`, which spans `size` bytes.
+///
+/// `on_file` is called with the image path and length of every file written.
+pub fn write<W: Write + Seek>(
+    out: &mut W,
+    size: u64,
+    tree: &Tree,
+    opts: &Options,
+    ctx: &crate::BuildContext,
+    on_file: &mut dyn FnMut(&Path, u64),
+) -> anyhow::Result<()> {
+    let label = opts.label.as_deref().unwrap_or_default();
+    if label.len() > 12 {
+        anyhow::bail!("XFS volume label is longer than 12 bytes");
+    }
+
+    let mut uuid = [0u8; 16];
+    ctx.random.fill(&mut uuid)?;
+    uuid[6] = (uuid[6] & 0x0f) | 0x40;
+    uuid[8] = (uuid[8] & 0x3f) | 0x80;
+
+    let mut layout = Layout::new(tree, size / BLOCK_SIZE, &uuid)?;
+    if layout.overflow > 0 {
+        anyhow::bail!(
+            "XFS image needs {} more blocks than the {size} bytes available",
+            layout.overflow
+        );
+    }
+    let geo = layout.geo;
+
+    // Inodes, all in the chunks of the first allocation group
+    let mut inodes = vec![0u8; (layout.chunks * CHUNK_BLOCKS * BLOCK_SIZE) as usize];
+    let first_ino = layout.chunk_start * INODES_PER_BLOCK;
+    for slot in 0..layout.chunks * CHUNK_INODES {
+        let d = dinode(first_ino + slot, &uuid, None, &[]);
+        let off = slot as usize * INODE_SIZE;
+        inodes[off..off + INODE_SIZE].copy_from_slice(&d);
+    }
+
+    let mut put_inode = |ino: u64, inode: &Inode, fork: &[u8]| {
+        let off = (ino - first_ino) as usize * INODE_SIZE;
+        inodes[off..off + INODE_SIZE].copy_from_slice(&dinode(ino, &uuid, Some(inode), fork));
+    };
+
+    // Realtime bitmap and summary, empty as there is no realtime device
+    let empty = Inode {
+        mode: S_IFREG,
+        uid: 0,
+        gid: 0,
+        nlink: 1,
+        size: 0,
+        nblocks: 0,
+        mtime: 0,
+        format: FMT_EXTENTS,
+        nextents: 0,
+    };
+    for i in 1..=RESERVED_INODES {
+        put_inode(first_ino + i, &empty, &[]);
+    }
+
+    for (idx, node) in tree.nodes.iter().enumerate() {
+        let extents = &layout.extents[idx];
+        let nblocks = extents.iter().map(|e| e.len).sum();
+
+        let (inode, fork) = match (&node.kind, &layout.dirs[idx]) {
+            (Kind::Dir(children), Some(d)) => {
+                let nlink = 2 + children.iter().filter(|&&c| tree.nodes[c].is_dir()).count();
+                let (format, size, fork) = match d {
+                    Dir::Local(sf) => (FMT_LOCAL, sf.len() as u64, sf.clone()),
+                    Dir::Blocks(_, size) => (FMT_EXTENTS, *size, extent_list(extents)),
+                };
+                let inode = Inode {
+                    mode: S_IFDIR | node.mode as u16,
+                    uid: node.uid,
+                    gid: node.gid,
+                    nlink: nlink as u32,
+                    size,
+                    nblocks,
+                    mtime: node.mtime,
+                    format,
+                    nextents: if format == FMT_LOCAL {
+                        0
+                    } else {
+                        extents.len() as u32
+                    },
+                };
+                (inode, fork)
+            }
+            (Kind::File { len, .. }, _) => {
+                let inode = Inode {
+                    mode: S_IFREG | node.mode as u16,
+                    uid: node.uid,
+                    gid: node.gid,
+                    nlink: 1,
+                    size: *len,
+                    nblocks,
+                    mtime: node.mtime,
+                    format: FMT_EXTENTS,
+                    nextents: extents.len() as u32,
+                };
+                (inode, extent_list(extents))
+            }
+            (Kind::Symlink(target), _) => {
+                let (format, fork) = if extents.is_empty() {
+                    (FMT_LOCAL, target.as_bytes().to_vec())
+                } else {
+                    (FMT_EXTENTS, extent_list(extents))
+                };
+                let inode = Inode {
+                    mode: S_IFLNK | node.mode as u16,
+                    uid: node.uid,
+                    gid: node.gid,
+                    nlink: 1,
+                    size: target.len() as u64,
+                    nblocks,
+                    mtime: node.mtime,
+                    format,
+                    nextents: extents.len() as u32,
+                };
+                (inode, fork)
+            }
+            _ => unreachable!(),
+        };
+
+        put_inode(layout.inos[idx], &inode, &fork);
+    }
+
+    write_at(out, layout.chunk_start * BLOCK_SIZE, &inodes)?;
+
+    // Log, starting with an unmount record to mark it clean
+    let mut zero = vec![0u8; BLOCK_SIZE as usize];
+    zero[..2 * SECTOR_SIZE].copy_from_slice(&unmount_record(&uuid));
+    for b in 0..LOG_BLOCKS {
+        write_at(out, (layout.log_start + b) * BLOCK_SIZE, &zero)?;
+        zero[..2 * SECTOR_SIZE].fill(0);
+    }
+
+    // Directory blocks
+    for (idx, d) in layout.dirs.iter_mut().enumerate() {
+        let Some(Dir::Blocks(blocks, _)) = d else {
+            continue;
+        };
+        let extents = &layout.extents[idx];
+
+        for (dablk, block) in blocks {
+            let e = extents
+                .iter()
+                .find(|e| (e.offset..e.offset + e.len).contains(dablk))
+                .unwrap();
+            let offset = geo.offset(e.block + (*dablk - e.offset));
+            finish_dir_block(block, offset / 512);
+            write_at(out, offset, block)?;
+        }
+    }
+
+    // Symbolic links that do not fit in their inode
+    for (idx, node) in tree.nodes.iter().enumerate() {
+        let (Kind::Symlink(target), [e]) = (&node.kind, &layout.extents[idx][..]) else {
+            continue;
+        };
+
+        let offset = geo.offset(e.block);
+        let mut block = vec![0u8; BLOCK_SIZE as usize];
+        be32(&mut block, 0, SYMLINK_MAGIC);
+        be32(&mut block, 8, target.len() as u32);
+        block[16..32].copy_from_slice(&uuid);
+        be64(&mut block, 32, layout.inos[idx]);
+        be64(&mut block, 40, offset / 512);
+        block[SYMLINK_HDR..SYMLINK_HDR + target.len()].copy_from_slice(target.as_bytes());
+        set_crc(&mut block, 12);
+        write_at(out, offset, &block)?;
+    }
+
+    // File data
+    for (idx, node) in tree.nodes.iter().enumerate() {
+        let Kind::File { source, len } = &node.kind else {
+            continue;
+        };
+
+        let mut file = source.open()?.take(*len);
+        let mut copied = 0;
+        for e in &layout.extents[idx] {
+            out.seek(SeekFrom::Start(geo.offset(e.block)))?;
+            copied += io::copy(&mut (&mut file).take(e.len * BLOCK_SIZE), out)?;
+        }
+        if copied != *len {
+            anyhow::bail!("{} changed while being copied", node.path.display());
+        }
+        on_file(&node.path, *len);
+    }
+
+    // Allocation group headers
+    let mut icount = 0;
+    let mut ifree = 0;
+    let mut fdblocks = 0;
+    let mut headers = vec![];
+
+    for agno in 0..geo.agcount {
+        let ag_len = geo.ag_len(agno);
+        let free = layout.free(agno);
+        let freeblks = free.iter().map(|f| f.1 as u64).sum::<u64>();
+        let longest = free.iter().map(|f| f.1).max().unwrap_or(0);
+        let agfl_start = layout.headers[agno as usize] - AGFL_BLOCKS;
+
+        let bno = free
+            .iter()
+            .map(|(s, l)| [s.to_be_bytes(), l.to_be_bytes()].concat())
+            .collect::<Vec<_>>();
+        let mut by_len = free.clone();
+        by_len.sort_by_key(|&(s, l)| (l, s));
+        let cnt = by_len
+            .iter()
+            .map(|(s, l)| [s.to_be_bytes(), l.to_be_bytes()].concat())
+            .collect::<Vec<_>>();
+
+        // Inode chunks, all allocated up to the last used inode
+        let (chunks, used) = if agno == 0 {
+            (layout.chunks, tree.nodes.len() as u64 + RESERVED_INODES)
+        } else {
+            (0, 0)
+        };
+        let inobt = (0..chunks)
+            .map(|c| {
+                let start = (layout.chunk_start + c * CHUNK_BLOCKS) * INODES_PER_BLOCK;
+                let in_use = used.saturating_sub(c * CHUNK_INODES).min(CHUNK_INODES);
+                let free_mask = if in_use == 64 { 0 } else { !0u64 << in_use };
+                let mut r = vec![0u8; 16];
+                be32(&mut r, 0, start as u32);
+                be32(&mut r, 4, (CHUNK_INODES - in_use) as u32);
+                be64(&mut r, 8, free_mask);
+                r
+            })
+            .collect::<Vec<_>>();
+
+        let mut blocks = vec![];
+        let (b, bno_levels) = btree(BNO_MAGIC, &bno, 8, 1, agno, &geo, &uuid);
+        blocks.extend(b);
+        let (b, cnt_levels) = btree(CNT_MAGIC, &cnt, 8, 2, agno, &geo, &uuid);
+        blocks.extend(b);
+        let (b, ino_levels) = btree(IBT_MAGIC, &inobt, 4, 3, agno, &geo, &uuid);
+        let ino_root = b.last().unwrap().0;
+        blocks.extend(b);
+
+        if bno_levels > 1 || cnt_levels > 1 {
+            anyhow::bail!("XFS free space is too fragmented");
+        }
+
+        // Free space btrees
+        let mut agf = vec![0u8; SECTOR_SIZE];
+        be32(&mut agf, 0, AGF_MAGIC);
+        be32(&mut agf, 4, 1);
+        be32(&mut agf, 8, agno as u32);
+        be32(&mut agf, 12, ag_len as u32);
+        be32(&mut agf, 16, 1);
+        be32(&mut agf, 20, 2);
+        be32(&mut agf, 28, bno_levels);
+        be32(&mut agf, 32, cnt_levels);
+        be32(&mut agf, 44, AGFL_BLOCKS as u32 - 1);
+        be32(&mut agf, 48, AGFL_BLOCKS as u32);
+        be32(&mut agf, 52, freeblks as u32);
+        be32(&mut agf, 56, longest);
+        agf[64..80].copy_from_slice(&uuid);
+        set_crc(&mut agf, 216);
+
+        // Inode btree
+        let mut agi = vec![0u8; SECTOR_SIZE];
+        be32(&mut agi, 0, AGI_MAGIC);
+        be32(&mut agi, 4, 1);
+        be32(&mut agi, 8, agno as u32);
+        be32(&mut agi, 12, ag_len as u32);
+        be32(&mut agi, 16, (chunks * CHUNK_INODES) as u32);
+        be32(&mut agi, 20, ino_root as u32);
+        be32(&mut agi, 24, ino_levels);
+        be32(&mut agi, 28, (chunks * CHUNK_INODES - used) as u32);
+        let newino = match chunks {
+            0 => NULL_AGINO,
+            _ => ((layout.chunk_start + (chunks - 1) * CHUNK_BLOCKS) * INODES_PER_BLOCK) as u32,
+        };
+        be32(&mut agi, 32, newino);
+        be32(&mut agi, 36, NULL_AGINO);
+        for i in 0..64 {
+            be32(&mut agi, 40 + i * 4, NULL_AGINO);
+        }
+        agi[296..312].copy_from_slice(&uuid);
+        set_crc(&mut agi, 312);
+
+        // Free list
+        let mut agfl = vec![0u8; SECTOR_SIZE];
+        be32(&mut agfl, 0, AGFL_MAGIC);
+        be32(&mut agfl, 4, agno as u32);
+        agfl[8..24].copy_from_slice(&uuid);
+        for i in 0..AGFL_SIZE {
+            let bno = if (i as u64) < AGFL_BLOCKS {
+                (agfl_start + i as u64) as u32
+            } else {
+                NULL_AGBLOCK
+            };
+            be32(&mut agfl, 36 + i * 4, bno);
+        }
+        set_crc(&mut agfl, 32);
+
+        icount += chunks * CHUNK_INODES;
+        ifree += chunks * CHUNK_INODES - used;
+        fdblocks += freeblks + AGFL_BLOCKS;
+        headers.push((agf, agi, agfl, blocks));
+    }
+
+    // Superblock, repeated at the start of every allocation group
+    let mut sb = vec![0u8; SECTOR_SIZE];
+    be32(&mut sb, 0, SB_MAGIC);
+    be32(&mut sb, 4, BLOCK_SIZE as u32);
+    be64(&mut sb, 8, geo.dblocks);
+    sb[32..48].copy_from_slice(&uuid);
+    be64(&mut sb, 48, geo.fsbno(0, layout.log_start));
+    be64(&mut sb, 56, first_ino);
+    be64(&mut sb, 64, first_ino + 1);
+    be64(&mut sb, 72, first_ino + 2);
+    be32(&mut sb, 80, 1);
+    be32(&mut sb, 84, geo.agblocks as u32);
+    be32(&mut sb, 88, geo.agcount as u32);
+    be32(&mut sb, 96, LOG_BLOCKS as u32);
+    be16(&mut sb, 100, SB_VERSION);
+    be16(&mut sb, 102, SECTOR_SIZE as u16);
+    be16(&mut sb, 104, INODE_SIZE as u16);
+    be16(&mut sb, 106, INODES_PER_BLOCK as u16);
+    sb[108..108 + label.len()].copy_from_slice(label.as_bytes());
+    sb[120] = BLOCK_SIZE.trailing_zeros() as u8;
+    sb[121] = SECTOR_SIZE.trailing_zeros() as u8;
+    sb[122] = INODE_SIZE.trailing_zeros() as u8;
+    sb[123] = INODES_PER_BLOCK.trailing_zeros() as u8;
+    sb[124] = geo.agblklog as u8;
+    sb[127] = 25;
+    be64(&mut sb, 128, icount);
+    be64(&mut sb, 136, ifree);
+    be64(&mut sb, 144, fdblocks);
+    be64(&mut sb, 160, NULL_FSINO);
+    be64(&mut sb, 168, NULL_FSINO);
+    be32(&mut sb, 180, INODE_ALIGN as u32);
+    be32(&mut sb, 196, 1);
+    be32(&mut sb, 200, SB_FEATURES2);
+    be32(&mut sb, 204, SB_FEATURES2);
+    be32(&mut sb, 216, SB_INCOMPAT_FTYPE);
+    be64(&mut sb, 232, NULL_FSINO);
+    set_crc(&mut sb, 224);
+
+    for (agno, (agf, agi, agfl, blocks)) in headers.iter().enumerate() {
+        let mut block = vec![0u8; BLOCK_SIZE as usize];
+        block[0..SECTOR_SIZE].copy_from_slice(&sb);
+        block[SECTOR_SIZE..2 * SECTOR_SIZE].copy_from_slice(agf);
+        block[2 * SECTOR_SIZE..3 * SECTOR_SIZE].copy_from_slice(agi);
+        block[3 * SECTOR_SIZE..4 * SECTOR_SIZE].copy_from_slice(agfl);
+
+        let base = agno as u64 * geo.agblocks * BLOCK_SIZE;
+        write_at(out, base, &block)?;
+        for (agbno, b) in blocks {
+            write_at(out, base + agbno * BLOCK_SIZE, b)?;
+        }
+    }
+
+    Ok(())
+}
+
+impl crate::FsWriter for Options {
+    fn estimate_size(&self, tree: &Tree) -> anyhow::Result<u64> {
+        estimate_size(tree)
+    }
+
+    fn write(
+        &self,
+        mut out: &mut dyn fatfs::ReadWriteSeek,
+        size: u64,
+        tree: &Tree,
+        ctx: &crate::BuildContext,
+        on_file: &mut dyn FnMut(&Path, u64),
+    ) -> anyhow::Result<()> {
+        write(&mut out, size, tree, self, ctx, on_file)
+    }
+}
@@ -0,0 +1,242 @@
+//! Reading of zip archives, to build images from.
+//!
+//! Entries are stored or deflated. Their DOS timestamps are in local time, and the extended
+//! timestamp field of Unix archivers takes precedence when present.
+
+use crate::tree::{Kind, Tree};
+use crate::{compress, tar, FileSource};
+use chrono::{Local, TimeZone};
+use log::*;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::Path;
+
+const END_OF_CENTRAL_DIR: u32 = 0x06054b50;
+const ZIP64_END_LOCATOR: u32 = 0x07064b50;
+const ZIP64_END_OF_CENTRAL_DIR: u32 = 0x06064b50;
+const CENTRAL_DIR_ENTRY: u32 = 0x02014b50;
+const LOCAL_HEADER: u32 = 0x04034b50;
+
+const EXTRA_ZIP64: u16 = 0x0001;
+const EXTRA_TIMESTAMP: u16 = 0x5455;
+
+const METHOD_STORED: u16 = 0;
+const METHOD_DEFLATE: u16 = 8;
+
+/// Host system of archives with Unix modes in their external attributes
+const MADE_BY_UNIX: u16 = 3;
+const S_IFMT: u32 = 0o170000;
+const S_IFLNK: u32 = 0o120000;
+
+fn u16_at(b: &[u8], at: usize) -> u16 {
+    u16::from_le_bytes([b[at], b[at + 1]])
+}
+
+fn u32_at(b: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes(b[at..at + 4].try_into().unwrap())
+}
+
+fn u64_at(b: &[u8], at: usize) -> u64 {
+    u64::from_le_bytes(b[at..at + 8].try_into().unwrap())
+}
+
+fn read_at(file: &mut File, offset: u64, len: usize) -> anyhow::Result<Vec<u8>> {
+    let mut buf = vec![0; len];
+    file.seek(SeekFrom::Start(offset))?;
+    file.read_exact(&mut buf)?;
+    Ok(buf)
+}
+
+/// Time of a DOS date and time, which are in local time with 2 second resolution.
+fn dos_time(date: u16, time: u16) -> Option<i64> {
+    let date = chrono::NaiveDate::from_ymd_opt(
+        1980 + (date >> 9) as i32,
+        ((date >> 5) & 0xf).into(),
+        (date & 0x1f).into(),
+    )?;
+    let time = date.and_hms_opt(
+        (time >> 11).into(),
+        ((time >> 5) & 0x3f).into(),
+        ((time & 0x1f) * 2).into(),
+    )?;
+    Some(Local.from_local_datetime(&time).earliest()?.timestamp())
+}
+
+/// An entry of the central directory.
+struct Entry {
+    name: String,
+    method: u16,
+    crc: u32,
+    compressed: u64,
+    size: u64,
+    offset: u64,
+    /// Unix mode, if the archive was made on Unix
+    mode: Option<u32>,
+    mtime: Option<i64>,
+}
+
+/// Position and number of entries of the central directory.
+fn central_dir(file: &mut File) -> anyhow::Result<(u64, u64)> {
+    let len = file.seek(SeekFrom::End(0))?;
+    // The end record is 22 bytes, followed by a comment of up to 64K
+    let tail_len = len.min(22 + 0xffff);
+    let tail = read_at(file, len - tail_len, tail_len as usize)?;
+
+    let end = (0..tail.len().saturating_sub(21))
+        .rev()
+        .find(|&i| u32_at(&tail, i) == END_OF_CENTRAL_DIR)
+        .ok_or_else(|| anyhow::anyhow!("no end of central directory, this is not a zip archive"))?;
+    let end_offset = len - tail_len + end as u64;
+
+    let entries = u16_at(&tail, end + 10) as u64;
+    let offset = u32_at(&tail, end + 16) as u64;
+
+    if entries != 0xffff && offset != 0xffff_ffff {
+        return Ok((offset, entries));
+    }
+
+    // Zip64 end records, found through the locator in front of the regular one
+    let locator = read_at(file, end_offset.saturating_sub(20), 20)?;
+    if u32_at(&locator, 0) != ZIP64_END_LOCATOR {
+        anyhow::bail!("zip64 archive without a zip64 end locator");
+    }
+    let record = read_at(file, u64_at(&locator, 8), 56)?;
+    if u32_at(&record, 0) != ZIP64_END_OF_CENTRAL_DIR {
+        anyhow::bail!("zip64 end locator points to no end record");
+    }
+
+    Ok((u64_at(&record, 48), u64_at(&record, 32)))
+}
+
+fn entries(file: &mut File) -> anyhow::Result<Vec<Entry>> {
+    let (mut offset, count) = central_dir(file)?;
+    let mut entries = vec![];
+
+    for _ in 0..count {
+        let h = read_at(file, offset, 46)?;
+        if u32_at(&h, 0) != CENTRAL_DIR_ENTRY {
+            anyhow::bail!("corrupt central directory");
+        }
+
+        let flags = u16_at(&h, 8);
+        let (name_len, extra_len) = (u16_at(&h, 28) as usize, u16_at(&h, 30) as usize);
+        let comment_len = u16_at(&h, 32) as usize;
+        let rest = read_at(file, offset + 46, name_len + extra_len)?;
+
+        // Windows archivers may separate paths with backslashes
+        let name = String::from_utf8_lossy(&rest[..name_len]).replace('\\', "/");
+        if flags & 1 != 0 {
+            anyhow::bail!("`{name}` is encrypted");
+        }
+
+        let mut entry = Entry {
+            method: u16_at(&h, 10),
+            crc: u32_at(&h, 16),
+            compressed: u32_at(&h, 20) as u64,
+            size: u32_at(&h, 24) as u64,
+            offset: u32_at(&h, 42) as u64,
+            mode: (u16_at(&h, 4) >> 8 == MADE_BY_UNIX)
+                .then(|| u32_at(&h, 38) >> 16)
+                .filter(|&mode| mode != 0),
+            mtime: dos_time(u16_at(&h, 14), u16_at(&h, 12)),
+            name,
+        };
+
+        let mut extra = &rest[name_len..];
+        while extra.len() >= 4 {
+            let (id, len) = (u16_at(extra, 0), u16_at(extra, 2) as usize);
+            let data = extra.get(4..4 + len).unwrap_or_default();
+
+            match id {
+                EXTRA_ZIP64 => {
+                    // Only the fields that do not fit their 32 bits, in this order
+                    let mut values = data.chunks_exact(8).map(|v| u64_at(v, 0));
+                    for field in [&mut entry.size, &mut entry.compressed, &mut entry.offset] {
+                        if *field == 0xffff_ffff {
+                            *field = values.next().ok_or_else(|| {
+                                anyhow::anyhow!("`{}` lacks its zip64 sizes", entry.name)
+                            })?;
+                        }
+                    }
+                }
+                EXTRA_TIMESTAMP if data.len() >= 5 && data[0] & 1 != 0 => {
+                    let secs = i32::from_le_bytes(data[1..5].try_into().unwrap());
+                    entry.mtime = Some(secs.into());
+                }
+                _ => {}
+            }
+
+            extra = extra.get(4 + len..).unwrap_or_default();
+        }
+
+        entries.push(entry);
+        offset += (46 + name_len + extra_len + comment_len) as u64;
+    }
+
+    Ok(entries)
+}
+
+/// Contents of an entry, checked against its CRC.
+fn read_entry(file: &mut File, entry: &Entry) -> anyhow::Result<Vec<u8>> {
+    let h = read_at(file, entry.offset, 30)?;
+    if u32_at(&h, 0) != LOCAL_HEADER {
+        anyhow::bail!("`{}` has no local header", entry.name);
+    }
+
+    // The local extra field may differ from the central one
+    let start = entry.offset + 30 + u16_at(&h, 26) as u64 + u16_at(&h, 28) as u64;
+    let raw = read_at(file, start, entry.compressed as usize)?;
+
+    let data = match entry.method {
+        METHOD_STORED => raw,
+        METHOD_DEFLATE => compress::inflate(&raw)?,
+        method => anyhow::bail!(
+            "`{}` uses unsupported compression method {method}",
+            entry.name
+        ),
+    };
+
+    if data.len() as u64 != entry.size || crc::crc32::checksum_ieee(&data) != entry.crc {
+        anyhow::bail!("`{}` does not match its CRC", entry.name);
+    }
+
+    Ok(data)
+}
+
+/// Read the zip archive at `path` into memory, keeping modification times, and the Unix modes and
+/// symbolic links of archives made on Unix. Entries without a time, and the root, take `time`.
+/// Returns the number of entries.
+pub fn read(path: &Path, time: i64) -> anyhow::Result<(Tree, u64)> {
+    let mut file = File::open(path)?;
+    let entries = entries(&mut file)?;
+    let mut tree = Tree::new(0o755, time);
+
+    for entry in &entries {
+        let dest = tar::entry_path(&entry.name)?;
+        if dest.as_os_str().is_empty() {
+            continue;
+        }
+        let mtime = entry.mtime.unwrap_or(time);
+
+        if entry.name.ends_with('/') {
+            let mode = entry.mode.unwrap_or(0o755) & 0o7777;
+            tree.insert(&dest, Kind::Dir(vec![]), mode, mtime)?;
+            continue;
+        }
+
+        let data = read_entry(&mut file, entry)?;
+
+        let kind = if entry.mode.is_some_and(|mode| mode & S_IFMT == S_IFLNK) {
+            Kind::Symlink(String::from_utf8(data)?)
+        } else {
+            debug!("{}: {} bytes", entry.name, data.len());
+            Kind::File {
+                len: data.len() as u64,
+                source: FileSource::Data(data.into()),
+            }
+        };
+        tree.insert(&dest, kind, entry.mode.unwrap_or(0o644) & 0o7777, mtime)?;
+    }
+
+    Ok((tree, entries.len() as u64))
+}
@@ -0,0 +1,74 @@
+//! Archives, read back with the tar reader or unpacked by the compressor tools.
+
+mod common;
+
+use mkimg::tree::Kind;
+use std::fs::{self, File};
+use std::path::Path;
+use std::process::Command;
+
+#[test]
+fn symlinks_are_kept() {
+    let dir = common::scratch_dir("symlinks");
+    fs::create_dir_all(dir.join("input/sub")).unwrap();
+    fs::write(dir.join("input/sub/hello.txt"), "hello\n").unwrap();
+    std::os::unix::fs::symlink("sub/hello.txt", dir.join("input/link")).unwrap();
+    std::os::unix::fs::symlink("/nowhere", dir.join("input/sub/dangling")).unwrap();
+
+    let build = |filesystem: &str| {
+        let name = format!("{filesystem}.img");
+        common::build(&dir, &name, &["--filesystem", filesystem])
+    };
+
+    let image = build("tar");
+    let (tree, _) = mkimg::tar::read(&mut File::open(&image).unwrap(), None, 0).unwrap();
+    let target = |path: &str| {
+        let node = tree.nodes.iter().find(|n| n.path == Path::new(path));
+        match node.map(|n| &n.kind) {
+            Some(Kind::Symlink(target)) => target.clone(),
+            other => panic!("{path} is {other:?}"),
+        }
+    };
+    assert_eq!(target("link"), "sub/hello.txt");
+    assert_eq!(target("sub/dangling"), "/nowhere");
+
+    // Filesystems without symbolic links leave them out
+    build("vfat");
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn initramfs_zstd_unpacks() {
+    if !common::installed("zstd") {
+        return;
+    }
+
+    let dir = common::scratch_dir("initramfs");
+    fs::create_dir_all(dir.join("input/etc")).unwrap();
+    fs::write(dir.join("input/etc/hostname"), "initrd\n").unwrap();
+
+    let image = common::build(
+        &dir,
+        "initramfs.img",
+        &["--filesystem", "initramfs", "--compression", "zstd"],
+    );
+
+    let output = Command::new("zstd")
+        .args(["-d", "-c"])
+        .arg(&image)
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "zstd -d failed");
+    let cpio = output.stdout;
+    assert_eq!(&cpio[..6], b"070701");
+    let name = cpio
+        .windows(13)
+        .position(|w| w == b"etc/hostname\0")
+        .unwrap();
+    // The data follows the name, padded to 4 bytes
+    let data = (name + 13).next_multiple_of(4);
+    assert_eq!(&cpio[data..data + 7], b"initrd\n");
+
+    fs::remove_dir_all(&dir).unwrap();
+}
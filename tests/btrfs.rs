@@ -0,0 +1,215 @@
+//! btrfs images, read back through their chunk, root, FS and checksum trees.
+
+mod common;
+
+use common::{le16, le32, le64};
+use crc::crc32;
+use std::collections::BTreeMap;
+use std::fs;
+
+const SUPER_OFFSET: usize = 64 << 10;
+const HEADER_SIZE: usize = 101;
+
+const INODE_ITEM: u8 = 1;
+const DIR_ITEM: u8 = 84;
+const EXTENT_DATA: u8 = 108;
+const EXTENT_CSUM: u8 = 128;
+const ROOT_ITEM: u8 = 132;
+const CHUNK_ITEM: u8 = 228;
+
+/// Key of an item, as (objectid, type, offset).
+type Key = (u64, u8, u64);
+
+fn key_at(b: &[u8], off: usize) -> Key {
+    (le64(b, off), b[off + 8], le64(b, off + 9))
+}
+
+struct Btrfs {
+    image: Vec<u8>,
+    fsid: [u8; 16],
+    node_size: usize,
+    sector_size: u64,
+    /// Chunks by logical address, as their length and physical address
+    chunks: BTreeMap<u64, (u64, u64)>,
+}
+
+impl Btrfs {
+    fn open(image: Vec<u8>) -> Self {
+        let sb = &image[SUPER_OFFSET..SUPER_OFFSET + 4096].to_vec();
+        assert_eq!(&sb[0x40..0x48], b"_BHRfS_M");
+        assert_eq!(le64(sb, 0x30), SUPER_OFFSET as u64);
+        assert_eq!(
+            le32(sb, 0),
+            crc32::checksum_castagnoli(&sb[32..]),
+            "superblock checksum"
+        );
+
+        let mut fs = Self {
+            fsid: sb[0x20..0x30].try_into().unwrap(),
+            node_size: le32(sb, 0x94) as usize,
+            sector_size: le32(sb, 0x90) as u64,
+            chunks: BTreeMap::new(),
+            image,
+        };
+
+        // The system chunk in the superblock leads to the chunk tree with all of them
+        let array = &sb[0x32b..0x32b + le32(sb, 0xa0) as usize];
+        let (key, chunk) = (key_at(array, 0), &array[17..]);
+        assert_eq!(key.1, CHUNK_ITEM);
+        fs.add_chunk(key.2, chunk);
+
+        for (key, chunk) in fs.items(le64(sb, 0x58), sb[0xc7]) {
+            if key.1 == CHUNK_ITEM {
+                fs.add_chunk(key.2, &chunk);
+            }
+        }
+
+        fs
+    }
+
+    fn add_chunk(&mut self, logical: u64, chunk: &[u8]) {
+        assert_eq!(le16(chunk, 44), 1, "chunks have a single stripe");
+        self.chunks
+            .insert(logical, (le64(chunk, 0), le64(chunk, 48 + 8)));
+    }
+
+    /// Bytes at a logical address.
+    fn read(&self, logical: u64, len: usize) -> &[u8] {
+        let (&start, &(chunk_len, physical)) = self.chunks.range(..=logical).next_back().unwrap();
+        assert!(
+            logical + len as u64 <= start + chunk_len,
+            "read past a chunk"
+        );
+        let at = (physical + logical - start) as usize;
+        &self.image[at..at + len]
+    }
+
+    /// Items of the tree with its root at `addr`, checking every block on the way.
+    fn items(&self, addr: u64, level: u8) -> Vec<(Key, Vec<u8>)> {
+        let block = self.read(addr, self.node_size);
+        assert_eq!(
+            le32(block, 0),
+            crc32::checksum_castagnoli(&block[32..]),
+            "checksum of the tree block at {addr:#x}"
+        );
+        assert_eq!(block[0x20..0x30], self.fsid);
+        assert_eq!(le64(block, 0x30), addr);
+        assert_eq!(block[0x64], level);
+
+        let count = le32(block, 0x60) as usize;
+        let mut items = vec![];
+        for i in 0..count {
+            if level == 0 {
+                let item = &block[HEADER_SIZE + i * 25..];
+                let offset = HEADER_SIZE + le32(item, 17) as usize;
+                let data = block[offset..offset + le32(item, 21) as usize].to_vec();
+                items.push((key_at(item, 0), data));
+            } else {
+                let ptr = &block[HEADER_SIZE + i * 33..];
+                let child = self.items(le64(ptr, 17), level - 1);
+                assert!(child.first().is_some_and(|(k, _)| *k == key_at(ptr, 0)));
+                items.extend(child);
+            }
+        }
+
+        assert!(
+            items.windows(2).all(|w| w[0].0 < w[1].0),
+            "keys out of order"
+        );
+        items
+    }
+
+    /// Items of the tree of `objectid`, from its root item.
+    fn tree(&self, root: &[(Key, Vec<u8>)], objectid: u64) -> Vec<(Key, Vec<u8>)> {
+        let (_, item) = root
+            .iter()
+            .find(|(k, _)| k.0 == objectid && k.1 == ROOT_ITEM)
+            .unwrap();
+        self.items(le64(item, 176), item[238])
+    }
+}
+
+/// Inode of `name` in the directory `dir`.
+fn lookup(fs_tree: &[(Key, Vec<u8>)], dir: u64, name: &str) -> u64 {
+    fs_tree
+        .iter()
+        .filter(|(k, _)| k.0 == dir && k.1 == DIR_ITEM)
+        .find(|(_, item)| &item[30..30 + le16(item, 27) as usize] == name.as_bytes())
+        .map(|(_, item)| key_at(item, 0).0)
+        .unwrap_or_else(|| panic!("no {name} in directory {dir}"))
+}
+
+/// Contents of the file `inode`, checking regular extents against the checksum tree.
+fn contents(
+    fs: &Btrfs,
+    fs_tree: &[(Key, Vec<u8>)],
+    csums: &[(Key, Vec<u8>)],
+    inode: u64,
+) -> Vec<u8> {
+    let (_, inode_item) = fs_tree
+        .iter()
+        .find(|(k, _)| *k == (inode, INODE_ITEM, 0))
+        .unwrap();
+    let size = le64(inode_item, 16);
+
+    let csum = |addr: u64| {
+        let (key, sums) = csums
+            .iter()
+            .rfind(|(k, _)| k.1 == EXTENT_CSUM && k.2 <= addr)
+            .unwrap();
+        let i = ((addr - key.2) / fs.sector_size) as usize;
+        le32(sums, i * 4)
+    };
+
+    let mut data = vec![];
+    for (key, extent) in fs_tree
+        .iter()
+        .filter(|(k, _)| k.0 == inode && k.1 == EXTENT_DATA)
+    {
+        assert_eq!(key.2, data.len() as u64, "extents leave a hole");
+        match extent[20] {
+            0 => data.extend_from_slice(&extent[21..]),
+            1 => {
+                let (disk, len) = (le64(extent, 21), le64(extent, 29));
+                for sector in (disk..disk + len).step_by(fs.sector_size as usize) {
+                    let bytes = fs.read(sector, fs.sector_size as usize);
+                    assert_eq!(crc32::checksum_castagnoli(bytes), csum(sector));
+                }
+                let offset = disk + le64(extent, 37);
+                data.extend_from_slice(fs.read(offset, le64(extent, 45) as usize));
+            }
+            kind => panic!("unknown extent type {kind}"),
+        }
+    }
+
+    data.truncate(size as usize);
+    data
+}
+
+#[test]
+fn files_read_back() {
+    let dir = common::scratch_dir("btrfs");
+    fs::create_dir_all(dir.join("input/sub")).unwrap();
+    let large = (0..70000u32).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+    fs::write(dir.join("input/hello.txt"), "hello\n").unwrap();
+    fs::write(dir.join("input/sub/data.bin"), &large).unwrap();
+
+    let image = common::build(
+        &dir,
+        "btrfs.img",
+        &["--filesystem", "btrfs", "--size", "64M"],
+    );
+    common::assert_blkid(&image, "btrfs");
+    let fs = Btrfs::open(fs::read(&image).unwrap());
+    fs::remove_dir_all(&dir).unwrap();
+
+    let sb = &fs.image[SUPER_OFFSET..];
+    let root = fs.items(le64(sb, 0x50), sb[0xc6]);
+    let (fs_tree, csums) = (fs.tree(&root, 5), fs.tree(&root, 7));
+
+    let hello = lookup(&fs_tree, 256, "hello.txt");
+    assert_eq!(contents(&fs, &fs_tree, &csums, hello), b"hello\n");
+
+    let data = lookup(&fs_tree, lookup(&fs_tree, 256, "sub"), "data.bin");
+    assert!(contents(&fs, &fs_tree, &csums, data) == large);
+}
@@ -0,0 +1,83 @@
+//! Helpers shared by the integration tests: a scratch directory to build images in, the build
+//! itself, readers of the integers in images, and checks by tools that may not be installed.
+
+// Every test crate includes this module, and uses only some of it
+#![allow(dead_code)]
+
+use clap::Parser;
+use mkimg::{Args, ImageBuilder};
+use std::ffi::OsString;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// Empty scratch directory of the test `name`, with an `input` directory to build images from.
+pub fn scratch_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("mkimg-test-{}-{name}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(dir.join("input")).unwrap();
+    dir
+}
+
+/// Build the `input` directory of `dir` into `dir/<name>` with a fixed seed and `args`, returning
+/// the path of the image.
+pub fn build(dir: &Path, name: &str, args: &[&str]) -> PathBuf {
+    let image = dir.join(name);
+    let mut argv: Vec<OsString> = ["mkimg", "--deterministic", "test"]
+        .iter()
+        .chain(args)
+        .map(Into::into)
+        .collect();
+    argv.extend(["--input-dir".into(), dir.join("input").into_os_string()]);
+    argv.extend(["--output-path".into(), image.clone().into_os_string()]);
+    ImageBuilder::new(Args::parse_from(argv)).build().unwrap();
+    image
+}
+
+pub fn le16(b: &[u8], off: usize) -> u16 {
+    u16::from_le_bytes(b[off..off + 2].try_into().unwrap())
+}
+
+pub fn le32(b: &[u8], off: usize) -> u32 {
+    u32::from_le_bytes(b[off..off + 4].try_into().unwrap())
+}
+
+pub fn le64(b: &[u8], off: usize) -> u64 {
+    u64::from_le_bytes(b[off..off + 8].try_into().unwrap())
+}
+
+pub fn be16(b: &[u8], off: usize) -> u16 {
+    u16::from_be_bytes(b[off..off + 2].try_into().unwrap())
+}
+
+pub fn be32(b: &[u8], off: usize) -> u32 {
+    u32::from_be_bytes(b[off..off + 4].try_into().unwrap())
+}
+
+pub fn be64(b: &[u8], off: usize) -> u64 {
+    u64::from_be_bytes(b[off..off + 8].try_into().unwrap())
+}
+
+/// Whether `tool` can be run, printing that the check is skipped if not.
+pub fn installed(tool: &str) -> bool {
+    let found = Command::new(tool).arg("--version").output().is_ok();
+    if !found {
+        eprintln!("{tool} is not installed, skipping");
+    }
+    found
+}
+
+/// Check that `blkid` probes a filesystem of type `ty` in `image`, if it is installed. libblkid
+/// checks the magic numbers of the superblock, and its checksum where the format has one.
+pub fn assert_blkid(image: &Path, ty: &str) {
+    if !installed("blkid") {
+        return;
+    }
+    let output = Command::new("blkid")
+        .args(["-p", "-o", "value", "-s", "TYPE"])
+        .arg(image)
+        .output()
+        .unwrap();
+    let found = String::from_utf8(output.stdout).unwrap();
+    assert_eq!(found.trim(), ty, "filesystem blkid finds");
+}
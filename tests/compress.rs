@@ -0,0 +1,54 @@
+//! Images compressed with --compress, either piped into the tool or from an uncompressed copy,
+//! unpacked again by the tool.
+
+mod common;
+
+use std::fs;
+use std::process::Command;
+
+#[test]
+fn compressed_output_unpacks() {
+    let dir = common::scratch_dir("compress");
+    fs::write(dir.join("input/hello.txt"), "hello\n").unwrap();
+
+    // Archives and Intel HEX are written front to back, vfat images seek
+    let cases: [(&str, &[&str], &str); 3] = [
+        ("zstd", &["--filesystem", "tar"], "hello\n"),
+        ("gzip", &["--output-format", "ihex"], ":00000001FF"),
+        ("xz", &[], "HELLO   TXT"),
+    ];
+
+    for (tool, options, expected) in cases {
+        if !common::installed(tool) {
+            continue;
+        }
+
+        let name = format!("{tool}.img");
+        let image = common::build(&dir, &name, &[&["--compress", tool], options].concat());
+
+        let output = Command::new(tool)
+            .args(["-d", "-c"])
+            .arg(&image)
+            .output()
+            .unwrap();
+        assert!(output.status.success(), "{tool} -d failed");
+        let found = output
+            .stdout
+            .windows(expected.len())
+            .any(|w| w == expected.as_bytes());
+        assert!(found, "{expected:?} not in the {tool} output");
+    }
+
+    // Nothing but the compressed images is left behind
+    let mut names = fs::read_dir(&dir)
+        .unwrap()
+        .map(|e| e.unwrap().file_name().into_string().unwrap())
+        .collect::<Vec<_>>();
+    names.sort();
+    assert!(
+        names.iter().all(|n| n == "input" || n.ends_with(".img")),
+        "{names:?}"
+    );
+
+    fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,281 @@
+//! F2FS images, read back through their checkpoint, node address table and dentry hash levels.
+
+mod common;
+
+use common::{le16, le32, le64};
+use std::fs;
+
+const MAGIC: u32 = 0xf2f5_2010;
+const BLOCK_SIZE: usize = 4096;
+const BLOCKS_PER_SEG: u64 = 512;
+const ROOT_INO: u32 = 3;
+
+const NAT_ENTRIES_PER_BLOCK: u32 = 455;
+const ADDRS_PER_INODE: usize = 923;
+const ADDRS_PER_BLOCK: usize = 1018;
+const FOOTER_OFFSET: usize = 4072;
+
+const DENTRIES_PER_BLOCK: usize = 214;
+const DENTRY_OFFSET: usize = 30;
+const NAME_OFFSET: usize = DENTRY_OFFSET + DENTRIES_PER_BLOCK * 11;
+
+/// CRC32 of F2FS, seeded with the magic number and not inverted at the end.
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = MAGIC;
+    for &b in data {
+        crc ^= b as u32;
+        for _ in 0..8 {
+            crc = (crc >> 1) ^ (0xedb8_8320 & (crc & 1).wrapping_neg());
+        }
+    }
+    crc
+}
+
+/// Hash of a directory entry name: TEA over 16 bytes of it at a time, as ext3 hashes them.
+fn dentry_hash(name: &[u8]) -> u32 {
+    if name == b"." || name == b".." {
+        return 0;
+    }
+
+    let mut buf = [0x6745_2301u32, 0xefcd_ab89, 0x98ba_dcfe, 0x1032_5476];
+    let pad = {
+        let len = name.len() as u32;
+        let pad = len | len << 8;
+        pad | pad << 16
+    };
+    let mut rest = name;
+    loop {
+        // Words of the next 16 bytes, padded with the length of the whole name
+        let mut input = [pad; 4];
+        let chunk = &rest[..rest.len().min(16)];
+        let mut val = pad;
+        for (i, &b) in chunk.iter().enumerate() {
+            val = (b as u32).wrapping_add(val << 8);
+            if i % 4 == 3 {
+                input[i / 4] = val;
+                val = pad;
+            }
+        }
+        if chunk.len() < 16 {
+            input[chunk.len() / 4] = val;
+        }
+
+        let (mut b0, mut b1, mut sum) = (buf[0], buf[1], 0u32);
+        for _ in 0..16 {
+            sum = sum.wrapping_add(0x9e37_79b9);
+            b0 = b0.wrapping_add(
+                (b1 << 4).wrapping_add(input[0])
+                    ^ b1.wrapping_add(sum)
+                    ^ (b1 >> 5).wrapping_add(input[1]),
+            );
+            b1 = b1.wrapping_add(
+                (b0 << 4).wrapping_add(input[2])
+                    ^ b0.wrapping_add(sum)
+                    ^ (b0 >> 5).wrapping_add(input[3]),
+            );
+        }
+        buf[0] = buf[0].wrapping_add(b0);
+        buf[1] = buf[1].wrapping_add(b1);
+
+        if rest.len() <= 16 {
+            return buf[0];
+        }
+        rest = &rest[16..];
+    }
+}
+
+struct F2fs {
+    image: Vec<u8>,
+    nat_addr: u64,
+    ssa_addr: u64,
+    main_addr: u64,
+    version: u64,
+}
+
+impl F2fs {
+    fn open(image: Vec<u8>) -> Self {
+        let sb = image[1024..BLOCK_SIZE].to_vec();
+        assert_eq!(le32(&sb, 0), MAGIC);
+        assert!(
+            sb == image[BLOCK_SIZE + 1024..2 * BLOCK_SIZE],
+            "superblock copies"
+        );
+        assert_eq!(le64(&sb, 36) as usize, image.len() / BLOCK_SIZE);
+
+        // Both checkpoint packs start and end with the same checkpoint, of a clean unmount
+        let cp_addr = le32(&sb, 72) as usize;
+        let cp = image[cp_addr * BLOCK_SIZE..][..BLOCK_SIZE].to_vec();
+        let crc_offset = le32(&cp, 164) as usize;
+        assert_eq!(le32(&cp, crc_offset), crc32(&cp[..crc_offset]));
+        assert_eq!(le32(&cp, 132) & 1, 1, "unmounted cleanly");
+        let pack = le32(&cp, 136) as usize;
+        for start in [cp_addr, cp_addr + BLOCKS_PER_SEG as usize] {
+            for at in [start, start + pack - 1] {
+                assert!(
+                    image[at * BLOCK_SIZE..][..BLOCK_SIZE] == cp,
+                    "checkpoint copy"
+                );
+            }
+        }
+
+        Self {
+            nat_addr: le32(&sb, 84) as u64,
+            ssa_addr: le32(&sb, 88) as u64,
+            main_addr: le32(&sb, 92) as u64,
+            version: le64(&cp, 0),
+            image,
+        }
+    }
+
+    fn block(&self, addr: u64) -> &[u8] {
+        &self.image[addr as usize * BLOCK_SIZE..][..BLOCK_SIZE]
+    }
+
+    /// Node block of `nid`, from the first copy of its NAT block, with its footer checked.
+    fn node(&self, nid: u32, ino: u32) -> &[u8] {
+        let b = (nid / NAT_ENTRIES_PER_BLOCK) as u64;
+        let nat = self
+            .block(self.nat_addr + b / BLOCKS_PER_SEG * 2 * BLOCKS_PER_SEG + b % BLOCKS_PER_SEG);
+        let entry = &nat[(nid % NAT_ENTRIES_PER_BLOCK) as usize * 9..][..9];
+        assert_eq!(le32(entry, 1), ino, "inode of node {nid}");
+
+        let node = self.block(le32(entry, 5) as u64);
+        assert_eq!(le32(node, FOOTER_OFFSET), nid);
+        assert_eq!(le32(node, FOOTER_OFFSET + 4), ino);
+        assert_eq!(le64(node, FOOTER_OFFSET + 12), self.version);
+        node
+    }
+
+    /// Addresses of the data blocks of a node from position `ofs`, checking that the summary
+    /// of each one points back at it.
+    fn addrs(&self, node: &[u8], nid: u32, at: usize, count: usize, out: &mut Vec<u32>) {
+        for ofs in 0..count {
+            let addr = le32(node, at + 4 * ofs);
+            if addr != 0 {
+                let block = addr as u64 - self.main_addr;
+                let ssa = self.block(self.ssa_addr + block / BLOCKS_PER_SEG);
+                let entry = &ssa[(block % BLOCKS_PER_SEG) as usize * 7..][..7];
+                assert_eq!(le32(entry, 0), nid, "summary of block {addr}");
+                assert_eq!(le16(entry, 5) as usize, ofs);
+            }
+            out.push(addr);
+        }
+    }
+
+    /// Addresses of the data blocks under the node `nid`, `depth` levels of nodes above them.
+    fn node_addrs(&self, nid: u32, ino: u32, depth: u32, out: &mut Vec<u32>) {
+        let node = self.node(nid, ino);
+        if depth == 0 {
+            return self.addrs(node, nid, 0, ADDRS_PER_BLOCK, out);
+        }
+        for i in 0..ADDRS_PER_BLOCK {
+            match le32(node, 4 * i) {
+                0 => return,
+                child => self.node_addrs(child, ino, depth - 1, out),
+            }
+        }
+    }
+
+    fn inode(&self, ino: u32) -> &[u8] {
+        self.node(ino, ino)
+    }
+
+    /// Contents of the file or directory `ino`, with holes read as zeros.
+    fn contents(&self, ino: u32) -> Vec<u8> {
+        let inode = self.inode(ino);
+        let mut addrs = vec![];
+        self.addrs(inode, ino, 360, ADDRS_PER_INODE, &mut addrs);
+        for (i, depth) in [0, 0, 1, 1, 2].into_iter().enumerate() {
+            match le32(inode, 4052 + 4 * i) {
+                0 => break,
+                nid => self.node_addrs(nid, ino, depth, &mut addrs),
+            }
+        }
+
+        let size = le64(inode, 16) as usize;
+        let mut data = vec![];
+        for addr in addrs.into_iter().take(size.div_ceil(BLOCK_SIZE)) {
+            match addr {
+                0 => data.extend_from_slice(&[0; BLOCK_SIZE]),
+                addr => data.extend_from_slice(self.block(addr as u64)),
+            }
+        }
+        assert!(data.len() >= size, "blocks missing from inode {ino}");
+        data.truncate(size);
+        data
+    }
+
+    /// Inode of `name` in the directory `dir`, looked up in the buckets its hash leads to as
+    /// the kernel looks it up.
+    fn lookup(&self, dir: u32, name: &str) -> u32 {
+        let depth = le32(self.inode(dir), 72);
+        let blocks = self.contents(dir);
+        let hash = dentry_hash(name.as_bytes());
+
+        let mut index = 0;
+        for level in 0..depth {
+            let (buckets, per_bucket) = if level < 31 {
+                (1 << level, 2)
+            } else {
+                (1 << 30, 4)
+            };
+            let first = index + (hash as usize % buckets) * per_bucket;
+            index += buckets * per_bucket;
+
+            for block in blocks.chunks(BLOCK_SIZE).skip(first).take(per_bucket) {
+                for slot in 0..DENTRIES_PER_BLOCK {
+                    if block[slot / 8] & 1 << (slot % 8) == 0 {
+                        continue;
+                    }
+                    let entry = &block[DENTRY_OFFSET + slot * 11..];
+                    let len = le16(entry, 8) as usize;
+                    let entry_name = &block[NAME_OFFSET + slot * 8..][..len];
+                    if le32(entry, 0) == hash && entry_name == name.as_bytes() {
+                        return le32(entry, 4);
+                    }
+                }
+            }
+        }
+        panic!("no {name} in directory {dir}");
+    }
+}
+
+#[test]
+fn files_read_back() {
+    let dir = common::scratch_dir("f2fs");
+    fs::create_dir_all(dir.join("input/sub")).unwrap();
+    fs::create_dir_all(dir.join("input/many")).unwrap();
+    let large = (0..70000u32).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+    // More blocks than the inode has addresses for, so some are in a direct node
+    let huge = (0..5 << 20).map(|i| (i % 253) as u8).collect::<Vec<_>>();
+    fs::write(dir.join("input/hello.txt"), "hello\n").unwrap();
+    fs::write(dir.join("input/sub/data.bin"), &large).unwrap();
+    fs::write(dir.join("input/sub/huge.bin"), &huge).unwrap();
+    // More entries than the first hash level holds
+    for i in 0..500 {
+        fs::write(
+            dir.join(format!("input/many/file-{i:03}.txt")),
+            i.to_string(),
+        )
+        .unwrap();
+    }
+
+    let image = common::build(&dir, "f2fs.img", &["--filesystem", "f2fs", "--size", "64M"]);
+    common::assert_blkid(&image, "f2fs");
+    let fs = F2fs::open(fs::read(&image).unwrap());
+    fs::remove_dir_all(&dir).unwrap();
+
+    assert_eq!(fs.contents(fs.lookup(ROOT_INO, "hello.txt")), b"hello\n");
+
+    let sub = fs.lookup(ROOT_INO, "sub");
+    assert_eq!(fs.lookup(sub, ".."), ROOT_INO);
+    assert!(fs.contents(fs.lookup(sub, "data.bin")) == large);
+    assert!(fs.contents(fs.lookup(sub, "huge.bin")) == huge);
+
+    let many = fs.lookup(ROOT_INO, "many");
+    assert!(le32(fs.inode(many), 72) > 1, "entries in one hash level");
+    for i in 0..500 {
+        let file = fs.lookup(many, &format!("file-{i:03}.txt"));
+        assert_eq!(fs.contents(file), i.to_string().as_bytes());
+    }
+}
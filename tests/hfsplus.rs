@@ -0,0 +1,254 @@
+//! HFS+ images, read back by searching their catalog B-tree as Mac OS does.
+
+mod common;
+
+use common::{be16, be32, be64};
+use std::cmp::Ordering;
+use std::fs;
+
+const BLOCK_SIZE: usize = 4096;
+const ROOT_ID: u32 = 2;
+
+const NODE_LEAF: u8 = 0xff;
+const NODE_INDEX: u8 = 0;
+const NODE_HEADER: u8 = 1;
+
+const FOLDER_RECORD: u16 = 1;
+const FILE_RECORD: u16 = 2;
+const FOLDER_THREAD: u16 = 3;
+const FILE_THREAD: u16 = 4;
+
+fn utf16(s: &str) -> Vec<u16> {
+    s.encode_utf16().collect()
+}
+
+/// Catalog key, as the parent ID and name.
+type Key = (u32, Vec<u16>);
+
+/// Name stored with its length at `off`.
+fn name_at(b: &[u8], off: usize) -> Vec<u16> {
+    (0..be16(b, off) as usize)
+        .map(|i| be16(b, off + 2 + 2 * i))
+        .collect()
+}
+
+fn key_of(record: &[u8]) -> (Key, &[u8]) {
+    let len = be16(record, 0) as usize;
+    ((be32(record, 2), name_at(record, 6)), &record[2 + len..])
+}
+
+/// Order of catalog keys, folding case as Mac OS does for the ASCII names used here.
+fn compare(a: &Key, b: &Key) -> Ordering {
+    let fold = |s: &[u16]| {
+        s.iter()
+            .map(|&u| match u {
+                0x41..=0x5a => u + 0x20,
+                u => u,
+            })
+            .collect::<Vec<_>>()
+    };
+    a.0.cmp(&b.0).then_with(|| fold(&a.1).cmp(&fold(&b.1)))
+}
+
+/// Contents of a fork, in the extents of its record.
+fn fork(image: &[u8], fork: &[u8]) -> Vec<u8> {
+    let mut data = vec![];
+    for extent in fork[16..80].chunks(8) {
+        let (start, count) = (be32(extent, 0) as usize, be32(extent, 4) as usize);
+        data.extend_from_slice(&image[start * BLOCK_SIZE..(start + count) * BLOCK_SIZE]);
+    }
+    assert_eq!(data.len(), be32(fork, 12) as usize * BLOCK_SIZE);
+    data.truncate(be64(fork, 0) as usize);
+    data
+}
+
+struct Catalog {
+    file: Vec<u8>,
+    node_size: usize,
+    /// Header record of the B-tree
+    header: Vec<u8>,
+}
+
+impl Catalog {
+    fn open(file: Vec<u8>) -> Self {
+        let node_size = be16(&file, 14 + 18) as usize;
+        let mut catalog = Self {
+            file,
+            node_size,
+            header: vec![],
+        };
+        let (desc, records) = catalog.node(0);
+        assert_eq!(desc[8], NODE_HEADER);
+        catalog.header = records[0].to_vec();
+        catalog
+    }
+
+    fn root(&self) -> u32 {
+        be32(&self.header, 2)
+    }
+
+    fn depth(&self) -> u16 {
+        be16(&self.header, 0)
+    }
+
+    /// Descriptor of node `n`, and its records from their offsets at the end of it.
+    fn node(&self, n: u32) -> (&[u8], Vec<&[u8]>) {
+        let node = &self.file[n as usize * self.node_size..][..self.node_size];
+        let count = be16(node, 10) as usize;
+        let offset = |i: usize| be16(node, self.node_size - 2 * (i + 1)) as usize;
+        assert_eq!(offset(0), 14);
+
+        let records = (0..count)
+            .map(|i| {
+                assert!(offset(i) < offset(i + 1), "record offsets of node {n}");
+                &node[offset(i)..offset(i + 1)]
+            })
+            .collect();
+        (&node[..14], records)
+    }
+
+    /// Leaf nodes under node `n` at `height`, checking the first key of every child.
+    fn leaves(&self, n: u32, height: u16, first: Option<&Key>, out: &mut Vec<u32>) {
+        let (desc, records) = self.node(n);
+        assert_eq!(desc[9] as u16, height, "height of node {n}");
+        if let Some(first) = first {
+            assert_eq!(&key_of(records[0]).0, first, "first key of node {n}");
+        }
+
+        if height == 1 {
+            assert_eq!(desc[8], NODE_LEAF);
+            out.push(n);
+            return;
+        }
+        assert_eq!(desc[8], NODE_INDEX);
+        for record in records {
+            let (key, child) = key_of(record);
+            self.leaves(be32(child, 0), height - 1, Some(&key), out);
+        }
+    }
+
+    /// Record with `key`, found by descending the index from the root.
+    fn lookup(&self, key: &Key) -> Option<&[u8]> {
+        let mut n = self.root();
+        loop {
+            let (desc, records) = self.node(n);
+            if desc[8] == NODE_LEAF {
+                return records.into_iter().find_map(|r| {
+                    let (k, data) = key_of(r);
+                    compare(&k, key).is_eq().then_some(data)
+                });
+            }
+            let child = records
+                .into_iter()
+                .take_while(|r| compare(&key_of(r).0, key).is_le())
+                .last()?;
+            n = be32(key_of(child).1, 0);
+        }
+    }
+
+    /// Folder or file record of `name` in the folder `parent`, with the thread record of its ID
+    /// leading back to it.
+    fn find(&self, parent: u32, name: &[u16]) -> &[u8] {
+        let record = self
+            .lookup(&(parent, name.to_vec()))
+            .unwrap_or_else(|| panic!("no {} in folder {parent}", String::from_utf16_lossy(name)));
+        let thread = self.lookup(&(be32(record, 8), vec![])).unwrap();
+        let expected = match be16(record, 0) {
+            FOLDER_RECORD => FOLDER_THREAD,
+            FILE_RECORD => FILE_THREAD,
+            other => panic!("unexpected record type {other}"),
+        };
+        assert_eq!(be16(thread, 0), expected);
+        let back = (be32(thread, 4), name_at(thread, 8));
+        assert!(compare(&back, &(parent, name.to_vec())).is_eq());
+        record
+    }
+}
+
+#[test]
+fn files_read_back() {
+    let dir = common::scratch_dir("hfsplus");
+    fs::create_dir_all(dir.join("input/sub")).unwrap();
+    let large = (0..70000u32).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+    fs::write(dir.join("input/hello.txt"), "hello\n").unwrap();
+    fs::write(dir.join("input/Zebra"), "zebra\n").unwrap();
+    fs::write(dir.join("input/café"), "coffee\n").unwrap();
+    fs::write(dir.join("input/sub/data.bin"), &large).unwrap();
+    // Enough records for index nodes above the leaves
+    for i in 0..100 {
+        fs::write(dir.join(format!("input/sub/{i}")), i.to_string()).unwrap();
+    }
+
+    let image = common::build(&dir, "hfsplus.img", &["--filesystem", "hfsplus"]);
+    common::assert_blkid(&image, "hfsplus");
+    let image = fs::read(&image).unwrap();
+    fs::remove_dir_all(&dir).unwrap();
+
+    // Volume header, and its copy at the end
+    let header = &image[1024..1536];
+    assert_eq!(&header[0..2], b"H+");
+    assert!(header == &image[image.len() - 1024..image.len() - 512]);
+    assert_eq!(be32(header, 40) as usize, BLOCK_SIZE);
+    let blocks = be32(header, 44) as usize;
+    assert_eq!(blocks, image.len() / BLOCK_SIZE);
+    assert_eq!((be32(header, 32), be32(header, 36)), (104, 1));
+
+    // The allocation bitmap counts the free blocks, and has the blocks of the files in use
+    let bitmap = fork(&image, &header[112..192]);
+    let used = |block: usize| bitmap[block / 8] & 0x80 >> (block % 8) != 0;
+    let free = (0..blocks).filter(|&b| !used(b)).count();
+    assert_eq!(free, be32(header, 48) as usize);
+    assert!(used(0) && used(blocks - 1));
+
+    let catalog = Catalog::open(fork(&image, &header[272..352]));
+
+    // Every leaf is reached from the root and in the chain of leaves, in order
+    let mut leaves = vec![];
+    catalog.leaves(catalog.root(), catalog.depth(), None, &mut leaves);
+    assert!(catalog.depth() > 1, "catalog without index nodes");
+    assert_eq!(leaves[0], be32(&catalog.header, 10));
+    assert_eq!(*leaves.last().unwrap(), be32(&catalog.header, 14));
+    for pair in leaves.windows(2) {
+        assert_eq!(be32(catalog.node(pair[0]).0, 0), pair[1], "next leaf");
+        assert_eq!(be32(catalog.node(pair[1]).0, 4), pair[0], "previous leaf");
+    }
+    let keys = leaves
+        .iter()
+        .flat_map(|&n| catalog.node(n).1)
+        .map(|r| key_of(r).0)
+        .collect::<Vec<_>>();
+    assert_eq!(keys.len(), be32(&catalog.header, 6) as usize);
+    assert!(
+        keys.windows(2).all(|w| compare(&w[0], &w[1]).is_lt()),
+        "catalog out of order"
+    );
+
+    let contents = |record: &[u8]| {
+        assert_eq!(be16(record, 0), FILE_RECORD);
+        let start = be32(record, 88 + 16) as usize;
+        let count = be32(record, 88 + 20) as usize;
+        assert!((start..start + count).all(used), "file blocks free");
+        fork(&image, &record[88..168])
+    };
+
+    // Names are found regardless of case, and stored decomposed
+    assert_eq!(
+        contents(catalog.find(ROOT_ID, &utf16("HELLO.txt"))),
+        b"hello\n"
+    );
+    assert_eq!(contents(catalog.find(ROOT_ID, &utf16("zebra"))), b"zebra\n");
+    assert_eq!(
+        contents(catalog.find(ROOT_ID, &utf16("cafe\u{301}"))),
+        b"coffee\n"
+    );
+
+    let sub = catalog.find(ROOT_ID, &utf16("sub"));
+    assert_eq!(be16(sub, 0), FOLDER_RECORD);
+    assert_eq!(be32(sub, 4), 101, "valence");
+    let sub = be32(sub, 8);
+    assert!(contents(catalog.find(sub, &utf16("data.bin"))) == large);
+    for i in 0..100 {
+        let file = catalog.find(sub, &utf16(&i.to_string()));
+        assert_eq!(contents(file), i.to_string().as_bytes());
+    }
+}
@@ -0,0 +1,227 @@
+//! littlefs images, read back by following their metadata pairs and file skip-lists.
+
+mod common;
+
+use common::le32;
+use std::collections::BTreeMap;
+use std::fs;
+
+const BLOCK_SIZE: usize = 4096;
+
+/// CRC32 of littlefs, without the inversions of the usual one.
+fn crc(mut crc: u32, data: &[u8]) -> u32 {
+    for &b in data {
+        crc ^= b as u32;
+        for _ in 0..8 {
+            crc = (crc >> 1) ^ (0xedb8_8320 & (crc & 1).wrapping_neg());
+        }
+    }
+    crc
+}
+
+/// Entry of a directory, by the type of its name tag and of its struct tag.
+#[derive(Debug, Default)]
+struct Entry {
+    kind: u32,
+    name: Vec<u8>,
+    structure: u32,
+    data: Vec<u8>,
+}
+
+/// Entries of the committed metadata block of a pair, and the tail it points to.
+fn fetch(image: &[u8], pair: [u32; 2]) -> (Vec<Entry>, Option<(u32, [u32; 2])>) {
+    // The block with the newer revision holds the latest commits
+    let [a, b] = pair.map(|block| &image[block as usize * BLOCK_SIZE..][..BLOCK_SIZE]);
+    let block = match (le32(a, 0), le32(b, 0)) {
+        (_, u32::MAX) => a,
+        (ra, rb) if (rb.wrapping_sub(ra) as i32) > 0 => b,
+        _ => a,
+    };
+
+    let mut entries = BTreeMap::<u32, Entry>::new();
+    let mut tail = None;
+    let mut commits = 0;
+    let (mut off, mut ptag, mut sum) = (4, u32::MAX, crc(u32::MAX, &block[..4]));
+
+    while off + 4 <= BLOCK_SIZE {
+        let stored = &block[off..off + 4];
+        let tag = u32::from_be_bytes(stored.try_into().unwrap()) ^ ptag;
+        if tag & 0x8000_0000 != 0 {
+            break;
+        }
+        let (ty, id, size) = (tag >> 20 & 0x7ff, tag >> 10 & 0x3ff, tag & 0x3ff);
+        let data = &block[off + 4..off + 4 + size as usize];
+        sum = crc(sum, stored);
+
+        if ty & 0x700 == 0x500 {
+            assert_eq!(le32(data, 0), sum, "CRC of commit {commits}");
+            commits += 1;
+            sum = u32::MAX;
+        } else {
+            sum = crc(sum, data);
+            match ty {
+                0x001 | 0x002 | 0x0ff => {
+                    let entry = entries.entry(id).or_default();
+                    entry.kind = ty;
+                    entry.name = data.to_vec();
+                }
+                0x200..=0x202 => {
+                    let entry = entries.entry(id).or_default();
+                    entry.structure = ty;
+                    entry.data = data.to_vec();
+                }
+                0x600 | 0x601 => tail = Some((ty, [le32(data, 0), le32(data, 4)])),
+                _ => panic!("unexpected tag {tag:#x}"),
+            }
+        }
+
+        ptag = tag;
+        off += 4 + size as usize;
+    }
+
+    assert!(commits > 0, "no commit in blocks {pair:?}");
+    (entries.into_values().collect(), tail)
+}
+
+/// Entries of the directory starting at `pair`, following its hard tails.
+fn read_dir(image: &[u8], mut pair: [u32; 2]) -> Vec<Entry> {
+    let mut entries = vec![];
+    loop {
+        let (more, tail) = fetch(image, pair);
+        entries.extend(more);
+        match tail {
+            Some((0x601, next)) => pair = next,
+            _ => return entries,
+        }
+    }
+}
+
+/// Contents of a file stored in a skip-list ending with block `head`.
+fn read_ctz(image: &[u8], head: u32, size: usize) -> Vec<u8> {
+    let pointers = |i: usize| {
+        if i == 0 {
+            0
+        } else {
+            i.trailing_zeros() as usize + 1
+        }
+    };
+
+    // Index of the last block, from how much each block holds after its pointers
+    let (mut last, mut held) = (0, BLOCK_SIZE);
+    while held < size {
+        last += 1;
+        held += BLOCK_SIZE - 4 * pointers(last);
+    }
+
+    let mut blocks = vec![head];
+    for _ in 0..last {
+        let block = *blocks.last().unwrap() as usize;
+        blocks.push(le32(image, block * BLOCK_SIZE));
+    }
+    blocks.reverse();
+
+    let mut data = vec![];
+    for (i, block) in blocks.into_iter().enumerate() {
+        let block = &image[block as usize * BLOCK_SIZE..][..BLOCK_SIZE];
+        data.extend_from_slice(&block[4 * pointers(i)..]);
+    }
+    data.truncate(size);
+    data
+}
+
+fn find<'a>(entries: &'a [Entry], name: &str) -> &'a Entry {
+    entries
+        .iter()
+        .find(|e| e.name == name.as_bytes())
+        .unwrap_or_else(|| panic!("no {name}"))
+}
+
+fn contents(image: &[u8], entry: &Entry) -> Vec<u8> {
+    assert_eq!(entry.kind, 0x001);
+    match entry.structure {
+        0x201 => entry.data.clone(),
+        0x202 => read_ctz(image, le32(&entry.data, 0), le32(&entry.data, 4) as usize),
+        other => panic!("unexpected struct {other:#x}"),
+    }
+}
+
+fn pair_of(entry: &Entry) -> [u32; 2] {
+    assert_eq!((entry.kind, entry.structure), (0x002, 0x200));
+    [le32(&entry.data, 0), le32(&entry.data, 4)]
+}
+
+#[test]
+fn files_read_back() {
+    let dir = common::scratch_dir("littlefs");
+    fs::create_dir_all(dir.join("input/sub")).unwrap();
+    fs::create_dir_all(dir.join("input/many")).unwrap();
+    let large = (0..70000u32).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+    fs::write(dir.join("input/hello.txt"), "hello\n").unwrap();
+    fs::write(dir.join("input/sub/data.bin"), &large).unwrap();
+    // More entries than half a block holds, split over several pairs
+    for i in 0..150 {
+        fs::write(
+            dir.join(format!("input/many/file-{i:03}.txt")),
+            i.to_string(),
+        )
+        .unwrap();
+    }
+
+    let image = common::build(
+        &dir,
+        "littlefs.img",
+        &["--filesystem", "littlefs", "--size", "1M"],
+    );
+    let image = fs::read(&image).unwrap();
+    fs::remove_dir_all(&dir).unwrap();
+
+    let root = read_dir(&image, [0, 1]);
+
+    let superblock = &root[0];
+    assert_eq!((superblock.kind, superblock.structure), (0x0ff, 0x201));
+    assert_eq!(superblock.name, b"littlefs");
+    assert_eq!(le32(&superblock.data, 0), 0x0002_0000);
+    assert_eq!(le32(&superblock.data, 4) as usize, BLOCK_SIZE);
+    assert_eq!(le32(&superblock.data, 8) as usize, image.len() / BLOCK_SIZE);
+
+    assert_eq!(contents(&image, find(&root, "hello.txt")), b"hello\n");
+
+    let sub = read_dir(&image, pair_of(find(&root, "sub")));
+    assert!(contents(&image, find(&sub, "data.bin")) == large);
+
+    let many = read_dir(&image, pair_of(find(&root, "many")));
+    assert_eq!(many.len(), 150);
+    for i in 0..150 {
+        let file = find(&many, &format!("file-{i:03}.txt"));
+        assert_eq!(contents(&image, file), i.to_string().as_bytes());
+    }
+}
+
+/// First metadata block of an 8KiB image holding only `hello.txt`, inline in the root directory,
+/// which the reference littlefs implementation mounts and reads the file back from. The rest of
+/// both blocks is left erased.
+const HELLO_BLOCK: [u8; 75] = [
+    0x01, 0x00, 0x00, 0x00, 0xf0, 0x0f, 0xff, 0xf7, 0x6c, 0x69, 0x74, 0x74, 0x6c, 0x65, 0x66, 0x73,
+    0x2f, 0xe0, 0x00, 0x10, 0x00, 0x00, 0x02, 0x00, 0x00, 0x10, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00,
+    0xff, 0x00, 0x00, 0x00, 0xff, 0xff, 0xff, 0x7f, 0xfe, 0x03, 0x00, 0x00, 0x20, 0x00, 0x04, 0x11,
+    0x68, 0x65, 0x6c, 0x6c, 0x6f, 0x2e, 0x74, 0x78, 0x74, 0x20, 0x00, 0x00, 0x0f, 0x68, 0x65, 0x6c,
+    0x6c, 0x6f, 0x0a, 0x70, 0x1f, 0xf8, 0x0f, 0x0b, 0x10, 0x66, 0x7f,
+];
+
+#[test]
+fn matches_reference_bytes() {
+    let dir = common::scratch_dir("littlefs-reference");
+    fs::write(dir.join("input/hello.txt"), "hello\n").unwrap();
+
+    let image = common::build(
+        &dir,
+        "littlefs.img",
+        &["--filesystem", "littlefs", "--size", "8K"],
+    );
+    let image = fs::read(&image).unwrap();
+    fs::remove_dir_all(&dir).unwrap();
+
+    assert_eq!(image.len(), 2 * BLOCK_SIZE);
+    assert_eq!(image[..HELLO_BLOCK.len()], HELLO_BLOCK);
+    assert!(image[HELLO_BLOCK.len()..].iter().all(|&b| b == 0xff));
+}
@@ -0,0 +1,319 @@
+//! Subcommands changing the partition tables of existing images.
+
+mod common;
+
+use clap::Parser;
+use mkimg::Cli;
+use std::fs;
+use std::io::{Cursor, Read};
+use std::path::{Path, PathBuf};
+
+/// Layout of two primary FAT partitions followed by three logical ones.
+const LOGICAL_LAYOUT: &str = r#"
+[[partition]]
+name = "one"
+filesystem = "vfat"
+size = "2M"
+input = "input"
+
+[[partition]]
+name = "two"
+filesystem = "vfat"
+size = "2M"
+input = "input"
+
+[[partition]]
+name = "three"
+filesystem = "vfat"
+size = "2M"
+input = "input"
+primary = false
+
+[[partition]]
+name = "four"
+filesystem = "vfat"
+size = "2M"
+input = "input"
+primary = false
+
+[[partition]]
+name = "five"
+filesystem = "vfat"
+size = "8M"
+input = "input"
+primary = false
+"#;
+
+fn scratch(name: &str) -> PathBuf {
+    let dir = common::scratch_dir(name);
+    fs::write(dir.join("input/hello.txt"), "hello\n").unwrap();
+    dir
+}
+
+fn try_mkimg(args: &[&Path]) -> anyhow::Result<()> {
+    let argv = std::iter::once(Path::new("mkimg")).chain(args.iter().copied());
+    mkimg::run(Cli::parse_from(argv))
+}
+
+fn mkimg(args: &[&Path]) {
+    try_mkimg(args).unwrap();
+}
+
+/// Build an MBR image of `layout` in `dir`.
+fn build_mbr(dir: &Path, layout: &str) -> PathBuf {
+    let image = dir.join("disk.img");
+    fs::write(dir.join("layout.toml"), layout).unwrap();
+    mkimg(&[
+        "--deterministic".as_ref(),
+        "test".as_ref(),
+        "--partition-table".as_ref(),
+        "mbr".as_ref(),
+        "--layout".as_ref(),
+        &dir.join("layout.toml"),
+        "--output-path".as_ref(),
+        &image,
+    ]);
+    image
+}
+
+/// Byte ranges of the partitions of an MBR, logical ones included.
+fn mbr_ranges(image: &Path) -> Vec<(u64, u64)> {
+    let mbr = mbrman::MBR::read_from(&mut fs::File::open(image).unwrap(), 512).unwrap();
+    mbr.iter()
+        .filter(|(_, p)| p.is_used() && !p.is_extended())
+        .map(|(_, p)| (p.starting_lba as u64 * 512, p.sectors as u64 * 512))
+        .collect()
+}
+
+#[test]
+fn convert_logical_partitions_to_gpt() {
+    let dir = scratch("convert-logical");
+    let image = build_mbr(&dir, LOGICAL_LAYOUT);
+    let ranges = mbr_ranges(&image);
+    assert_eq!(ranges.len(), 5);
+
+    mkimg(&[
+        "convert-table".as_ref(),
+        "--image".as_ref(),
+        &image,
+        "--to".as_ref(),
+        "gpt".as_ref(),
+    ]);
+
+    let disk = gpt::GptConfig::new().writable(false).open(&image).unwrap();
+    let converted = disk
+        .partitions()
+        .values()
+        .map(|p| (p.first_lba * 512, (p.last_lba - p.first_lba + 1) * 512))
+        .collect::<Vec<_>>();
+    fs::remove_dir_all(&dir).unwrap();
+
+    assert_eq!(converted, ranges);
+}
+
+/// Contents of the partitions of an MBR image, logical ones included.
+fn mbr_contents(image: &Path) -> Vec<Vec<u8>> {
+    let data = fs::read(image).unwrap();
+    mbr_ranges(image)
+        .into_iter()
+        .map(|(start, len)| data[start as usize..(start + len) as usize].to_vec())
+        .collect()
+}
+
+#[test]
+fn split_and_rebuild_logical_partitions() {
+    let dir = scratch("split-logical");
+    let image = build_mbr(&dir, LOGICAL_LAYOUT);
+    let split = dir.join("split");
+
+    mkimg(&[
+        "split-partitions".as_ref(),
+        &image,
+        "--output".as_ref(),
+        &split,
+    ]);
+    let manifest = fs::read_to_string(split.join("layout.toml")).unwrap();
+    assert_eq!(manifest.matches("[[partition]]").count(), 5);
+
+    let rebuilt = dir.join("rebuilt.img");
+    mkimg(&[
+        "--deterministic".as_ref(),
+        "test".as_ref(),
+        "--partition-table".as_ref(),
+        "mbr".as_ref(),
+        "--layout".as_ref(),
+        &split.join("layout.toml"),
+        "--output-path".as_ref(),
+        &rebuilt,
+    ]);
+
+    let logical = mbrman::MBR::read_from(&mut fs::File::open(&rebuilt).unwrap(), 512)
+        .unwrap()
+        .logical_partitions
+        .len();
+    let (original, rebuilt) = (mbr_contents(&image), mbr_contents(&rebuilt));
+    fs::remove_dir_all(&dir).unwrap();
+
+    assert_eq!(logical, 3);
+    assert!(original == rebuilt, "partitions differ after splitting");
+}
+
+/// Check that the extended partition, the last logical partition and the entry of the EBR before
+/// linking to it all end with the image.
+fn assert_logical_last(image: &Path) {
+    let mut file = fs::File::open(image).unwrap();
+    let sectors = file.metadata().unwrap().len() / 512;
+    let mbr = mbrman::MBR::read_from(&mut file, 512).unwrap();
+
+    let extended = mbr.header.iter().find(|(_, p)| p.is_extended()).unwrap().1;
+    let last = mbr.logical_partitions.last().unwrap();
+
+    assert_eq!(
+        extended.starting_lba as u64 + extended.sectors as u64,
+        sectors
+    );
+    assert_eq!(
+        last.partition.starting_lba as u64 + last.partition.sectors as u64,
+        sectors
+    );
+    assert_eq!(
+        last.absolute_ebr_lba as u64 + last.ebr_sectors.unwrap() as u64,
+        sectors
+    );
+}
+
+#[test]
+fn resize_logical_last_partition() {
+    let dir = scratch("resize-logical");
+    let image = build_mbr(&dir, LOGICAL_LAYOUT);
+    let before = mbr_contents(&image);
+
+    mkimg(&[
+        "resize".as_ref(),
+        "--image".as_ref(),
+        &image,
+        "--size".as_ref(),
+        "24M".as_ref(),
+    ]);
+
+    assert_eq!(fs::metadata(&image).unwrap().len(), 24 << 20);
+    assert_logical_last(&image);
+    let after = mbr_contents(&image);
+    fs::remove_dir_all(&dir).unwrap();
+
+    assert!(
+        before[..4] == after[..4],
+        "partitions before the last changed"
+    );
+}
+
+#[test]
+fn shrink_logical_last_partition() {
+    let dir = scratch("shrink-logical");
+    let image = build_mbr(&dir, LOGICAL_LAYOUT);
+    let before = mbr_contents(&image);
+
+    mkimg(&[
+        "shrink".as_ref(),
+        "--image".as_ref(),
+        &image,
+        "--slack".as_ref(),
+        "64K".as_ref(),
+    ]);
+
+    assert!(fs::metadata(&image).unwrap().len() < 16 << 20);
+    assert_logical_last(&image);
+    let after = mbr_contents(&image);
+    fs::remove_dir_all(&dir).unwrap();
+
+    assert!(
+        before[..4] == after[..4],
+        "partitions before the last changed"
+    );
+    let last = Cursor::new(after[4].clone());
+    let fs = fatfs::FileSystem::new(last, fatfs::FsOptions::new()).unwrap();
+    let mut hello = String::new();
+    let mut file = fs.root_dir().open_file("hello.txt").unwrap();
+    file.read_to_string(&mut hello).unwrap();
+    assert_eq!(hello, "hello\n");
+}
+
+#[test]
+fn resize_refuses_fat_it_cannot_grow() {
+    let dir = scratch("resize-fat12");
+    let image = dir.join("disk.img");
+    mkimg(&[
+        "--deterministic".as_ref(),
+        "test".as_ref(),
+        "--partition-table".as_ref(),
+        "gpt".as_ref(),
+        "--size".as_ref(),
+        "2M".as_ref(),
+        "--input-dir".as_ref(),
+        &dir.join("input"),
+        "--output-path".as_ref(),
+        &image,
+    ]);
+    let before = fs::read(&image).unwrap();
+
+    // FAT12 with 512 byte clusters addresses about 2 MiB
+    let resized = try_mkimg(&[
+        "resize".as_ref(),
+        "--image".as_ref(),
+        &image,
+        "--size".as_ref(),
+        "16M".as_ref(),
+    ]);
+    let after = fs::read(&image).unwrap();
+    fs::remove_dir_all(&dir).unwrap();
+
+    assert!(resized.is_err());
+    assert!(before == after, "the image changed");
+}
+
+#[test]
+fn list_and_extract_4k_sector_images() {
+    for table in ["gpt", "mbr"] {
+        let dir = scratch(&format!("4kn-{table}"));
+        let image = dir.join("disk.img");
+        let layout = match table {
+            "gpt" => LOGICAL_LAYOUT.replace("primary = false\n", ""),
+            _ => LOGICAL_LAYOUT.to_owned(),
+        };
+        fs::write(dir.join("layout.toml"), layout).unwrap();
+        mkimg(&[
+            "--deterministic".as_ref(),
+            "test".as_ref(),
+            "--sector-size".as_ref(),
+            "4096".as_ref(),
+            "--partition-table".as_ref(),
+            table.as_ref(),
+            "--layout".as_ref(),
+            &dir.join("layout.toml"),
+            "--output-path".as_ref(),
+            &image,
+        ]);
+
+        for number in ["1", "5"] {
+            mkimg(&[
+                "list".as_ref(),
+                &image,
+                "--partition".as_ref(),
+                number.as_ref(),
+            ]);
+        }
+        mkimg(&[
+            "extract".as_ref(),
+            "--image".as_ref(),
+            &image,
+            "--partition".as_ref(),
+            "5".as_ref(),
+            "--output-dir".as_ref(),
+            &dir.join("out"),
+        ]);
+
+        let hello = fs::read_to_string(dir.join("out/hello.txt")).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(hello, "hello\n", "{table}");
+    }
+}
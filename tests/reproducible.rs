@@ -0,0 +1,81 @@
+//! Building the same input twice with a fixed seed and time must give the same bytes.
+
+mod common;
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Scratch directory of `name` with a few files and directories to build from.
+fn scratch(name: &str) -> PathBuf {
+    let dir = common::scratch_dir(name);
+    fs::create_dir_all(dir.join("input/sub/deeper")).unwrap();
+    fs::write(dir.join("input/hello.txt"), "hello\n").unwrap();
+    fs::write(dir.join("input/sub/data.bin"), vec![0xa5; 70000]).unwrap();
+    fs::write(dir.join("input/sub/deeper/empty"), "").unwrap();
+    dir
+}
+
+/// Image built from the scratch directory at a fixed time.
+fn build(dir: &Path, name: &str, extra: &[&str]) -> Vec<u8> {
+    let args = [&["--timestamp", "1700000000"], extra].concat();
+    fs::read(common::build(dir, name, &args)).unwrap()
+}
+
+fn assert_reproducible(name: &str, extra: &[&str]) {
+    let dir = scratch(name);
+    let first = build(&dir, "first.img", extra);
+    let second = build(&dir, "second.img", extra);
+    fs::remove_dir_all(&dir).unwrap();
+    assert!(first == second, "{name} images differ between builds");
+}
+
+#[test]
+fn filesystems() {
+    for fs in [
+        "vfat", "ext2", "exfat", "iso9660", "squashfs", "erofs", "romfs", "cramfs", "jffs2", "xfs",
+        "btrfs", "udf", "hfsplus", "f2fs", "littlefs", "ubifs",
+    ] {
+        assert_reproducible(fs, &["--filesystem", fs, "--size", "64M"]);
+    }
+}
+
+#[test]
+fn btrfs_subvolumes() {
+    assert_reproducible(
+        "btrfs-subvolumes",
+        &[
+            "--filesystem",
+            "btrfs",
+            "--btrfs-subvolume",
+            "@",
+            "--btrfs-subvolume",
+            "@/nested",
+            "--btrfs-default-subvolume",
+            "@",
+        ],
+    );
+}
+
+#[test]
+fn partition_tables() {
+    for table in ["gpt", "mbr", "hybrid"] {
+        assert_reproducible(
+            table,
+            &[
+                "--partition-table",
+                table,
+                "--size",
+                "4M",
+                "--fill",
+                "random",
+            ],
+        );
+    }
+}
+
+#[test]
+fn archives() {
+    for fs in ["tar", "initramfs"] {
+        assert_reproducible(fs, &["--filesystem", fs]);
+    }
+}
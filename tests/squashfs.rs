@@ -0,0 +1,114 @@
+//! squashfs images compressed by the zstd and xz tools, read back by decompressing their blocks
+//! with the same tools.
+
+mod common;
+
+use common::{le16, le32, le64};
+use std::fs;
+use std::io::Write;
+use std::process::{Command, Stdio};
+
+fn decompress(tool: &str, data: &[u8]) -> Vec<u8> {
+    let mut child = Command::new(tool)
+        .args(["-d", "-c"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child.stdin.take().unwrap().write_all(data).unwrap();
+    let output = child.wait_with_output().unwrap();
+    assert!(output.status.success(), "{tool} -d failed");
+    output.stdout
+}
+
+/// Contents of `file` in an image compressed with `tool`, found in the root directory.
+fn read_back(tool: &str, id: u16, image: &[u8], file: &str) -> Vec<u8> {
+    assert_eq!(le32(image, 0), 0x7371_7368);
+    assert_eq!(le16(image, 20), id, "compressor");
+    let block_size = le32(image, 12) as usize;
+
+    // Metadata blocks of a table, by their offset from its start
+    let table = |start: u64, end: u64| {
+        let (mut data, mut blocks) = (vec![], vec![]);
+        let mut pos = start as usize;
+        while pos < end as usize {
+            let header = le16(image, pos);
+            let block = &image[pos + 2..pos + 2 + (header & 0x7fff) as usize];
+            blocks.push((pos - start as usize, data.len()));
+            match header & 0x8000 {
+                0 => data.extend(decompress(tool, block)),
+                _ => data.extend_from_slice(block),
+            }
+            pos += 2 + block.len();
+        }
+        (data, blocks)
+    };
+    let (inodes, inode_blocks) = table(le64(image, 64), le64(image, 72));
+    let (dirs, dir_blocks) = table(le64(image, 72), le64(image, 48));
+    let at = |blocks: &[(usize, usize)], block: u32, offset: u16| {
+        let (_, start) = blocks.iter().find(|&&(b, _)| b == block as usize).unwrap();
+        start + offset as usize
+    };
+
+    let root = le64(image, 32);
+    let root = &inodes[at(&inode_blocks, (root >> 16) as u32, root as u16)..];
+    assert_eq!(le16(root, 0), 1, "root directory");
+    let listing = at(&dir_blocks, le32(root, 16), le16(root, 26));
+    let listing = &dirs[listing..listing + le16(root, 24) as usize - 3];
+
+    let (mut pos, mut inode) = (0, None);
+    while pos < listing.len() && inode.is_none() {
+        let (count, block) = (le32(listing, pos) + 1, le32(listing, pos + 4));
+        pos += 12;
+        for _ in 0..count {
+            let entry = &listing[pos..];
+            let len = le16(entry, 6) as usize + 1;
+            if &entry[8..8 + len] == file.as_bytes() {
+                inode = Some(at(&inode_blocks, block, le16(entry, 0)));
+            }
+            pos += 8 + len;
+        }
+    }
+
+    let inode = &inodes[inode.unwrap_or_else(|| panic!("no {file}"))..];
+    assert_eq!(le16(inode, 0), 2, "basic file");
+    let (mut pos, len) = (le32(inode, 16) as usize, le32(inode, 28) as usize);
+    let mut data = vec![];
+    for i in 0..len.div_ceil(block_size) {
+        let size = le32(inode, 32 + 4 * i);
+        let block = &image[pos..pos + (size & 0xff_ffff) as usize];
+        match size & 1 << 24 {
+            0 => data.extend(decompress(tool, block)),
+            _ => data.extend_from_slice(block),
+        }
+        pos += block.len();
+    }
+    data
+}
+
+#[test]
+fn zstd_and_xz_blocks_read_back() {
+    let dir = common::scratch_dir("squashfs");
+    let text = (0..40000).map(|i| format!("{i}\n")).collect::<String>();
+    fs::write(dir.join("input/numbers.txt"), &text).unwrap();
+
+    for (tool, id) in [("zstd", 6), ("xz", 4)] {
+        if !common::installed(tool) {
+            continue;
+        }
+
+        let name = format!("{tool}.img");
+        let image = common::build(
+            &dir,
+            &name,
+            &["--filesystem", "squashfs", "--compression", tool],
+        );
+        let image = fs::read(&image).unwrap();
+
+        let data = read_back(tool, id, &image, "numbers.txt");
+        assert!(data == text.as_bytes(), "contents of numbers.txt");
+        assert!(image.len() < text.len() / 2, "blocks left uncompressed");
+    }
+
+    fs::remove_dir_all(&dir).unwrap();
+}
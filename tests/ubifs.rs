@@ -0,0 +1,186 @@
+//! UBI images, read back through their UBI headers, volume table and UBIFS index.
+
+mod common;
+
+use common::{be32, le16, le32, le64};
+use std::collections::BTreeMap;
+use std::fs;
+
+const PEB_SIZE: usize = 128 << 10;
+const LAYOUT_VOLUME_ID: u32 = 0x7fff_efff;
+const NODE_MAGIC: u32 = 0x0610_1831;
+
+const INO_NODE: u8 = 0;
+const DATA_NODE: u8 = 1;
+const DENT_NODE: u8 = 2;
+const SB_NODE: u8 = 6;
+const MST_NODE: u8 = 7;
+const IDX_NODE: u8 = 9;
+
+/// CRC32 of UBI and UBIFS, seeded with all ones and not inverted at the end.
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = u32::MAX;
+    for &b in data {
+        crc ^= b as u32;
+        for _ in 0..8 {
+            crc = (crc >> 1) ^ (0xedb8_8320 & (crc & 1).wrapping_neg());
+        }
+    }
+    crc
+}
+
+/// LEBs of every volume by volume ID and LEB number, checking the UBI headers of all PEBs.
+fn lebs(image: &[u8]) -> BTreeMap<(u32, u32), &[u8]> {
+    let mut lebs = BTreeMap::new();
+    let mut image_seq = None;
+
+    for peb in image.chunks(PEB_SIZE) {
+        assert_eq!(&peb[0..4], b"UBI#");
+        assert_eq!(be32(peb, 60), crc32(&peb[..60]), "erase counter header CRC");
+        let (vid_offset, data_offset) = (be32(peb, 16) as usize, be32(peb, 20) as usize);
+        assert_eq!(*image_seq.get_or_insert(be32(peb, 24)), be32(peb, 24));
+
+        let vid = &peb[vid_offset..vid_offset + 64];
+        if vid.iter().all(|&b| b == 0xff) {
+            assert!(peb[data_offset..].iter().all(|&b| b == 0xff));
+            continue;
+        }
+        assert_eq!(&vid[0..4], b"UBI!");
+        assert_eq!(be32(vid, 60), crc32(&vid[..60]), "volume ID header CRC");
+
+        let key = (be32(vid, 8), be32(vid, 12));
+        assert!(
+            lebs.insert(key, &peb[data_offset..]).is_none(),
+            "LEB {key:?} mapped twice"
+        );
+    }
+
+    lebs
+}
+
+/// Node at `offs` of a LEB, with its header checked.
+fn node(leb: &[u8], offs: usize, node_type: u8) -> &[u8] {
+    let node = &leb[offs..offs + le32(leb, offs + 16) as usize];
+    assert_eq!(le32(node, 0), NODE_MAGIC);
+    assert_eq!(le32(node, 4), crc32(&node[8..]), "node CRC");
+    assert_eq!(node[20], node_type);
+    node
+}
+
+/// Nodes the index leads to from the index node at `branch`, by their keys.
+fn walk<'a>(
+    volume: &BTreeMap<u32, &'a [u8]>,
+    (lnum, offs, len): (u32, u32, u32),
+    leaves: &mut BTreeMap<(u32, u32), &'a [u8]>,
+) {
+    let idx = node(volume[&lnum], offs as usize, IDX_NODE);
+    assert_eq!(idx.len(), len as usize);
+    let (count, level) = (le16(idx, 24) as usize, le16(idx, 26));
+
+    for branch in idx[28..].chunks(20).take(count) {
+        let child = (le32(branch, 0), le32(branch, 4), le32(branch, 8));
+        let key = (le32(branch, 12), le32(branch, 16));
+        if level > 0 {
+            walk(volume, child, leaves);
+            continue;
+        }
+
+        let leb = volume[&child.0];
+        let leaf = &leb[child.1 as usize..][..child.2 as usize];
+        let leaf = node(leb, child.1 as usize, leaf[20]);
+        assert_eq!((le32(leaf, 24), le32(leaf, 28)), key, "key of a leaf");
+        assert!(leaves.insert(key, leaf).is_none());
+    }
+}
+
+/// Inode of `name` in the directory `dir`, from the directory entry nodes.
+fn lookup(leaves: &BTreeMap<(u32, u32), &[u8]>, dir: u32, name: &str) -> u32 {
+    leaves
+        .values()
+        .filter(|n| n[20] == DENT_NODE && le32(n, 24) == dir)
+        .find(|n| &n[56..56 + le16(n, 50) as usize] == name.as_bytes())
+        .map(|n| le64(n, 40) as u32)
+        .unwrap_or_else(|| panic!("no {name} in directory {dir}"))
+}
+
+/// Contents of the file `inum`, from its data nodes.
+fn contents(leaves: &BTreeMap<(u32, u32), &[u8]>, inum: u32) -> Vec<u8> {
+    let size = le64(leaves[&(inum, 0)], 48) as usize;
+    assert_eq!(leaves[&(inum, 0)][20], INO_NODE);
+
+    let mut data = vec![0; size];
+    for (&(_, key), n) in leaves.range((inum, 1)..(inum + 1, 0)) {
+        assert_eq!(n[20], DATA_NODE);
+        let block = (key & ((1 << 29) - 1)) as usize;
+        let len = le32(n, 40) as usize;
+        let bytes = match le16(n, 44) {
+            0 => n[48..].to_vec(),
+            2 => mkimg::compress::inflate(&n[48..]).unwrap(),
+            other => panic!("unknown compression {other}"),
+        };
+        assert_eq!(bytes.len(), len);
+        data[block * 4096..block * 4096 + len].copy_from_slice(&bytes);
+    }
+    data
+}
+
+#[test]
+fn files_read_back() {
+    let dir = common::scratch_dir("ubifs");
+    fs::create_dir_all(dir.join("input/sub")).unwrap();
+    let large = (0..70000u32).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+    fs::write(dir.join("input/hello.txt"), "hello\n").unwrap();
+    fs::write(dir.join("input/sub/data.bin"), &large).unwrap();
+    // Enough entries for an index of several levels
+    for i in 0..100 {
+        fs::write(dir.join(format!("input/sub/{i}")), i.to_string()).unwrap();
+    }
+
+    let path = common::build(&dir, "ubi.img", &["--filesystem", "ubifs", "--size", "32M"]);
+    common::assert_blkid(&path, "ubi");
+    let image = fs::read(&path).unwrap();
+
+    let lebs = lebs(&image);
+
+    // Both copies of the volume table name the UBIFS volume
+    for lnum in 0..2 {
+        let record = &lebs[&(LAYOUT_VOLUME_ID, lnum)][..172];
+        assert_eq!(be32(record, 168), crc32(&record[..168]), "volume table CRC");
+        assert_eq!(&record[16..16 + 6], b"rootfs");
+    }
+
+    let volume = lebs
+        .iter()
+        .filter(|((vol, _), _)| *vol == 0)
+        .map(|(&(_, lnum), &leb)| (lnum, leb))
+        .collect::<BTreeMap<_, _>>();
+
+    // blkid also finds UBIFS in the first LEB of the volume, with the superblock node
+    let first = dir.join("leb0.img");
+    fs::write(&first, volume[&0]).unwrap();
+    common::assert_blkid(&first, "ubifs");
+    fs::remove_dir_all(&dir).unwrap();
+
+    let sb = node(volume[&0], 0, SB_NODE);
+    assert_eq!(le32(sb, 36) as usize, volume[&0].len(), "LEB size");
+    let mst = node(volume[&1], 0, MST_NODE);
+    assert!(mst == node(volume[&2], 0, MST_NODE));
+
+    let mut leaves = BTreeMap::new();
+    walk(
+        &volume,
+        (le32(mst, 48), le32(mst, 52), le32(mst, 56)),
+        &mut leaves,
+    );
+
+    assert_eq!(
+        contents(&leaves, lookup(&leaves, 1, "hello.txt")),
+        b"hello\n"
+    );
+    let sub = lookup(&leaves, 1, "sub");
+    assert!(contents(&leaves, lookup(&leaves, sub, "data.bin")) == large);
+    for i in 0..100 {
+        let file = lookup(&leaves, sub, &i.to_string());
+        assert_eq!(contents(&leaves, file), i.to_string().as_bytes());
+    }
+}
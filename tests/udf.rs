@@ -0,0 +1,239 @@
+//! UDF images, read back from their anchors through the volume descriptors and file entries.
+
+mod common;
+
+use common::{le16, le32, le64};
+use std::fs;
+
+const BLOCK_SIZE: usize = 512;
+
+const TAG_AVDP: u16 = 2;
+const TAG_PD: u16 = 5;
+const TAG_LVD: u16 = 6;
+const TAG_TD: u16 = 8;
+const TAG_LVID: u16 = 9;
+const TAG_FSD: u16 = 256;
+const TAG_FID: u16 = 257;
+const TAG_FE: u16 = 261;
+const TAG_SBD: u16 = 264;
+
+/// CRC-ITU-T of descriptors.
+fn crc16(data: &[u8]) -> u16 {
+    let mut crc = 0u16;
+    for &b in data {
+        crc ^= (b as u16) << 8;
+        for _ in 0..8 {
+            crc = (crc << 1) ^ (0x1021 & (crc >> 15).wrapping_neg());
+        }
+    }
+    crc
+}
+
+/// Descriptor at the start of `d`, with its tag checked against its identifier and location.
+fn descriptor(d: &[u8], id: u16, location: u32) -> &[u8] {
+    let checksum = d[..16]
+        .iter()
+        .enumerate()
+        .filter(|&(i, _)| i != 4)
+        .fold(0u8, |sum, (_, &b)| sum.wrapping_add(b));
+    assert_eq!(d[4], checksum, "tag checksum at {location}");
+    assert_eq!(le16(d, 0), id, "descriptor at {location}");
+    assert_eq!(le32(d, 12), location);
+
+    let len = le16(d, 10) as usize;
+    assert_eq!(le16(d, 8), crc16(&d[16..16 + len]), "CRC at {location}");
+    &d[..16 + len]
+}
+
+/// Name in OSTA compressed Unicode.
+fn cs0(b: &[u8]) -> String {
+    match b[0] {
+        8 => b[1..].iter().map(|&c| c as char).collect(),
+        16 => String::from_utf16(
+            &b[1..]
+                .chunks(2)
+                .map(|c| u16::from_be_bytes([c[0], c[1]]))
+                .collect::<Vec<_>>(),
+        )
+        .unwrap(),
+        other => panic!("unknown compression ID {other}"),
+    }
+}
+
+struct Udf {
+    image: Vec<u8>,
+    partition: usize,
+    /// Partition blocks taken by descriptors and contents, read from them
+    used: Vec<u32>,
+}
+
+impl Udf {
+    fn block(&self, block: u32) -> &[u8] {
+        &self.image[block as usize * BLOCK_SIZE..][..BLOCK_SIZE]
+    }
+
+    fn part_block(&mut self, block: u32) -> &[u8] {
+        self.used.push(block);
+        &self.image[(self.partition + block as usize) * BLOCK_SIZE..][..BLOCK_SIZE]
+    }
+
+    /// File entry at `block` of the partition, and its contents.
+    fn entry(&mut self, block: u32) -> (u8, Vec<u8>) {
+        let (file_type, data, _) = self.entry_at(block);
+        (file_type, data)
+    }
+
+    /// File entry at `block` of the partition, its contents, and the block they start in.
+    fn entry_at(&mut self, block: u32) -> (u8, Vec<u8>, u32) {
+        let fe = descriptor(self.part_block(block), TAG_FE, block).to_vec();
+        let file_type = fe[27];
+        let len = le64(&fe, 56) as usize;
+        let (ea_len, ad_len) = (le32(&fe, 168) as usize, le32(&fe, 172) as usize);
+        let ads = &fe[176 + ea_len..176 + ea_len + ad_len];
+
+        let (data, start) = match le16(&fe, 34) & 7 {
+            // In the file entry
+            3 => (ads.to_vec(), block),
+            // Short allocation descriptors
+            0 => {
+                let mut data = vec![];
+                for ad in ads.chunks(8) {
+                    let (ext_len, start) = (le32(ad, 0) as usize, le32(ad, 4));
+                    assert_eq!(ext_len >> 30, 0, "extent recorded and allocated");
+                    for i in 0..ext_len.div_ceil(BLOCK_SIZE) as u32 {
+                        data.extend_from_slice(self.part_block(start + i));
+                    }
+                    data.truncate(data.len() - (ext_len.next_multiple_of(BLOCK_SIZE) - ext_len));
+                }
+                (data, le32(ads, 4))
+            }
+            other => panic!("unexpected allocation type {other}"),
+        };
+        assert_eq!(data.len(), len, "length of the file entry at {block}");
+        (file_type, data, start)
+    }
+
+    /// Entries of the directory at `block`, as their names and file entry blocks, checking the
+    /// parent entry leads to `parent`.
+    fn read_dir(&mut self, block: u32, parent: u32) -> Vec<(String, u32)> {
+        let (file_type, data, start) = self.entry_at(block);
+        assert_eq!(file_type, 4);
+
+        let mut entries = vec![];
+        let mut off = 0;
+        while off < data.len() {
+            let fid = &data[off..];
+            let (name_len, iu_len) = (fid[19] as usize, le16(fid, 36) as usize);
+            let len = (38 + iu_len + name_len).next_multiple_of(4);
+            // Located at the block it starts in, the contents being in a row
+            let location = start + (off / BLOCK_SIZE) as u32;
+            let fid = descriptor(&fid[..len], TAG_FID, location);
+            let icb = le32(fid, 24);
+            if fid[18] & 0x8 != 0 {
+                assert_eq!(icb, parent, "parent of the directory at {block}");
+            } else {
+                entries.push((cs0(&fid[38 + iu_len..38 + iu_len + name_len]), icb));
+            }
+            off += len;
+        }
+        entries
+    }
+}
+
+fn find(entries: &[(String, u32)], name: &str) -> u32 {
+    entries
+        .iter()
+        .find(|(n, _)| n == name)
+        .unwrap_or_else(|| panic!("no {name}"))
+        .1
+}
+
+#[test]
+fn files_read_back() {
+    let dir = common::scratch_dir("udf");
+    fs::create_dir_all(dir.join("input/sub")).unwrap();
+    let large = (0..70000u32).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+    fs::write(dir.join("input/hello.txt"), "hello\n").unwrap();
+    fs::write(dir.join("input/日本語.txt"), "unicode\n").unwrap();
+    fs::write(dir.join("input/sub/data.bin"), &large).unwrap();
+    // Directory entries over several blocks
+    for i in 0..100 {
+        fs::write(dir.join(format!("input/sub/{i}")), i.to_string()).unwrap();
+    }
+
+    let image = common::build(&dir, "udf.img", &["--filesystem", "udf"]);
+    common::assert_blkid(&image, "udf");
+    let image = fs::read(&image).unwrap();
+    fs::remove_dir_all(&dir).unwrap();
+
+    // Volume recognition sequence, in 2K descriptors
+    for (i, id) in [b"BEA01", b"NSR03", b"TEA01"].into_iter().enumerate() {
+        assert_eq!(&image[(32 << 10) + i * 2048 + 1..][..5], id);
+    }
+
+    // Both anchors lead to the same volume descriptor sequences
+    let last = (image.len() / BLOCK_SIZE - 1) as u32;
+    let mut udf = Udf {
+        image,
+        partition: 0,
+        used: vec![],
+    };
+    let anchor = descriptor(udf.block(256), TAG_AVDP, 256).to_vec();
+    assert!(descriptor(udf.block(last), TAG_AVDP, last)[16..] == anchor[16..]);
+
+    let (mut pd, mut lvd) = (None, None);
+    for seq in [16, 24] {
+        let start = le32(&anchor, seq + 4);
+        for block in start.. {
+            let d = udf.block(block);
+            let d = descriptor(d, le16(d, 0), block).to_vec();
+            match le16(&d, 0) {
+                TAG_TD => break,
+                TAG_PD => pd = Some(d),
+                TAG_LVD => lvd = Some(d),
+                _ => {}
+            }
+        }
+    }
+    let (pd, lvd) = (pd.unwrap(), lvd.unwrap());
+    assert_eq!(le32(&lvd, 212) as usize, BLOCK_SIZE);
+    let integrity = le32(&lvd, 436);
+    let lvid = descriptor(udf.block(integrity), TAG_LVID, integrity);
+    assert_eq!(le32(lvid, 28), 1, "integrity closed");
+
+    udf.partition = le32(&pd, 188) as usize;
+    let partition_len = le32(&pd, 192);
+    let fsd_block = le32(&lvd, 252);
+    let fsd = descriptor(udf.part_block(fsd_block), TAG_FSD, fsd_block).to_vec();
+    let root_block = le32(&fsd, 404);
+
+    let root = udf.read_dir(root_block, root_block);
+    let (_, hello) = udf.entry(find(&root, "hello.txt"));
+    assert_eq!(hello, b"hello\n");
+    let (_, unicode) = udf.entry(find(&root, "日本語.txt"));
+    assert_eq!(unicode, b"unicode\n");
+
+    let sub_block = find(&root, "sub");
+    let sub = udf.read_dir(sub_block, root_block);
+    let (file_type, data) = udf.entry(find(&sub, "data.bin"));
+    assert_eq!(file_type, 5);
+    assert!(data == large);
+    for i in 0..100 {
+        let (_, data) = udf.entry(find(&sub, &i.to_string()));
+        assert_eq!(data, i.to_string().as_bytes());
+    }
+
+    // Every block read is allocated in the space bitmap, where set bits are free blocks
+    let bitmap_block = le32(&pd, 68);
+    let bitmap = descriptor(&udf.part_block(bitmap_block)[..24], TAG_SBD, bitmap_block).to_vec();
+    assert_eq!(le32(&bitmap, 16), partition_len);
+    let bits = &udf.image[(udf.partition + bitmap_block as usize) * BLOCK_SIZE + 24..];
+    for &block in &udf.used {
+        assert!(block < partition_len);
+        assert_eq!(
+            bits[block as usize / 8] & 1 << (block % 8),
+            0,
+            "block {block} free"
+        );
+    }
+}
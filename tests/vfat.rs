@@ -0,0 +1,119 @@
+//! vfat images, read back with fatfs.
+
+mod common;
+
+use clap::Parser;
+use mkimg::{Args, ImageBuilder};
+use std::fs::{self, File};
+use std::io::Read;
+use std::time::{Duration, SystemTime};
+
+#[test]
+fn entries_keep_their_modification_times() {
+    let dir = common::scratch_dir("vfat-mtime");
+    fs::create_dir_all(dir.join("input/sub")).unwrap();
+    fs::write(dir.join("input/sub/hello.txt"), "hello\n").unwrap();
+
+    // 2001-02-03 04:05:06 and 1999-09-09 09:09:10 UTC
+    let at = |secs| SystemTime::UNIX_EPOCH + Duration::from_secs(secs);
+    let file = File::options()
+        .write(true)
+        .open(dir.join("input/sub/hello.txt"))
+        .unwrap();
+    file.set_modified(at(981_173_106)).unwrap();
+    File::open(dir.join("input/sub"))
+        .unwrap()
+        .set_modified(at(936_868_150))
+        .unwrap();
+
+    let image = dir.join("vfat.img");
+    ImageBuilder::new(Args::parse_from([
+        "mkimg".as_ref(),
+        "--timestamp".as_ref(),
+        "1700000000".as_ref(),
+        "--input-dir".as_ref(),
+        dir.join("input").as_os_str(),
+        "--output-path".as_ref(),
+        image.as_os_str(),
+    ]))
+    .build()
+    .unwrap();
+
+    let fs = fatfs::FileSystem::new(File::open(&image).unwrap(), fatfs::FsOptions::new()).unwrap();
+    let time = |path: &str| {
+        let parent = fs.root_dir();
+        let entry = parent
+            .iter()
+            .chain(parent.open_dir("sub").unwrap().iter())
+            .map(Result::unwrap)
+            .find(|e| e.file_name() == path)
+            .unwrap();
+        let t = entry.modified();
+        (
+            t.date.year,
+            t.date.month,
+            t.date.day,
+            t.time.hour,
+            t.time.min,
+            t.time.sec,
+        )
+    };
+    let (sub, hello) = (time("sub"), time("hello.txt"));
+    drop(fs);
+    fs::remove_dir_all(&dir).unwrap();
+
+    // Times of reproducible images are in UTC
+    assert_eq!(sub, (1999, 9, 9, 9, 9, 10));
+    assert_eq!(hello, (2001, 2, 3, 4, 5, 6));
+}
+
+#[test]
+fn partition_of_files_only() {
+    let dir = common::scratch_dir("vfat-files");
+    fs::write(
+        dir.join("layout.toml"),
+        r#"
+[[partition]]
+name = "esp"
+type = "esp"
+filesystem = "vfat"
+size = "2M"
+
+[[files]]
+path = "/loader/loader.conf"
+content = "timeout 3\n"
+"#,
+    )
+    .unwrap();
+
+    let image = dir.join("disk.img");
+    ImageBuilder::new(Args::parse_from([
+        "mkimg".as_ref(),
+        "--deterministic".as_ref(),
+        "test".as_ref(),
+        "--partition-table".as_ref(),
+        "gpt".as_ref(),
+        "--layout".as_ref(),
+        dir.join("layout.toml").as_os_str(),
+        "--output-path".as_ref(),
+        image.as_os_str(),
+    ]))
+    .build()
+    .unwrap();
+
+    let disk = gpt::GptConfig::new().writable(false).open(&image).unwrap();
+    let part = &disk.partitions()[&1];
+    let (start, end) = (part.first_lba * 512, (part.last_lba + 1) * 512);
+    let data = fs::read(&image).unwrap();
+    fs::remove_dir_all(&dir).unwrap();
+
+    let part = std::io::Cursor::new(data[start as usize..end as usize].to_vec());
+    let fs = fatfs::FileSystem::new(part, fatfs::FsOptions::new()).unwrap();
+    let mut conf = String::new();
+    fs.root_dir()
+        .open_file("loader/loader.conf")
+        .unwrap()
+        .read_to_string(&mut conf)
+        .unwrap();
+    assert_eq!(conf, "timeout 3\n");
+}
@@ -0,0 +1,319 @@
+//! XFS images, read back from their superblock and allocation group headers, finding entries by
+//! their name hash in every directory format.
+
+mod common;
+
+use common::{be16, be32, be64};
+use crc::crc32;
+use std::fs;
+
+const BLOCK_SIZE: usize = 4096;
+const SECTOR_SIZE: usize = 512;
+const INODE_SIZE: usize = 512;
+
+const DIR_LEAF_DABLK: u64 = (32 << 30) / BLOCK_SIZE as u64;
+
+/// Check the CRC32C of a metadata structure, kept little endian at `off`.
+fn check_crc(b: &[u8], off: usize, what: &str) {
+    let mut copy = b.to_vec();
+    copy[off..off + 4].fill(0);
+    let crc = u32::from_le_bytes(b[off..off + 4].try_into().unwrap());
+    assert_eq!(crc, crc32::checksum_castagnoli(&copy), "CRC of {what}");
+}
+
+/// Name hash of directory entries, which leaf blocks are sorted by.
+fn da_hash(name: &[u8]) -> u32 {
+    let mut hash = 0u32;
+    let mut chunks = name.chunks_exact(4);
+    for c in &mut chunks {
+        hash = (c[0] as u32) << 21
+            ^ (c[1] as u32) << 14
+            ^ (c[2] as u32) << 7
+            ^ c[3] as u32
+            ^ hash.rotate_left(28);
+    }
+    match *chunks.remainder() {
+        [a, b, c] => (a as u32) << 14 ^ (b as u32) << 7 ^ c as u32 ^ hash.rotate_left(21),
+        [a, b] => (a as u32) << 7 ^ b as u32 ^ hash.rotate_left(14),
+        [a] => a as u32 ^ hash.rotate_left(7),
+        _ => hash,
+    }
+}
+
+/// Mapping of file blocks starting at `offset` to filesystem blocks.
+struct Extent {
+    offset: u64,
+    block: u64,
+    len: u64,
+}
+
+struct Xfs {
+    image: Vec<u8>,
+    agblocks: u64,
+    agblklog: u32,
+    uuid: [u8; 16],
+}
+
+impl Xfs {
+    fn block(&self, fsbno: u64) -> &[u8] {
+        let agno = fsbno >> self.agblklog;
+        let agbno = fsbno & ((1 << self.agblklog) - 1);
+        &self.image[((agno * self.agblocks + agbno) as usize) * BLOCK_SIZE..][..BLOCK_SIZE]
+    }
+
+    fn daddr(&self, fsbno: u64) -> u64 {
+        let agno = fsbno >> self.agblklog;
+        let agbno = fsbno & ((1 << self.agblklog) - 1);
+        (agno * self.agblocks + agbno) * (BLOCK_SIZE / 512) as u64
+    }
+
+    fn inode(&self, ino: u64) -> &[u8] {
+        let fsbno = ino >> 3;
+        let inode = &self.block(fsbno)[(ino & 7) as usize * INODE_SIZE..][..INODE_SIZE];
+        assert_eq!(&inode[0..2], b"IN");
+        assert_eq!(inode[4], 3, "version of inode {ino}");
+        check_crc(inode, 100, &format!("inode {ino}"));
+        assert_eq!(be64(inode, 152), ino);
+        assert_eq!(inode[160..176], self.uuid);
+        inode
+    }
+
+    fn extents(&self, inode: &[u8]) -> Vec<Extent> {
+        assert_eq!(inode[5], 2, "extents format");
+        (0..be32(inode, 76) as usize)
+            .map(|i| {
+                let (hi, lo) = (be64(inode, 176 + 16 * i), be64(inode, 184 + 16 * i));
+                Extent {
+                    offset: hi >> 9 & ((1 << 54) - 1),
+                    block: (hi & 0x1ff) << 43 | lo >> 21,
+                    len: lo & ((1 << 21) - 1),
+                }
+            })
+            .collect()
+    }
+
+    fn contents(&self, ino: u64) -> Vec<u8> {
+        let inode = self.inode(ino);
+        let mut data = vec![];
+        for e in self.extents(inode) {
+            assert_eq!(e.offset as usize * BLOCK_SIZE, data.len(), "hole in {ino}");
+            for i in 0..e.len {
+                data.extend_from_slice(self.block(e.block + i));
+            }
+        }
+        assert_eq!(
+            be64(inode, 64) as usize * BLOCK_SIZE,
+            data.len(),
+            "blocks of {ino}"
+        );
+        data.truncate(be64(inode, 56) as usize);
+        data
+    }
+
+    /// Block `dablk` of a directory, with its header checked.
+    fn dir_block(&self, ino: u64, extents: &[Extent], dablk: u64) -> &[u8] {
+        let e = extents
+            .iter()
+            .find(|e| (e.offset..e.offset + e.len).contains(&dablk))
+            .unwrap_or_else(|| panic!("no block {dablk} in directory {ino}"));
+        let fsbno = e.block + dablk - e.offset;
+        let block = self.block(fsbno);
+
+        // Data and free index blocks have a 32 bit magic, leaf and node blocks a 16 bit one
+        let (crc, blkno, uuid, owner) = match &block[0..4] {
+            b"XDB3" | b"XDD3" | b"XDF3" => (4, 8, 24, 40),
+            _ => (12, 16, 32, 48),
+        };
+        check_crc(block, crc, &format!("block {dablk} of directory {ino}"));
+        assert_eq!(be64(block, blkno), self.daddr(fsbno));
+        assert_eq!(block[uuid..uuid + 16], self.uuid);
+        assert_eq!(be64(block, owner), ino);
+        block
+    }
+
+    /// Inode of `name` in the directory `dir`, found by its hash in the leaf entries of the
+    /// directory, or by its name in a short form one.
+    fn lookup(&self, dir: u64, name: &str) -> u64 {
+        let name = name.as_bytes();
+        let inode = self.inode(dir);
+        assert_eq!(be16(inode, 2) & 0o170000, 0o040000);
+
+        if inode[5] == 1 {
+            let sf = &inode[176..];
+            assert_eq!(sf[1], 0, "short form entries with 8 byte inode numbers");
+            let mut pos = 6;
+            for _ in 0..sf[0] {
+                let len = sf[pos] as usize;
+                if &sf[pos + 3..pos + 3 + len] == name {
+                    return be32(sf, pos + 4 + len) as u64;
+                }
+                pos += 8 + len;
+            }
+            panic!("no {} in directory {dir}", String::from_utf8_lossy(name));
+        }
+
+        let extents = self.extents(inode);
+        let hash = da_hash(name);
+        let size = be64(inode, 56);
+
+        // Hash and address of the entries a leaf block has
+        let leaf_entries = |block: &[u8], start: usize, count: usize| {
+            (0..count)
+                .map(|i| (be32(block, start + 8 * i), be32(block, start + 8 * i + 4)))
+                .collect::<Vec<_>>()
+        };
+        // A single block, with the hash index at its end, when nothing is mapped after it
+        let end = extents.iter().map(|e| e.offset + e.len).max().unwrap();
+        let (entries, data_blocks) = if end == 1 {
+            let block = self.dir_block(dir, &extents, 0);
+            assert_eq!(&block[0..4], b"XDB3");
+            let count = be32(block, BLOCK_SIZE - 8) as usize;
+            let entries = leaf_entries(block, BLOCK_SIZE - 8 - 8 * count, count);
+            (entries, vec![(0, block)])
+        } else {
+            let leaf = self.dir_block(dir, &extents, DIR_LEAF_DABLK);
+            let leaf = match be16(leaf, 8) {
+                0x3df1 => leaf,
+                // Node over leaf blocks, the first covering the hash
+                0x3ebe => {
+                    let count = be16(leaf, 56) as usize;
+                    let before = (0..count)
+                        .map(|i| (be32(leaf, 64 + 8 * i), be32(leaf, 68 + 8 * i)))
+                        .find(|&(h, _)| h >= hash)
+                        .unwrap()
+                        .1;
+                    let leafn = self.dir_block(dir, &extents, before as u64);
+                    assert_eq!(be16(leafn, 8), 0x3dff);
+                    leafn
+                }
+                other => panic!("unexpected leaf magic {other:#x}"),
+            };
+            let entries = leaf_entries(leaf, 64, be16(leaf, 56) as usize);
+            assert!(entries.windows(2).all(|w| w[0].0 <= w[1].0), "leaf order");
+            let data_blocks = (0..size / BLOCK_SIZE as u64)
+                .map(|db| {
+                    let block = self.dir_block(dir, &extents, db);
+                    assert_eq!(&block[0..4], b"XDD3");
+                    (db, block)
+                })
+                .collect::<Vec<_>>();
+            (entries, data_blocks)
+        };
+
+        for (_, addr) in entries.into_iter().filter(|&(h, _)| h == hash) {
+            let offset = addr as usize * 8;
+            let block = data_blocks
+                .iter()
+                .find(|(db, _)| *db as usize == offset / BLOCK_SIZE)
+                .unwrap()
+                .1;
+            let entry = &block[offset % BLOCK_SIZE..];
+            let len = entry[8] as usize;
+            if &entry[9..9 + len] == name {
+                let tag = (9 + len + 1).next_multiple_of(8) - 2;
+                assert_eq!(be16(entry, tag) as usize, offset % BLOCK_SIZE);
+                return be64(entry, 0);
+            }
+        }
+        panic!("no {} in directory {dir}", String::from_utf8_lossy(name));
+    }
+}
+
+#[test]
+fn files_read_back() {
+    let dir = common::scratch_dir("xfs");
+    let large = (0..70000u32).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+    fs::create_dir_all(dir.join("input/sub")).unwrap();
+    fs::write(dir.join("input/hello.txt"), "hello\n").unwrap();
+    fs::write(dir.join("input/sub/data.bin"), &large).unwrap();
+    // Directories in block, leaf and node format, under the short form root
+    for (name, count) in [("block", 30), ("sub", 150), ("many", 1000)] {
+        fs::create_dir_all(dir.join("input").join(name)).unwrap();
+        for i in 0..count {
+            fs::write(
+                dir.join(format!("input/{name}/file-{i:03}.txt")),
+                i.to_string(),
+            )
+            .unwrap();
+        }
+    }
+
+    let image = common::build(&dir, "xfs.img", &["--filesystem", "xfs"]);
+    common::assert_blkid(&image, "xfs");
+    let image = fs::read(&image).unwrap();
+    fs::remove_dir_all(&dir).unwrap();
+
+    let sb = image[..SECTOR_SIZE].to_vec();
+    assert_eq!(&sb[0..4], b"XFSB");
+    check_crc(&sb, 224, "superblock");
+    assert_eq!(be32(&sb, 4) as usize, BLOCK_SIZE);
+    assert_eq!(be64(&sb, 8) as usize, image.len() / BLOCK_SIZE);
+    let xfs = Xfs {
+        agblocks: be32(&sb, 84) as u64,
+        agblklog: sb[124] as u32,
+        uuid: sb[32..48].try_into().unwrap(),
+        image,
+    };
+
+    // Every allocation group starts with the superblock and its checked headers, and its free
+    // space btrees hold the free blocks it counts
+    let mut free_total = 0;
+    for agno in 0..be32(&sb, 88) as u64 {
+        let ag = &xfs.image[(agno * xfs.agblocks) as usize * BLOCK_SIZE..][..BLOCK_SIZE];
+        assert!(ag[..SECTOR_SIZE] == sb, "superblock of group {agno}");
+        let (agf, agi, agfl) = (&ag[512..1024], &ag[1024..1536], &ag[1536..2048]);
+        assert_eq!(&agf[0..4], b"XAGF");
+        check_crc(agf, 216, "AGF");
+        assert_eq!(&agi[0..4], b"XAGI");
+        check_crc(agi, 312, "AGI");
+        assert_eq!(&agfl[0..4], b"XAFL");
+        check_crc(agfl, 32, "AGFL");
+
+        let mut trees = vec![];
+        for (root, levels, magic) in [(16, 28, b"AB3B"), (20, 32, b"AB3C")] {
+            assert_eq!(be32(agf, levels), 1);
+            let block = xfs.block(agno << xfs.agblklog | be32(agf, root) as u64);
+            assert_eq!(&block[0..4], magic);
+            check_crc(block, 52, "free space btree block");
+            let mut recs = (0..be16(block, 6) as usize)
+                .map(|i| (be32(block, 56 + 8 * i), be32(block, 60 + 8 * i)))
+                .collect::<Vec<_>>();
+            recs.sort();
+            trees.push(recs);
+        }
+        assert_eq!(trees[0], trees[1]);
+        let free = trees[0].iter().map(|r| r.1).sum::<u32>();
+        assert_eq!(free, be32(agf, 52));
+        free_total += free as u64 + be32(agf, 48) as u64;
+
+        let inobt = xfs.block(agno << xfs.agblklog | be32(agi, 20) as u64);
+        assert_eq!(&inobt[0..4], b"IAB3");
+        check_crc(inobt, 52, "inode btree block");
+    }
+    assert_eq!(free_total, be64(&sb, 144), "free blocks");
+
+    let root = be64(&sb, 56);
+    assert_eq!(xfs.inode(root)[5], 1, "short form root");
+    assert_eq!(xfs.contents(xfs.lookup(root, "hello.txt")), b"hello\n");
+
+    let sub = xfs.lookup(root, "sub");
+    assert!(xfs.contents(xfs.lookup(sub, "data.bin")) == large);
+    for (name, count, leaf) in [
+        ("block", 30, None),
+        ("sub", 150, Some(0x3df1)),
+        ("many", 1000, Some(0x3ebe)),
+    ] {
+        let d = xfs.lookup(root, name);
+        let extents = xfs.extents(xfs.inode(d));
+        match leaf {
+            Some(magic) => {
+                assert_eq!(be16(xfs.dir_block(d, &extents, DIR_LEAF_DABLK), 8), magic);
+            }
+            None => assert_eq!(&xfs.dir_block(d, &extents, 0)[0..4], b"XDB3"),
+        }
+        for i in 0..count {
+            let file = xfs.lookup(d, &format!("file-{i:03}.txt"));
+            assert_eq!(xfs.contents(file), i.to_string().as_bytes());
+        }
+    }
+}